@@ -0,0 +1,88 @@
+// Reading bodies: body() copies the buffer into a Vec on every call, body_bytes() shares it.
+// Run with `cargo bench --bench body`, the allocations of one read are printed before the timings.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rhodium::request::RhodRequest;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::runtime::Runtime;
+
+// Counts the allocations, to compare the APIs beyond their time
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const SIZES: [usize; 3] = [64, 4096, 65536];
+const READS: usize = 4; // ie: a few handlers and the service reading the body
+
+fn request(size: usize) -> RhodRequest {
+    RhodRequest::builder()
+        .body_bytes(&vec![b'a'; size])
+        .build()
+        .unwrap()
+}
+
+async fn read_vec(mut req: RhodRequest) -> usize {
+    let mut read = 0;
+    for _ in 0..READS {
+        read += req.body().await.unwrap().len();
+    }
+    read
+}
+
+async fn read_bytes(mut req: RhodRequest) -> usize {
+    let mut read = 0;
+    for _ in 0..READS {
+        read += req.body_bytes().await.unwrap().len();
+    }
+    read
+}
+
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_body(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    for size in SIZES {
+        let vec = allocations(|| {
+            rt.block_on(read_vec(request(size)));
+        });
+        let bytes = allocations(|| {
+            rt.block_on(read_bytes(request(size)));
+        });
+        println!(
+            "{} bytes read {} times: body() {} allocations, body_bytes() {} allocations",
+            size, READS, vec, bytes
+        );
+    }
+
+    let mut group = c.benchmark_group("body");
+    for size in SIZES {
+        group.bench_with_input(BenchmarkId::new("body", size), &size, |b, size| {
+            b.iter(|| rt.block_on(read_vec(request(*size))))
+        });
+        group.bench_with_input(BenchmarkId::new("body_bytes", size), &size, |b, size| {
+            b.iter(|| rt.block_on(read_bytes(request(*size))))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_body);
+criterion_main!(benches);
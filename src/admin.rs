@@ -0,0 +1,474 @@
+// Admin server: runtime controls of a Rhodium server on its own listener, served by a RhodStack isolated
+// from the public one. It runs while the server runs.
+// Anyone reaching the listener controls the server: bind it to a loopback address or to a unix socket
+// (created readable only by the user of the process), and require a bearer token with with_token when
+// other local users or processes arent trusted.
+//
+//  GET  /stats                          connection and request counters
+//  GET  /admission                      running, queued and shed requests (stacks with admission control)
+//  GET  /handlers                       handlers and service of the current stack
+//  GET  /config                         config file currently applied (servers created from a config file)
+//  POST /reload                         reloads the config file
+//  GET  /log-level                      current log level
+//  PUT  /log-level?level=debug          changes the log level
+//  GET  /drain                         whether the server is draining
+//  PUT  /drain?enabled=true             starts (or stops with false) draining
+//  POST /cache/purge?name=pages         clears a registered cache, or only a resource with &uri=/index.html
+//                                       (with the host of the responses, ie: &uri=http%3A%2F%2Fexample.com%2Findex.html)
+//
+// Requests changing the server (every method but GET) are written to the audit log, if set.
+use crate::audit::{AuditEvent, AuditLog};
+use crate::drain::Drain;
+use crate::errors::{RhodHyperError, RhodResult};
+use crate::handlers::{cache_key, CacheStore};
+use crate::protocols::{HttpProtocolConf, SocketConf};
+use crate::reload::{ConfigReloader, LiveSettings};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::{RhodService, RhodStack};
+use crate::stats::ServerStats;
+use crate::{background::BackgroundJob, CommunicationChannel, RhodConnInfo, Rhodium};
+use async_trait::async_trait;
+use http::header::AUTHORIZATION;
+use http::{Method, StatusCode, Uri};
+use log::LevelFilter;
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+enum AdminListener {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+pub struct AdminServer {
+    listener: AdminListener,
+    token: Option<String>,
+    caches: Vec<(String, Arc<dyn CacheStore>)>,
+    audit: Option<AuditLog>,
+}
+
+impl AdminServer {
+    pub fn new(addr: SocketAddr) -> AdminServer {
+        AdminServer {
+            listener: AdminListener::Tcp(addr),
+            token: None,
+            caches: vec![],
+            audit: None,
+        }
+    }
+
+    // Admin server on a unix socket, replacing the socket left by a previous run
+    #[cfg(unix)]
+    pub fn unix(path: &Path) -> AdminServer {
+        AdminServer {
+            listener: AdminListener::Unix(path.to_path_buf()),
+            ..AdminServer::new(SocketAddr::from(([127, 0, 0, 1], 0)))
+        }
+    }
+
+    // Requests without Authorization: Bearer <token> are answered with 401
+    pub fn with_token(self, token: &str) -> Self {
+        AdminServer {
+            token: Some(token.to_string()),
+            ..self
+        }
+    }
+
+    // Cache that can be purged by name
+    pub fn with_cache(mut self, name: &str, store: Arc<dyn CacheStore>) -> Self {
+        self.caches.push((name.to_string(), store));
+        self
+    }
+
+    // Writes the admin actions to the audit log
+    pub fn with_audit(self, audit: AuditLog) -> Self {
+        AdminServer {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    // Binds the admin listener, returning the job serving it
+    pub(crate) fn start<C: CommunicationChannel>(
+        self,
+        live: Arc<LiveSettings<C>>,
+        stats: Arc<ServerStats>,
+        drain: Drain,
+        reloader: Option<Arc<ConfigReloader<C>>>,
+    ) -> Result<BackgroundJob, RhodHyperError> {
+        let bind_error =
+            |e| RhodHyperError::ConfigError(format!("Error when binding admin server. {}", e));
+        let has_token = self.token.is_some();
+        let service = AdminService {
+            live,
+            stats,
+            drain,
+            reloader,
+            caches: self.caches,
+            token: self.token,
+            audit: self.audit,
+        };
+        let stack = Arc::new(RhodStack::new(vec![], Box::new(service)));
+        match self.listener {
+            AdminListener::Tcp(addr) => {
+                let tcp = SocketConf::default().bind(&addr).map_err(bind_error)?;
+                if !addr.ip().is_loopback() && !has_token {
+                    warn!("Admin server on {} without a token", addr);
+                }
+                let admin = Rhodium::new(stack, addr, HttpProtocolConf::HTTP);
+                info!("Admin server listening on http://{}", addr);
+                Ok(BackgroundJob::once(async move {
+                    if let Err(e) = admin.serve(tcp).await {
+                        error!("Admin server stopped. {}", e);
+                    }
+                }))
+            }
+            #[cfg(unix)]
+            AdminListener::Unix(path) => {
+                let listener = bind_unix(&path).map_err(bind_error)?;
+                let local = SocketAddr::from(([127, 0, 0, 1], 0));
+                let admin = Rhodium::new(stack, local, HttpProtocolConf::HTTP);
+                info!("Admin server listening on {}", path.display());
+                Ok(BackgroundJob::once(async move {
+                    if let Err(e) = admin.serve_unix(listener).await {
+                        error!("Admin server stopped. {}", e);
+                    }
+                }))
+            }
+        }
+    }
+}
+
+// Unix socket only the user of the process can connect to
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let previous = std::fs::symlink_metadata(path);
+    if previous.is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+pub(crate) struct AdminComm {}
+
+impl CommunicationChannel for AdminComm {
+    fn new() -> AdminComm {
+        AdminComm {}
+    }
+}
+
+pub(crate) struct AdminService<C> {
+    live: Arc<LiveSettings<C>>,
+    stats: Arc<ServerStats>,
+    drain: Drain,
+    reloader: Option<Arc<ConfigReloader<C>>>,
+    caches: Vec<(String, Arc<dyn CacheStore>)>,
+    token: Option<String>,
+    audit: Option<AuditLog>,
+}
+
+impl<C: CommunicationChannel> AdminService<C> {
+    // Whether the request has the token, if one is required. Compared in constant time
+    fn authorized(&self, req: &RhodRequest) -> bool {
+        let token = match &self.token {
+            Some(token) => token.as_bytes(),
+            None => return true,
+        };
+        let given = req
+            .header_str(AUTHORIZATION)
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("")
+            .as_bytes();
+        given.len() == token.len()
+            && given
+                .iter()
+                .zip(token)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    async fn route(&self, req: &RhodRequest) -> RhodResult<RhodResponse> {
+        if !self.authorized(req) {
+            return answer(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+        }
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/stats") => ok(&self.stats.snapshot()),
+            (&Method::GET, "/admission") => match self.live.stack.load().admission() {
+                Some(admission) => ok(&admission.stats()),
+                None => answer(StatusCode::NOT_FOUND, "The stack has no admission control"),
+            },
+            (&Method::GET, "/handlers") => {
+                let description = self.live.stack.load().describe();
+                ok(&json!({
+                    "handlers": description.handlers,
+                    "service": description.service,
+                }))
+            }
+            (&Method::GET, "/config") => match &self.reloader {
+                Some(reloader) => ok(&reloader.current()),
+                None => answer(
+                    StatusCode::NOT_FOUND,
+                    "The server wasnt created from a config file",
+                ),
+            },
+            (&Method::POST, "/reload") => match &self.reloader {
+                Some(reloader) => match reloader.reload() {
+                    Ok(()) => ok(&json!({ "reloaded": true })),
+                    Err(e) => answer(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+                },
+                None => answer(
+                    StatusCode::NOT_FOUND,
+                    "The server wasnt created from a config file",
+                ),
+            },
+            (&Method::GET, "/log-level") => ok(&json!({ "level": log::max_level().to_string() })),
+            (&Method::PUT, "/log-level") => {
+                match query_param(req.uri(), "level").map(|l| LevelFilter::from_str(&l)) {
+                    Some(Ok(level)) => {
+                        log::set_max_level(level);
+                        ok(&json!({ "level": level.to_string() }))
+                    }
+                    _ => answer(
+                        StatusCode::BAD_REQUEST,
+                        "Expected level=off|error|warn|info|debug|trace",
+                    ),
+                }
+            }
+            (&Method::GET, "/drain") => ok(&json!({ "draining": self.drain.is_draining() })),
+            (&Method::PUT, "/drain") => match query_param(req.uri(), "enabled").as_deref() {
+                Some("true") => {
+                    self.drain.start();
+                    ok(&json!({ "draining": true }))
+                }
+                Some("false") => {
+                    self.drain.stop();
+                    ok(&json!({ "draining": false }))
+                }
+                _ => answer(StatusCode::BAD_REQUEST, "Expected enabled=true|false"),
+            },
+            (&Method::POST, "/cache/purge") => self.purge(req.uri()).await,
+            _ => answer(StatusCode::NOT_FOUND, "Unknown admin endpoint"),
+        }
+    }
+
+    async fn purge(&self, uri: &Uri) -> RhodResult<RhodResponse> {
+        let name = query_param(uri, "name").unwrap_or_default();
+        let store = match self.caches.iter().find(|(cache, _)| *cache == name) {
+            Some((_, store)) => store,
+            None => return answer(StatusCode::NOT_FOUND, "Unknown cache"),
+        };
+        match query_param(uri, "uri") {
+            Some(resource) => match resource.parse::<Uri>() {
+                Ok(resource) => store.remove(&cache_key(&Method::GET, &resource)).await,
+                Err(_) => return answer(StatusCode::BAD_REQUEST, "Invalid uri"),
+            },
+            None => store.clear().await,
+        }
+        ok(&json!({ "purged": name }))
+    }
+}
+
+#[async_trait]
+impl<C: CommunicationChannel> RhodService<AdminComm> for AdminService<C> {
+    async fn serve(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+        _comm: &mut AdminComm,
+    ) -> RhodResult<RhodResponse> {
+        let res = self.route(&req).await;
+        if let Some(audit) = self.audit.as_ref().filter(|_| req.method() != Method::GET) {
+            let status = res.as_ref().map_or(500, |res| res.status_as_int());
+            audit
+                .emit(
+                    AuditEvent::new("admin_action")
+                        .with_client(&conn.addr.to_string())
+                        .with_detail("method", req.method_str())
+                        .with_detail("uri", &req.uri().to_string())
+                        .with_detail("status", &status.to_string()),
+                )
+                .await;
+        }
+        res
+    }
+}
+
+fn ok<T: serde::Serialize>(value: &T) -> RhodResult<RhodResponse> {
+    RhodResponse::builder().body_json(value).build()
+}
+
+fn answer(status: StatusCode, error: &str) -> RhodResult<RhodResponse> {
+    RhodResponse::builder()
+        .status(status)
+        .body_json(&json!({ "error": error }))
+        .build()
+}
+
+// First value of a query parameter, percent decoded
+fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key != name {
+            return None;
+        }
+        percent_decode_str(&value.replace('+', " "))
+            .decode_utf8()
+            .map(|v| v.into_owned())
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::{CachedResponse, MemoryCacheStore, PassThroughHandler};
+    use crate::protocols::HttpProtocol;
+    use crate::stack::RhodHandlerInStack;
+
+    struct Service {}
+    #[async_trait]
+    impl RhodService<AdminComm> for Service {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: RhodRequest,
+            _comm: &mut AdminComm,
+        ) -> RhodResult<RhodResponse> {
+            RhodResponse::builder().build()
+        }
+    }
+
+    async fn call(
+        admin: &RhodStack<AdminComm>,
+        method: Method,
+        uri: &str,
+    ) -> (u16, serde_json::Value) {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .method(method)
+            .uri(uri)
+            .build()
+            .unwrap();
+        let mut res = admin.execute(&conn, req).await.unwrap();
+        let body = serde_json::from_slice(&res.body().await.unwrap()).unwrap();
+        (res.status_as_int(), body)
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let served = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(
+                PassThroughHandler {},
+            ))],
+            Box::new(Service {}),
+        );
+        let stats = Arc::new(ServerStats::new());
+        let _conn = stats.connection();
+        let store = Arc::new(MemoryCacheStore::new(10));
+        let key = cache_key(&Method::GET, &"/index.html".parse().unwrap());
+        let cached = CachedResponse {
+            status: 200,
+            headers: vec![],
+            body: vec![],
+            stored_at: 0,
+            expires_at: u64::MAX,
+            vary: vec![],
+            variants: vec![],
+        };
+        store.put(&key, cached).await;
+        let drain = Drain::new();
+        let admin = RhodStack::new(
+            vec![],
+            Box::new(AdminService {
+                live: Arc::new(LiveSettings::new(Arc::new(served))),
+                stats,
+                drain: drain.clone(),
+                reloader: None,
+                caches: vec![("pages".to_string(), store.clone() as Arc<dyn CacheStore>)],
+                token: None,
+                audit: None,
+            }),
+        );
+
+        let (status, stats) = call(&admin, Method::GET, "/stats").await;
+        assert_eq!(status, 200);
+        assert_eq!(stats["open_connections"], 1);
+
+        let (_, handlers) = call(&admin, Method::GET, "/handlers").await;
+        assert_eq!(handlers["handlers"].as_array().unwrap().len(), 1);
+
+        let (_, draining) = call(&admin, Method::PUT, "/drain?enabled=true").await;
+        assert_eq!(draining["draining"], true);
+        assert!(drain.is_draining());
+
+        let (status, _) = call(&admin, Method::GET, "/config").await;
+        assert_eq!(status, 404);
+
+        let (status, _) = call(&admin, Method::PUT, "/log-level?level=loud").await;
+        assert_eq!(status, 400);
+
+        let (status, _) = call(
+            &admin,
+            Method::POST,
+            "/cache/purge?name=pages&uri=%2Findex.html",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(store.get(&key).await.is_none());
+        let (status, _) = call(&admin, Method::POST, "/cache/purge?name=other").await;
+        assert_eq!(status, 404);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_admin_unix_socket() {
+        use crate::background::BackgroundTasks;
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let served = RhodStack::new(vec![], Box::new(Service {}));
+        let path = std::env::temp_dir().join(format!("rhodium-admin-{}.sock", std::process::id()));
+        let job = AdminServer::unix(&path)
+            .with_token("s3cret")
+            .start(
+                Arc::new(LiveSettings::new(Arc::new(served))),
+                Arc::new(ServerStats::new()),
+                Drain::new(),
+                None,
+            )
+            .unwrap();
+        let _tasks = BackgroundTasks::spawn(vec![("admin".to_string(), job)]);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let get = |token: &str| {
+            let request = format!(
+                "GET /stats HTTP/1.1\r\nHost: admin\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+                token
+            );
+            let path = path.clone();
+            async move {
+                let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut res = String::new();
+                stream.read_to_string(&mut res).await.unwrap();
+                res
+            }
+        };
+        assert!(get("s3cret").await.starts_with("HTTP/1.1 200"));
+        assert!(get("wrong").await.starts_with("HTTP/1.1 401"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+// Admission control of a stack, see RhodStack::with_admission. At most max_concurrent requests run
+// through the stack at once, and up to max_queue more wait for a slot (for the queue timeout at most).
+// Requests arriving with the queue full, or waiting too long, are shed: answered right away with
+// 503 and Retry-After, instead of piling up and making every request slow.
+// With an adaptive limit the concurrency follows the latency of the stack (AIMD): it grows by one while
+// requests are fast and the limit is reached, and shrinks by a factor on every slow request, finding
+// what a service backed by an external dependency sustains.
+// Requests are admitted after the request handlers, right before the service, so handlers can give
+// them a Priority. Shed requests are answered through the handlers that saw the request.
+use crate::response::RhodResponse;
+use crate::runtime;
+use http::header::RETRY_AFTER;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+// Additive increase, multiplicative decrease of the concurrency limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AimdLimit {
+    latency_threshold: Duration, // slower requests shrink the limit
+    min: usize,
+    max: usize,
+    backoff: f64,
+}
+
+impl AimdLimit {
+    pub fn new(latency_threshold: Duration) -> AimdLimit {
+        AimdLimit {
+            latency_threshold,
+            min: 1,
+            max: 1000,
+            backoff: 0.9,
+        }
+    }
+
+    pub fn with_range(self, min: usize, max: usize) -> Self {
+        AimdLimit {
+            min: min.max(1),
+            max: max.max(min),
+            ..self
+        }
+    }
+
+    // Factor applied to the limit on a slow request, between 0.5 and 1
+    pub fn with_backoff(self, backoff: f64) -> Self {
+        AimdLimit {
+            backoff: backoff.clamp(0.5, 1.0),
+            ..self
+        }
+    }
+
+    fn next(&self, limit: usize, latency: Duration, saturated: bool) -> usize {
+        if latency > self.latency_threshold {
+            ((limit as f64 * self.backoff) as usize).max(self.min)
+        } else if saturated {
+            (limit + 1).min(self.max)
+        } else {
+            limit
+        }
+    }
+}
+
+// Priority of a request for the admission control, given by a PriorityHandler (Interactive when
+// none is given). Freed slots go to the highest priority waiting, and with the queue full a request
+// takes the place of a queued one of lower priority, which is shed
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Batch,
+    #[default]
+    Interactive,
+    Critical, // ie: health checks and admin
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Batch => "batch",
+            Priority::Interactive => "interactive",
+            Priority::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct PriorityCounts {
+    pub critical: u64,
+    pub interactive: u64,
+    pub batch: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct AdmissionStats {
+    pub limit: u64, // requests allowed to run at once
+    pub running: u64,
+    pub queued: u64,    // waiting for a slot now
+    pub admitted: u64,  // since the stack was created
+    pub shed: u64,      // queue full, queue timeout or taken over by a higher priority
+    pub timed_out: u64, // shed after waiting in the queue
+    pub shed_by_priority: PriorityCounts,
+}
+
+// Request waiting in the queue, admitted when the sender is used and shed when it is dropped
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    admit: oneshot::Sender<()>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    limit: usize,
+    running: usize,
+    queues: [VecDeque<Waiter>; 3], // by priority
+    next_id: u64,
+}
+
+impl Slots {
+    fn queued(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    // Hands the free slots to the waiters, higher priorities first
+    fn wake(&mut self) {
+        while self.running < self.limit {
+            let waiter = match self.queues.iter_mut().rev().find_map(VecDeque::pop_front) {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            // the waiter could have gone away
+            if waiter.admit.send(()).is_ok() {
+                self.running += 1;
+            }
+        }
+    }
+
+    // Sheds the newest waiter of a priority lower than the given one, if any
+    fn evict_below(&mut self, priority: Priority) -> bool {
+        self.queues[..priority as usize]
+            .iter_mut()
+            .find_map(VecDeque::pop_back)
+            .is_some()
+    }
+
+    fn leave(&mut self, priority: Priority, id: u64) -> bool {
+        let queue = &mut self.queues[priority as usize];
+        match queue.iter().position(|waiter| waiter.id == id) {
+            Some(position) => queue.remove(position).is_some(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AdmissionController {
+    slots: Mutex<Slots>,
+    adaptive: Option<AimdLimit>,
+    max_queue: usize,
+    queue_timeout: Option<Duration>,
+    retry_after: Duration,
+    admitted: AtomicU64,
+    shed: [AtomicU64; 3], // by priority
+    timed_out: AtomicU64,
+}
+
+// Slot of an admitted request, freed when dropped
+pub(crate) struct Admission<'a> {
+    controller: &'a AdmissionController,
+    start: Instant,
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        self.controller.release(self.start.elapsed());
+    }
+}
+
+// Place of a request in the queue, left when the request is admitted, shed or goes away
+struct Queued<'a> {
+    controller: &'a AdmissionController,
+    priority: Priority,
+    id: u64,
+    admit: oneshot::Receiver<()>,
+    left: bool,
+}
+
+impl Queued<'_> {
+    // Whether it was still waiting
+    fn leave(&mut self) -> bool {
+        if self.left {
+            return false;
+        }
+        self.left = true;
+        let mut slots = self.controller.slots.lock().unwrap();
+        slots.leave(self.priority, self.id)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        // the slot handed to a request that went away before taking it is freed
+        if !self.leave() && self.admit.try_recv().is_ok() {
+            let mut slots = self.controller.slots.lock().unwrap();
+            slots.running -= 1;
+            slots.wake();
+        }
+    }
+}
+
+impl AdmissionController {
+    pub fn new(max_concurrent: usize, max_queue: usize) -> AdmissionController {
+        AdmissionController {
+            slots: Mutex::new(Slots {
+                limit: max_concurrent,
+                running: 0,
+                queues: Default::default(),
+                next_id: 0,
+            }),
+            adaptive: None,
+            max_queue,
+            queue_timeout: None,
+            retry_after: Duration::from_secs(1),
+            admitted: AtomicU64::new(0),
+            shed: Default::default(),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_queue_timeout(self, queue_timeout: Duration) -> Self {
+        AdmissionController {
+            queue_timeout: Some(queue_timeout),
+            ..self
+        }
+    }
+
+    // The limit starts at max_concurrent and then follows the latency
+    pub fn with_adaptive_limit(self, adaptive: AimdLimit) -> Self {
+        AdmissionController {
+            adaptive: Some(adaptive),
+            ..self
+        }
+    }
+
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        AdmissionController {
+            retry_after,
+            ..self
+        }
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        let (limit, running, queued) = {
+            let slots = self.slots.lock().unwrap();
+            (slots.limit, slots.running, slots.queued())
+        };
+        let shed = |priority: Priority| self.shed[priority as usize].load(Ordering::Relaxed);
+        let shed_by_priority = PriorityCounts {
+            critical: shed(Priority::Critical),
+            interactive: shed(Priority::Interactive),
+            batch: shed(Priority::Batch),
+        };
+        AdmissionStats {
+            limit: limit as u64,
+            running: running as u64,
+            queued: queued as u64,
+            admitted: self.admitted.load(Ordering::Relaxed),
+            shed: shed_by_priority.critical + shed_by_priority.interactive + shed_by_priority.batch,
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            shed_by_priority,
+        }
+    }
+
+    // Waits for a slot, None if the request is shed
+    pub(crate) async fn admit(&self, priority: Priority) -> Option<Admission<'_>> {
+        let (id, admit) = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.running < slots.limit {
+                slots.running += 1;
+                return Some(self.admitted());
+            }
+            // with the queue full, takes the place of a lower priority
+            if slots.queued() >= self.max_queue && !slots.evict_below(priority) {
+                drop(slots);
+                return self.shed(priority);
+            }
+            let (sender, receiver) = oneshot::channel();
+            slots.next_id += 1;
+            let id = slots.next_id;
+            slots.queues[priority as usize].push_back(Waiter { id, admit: sender });
+            (id, receiver)
+        };
+        let mut queued = Queued {
+            controller: self,
+            priority,
+            id,
+            admit,
+            left: false,
+        };
+        let waited = match self.queue_timeout {
+            Some(timeout) => runtime::timeout(timeout, &mut queued.admit).await.ok(),
+            None => Some((&mut queued.admit).await),
+        };
+        let waiting = queued.leave();
+        match waited {
+            Some(Ok(())) => Some(self.admitted()),
+            // the slot could be given right as the timeout expired
+            None if !waiting && queued.admit.try_recv().is_ok() => Some(self.admitted()),
+            None if waiting => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                self.shed(priority)
+            }
+            _ => self.shed(priority),
+        }
+    }
+
+    fn admitted(&self) -> Admission<'_> {
+        self.admitted.fetch_add(1, Ordering::Relaxed);
+        Admission {
+            controller: self,
+            start: Instant::now(),
+        }
+    }
+
+    fn shed(&self, priority: Priority) -> Option<Admission<'_>> {
+        self.shed[priority as usize].fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    fn release(&self, latency: Duration) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(adaptive) = &self.adaptive {
+            slots.limit = adaptive.next(slots.limit, latency, slots.running >= slots.limit);
+        }
+        slots.running -= 1;
+        slots.wake();
+    }
+
+    pub(crate) fn shed_response(&self) -> RhodResponse {
+        let mut res = http::Response::new(Default::default());
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        res.headers_mut()
+            .insert(RETRY_AFTER, self.retry_after.as_secs().max(1).into());
+        RhodResponse::new(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admission() {
+        let admission =
+            AdmissionController::new(2, 1).with_queue_timeout(Duration::from_millis(50));
+        let first = admission.admit(Priority::default()).await.unwrap();
+        let _second = admission.admit(Priority::default()).await.unwrap();
+        // third waits in the queue, fourth is shed
+        let (third, fourth) = tokio::join!(admission.admit(Priority::default()), async {
+            tokio::task::yield_now().await;
+            let shed = admission.admit(Priority::default()).await.is_none();
+            drop(first);
+            shed
+        });
+        assert!(third.is_some());
+        assert!(fourth);
+        let stats = admission.stats();
+        assert_eq!((stats.running, stats.admitted, stats.shed), (2, 3, 1));
+
+        // times out in the queue
+        assert!(admission.admit(Priority::default()).await.is_none());
+        assert_eq!(admission.stats().timed_out, 1);
+        assert_eq!(admission.shed_response().status_as_int(), 503);
+
+        let limit = AimdLimit::new(Duration::from_millis(20)).with_range(2, 4);
+        let adaptive = AdmissionController::new(3, 0).with_adaptive_limit(limit);
+        // fast requests at the limit grow it, up to the max
+        for _ in 0..3 {
+            let running: Vec<_> = futures_util::future::join_all(
+                (0..adaptive.stats().limit).map(|_| adaptive.admit(Priority::default())),
+            )
+            .await;
+            drop(running);
+        }
+        assert_eq!(adaptive.stats().limit, 4);
+        // slow ones shrink it, down to the min
+        for _ in 0..10 {
+            let slow = adaptive.admit(Priority::default()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(25)).await;
+            drop(slow);
+        }
+        assert_eq!(adaptive.stats().limit, 2);
+        let first = adaptive.admit(Priority::default()).await;
+        let second = adaptive.admit(Priority::default()).await;
+        assert!(first.is_some() && second.is_some());
+        assert!(adaptive.admit(Priority::default()).await.is_none());
+
+        // a higher priority takes the place of a queued lower one, and gets the first free slot
+        let admission = AdmissionController::new(1, 1);
+        let running = admission.admit(Priority::Batch).await.unwrap();
+        let (batch, critical) = tokio::join!(admission.admit(Priority::Batch), async {
+            tokio::task::yield_now().await;
+            let critical = admission.admit(Priority::Critical);
+            tokio::pin!(critical);
+            assert!(futures_util::poll!(&mut critical).is_pending());
+            drop(running);
+            critical.await
+        });
+        assert!(batch.is_none());
+        assert!(critical.is_some());
+        assert_eq!(admission.stats().shed_by_priority.batch, 1);
+
+        // the slot handed to a request dropped before taking it is freed
+        let admission = AdmissionController::new(1, 1);
+        let running = admission.admit(Priority::default()).await.unwrap();
+        let mut queued = Box::pin(admission.admit(Priority::default()));
+        assert!(futures_util::poll!(&mut queued).is_pending());
+        drop(running);
+        assert_eq!(admission.stats().running, 1);
+        drop(queued);
+        assert_eq!(admission.stats().running, 0);
+        assert!(admission.admit(Priority::default()).await.is_some());
+    }
+}
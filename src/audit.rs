@@ -0,0 +1,285 @@
+// Append-only audit log of security events (auth failures, WAF matches, admin actions), kept apart from
+// the access and error logs. Entries are JSON lines chained by hash: each entry holds the SHA-256 of the
+// previous one and its own, so removing or editing an entry breaks the chain (see verify_chain).
+// Entries are written to a LogSink, flushed (fsynced for files) every interval and by sync_job while
+// there are no new events. Written to a RollingFileSink, the chain continues across rotated files.
+use crate::background::BackgroundJob;
+use crate::log_sink::{LogSink, RollingFileSink};
+use crate::util::sha256_hex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+// Previous hash of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub kind: String, // ie: auth_failure, waf_match, admin_action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<String, String>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: &str) -> AuditEvent {
+        AuditEvent {
+            kind: kind.to_string(),
+            client: None,
+            details: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_client(self, client: &str) -> Self {
+        AuditEvent {
+            client: Some(client.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_detail(mut self, name: &str, value: &str) -> Self {
+        self.details.insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+// An event as written to the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub time: u64, // unix millis
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    pub prev: String, // hash of the previous entry
+    pub hash: String, // SHA-256 (hex) of the entry serialized with an empty hash
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> io::Result<String> {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unhashed)?;
+        Ok(sha256_hex(&json))
+    }
+}
+
+// Handle of an audit log. Clones write to the same chain
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Arc<dyn LogSink>,
+    chain: Arc<Mutex<Chain>>,
+    fsync_interval: Duration,
+}
+
+// End of the chain, and the entries written after the last flush of the sink
+struct Chain {
+    seq: u64,
+    last_hash: String,
+    last_sync: Instant,
+    unsynced: bool,
+}
+
+impl AuditLog {
+    // Starts a new chain on the sink (ie: syslog, where the previous entries cant be read back)
+    pub fn new(sink: Arc<dyn LogSink>) -> AuditLog {
+        AuditLog::continuing(sink, 0, GENESIS.to_string())
+    }
+
+    // Appends to the file, continuing the chain of its last entry
+    pub async fn open(sink: RollingFileSink) -> io::Result<AuditLog> {
+        let (seq, last_hash) = match std::fs::File::open(sink.path()) {
+            Ok(file) => last_entry(BufReader::new(file))?
+                .map_or((0, GENESIS.to_string()), |entry| {
+                    (entry.seq + 1, entry.hash)
+                }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, GENESIS.to_string()),
+            Err(e) => return Err(e),
+        };
+        Ok(AuditLog::continuing(Arc::new(sink), seq, last_hash))
+    }
+
+    fn continuing(sink: Arc<dyn LogSink>, seq: u64, last_hash: String) -> AuditLog {
+        let chain = Chain {
+            seq,
+            last_hash,
+            last_sync: Instant::now(),
+            unsynced: false,
+        };
+        AuditLog {
+            sink,
+            chain: Arc::new(Mutex::new(chain)),
+            fsync_interval: Duration::from_secs(1),
+        }
+    }
+
+    // Time between flushes of the sink (zero flushes every entry). Defaults to 1 second
+    pub fn with_fsync_interval(self, fsync_interval: Duration) -> Self {
+        AuditLog {
+            fsync_interval,
+            ..self
+        }
+    }
+
+    // Writes the event. Failures are logged, events are never dropped silently
+    pub async fn emit(&self, event: AuditEvent) {
+        let kind = event.kind.clone();
+        if let Err(e) = self.append(event).await {
+            error!("Couldnt write audit event {}. {}", kind, e);
+        }
+    }
+
+    async fn append(&self, event: AuditEvent) -> io::Result<()> {
+        // the chain is locked until the entry is written, so entries are written in order
+        let mut chain = self.chain.lock().await;
+        let mut entry = AuditEntry {
+            seq: chain.seq,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            event,
+            prev: chain.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash()?;
+        self.sink.write(&serde_json::to_value(&entry)?).await?;
+        chain.seq += 1;
+        chain.last_hash = entry.hash;
+        chain.unsynced = true;
+        if chain.last_sync.elapsed() >= self.fsync_interval {
+            self.flush(&mut chain).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self, chain: &mut Chain) -> io::Result<()> {
+        if chain.unsynced {
+            self.sink.flush().await?;
+            chain.unsynced = false;
+        }
+        chain.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Flushes the entries written since the last flush
+    pub async fn sync(&self) -> io::Result<()> {
+        self.flush(&mut *self.chain.lock().await).await
+    }
+
+    // Job flushing the log every interval, for the entries written while no other event came
+    pub fn sync_job(&self, interval: Duration) -> BackgroundJob {
+        let audit = self.clone();
+        BackgroundJob::every(interval, move || {
+            let audit = audit.clone();
+            async move {
+                if let Err(e) = audit.sync().await {
+                    error!("Couldnt fsync the audit log. {}", e);
+                }
+            }
+        })
+    }
+}
+
+fn last_entry<R: BufRead>(reader: R) -> io::Result<Option<AuditEntry>> {
+    let mut last = None;
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    match last {
+        Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+        None => Ok(None),
+    }
+}
+
+// Checks the chain of a log, returning the hash of its last entry (to verify the next rotated file).
+// prev is the last hash of the previous file, or None for the first file of the chain
+pub fn verify_chain<R: BufRead>(reader: R, prev: Option<&str>) -> io::Result<Option<String>> {
+    let broken = |seq: u64, msg: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Audit chain broken at entry {}. {}", seq, msg),
+        )
+    };
+    let mut last: Option<(u64, String)> = None;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: AuditEntry = serde_json::from_str(&line)?;
+        let expected_prev = match (&last, prev) {
+            (Some((_, hash)), _) => hash.as_str(),
+            (None, Some(prev)) => prev,
+            (None, None) => GENESIS,
+        };
+        if entry.prev != expected_prev {
+            return Err(broken(entry.seq, "Previous hash doesnt match"));
+        }
+        if let Some((seq, _)) = &last {
+            if entry.seq != seq + 1 {
+                return Err(broken(entry.seq, "Missing entries"));
+            }
+        }
+        if entry.compute_hash()? != entry.hash {
+            return Err(broken(entry.seq, "Entry was modified"));
+        }
+        last = Some((entry.seq, entry.hash));
+    }
+    Ok(last.map(|(_, hash)| hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    #[tokio::test]
+    async fn test_audit_chain() {
+        let path = std::env::temp_dir().join(format!("rhodium-audit-{}", std::process::id()));
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        let sink = RollingFileSink::open(&path)
+            .await
+            .unwrap()
+            .with_rotation(600, 1);
+        let audit = AuditLog::open(sink)
+            .await
+            .unwrap()
+            .with_fsync_interval(Duration::ZERO);
+        for user in ["alice", "bob", "carol"] {
+            audit
+                .emit(
+                    AuditEvent::new("auth_failure")
+                        .with_client("10.0.0.1:4000")
+                        .with_detail("user", user),
+                )
+                .await;
+        }
+        drop(audit);
+        // reopening continues the chain
+        let sink = RollingFileSink::open(&path).await.unwrap();
+        let audit = AuditLog::open(sink).await.unwrap();
+        audit.emit(AuditEvent::new("admin_action")).await;
+        audit.sync().await.unwrap();
+
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        let last = verify_chain(read(&rotated).as_bytes(), None).unwrap();
+        assert!(verify_chain(read(&path).as_bytes(), last.as_deref())
+            .unwrap()
+            .is_some());
+        let entries = read(&rotated).lines().count() + read(&path).lines().count();
+        assert_eq!(entries, 4);
+
+        let tampered = read(&rotated).replacen("alice", "mallory", 1);
+        assert!(verify_chain(tampered.as_bytes(), None).is_err());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Headers set by the executor on the final response of a stack, see RhodStack::with_auto_headers, so
+// handlers rewriting bodies dont have to keep them right by hand:
+// - Date, when the response has none
+// - Server, with the configured name, when the response has none
+// - Content-Length matching the body: set when its size is known, removed when it isnt (the body is then
+//   sent chunked in HTTP/1.1). Transfer-Encoding is removed, the connection sets it.
+//   Responses without a body (HEAD, 1xx, 204, 304) are left untouched.
+use crate::response::RhodResponse;
+use http::header::{HeaderValue, CONTENT_LENGTH, DATE, SERVER, TRANSFER_ENCODING};
+use http::StatusCode;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AutoHeaders {
+    date: bool,
+    server: Option<HeaderValue>,
+    content_length: bool,
+}
+
+impl AutoHeaders {
+    // Date and Content-Length
+    pub fn new() -> AutoHeaders {
+        AutoHeaders {
+            date: true,
+            server: None,
+            content_length: true,
+        }
+    }
+
+    // Invalid header values are ignored
+    pub fn with_server(self, server: &str) -> Self {
+        AutoHeaders {
+            server: HeaderValue::from_str(server).ok(),
+            ..self
+        }
+    }
+
+    pub fn without_date(self) -> Self {
+        AutoHeaders {
+            date: false,
+            ..self
+        }
+    }
+
+    pub fn without_content_length(self) -> Self {
+        AutoHeaders {
+            content_length: false,
+            ..self
+        }
+    }
+
+    // head tells if the response answers a HEAD request (its body is always empty)
+    pub(crate) fn apply(&self, res: &mut RhodResponse, head: bool) {
+        if self.date && !res.headers().contains_key(DATE) {
+            let date = httpdate::fmt_http_date(SystemTime::now());
+            if let Ok(date) = HeaderValue::from_str(&date) {
+                res.headers_mut().insert(DATE, date);
+            }
+        }
+        if let Some(server) = &self.server {
+            if !res.headers().contains_key(SERVER) {
+                res.headers_mut().insert(SERVER, server.clone());
+            }
+        }
+        let status = res.status_as_int();
+        let bodyless = head
+            || status < 200
+            || status == StatusCode::NO_CONTENT.as_u16()
+            || status == StatusCode::NOT_MODIFIED.as_u16();
+        if !self.content_length || bodyless {
+            return;
+        }
+        res.headers_mut().remove(TRANSFER_ENCODING);
+        match res.body_size_hint() {
+            Some(size) => {
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(size));
+            }
+            None => {
+                res.headers_mut().remove(CONTENT_LENGTH);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::{BoxError, RhodBody};
+
+    #[test]
+    fn test_auto_headers() {
+        let auto = AutoHeaders::new().with_server("rhodium");
+        let mut res = RhodResponse::builder()
+            .header("Content-Length", "2")
+            .header("Transfer-Encoding", "chunked")
+            .body_str("hello")
+            .build()
+            .unwrap();
+        auto.apply(&mut res, false);
+        assert_eq!(res.headers()[CONTENT_LENGTH], "5");
+        assert!(!res.headers().contains_key(TRANSFER_ENCODING));
+        assert!(res.headers().contains_key(DATE));
+        assert_eq!(res.headers()[SERVER], "rhodium");
+
+        let chunks = futures_util::stream::iter(vec![Ok::<_, BoxError>("a")]);
+        res.set_body(RhodBody::wrap_stream(chunks));
+        auto.apply(&mut res, false);
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+
+        // HEAD keeps the length of the body it would have
+        let mut res = RhodResponse::builder()
+            .header("Content-Length", "5")
+            .build()
+            .unwrap();
+        auto.apply(&mut res, true);
+        assert_eq!(res.headers()[CONTENT_LENGTH], "5");
+    }
+}
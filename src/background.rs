@@ -0,0 +1,144 @@
+// Jobs running next to the server (cache eviction, cert refresh, metrics flush, health checks).
+// They are spawned when the server starts running, and cancelled when it stops (the run future
+// completes or is dropped). A panicking job is logged as an error instead of taking the server down.
+use crate::errors::{RhodError, RhodErrorLevel};
+use crate::runtime::{self, Task, TaskHandle};
+use futures_util::future::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::time::Duration;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub enum BackgroundJob {
+    // Runs the future once
+    Once(BoxFuture),
+    // Runs the job every interval (the first run is right after the server starts).
+    // A run that panics doesnt stop the next ones
+    Every(Duration, Box<dyn Fn() -> BoxFuture + Send + Sync>),
+}
+
+impl BackgroundJob {
+    pub fn once<F>(future: F) -> BackgroundJob
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        BackgroundJob::Once(Box::pin(future))
+    }
+
+    pub fn every<J, F>(interval: Duration, job: J) -> BackgroundJob
+    where
+        J: Fn() -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        BackgroundJob::Every(interval, Box::new(move || Box::pin(job())))
+    }
+}
+
+// Runs the future, logging a panic as an error of the job
+async fn run_logged(name: &str, future: BoxFuture) {
+    if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+        RhodError::from_string(
+            format!(
+                "Background task {} panicked. {}",
+                name,
+                panic_message(&panic)
+            ),
+            RhodErrorLevel::Error,
+        )
+        .log();
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("unknown panic"),
+    }
+}
+
+// Handles of the spawned jobs. Dropping it cancels them
+pub(crate) struct BackgroundTasks {
+    tasks: Vec<TaskHandle>,
+}
+
+impl BackgroundTasks {
+    pub(crate) fn spawn(jobs: Vec<(String, BackgroundJob)>) -> BackgroundTasks {
+        let tasks = jobs
+            .into_iter()
+            .map(|(name, job)| {
+                debug!("Starting background task {}", name);
+                runtime::spawn(async move {
+                    match job {
+                        BackgroundJob::Once(future) => run_logged(&name, future).await,
+                        BackgroundJob::Every(interval, job) => loop {
+                            run_logged(&name, job()).await;
+                            runtime::sleep(interval).await;
+                        },
+                    }
+                })
+            })
+            .collect();
+        BackgroundTasks { tasks }
+    }
+}
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            Task::abort(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_background_tasks() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let tasks = BackgroundTasks::spawn(vec![
+            (
+                "panics".to_string(),
+                BackgroundJob::every(Duration::from_millis(10), move || {
+                    let counter = Arc::clone(&counter);
+                    async move {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        panic!("job failed");
+                    }
+                }),
+            ),
+            (
+                "idle".to_string(),
+                BackgroundJob::once(futures_util::future::pending()),
+            ),
+        ]);
+
+        // the job keeps running after panicking. Panics can be slow (ie: capturing a backtrace with
+        // RUST_BACKTRACE), so the runs are awaited instead of counted after a fixed time
+        let reruns = async {
+            while runs.load(Ordering::SeqCst) < 3 {
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(runtime::timeout(Duration::from_secs(30), reruns)
+            .await
+            .is_ok());
+
+        // and is cancelled with the server
+        drop(tasks);
+        runtime::sleep(Duration::from_millis(20)).await;
+        let stopped = runs.load(Ordering::SeqCst);
+        runtime::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), stopped);
+    }
+}
@@ -0,0 +1,422 @@
+// Body of RhodRequests and RhodResponses, and helpers to work with bodies without losing their trailers.
+// RhodBody boxes any http_body::Body, so the hyper version used to serve and send requests is not part of the API
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{Stream, TryStreamExt};
+use http::HeaderMap;
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use sync_wrapper::SyncStream;
+use tokio::sync::mpsc;
+
+mod buffer;
+pub use buffer::{BodyBuffer, BodyReader};
+mod file;
+pub use file::BodySource;
+mod scan;
+use scan::ScanBody;
+pub use scan::{BodyScanner, ScanAction, ScanVerdict};
+mod tee;
+use tee::TeeBody;
+pub use tee::TeePolicy;
+mod writer;
+pub use writer::BodyWriter;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Saved in the request context by stacks with body passthrough (RhodStack::with_body_passthrough):
+// the bodies must be streamed as they are, not read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyPassthrough;
+
+pub struct RhodBody {
+    inner: BoxBody<Bytes, BoxError>,
+}
+
+impl RhodBody {
+    pub fn new<B>(body: B) -> RhodBody
+    where
+        B: Body<Data = Bytes> + Send + Sync + 'static,
+        B::Error: Into<BoxError>,
+    {
+        RhodBody {
+            inner: body.map_err(Into::into).boxed(),
+        }
+    }
+
+    pub fn empty() -> RhodBody {
+        RhodBody::new(Empty::new())
+    }
+
+    // Streams the chunks of the stream as the body
+    pub fn wrap_stream<S, O, E>(stream: S) -> RhodBody
+    where
+        S: Stream<Item = Result<O, E>> + Send + 'static,
+        O: Into<Bytes> + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        let frames = stream.map_ok(|chunk| Frame::data(chunk.into()));
+        RhodBody::new(StreamBody::new(SyncStream::new(frames)))
+    }
+
+    // A body streamed by the returned sender
+    pub fn channel() -> (RhodBodySender, RhodBody) {
+        RhodBody::channel_with_capacity(1)
+    }
+
+    pub(crate) fn channel_with_capacity(capacity: usize) -> (RhodBodySender, RhodBody) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let aborted = Arc::new(AtomicBool::new(false));
+        let body = ChannelBody {
+            rx,
+            aborted: Arc::clone(&aborted),
+        };
+        (RhodBodySender { tx, aborted }, RhodBody::new(body))
+    }
+
+    // The body, and a copy of it taken as the body is read, buffering up to buffer chunks (see TeePolicy)
+    pub fn tee(self, buffer: usize, policy: TeePolicy) -> (RhodBody, RhodBody) {
+        let (tee, copy) = TeeBody::new(self, buffer, policy);
+        (RhodBody::new(tee), copy)
+    }
+
+    // The body released as the scanner finds it clean, see BodyScanner
+    pub fn scan(self, scanner: Box<dyn BodyScanner>, action: ScanAction) -> RhodBody {
+        RhodBody::new(ScanBody::new(self, scanner, action))
+    }
+
+    // Reads the whole body, dropping its trailers
+    pub async fn to_bytes(self) -> Result<Bytes, BoxError> {
+        Ok(self.collect().await?.to_bytes())
+    }
+}
+
+impl Body for RhodBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Default for RhodBody {
+    fn default() -> RhodBody {
+        RhodBody::empty()
+    }
+}
+
+impl fmt::Debug for RhodBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RhodBody")
+            .field("size_hint", &self.size_hint())
+            .finish()
+    }
+}
+
+impl From<Bytes> for RhodBody {
+    fn from(bytes: Bytes) -> RhodBody {
+        RhodBody::new(Full::new(bytes))
+    }
+}
+
+impl From<Vec<u8>> for RhodBody {
+    fn from(bytes: Vec<u8>) -> RhodBody {
+        RhodBody::from(Bytes::from(bytes))
+    }
+}
+
+impl From<String> for RhodBody {
+    fn from(s: String) -> RhodBody {
+        RhodBody::from(Bytes::from(s))
+    }
+}
+
+impl From<&'static str> for RhodBody {
+    fn from(s: &'static str) -> RhodBody {
+        RhodBody::from(Bytes::from_static(s.as_bytes()))
+    }
+}
+
+impl From<&'static [u8]> for RhodBody {
+    fn from(bytes: &'static [u8]) -> RhodBody {
+        RhodBody::from(Bytes::from_static(bytes))
+    }
+}
+
+// Sends the data and trailers of a channel body. Dropping it ends the body
+pub struct RhodBodySender {
+    tx: mpsc::Sender<Frame<Bytes>>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl RhodBodySender {
+    // Fails if the body was dropped
+    pub async fn send_data(&mut self, chunk: Bytes) -> Result<(), BoxError> {
+        self.send(Frame::data(chunk)).await
+    }
+
+    pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), BoxError> {
+        self.send(Frame::trailers(trailers)).await
+    }
+
+    // Ends the body with an error, so it is not taken as complete
+    pub fn abort(self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+
+    async fn send(&mut self, frame: Frame<Bytes>) -> Result<(), BoxError> {
+        self.tx
+            .send(frame)
+            .await
+            .map_err(|_| BoxError::from("body receiver dropped"))
+    }
+}
+
+struct ChannelBody {
+    rx: mpsc::Receiver<Frame<Bytes>>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(None) if self.aborted.load(Ordering::Acquire) => {
+                Poll::Ready(Some(Err(BoxError::from("body aborted"))))
+            }
+            Poll::Ready(frame) => Poll::Ready(frame.map(Ok)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Streams the body applying map to every chunk, and then its trailers merged with extra
+struct MapBody<F> {
+    inner: RhodBody,
+    map: Option<F>,
+    extra: Option<HeaderMap>,
+}
+
+impl<F> Body for MapBody<F>
+where
+    F: Fn(Bytes) -> Bytes + Unpin,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            Poll::Ready(None) => {
+                return Poll::Ready(this.extra.take().map(|t| Ok(Frame::trailers(t))))
+            }
+            other => return other,
+        };
+        let frame = match frame.into_trailers() {
+            Ok(mut trailers) => {
+                // extra values replace the body ones
+                if let Some(extra) = this.extra.take() {
+                    trailers.extend(extra);
+                }
+                Frame::trailers(trailers)
+            }
+            Err(frame) => match &this.map {
+                Some(map) => frame.map_data(map),
+                None => frame,
+            },
+        };
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.extra.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // mapped chunks may change their length
+        match self.map {
+            Some(_) => SizeHint::default(),
+            None => self.inner.size_hint(),
+        }
+    }
+}
+
+// Reads the whole body, and then its trailers
+pub(crate) async fn read_with_trailers(
+    body: RhodBody,
+) -> Result<(Bytes, Option<HeaderMap>), BoxError> {
+    let collected = body.collect().await?;
+    let trailers = collected.trailers().cloned();
+    Ok((collected.to_bytes(), trailers))
+}
+
+// Reads the body until its end, or until more than limit bytes are read. Returns the bytes read, and
+// the trailers if it ended or the rest of the body if it is longer than limit
+pub(crate) async fn read_limited(
+    mut body: RhodBody,
+    limit: usize,
+) -> Result<(Bytes, Option<HeaderMap>, Option<RhodBody>), BoxError> {
+    let mut data = BytesMut::new();
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(chunk) => {
+                data.extend_from_slice(&chunk);
+                if data.len() > limit {
+                    return Ok((data.freeze(), None, Some(body)));
+                }
+            }
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+    }
+    Ok((data.freeze(), trailers, None))
+}
+
+// Streams the bytes already read of a body, and then the rest of it
+pub(crate) fn prepend(read: Bytes, rest: RhodBody) -> RhodBody {
+    RhodBody::new(PrependBody {
+        read: Some(read).filter(|read| !read.is_empty()),
+        rest,
+    })
+}
+
+struct PrependBody {
+    read: Option<Bytes>,
+    rest: RhodBody,
+}
+
+impl Body for PrependBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match self.read.take() {
+            Some(read) => Poll::Ready(Some(Ok(Frame::data(read)))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.read.is_none() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let read = self.read.as_ref().map_or(0, |read| read.len() as u64);
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + read);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + read);
+        }
+        hint
+    }
+}
+
+// Streams the body applying map to every chunk, and then its trailers merged with extra
+// (extra values replace the body ones)
+pub(crate) fn map_body<F>(body: RhodBody, map: F, extra: HeaderMap) -> RhodBody
+where
+    F: Fn(Bytes) -> Bytes + Send + Sync + Unpin + 'static,
+{
+    RhodBody::new(MapBody {
+        inner: body,
+        map: Some(map),
+        extra: Some(extra).filter(|e| !e.is_empty()),
+    })
+}
+
+// Streams the body, and then its trailers merged with extra
+pub(crate) fn with_trailers(body: RhodBody, extra: HeaderMap) -> RhodBody {
+    RhodBody::new(MapBody {
+        inner: body,
+        map: None::<fn(Bytes) -> Bytes>,
+        extra: Some(extra).filter(|e| !e.is_empty()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    #[tokio::test]
+    async fn test_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers.insert("x-kept", HeaderValue::from_static("yes"));
+        let body = with_trailers(RhodBody::from("data"), trailers);
+
+        let mut extra = HeaderMap::new();
+        extra.insert("grpc-status", HeaderValue::from_static("13"));
+        let body = with_trailers(body, extra);
+
+        let (data, trailers) = read_with_trailers(body).await.unwrap();
+        let trailers = trailers.unwrap();
+        assert_eq!(data, Bytes::from("data"));
+        assert_eq!(trailers.get("grpc-status").unwrap(), "13");
+        assert_eq!(trailers.get("x-kept").unwrap(), "yes");
+    }
+
+    #[tokio::test]
+    async fn test_channel() {
+        let (mut sender, body) = RhodBody::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("da")).await.unwrap();
+            sender.send_data(Bytes::from("ta")).await.unwrap();
+        });
+        assert_eq!(body.to_bytes().await.unwrap(), Bytes::from("data"));
+
+        let (mut sender, body) = RhodBody::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("da")).await.unwrap();
+            sender.abort();
+        });
+        assert!(body.to_bytes().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_map_body() {
+        let body = RhodBody::wrap_stream(futures_util::stream::iter(vec![
+            Ok::<_, BoxError>("ab"),
+            Ok("cd"),
+        ]));
+        let body = map_body(
+            body,
+            |chunk| chunk.to_ascii_uppercase().into(),
+            HeaderMap::new(),
+        );
+        assert_eq!(body.size_hint().exact(), None);
+        let (data, trailers) = read_with_trailers(body).await.unwrap();
+        assert_eq!(data, Bytes::from("ABCD"));
+        assert!(trailers.is_none());
+    }
+}
@@ -0,0 +1,239 @@
+// Whole body kept in memory up to a threshold, and in a temporary file beyond it, so handlers that
+// need the complete body (scanners, WAF) can inspect big uploads without holding them in memory.
+// The file is removed when the buffer and every reader and body created from it are dropped.
+use super::{BoxError, RhodBody};
+use crate::util::hex;
+use bytes::{Bytes, BytesMut};
+use http::HeaderMap;
+use http_body_util::BodyExt;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+// Size of the chunks read from the file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct BodyBuffer {
+    content: Content,
+    len: u64,
+    trailers: Option<HeaderMap>,
+}
+
+enum Content {
+    Memory(Bytes),
+    File(Arc<TempPath>),
+}
+
+// Temporary file, removed on drop
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Couldnt remove {}. {}", self.0.display(), e);
+        }
+    }
+}
+
+impl BodyBuffer {
+    // Reads the whole body, spilling it to a file in the temporary dir when it is larger than threshold bytes
+    pub async fn collect(body: RhodBody, threshold: usize) -> Result<BodyBuffer, BoxError> {
+        BodyBuffer::collect_in(body, threshold, &std::env::temp_dir()).await
+    }
+
+    // Reads the whole body, spilling it to a file in dir when it is larger than threshold bytes
+    pub async fn collect_in(
+        mut body: RhodBody,
+        threshold: usize,
+        dir: &Path,
+    ) -> Result<BodyBuffer, BoxError> {
+        let mut memory = BytesMut::new();
+        let mut spilled: Option<(File, Arc<TempPath>)> = None;
+        let mut len = 0;
+        let mut trailers = None;
+        while let Some(frame) = body.frame().await {
+            let data = match frame?.into_data() {
+                Ok(data) => data,
+                Err(frame) => {
+                    trailers = frame.into_trailers().ok();
+                    continue;
+                }
+            };
+            len += data.len() as u64;
+            match spilled.as_mut() {
+                Some((file, _)) => file.write_all(&data).await?,
+                None if memory.len() + data.len() > threshold => {
+                    let (mut file, path) = spill_file(dir).await?;
+                    file.write_all(&memory).await?;
+                    file.write_all(&data).await?;
+                    memory = BytesMut::new();
+                    spilled = Some((file, path));
+                }
+                None => memory.extend_from_slice(&data),
+            }
+        }
+
+        let content = match spilled {
+            Some((mut file, path)) => {
+                file.flush().await?;
+                Content::File(path)
+            }
+            None => Content::Memory(memory.freeze()),
+        };
+        Ok(BodyBuffer {
+            content,
+            len,
+            trailers,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The body, if it wasnt spilled to a file
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match &self.content {
+            Content::Memory(bytes) => Some(bytes),
+            Content::File(_) => None,
+        }
+    }
+
+    // File holding the body, if it was spilled
+    pub fn path(&self) -> Option<&Path> {
+        match &self.content {
+            Content::Memory(_) => None,
+            Content::File(path) => Some(&path.0),
+        }
+    }
+
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
+
+    // Reads the body from the start
+    pub async fn reader(&self) -> io::Result<BodyReader> {
+        let inner = match &self.content {
+            Content::Memory(bytes) => Reader::Memory(Cursor::new(bytes.clone())),
+            Content::File(path) => Reader::File {
+                file: File::open(&path.0).await?,
+                _path: Arc::clone(path),
+            },
+        };
+        Ok(BodyReader { inner })
+    }
+
+    // Body streaming the buffered data (without the trailers)
+    pub fn to_body(&self) -> RhodBody {
+        let path = match &self.content {
+            Content::Memory(bytes) => return RhodBody::from(bytes.clone()),
+            Content::File(path) => Arc::clone(path),
+        };
+        let chunks = futures_util::stream::try_unfold(
+            (None, path),
+            |(reader, path): (Option<File>, Arc<TempPath>)| async move {
+                let mut file = match reader {
+                    Some(file) => file,
+                    None => File::open(&path.0).await?,
+                };
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let read = file.read(&mut chunk).await?;
+                if read == 0 {
+                    return Ok::<_, io::Error>(None);
+                }
+                chunk.truncate(read);
+                Ok(Some((Bytes::from(chunk), (Some(file), path))))
+            },
+        );
+        RhodBody::wrap_stream(chunks)
+    }
+}
+
+// New temporary file, with a random name (so other users of the directory cant guess it) readable only
+// by the process user
+async fn spill_file(dir: &Path) -> io::Result<(File, Arc<TempPath>)> {
+    let mut random = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut random)
+        .map_err(|_| io::Error::other("Cant name the temporary file"))?;
+    let path = dir.join(format!("rhodium-body-{}", hex(&random)));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options.open(&path).await?;
+    Ok((file, Arc::new(TempPath(path))))
+}
+
+// Async reader of a BodyBuffer. Keeps the file of the buffer while alive
+pub struct BodyReader {
+    inner: Reader,
+}
+
+enum Reader {
+    Memory(Cursor<Bytes>),
+    File { file: File, _path: Arc<TempPath> },
+}
+
+impl AsyncRead for BodyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Reader::Memory(cursor) => Pin::new(cursor).poll_read(cx, buf),
+            Reader::File { file, .. } => Pin::new(file).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_body_buffer() {
+        let small = BodyBuffer::collect(RhodBody::from("small"), 8)
+            .await
+            .unwrap();
+        assert_eq!(small.as_bytes().unwrap(), "small");
+
+        let chunks =
+            futures_util::stream::iter(vec![Ok::<_, BoxError>("0123"), Ok("4567"), Ok("89")]);
+        let large = BodyBuffer::collect(RhodBody::wrap_stream(chunks), 8)
+            .await
+            .unwrap();
+        assert_eq!(large.len(), 10);
+        assert!(large.as_bytes().is_none());
+        let path = large.path().unwrap().to_path_buf();
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut content = String::new();
+        let mut reader = large.reader().await.unwrap();
+        reader.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "0123456789");
+
+        // the file is kept while a body streams it
+        let body = large.to_body();
+        drop(large);
+        drop(reader);
+        assert_eq!(body.to_bytes().await.unwrap(), "0123456789");
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,139 @@
+// Body streamed from a file, read in chunks while it is sent instead of reading the whole file into
+// memory. The size is taken when the body is created: a file growing afterwards is cut there, and one
+// shrinking ends the body with an error.
+use super::{BoxError, RhodBody};
+use bytes::{Bytes, BytesMut};
+use http_body::{Body, Frame, SizeHint};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Content of a body, see RhodBody::from_source
+pub enum BodySource {
+    Bytes(Bytes),
+    File { file: File, chunk_size: usize },
+}
+
+impl From<Bytes> for BodySource {
+    fn from(bytes: Bytes) -> BodySource {
+        BodySource::Bytes(bytes)
+    }
+}
+
+impl From<File> for BodySource {
+    fn from(file: File) -> BodySource {
+        BodySource::File {
+            file,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+pub(crate) struct FileBody {
+    file: File,
+    left: u64,
+    chunk_size: usize,
+}
+
+impl FileBody {
+    // Sends from the current position of the file to its end
+    pub(crate) async fn new(mut file: File, chunk_size: usize) -> io::Result<FileBody> {
+        let len = file.metadata().await?.len();
+        let position = file.stream_position().await?;
+        Ok(FileBody {
+            file,
+            left: len.saturating_sub(position),
+            chunk_size: chunk_size.max(1),
+        })
+    }
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if self.left == 0 {
+            return Poll::Ready(None);
+        }
+        let size = self.left.min(self.chunk_size as u64) as usize;
+        let mut chunk = BytesMut::zeroed(size);
+        let mut buf = ReadBuf::new(&mut chunk);
+        let read =
+            ready!(Pin::new(&mut self.file).poll_read(cx, &mut buf)).map(|()| buf.filled().len());
+        match read {
+            Ok(0) => Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File shorter than its size",
+            )
+            .into()))),
+            Ok(read) => {
+                chunk.truncate(read);
+                self.left -= read as u64;
+                Poll::Ready(Some(Ok(Frame::data(chunk.freeze()))))
+            }
+            Err(e) => Poll::Ready(Some(Err(e.into()))),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.left == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.left)
+    }
+}
+
+impl RhodBody {
+    // Body of the file from its current position, read in chunks of 64 KiB
+    pub async fn from_file(file: File) -> io::Result<RhodBody> {
+        RhodBody::from_source(BodySource::from(file)).await
+    }
+
+    pub async fn from_source(source: BodySource) -> io::Result<RhodBody> {
+        match source {
+            BodySource::Bytes(bytes) => Ok(RhodBody::from(bytes)),
+            BodySource::File { file, chunk_size } => {
+                Ok(RhodBody::new(FileBody::new(file, chunk_size).await?))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_file_body() {
+        let path = std::env::temp_dir().join(format!("rhodium-file-body-{}", fastrand::u64(..)));
+        let mut file = File::create(&path).await.unwrap();
+        file.write_all(b"0123456789").await.unwrap();
+        drop(file);
+
+        let mut file = File::open(&path).await.unwrap();
+        file.seek(io::SeekFrom::Start(2)).await.unwrap();
+        let source = BodySource::File {
+            file,
+            chunk_size: 3,
+        };
+        let mut body = RhodBody::from_source(source).await.unwrap();
+        assert_eq!(body.size_hint().exact(), Some(8));
+        let mut chunks = vec![];
+        while let Some(frame) = body.frame().await {
+            chunks.push(frame.unwrap().into_data().unwrap());
+        }
+        assert_eq!(chunks, vec!["234", "567", "89"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+// Bodies inspected by a BodyScanner (antivirus, DLP) as they are streamed. Chunks are released once
+// the scanner says the content fed so far is clean, and held while it needs more; a blocked body is
+// aborted, replaced or only logged (ScanAction). Scanners holding their verdict hold the chunks in
+// memory, so they should decide as soon as they can.
+use super::{BoxError, RhodBody};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use sync_wrapper::SyncWrapper;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,           // the content fed so far is clean, its chunks are released
+    NeedsMore,       // cant decide yet, its chunks are held
+    Blocked(String), // with the reason
+}
+
+// What a blocked body becomes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanAction {
+    Abort,          // ends with an error, so it is not taken as complete
+    Replace(Bytes), // the body is replaced, or aborted if some chunks were already released
+    Log,            // only logged, the body is released and no longer scanned
+}
+
+// Scan of one body
+#[async_trait]
+pub trait BodyScanner: Send + Sync {
+    async fn feed(&mut self, chunk: &Bytes) -> ScanVerdict;
+
+    // End of the body, a body still needing more is taken as clean
+    async fn finish(&mut self) -> ScanVerdict {
+        ScanVerdict::Clean
+    }
+}
+
+type Scanning =
+    SyncWrapper<Pin<Box<dyn Future<Output = (Box<dyn BodyScanner>, ScanVerdict)> + Send>>>;
+
+pub(crate) struct ScanBody {
+    inner: RhodBody,
+    action: ScanAction,
+    scanner: Option<Box<dyn BodyScanner>>, // None while scanning or once no longer scanned
+    scanning: Option<Scanning>,
+    finishing: bool,
+    held: VecDeque<Frame<Bytes>>,
+    ready: VecDeque<Frame<Bytes>>,
+    released: bool, // whether some data was released
+    error: Option<BoxError>,
+    done: bool,
+}
+
+impl ScanBody {
+    pub(crate) fn new(
+        body: RhodBody,
+        scanner: Box<dyn BodyScanner>,
+        action: ScanAction,
+    ) -> ScanBody {
+        ScanBody {
+            inner: body,
+            action,
+            scanner: Some(scanner),
+            scanning: None,
+            finishing: false,
+            held: VecDeque::new(),
+            ready: VecDeque::new(),
+            released: false,
+            error: None,
+            done: false,
+        }
+    }
+
+    fn release(&mut self) {
+        self.released |= self.held.iter().any(Frame::is_data);
+        self.ready.append(&mut self.held);
+    }
+
+    fn apply(&mut self, verdict: ScanVerdict) {
+        match verdict {
+            ScanVerdict::NeedsMore if !self.finishing => {}
+            ScanVerdict::Clean | ScanVerdict::NeedsMore => self.release(),
+            ScanVerdict::Blocked(reason) => {
+                warn!("Body blocked by its scanner. {}", reason);
+                match &self.action {
+                    ScanAction::Log => {
+                        self.scanner = None;
+                        self.release();
+                        return;
+                    }
+                    ScanAction::Replace(replacement) if !self.released => {
+                        self.ready.push_back(Frame::data(replacement.clone()));
+                    }
+                    _ => {
+                        self.error = Some(BoxError::from(format!("body blocked. {}", reason)));
+                    }
+                }
+                self.scanner = None;
+                self.held.clear();
+                self.done = true;
+                return;
+            }
+        }
+        if self.finishing {
+            self.scanner = None;
+            self.done = true;
+        }
+    }
+}
+
+impl Body for ScanBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        loop {
+            if let Some(frame) = this.ready.pop_front() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            if let Some(e) = this.error.take() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            if let Some(scanning) = &mut this.scanning {
+                let (scanner, verdict) = ready!(scanning.get_mut().as_mut().poll(cx));
+                this.scanning = None;
+                this.scanner = Some(scanner);
+                this.apply(verdict);
+                continue;
+            }
+
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    match this.scanner.take() {
+                        Some(mut scanner) => {
+                            this.finishing = true;
+                            this.scanning = Some(SyncWrapper::new(Box::pin(async move {
+                                let verdict = scanner.finish().await;
+                                (scanner, verdict)
+                            })));
+                        }
+                        None => this.done = true,
+                    }
+                    continue;
+                }
+            };
+            let mut scanner = match this.scanner.take() {
+                Some(scanner) => scanner,
+                None => return Poll::Ready(Some(Ok(frame))),
+            };
+            let chunk = frame.data_ref().cloned();
+            this.held.push_back(frame);
+            match chunk {
+                Some(chunk) => {
+                    this.scanning = Some(SyncWrapper::new(Box::pin(async move {
+                        let verdict = scanner.feed(&chunk).await;
+                        (scanner, verdict)
+                    })))
+                }
+                // trailers, held until the final verdict
+                None => this.scanner = Some(scanner),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done && self.ready.is_empty() && self.error.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.action {
+            ScanAction::Replace(_) => SizeHint::default(),
+            _ => self.inner.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    // Blocks bodies containing the word, which may be split between chunks
+    struct WordScanner {
+        word: &'static str,
+        tail: Vec<u8>,
+    }
+
+    #[async_trait]
+    impl BodyScanner for WordScanner {
+        async fn feed(&mut self, chunk: &Bytes) -> ScanVerdict {
+            self.tail.extend_from_slice(chunk);
+            if self
+                .tail
+                .windows(self.word.len())
+                .any(|w| w == self.word.as_bytes())
+            {
+                return ScanVerdict::Blocked(format!("found {}", self.word));
+            }
+            // the end of the content could start the word
+            let len = self.tail.len();
+            let partial = (1..self.word.len().min(len + 1))
+                .rev()
+                .find(|n| self.word.as_bytes().starts_with(&self.tail[len - n..]));
+            match partial {
+                Some(n) => {
+                    self.tail.drain(..len - n);
+                    ScanVerdict::NeedsMore
+                }
+                None => {
+                    self.tail.clear();
+                    ScanVerdict::Clean
+                }
+            }
+        }
+    }
+
+    fn scanned(chunks: Vec<&'static str>, action: ScanAction) -> RhodBody {
+        let body = RhodBody::wrap_stream(stream::iter(chunks.into_iter().map(Ok::<_, BoxError>)));
+        let scanner = WordScanner {
+            word: "virus",
+            tail: vec![],
+        };
+        body.scan(Box::new(scanner), action)
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let clean = scanned(vec!["hello ", "world"], ScanAction::Abort);
+        assert_eq!(clean.to_bytes().await.unwrap(), Bytes::from("hello world"));
+
+        let blocked = scanned(vec!["a vi", "rus here"], ScanAction::Abort);
+        assert!(blocked.to_bytes().await.is_err());
+
+        // the held chunks are replaced
+        let replaced = scanned(
+            vec!["v", "irus"],
+            ScanAction::Replace(Bytes::from("removed")),
+        );
+        assert_eq!(replaced.to_bytes().await.unwrap(), Bytes::from("removed"));
+        // unless some were already released
+        let released = scanned(
+            vec!["clean ", "virus"],
+            ScanAction::Replace(Bytes::from("removed")),
+        );
+        assert!(released.to_bytes().await.is_err());
+
+        let logged = scanned(vec!["a virus", " and more"], ScanAction::Log);
+        assert_eq!(
+            logged.to_bytes().await.unwrap(),
+            Bytes::from("a virus and more")
+        );
+    }
+}
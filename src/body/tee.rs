@@ -0,0 +1,192 @@
+// Copy of a body for an async consumer (DLP scanners, analytics) taken while the body is streamed to
+// its destination. The copy buffers up to a number of chunks; when its consumer falls behind, the
+// TeePolicy decides whether the copy is given up (it ends with an error, so it is not taken as
+// complete) or the original waits for it. A copy whose consumer is gone never holds the original.
+use super::{BoxError, RhodBody, RhodBodySender};
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::OwnedPermit;
+
+type Reserve =
+    Pin<Box<dyn Future<Output = Result<OwnedPermit<Frame<Bytes>>, SendError<()>>> + Send + Sync>>;
+
+// What the original body does when the copy buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeePolicy {
+    Drop,         // gives up the copy, the original is never delayed
+    Backpressure, // waits for the consumer, slowing down the original
+}
+
+pub(crate) struct TeeBody {
+    inner: RhodBody,
+    copy: Option<RhodBodySender>, // None once the copy ended or was given up
+    policy: TeePolicy,
+    pending: Option<Frame<Bytes>>, // frame waiting for room in the copy
+    reserving: Option<Reserve>,
+    ended: bool,
+}
+
+impl TeeBody {
+    pub(crate) fn new(body: RhodBody, buffer: usize, policy: TeePolicy) -> (TeeBody, RhodBody) {
+        let (copy, body_copy) = RhodBody::channel_with_capacity(buffer.max(1));
+        let tee = TeeBody {
+            inner: body,
+            copy: Some(copy),
+            policy,
+            pending: None,
+            reserving: None,
+            ended: false,
+        };
+        (tee, body_copy)
+    }
+
+    fn give_up(&mut self) {
+        self.reserving = None;
+        if let Some(copy) = self.copy.take() {
+            copy.abort();
+        }
+    }
+
+    // Waits for room in the copy and sends the pending frame, or drops the copy if its consumer is gone
+    fn poll_copy(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let copy = match &self.copy {
+            Some(copy) => copy,
+            None => return Poll::Ready(()),
+        };
+        let reserving = self
+            .reserving
+            .get_or_insert_with(|| Box::pin(copy.tx.clone().reserve_owned()));
+        let reserved = ready!(reserving.as_mut().poll(cx));
+        self.reserving = None;
+        match (reserved, self.pending.as_ref().and_then(copy_frame)) {
+            (Ok(permit), Some(frame)) => {
+                permit.send(frame);
+            }
+            (Ok(_), None) => {}
+            (Err(_), _) => self.copy = None,
+        }
+        Poll::Ready(())
+    }
+}
+
+fn copy_frame(frame: &Frame<Bytes>) -> Option<Frame<Bytes>> {
+    if let Some(data) = frame.data_ref() {
+        Some(Frame::data(data.clone()))
+    } else {
+        frame.trailers_ref().map(|t| Frame::trailers(t.clone()))
+    }
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        if this.pending.is_none() {
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match (this.policy, &this.copy) {
+                    (_, None) => return Poll::Ready(Some(Ok(frame))),
+                    (TeePolicy::Drop, Some(copy)) => {
+                        let copied = match copy_frame(&frame) {
+                            Some(copied) => copy.tx.try_send(copied),
+                            None => Ok(()),
+                        };
+                        match copied {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(_)) => {
+                                debug!("Tee consumer too slow, giving up its copy");
+                                this.give_up();
+                            }
+                            Err(TrySendError::Closed(_)) => this.copy = None,
+                        }
+                        return Poll::Ready(Some(Ok(frame)));
+                    }
+                    (TeePolicy::Backpressure, Some(_)) => this.pending = Some(frame),
+                },
+                Some(Err(e)) => {
+                    this.give_up();
+                    return Poll::Ready(Some(Err(e)));
+                }
+                None => {
+                    // dropping the sender ends the copy
+                    this.ended = true;
+                    this.copy = None;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+        ready!(this.poll_copy(cx));
+        Poll::Ready(this.pending.take().map(Ok))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        // a body dropped before its end (ie: the client went away) leaves an incomplete copy
+        if !self.ended && !self.is_end_stream() {
+            self.give_up();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use http::HeaderMap;
+    use http_body_util::BodyExt;
+
+    fn chunks() -> RhodBody {
+        RhodBody::wrap_stream(stream::iter(vec![Ok::<_, BoxError>("a"), Ok("b"), Ok("c")]))
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        // the copy gets the data and trailers
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+        let body = crate::body::with_trailers(chunks(), trailers);
+        let (body, copy) = body.tee(8, TeePolicy::Drop);
+        let consumer = tokio::spawn(crate::body::read_with_trailers(copy));
+        assert_eq!(body.to_bytes().await.unwrap(), Bytes::from("abc"));
+        let (data, trailers) = consumer.await.unwrap().unwrap();
+        assert_eq!(data, Bytes::from("abc"));
+        assert_eq!(trailers.unwrap()["x-checksum"], "abc");
+
+        // a slow consumer loses the copy, the original isnt delayed
+        let (body, copy) = chunks().tee(1, TeePolicy::Drop);
+        assert_eq!(body.to_bytes().await.unwrap(), Bytes::from("abc"));
+        assert!(copy.to_bytes().await.is_err());
+
+        // or slows down the original
+        let (body, mut copy) = chunks().tee(1, TeePolicy::Backpressure);
+        let original = tokio::spawn(body.to_bytes());
+        let mut read = vec![];
+        while let Some(frame) = copy.frame().await {
+            read.extend_from_slice(&frame.unwrap().into_data().unwrap());
+        }
+        assert_eq!(read, b"abc");
+        assert_eq!(original.await.unwrap().unwrap(), Bytes::from("abc"));
+
+        // without consumer the original goes on
+        let (body, copy) = chunks().tee(1, TeePolicy::Backpressure);
+        drop(copy);
+        assert_eq!(body.to_bytes().await.unwrap(), Bytes::from("abc"));
+    }
+}
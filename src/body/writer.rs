@@ -0,0 +1,155 @@
+// Body written through AsyncWrite, for services generating their output incrementally (templates,
+// CSV exports). Writes are buffered up to the buffer size and then sent as a chunk; flush sends what
+// is buffered right away, waiting for the client to take the previous chunks. shutdown ends the body,
+// dropping the writer before it ends the body with an error, so it is not taken as complete.
+use super::{RhodBody, RhodBodySender};
+use bytes::{Bytes, BytesMut};
+use http_body::Frame;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::OwnedPermit;
+
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+type Reserve =
+    Pin<Box<dyn Future<Output = Result<OwnedPermit<Frame<Bytes>>, SendError<()>>> + Send + Sync>>;
+
+pub struct BodyWriter {
+    sender: Option<RhodBodySender>, // None once shut down
+    buffer: BytesMut,
+    buffer_size: usize,
+    reserving: Option<Reserve>, // room in the channel for the next chunk
+}
+
+impl BodyWriter {
+    // The writer and the body it writes
+    pub fn new() -> (BodyWriter, RhodBody) {
+        let (sender, body) = RhodBody::channel();
+        let writer = BodyWriter {
+            sender: Some(sender),
+            buffer: BytesMut::new(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            reserving: None,
+        };
+        (writer, body)
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    // Sends the buffered data as a chunk
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+        let reserving = self
+            .reserving
+            .get_or_insert_with(|| Box::pin(sender.tx.clone().reserve_owned()));
+        let reserved = ready!(reserving.as_mut().poll(cx));
+        self.reserving = None;
+        match reserved {
+            Ok(permit) => {
+                permit.send(Frame::data(self.buffer.split().freeze()));
+                Poll::Ready(Ok(()))
+            }
+            Err(_) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "body receiver dropped",
+            ))),
+        }
+    }
+}
+
+impl AsyncWrite for BodyWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if self.buffer.len() + len > self.buffer_size {
+            ready!(self.poll_send(cx))?;
+        }
+        if self.sender.is_none() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        for buf in bufs {
+            self.buffer.extend_from_slice(buf);
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_send(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send(cx))?;
+        // dropping the sender ends the body
+        self.sender = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for BodyWriter {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            sender.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_writer() {
+        let (writer, mut body) = BodyWriter::new();
+        let mut writer = writer.with_buffer_size(8);
+        tokio::spawn(async move {
+            writer.write_all(b"id,name\n").await.unwrap();
+            // the buffer is full, so it is sent before writing more
+            let row = [IoSlice::new(b"1,"), IoSlice::new(b"a\n")];
+            assert_eq!(writer.write_vectored(&row).await.unwrap(), 4);
+            writer.flush().await.unwrap();
+            writer.write_all(b"2,b\n").await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        let mut chunks = vec![];
+        while let Some(frame) = body.frame().await {
+            chunks.push(frame.unwrap().into_data().unwrap());
+        }
+        assert_eq!(chunks, vec!["id,name\n", "1,a\n", "2,b\n"]);
+
+        // not shut down
+        let (mut writer, body) = BodyWriter::new();
+        writer.write_all(b"partial").await.unwrap();
+        drop(writer);
+        assert!(body.to_bytes().await.is_err());
+    }
+}
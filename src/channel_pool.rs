@@ -0,0 +1,165 @@
+// Pool of communication channels, see RhodStack::with_channel_pool. Channels holding buffers are costly
+// to create on every request: with a pool, the channel of a finished request is reset
+// (CommunicationChannel::reset) and kept for the next one, which gets it instead of calling try_new.
+// Channels that cant be reset, or exceeding max_idle, are dropped.
+use crate::errors::RhodResult;
+use crate::request::RhodRequest;
+use crate::{CommunicationChannel, RhodConnInfo};
+use serde::Serialize;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct ChannelPoolStats {
+    pub created: u64,
+    pub reused: u64,
+    pub idle: u64, // waiting for a request now
+}
+
+#[derive(Debug)]
+pub struct ChannelPool<C> {
+    idle: Mutex<Vec<C>>,
+    max_idle: usize,
+    created: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl<C> ChannelPool<C> {
+    pub fn new(max_idle: usize) -> ChannelPool<C> {
+        ChannelPool {
+            idle: Mutex::new(Vec::with_capacity(max_idle)),
+            max_idle,
+            created: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> ChannelPoolStats {
+        ChannelPoolStats {
+            created: self.created.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            idle: self.idle.lock().unwrap().len() as u64,
+        }
+    }
+}
+
+impl<C: CommunicationChannel> ChannelPool<C> {
+    // An idle channel, or a new one
+    pub(crate) async fn checkout(&self, conn: &RhodConnInfo, req: &RhodRequest) -> RhodResult<C> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(channel) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                Ok(channel)
+            }
+            None => {
+                self.created.fetch_add(1, Ordering::Relaxed);
+                C::try_new(conn, req).await
+            }
+        }
+    }
+
+    fn checkin(&self, mut channel: C) {
+        if !channel.reset() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(channel);
+        }
+    }
+}
+
+// Channel of a request, given back to the pool (if any) when dropped
+pub(crate) struct PooledChannel<'a, C: CommunicationChannel> {
+    channel: Option<C>,
+    pool: Option<&'a ChannelPool<C>>,
+}
+
+impl<'a, C: CommunicationChannel> PooledChannel<'a, C> {
+    pub(crate) fn new(channel: C, pool: Option<&'a ChannelPool<C>>) -> PooledChannel<'a, C> {
+        PooledChannel {
+            channel: Some(channel),
+            pool,
+        }
+    }
+}
+
+impl<C: CommunicationChannel> Deref for PooledChannel<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.channel.as_ref().unwrap()
+    }
+}
+
+impl<C: CommunicationChannel> DerefMut for PooledChannel<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.channel.as_mut().unwrap()
+    }
+}
+
+impl<C: CommunicationChannel> Drop for PooledChannel<'_, C> {
+    fn drop(&mut self) {
+        if let (Some(pool), Some(channel)) = (self.pool, self.channel.take()) {
+            pool.checkin(channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::response::RhodResponse;
+    use crate::stack::{RhodService, RhodStack};
+    use async_trait::async_trait;
+
+    // Keeps a buffer between requests
+    struct Buffered {
+        buffer: Vec<u8>,
+    }
+    impl CommunicationChannel for Buffered {
+        fn new() -> Buffered {
+            Buffered {
+                buffer: Vec::with_capacity(1024),
+            }
+        }
+
+        fn reset(&mut self) -> bool {
+            self.buffer.clear();
+            true
+        }
+    }
+
+    // Answers with the length of the buffer before writing the request path to it
+    struct Service;
+    #[async_trait]
+    impl RhodService<Buffered> for Service {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            comm: &mut Buffered,
+        ) -> RhodResult<RhodResponse> {
+            let used = comm.buffer.len();
+            comm.buffer.extend_from_slice(req.uri().path().as_bytes());
+            RhodResponse::builder().body_str(&used.to_string()).build()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_pool() {
+        let stack = RhodStack::new(vec![], Box::new(Service)).with_channel_pool(4);
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        for _ in 0..3 {
+            let req = RhodRequest::builder().uri("/path").build().unwrap();
+            let mut res = stack.execute(&conn, req).await.unwrap();
+            // reset between requests
+            assert_eq!(res.body().await.unwrap(), b"0");
+        }
+        let stats = stack.channel_pool().unwrap().stats();
+        assert_eq!((stats.created, stats.reused, stats.idle), (1, 2, 1));
+    }
+}
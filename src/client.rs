@@ -0,0 +1,676 @@
+// Shared HTTP client to call upstreams from services and handlers.
+// It keeps a pool of connections, speaks TLS with https uris (Mozilla roots by default)
+// and converts RhodRequests/RhodResponses, so there is no need to build hyper clients by hand.
+mod balancer;
+mod circuit_breaker;
+mod connector;
+mod resolver;
+mod retry;
+pub use balancer::{Affinity, Balancer, Selection};
+pub use circuit_breaker::{CircuitBreaker, CircuitBreakerConf, CircuitState};
+use connector::{ConnectionOpened, PoolCounters};
+pub use connector::{MaybeTlsStream, PoolStats, PooledStream, RhodConnector};
+pub use resolver::{CachingResolver, Resolve, RhodResolver, StaticResolver, SystemResolver};
+pub use retry::RetryPolicy;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::Request as HyperRequest;
+use http::Response as HyperResponse;
+use http::{HeaderMap, Method, StatusCode, Uri, Version};
+use http_body::Body as _;
+use hyper::body::Incoming;
+use hyper_util::client::legacy::connect::{capture_connection, HttpConnector};
+use hyper_util::client::legacy::{Client, Error as ClientError};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::CertificateDer;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::body::{BodyPassthrough, RhodBody};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::informational::Informational;
+use crate::limits::{grpc_timeout, Deadline};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+
+#[derive(Clone)]
+pub struct RhodClient {
+    client: Client<RhodConnector, RhodBody>,
+    timeout: Option<Duration>, // until the response headers are received
+    retry: RetryPolicy,
+    breaker: Option<Arc<CircuitBreaker>>,
+    max_lifetime: Option<Duration>,
+    counters: Arc<PoolCounters>,
+    propagate_deadline: bool,
+    relay_informational: bool,
+}
+
+impl RhodClient {
+    pub fn new() -> RhodClient {
+        RhodClient::builder().build()
+    }
+
+    pub fn builder() -> RhodClientBuilder {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        RhodClientBuilder {
+            timeout: None,
+            connect_timeout: None,
+            retry: RetryPolicy::default(),
+            breaker: None,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: usize::MAX,
+            pool_max_lifetime: None,
+            http2_only: false,
+            http1_only: false,
+            resolver: Arc::new(SystemResolver),
+            propagate_deadline: false,
+            relay_informational: false,
+            roots,
+        }
+    }
+
+    // Connections opened, open and retired, and requests sent
+    pub fn pool_stats(&self) -> PoolStats {
+        self.counters.stats()
+    }
+
+    // Circuit breaker of the upstream hosts, if enabled
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_deref()
+    }
+
+    // Sends the request to its uri (must be absolute), retrying it following the RetryPolicy.
+    // Errors carry a 502/503/504 response, so services can return them as they are.
+    pub async fn send(&self, req: RhodRequest) -> RhodResult<RhodResponse> {
+        let host = req
+            .uri()
+            .authority()
+            .map(|authority| authority.to_string())
+            .unwrap_or_default();
+        let deadline = req.context().get::<Deadline>();
+        if deadline.is_some_and(|deadline| deadline.is_exhausted()) {
+            return Err(SendError::Timeout.into());
+        }
+        let passthrough = req.context().get::<BodyPassthrough>().is_some();
+        let informational = match self.relay_informational {
+            true => req.context().get::<Informational>(),
+            false => None,
+        };
+        let (mut next, replay) = self
+            .replayable(req.into_hyper_request(), passthrough)
+            .await?;
+
+        let mut attempt = 0;
+        loop {
+            let permit = match &self.breaker {
+                Some(breaker) => match breaker.acquire(&host) {
+                    Some(permit) => Some(permit),
+                    None => return Err(circuit_open(&host)),
+                },
+                None => None,
+            };
+
+            let result = self.send_once(next, deadline, informational.clone()).await;
+            if let Some(permit) = permit {
+                permit.record(match &result {
+                    Ok(res) => res.status().is_server_error(),
+                    Err(_) => true,
+                });
+            }
+
+            let retry = match &result {
+                Ok(res) => self.retry.retry_statuses.contains(&res.status()),
+                Err(_) => true,
+            };
+            let backoff = self.retry.backoff(attempt);
+            // no retry that would start after the deadline
+            let in_time = deadline.is_none_or(|deadline| deadline.remaining() > backoff);
+            match &replay {
+                Some(replay) if retry && in_time && attempt < self.retry.max_retries => {
+                    runtime::sleep(backoff).await;
+                    attempt += 1;
+                    next = replay.request();
+                }
+                _ => {
+                    return result
+                        .map(|res| RhodResponse::new(res.map(RhodBody::new)))
+                        .map_err(Into::into)
+                }
+            }
+        }
+    }
+
+    // Keeps what is needed to send the request again, if it can be retried. With body passthrough only
+    // requests without body are kept
+    async fn replayable(
+        &self,
+        req: HyperRequest<RhodBody>,
+        passthrough: bool,
+    ) -> RhodResult<(HyperRequest<RhodBody>, Option<Replay>)> {
+        if !self.retry.retries_method(req.method()) {
+            return Ok((req, None));
+        }
+        let max_replay_body = match passthrough {
+            true => 0,
+            false => self.retry.max_replay_body as u64,
+        };
+        match req.body().size_hint().exact() {
+            Some(size) if size <= max_replay_body => {
+                let (parts, body) = req.into_parts();
+                let body = body.to_bytes().await.map_err(|e| {
+                    RhodError::from_string(
+                        format!("Cant read request body. {}", e),
+                        RhodErrorLevel::Error,
+                    )
+                })?;
+                let replay = Replay {
+                    method: parts.method,
+                    uri: parts.uri,
+                    version: parts.version,
+                    headers: parts.headers,
+                    body,
+                };
+                Ok((replay.request(), Some(replay)))
+            }
+            _ => Ok((req, None)),
+        }
+    }
+
+    // The call is cancelled at the timeout of the client or the deadline of the request, the earliest
+    async fn send_once(
+        &self,
+        mut req: HyperRequest<RhodBody>,
+        deadline: Option<Deadline>,
+        informational: Option<Informational>,
+    ) -> Result<HyperResponse<Incoming>, SendError> {
+        let remaining = deadline.map(|deadline| deadline.remaining());
+        if let (Some(remaining), true) = (remaining, self.propagate_deadline) {
+            set_timeout_headers(&mut req, remaining);
+        }
+        let timeout = match (self.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        if let Some(informational) = informational {
+            // only HTTP/1 upstreams, hyper doesnt report them on HTTP/2
+            hyper::ext::on_informational(&mut req, move |res| {
+                informational.send(res.status(), res.headers());
+            });
+        }
+        let connection = capture_connection(&mut req);
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+        let sending = self.client.request(req);
+        let result = match timeout {
+            Some(timeout) => match runtime::timeout(timeout, sending).await {
+                Ok(result) => result.map_err(SendError::Hyper),
+                Err(_) => Err(SendError::Timeout),
+            },
+            None => sending.await.map_err(SendError::Hyper),
+        };
+        // connections past their lifetime are closed once idle instead of going back to the pool
+        if let (Ok(res), Some(max_lifetime)) = (&result, self.max_lifetime) {
+            let expired = res
+                .extensions()
+                .get::<ConnectionOpened>()
+                .is_some_and(|opened| opened.0.elapsed() >= max_lifetime);
+            if expired {
+                if let Some(connected) = connection.connection_metadata().as_ref() {
+                    connected.poison();
+                    self.counters.retired.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        result
+    }
+}
+
+// Remaining time for the upstream: grpc-timeout for gRPC calls, X-Request-Timeout (milliseconds) otherwise
+fn set_timeout_headers(req: &mut HyperRequest<RhodBody>, remaining: Duration) {
+    let grpc = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/grpc"));
+    let (name, value) = if grpc {
+        ("grpc-timeout", grpc_timeout(remaining))
+    } else {
+        ("x-request-timeout", remaining.as_millis().to_string())
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        req.headers_mut().insert(name, value);
+    }
+}
+
+impl Default for RhodClient {
+    fn default() -> RhodClient {
+        RhodClient::new()
+    }
+}
+
+enum SendError {
+    Hyper(ClientError),
+    Timeout,
+}
+
+impl From<SendError> for RhodError {
+    fn from(e: SendError) -> RhodError {
+        let (status, msg) = match e {
+            SendError::Hyper(e) => (StatusCode::BAD_GATEWAY, format!("Upstream error. {}", e)),
+            SendError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "Upstream timeout".to_string()),
+        };
+        RhodError::with_status(status, msg, RhodErrorLevel::Error)
+    }
+}
+
+fn circuit_open(host: &str) -> RhodError {
+    RhodError::with_status(
+        StatusCode::SERVICE_UNAVAILABLE,
+        format!("Circuit open for {}", host),
+        RhodErrorLevel::Warning,
+    )
+}
+
+// What is needed to send a request again
+struct Replay {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Replay {
+    fn request(&self) -> HyperRequest<RhodBody> {
+        let mut req = HyperRequest::new(RhodBody::from(self.body.clone()));
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        req
+    }
+}
+
+// Builds a RhodClient setting timeouts, retries, pool and TLS options
+pub struct RhodClientBuilder {
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    breaker: Option<CircuitBreakerConf>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: usize,
+    pool_max_lifetime: Option<Duration>,
+    http2_only: bool,
+    http1_only: bool,
+    resolver: Arc<dyn Resolve>,
+    propagate_deadline: bool,
+    relay_informational: bool,
+    roots: RootCertStore,
+}
+
+impl RhodClientBuilder {
+    pub fn timeout(self, timeout: Duration) -> RhodClientBuilder {
+        RhodClientBuilder {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn connect_timeout(self, timeout: Duration) -> RhodClientBuilder {
+        RhodClientBuilder {
+            connect_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    // Retries idempotent requests with the default RetryPolicy
+    pub fn retries(self, retries: u32) -> RhodClientBuilder {
+        self.retry_policy(RetryPolicy::new(retries))
+    }
+
+    pub fn retry_policy(self, retry: RetryPolicy) -> RhodClientBuilder {
+        RhodClientBuilder { retry, ..self }
+    }
+
+    pub fn circuit_breaker(self, conf: CircuitBreakerConf) -> RhodClientBuilder {
+        RhodClientBuilder {
+            breaker: Some(conf),
+            ..self
+        }
+    }
+
+    pub fn pool_idle_timeout(self, timeout: Duration) -> RhodClientBuilder {
+        RhodClientBuilder {
+            pool_idle_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn pool_max_idle_per_host(self, max: usize) -> RhodClientBuilder {
+        RhodClientBuilder {
+            pool_max_idle_per_host: max,
+            ..self
+        }
+    }
+
+    // Connections are not reused after this time (ie: so upstreams behind DNS or a load balancer
+    // get rebalanced). A connection in use is closed after its response
+    pub fn pool_max_lifetime(self, lifetime: Duration) -> RhodClientBuilder {
+        RhodClientBuilder {
+            pool_max_lifetime: Some(lifetime),
+            ..self
+        }
+    }
+
+    // Uses HTTP/2 with prior knowledge for http uris (h2c)
+    pub fn http2_only(self, http2_only: bool) -> RhodClientBuilder {
+        RhodClientBuilder { http2_only, ..self }
+    }
+
+    // By default HTTP/2 is negotiated with https upstreams (ALPN), multiplexing the requests to a host
+    // on one connection. HTTP/1.1 only uses a connection per concurrent request instead
+    pub fn http1_only(self, http1_only: bool) -> RhodClientBuilder {
+        RhodClientBuilder { http1_only, ..self }
+    }
+
+    // Sends the time left until the Deadline of the request to the upstream (grpc-timeout for gRPC,
+    // X-Request-Timeout otherwise), so it can give up too. The call is limited to it either way
+    pub fn propagate_deadline(self, propagate_deadline: bool) -> RhodClientBuilder {
+        RhodClientBuilder {
+            propagate_deadline,
+            ..self
+        }
+    }
+
+    // Relays the informational responses of the upstreams (ie: 103 Early Hints) to the client of the
+    // request, when it can receive them (see informational). Off by default, as handlers calling other
+    // services with the request context would relay theirs too
+    pub fn relay_informational(self, relay_informational: bool) -> RhodClientBuilder {
+        RhodClientBuilder {
+            relay_informational,
+            ..self
+        }
+    }
+
+    // Resolver of the upstream hostnames, the system one by default
+    pub fn resolver(self, resolver: Arc<dyn Resolve>) -> RhodClientBuilder {
+        RhodClientBuilder { resolver, ..self }
+    }
+
+    // Trusts the certificates of a PEM file, besides the default roots
+    pub fn root_certificates_pem(mut self, pem: &[u8]) -> RhodResult<RhodClientBuilder> {
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            let added = cert
+                .map_err(|e| e.to_string())
+                .and_then(|cert| self.roots.add(cert).map_err(|e| e.to_string()));
+            if let Err(e) = added {
+                return Err(RhodError::from_string(
+                    format!("Invalid root certificate. {}", e),
+                    RhodErrorLevel::Error,
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> RhodClient {
+        let mut tls = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(self.roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = if self.http2_only {
+            vec![b"h2".to_vec()]
+        } else if self.http1_only {
+            vec![b"http/1.1".to_vec()]
+        } else {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        };
+
+        let mut http = HttpConnector::new_with_resolver(RhodResolver::new(self.resolver));
+        http.set_connect_timeout(self.connect_timeout);
+        let connector = RhodConnector::new(http, TlsConnector::from(Arc::new(tls)));
+        let counters = connector.counters();
+
+        let client = Client::builder(runtime::executor())
+            .pool_timer(runtime::timer())
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .http2_only(self.http2_only)
+            .build(connector);
+
+        RhodClient {
+            client,
+            timeout: self.timeout,
+            retry: self.retry,
+            breaker: self.breaker.map(|conf| Arc::new(CircuitBreaker::new(conf))),
+            max_lifetime: self.pool_max_lifetime,
+            counters,
+            propagate_deadline: self.propagate_deadline,
+            relay_informational: self.relay_informational,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_config::{get_configuration, HyperTlsAcceptor};
+    use crate::protocols::{HttpProtocolConf, TlsConfig};
+    use crate::reload::TlsListenerConf;
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::TcpListener;
+
+    static FLAKY_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    async fn hello(req: HyperRequest<Incoming>) -> Result<HyperResponse<RhodBody>, Infallible> {
+        match req.uri().path() {
+            "/slow" => tokio::time::sleep(Duration::from_millis(500)).await,
+            // fails twice every 3 calls
+            "/flaky" if FLAKY_CALLS.fetch_add(1, Ordering::SeqCst) % 3 != 2 => {
+                let mut res = HyperResponse::new(RhodBody::empty());
+                *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                return Ok(res);
+            }
+            "/down" => {
+                let mut res = HyperResponse::new(RhodBody::empty());
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return Ok(res);
+            }
+            _ => (),
+        }
+        Ok(HyperResponse::new(RhodBody::from("hello")))
+    }
+
+    async fn serve_hello<S>(stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let _ = Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(stream), service_fn(hello))
+            .await;
+    }
+
+    async fn spawn_server() -> std::net::SocketAddr {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = tcp.accept().await {
+                tokio::spawn(serve_hello(stream));
+            }
+        });
+        addr
+    }
+
+    fn get(uri: &str) -> RhodRequest {
+        RhodRequest::builder().uri(uri).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let addr = spawn_server().await;
+
+        let client = RhodClient::builder()
+            .timeout(Duration::from_millis(100))
+            .build();
+        let mut res = client
+            .send(get(&format!("http://{}/", addr)))
+            .await
+            .unwrap();
+        assert_eq!(res.status_as_int(), 200);
+        assert_eq!(res.body().await.unwrap(), b"hello".to_vec());
+
+        let err = client
+            .send(get(&format!("http://{}/slow", addr)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 504);
+
+        let client = RhodClient::builder().retries(2).build();
+        let err = client.send(get("http://127.0.0.1:1/")).await.unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 502);
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let addr = spawn_server().await;
+        let client = RhodClient::builder()
+            .retries(3)
+            .propagate_deadline(true)
+            .build();
+
+        let req = get(&format!("http://{}/slow", addr));
+        req.context()
+            .insert(Deadline::after(Duration::from_millis(100)));
+        let start = std::time::Instant::now();
+        let err = client.send(req).await.unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 504);
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        let mut req = HyperRequest::new(RhodBody::empty());
+        req.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        set_timeout_headers(&mut req, Duration::from_millis(1500));
+        assert_eq!(req.headers()["grpc-timeout"], "1500m");
+        assert_eq!(grpc_timeout(Duration::from_secs(200_000)), "200000S");
+    }
+
+    #[tokio::test]
+    async fn test_pool_lifetime() {
+        let addr = spawn_server().await;
+        let uri = format!("http://{}/", addr);
+
+        let client = RhodClient::new();
+        for _ in 0..3 {
+            client.send(get(&uri)).await.unwrap().body().await.unwrap();
+        }
+        let stats = client.pool_stats();
+        assert_eq!((stats.opened, stats.open, stats.requests), (1, 1, 3));
+
+        // every connection is too old to be reused
+        let client = RhodClient::builder()
+            .pool_max_lifetime(Duration::ZERO)
+            .build();
+        for _ in 0..3 {
+            client.send(get(&uri)).await.unwrap().body().await.unwrap();
+        }
+        let stats = client.pool_stats();
+        assert_eq!((stats.opened, stats.retired), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let addr = spawn_server().await;
+        let uri = format!("http://{}/flaky", addr);
+        let policy =
+            RetryPolicy::new(2).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let client = RhodClient::builder().retry_policy(policy).build();
+
+        let res = client
+            .send(
+                RhodRequest::builder()
+                    .method(Method::PUT)
+                    .uri(&uri)
+                    .body_str("v")
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status_as_int(), 200);
+
+        // not idempotent
+        let post = RhodRequest::builder()
+            .method(Method::POST)
+            .uri(&uri)
+            .build()
+            .unwrap();
+        assert_eq!(client.send(post).await.unwrap().status_as_int(), 503);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let addr = spawn_server().await;
+        let client = RhodClient::builder()
+            .circuit_breaker(CircuitBreakerConf {
+                window: 2,
+                min_calls: 2,
+                ..CircuitBreakerConf::default()
+            })
+            .build();
+        let uri = format!("http://{}/down", addr);
+
+        for _ in 0..2 {
+            assert_eq!(client.send(get(&uri)).await.unwrap().status_as_int(), 500);
+        }
+        let err = client.send(get(&uri)).await.unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 503);
+        assert_eq!(
+            client.circuit_breaker().unwrap().state(&addr.to_string()),
+            CircuitState::Open
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_tls() {
+        let protocol = HttpProtocolConf::HTTPS {
+            cert_file: "tests/assets/certs/server.crt".to_string(),
+            key_file: "tests/assets/certs/server.key".to_string(),
+        };
+        let server_config =
+            get_configuration(&protocol, &TlsConfig::default(), &Default::default()).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let tls = TlsListenerConf::shared(server_config, None);
+        let stats = Arc::new(crate::stats::ServerStats::new());
+        let mut acceptor = HyperTlsAcceptor::new(tcp, tls, stats).unwrap();
+        tokio::spawn(async move {
+            while let Some(stream) = acceptor.accept().await {
+                tokio::spawn(serve_hello(stream));
+            }
+        });
+        let uri = format!("https://localhost:{}/", port);
+
+        // the test CA is not trusted by default
+        let err = RhodClient::new().send(get(&uri)).await.unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 502);
+
+        let client = RhodClient::builder()
+            .root_certificates_pem(include_bytes!("../tests/assets/certs/CA.pem"))
+            .unwrap()
+            .build();
+        let mut res = client.send(get(&uri)).await.unwrap();
+        assert_eq!(res.body().await.unwrap(), b"hello".to_vec());
+    }
+}
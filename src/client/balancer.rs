@@ -0,0 +1,370 @@
+use super::resolver::Resolve;
+use crate::background::BackgroundJob;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::RhodConnInfo;
+use http::header::{HeaderName, HeaderValue, SET_COOKIE};
+use http::Uri;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// How repeat requests of a client are sent to the same upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    None,                     // round robin
+    ClientIp,                 // consistent hash of the client address
+    Header(HeaderName),       // consistent hash of the header (round robin without it)
+    Cookie(String, Duration), // cookie naming the upstream, and its max age
+}
+
+struct Upstream {
+    uri: Uri,
+    id: String, // sent in the affinity cookie instead of the address
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    fn new(uri: Uri) -> Arc<Upstream> {
+        Arc::new(Upstream {
+            id: format!("{:016x}", fnv1a(uri.to_string().as_bytes())),
+            uri,
+            healthy: AtomicBool::new(true),
+        })
+    }
+}
+
+// Hostname resolved to get the upstreams
+struct DnsSource {
+    uri: Uri,
+    resolver: Arc<dyn Resolve>,
+}
+
+// Upstream chosen for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub uri: Uri,
+    set_cookie: Option<HeaderValue>,
+}
+
+impl Selection {
+    // Sets the affinity cookie on the response, for clients that didnt have it (or had a stale one)
+    pub fn apply(&self, res: &mut RhodResponse) {
+        if let Some(cookie) = &self.set_cookie {
+            res.headers_mut().append(SET_COOKIE, cookie.clone());
+        }
+    }
+}
+
+// Spreads requests between upstreams, keeping clients on the same one with affinity.
+// Hashing is rendezvous (highest random weight): when an upstream is marked unhealthy only its clients
+// move to others, and they come back when it is healthy again. Cookie affinity assigns an upstream on
+// the first request and moves the client when the one in its cookie is unhealthy.
+// Upstreams can also be the addresses of a hostname (ie: a Kubernetes headless service), resolved
+// again periodically to follow the changes.
+pub struct Balancer {
+    upstreams: RwLock<Vec<Arc<Upstream>>>,
+    dns: Option<DnsSource>,
+    affinity: Affinity,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(upstreams: Vec<Uri>) -> Balancer {
+        Balancer {
+            upstreams: RwLock::new(upstreams.into_iter().map(Upstream::new).collect()),
+            dns: None,
+            affinity: Affinity::None,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // Upstreams at the addresses of the host of the uri, with its scheme and port. There are none
+    // until refresh is called (ie: by refresh_job).
+    // The upstream uris have the addresses as host, so https upstreams need certificates for them
+    pub fn resolving(uri: Uri, resolver: Arc<dyn Resolve>) -> Balancer {
+        Balancer {
+            dns: Some(DnsSource { uri, resolver }),
+            ..Balancer::new(vec![])
+        }
+    }
+
+    // Resolves the hostname again: new addresses are added (healthy), missing ones removed, and the
+    // ones kept keep their health
+    pub async fn refresh(&self) -> io::Result<()> {
+        let dns = match &self.dns {
+            Some(dns) => dns,
+            None => return Ok(()),
+        };
+        let host = dns.uri.host().unwrap_or_default();
+        let ips = dns.resolver.resolve(host).await?;
+        let uris: Vec<Uri> = ips
+            .into_iter()
+            .filter_map(|ip| {
+                let port = dns
+                    .uri
+                    .port_u16()
+                    .map_or(String::new(), |p| format!(":{}", p));
+                let host = match ip {
+                    IpAddr::V4(ip) => ip.to_string(),
+                    IpAddr::V6(ip) => format!("[{}]", ip),
+                };
+                let scheme = dns.uri.scheme_str().unwrap_or("http");
+                format!("{}://{}{}", scheme, host, port).parse().ok()
+            })
+            .collect();
+        let mut upstreams = self.upstreams.write().unwrap();
+        let refreshed: Vec<Arc<Upstream>> = uris
+            .into_iter()
+            .map(|uri| match upstreams.iter().find(|u| u.uri == uri) {
+                Some(kept) => Arc::clone(kept),
+                None => {
+                    info!("Upstream {} added from {}", uri, host);
+                    Upstream::new(uri)
+                }
+            })
+            .collect();
+        *upstreams = refreshed;
+        Ok(())
+    }
+
+    // Job resolving the hostname every interval, to add to the server
+    pub fn refresh_job(balancer: &Arc<Balancer>, interval: Duration) -> BackgroundJob {
+        let balancer = Arc::clone(balancer);
+        BackgroundJob::every(interval, move || {
+            let balancer = Arc::clone(&balancer);
+            async move {
+                // the last addresses are kept
+                if let Err(e) = balancer.refresh().await {
+                    warn!("Couldnt resolve the upstreams. {}", e);
+                }
+            }
+        })
+    }
+
+    pub fn with_affinity(self, affinity: Affinity) -> Self {
+        Balancer { affinity, ..self }
+    }
+
+    // Unhealthy upstreams get no requests (ie: marked by health checks or after failures)
+    pub fn mark_unhealthy(&self, uri: &Uri) {
+        self.set_health(uri, false);
+    }
+
+    pub fn mark_healthy(&self, uri: &Uri) {
+        self.set_health(uri, true);
+    }
+
+    fn set_health(&self, uri: &Uri, healthy: bool) {
+        let upstreams = self.upstreams.read().unwrap();
+        for upstream in upstreams.iter().filter(|u| u.uri == *uri) {
+            if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                info!(
+                    "Upstream {} marked {}",
+                    uri,
+                    if healthy { "healthy" } else { "unhealthy" }
+                );
+            }
+        }
+    }
+
+    pub fn is_healthy(&self, uri: &Uri) -> bool {
+        self.healthy().iter().any(|u| u.uri == *uri)
+    }
+
+    pub fn upstreams(&self) -> Vec<Uri> {
+        let upstreams = self.upstreams.read().unwrap();
+        upstreams.iter().map(|u| u.uri.clone()).collect()
+    }
+
+    fn healthy(&self) -> Vec<Arc<Upstream>> {
+        let upstreams = self.upstreams.read().unwrap();
+        upstreams
+            .iter()
+            .filter(|u| u.healthy.load(Ordering::Relaxed))
+            .cloned()
+            .collect()
+    }
+
+    fn round_robin(&self, healthy: &[Arc<Upstream>]) -> Option<Arc<Upstream>> {
+        if healthy.is_empty() {
+            return None;
+        }
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(Arc::clone(&healthy[n % healthy.len()]))
+    }
+
+    fn rendezvous(&self, healthy: &[Arc<Upstream>], key: &[u8]) -> Option<Arc<Upstream>> {
+        healthy.iter().cloned().max_by_key(|upstream| {
+            let mut bytes = key.to_vec();
+            bytes.extend(upstream.id.as_bytes());
+            mix(fnv1a(&bytes))
+        })
+    }
+
+    // None when every upstream is unhealthy
+    pub fn select(&self, conn: &RhodConnInfo, req: &RhodRequest) -> Option<Selection> {
+        let healthy = self.healthy();
+        let selection = |upstream: Arc<Upstream>| Selection {
+            uri: upstream.uri.clone(),
+            set_cookie: None,
+        };
+        match &self.affinity {
+            Affinity::None => self.round_robin(&healthy).map(selection),
+            Affinity::ClientIp => self
+                .rendezvous(&healthy, &ip_bytes(conn.addr.ip()))
+                .map(selection),
+            Affinity::Header(name) => match req.headers().get(name) {
+                Some(value) => self.rendezvous(&healthy, value.as_bytes()).map(selection),
+                None => self.round_robin(&healthy).map(selection),
+            },
+            Affinity::Cookie(name, max_age) => {
+                let pinned = req
+                    .cookie(name)
+                    .and_then(|id| healthy.iter().find(|u| u.id == id));
+                if let Some(upstream) = pinned {
+                    return Some(selection(Arc::clone(upstream)));
+                }
+                let upstream = self.round_robin(&healthy)?;
+                let cookie = format!(
+                    "{}={}; Path=/; Max-Age={}; HttpOnly",
+                    name,
+                    upstream.id,
+                    max_age.as_secs()
+                );
+                Some(Selection {
+                    uri: upstream.uri.clone(),
+                    set_cookie: HeaderValue::from_str(&cookie).ok(),
+                })
+            }
+        }
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// Stable between runs, so clients keep their upstream after restarts
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// splitmix64 finalizer, FNV alone spreads keys differing in a few bytes poorly
+fn mix(hash: u64) -> u64 {
+    let hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    struct Endpoints(Mutex<Vec<IpAddr>>);
+
+    #[async_trait]
+    impl Resolve for Endpoints {
+        async fn resolve(&self, _host: &str) -> io::Result<Vec<IpAddr>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balancer_refresh() {
+        let ips: Vec<IpAddr> = vec!["10.0.1.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+        let endpoints = Arc::new(Endpoints(Mutex::new(ips)));
+        let balancer = Balancer::resolving(
+            "http://api.default.svc:8080".parse().unwrap(),
+            endpoints.clone(),
+        );
+        assert!(balancer.upstreams().is_empty());
+        balancer.refresh().await.unwrap();
+        let first: Uri = "http://10.0.1.1:8080".parse().unwrap();
+        assert_eq!(
+            balancer.upstreams(),
+            vec![first.clone(), "http://[fd00::1]:8080".parse().unwrap()]
+        );
+
+        // health is kept for the addresses still resolved
+        balancer.mark_unhealthy(&first);
+        endpoints
+            .0
+            .lock()
+            .unwrap()
+            .push("10.0.1.2".parse().unwrap());
+        endpoints.0.lock().unwrap().remove(1);
+        balancer.refresh().await.unwrap();
+        assert_eq!(balancer.upstreams().len(), 2);
+        assert!(!balancer.is_healthy(&first));
+        assert!(balancer.is_healthy(&"http://10.0.1.2:8080".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_balancer_affinity() {
+        let uris: Vec<Uri> = (1..=3)
+            .map(|n| format!("http://10.0.1.{}:8080", n).parse().unwrap())
+            .collect();
+        let balancer = Balancer::new(uris.clone()).with_affinity(Affinity::ClientIp);
+        let req = RhodRequest::builder().build().unwrap();
+        let conns: Vec<RhodConnInfo> = (0..100)
+            .map(|n| {
+                RhodConnInfo::new(
+                    format!("10.0.0.{}:4000", n).parse().unwrap(),
+                    HttpProtocol::HTTP,
+                )
+            })
+            .collect();
+        let before: Vec<Uri> = conns
+            .iter()
+            .map(|conn| balancer.select(conn, &req).unwrap().uri)
+            .collect();
+        for (conn, uri) in conns.iter().zip(&before) {
+            assert_eq!(balancer.select(conn, &req).unwrap().uri, *uri);
+        }
+        // only the clients of the unhealthy upstream move
+        balancer.mark_unhealthy(&uris[0]);
+        for (conn, uri) in conns.iter().zip(&before) {
+            let now = balancer.select(conn, &req).unwrap().uri;
+            assert_ne!(now, uris[0]);
+            if *uri != uris[0] {
+                assert_eq!(now, *uri);
+            }
+        }
+        balancer.mark_healthy(&uris[0]);
+        assert_eq!(balancer.select(&conns[0], &req).unwrap().uri, before[0]);
+
+        let balancer = Balancer::new(uris.clone()).with_affinity(Affinity::Cookie(
+            "upstream".to_string(),
+            Duration::from_secs(60),
+        ));
+        let selection = balancer.select(&conns[0], &req).unwrap();
+        let mut res = RhodResponse::builder().build().unwrap();
+        selection.apply(&mut res);
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        let pair = cookie.split(';').next().unwrap();
+        let req = RhodRequest::builder()
+            .header("Cookie", pair)
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            let again = balancer.select(&conns[0], &req).unwrap();
+            assert_eq!(again.uri, selection.uri);
+            assert_eq!(again.set_cookie, None);
+        }
+        balancer.mark_unhealthy(&selection.uri);
+        let moved = balancer.select(&conns[0], &req).unwrap();
+        assert_ne!(moved.uri, selection.uri);
+        assert!(moved.set_cookie.is_some());
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// When to stop calling an upstream host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreakerConf {
+    pub window: usize,           // last calls considered
+    pub min_calls: usize,        // calls needed in the window before opening
+    pub failure_percent: u32,    // opens when this percent of the window failed
+    pub open_duration: Duration, // time failing fast before probing again
+    pub half_open_probes: u32,   // successful probes needed to close
+}
+
+impl Default for CircuitBreakerConf {
+    fn default() -> CircuitBreakerConf {
+        CircuitBreakerConf {
+            window: 20,
+            min_calls: 10,
+            failure_percent: 50,
+            open_duration: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,   // calls go through
+    Open,     // calls fail fast
+    HalfOpen, // a few probes go through to check if the host recovered
+}
+
+enum HostState {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, successes: u32 },
+}
+
+struct HostCircuit {
+    state: HostState,
+    results: VecDeque<bool>, // true if the call failed
+}
+
+// Circuit breaker per upstream host. Failures are transport errors, timeouts and 5xx responses
+pub struct CircuitBreaker {
+    conf: CircuitBreakerConf,
+    hosts: Mutex<HashMap<String, HostCircuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(conf: CircuitBreakerConf) -> CircuitBreaker {
+        CircuitBreaker {
+            conf,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn state(&self, host: &str) -> CircuitState {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .get(host)
+            .map_or(CircuitState::Closed, |circuit| circuit.public_state())
+    }
+
+    // State of every host called, to be exposed as metrics
+    pub fn states(&self) -> Vec<(String, CircuitState)> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut states: Vec<_> = hosts
+            .iter()
+            .map(|(host, circuit)| (host.clone(), circuit.public_state()))
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    // None if the call must fail fast
+    pub(crate) fn acquire(&self, host: &str) -> Option<CircuitPermit<'_>> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let circuit = hosts
+            .entry(host.to_string())
+            .or_insert_with(|| HostCircuit {
+                state: HostState::Closed,
+                results: VecDeque::new(),
+            });
+        let allowed = match &mut circuit.state {
+            HostState::Closed => true,
+            HostState::Open { until } => {
+                if Instant::now() >= *until {
+                    circuit.state = HostState::HalfOpen {
+                        in_flight: 1,
+                        successes: 0,
+                    };
+                    true
+                } else {
+                    false
+                }
+            }
+            HostState::HalfOpen { in_flight, .. } => {
+                if *in_flight < self.conf.half_open_probes {
+                    *in_flight += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if allowed {
+            Some(CircuitPermit {
+                breaker: self,
+                host: host.to_string(),
+                recorded: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn record(&self, host: &str, failed: bool) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let circuit = match hosts.get_mut(host) {
+            Some(circuit) => circuit,
+            None => return,
+        };
+        let open = HostState::Open {
+            until: Instant::now() + self.conf.open_duration,
+        };
+        match &mut circuit.state {
+            HostState::Closed => {
+                circuit.results.push_back(failed);
+                while circuit.results.len() > self.conf.window {
+                    circuit.results.pop_front();
+                }
+                let calls = circuit.results.len();
+                let failures = circuit.results.iter().filter(|failed| **failed).count();
+                if calls >= self.conf.min_calls
+                    && failures * 100 >= self.conf.failure_percent as usize * calls
+                {
+                    warn!("Circuit opened for {}", host);
+                    circuit.state = open;
+                    circuit.results.clear();
+                }
+            }
+            HostState::HalfOpen {
+                in_flight,
+                successes,
+            } => {
+                *in_flight = in_flight.saturating_sub(1);
+                if failed {
+                    circuit.state = open;
+                } else {
+                    *successes += 1;
+                    if *successes >= self.conf.half_open_probes {
+                        info!("Circuit closed for {}", host);
+                        circuit.state = HostState::Closed;
+                    }
+                }
+            }
+            // results of calls started before opening
+            HostState::Open { .. } => (),
+        }
+    }
+
+    // A call acquired but not recorded (ie: cancelled) frees its probe slot
+    fn release(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(HostCircuit {
+            state: HostState::HalfOpen { in_flight, .. },
+            ..
+        }) = hosts.get_mut(host)
+        {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+}
+
+impl HostCircuit {
+    fn public_state(&self) -> CircuitState {
+        match self.state {
+            HostState::Closed => CircuitState::Closed,
+            HostState::Open { .. } => CircuitState::Open,
+            HostState::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+// Allows a call to a host. Its result must be recorded
+pub(crate) struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    host: String,
+    recorded: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub(crate) fn record(mut self, failed: bool) {
+        self.recorded = true;
+        self.breaker.record(&self.host, failed);
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.release(&self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConf {
+            window: 4,
+            min_calls: 4,
+            failure_percent: 50,
+            open_duration: Duration::from_millis(20),
+            half_open_probes: 1,
+        });
+        let host = "upstream:80";
+
+        for failed in [false, true, false, false] {
+            breaker.acquire(host).unwrap().record(failed);
+        }
+        assert_eq!(breaker.state(host), CircuitState::Closed);
+
+        // 2 failures in the last 4 calls
+        breaker.acquire(host).unwrap().record(true);
+        assert_eq!(breaker.state(host), CircuitState::Open);
+        assert!(breaker.acquire(host).is_none());
+
+        // after open_duration, one probe goes through
+        std::thread::sleep(Duration::from_millis(30));
+        let probe = breaker.acquire(host).unwrap();
+        assert_eq!(breaker.state(host), CircuitState::HalfOpen);
+        assert!(breaker.acquire(host).is_none());
+        probe.record(true);
+        assert_eq!(breaker.state(host), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        // a cancelled probe frees its slot
+        drop(breaker.acquire(host).unwrap());
+        breaker.acquire(host).unwrap().record(false);
+        assert_eq!(breaker.state(host), CircuitState::Closed);
+        assert_eq!(
+            breaker.states(),
+            vec![(host.to_string(), CircuitState::Closed)]
+        );
+    }
+}
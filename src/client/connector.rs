@@ -0,0 +1,233 @@
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http::uri::Scheme;
+use http::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::rt::TokioIo;
+use rustls_pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tower_service::Service;
+
+use super::resolver::RhodResolver;
+use crate::body::BoxError;
+
+// Connections of a client pool, see RhodClient::pool_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub opened: u64,   // connections opened since the client was built
+    pub open: u64,     // connections open now, idle or in use
+    pub retired: u64,  // connections not reused because they reached the max lifetime
+    pub requests: u64, // requests sent, so requests - opened were sent on reused connections
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PoolCounters {
+    opened: AtomicU64,
+    open: AtomicU64,
+    pub(crate) retired: AtomicU64,
+    pub(crate) requests: AtomicU64,
+}
+
+impl PoolCounters {
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            opened: self.opened.load(Ordering::Relaxed),
+            open: self.open.load(Ordering::Relaxed),
+            retired: self.retired.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Time a connection was opened, set in the extensions of its responses
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectionOpened(pub(crate) Instant);
+
+// Connects with TLS to https uris, and plain TCP to the rest
+#[derive(Clone)]
+pub struct RhodConnector {
+    http: HttpConnector<RhodResolver>,
+    tls: TlsConnector,
+    counters: Arc<PoolCounters>,
+}
+
+impl RhodConnector {
+    pub fn new(mut http: HttpConnector<RhodResolver>, tls: TlsConnector) -> RhodConnector {
+        http.enforce_http(false);
+        RhodConnector {
+            http,
+            tls,
+            counters: Arc::new(PoolCounters::default()),
+        }
+    }
+
+    pub(crate) fn counters(&self) -> Arc<PoolCounters> {
+        Arc::clone(&self.counters)
+    }
+}
+
+impl Service<Uri> for RhodConnector {
+    type Response = TokioIo<PooledStream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let is_https = uri.scheme() == Some(&Scheme::HTTPS);
+        // ipv6 hosts come between brackets
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let connecting = self.http.call(uri);
+        let tls = self.tls.clone();
+        let counters = Arc::clone(&self.counters);
+
+        Box::pin(async move {
+            let tcp = connecting.await?.into_inner();
+            let stream = if is_https {
+                let server_name = ServerName::try_from(host)?;
+                let tls_stream = tls.connect(server_name, tcp).await?;
+                MaybeTlsStream::Tls(Box::new(tls_stream))
+            } else {
+                MaybeTlsStream::Plain(tcp)
+            };
+            Ok(TokioIo::new(PooledStream::new(stream, counters)))
+        })
+    }
+}
+
+// Connection of the pool, counted as open until it is dropped
+pub struct PooledStream {
+    stream: MaybeTlsStream,
+    opened: Instant,
+    counters: Arc<PoolCounters>,
+}
+
+impl PooledStream {
+    fn new(stream: MaybeTlsStream, counters: Arc<PoolCounters>) -> PooledStream {
+        counters.opened.fetch_add(1, Ordering::Relaxed);
+        counters.open.fetch_add(1, Ordering::Relaxed);
+        PooledStream {
+            stream,
+            opened: Instant::now(),
+            counters,
+        }
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        self.counters.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Connection for PooledStream {
+    fn connected(&self) -> Connected {
+        self.stream.connected().extra(ConnectionOpened(self.opened))
+    }
+}
+
+impl AsyncRead for PooledStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PooledStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for MaybeTlsStream {
+    fn connected(&self) -> Connected {
+        match self {
+            MaybeTlsStream::Plain(tcp) => tcp.connected(),
+            MaybeTlsStream::Tls(tls) => {
+                let (tcp, session) = tls.get_ref();
+                if session.alpn_protocol() == Some(b"h2") {
+                    tcp.connected().negotiated_h2()
+                } else {
+                    tcp.connected()
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
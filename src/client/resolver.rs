@@ -0,0 +1,181 @@
+use async_trait::async_trait;
+use hyper_util::client::legacy::connect::dns::Name;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower_service::Service;
+
+// Resolves upstream hostnames, see RhodClientBuilder::resolver and Balancer::resolving
+#[async_trait]
+pub trait Resolve: Send + Sync {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+// Resolver of the system (getaddrinfo, so /etc/hosts and resolv.conf apply)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolve for SystemResolver {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs = tokio::net::lookup_host((host, 0)).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+// Keeps the answers of another resolver for a time. The system resolver doesnt tell the TTL of the
+// records, so it is set here (ie: the TTL of the upstream records). When resolving fails, the last
+// answer is used until the name resolves again
+pub struct CachingResolver {
+    inner: Arc<dyn Resolve>,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>>, // answer and when it was resolved
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolve>, ttl: Duration) -> CachingResolver {
+        CachingResolver {
+            inner,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Resolve for CachingResolver {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let cached = self.cache.lock().unwrap().get(host).cloned();
+        if let Some((resolved, ips)) = &cached {
+            if resolved.elapsed() < self.ttl {
+                return Ok(ips.clone());
+            }
+        }
+        match self.inner.resolve(host).await {
+            Ok(ips) => {
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(host.to_string(), (Instant::now(), ips.clone()));
+                Ok(ips)
+            }
+            Err(e) => match cached {
+                Some((_, ips)) => {
+                    warn!("Couldnt resolve {}, using the last answer. {}", host, e);
+                    Ok(ips)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+// Fixed addresses for some hostnames (ie: tests, or hosts missing from DNS), the rest are resolved
+// by another resolver
+pub struct StaticResolver {
+    overrides: HashMap<String, Vec<IpAddr>>,
+    fallback: Arc<dyn Resolve>,
+}
+
+impl StaticResolver {
+    pub fn new(fallback: Arc<dyn Resolve>) -> StaticResolver {
+        StaticResolver {
+            overrides: HashMap::new(),
+            fallback,
+        }
+    }
+
+    pub fn with_override(mut self, host: &str, ips: Vec<IpAddr>) -> Self {
+        self.overrides.insert(host.to_ascii_lowercase(), ips);
+        self
+    }
+}
+
+#[async_trait]
+impl Resolve for StaticResolver {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        match self.overrides.get(&host.to_ascii_lowercase()) {
+            Some(ips) => Ok(ips.clone()),
+            None => self.fallback.resolve(host).await,
+        }
+    }
+}
+
+// Resolve as the resolver of the connector (HttpConnector::new_with_resolver)
+#[derive(Clone)]
+pub struct RhodResolver(Arc<dyn Resolve>);
+
+impl RhodResolver {
+    pub fn new(resolver: Arc<dyn Resolve>) -> RhodResolver {
+        RhodResolver(resolver)
+    }
+}
+
+impl Service<Name> for RhodResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = Arc::clone(&self.0);
+        Box::pin(async move {
+            let ips = resolver.resolve(name.as_str()).await?;
+            // the connector sets the port of the uri
+            let addrs: Vec<SocketAddr> = ips.into_iter().map(|ip| (ip, 0).into()).collect();
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Answers 10.0.0.n on the n-th call, failing after the second
+    #[derive(Default)]
+    struct Changing {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Resolve for Changing {
+        async fn resolve(&self, _host: &str) -> io::Result<Vec<IpAddr>> {
+            match self.calls.fetch_add(1, Ordering::SeqCst) + 1 {
+                n if n <= 2 => Ok(vec![IpAddr::from([10, 0, 0, n as u8])]),
+                _ => Err(io::Error::other("no answer")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolvers() {
+        let caching =
+            CachingResolver::new(Arc::new(Changing::default()), Duration::from_millis(50));
+        let first = vec![IpAddr::from([10, 0, 0, 1])];
+        assert_eq!(caching.resolve("api").await.unwrap(), first);
+        assert_eq!(caching.resolve("api").await.unwrap(), first);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let second = vec![IpAddr::from([10, 0, 0, 2])];
+        assert_eq!(caching.resolve("api").await.unwrap(), second);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // stale answer while resolving fails
+        assert_eq!(caching.resolve("api").await.unwrap(), second);
+
+        let fixed = vec![IpAddr::from([192, 168, 0, 1])];
+        let resolver = StaticResolver::new(Arc::new(SystemResolver))
+            .with_override("Upstream.internal", fixed.clone());
+        assert_eq!(resolver.resolve("upstream.internal").await.unwrap(), fixed);
+        let localhost = resolver.resolve("localhost").await.unwrap();
+        assert!(localhost.iter().all(|ip| ip.is_loopback()));
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use http::{Method, StatusCode};
+
+// When and how to send again a failed upstream call.
+// Only idempotent methods are retried, and only if their body is empty or small enough to be kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_backoff: Duration, // doubled on every retry
+    pub max_backoff: Duration,
+    pub retry_statuses: Vec<StatusCode>, // responses retried, besides transport errors and timeouts
+    pub max_replay_body: usize, // bigger bodies are not kept, so those requests are not retried
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            base_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            retry_statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            max_replay_body: 64 * 1024,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            ..RetryPolicy::default()
+        }
+    }
+
+    pub fn with_backoff(self, base_backoff: Duration, max_backoff: Duration) -> Self {
+        RetryPolicy {
+            base_backoff,
+            max_backoff,
+            ..self
+        }
+    }
+
+    pub fn with_retry_statuses(self, retry_statuses: Vec<StatusCode>) -> Self {
+        RetryPolicy {
+            retry_statuses,
+            ..self
+        }
+    }
+
+    pub fn with_max_replay_body(self, max_replay_body: usize) -> Self {
+        RetryPolicy {
+            max_replay_body,
+            ..self
+        }
+    }
+
+    pub(crate) fn retries_method(&self, method: &Method) -> bool {
+        self.max_retries > 0
+            && matches!(
+                *method,
+                Method::GET
+                    | Method::HEAD
+                    | Method::OPTIONS
+                    | Method::PUT
+                    | Method::DELETE
+                    | Method::TRACE
+            )
+    }
+
+    // Exponential backoff with full jitter: random between 0 and base * 2^attempt (capped)
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        let cap = self
+            .base_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+        cap.mul_f64(fastrand::f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let policy =
+            RetryPolicy::new(3).with_backoff(Duration::from_millis(10), Duration::from_millis(50));
+        assert!(policy.retries_method(&Method::GET));
+        assert!(policy.retries_method(&Method::PUT));
+        assert!(!policy.retries_method(&Method::POST));
+        assert!(!RetryPolicy::default().retries_method(&Method::GET));
+
+        for attempt in 0..40 {
+            let backoff = policy.backoff(attempt);
+            assert!(backoff <= Duration::from_millis(50));
+            if attempt == 0 {
+                assert!(backoff <= Duration::from_millis(10));
+            }
+        }
+    }
+}
@@ -0,0 +1,485 @@
+// Server settings and built-in handlers read from a TOML or YAML file, so ports, timeouts and limits
+// can be changed without recompiling. Every section is optional, missing values keep the defaults.
+//
+//  [server]
+//  listen = "0.0.0.0:8443"
+//  acceptors = 4
+//
+//  [server.tls]
+//  cert_file = "certs/server.crt"
+//  key_file = "certs/server.key"
+//  min_version = "tls13"
+//
+//  [server.timeouts]
+//  idle_secs = 60
+//  response_secs = 30
+//
+//  [[handlers]]
+//  type = "normalize"
+//  lowercase_host = true
+use crate::auto_headers::AutoHeaders;
+use crate::errors::RhodHyperError;
+use crate::handlers::{
+    CacheHandler, ConditionalGetHandler, EtagKind, MemoryCacheStore, NormalizeHandler,
+    RequestIdHandler, RequestIdTrust,
+};
+use crate::limits::ResponseLimits;
+use crate::protocols::{ConnectionConf, HttpProtocolConf, SocketConf, TlsConfig, TlsVersion};
+use crate::stack::RhodHandlerInStack;
+use http::header::HeaderName;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RhodConfig {
+    pub server: ServerSettings,
+    pub handlers: Vec<HandlerSettings>, // built-in handlers, in stack order
+    pub log: LogSettings,
+}
+
+impl RhodConfig {
+    // Reads a .toml, .yaml or .yml file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RhodConfig, RhodHyperError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            RhodHyperError::ConfigError(format!("Couldnt read {}. {}", path.display(), e))
+        })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => RhodConfig::from_toml(&content),
+            Some("yaml") | Some("yml") => RhodConfig::from_yaml(&content),
+            _ => Err(RhodHyperError::ConfigError(format!(
+                "Unknown config format of {}, expected .toml, .yaml or .yml",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<RhodConfig, RhodHyperError> {
+        let config: RhodConfig = toml::from_str(content)
+            .map_err(|e| RhodHyperError::ConfigError(format!("Invalid TOML config. {}", e)))?;
+        config.log.level_filter()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml(content: &str) -> Result<RhodConfig, RhodHyperError> {
+        let config: RhodConfig = serde_yaml::from_str(content)
+            .map_err(|e| RhodHyperError::ConfigError(format!("Invalid YAML config. {}", e)))?;
+        config.log.level_filter()?;
+        Ok(config)
+    }
+
+    // Built-in handlers to put in the stack
+    pub fn stack_handlers<C: Send + Sync>(&self) -> Vec<RhodHandlerInStack<C>> {
+        self.handlers.iter().map(HandlerSettings::handler).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub listen: SocketAddr,
+    pub acceptors: usize,
+    pub tls: Option<TlsSettings>, // None serves plain HTTP
+    pub timeouts: TimeoutSettings,
+    pub limits: LimitSettings,
+    pub http: HttpSettings,
+    pub socket: SocketSettings,
+}
+
+impl Default for ServerSettings {
+    fn default() -> ServerSettings {
+        ServerSettings {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            acceptors: 1,
+            tls: None,
+            timeouts: TimeoutSettings::default(),
+            limits: LimitSettings::default(),
+            http: HttpSettings::default(),
+            socket: SocketSettings::default(),
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn protocol(&self) -> HttpProtocolConf {
+        match &self.tls {
+            Some(tls) => HttpProtocolConf::HTTPS {
+                cert_file: tls.cert_file.clone(),
+                key_file: tls.key_file.clone(),
+            },
+            None => HttpProtocolConf::HTTP,
+        }
+    }
+
+    pub fn tls_config(&self) -> TlsConfig {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return TlsConfig::default(),
+        };
+        let defaults = TlsConfig::default();
+        let config = TlsConfig::new().with_versions(
+            tls.min_version.map_or(defaults.min_version, Into::into),
+            tls.max_version.map_or(defaults.max_version, Into::into),
+        );
+        match &tls.cipher_suites {
+            Some(suites) => config.with_cipher_suites(suites.iter().map(String::as_str).collect()),
+            None => config,
+        }
+    }
+
+    pub fn connection_conf(&self) -> ConnectionConf {
+        ConnectionConf {
+            http1_keepalive: self.http.keepalive,
+            idle_timeout: self.timeouts.idle_secs.map(Duration::from_secs),
+            tls_handshake_timeout: self.timeouts.tls_handshake_secs.map(Duration::from_secs),
+            header_read_timeout: self.timeouts.header_read_secs.map(Duration::from_secs),
+            body_read_timeout: self.timeouts.body_read_secs.map(Duration::from_secs),
+            http1_max_buf_size: self.limits.max_header_bytes,
+            http1_half_close: self.http.half_close,
+            http1_only: self.http.http1_only,
+            h2c: self.http.h2c,
+            http2_only: self.http.http2_only,
+            http2_initial_stream_window_size: self.limits.http2_stream_window,
+            http2_initial_connection_window_size: self.limits.http2_connection_window,
+            http2_adaptive_window: self.http.http2_adaptive_window,
+            http2_max_concurrent_streams: self.limits.http2_max_concurrent_streams,
+            http2_max_frame_size: self.limits.http2_max_frame_size,
+            http2_keepalive_interval: self.timeouts.http2_keepalive_secs.map(Duration::from_secs),
+            http2_keepalive_timeout: self
+                .timeouts
+                .http2_keepalive_timeout_secs
+                .map(Duration::from_secs),
+        }
+    }
+
+    pub fn response_limits(&self) -> ResponseLimits {
+        let mut limits = ResponseLimits::new();
+        if let Some(size) = self.limits.max_response_body {
+            limits = limits.with_max_body_size(size);
+        }
+        if let Some(secs) = self.timeouts.response_secs {
+            limits = limits.with_timeout(Duration::from_secs(secs));
+        }
+        limits
+    }
+
+    pub fn auto_headers(&self) -> AutoHeaders {
+        let mut auto = if self.http.auto_headers {
+            AutoHeaders::new()
+        } else {
+            AutoHeaders::default()
+        };
+        if let Some(server) = &self.http.server_header {
+            auto = auto.with_server(server);
+        }
+        auto
+    }
+
+    pub fn socket_conf(&self) -> SocketConf {
+        let defaults = SocketConf::default();
+        SocketConf {
+            nodelay: self.socket.nodelay,
+            reuse_port: self.socket.reuse_port,
+            backlog: self.limits.backlog.unwrap_or(defaults.backlog),
+            keepalive_time: self.socket.keepalive_secs.map(Duration::from_secs),
+            keepalive_interval: self.socket.keepalive_interval_secs.map(Duration::from_secs),
+            keepalive_retries: self.socket.keepalive_retries,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_file: String,
+    pub key_file: String,
+    #[serde(default)]
+    pub min_version: Option<TlsVersionSetting>,
+    #[serde(default)]
+    pub max_version: Option<TlsVersionSetting>,
+    #[serde(default)]
+    pub cipher_suites: Option<Vec<String>>, // IANA names
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsVersionSetting {
+    Tls12,
+    Tls13,
+}
+
+impl From<TlsVersionSetting> for TlsVersion {
+    fn from(version: TlsVersionSetting) -> TlsVersion {
+        match version {
+            TlsVersionSetting::Tls12 => TlsVersion::TLS12,
+            TlsVersionSetting::Tls13 => TlsVersion::TLS13,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutSettings {
+    pub idle_secs: Option<u64>,
+    pub tls_handshake_secs: Option<u64>,
+    pub header_read_secs: Option<u64>,
+    pub body_read_secs: Option<u64>, // between chunks of a request body
+    pub response_secs: Option<u64>,  // service response, including its body
+    pub http2_keepalive_secs: Option<u64>, // interval of the PING frames
+    pub http2_keepalive_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_header_bytes: Option<usize>, // HTTP/1 buffer size
+    pub backlog: Option<u32>,
+    pub http2_stream_window: Option<u32>,
+    pub http2_connection_window: Option<u32>,
+    pub http2_max_concurrent_streams: Option<u32>,
+    pub http2_max_frame_size: Option<u32>,
+    pub max_response_body: Option<u64>, // bytes of a service response body
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    pub keepalive: Option<bool>,
+    pub half_close: Option<bool>,
+    pub h2c: Option<bool>,
+    pub http1_only: bool,
+    pub http2_only: bool,
+    pub http2_adaptive_window: Option<bool>,
+    pub auto_headers: bool, // Date and Content-Length set by the executor
+    pub server_header: Option<String>, // Server set by the executor
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketSettings {
+    pub nodelay: bool,
+    pub reuse_port: bool,
+    pub keepalive_secs: Option<u64>,
+    pub keepalive_interval_secs: Option<u64>,
+    pub keepalive_retries: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub level: Option<String>, // off, error, warn, info, debug or trace. None keeps the current level
+}
+
+impl LogSettings {
+    pub fn level_filter(&self) -> Result<Option<LevelFilter>, RhodHyperError> {
+        match &self.level {
+            Some(level) => LevelFilter::from_str(level)
+                .map(Some)
+                .map_err(|_| RhodHyperError::ConfigError(format!("Unknown log level {}", level))),
+            None => Ok(None),
+        }
+    }
+}
+
+// Built-in handlers that only need settings to be built
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum HandlerSettings {
+    Normalize {
+        #[serde(default)]
+        lowercase_host: bool,
+    },
+    ConditionalGet {
+        #[serde(default)]
+        etag: Option<EtagSetting>,
+        #[serde(default)]
+        max_body_size: Option<u64>,
+    },
+    // In memory cache
+    Cache {
+        capacity: usize,
+        #[serde(default)]
+        default_ttl_secs: Option<u64>,
+        #[serde(default)]
+        max_body_size: Option<usize>,
+    },
+    RequestId {
+        #[serde(default)]
+        header: Option<String>, // X-Request-Id by default
+        #[serde(default)]
+        trusted_peers: Option<Vec<IpAddr>>, // every peer by default
+        #[serde(default = "default_true")]
+        trace_context: bool,
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtagSetting {
+    Strong,
+    Weak,
+    Service, // only ETags provided by the service
+}
+
+impl HandlerSettings {
+    pub fn handler<C: Send + Sync>(&self) -> RhodHandlerInStack<C> {
+        match self {
+            HandlerSettings::Normalize { lowercase_host } => {
+                let handler = NormalizeHandler::new();
+                let handler = if *lowercase_host {
+                    handler.lowercase_host()
+                } else {
+                    handler
+                };
+                RhodHandlerInStack::RhodHandler(Box::new(handler))
+            }
+            HandlerSettings::ConditionalGet {
+                etag,
+                max_body_size,
+            } => {
+                let mut handler = match etag {
+                    Some(EtagSetting::Strong) | None => ConditionalGetHandler::new(),
+                    Some(EtagSetting::Weak) => {
+                        ConditionalGetHandler::new().with_etag_kind(EtagKind::Weak)
+                    }
+                    Some(EtagSetting::Service) => ConditionalGetHandler::new().service_etags_only(),
+                };
+                if let Some(size) = max_body_size {
+                    handler = handler.with_max_body_size(*size);
+                }
+                RhodHandlerInStack::RhodHandler(Box::new(handler))
+            }
+            HandlerSettings::Cache {
+                capacity,
+                default_ttl_secs,
+                max_body_size,
+            } => {
+                let mut handler = CacheHandler::new(Arc::new(MemoryCacheStore::new(*capacity)));
+                if let Some(ttl) = default_ttl_secs {
+                    handler = handler.with_default_ttl(Duration::from_secs(*ttl));
+                }
+                if let Some(size) = max_body_size {
+                    handler = handler.with_max_body_size(*size);
+                }
+                RhodHandlerInStack::RhodHandler(Box::new(handler))
+            }
+            HandlerSettings::RequestId {
+                header,
+                trusted_peers,
+                trace_context,
+            } => {
+                let mut handler = RequestIdHandler::new();
+                if let Some(header) = header {
+                    match HeaderName::from_str(header) {
+                        Ok(header) => handler = handler.with_header(header),
+                        Err(_) => error!("Invalid request id header {}, using the default", header),
+                    }
+                }
+                if let Some(peers) = trusted_peers {
+                    handler = handler.with_trust(RequestIdTrust::Peers(peers.clone()));
+                }
+                if !trace_context {
+                    handler = handler.without_trace_context();
+                }
+                RhodHandlerInStack::RhodHandler(Box::new(handler))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+[server]
+listen = "0.0.0.0:8443"
+acceptors = 2
+
+[server.tls]
+cert_file = "server.crt"
+key_file = "server.key"
+min_version = "tls13"
+
+[server.timeouts]
+idle_secs = 60
+http2_keepalive_secs = 20
+
+[server.limits]
+backlog = 128
+http2_max_concurrent_streams = 1000
+
+[[handlers]]
+type = "normalize"
+lowercase_host = true
+
+[[handlers]]
+type = "cache"
+capacity = 100
+"#;
+
+    const YAML: &str = r#"
+server:
+  listen: "0.0.0.0:8443"
+  acceptors: 2
+  tls:
+    cert_file: server.crt
+    key_file: server.key
+    min_version: tls13
+  timeouts:
+    idle_secs: 60
+    http2_keepalive_secs: 20
+  limits:
+    backlog: 128
+    http2_max_concurrent_streams: 1000
+handlers:
+  - type: normalize
+    lowercase_host: true
+  - type: cache
+    capacity: 100
+"#;
+
+    #[test]
+    fn test_config() {
+        let config = RhodConfig::from_toml(TOML).unwrap();
+        assert_eq!(config, RhodConfig::from_yaml(YAML).unwrap());
+
+        let server = &config.server;
+        assert_eq!(server.listen, "0.0.0.0:8443".parse().unwrap());
+        assert_eq!(server.acceptors, 2);
+        assert_eq!(
+            server.protocol(),
+            HttpProtocolConf::HTTPS {
+                cert_file: "server.crt".to_string(),
+                key_file: "server.key".to_string(),
+            }
+        );
+        assert_eq!(server.tls_config().min_version, TlsVersion::TLS13);
+        assert_eq!(
+            server.connection_conf(),
+            ConnectionConf::new()
+                .with_idle_timeout(Duration::from_secs(60))
+                .with_http2_keepalive(Duration::from_secs(20), None)
+                .with_http2_max_concurrent_streams(1000)
+        );
+        assert_eq!(server.socket_conf(), SocketConf::new().with_backlog(128));
+        assert_eq!(config.stack_handlers::<()>().len(), 2);
+
+        // defaults, and typos are rejected
+        let empty = RhodConfig::from_toml("").unwrap();
+        assert_eq!(empty.server.protocol(), HttpProtocolConf::HTTP);
+        assert!(RhodConfig::from_toml("[server]\nlisten_addr = \"0.0.0.0:80\"").is_err());
+        assert!(RhodConfig::from_file("config.ini").is_err());
+        assert!(RhodConfig::from_toml("[log]\nlevel = \"loud\"").is_err());
+    }
+}
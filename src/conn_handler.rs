@@ -0,0 +1,32 @@
+// Handlers of connections, see Rhodium::with_conn_handler. They are called once per accepted TCP/TLS
+// connection instead of once per request, for connection tracking, per-connection limits or TLS
+// fingerprinting. on_connect runs before serving the connection, in its own task: a failing handler
+// closes it, and the following handlers arent called. on_disconnect is called on every handler once the
+// connection is closed, even when on_connect failed.
+use crate::errors::RhodResult;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::time::Duration;
+
+// What was served on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct ConnStats {
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub duration: Duration,
+}
+
+#[async_trait]
+pub trait RhodConnHandler: Send + Sync {
+    async fn on_connect(&self, _conn: &RhodConnInfo) -> RhodResult<()> {
+        Ok(())
+    }
+
+    async fn on_disconnect(&self, _conn: &RhodConnInfo, _stats: &ConnStats) {}
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
@@ -0,0 +1,139 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type AnyMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+// Typed map (one value per type) available on every request and response.
+// Lets independent handlers share data without agreeing on a single CommunicationChannel type.
+// The executor attaches the request context to the response, so values inserted while
+// handling the request are available while handling the response.
+// Cloning a RhodContext returns a handle to the same map.
+#[derive(Clone, Default)]
+pub struct RhodContext {
+    map: Arc<Mutex<AnyMap>>,
+}
+
+impl RhodContext {
+    pub fn new() -> RhodContext {
+        RhodContext::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AnyMap> {
+        // a panic while holding the lock cant leave a value half written, so poisoning is ignored
+        self.map.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Inserts a value, returning the previous value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        self.lock()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast::<T>().ok())
+            .map(|prev| *prev)
+    }
+
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock()
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref::<T>())
+            .cloned()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.lock().contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock()
+            .remove(&TypeId::of::<T>())
+            .and_then(|prev| prev.downcast::<T>().ok())
+            .map(|prev| *prev)
+    }
+
+    // Calls f with a mutable reference to the value of type T, if present
+    pub fn update<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.lock()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_mut::<T>())
+            .map(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    // Moves the values of other into this context (overwriting values of the same type),
+    // and makes other a handle to this context
+    pub(crate) fn absorb(&self, other: &mut RhodContext) {
+        if Arc::ptr_eq(&self.map, &other.map) {
+            return;
+        }
+
+        let values: Vec<_> = other.lock().drain().collect();
+        self.lock().extend(values);
+        *other = self.clone();
+    }
+}
+
+impl fmt::Debug for RhodContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RhodContext {{ {} values }}", self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Principal(String);
+
+    #[test]
+    fn test_context() {
+        let ctx = RhodContext::new();
+        assert!(ctx.is_empty());
+        assert_eq!(ctx.insert(Principal("alice".to_string())), None);
+        assert_eq!(ctx.insert(3u32), None);
+        assert_eq!(ctx.get::<Principal>(), Some(Principal("alice".to_string())));
+        assert!(ctx.contains::<u32>());
+        assert!(!ctx.contains::<u64>());
+
+        // clones share the values
+        let shared = ctx.clone();
+        assert_eq!(shared.update(|v: &mut u32| *v += 1), Some(()));
+        assert_eq!(ctx.get::<u32>(), Some(4));
+        assert_eq!(
+            shared.insert(Principal("bob".to_string())),
+            Some(Principal("alice".to_string()))
+        );
+        assert_eq!(
+            ctx.remove::<Principal>(),
+            Some(Principal("bob".to_string()))
+        );
+        assert_eq!(ctx.len(), 1);
+    }
+
+    #[test]
+    fn test_absorb() {
+        let ctx = RhodContext::new();
+        ctx.insert(1u8);
+        ctx.insert(1u16);
+
+        let mut other = RhodContext::new();
+        other.insert(2u16);
+        other.insert(2u32);
+
+        ctx.absorb(&mut other);
+        assert_eq!(ctx.get::<u8>(), Some(1));
+        assert_eq!(ctx.get::<u16>(), Some(2));
+        assert_eq!(ctx.get::<u32>(), Some(2));
+
+        other.insert(3u64);
+        assert_eq!(ctx.get::<u64>(), Some(3));
+    }
+}
@@ -0,0 +1,113 @@
+// Maintenance mode for zero-downtime deploys: while draining, new requests are answered with
+// 503 Service Unavailable and Retry-After (closing HTTP/1 connections, so load balancers move them),
+// while requests in flight finish normally. Exempt paths (ie: health checks) are still served.
+// The switch is toggled through a Drain handle (see Rhodium::drain) or the admin server.
+use crate::response::RhodResponse;
+use http::header::{CONNECTION, RETRY_AFTER};
+use http::{StatusCode, Version};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Clones share the switch
+#[derive(Debug, Clone)]
+pub struct Drain {
+    draining: Arc<AtomicBool>,
+    retry_after: Duration,
+    exempt: Arc<Vec<String>>, // path prefixes served while draining
+}
+
+impl Default for Drain {
+    fn default() -> Drain {
+        Drain {
+            draining: Arc::new(AtomicBool::new(false)),
+            retry_after: Duration::from_secs(30),
+            exempt: Arc::new(vec![]),
+        }
+    }
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain::default()
+    }
+
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        Drain {
+            retry_after,
+            ..self
+        }
+    }
+
+    // Requests under the path prefix are served while draining
+    pub fn with_exempt_path(self, prefix: &str) -> Self {
+        let mut exempt = self.exempt.to_vec();
+        exempt.push(prefix.to_string());
+        Drain {
+            exempt: Arc::new(exempt),
+            ..self
+        }
+    }
+
+    pub fn start(&self) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            info!("Draining: new requests are answered with 503");
+        }
+    }
+
+    pub fn stop(&self) {
+        if self.draining.swap(false, Ordering::SeqCst) {
+            info!("Draining stopped");
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // Response for a new request, if it isnt served because the server is draining
+    pub(crate) fn reject(&self, version: Version, path: &str) -> Option<RhodResponse> {
+        if !self.is_draining() || self.exempt.iter().any(|p| path.starts_with(p.as_str())) {
+            return None;
+        }
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(
+                RETRY_AFTER.as_str(),
+                &self.retry_after.as_secs().to_string(),
+            );
+        if version < Version::HTTP_2 {
+            res = res.header(CONNECTION.as_str(), "close");
+        }
+        res.build().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain() {
+        let drain = Drain::new()
+            .with_retry_after(Duration::from_secs(5))
+            .with_exempt_path("/health");
+        let switch = drain.clone();
+        assert!(drain.reject(Version::HTTP_11, "/").is_none());
+
+        switch.start();
+        let res = drain.reject(Version::HTTP_11, "/").unwrap();
+        assert_eq!(res.status_as_int(), 503);
+        assert_eq!(res.headers()[RETRY_AFTER], "5");
+        assert_eq!(res.headers()[CONNECTION], "close");
+        assert!(!drain
+            .reject(Version::HTTP_2, "/")
+            .unwrap()
+            .headers()
+            .contains_key(CONNECTION));
+        assert!(drain.reject(Version::HTTP_11, "/health/live").is_none());
+
+        switch.stop();
+        assert!(drain.reject(Version::HTTP_11, "/").is_none());
+    }
+}
@@ -0,0 +1,285 @@
+// Error pages rendered by the executor, see RhodStack::with_error_pages. Error responses (4xx, 5xx)
+// with an empty or plain text body get a page instead: the template registered for the status, else
+// the one of its class (4xx or 5xx), else a built-in one. Clients preferring JSON (Accept) get an
+// RFC 7807 problem detail. Errors without a response are answered with a 500 page.
+// Plain text bodies are kept as the detail of the error.
+//
+// HTML templates can use {{status}}, {{reason}}, {{detail}}, {{request_id}} and {{timestamp}},
+// replaced by their values (HTML escaped).
+use crate::body::RhodBody;
+use crate::handlers::RequestId;
+use crate::log_sink::iso8601;
+use crate::negotiation::Accept;
+use crate::response::RhodResponse;
+use http::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use http::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAX_DETAIL_SIZE: u64 = 4096;
+const DEFAULT_TEMPLATE: &str =
+    "<!DOCTYPE html>\n<html><head><title>{{status}} {{reason}}</title></head>\n\
+<body><h1>{{status}} {{reason}}</h1><p>{{detail}}</p>\n\
+<p><small>Request {{request_id}} at {{timestamp}}</small></p></body></html>\n";
+
+// What an error page shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorInfo {
+    pub status: StatusCode,
+    pub detail: Option<String>,     // plain text body of the response
+    pub request_id: Option<String>, // given by a RequestIdHandler
+    pub timestamp: String,          // ISO 8601
+}
+
+impl ErrorInfo {
+    fn reason(&self) -> &'static str {
+        self.status.canonical_reason().unwrap_or("Error")
+    }
+}
+
+#[derive(Clone)]
+pub enum ErrorTemplate {
+    Html(String),
+    Callback(Arc<dyn Fn(&ErrorInfo) -> String + Send + Sync>), // returns the HTML page
+}
+
+impl ErrorTemplate {
+    pub fn callback<F>(callback: F) -> ErrorTemplate
+    where
+        F: Fn(&ErrorInfo) -> String + Send + Sync + 'static,
+    {
+        ErrorTemplate::Callback(Arc::new(callback))
+    }
+
+    fn render(&self, info: &ErrorInfo) -> String {
+        match self {
+            ErrorTemplate::Html(template) => render_html(template, info),
+            ErrorTemplate::Callback(callback) => callback(info),
+        }
+    }
+}
+
+impl fmt::Debug for ErrorTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorTemplate::Html(template) => f.debug_tuple("Html").field(template).finish(),
+            ErrorTemplate::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_html(template: &str, info: &ErrorInfo) -> String {
+    template
+        .replace("{{status}}", info.status.as_str())
+        .replace("{{reason}}", &escape_html(info.reason()))
+        .replace(
+            "{{detail}}",
+            &escape_html(info.detail.as_deref().unwrap_or("")),
+        )
+        .replace(
+            "{{request_id}}",
+            &escape_html(info.request_id.as_deref().unwrap_or("-")),
+        )
+        .replace("{{timestamp}}", &info.timestamp)
+}
+
+// RFC 7807 problem detail, with the request id and timestamp as extensions
+fn problem(info: &ErrorInfo) -> serde_json::Value {
+    let mut problem = json!({
+        "type": "about:blank",
+        "title": info.reason(),
+        "status": info.status.as_u16(),
+        "timestamp": info.timestamp,
+    });
+    if let Some(detail) = &info.detail {
+        problem["detail"] = json!(detail);
+    }
+    if let Some(request_id) = &info.request_id {
+        problem["request_id"] = json!(request_id);
+    }
+    problem
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    statuses: HashMap<u16, ErrorTemplate>,
+    classes: HashMap<u16, ErrorTemplate>, // 4 or 5
+}
+
+impl ErrorPages {
+    pub fn new() -> ErrorPages {
+        ErrorPages::default()
+    }
+
+    pub fn with_page(mut self, status: StatusCode, template: ErrorTemplate) -> Self {
+        self.statuses.insert(status.as_u16(), template);
+        self
+    }
+
+    // Page of the statuses of a class without their own page, ie: 5 for 5xx
+    pub fn with_class_page(mut self, class: u16, template: ErrorTemplate) -> Self {
+        self.classes.insert(class, template);
+        self
+    }
+
+    fn template(&self, status: StatusCode) -> Option<&ErrorTemplate> {
+        let status = status.as_u16();
+        self.statuses
+            .get(&status)
+            .or_else(|| self.classes.get(&(status / 100)))
+    }
+
+    // 500 for errors without a response
+    pub(crate) fn internal_error() -> RhodResponse {
+        let mut res = http::Response::new(RhodBody::empty());
+        *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        RhodResponse::new(res)
+    }
+
+    // Replaces empty or plain text bodies of error responses (only empty ones with body passthrough)
+    pub(crate) async fn render(&self, res: &mut RhodResponse, accept: &Accept, passthrough: bool) {
+        let status = match StatusCode::from_u16(res.status_as_int()) {
+            Ok(status) if status.as_u16() >= 400 => status,
+            _ => return,
+        };
+        let plain_text = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/plain"));
+        let detail = match res.body_size_hint() {
+            Some(0) => None,
+            Some(size) if plain_text && size <= MAX_DETAIL_SIZE && !passthrough => {
+                match res.body_bytes().await {
+                    Ok(body) => Some(String::from_utf8_lossy(&body).trim().to_string()),
+                    Err(_) => None,
+                }
+            }
+            _ => return,
+        };
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let info = ErrorInfo {
+            status,
+            detail,
+            request_id: res.context().get::<RequestId>().map(|ids| ids.id),
+            timestamp: iso8601(millis),
+        };
+
+        let available = [
+            mime::TEXT_HTML,
+            "application/problem+json".parse().unwrap(),
+            mime::APPLICATION_JSON,
+        ];
+        let json = accept
+            .best_match(&available)
+            .is_some_and(|best| best.subtype() == mime::JSON || best.suffix() == Some(mime::JSON));
+        let (content_type, body) = if json {
+            ("application/problem+json", problem(&info).to_string())
+        } else {
+            let page = match self.template(status) {
+                Some(template) => template.render(&info),
+                None => render_html(DEFAULT_TEMPLATE, &info),
+            };
+            ("text/html; charset=utf-8", page)
+        };
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        res.set_body(RhodBody::from(body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn render(pages: &ErrorPages, res: &mut RhodResponse, accept: &str) -> String {
+        pages.render(res, &Accept::parse(accept), false).await;
+        String::from_utf8(res.body().await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_pages() {
+        let pages = ErrorPages::new()
+            .with_page(
+                StatusCode::NOT_FOUND,
+                ErrorTemplate::Html("<h1>{{reason}}</h1>{{detail}}".to_string()),
+            )
+            .with_class_page(
+                5,
+                ErrorTemplate::callback(|info| format!("down {}", info.status.as_u16())),
+            );
+
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain")
+            .body_str("no <such> page")
+            .build()
+            .unwrap();
+        res.context().insert(RequestId {
+            id: "abc".to_string(),
+            trace_id: None,
+        });
+        let page = render(&pages, &mut res, "text/html").await;
+        assert_eq!(page, "<h1>Not Found</h1>no &lt;such&gt; page");
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .build()
+            .unwrap();
+        assert_eq!(render(&pages, &mut res, "*/*").await, "down 502");
+
+        let mut res = ErrorPages::internal_error();
+        res.context().insert(RequestId {
+            id: "abc".to_string(),
+            trace_id: None,
+        });
+        let body = render(&pages, &mut res, "application/json").await;
+        let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(problem["status"], 500);
+        assert_eq!(problem["title"], "Internal Server Error");
+        assert_eq!(problem["request_id"], "abc");
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+
+        // bodies of other types are kept
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header("Content-Type", "application/json")
+            .body_str("{}")
+            .build()
+            .unwrap();
+        assert_eq!(render(&pages, &mut res, "*/*").await, "{}");
+
+        // with body passthrough the body isnt read
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain")
+            .body_str("no such page")
+            .build()
+            .unwrap();
+        pages.render(&mut res, &Accept::parse("*/*"), true).await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+    }
+}
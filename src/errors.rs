@@ -1,3 +1,5 @@
+use crate::response::RhodResponse;
+use http::StatusCode;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -21,20 +23,65 @@ pub enum RhodErrorLevel {
 pub struct RhodError {
     msg: String,
     level: RhodErrorLevel,
+    response: Option<Box<RhodResponse>>, // if Some, the flow is ended answering with this response
 }
 
 impl RhodError {
     pub fn from_string(msg: String, level: RhodErrorLevel) -> RhodError {
-        RhodError { msg, level }
+        RhodError {
+            msg,
+            level,
+            response: None,
+        }
     }
 
     pub fn from_str(msg: &str, level: RhodErrorLevel) -> RhodError {
         RhodError {
             msg: String::from(msg),
             level,
+            response: None,
+        }
+    }
+
+    // Ends the flow answering with res instead of failing (ie: a response served from a cache, a 401).
+    // Handlers that already handled the request get handle_response called with res, in reverse order.
+    pub fn from_response(res: RhodResponse) -> RhodError {
+        RhodError {
+            msg: format!("Answered early with status {}", res.status_as_int()),
+            level: RhodErrorLevel::Debug,
+            response: Some(Box::new(res)),
+        }
+    }
+
+    pub fn with_response(self, res: RhodResponse) -> RhodError {
+        RhodError {
+            response: Some(Box::new(res)),
+            ..self
+        }
+    }
+
+    // Ends the flow answering with an empty response with status (ie: a 403 from an authorization handler)
+    pub fn with_status(status: StatusCode, msg: String, level: RhodErrorLevel) -> RhodError {
+        RhodError::from_string(msg, level)
+            .with_built(RhodResponse::builder().status(status).build())
+    }
+
+    // Ends the flow answering with a built response, or fails with the build error if it couldnt be built
+    pub fn with_built(self, res: RhodResult<RhodResponse>) -> RhodError {
+        match res {
+            Ok(res) => self.with_response(res),
+            Err(e) => e,
         }
     }
 
+    pub fn response(&self) -> Option<&RhodResponse> {
+        self.response.as_deref()
+    }
+
+    pub fn take_response(&mut self) -> Option<RhodResponse> {
+        self.response.take().map(|res| *res)
+    }
+
     pub fn log(&self) {
         match self.level {
             RhodErrorLevel::Warning => warn!("{}", self),
@@ -0,0 +1,79 @@
+// Built-in handlers ready to be added to a RhodStack
+mod access_log;
+pub use access_log::AccessLogHandler;
+mod api_key;
+pub use api_key::{ApiKey, ApiKeyHandler, CachedKeyStore, KeySource, KeyStore, StaticKeyStore};
+mod auth;
+pub use auth::{
+    BasicAuthHandler, BearerAuthHandler, CredentialVerifier, Principal, TokenValidator,
+};
+#[cfg(feature = "ldap")]
+pub use auth::{LdapBind, LdapGroups, LdapOutage, LdapVerifier};
+mod authorization;
+pub use authorization::{AccessRule, AuthorizationDecision, AuthorizationHandler};
+mod bot_detection;
+pub use bot_detection::{BotAction, BotDetectionHandler, BotScore};
+mod body_rewrite;
+#[cfg(feature = "plugins")]
+pub(crate) use body_rewrite::fix_length_headers;
+pub use body_rewrite::{BodyRewrite, BodyRewriteHandler, BufferedRewrite, StreamingRewrite};
+mod cache;
+#[cfg(feature = "redis-cache")]
+pub use cache::RedisCacheStore;
+pub use cache::{cache_key, CacheHandler, CacheStore, CachedResponse, MemoryCacheStore};
+mod conditional;
+mod conditional_get;
+pub use conditional::{ConditionalHandler, PassThroughHandler, RequestPredicate};
+pub use conditional_get::{compute_etag, ConditionalGetHandler, EtagKind};
+mod dynamic;
+pub use dynamic::{CachedDynamicHandler, HandlerResolver, ResolutionKey};
+mod expect;
+pub use expect::ExpectContinueHandler;
+mod ext_authz;
+pub use ext_authz::{ExtAuthzFailure, ExtAuthzHandler, ExtAuthzService};
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "geoip")]
+pub use geoip::{GeoInfo, GeoIpDb, GeoIpHandler, GeoList};
+mod graphql;
+pub use graphql::{GraphQlHandler, GraphQlQuery};
+mod header_rules;
+pub use header_rules::{HeaderAction, HeaderRule, HeaderRulesHandler};
+mod html_injection;
+pub use html_injection::{HtmlInjectionHandler, InjectPosition};
+#[cfg(feature = "icap")]
+mod icap;
+#[cfg(feature = "icap")]
+pub use icap::{IcapAdaptation, IcapClient, IcapFailure, IcapHandler};
+mod idempotency;
+pub use idempotency::{
+    IdempotencyEntry, IdempotencyHandler, IdempotencyStore, MemoryIdempotencyStore,
+};
+#[cfg(feature = "lua")]
+mod lua;
+#[cfg(feature = "lua")]
+pub use lua::{LuaHandler, LuaLimits, LuaScript};
+mod methods;
+pub use methods::MethodsHandler;
+mod normalize;
+pub use normalize::{normalize_path, NormalizeHandler, OriginalUri};
+#[cfg(feature = "openapi")]
+mod openapi;
+#[cfg(feature = "openapi")]
+pub use openapi::{OpenApiHandler, OpenApiOperation, ResponseValidation};
+mod priority;
+pub use priority::PriorityHandler;
+mod request_id;
+pub use request_id::{RequestId, RequestIdHandler, RequestIdTrust};
+#[cfg(feature = "saml")]
+mod saml;
+#[cfg(feature = "saml")]
+pub use saml::{SamlHandler, SamlIdp, SamlServiceProvider, SamlSession};
+mod scan;
+pub use scan::{ScanHandler, ScanStats, ScannerFactory};
+mod signature;
+pub use signature::{SignatureAlgorithm, SignatureEncoding, SignatureHandler, SignatureScheme};
+mod tee;
+pub use tee::{TeeConsumer, TeeHandler, TeeStats, TeedResponse};
+mod traffic_split;
+pub use traffic_split::{Stickiness, TrafficSplitHandler, TrafficVariant};
@@ -0,0 +1,171 @@
+use crate::admission::Priority;
+use crate::errors::{RhodError, RhodResult};
+use crate::handlers::{AuthorizationDecision, RequestId};
+use crate::log_sink::{iso8601, LogSink};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::tenancy::TenantId;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::REFERER;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Request part of an access log record, saved in the request context
+struct PendingAccess {
+    record: Value,
+    started: Instant,
+}
+
+// Writes a record per answered request to the sink: time, client, request line, user agent, referer,
+// status, duration and size of the body (when known), the id given by a RequestIdHandler, the
+// priority given by a PriorityHandler, the tenant given by a MultiTenantStack and the decision of an
+// AuthorizationHandler.
+// Added first, it logs the response as sent
+pub struct AccessLogHandler {
+    sink: Arc<dyn LogSink>,
+}
+
+impl AccessLogHandler {
+    pub fn new(sink: Arc<dyn LogSink>) -> AccessLogHandler {
+        AccessLogHandler { sink }
+    }
+
+    async fn write(&self, res: &RhodResponse, failed: bool) {
+        let PendingAccess {
+            mut record,
+            started,
+        } = match res.context().remove::<PendingAccess>() {
+            Some(pending) => pending,
+            None => return,
+        };
+        record["status"] = json!(res.status_as_int());
+        record["duration_ms"] = json!(started.elapsed().as_secs_f64() * 1000.0);
+        record["size"] = json!(res.body_size_hint());
+        if let Some(request_id) = res.context().get::<RequestId>() {
+            record["request_id"] = json!(request_id.id);
+        }
+        if let Some(priority) = res.context().get::<Priority>() {
+            record["priority"] = json!(priority.as_str());
+        }
+        if let Some(TenantId(tenant)) = res.context().get::<TenantId>() {
+            record["tenant"] = json!(tenant);
+        }
+        if let Some(decision) = res.context().get::<AuthorizationDecision>() {
+            record["authorization"] = json!(decision);
+        }
+        if failed {
+            record["failed"] = json!(true);
+        }
+        if let Err(e) = self.sink.write(&record).await {
+            warn!("Couldnt write access log record. {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for AccessLogHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let record = json!({
+            "type": "access",
+            "time": iso8601(millis),
+            "client": conn.addr.to_string(),
+            "method": req.method_str(),
+            "uri": req.uri().to_string(),
+            "version": req.version_string(),
+            "user_agent": req.user_agent(),
+            "referer": req.header_str(REFERER),
+        });
+        req.context().insert(PendingAccess {
+            record,
+            started: Instant::now(),
+        });
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        self.write(&res, false).await;
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+        self.write(res, true).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemorySink {
+        records: Mutex<Vec<Value>>,
+    }
+
+    #[async_trait]
+    impl LogSink for MemorySink {
+        async fn write(&self, record: &Value) -> std::io::Result<()> {
+            self.records.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let sink = Arc::new(MemorySink::default());
+        let handler = AccessLogHandler::new(sink.clone());
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let mut req = RhodRequest::builder()
+            .uri("/index.html?q=1")
+            .header("User-Agent", "agent/1.0")
+            .build()
+            .unwrap();
+        RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap();
+        let mut res = RhodResponse::builder().body_str("hello").build().unwrap();
+        res.attach_context(req.context());
+        let (_, result) = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        result.unwrap();
+
+        let records = sink.records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["client"], "10.0.0.1:4000");
+        assert_eq!(records[0]["uri"], "/index.html?q=1");
+        assert_eq!(records[0]["user_agent"], "agent/1.0");
+        assert_eq!(records[0]["status"], 200);
+        assert_eq!(records[0]["size"], 5);
+    }
+}
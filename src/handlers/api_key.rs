@@ -0,0 +1,298 @@
+use super::auth::{audit_failure, Principal};
+use crate::audit::AuditLog;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::util::unix_now;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::HeaderName;
+use http::uri::PathAndQuery;
+use http::{StatusCode, Uri};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+mod store;
+pub use store::{CachedKeyStore, StaticKeyStore};
+
+// Metadata of an API key, saved in the request context by ApiKeyHandler
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiKey {
+    pub id: String, // names the key in logs and audits, never the key itself
+    pub tenant: Option<String>,
+    pub scopes: Vec<String>,
+    pub revoked: bool,
+    pub expires: Option<u64>, // unix secs
+}
+
+impl ApiKey {
+    pub fn new(id: &str) -> ApiKey {
+        ApiKey {
+            id: id.to_string(),
+            ..ApiKey::default()
+        }
+    }
+
+    pub fn with_tenant(self, tenant: &str) -> Self {
+        ApiKey {
+            tenant: Some(tenant.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_scopes(self, scopes: &[&str]) -> Self {
+        ApiKey {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            ..self
+        }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    fn expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_now())
+    }
+}
+
+// Looks up the metadata of an API key, None for unknown keys. Revoked keys may be returned as revoked,
+// so their use is told apart in the audit log
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    async fn lookup(&self, key: &str) -> RhodResult<Option<ApiKey>>;
+}
+
+// Callbacks are stores too (ie: a database query)
+#[async_trait]
+impl<F> KeyStore for F
+where
+    F: Fn(&str) -> Option<ApiKey> + Send + Sync,
+{
+    async fn lookup(&self, key: &str) -> RhodResult<Option<ApiKey>> {
+        Ok(self(key))
+    }
+}
+
+// Where the key is taken from, the first source with a key is used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    Header(HeaderName),
+    Query(String), // parameter of the query string
+}
+
+impl KeySource {
+    fn key(&self, req: &RhodRequest) -> Option<String> {
+        match self {
+            KeySource::Header(name) => req.header_str(name).map(|key| key.trim().to_string()),
+            KeySource::Query(param) => req.uri().query()?.split('&').find_map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                if name != param {
+                    return None;
+                }
+                percent_decode_str(value)
+                    .decode_utf8()
+                    .map(|v| v.into_owned())
+                    .ok()
+            }),
+        }
+        .filter(|key| !key.is_empty())
+    }
+
+    fn strip(&self, req: &mut RhodRequest) {
+        match self {
+            KeySource::Header(name) => {
+                req.headers_mut().remove(name);
+            }
+            KeySource::Query(param) => {
+                let query = match req.uri().query() {
+                    Some(query) => query,
+                    None => return,
+                };
+                let kept: Vec<&str> = query
+                    .split('&')
+                    .filter(|pair| pair.split('=').next() != Some(param.as_str()))
+                    .collect();
+                let path_and_query = match kept.is_empty() {
+                    true => req.uri().path().to_string(),
+                    false => format!("{}?{}", req.uri().path(), kept.join("&")),
+                };
+                let mut parts = req.uri().clone().into_parts();
+                parts.path_and_query = path_and_query.parse::<PathAndQuery>().ok();
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+            }
+        }
+    }
+}
+
+fn unauthorized(msg: &str) -> RhodError {
+    let err = RhodError::from_string(format!("Unauthorized. {}", msg), RhodErrorLevel::Warning);
+    err.with_built(
+        RhodResponse::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body_str(msg)
+            .build(),
+    )
+}
+
+// Short-circuits requests without a valid API key with a 401: missing, unknown, revoked or expired.
+// The key is taken from the X-Api-Key header by default. Its metadata is saved in the request context
+// as ApiKey, and as a Principal with the key id and its scopes as roles.
+pub struct ApiKeyHandler {
+    store: Box<dyn KeyStore>,
+    sources: Vec<KeySource>,
+    strip: bool,
+    audit: Option<AuditLog>,
+}
+
+impl ApiKeyHandler {
+    pub fn new(store: Box<dyn KeyStore>) -> ApiKeyHandler {
+        ApiKeyHandler {
+            store,
+            sources: vec![KeySource::Header(HeaderName::from_static("x-api-key"))],
+            strip: false,
+            audit: None,
+        }
+    }
+
+    // Sources of the key, in the order they are tried
+    pub fn with_sources(self, sources: Vec<KeySource>) -> Self {
+        ApiKeyHandler { sources, ..self }
+    }
+
+    // Removes the key from the request once validated, so it isnt forwarded nor logged
+    pub fn strip_key(self) -> Self {
+        ApiKeyHandler {
+            strip: true,
+            ..self
+        }
+    }
+
+    // Writes the rejected keys to the audit log
+    pub fn with_audit(self, audit: AuditLog) -> Self {
+        ApiKeyHandler {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    async fn reject(&self, conn: &RhodConnInfo, reason: &str) -> RhodError {
+        audit_failure(&self.audit, conn, "api-key", reason).await;
+        unauthorized(reason)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for ApiKeyHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let (source, key) = match self
+            .sources
+            .iter()
+            .find_map(|source| source.key(req).map(|key| (source, key)))
+        {
+            Some(found) => found,
+            None => return Err(unauthorized("Missing API key")),
+        };
+        let api_key = match self.store.lookup(&key).await? {
+            Some(api_key) => api_key,
+            None => return Err(self.reject(conn, "Unknown API key").await),
+        };
+        if api_key.revoked {
+            let reason = format!("Revoked API key {}", api_key.id);
+            return Err(self.reject(conn, &reason).await);
+        }
+        if api_key.expired() {
+            let reason = format!("Expired API key {}", api_key.id);
+            return Err(self.reject(conn, &reason).await);
+        }
+        if self.strip {
+            source.strip(req);
+        }
+        let scopes: Vec<&str> = api_key.scopes.iter().map(String::as_str).collect();
+        req.context()
+            .insert(Principal::new(&api_key.id).with_roles(&scopes));
+        req.context().insert(api_key);
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[tokio::test]
+    async fn test_api_key_handler() {
+        let store = StaticKeyStore::new()
+            .with_key("k-live", ApiKey::new("billing").with_scopes(&["read"]))
+            .with_key(
+                "k-old",
+                ApiKey {
+                    revoked: true,
+                    ..ApiKey::new("legacy")
+                },
+            );
+        let handler = ApiKeyHandler::new(Box::new(store))
+            .with_sources(vec![
+                KeySource::Header(HeaderName::from_static("x-api-key")),
+                KeySource::Query("api_key".to_string()),
+            ])
+            .strip_key();
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let handle = |uri: &str| {
+            let mut req = RhodRequest::builder().uri(uri).build().unwrap();
+            let (handler, conn) = (&handler, &conn);
+            async move {
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ())
+                    .await
+                    .map(|_| req)
+            }
+        };
+
+        let req = handle("/items?page=1&api_key=k-live").await.unwrap();
+        assert_eq!(req.uri().to_string(), "/items?page=1");
+        assert!(req.context().get::<ApiKey>().unwrap().has_scope("read"));
+        assert_eq!(req.context().get::<Principal>().unwrap().id, "billing");
+
+        for uri in ["/items", "/items?api_key=k-old", "/items?api_key=other"] {
+            let err = handle(uri).await.unwrap_err();
+            assert_eq!(err.response().unwrap().status_as_int(), 401);
+        }
+    }
+}
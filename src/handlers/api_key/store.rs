@@ -0,0 +1,183 @@
+use super::{ApiKey, KeyStore};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::util::{sha256_hex, Lookups};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Key of a keys file
+#[derive(Deserialize)]
+struct KeyEntry {
+    key_sha256: String, // hex
+    #[serde(flatten)]
+    api_key: ApiKey,
+}
+
+// Keys known in advance, given by code or read from a JSON file listing them by their SHA-256:
+//
+//  [{"key_sha256": "9f86d0...", "id": "billing", "tenant": "acme", "scopes": ["read"]}]
+//
+// Keys are kept by their SHA-256, so neither the files nor the memory hold them
+#[derive(Default)]
+pub struct StaticKeyStore {
+    keys: HashMap<String, ApiKey>,
+}
+
+impl StaticKeyStore {
+    pub fn new() -> StaticKeyStore {
+        StaticKeyStore::default()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> RhodResult<StaticKeyStore> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            RhodError::from_string(
+                format!("Couldnt read keys file {}. {}", path.display(), e),
+                RhodErrorLevel::Error,
+            )
+        })?;
+        StaticKeyStore::from_json(&content)
+    }
+
+    pub fn from_json(content: &str) -> RhodResult<StaticKeyStore> {
+        let entries: Vec<KeyEntry> = serde_json::from_str(content).map_err(|e| {
+            RhodError::from_string(format!("Invalid keys file. {}", e), RhodErrorLevel::Error)
+        })?;
+        let keys = entries
+            .into_iter()
+            .map(|entry| (entry.key_sha256.to_ascii_lowercase(), entry.api_key))
+            .collect();
+        Ok(StaticKeyStore { keys })
+    }
+
+    pub fn with_key(mut self, key: &str, api_key: ApiKey) -> Self {
+        self.keys.insert(sha256_hex(key.as_bytes()), api_key);
+        self
+    }
+}
+
+#[async_trait]
+impl KeyStore for StaticKeyStore {
+    async fn lookup(&self, key: &str) -> RhodResult<Option<ApiKey>> {
+        Ok(self.keys.get(&sha256_hex(key.as_bytes())).cloned())
+    }
+}
+
+// Caches the lookups of a remote store (ie: an identity service) for a ttl, unknown keys included so
+// bad keys dont hit the store on every request. Failed lookups arent cached.
+// Unknown keys are kept apart, so a flood of bad keys cant evict the valid ones.
+// Revoking a key takes up to the ttl to be seen.
+pub struct CachedKeyStore {
+    inner: Box<dyn KeyStore>,
+    ttl: Duration,
+    known: Mutex<Lookups<ApiKey>>,
+    unknown: Mutex<Lookups<()>>,
+}
+
+impl CachedKeyStore {
+    pub fn new(inner: Box<dyn KeyStore>) -> CachedKeyStore {
+        CachedKeyStore {
+            inner,
+            ttl: Duration::from_secs(60),
+            known: Mutex::new(Lookups::new(10_000)),
+            unknown: Mutex::new(Lookups::new(1_000)),
+        }
+    }
+
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        CachedKeyStore { ttl, ..self }
+    }
+
+    // Max number of cached keys, the oldest one is evicted when it is reached
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        CachedKeyStore {
+            known: Mutex::new(Lookups::new(capacity)),
+            ..self
+        }
+    }
+
+    // Max number of cached unknown keys
+    pub fn with_unknown_capacity(self, capacity: usize) -> Self {
+        CachedKeyStore {
+            unknown: Mutex::new(Lookups::new(capacity)),
+            ..self
+        }
+    }
+
+    pub fn cached(&self) -> usize {
+        self.known.lock().unwrap().len() + self.unknown.lock().unwrap().len()
+    }
+}
+
+#[async_trait]
+impl KeyStore for CachedKeyStore {
+    async fn lookup(&self, key: &str) -> RhodResult<Option<ApiKey>> {
+        let hash = sha256_hex(key.as_bytes());
+        if let Some(api_key) = self.known.lock().unwrap().get(&hash, Some(self.ttl)) {
+            return Ok(Some(api_key));
+        }
+        if self
+            .unknown
+            .lock()
+            .unwrap()
+            .get(&hash, Some(self.ttl))
+            .is_some()
+        {
+            return Ok(None);
+        }
+        let api_key = self.inner.lookup(key).await?;
+        match &api_key {
+            Some(api_key) => self.known.lock().unwrap().insert(hash, api_key.clone()),
+            None => self.unknown.lock().unwrap().insert(hash, ()),
+        }
+        Ok(api_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingStore {
+        inner: StaticKeyStore,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl KeyStore for CountingStore {
+        async fn lookup(&self, key: &str) -> RhodResult<Option<ApiKey>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.inner.lookup(key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_key_store() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let inner = CountingStore {
+            inner: StaticKeyStore::new().with_key("k-live", ApiKey::new("billing")),
+            lookups: Arc::clone(&lookups),
+        };
+        let store = CachedKeyStore::new(Box::new(inner))
+            .with_capacity(2)
+            .with_unknown_capacity(2);
+
+        assert!(store.lookup("k-live").await.unwrap().is_some());
+        // a flood of unknown keys doesnt evict the known ones
+        for i in 0..10 {
+            assert!(store.lookup(&format!("bad-{}", i)).await.unwrap().is_none());
+        }
+        assert_eq!(store.cached(), 3);
+        assert!(store.lookup("k-live").await.unwrap().is_some());
+        assert!(store.lookup("bad-9").await.unwrap().is_none());
+        assert_eq!(lookups.load(Ordering::SeqCst), 11);
+        // the oldest unknown keys were evicted
+        assert!(store.lookup("bad-0").await.unwrap().is_none());
+        assert_eq!(lookups.load(Ordering::SeqCst), 12);
+    }
+}
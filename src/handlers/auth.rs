@@ -0,0 +1,394 @@
+use crate::audit::{AuditEvent, AuditLog};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use base64::Engine;
+use http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use http::StatusCode;
+
+#[cfg(feature = "ldap")]
+mod ldap;
+#[cfg(feature = "ldap")]
+pub use ldap::{LdapBind, LdapGroups, LdapOutage, LdapVerifier};
+
+// Authenticated user, saved in the request context by the auth handlers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub id: String,
+    pub roles: Vec<String>,
+}
+
+impl Principal {
+    pub fn new(id: &str) -> Principal {
+        Principal {
+            id: id.to_string(),
+            roles: vec![],
+        }
+    }
+
+    pub fn with_roles(self, roles: &[&str]) -> Principal {
+        Principal {
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            ..self
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+// Checks a user and password, returning the principal if they are valid
+#[async_trait]
+pub trait CredentialVerifier: Send + Sync {
+    async fn verify(&self, user: &str, password: &str) -> Option<Principal>;
+}
+
+#[async_trait]
+impl<F> CredentialVerifier for F
+where
+    F: Fn(&str, &str) -> Option<Principal> + Send + Sync,
+{
+    async fn verify(&self, user: &str, password: &str) -> Option<Principal> {
+        self(user, password)
+    }
+}
+
+// Checks a bearer token, returning the principal if it is valid
+#[async_trait]
+pub trait TokenValidator: Send + Sync {
+    async fn validate(&self, token: &str) -> Option<Principal>;
+}
+
+#[async_trait]
+impl<F> TokenValidator for F
+where
+    F: Fn(&str) -> Option<Principal> + Send + Sync,
+{
+    async fn validate(&self, token: &str) -> Option<Principal> {
+        self(token)
+    }
+}
+
+// Credentials of the Authorization header for the given scheme (case insensitive)
+fn credentials<'a>(req: &'a RhodRequest, scheme: &str) -> Option<&'a str> {
+    let value = req.header_str(AUTHORIZATION)?.trim();
+    let (req_scheme, credentials) = value.split_at(value.find(' ')?);
+    if req_scheme.eq_ignore_ascii_case(scheme) {
+        Some(credentials.trim())
+    } else {
+        None
+    }
+}
+
+// Audit event of a failed authentication
+pub(super) async fn audit_failure(
+    audit: &Option<AuditLog>,
+    conn: &RhodConnInfo,
+    realm: &str,
+    reason: &str,
+) {
+    if let Some(audit) = audit {
+        audit
+            .emit(
+                AuditEvent::new("auth_failure")
+                    .with_client(&conn.addr.to_string())
+                    .with_detail("realm", realm)
+                    .with_detail("reason", reason),
+            )
+            .await;
+    }
+}
+
+fn unauthorized(challenge: &str, msg: &str) -> RhodError {
+    let err = RhodError::from_string(format!("Unauthorized. {}", msg), RhodErrorLevel::Warning);
+    err.with_built(
+        RhodResponse::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE.as_str(), challenge)
+            .build(),
+    )
+}
+
+// Short-circuits requests without valid Basic credentials with a 401 and a WWW-Authenticate challenge.
+// The principal returned by the verifier is saved in the request context.
+pub struct BasicAuthHandler {
+    realm: String,
+    verifier: Box<dyn CredentialVerifier>,
+    audit: Option<AuditLog>,
+}
+
+impl BasicAuthHandler {
+    pub fn new(realm: &str, verifier: Box<dyn CredentialVerifier>) -> BasicAuthHandler {
+        BasicAuthHandler {
+            realm: realm.to_string(),
+            verifier,
+            audit: None,
+        }
+    }
+
+    // Writes the failed authentications to the audit log
+    pub fn with_audit(self, audit: AuditLog) -> Self {
+        BasicAuthHandler {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for BasicAuthHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let decoded = credentials(req, "Basic")
+            .and_then(|c| base64::engine::general_purpose::STANDARD.decode(c).ok())
+            .and_then(|c| String::from_utf8(c).ok());
+        let (user, password) = match decoded.as_ref().and_then(|c| c.split_once(':')) {
+            Some(credentials) => credentials,
+            None => return Err(unauthorized(&self.challenge(), "Missing basic credentials")),
+        };
+
+        match self.verifier.verify(user, password).await {
+            Some(principal) => {
+                req.context().insert(principal);
+                Ok(())
+            }
+            None => {
+                let reason = format!("Invalid credentials for user {}", user);
+                audit_failure(&self.audit, conn, &self.realm, &reason).await;
+                Err(unauthorized(&self.challenge(), &reason))
+            }
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+// Short-circuits requests without a valid Bearer token with a 401 and a WWW-Authenticate challenge.
+// The principal returned by the validator is saved in the request context.
+pub struct BearerAuthHandler {
+    realm: String,
+    validator: Box<dyn TokenValidator>,
+    audit: Option<AuditLog>,
+}
+
+impl BearerAuthHandler {
+    pub fn new(realm: &str, validator: Box<dyn TokenValidator>) -> BearerAuthHandler {
+        BearerAuthHandler {
+            realm: realm.to_string(),
+            validator,
+            audit: None,
+        }
+    }
+
+    // Writes the failed authentications to the audit log
+    pub fn with_audit(self, audit: AuditLog) -> Self {
+        BearerAuthHandler {
+            audit: Some(audit),
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for BearerAuthHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let token = match credentials(req, "Bearer") {
+            Some(token) if !token.is_empty() => token,
+            _ => {
+                let challenge = format!("Bearer realm=\"{}\"", self.realm);
+                return Err(unauthorized(&challenge, "Missing bearer token"));
+            }
+        };
+
+        match self.validator.validate(token).await {
+            Some(principal) => {
+                req.context().insert(principal);
+                Ok(())
+            }
+            None => {
+                audit_failure(&self.audit, conn, &self.realm, "Invalid bearer token").await;
+                let challenge = format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm);
+                Err(unauthorized(&challenge, "Invalid bearer token"))
+            }
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandlerInStack, RhodService, RhodStack};
+    use crate::CommunicationChannel;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // Answers with the id of the principal
+    struct WhoAmIService {}
+    #[async_trait]
+    impl RhodService<Comm> for WhoAmIService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let principal = req.context().get::<Principal>().unwrap();
+            RhodResponse::builder().body_str(&principal.id).build()
+        }
+    }
+
+    async fn run(stack: &RhodStack<Comm>, authorization: Option<&str>) -> RhodResponse {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let builder = RhodRequest::builder();
+        let builder = match authorization {
+            Some(value) => builder.header("Authorization", value),
+            None => builder,
+        };
+        stack
+            .execute(&conn, builder.build().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let verifier = |user: &str, password: &str| {
+            if user == "alice" && password == "secret:1" {
+                Some(Principal::new(user).with_roles(&["admin"]))
+            } else {
+                None
+            }
+        };
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(
+                BasicAuthHandler::new("api", Box::new(verifier)),
+            ))],
+            Box::new(WhoAmIService {}),
+        );
+
+        // alice:secret:1
+        let mut res = run(&stack, Some("basic YWxpY2U6c2VjcmV0OjE=")).await;
+        assert_eq!(res.status_as_int(), 200);
+        assert_eq!(res.body().await.unwrap(), b"alice".to_vec());
+
+        // alice:wrong
+        let res = run(&stack, Some("Basic YWxpY2U6d3Jvbmc=")).await;
+        assert_eq!(res.status_as_int(), 401);
+        assert_eq!(
+            res.headers().get("WWW-Authenticate").unwrap(),
+            "Basic realm=\"api\", charset=\"UTF-8\""
+        );
+
+        let res = run(&stack, None).await;
+        assert_eq!(res.status_as_int(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_auth() {
+        let validator = |token: &str| {
+            if token == "t0k3n" {
+                Some(Principal::new("service-a"))
+            } else {
+                None
+            }
+        };
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(
+                BearerAuthHandler::new("api", Box::new(validator)),
+            ))],
+            Box::new(WhoAmIService {}),
+        );
+
+        let mut res = run(&stack, Some("Bearer t0k3n")).await;
+        assert_eq!(res.body().await.unwrap(), b"service-a".to_vec());
+
+        let res = run(&stack, Some("Bearer other")).await;
+        assert_eq!(res.status_as_int(), 401);
+        assert_eq!(
+            res.headers().get("WWW-Authenticate").unwrap(),
+            "Bearer realm=\"api\", error=\"invalid_token\""
+        );
+
+        let res = run(&stack, Some("Basic t0k3n")).await;
+        assert_eq!(res.status_as_int(), 401);
+        assert_eq!(
+            res.headers().get("WWW-Authenticate").unwrap(),
+            "Bearer realm=\"api\""
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use super::{CredentialVerifier, Principal};
+use crate::runtime;
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use log::{debug, warn};
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+// Result code of a bind with wrong credentials
+const INVALID_CREDENTIALS: u32 = 49;
+
+// How the DN of a user is found. {user} is replaced by the escaped user name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LdapBind {
+    // DN built from a template (ie: uid={user},ou=people,dc=example,dc=com)
+    Template(String),
+    // DN searched with a service account (ie: filter (sAMAccountName={user}) on Active Directory)
+    Search {
+        base: String,
+        filter: String,
+        bind_dn: String,
+        bind_password: String,
+    },
+}
+
+// How the groups of a user are found, they are the roles of its Principal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LdapGroups {
+    None,
+    // CN of the DNs of an attribute of the user entry (ie: memberOf)
+    Attribute(String),
+    // CN of the entries found by a search, {dn} is replaced by the escaped DN of the user and {user}
+    // by the escaped user name (ie: (&(objectClass=groupOfNames)(member={dn})))
+    Search { base: String, filter: String },
+}
+
+// What to do when the directory cant be reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LdapOutage {
+    Deny,
+    // Accepts credentials that were valid up to the duration past their cache ttl
+    ServeStale(Duration),
+}
+
+// Checks Basic credentials binding to an LDAP (or Active Directory) server as the user, for a
+// BasicAuthHandler. Connections are pooled, and successful authentications are cached for a ttl
+// by user and password hash. Wrong credentials are never cached
+pub struct LdapVerifier {
+    url: String,
+    bind: LdapBind,
+    groups: LdapGroups,
+    outage: LdapOutage,
+    timeout: Duration,
+    cache_ttl: Duration,
+    connections: Semaphore,
+    idle: Mutex<Vec<Ldap>>,
+    cache: Mutex<HashMap<CacheKey, (Principal, Instant)>>, // with the verification time
+}
+
+// User and SHA-256 of the password
+type CacheKey = (String, Vec<u8>);
+
+// Error reaching the directory, told apart from rejected credentials
+struct Outage(String);
+
+impl From<ldap3::LdapError> for Outage {
+    fn from(e: ldap3::LdapError) -> Outage {
+        Outage(e.to_string())
+    }
+}
+
+impl LdapVerifier {
+    // url is ldap://, ldaps:// or ldapi://
+    pub fn new(url: &str, bind: LdapBind) -> LdapVerifier {
+        LdapVerifier {
+            url: url.to_string(),
+            bind,
+            groups: LdapGroups::None,
+            outage: LdapOutage::Deny,
+            timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_secs(300),
+            connections: Semaphore::new(8),
+            idle: Mutex::new(vec![]),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_groups(self, groups: LdapGroups) -> Self {
+        LdapVerifier { groups, ..self }
+    }
+
+    pub fn with_outage(self, outage: LdapOutage) -> Self {
+        LdapVerifier { outage, ..self }
+    }
+
+    // Timeout of the connections and of each operation
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        LdapVerifier { timeout, ..self }
+    }
+
+    // Zero disables the cache
+    pub fn with_cache_ttl(self, cache_ttl: Duration) -> Self {
+        LdapVerifier { cache_ttl, ..self }
+    }
+
+    // Max connections to the directory, verifications wait for a free one
+    pub fn with_pool_size(self, size: usize) -> Self {
+        LdapVerifier {
+            connections: Semaphore::new(size.max(1)),
+            ..self
+        }
+    }
+
+    pub fn invalidate(&self, user: &str) {
+        self.cache
+            .lock()
+            .unwrap()
+            .retain(|(cached, _), _| cached != user);
+    }
+
+    fn cached(&self, key: &CacheKey, max_age: Duration) -> Option<Principal> {
+        match self.cache.lock().unwrap().get(key) {
+            Some((principal, verified)) if verified.elapsed() < max_age => Some(principal.clone()),
+            _ => None,
+        }
+    }
+
+    fn store(&self, key: CacheKey, principal: &Principal) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let keep = self.cache_ttl + self.stale();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, verified)| verified.elapsed() < keep);
+        cache.insert(key, (principal.clone(), Instant::now()));
+    }
+
+    fn stale(&self) -> Duration {
+        match self.outage {
+            LdapOutage::Deny => Duration::ZERO,
+            LdapOutage::ServeStale(stale) => stale,
+        }
+    }
+
+    async fn connect(&self) -> Result<Ldap, Outage> {
+        while let Some(mut ldap) = self.idle.lock().unwrap().pop() {
+            if !ldap.is_closed() {
+                return Ok(ldap);
+            }
+        }
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        runtime::spawn(async move {
+            if let Err(e) = conn.drive().await {
+                debug!("LDAP connection closed. {}", e);
+            }
+        });
+        Ok(ldap)
+    }
+
+    // Binds as the user, returning its principal, None for wrong credentials
+    async fn authenticate(
+        &self,
+        ldap: &mut Ldap,
+        user: &str,
+        password: &str,
+    ) -> Result<Option<Principal>, Outage> {
+        ldap.with_timeout(self.timeout);
+        let dn = match &self.bind {
+            LdapBind::Template(template) => template.replace("{user}", &dn_escape(user)),
+            LdapBind::Search {
+                base,
+                filter,
+                bind_dn,
+                bind_password,
+            } => {
+                ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+                let filter = filter.replace("{user}", &ldap_escape(user));
+                ldap.with_timeout(self.timeout);
+                let (entries, _) = ldap
+                    .search(base, Scope::Subtree, &filter, vec!["1.1"])
+                    .await?
+                    .success()?;
+                match entries.as_slice() {
+                    [entry] => SearchEntry::construct(entry.clone()).dn,
+                    // unknown and ambiguous users alike
+                    _ => return Ok(None),
+                }
+            }
+        };
+        ldap.with_timeout(self.timeout);
+        let bound = ldap.simple_bind(&dn, password).await?;
+        if bound.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bound.success()?;
+        let groups = self.groups(ldap, user, &dn).await?;
+        let roles: Vec<&str> = groups.iter().map(String::as_str).collect();
+        Ok(Some(Principal::new(user).with_roles(&roles)))
+    }
+
+    async fn groups(&self, ldap: &mut Ldap, user: &str, dn: &str) -> Result<Vec<String>, Outage> {
+        // the groups are read from an attribute of the user, or are the entries found
+        let (base, scope, filter, attribute) = match &self.groups {
+            LdapGroups::None => return Ok(vec![]),
+            LdapGroups::Attribute(attribute) => (
+                dn.to_string(),
+                Scope::Base,
+                "(objectClass=*)".to_string(),
+                Some(attribute.as_str()),
+            ),
+            LdapGroups::Search { base, filter } => {
+                let filter = filter
+                    .replace("{dn}", &ldap_escape(dn))
+                    .replace("{user}", &ldap_escape(user));
+                (base.clone(), Scope::Subtree, filter, None)
+            }
+        };
+        ldap.with_timeout(self.timeout);
+        let (entries, _) = ldap
+            .search(&base, scope, &filter, vec![attribute.unwrap_or("1.1")])
+            .await?
+            .success()?;
+        let mut groups = vec![];
+        for entry in entries {
+            let entry = SearchEntry::construct(entry);
+            match attribute {
+                Some(attribute) => {
+                    let values = entry.attrs.get(attribute).cloned().unwrap_or_default();
+                    groups.extend(values.iter().map(|dn| common_name(dn)));
+                }
+                None => groups.push(common_name(&entry.dn)),
+            }
+        }
+        Ok(groups)
+    }
+}
+
+// First CN of a DN, or the DN if it has none (ie: cn=admins,ou=groups,dc=example,dc=com is admins)
+fn common_name(dn: &str) -> String {
+    dn.split(',')
+        .next()
+        .and_then(|rdn| rdn.split_once('='))
+        .filter(|(attribute, _)| attribute.trim().eq_ignore_ascii_case("cn"))
+        .map_or(dn, |(_, value)| value)
+        .trim()
+        .to_string()
+}
+
+#[async_trait]
+impl CredentialVerifier for LdapVerifier {
+    async fn verify(&self, user: &str, password: &str) -> Option<Principal> {
+        // an empty password is an anonymous bind, which succeeds
+        if user.is_empty() || password.is_empty() {
+            return None;
+        }
+        let key = (
+            user.to_string(),
+            digest(&SHA256, password.as_bytes()).as_ref().to_vec(),
+        );
+        if let Some(principal) = self.cached(&key, self.cache_ttl) {
+            return Some(principal);
+        }
+        let verified = match self.connections.acquire().await {
+            Ok(_permit) => match self.connect().await {
+                Ok(mut ldap) => {
+                    let verified = self.authenticate(&mut ldap, user, password).await;
+                    // connections that failed are dropped, the others go back to the pool
+                    if verified.is_ok() {
+                        self.idle.lock().unwrap().push(ldap);
+                    }
+                    verified
+                }
+                Err(e) => Err(e),
+            },
+            Err(_) => Err(Outage("Pool closed".to_string())),
+        };
+        match verified {
+            Ok(Some(principal)) => {
+                self.store(key, &principal);
+                Some(principal)
+            }
+            Ok(None) => None,
+            Err(Outage(e)) => {
+                let stale = self.cached(&key, self.cache_ttl + self.stale());
+                warn!(
+                    "LDAP directory {} unavailable, {}. {}",
+                    self.url,
+                    if stale.is_some() {
+                        "using a cached authentication"
+                    } else {
+                        "denying the authentication"
+                    },
+                    e
+                );
+                stale
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ldap_verifier_outage() {
+        assert_eq!(
+            common_name("CN=Admins,OU=Groups,DC=example,DC=com"),
+            "Admins"
+        );
+        assert_eq!(common_name("ou=staff"), "ou=staff");
+
+        // nothing listens on the port
+        let template = LdapBind::Template("uid={user},dc=example,dc=com".to_string());
+        let verifier = LdapVerifier::new("ldap://127.0.0.1:1", template)
+            .with_cache_ttl(Duration::from_millis(50))
+            .with_outage(LdapOutage::ServeStale(Duration::from_secs(60)));
+        assert_eq!(verifier.verify("alice", "").await, None);
+        assert_eq!(verifier.verify("alice", "secret").await, None);
+
+        // an expired authentication is used while the directory is down, for the right password only
+        let key = (
+            "alice".to_string(),
+            digest(&SHA256, b"secret").as_ref().to_vec(),
+        );
+        verifier.store(key, &Principal::new("alice").with_roles(&["admins"]));
+        std::thread::sleep(Duration::from_millis(60));
+        let principal = verifier.verify("alice", "secret").await.unwrap();
+        assert!(principal.has_role("admins"));
+        assert_eq!(verifier.verify("alice", "wrong").await, None);
+
+        let verifier = verifier.with_outage(LdapOutage::Deny);
+        assert_eq!(verifier.verify("alice", "secret").await, None);
+    }
+}
@@ -0,0 +1,372 @@
+use super::{normalize_path, ApiKey, Principal};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::{Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+
+// Rule of an AuthorizationHandler: the methods (any if empty) and path pattern it applies to, and what
+// the principal needs. Patterns match whole segments: * matches one segment and a trailing ** the rest
+// of the path (ie: /orders/*/items, /admin/**)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRule {
+    methods: Vec<Method>,
+    pattern: String,
+    public: bool,
+    roles: Vec<String>,  // any of them
+    scopes: Vec<String>, // all of them
+}
+
+impl AccessRule {
+    // Rule requiring an authenticated principal
+    pub fn new(methods: &[Method], pattern: &str) -> AccessRule {
+        AccessRule {
+            methods: methods.to_vec(),
+            pattern: pattern.to_string(),
+            public: false,
+            roles: vec![],
+            scopes: vec![],
+        }
+    }
+
+    // Allows anyone, authenticated or not
+    pub fn public(self) -> Self {
+        AccessRule {
+            public: true,
+            ..self
+        }
+    }
+
+    // The principal needs one of the roles
+    pub fn with_roles(self, roles: &[&str]) -> Self {
+        AccessRule {
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            ..self
+        }
+    }
+
+    // The principal needs all the scopes, from its API key or its roles
+    pub fn with_scopes(self, scopes: &[&str]) -> Self {
+        AccessRule {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            ..self
+        }
+    }
+
+    fn applies(&self, method: &Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(method))
+            && path_matches(&self.pattern, path)
+    }
+
+    // Why the principal isnt allowed, None if it is
+    fn denial(&self, req: &RhodRequest) -> Option<String> {
+        if self.public {
+            return None;
+        }
+        let principal = match req.context().get::<Principal>() {
+            Some(principal) => principal,
+            None => return Some("Not authenticated".to_string()),
+        };
+        if !self.roles.is_empty() && !self.roles.iter().any(|role| principal.has_role(role)) {
+            return Some(format!(
+                "Missing one of the roles {}",
+                self.roles.join(", ")
+            ));
+        }
+        let api_key = req.context().get::<ApiKey>();
+        let missing: Vec<&str> = self
+            .scopes
+            .iter()
+            .filter(|scope| {
+                !principal.has_role(scope) && !api_key.as_ref().is_some_and(|k| k.has_scope(scope))
+            })
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Some(format!("Missing the scopes {}", missing.join(", ")));
+        }
+        None
+    }
+}
+
+// Path the rules are matched against: dot segments resolved, then percent-decoded. None for the paths
+// going above the root, or with dot segments once decoded (ie: /public%2F..%2Fadmin)
+fn canonical_path(path: &str) -> Option<String> {
+    let path = normalize_path(path)?;
+    let decoded = percent_decode_str(&path).decode_utf8().ok()?;
+    if decoded.split('/').any(|s| s == "." || s == "..") {
+        return None;
+    }
+    Some(decoded.into_owned())
+}
+
+// Empty segments are ignored, so /admin/ and //admin match /admin
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    for expected in pattern.trim_start_matches('/').split('/') {
+        if expected == "**" {
+            return true;
+        }
+        match segments.next() {
+            Some(segment) if expected == "*" || expected == segment => (),
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+// Decision of an AuthorizationHandler, saved in the request context for the access and audit logs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthorizationDecision {
+    pub allowed: bool,
+    pub principal: Option<String>,
+    pub rule: Option<String>, // pattern of the rule applied, None when no rule applied
+    pub reason: String,
+}
+
+// Authorizes requests with the principal left in the context by the auth handlers (BasicAuthHandler,
+// ApiKeyHandler...) running before it. The first rule applying to the method and path decides,
+// requests no rule applies to are denied unless allow_unmatched. Denied requests are answered with
+// a 403. The decision is saved in the context, and the denials written to the audit log
+pub struct AuthorizationHandler {
+    rules: Vec<AccessRule>,
+    allow_unmatched: bool,
+    audit: Option<AuditLog>,
+    audit_allowed: bool,
+}
+
+impl AuthorizationHandler {
+    pub fn new(rules: Vec<AccessRule>) -> AuthorizationHandler {
+        AuthorizationHandler {
+            rules,
+            allow_unmatched: false,
+            audit: None,
+            audit_allowed: false,
+        }
+    }
+
+    // Allows the requests no rule applies to
+    pub fn allow_unmatched(self) -> Self {
+        AuthorizationHandler {
+            allow_unmatched: true,
+            ..self
+        }
+    }
+
+    // Writes the denials to the audit log, and the allowed requests too if audit_allowed
+    pub fn with_audit(self, audit: AuditLog, audit_allowed: bool) -> Self {
+        AuthorizationHandler {
+            audit: Some(audit),
+            audit_allowed,
+            ..self
+        }
+    }
+
+    fn decide(&self, req: &RhodRequest) -> AuthorizationDecision {
+        let principal = req.context().get::<Principal>().map(|p| p.id);
+        let path = match canonical_path(req.uri().path()) {
+            Some(path) => path,
+            None => {
+                return AuthorizationDecision {
+                    allowed: false,
+                    principal,
+                    rule: None,
+                    reason: "Invalid path".to_string(),
+                }
+            }
+        };
+        let rule = self.rules.iter().find(|r| r.applies(req.method(), &path));
+        let (allowed, reason) = match rule {
+            Some(rule) => match rule.denial(req) {
+                Some(reason) => (false, reason),
+                None => (true, "Allowed by rule".to_string()),
+            },
+            None if self.allow_unmatched => (true, "No rule applies".to_string()),
+            None => (false, "No rule applies".to_string()),
+        };
+        AuthorizationDecision {
+            allowed,
+            principal,
+            rule: rule.map(|r| r.pattern.clone()),
+            reason,
+        }
+    }
+
+    async fn audit(
+        &self,
+        conn: &RhodConnInfo,
+        req: &RhodRequest,
+        decision: &AuthorizationDecision,
+    ) {
+        let audit = match &self.audit {
+            Some(audit) if !decision.allowed || self.audit_allowed => audit,
+            _ => return,
+        };
+        let kind = if decision.allowed {
+            "authz_allowed"
+        } else {
+            "authz_denied"
+        };
+        let mut event = AuditEvent::new(kind)
+            .with_client(&conn.addr.to_string())
+            .with_detail("method", req.method_str())
+            .with_detail("path", req.uri().path())
+            .with_detail("reason", &decision.reason);
+        if let Some(principal) = &decision.principal {
+            event = event.with_detail("principal", principal);
+        }
+        if let Some(rule) = &decision.rule {
+            event = event.with_detail("rule", rule);
+        }
+        audit.emit(event).await;
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for AuthorizationHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let decision = self.decide(req);
+        self.audit(conn, req, &decision).await;
+        let allowed = decision.allowed;
+        let msg = format!(
+            "Forbidden {} {}. {}",
+            req.method_str(),
+            req.uri().path(),
+            decision.reason
+        );
+        req.context().insert(decision);
+        if allowed {
+            return Ok(());
+        }
+        let res = RhodResponse::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body_str("Forbidden")
+            .build();
+        Err(RhodError::from_string(msg, RhodErrorLevel::Warning).with_built(res))
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[tokio::test]
+    async fn test_authorization_handler() {
+        let handler = AuthorizationHandler::new(vec![
+            AccessRule::new(&[Method::GET], "/health").public(),
+            AccessRule::new(&[], "/admin/**").with_roles(&["admin", "ops"]),
+            AccessRule::new(&[Method::DELETE], "/orders/*").with_scopes(&["orders:write"]),
+            AccessRule::new(&[], "/orders/**"),
+        ]);
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let handle = |method: Method, uri: &str, principal: Option<Principal>| {
+            let mut req = RhodRequest::builder()
+                .method(method)
+                .uri(uri)
+                .build()
+                .unwrap();
+            if let Some(principal) = principal {
+                req.context().insert(principal);
+            }
+            let (handler, conn) = (&handler, &conn);
+            async move {
+                let result =
+                    RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ()).await;
+                let decision = req.context().get::<AuthorizationDecision>().unwrap();
+                assert_eq!(decision.allowed, result.is_ok());
+                decision
+            }
+        };
+        let alice = || Some(Principal::new("alice").with_roles(&["ops"]));
+
+        assert!(handle(Method::GET, "/health", None).await.allowed);
+        assert!(!handle(Method::POST, "/health", alice()).await.allowed);
+        assert!(handle(Method::GET, "/admin/users/1", alice()).await.allowed);
+        let decision = handle(Method::GET, "/admin", Some(Principal::new("bob"))).await;
+        assert_eq!(decision.principal.as_deref(), Some("bob"));
+        assert_eq!(decision.rule.as_deref(), Some("/admin/**"));
+        assert!(!decision.allowed);
+
+        assert!(!handle(Method::DELETE, "/orders/7", alice()).await.allowed);
+        let writer = Principal::new("svc").with_roles(&["orders:write"]);
+        assert!(
+            handle(Method::DELETE, "/orders/7", Some(writer))
+                .await
+                .allowed
+        );
+        assert!(
+            handle(Method::GET, "/orders/7/items", alice())
+                .await
+                .allowed
+        );
+        assert!(!handle(Method::GET, "/orders/7/items", None).await.allowed);
+        assert!(!handle(Method::GET, "/ordersx", alice()).await.allowed);
+
+        // the rules see the path as the service does
+        let handler = AuthorizationHandler::new(vec![
+            AccessRule::new(&[], "/admin/**").with_roles(&["admin"]),
+            AccessRule::new(&[], "/internal").with_roles(&["admin"]),
+        ])
+        .allow_unmatched();
+        let handle = |uri: &str| {
+            let mut req = RhodRequest::builder().uri(uri).build().unwrap();
+            let (handler, conn) = (&handler, &conn);
+            async move {
+                let result =
+                    RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ()).await;
+                result.is_ok()
+            }
+        };
+        assert!(handle("/public/x").await);
+        for uri in [
+            "/public/../admin/x",
+            "/%61dmin/x",
+            "/admin/",
+            "//admin/x",
+            "/internal/",
+            "/public/%2e%2e/admin/x",
+            "/public%2F..%2Fadmin/x",
+            "/../admin/x",
+        ] {
+            assert!(!handle(uri).await, "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::body::map_body;
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{Method, StatusCode};
+use std::sync::Arc;
+
+pub type BufferedRewrite = Box<dyn Fn(Vec<u8>) -> Vec<u8> + Send + Sync>;
+pub type StreamingRewrite = Arc<dyn Fn(Bytes) -> Bytes + Send + Sync>;
+
+// Function applied to a body
+pub enum BodyRewrite {
+    Buffered(BufferedRewrite),   // receives the whole body
+    Streaming(StreamingRewrite), // receives each chunk, as it arrives
+}
+
+// Sets the length headers for a body: Content-Length if the length is known, otherwise
+// none of them, so hyper uses chunked encoding (HTTP/1.1) or data frames (HTTP/2)
+pub(crate) fn fix_length_headers(headers: &mut HeaderMap, length: Option<usize>) {
+    headers.remove(TRANSFER_ENCODING);
+    match length {
+        Some(length) => {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+        None => {
+            headers.remove(CONTENT_LENGTH);
+        }
+    }
+}
+
+// Saved in the context of the requests whose responses can be rewritten, not those of HEAD requests
+#[derive(Clone, Copy)]
+struct Rewritable;
+
+// Responses with a body that isnt encoded (ie: compressed)
+fn rewritable(res: &RhodResponse) -> bool {
+    let status = res.status_as_int();
+    let no_body =
+        status < 200 || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED;
+    let encoding = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+    !no_body && encoding.is_none_or(|e| e.trim().eq_ignore_ascii_case("identity"))
+}
+
+fn stream_rewrite(body: RhodBody, rewrite: &StreamingRewrite) -> RhodBody {
+    let rewrite = Arc::clone(rewrite);
+    // keeps the trailers of the body
+    map_body(body, move |chunk| rewrite(chunk), HeaderMap::new())
+}
+
+// Applies functions to request and/or response bodies, updating Content-Length/Transfer-Encoding.
+// Combine it with a ConditionalHandler to rewrite only some bodies (ie: by content type).
+// Responses without a body (HEAD, 1xx, 204, 304) and encoded ones are left as they are. Buffered
+// bodies larger than max_body_size (1MiB by default) arent rewritten: requests are answered 413,
+// responses are streamed as they are.
+pub struct BodyRewriteHandler {
+    request: Option<BodyRewrite>,
+    response: Option<BodyRewrite>,
+    max_body_size: usize,
+}
+
+impl BodyRewriteHandler {
+    pub fn new() -> BodyRewriteHandler {
+        BodyRewriteHandler {
+            request: None,
+            response: None,
+            max_body_size: 1 << 20,
+        }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> BodyRewriteHandler {
+        BodyRewriteHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    pub fn on_request(self, rewrite: BodyRewrite) -> BodyRewriteHandler {
+        BodyRewriteHandler {
+            request: Some(rewrite),
+            ..self
+        }
+    }
+
+    pub fn on_response(self, rewrite: BodyRewrite) -> BodyRewriteHandler {
+        BodyRewriteHandler {
+            response: Some(rewrite),
+            ..self
+        }
+    }
+}
+
+impl Default for BodyRewriteHandler {
+    fn default() -> BodyRewriteHandler {
+        BodyRewriteHandler::new()
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for BodyRewriteHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if self.response.is_some() && req.method() != Method::HEAD {
+            req.context().insert(Rewritable);
+        }
+        match &self.request {
+            Some(BodyRewrite::Buffered(rewrite)) => {
+                let body = match req.body_bytes_limited(self.max_body_size).await? {
+                    Some(body) => rewrite(body.to_vec()),
+                    None => {
+                        return Err(RhodError::with_status(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            format!("Request body larger than {} bytes", self.max_body_size),
+                            RhodErrorLevel::Warning,
+                        ))
+                    }
+                };
+                fix_length_headers(req.headers_mut(), Some(body.len()));
+                req.set_body(RhodBody::from(body));
+            }
+            Some(BodyRewrite::Streaming(rewrite)) => {
+                let body = req.take_body();
+                req.set_body(stream_rewrite(body, rewrite));
+                fix_length_headers(req.headers_mut(), None);
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if res.context().remove::<Rewritable>().is_none() || !rewritable(&res) {
+            return (res, Ok(()));
+        }
+        match &self.response {
+            Some(BodyRewrite::Buffered(rewrite)) => {
+                let body = match res.body_bytes_limited(self.max_body_size).await {
+                    Ok(Some(body)) => rewrite(body.to_vec()),
+                    Ok(None) => return (res, Ok(())),
+                    Err(e) => return (res, Err(e)),
+                };
+                fix_length_headers(res.headers_mut(), Some(body.len()));
+                res.set_body(RhodBody::from(body));
+            }
+            Some(BodyRewrite::Streaming(rewrite)) => {
+                let body = res.take_body();
+                res.set_body(stream_rewrite(body, rewrite));
+                fix_length_headers(res.headers_mut(), None);
+            }
+            None => (),
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandlerInStack, RhodService, RhodStack};
+    use crate::CommunicationChannel;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // Echoes the request body and its Content-Length
+    struct EchoService {}
+    #[async_trait]
+    impl RhodService<Comm> for EchoService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            mut req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let length = req
+                .headers()
+                .get("Content-Length")
+                .map_or("none", |v| v.to_str().unwrap())
+                .to_string();
+            let body = req.body().await?;
+            RhodResponse::builder()
+                .header("x-request-length", &length)
+                .header("Content-Length", &body.len().to_string())
+                .body_bytes(&body)
+                .build()
+        }
+    }
+
+    async fn run(handler: BodyRewriteHandler) -> RhodResponse {
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(handler))],
+            Box::new(EchoService {}),
+        );
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .header("Content-Length", "11")
+            .body_str("hello world")
+            .build()
+            .unwrap();
+        stack.execute(&conn, req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_buffered_rewrite() {
+        let handler = BodyRewriteHandler::new()
+            .on_request(BodyRewrite::Buffered(Box::new(|b| b[..5].to_vec())))
+            .on_response(BodyRewrite::Buffered(Box::new(|b| {
+                String::from_utf8(b).unwrap().repeat(2).into_bytes()
+            })));
+
+        let mut res = run(handler).await;
+        assert_eq!(res.headers().get("x-request-length").unwrap(), "5");
+        assert_eq!(res.headers().get("Content-Length").unwrap(), "10");
+        assert_eq!(res.body().await.unwrap(), b"hellohello".to_vec());
+    }
+
+    async fn rewrite_response(
+        handler: &BodyRewriteHandler,
+        method: Method,
+        status: u16,
+        encoding: Option<&str>,
+        body: &str,
+    ) -> RhodResponse {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let mut req = RhodRequest::builder().method(method).build().unwrap();
+        RhodHandler::<Comm>::handle_request(handler, &conn, &mut req, &mut Comm {})
+            .await
+            .unwrap();
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::from_u16(status).unwrap())
+            .header("Content-Length", &body.len().to_string())
+            .body_str(body);
+        if let Some(encoding) = encoding {
+            res = res.header("Content-Encoding", encoding);
+        }
+        let mut res = res.build().unwrap();
+        res.attach_context(req.context());
+        let (res, result) =
+            RhodHandler::<Comm>::handle_response(handler, &conn, res, &mut Comm {}).await;
+        assert!(result.is_ok());
+        res
+    }
+
+    #[tokio::test]
+    async fn test_responses_left_as_they_are() {
+        let handler = BodyRewriteHandler::new()
+            .on_request(BodyRewrite::Buffered(Box::new(|b| b)))
+            .on_response(BodyRewrite::Buffered(Box::new(|_| b"rewritten".to_vec())))
+            .with_max_body_size(8);
+        let mut res = rewrite_response(&handler, Method::GET, 200, None, "body").await;
+        assert_eq!(res.body().await.unwrap(), b"rewritten".to_vec());
+
+        // without a body, encoded or larger
+        let res = rewrite_response(&handler, Method::HEAD, 200, None, "").await;
+        assert_eq!(res.headers()["Content-Length"], "0");
+        for status in [204, 304] {
+            let res = rewrite_response(&handler, Method::GET, status, None, "").await;
+            assert!(res
+                .headers()
+                .get("Content-Length")
+                .is_some_and(|l| l == "0"));
+        }
+        let mut res = rewrite_response(&handler, Method::GET, 200, Some("gzip"), "body").await;
+        assert_eq!(res.body().await.unwrap(), b"body".to_vec());
+        let mut res = rewrite_response(&handler, Method::GET, 200, None, "larger body").await;
+        assert_eq!(res.body().await.unwrap(), b"larger body".to_vec());
+
+        // larger requests are refused
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let mut req = RhodRequest::builder()
+            .body_str("larger body")
+            .build()
+            .unwrap();
+        let mut err = RhodHandler::<Comm>::handle_request(&handler, &conn, &mut req, &mut Comm {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 413);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_rewrite() {
+        let upper: StreamingRewrite = Arc::new(|chunk| Bytes::from(chunk.to_ascii_uppercase()));
+        let handler = BodyRewriteHandler::new()
+            .on_request(BodyRewrite::Streaming(Arc::clone(&upper)))
+            .on_response(BodyRewrite::Streaming(Arc::new(|chunk| {
+                Bytes::from(chunk.iter().rev().cloned().collect::<Vec<u8>>())
+            })));
+
+        let mut res = run(handler).await;
+        assert_eq!(res.headers().get("x-request-length").unwrap(), "none");
+        assert!(res.headers().get("Content-Length").is_none());
+        assert_eq!(res.body().await.unwrap(), b"DLROW OLLEH".to_vec());
+    }
+}
@@ -0,0 +1,338 @@
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+use crate::stack::RhodHandler;
+use crate::tarpit::Tarpit;
+use crate::util::WindowCounters;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::USER_AGENT;
+use http::StatusCode;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Score given by BotDetectionHandler to a request, saved in the request context
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BotScore {
+    pub score: u32,
+    pub signals: Vec<String>, // signals adding to the score
+    pub tag: Option<String>,  // set by a Tag action
+}
+
+// Taken when the score reaches its threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotAction {
+    Tag(String),         // only tags the score in the context
+    Challenge(Duration), // delays the request, slowing down automated clients
+    Block,               // answers 403
+    Tarpit, // answers with the tarpit of the handler (with_tarpit), or 403 when it is full
+}
+
+// Scores requests by combining signals of automated clients, each adding its weight:
+// - user agent rules (regex on the User-Agent, ie: known scanners) and a missing User-Agent
+// - header order anomalies: clients claiming to be a browser (by their User-Agent) must send the
+//   headers in the order of that browser. Headers are taken in the order they were received
+// - known TLS fingerprints (JA3 hash or JA4) of the connection
+// - request rate of the client over a window
+// The score is saved as a BotScore in the context, and the action with the highest threshold reached
+// by the score is taken:
+//
+//  let bots = BotDetectionHandler::new()
+//      .with_user_agent(Regex::new("(?i)sqlmap|nikto").unwrap(), 100)
+//      .with_missing_user_agent(40)
+//      .with_rate(50, Duration::from_secs(10), 30)
+//      .with_action(40, BotAction::Challenge(Duration::from_secs(2)))
+//      .with_action(100, BotAction::Block);
+pub struct BotDetectionHandler {
+    user_agents: Vec<(Regex, u32)>,
+    missing_user_agent: u32,
+    header_orders: Vec<(Regex, Vec<String>, u32)>,
+    fingerprints: HashMap<String, u32>,
+    rate: Option<(u32, Duration, u32)>, // max requests, window, weight
+    rates: Mutex<WindowCounters<IpAddr>>, // requests by client
+    actions: Vec<(u32, BotAction)>,     // sorted by threshold
+    tarpit: Tarpit,
+}
+
+impl Default for BotDetectionHandler {
+    fn default() -> Self {
+        BotDetectionHandler::new()
+    }
+}
+
+impl BotDetectionHandler {
+    pub fn new() -> BotDetectionHandler {
+        BotDetectionHandler {
+            user_agents: vec![],
+            missing_user_agent: 0,
+            header_orders: vec![],
+            fingerprints: HashMap::new(),
+            rate: None,
+            rates: Mutex::new(WindowCounters::new()),
+            actions: vec![],
+            tarpit: Tarpit::drip(),
+        }
+    }
+
+    pub fn with_user_agent(mut self, user_agent: Regex, weight: u32) -> Self {
+        self.user_agents.push((user_agent, weight));
+        self
+    }
+
+    pub fn with_missing_user_agent(self, weight: u32) -> Self {
+        BotDetectionHandler {
+            missing_user_agent: weight,
+            ..self
+        }
+    }
+
+    // Headers expected in this order from the clients whose User-Agent matches the browser regex.
+    // A missing header is an anomaly too
+    pub fn with_header_order(mut self, browser: Regex, headers: &[&str], weight: u32) -> Self {
+        let headers = headers.iter().map(|h| h.to_lowercase()).collect();
+        self.header_orders.push((browser, headers, weight));
+        self
+    }
+
+    // JA3 hash or JA4 fingerprint of a known client
+    pub fn with_fingerprint(mut self, fingerprint: &str, weight: u32) -> Self {
+        self.fingerprints.insert(fingerprint.to_string(), weight);
+        self
+    }
+
+    // Adds the weight to the requests of the clients sending more than max requests in the window
+    pub fn with_rate(self, max: u32, window: Duration, weight: u32) -> Self {
+        BotDetectionHandler {
+            rate: Some((max, window, weight)),
+            ..self
+        }
+    }
+
+    pub fn with_action(mut self, threshold: u32, action: BotAction) -> Self {
+        self.actions.push((threshold, action));
+        self.actions.sort_by_key(|(threshold, _)| *threshold);
+        self
+    }
+
+    // Tarpit of the Tarpit action, dripping with the default settings if not set
+    pub fn with_tarpit(self, tarpit: Tarpit) -> Self {
+        BotDetectionHandler { tarpit, ..self }
+    }
+
+    fn score(&self, conn: &RhodConnInfo, req: &RhodRequest) -> BotScore {
+        let mut score = BotScore::default();
+        let mut signal = |signal: String, weight: u32| {
+            score.score += weight;
+            score.signals.push(signal);
+        };
+
+        match req.headers().get(USER_AGENT).map(|v| v.to_str()) {
+            Some(Ok(user_agent)) => {
+                for (rule, weight) in &self.user_agents {
+                    if rule.is_match(user_agent) {
+                        signal(format!("user_agent:{}", rule.as_str()), *weight);
+                    }
+                }
+                for (browser, headers, weight) in &self.header_orders {
+                    if browser.is_match(user_agent) && !in_order(req, headers) {
+                        signal(format!("header_order:{}", browser.as_str()), *weight);
+                    }
+                }
+            }
+            Some(Err(_)) => {}
+            None if self.missing_user_agent > 0 => {
+                signal("missing_user_agent".to_string(), self.missing_user_agent)
+            }
+            None => {}
+        }
+
+        if let Some(fingerprint) = &conn.tls_fingerprint {
+            for known in [&fingerprint.ja3, &fingerprint.ja4] {
+                if let Some(weight) = self.fingerprints.get(known) {
+                    signal(format!("tls_fingerprint:{}", known), *weight);
+                }
+            }
+        }
+
+        if let Some((max, window, weight)) = self.rate {
+            if self.count_request(conn.addr.ip(), window) > max {
+                signal("request_rate".to_string(), weight);
+            }
+        }
+        score
+    }
+
+    // Requests of the client in the current window, counting this one
+    fn count_request(&self, ip: IpAddr, window: Duration) -> u32 {
+        self.rates.lock().unwrap().count(ip, window)
+    }
+}
+
+// Whether the expected headers are all in the request, in that order (others may be in between)
+fn in_order(req: &RhodRequest, expected: &[String]) -> bool {
+    let mut expected = expected.iter().peekable();
+    for name in req.headers().keys() {
+        if expected.peek().is_some_and(|e| *e == name.as_str()) {
+            expected.next();
+        }
+    }
+    expected.peek().is_none()
+}
+
+fn blocked(conn: &RhodConnInfo) -> RhodResult<RhodError> {
+    let err = RhodError::from_string(
+        format!("Request of {} blocked as a bot", conn.addr),
+        RhodErrorLevel::Warning,
+    );
+    let res = RhodResponse::builder()
+        .status(StatusCode::FORBIDDEN)
+        .build()?;
+    Ok(err.with_response(res))
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for BotDetectionHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let mut score = self.score(conn, req);
+        let action = self
+            .actions
+            .iter()
+            .rev()
+            .find(|(threshold, _)| score.score >= *threshold)
+            .map(|(_, action)| action);
+        if let Some(BotAction::Tag(tag)) = action {
+            score.tag = Some(tag.clone());
+        }
+        if score.score > 0 {
+            debug!(
+                "Bot score {} for {} ({})",
+                score.score,
+                conn.addr,
+                score.signals.join(", ")
+            );
+        }
+        req.context().insert(score);
+
+        match action {
+            Some(BotAction::Challenge(delay)) => {
+                runtime::sleep(*delay).await;
+                Ok(())
+            }
+            Some(BotAction::Tarpit) => match self.tarpit.response()? {
+                Some(res) => {
+                    let err = RhodError::from_string(
+                        format!("Request of {} tarpitted as a bot", conn.addr),
+                        RhodErrorLevel::Warning,
+                    );
+                    Err(err.with_response(res))
+                }
+                None => Err(blocked(conn)?),
+            },
+            Some(BotAction::Block) => Err(blocked(conn)?),
+            _ => Ok(()),
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[tokio::test]
+    async fn test_bot_detection() {
+        let handler = BotDetectionHandler::new()
+            .with_user_agent(Regex::new("(?i)sqlmap").unwrap(), 100)
+            .with_missing_user_agent(40)
+            .with_header_order(
+                Regex::new("Firefox/").unwrap(),
+                &["user-agent", "accept", "accept-language"],
+                30,
+            )
+            .with_rate(3, Duration::from_secs(60), 20)
+            .with_action(30, BotAction::Tag("suspicious".to_string()))
+            .with_action(60, BotAction::Challenge(Duration::from_millis(1)))
+            .with_action(100, BotAction::Block);
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let firefox = "Mozilla/5.0 Firefox/120.0";
+
+        for (headers, score, signals, blocked) in [
+            (
+                vec![
+                    ("user-agent", firefox),
+                    ("accept", "*/*"),
+                    ("accept-language", "en"),
+                ],
+                0,
+                vec![],
+                false,
+            ),
+            (
+                vec![("accept", "*/*"), ("user-agent", firefox)],
+                30,
+                vec!["header_order:Firefox/"],
+                false,
+            ),
+            (vec![], 40, vec!["missing_user_agent"], false),
+            // over the rate
+            (
+                vec![("user-agent", "sqlmap/1.7")],
+                120,
+                vec!["user_agent:(?i)sqlmap", "request_rate"],
+                true,
+            ),
+        ] {
+            let mut builder = RhodRequest::builder();
+            for (name, value) in headers {
+                builder = builder.header(name, value);
+            }
+            let mut req = builder.build().unwrap();
+            let result =
+                RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ()).await;
+            assert_eq!(result.is_err(), blocked);
+            let bot = req.context().get::<BotScore>().unwrap();
+            assert_eq!(bot.score, score);
+            assert_eq!(bot.signals, signals);
+            if score == 30 {
+                assert_eq!(bot.tag.as_deref(), Some("suspicious"));
+            }
+        }
+    }
+}
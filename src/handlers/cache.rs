@@ -0,0 +1,695 @@
+#[cfg(feature = "redis-cache")]
+mod redis_store;
+#[cfg(feature = "redis-cache")]
+pub use redis_store::RedisCacheStore;
+
+use super::conditional_get::{not_modified, parse_http_date, NOT_MODIFIED_HEADERS};
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::util::unix_now;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{
+    HeaderMap, HeaderName, AGE, AUTHORIZATION, CACHE_CONTROL, DATE, ETAG, EXPIRES, HOST,
+    LAST_MODIFIED, SET_COOKIE, VARY,
+};
+use http::uri::Authority;
+use http::Response as HyperResponse;
+use http::{Method, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Statuses cacheable by default (RFC 7231, section 6.1)
+const CACHEABLE_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+// Responses kept for a resource, for different values of its Vary headers
+const MAX_VARIANTS: usize = 16;
+
+// A response as kept by a CacheStore
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub stored_at: u64,                       // unix seconds
+    pub expires_at: u64,                      // unix seconds
+    pub vary: Vec<(String, Option<Vec<u8>>)>, // values of the Vary request headers the response was stored for
+    #[serde(default)]
+    pub variants: Vec<CachedResponse>, // responses of the resource for other values of the Vary headers
+}
+
+impl CachedResponse {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now < self.expires_at
+    }
+
+    pub fn ttl(&self, now: u64) -> Duration {
+        Duration::from_secs(self.expires_at.saturating_sub(now))
+    }
+
+    pub fn header(&self, name: &HeaderName) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.as_str() == name.as_str())
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn matches_vary(&self, headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| {
+            headers.get(name.as_str()).map(|v| v.as_bytes()) == value.as_deref()
+        })
+    }
+
+    // The fresh response stored for the values of the request headers
+    fn variant(&self, headers: &HeaderMap, now: u64) -> Option<&CachedResponse> {
+        std::iter::once(self)
+            .chain(self.variants.iter())
+            .find(|entry| entry.is_fresh(now) && entry.matches_vary(headers))
+    }
+
+    fn has_fresh(&self, now: u64) -> bool {
+        self.is_fresh(now) || self.variants.iter().any(|entry| entry.is_fresh(now))
+    }
+
+    // The response and its variants
+    fn into_variants(mut self) -> impl Iterator<Item = CachedResponse> {
+        let variants = std::mem::take(&mut self.variants);
+        std::iter::once(self).chain(variants)
+    }
+
+    fn to_response(
+        &self,
+        now: u64,
+        status: StatusCode,
+        with_body: bool,
+    ) -> RhodResult<RhodResponse> {
+        let mut builder = HyperResponse::builder().status(status);
+        let mut upstream_age = 0;
+        for (name, value) in self.headers.iter() {
+            let name = name.as_str();
+            // the age the response had when stored, the time in the cache is added to it
+            if name.eq_ignore_ascii_case(AGE.as_str()) {
+                upstream_age = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|age| age.trim().parse::<u64>().ok())
+                    .unwrap_or(0);
+                continue;
+            }
+            if status == StatusCode::NOT_MODIFIED
+                && !NOT_MODIFIED_HEADERS.iter().any(|h| h.as_str() == name)
+            {
+                continue;
+            }
+            builder = builder.header(name, value.as_slice());
+        }
+        builder = builder.header(
+            AGE,
+            upstream_age.saturating_add(now.saturating_sub(self.stored_at)),
+        );
+
+        let body = if with_body && status != StatusCode::NOT_MODIFIED {
+            RhodBody::from(self.body.clone())
+        } else {
+            RhodBody::empty()
+        };
+        match builder.body(body) {
+            Ok(res) => Ok(RhodResponse::new(res)),
+            Err(e) => Err(RhodError::from_string(
+                format!("Cant build cached response. {}", e),
+                crate::errors::RhodErrorLevel::Error,
+            )),
+        }
+    }
+}
+
+// Backend where the CacheHandler keeps responses.
+// Failures are expected to be logged by the store and treated as misses.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<CachedResponse>;
+    async fn put(&self, key: &str, entry: CachedResponse);
+    async fn remove(&self, key: &str);
+    async fn clear(&self);
+}
+
+// In memory store that evicts the least recently used response when full
+pub struct MemoryCacheStore {
+    capacity: usize,
+    inner: Mutex<MemoryCacheInner>,
+}
+
+struct MemoryCacheInner {
+    entries: HashMap<String, (CachedResponse, u64)>, // response + last use
+    tick: u64,
+}
+
+impl MemoryCacheStore {
+    pub fn new(capacity: usize) -> MemoryCacheStore {
+        MemoryCacheStore {
+            capacity,
+            inner: Mutex::new(MemoryCacheInner {
+                entries: HashMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCacheStore {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.get_mut(key).map(|(entry, last_use)| {
+            *last_use = tick;
+            entry.clone()
+        })
+    }
+
+    async fn put(&self, key: &str, entry: CachedResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if !inner.entries.contains_key(key) && inner.entries.len() >= self.capacity {
+            let lru = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(k, _)| k.clone());
+            if let Some(lru) = lru {
+                inner.entries.remove(&lru);
+            }
+        }
+        inner.entries.insert(key.to_string(), (entry, tick));
+    }
+
+    async fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().entries.remove(key);
+    }
+
+    async fn clear(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+// Cache-Control directives used by the cache
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &HeaderMap) -> CacheControl {
+        let mut cc = CacheControl::default();
+        for value in headers.get_all(CACHE_CONTROL).iter() {
+            let value = match value.to_str() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for directive in value.split(',') {
+                let mut parts = directive.splitn(2, '=');
+                let name = parts.next().unwrap_or("").trim().to_lowercase();
+                let arg = parts
+                    .next()
+                    .map(|a| a.trim().trim_matches('"'))
+                    .and_then(|a| a.parse::<u64>().ok());
+                match name.as_str() {
+                    "no-store" => cc.no_store = true,
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "public" => cc.public = true,
+                    "max-age" => cc.max_age = arg,
+                    "s-maxage" => cc.s_maxage = arg,
+                    _ => (),
+                }
+            }
+        }
+        cc
+    }
+}
+
+// Key used to store the responses of a resource, with the host of the uri (ie: GET example.com/a?b=1).
+// Responses of requests with a Host header are purged with their absolute uri
+pub fn cache_key(method: &Method, uri: &Uri) -> String {
+    resource_key(method, uri.authority().map(Authority::as_str), uri)
+}
+
+fn resource_key(method: &Method, host: Option<&str>, uri: &Uri) -> String {
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let host = host.unwrap_or("").to_ascii_lowercase();
+    format!("{} {}{}", method, host, path)
+}
+
+// Key of the resource requested, on the host of the uri or the Host header: virtual hosts sharing a
+// cache dont get the responses of each other
+fn request_key(req: &RhodRequest) -> String {
+    let host = req
+        .uri()
+        .authority()
+        .map(Authority::as_str)
+        .or_else(|| req.header_str(HOST));
+    resource_key(&Method::GET, host, req.uri())
+}
+
+// Saved in the request context when the response has to be stored
+#[derive(Clone)]
+struct PendingCacheEntry {
+    key: String,
+    request_headers: HeaderMap,
+}
+
+// Caches GET responses, and serves them for GET and HEAD requests while they are fresh.
+// Freshness comes from Cache-Control (s-maxage, max-age) or Expires, or the default ttl if set.
+// Responses are stored per resource, one for each value of the request headers listed in Vary.
+// Bodies larger than max_body_size are streamed without being stored.
+// To purge responses, keep a reference to the store and remove keys built with cache_key.
+pub struct CacheHandler {
+    store: Arc<dyn CacheStore>,
+    default_ttl: Option<Duration>, // ttl for responses without freshness information. None: not cached
+    max_body_size: usize,
+}
+
+impl CacheHandler {
+    pub fn new(store: Arc<dyn CacheStore>) -> CacheHandler {
+        CacheHandler {
+            store,
+            default_ttl: None,
+            max_body_size: 1024 * 1024,
+        }
+    }
+
+    pub fn with_default_ttl(self, ttl: Duration) -> CacheHandler {
+        CacheHandler {
+            default_ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> CacheHandler {
+        CacheHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    pub fn store(&self) -> Arc<dyn CacheStore> {
+        Arc::clone(&self.store)
+    }
+
+    pub async fn purge(&self, uri: &Uri) {
+        self.store.remove(&cache_key(&Method::GET, uri)).await;
+    }
+
+    pub async fn purge_all(&self) {
+        self.store.clear().await;
+    }
+
+    // Seconds the response can be served from the cache
+    fn ttl(&self, headers: &HeaderMap, cc: &CacheControl) -> Option<u64> {
+        cc.s_maxage
+            .or(cc.max_age)
+            .or_else(|| {
+                let expires = parse_http_date(headers.get(EXPIRES))?;
+                let date = parse_http_date(headers.get(DATE)).unwrap_or_else(unix_now);
+                Some(expires.saturating_sub(date))
+            })
+            .or_else(|| self.default_ttl.map(|ttl| ttl.as_secs()))
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for CacheHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let is_head = req.method() == Method::HEAD;
+        if req.method() != Method::GET && !is_head {
+            return Ok(());
+        }
+
+        let cc = CacheControl::parse(req.headers());
+        if cc.no_store {
+            return Ok(());
+        }
+
+        let key = request_key(req);
+        if !cc.no_cache {
+            if let Some(stored) = self.store.get(&key).await {
+                let now = unix_now();
+                if !stored.has_fresh(now) {
+                    self.store.remove(&key).await;
+                } else if let Some(entry) = stored.variant(req.headers(), now) {
+                    let status = if not_modified(
+                        req.headers(),
+                        entry.header(&ETAG),
+                        entry.header(&LAST_MODIFIED),
+                    ) {
+                        StatusCode::NOT_MODIFIED
+                    } else {
+                        StatusCode::from_u16(entry.status).unwrap_or(StatusCode::OK)
+                    };
+                    let res = entry.to_response(now, status, !is_head)?;
+                    return Err(RhodError::from_response(res));
+                }
+            }
+        }
+
+        if !is_head {
+            req.context().insert(PendingCacheEntry {
+                key,
+                request_headers: req.headers().clone(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let pending = match res.context().remove::<PendingCacheEntry>() {
+            Some(pending) => pending,
+            None => return (res, Ok(())),
+        };
+
+        if !CACHEABLE_STATUSES.contains(&res.status_as_int())
+            || res.headers().contains_key(SET_COOKIE)
+        {
+            return (res, Ok(()));
+        }
+
+        let cc = CacheControl::parse(res.headers());
+        if cc.no_store
+            || cc.no_cache
+            || cc.private
+            || (pending.request_headers.contains_key(AUTHORIZATION)
+                && !cc.public
+                && cc.s_maxage.is_none())
+        {
+            return (res, Ok(()));
+        }
+
+        let mut vary = vec![];
+        for value in res.headers().get_all(VARY).iter() {
+            for name in value.to_str().unwrap_or("*").split(',') {
+                let name = name.trim().to_lowercase();
+                if name == "*" {
+                    return (res, Ok(()));
+                }
+                let value = pending
+                    .request_headers
+                    .get(name.as_str())
+                    .map(|v| v.as_bytes().to_vec());
+                vary.push((name, value));
+            }
+        }
+
+        let ttl = match self.ttl(res.headers(), &cc) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return (res, Ok(())),
+        };
+
+        // larger bodies are streamed as they are, without being stored
+        let body = match res.body_bytes_limited(self.max_body_size).await {
+            Ok(Some(body)) => body.to_vec(),
+            Ok(None) => return (res, Ok(())),
+            Err(e) => return (res, Err(e)),
+        };
+
+        let now = unix_now();
+        let mut entry = CachedResponse {
+            status: res.status_as_int(),
+            headers: res
+                .headers()
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            body,
+            stored_at: now,
+            expires_at: now + ttl,
+            vary,
+            variants: vec![],
+        };
+        // the responses for other values of the Vary headers are kept
+        if !entry.vary.is_empty() {
+            if let Some(stored) = self.store.get(&pending.key).await {
+                entry.variants = stored
+                    .into_variants()
+                    .filter(|variant| variant.is_fresh(now) && variant.vary != entry.vary)
+                    .take(MAX_VARIANTS - 1)
+                    .collect();
+            }
+        }
+        self.store.put(&pending.key, entry).await;
+
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandlerInStack, RhodService, RhodStack};
+    use crate::CommunicationChannel;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    struct CountingService {
+        calls: Arc<AtomicUsize>,
+    }
+    #[async_trait]
+    impl RhodService<Comm> for CountingService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let cache_control = if req.uri().path() == "/private" {
+                "private, max-age=60"
+            } else {
+                "max-age=60"
+            };
+            RhodResponse::builder()
+                .header("Cache-Control", cache_control)
+                .header("ETag", "\"v1\"")
+                .header("Vary", "Accept-Language")
+                .body_str("cached body")
+                .build()
+        }
+    }
+
+    async fn get(stack: &RhodStack<Comm>, req: RhodRequest) -> RhodResponse {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        stack.execute(&conn, req).await.unwrap()
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let req = RhodRequest::builder()
+            .header("Cache-Control", "public, max-age=\"10\"")
+            .header("Cache-Control", "S-MAXAGE=20, no-cache")
+            .build()
+            .unwrap();
+        assert_eq!(
+            CacheControl::parse(req.headers()),
+            CacheControl {
+                no_cache: true,
+                public: true,
+                max_age: Some(10),
+                s_maxage: Some(20),
+                ..CacheControl::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_age() {
+        let entry = CachedResponse {
+            status: 200,
+            headers: vec![("age".to_string(), b"30".to_vec())],
+            body: vec![],
+            stored_at: 100,
+            expires_at: 200,
+            vary: vec![],
+            variants: vec![],
+        };
+        // the upstream age plus the time in the cache, once
+        let res = entry.to_response(110, StatusCode::OK, true).unwrap();
+        let ages: Vec<_> = res.headers().get_all(AGE).iter().collect();
+        assert_eq!(ages, ["40"]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_lru() {
+        let store = MemoryCacheStore::new(2);
+        let entry = CachedResponse {
+            status: 200,
+            headers: vec![],
+            body: vec![],
+            stored_at: 0,
+            expires_at: 10,
+            vary: vec![],
+            variants: vec![],
+        };
+        store.put("a", entry.clone()).await;
+        store.put("b", entry.clone()).await;
+        assert!(store.get("a").await.is_some());
+        store.put("c", entry.clone()).await;
+        assert_eq!(store.len(), 2);
+        assert!(store.get("b").await.is_none());
+        assert!(store.get("a").await.is_some());
+        store.clear().await;
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let store = Arc::new(MemoryCacheStore::new(10));
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(
+                CacheHandler::new(store.clone()),
+            ))],
+            Box::new(CountingService {
+                calls: calls.clone(),
+            }),
+        );
+
+        let req = || RhodRequest::builder().uri("/resource");
+        let mut res = get(&stack, req().build().unwrap()).await;
+        assert_eq!(res.body().await.unwrap(), b"cached body".to_vec());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // served from cache
+        let mut res = get(&stack, req().build().unwrap()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(res.headers().contains_key("Age"));
+        assert_eq!(res.body().await.unwrap(), b"cached body".to_vec());
+
+        // HEAD is served from the GET response, without body
+        let mut res = get(&stack, req().method(Method::HEAD).build().unwrap()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(res.body().await.unwrap(), Vec::<u8>::new());
+
+        // conditional request
+        let res = get(
+            &stack,
+            req().header("If-None-Match", "\"v1\"").build().unwrap(),
+        )
+        .await;
+        assert_eq!(res.status_as_int(), 304);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // other value of a Vary header
+        get(
+            &stack,
+            req().header("Accept-Language", "es").build().unwrap(),
+        )
+        .await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // both variants are kept
+        get(&stack, req().build().unwrap()).await;
+        get(
+            &stack,
+            req().header("Accept-Language", "es").build().unwrap(),
+        )
+        .await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // purge
+        store
+            .remove(&cache_key(&Method::GET, &"/resource".parse().unwrap()))
+            .await;
+        get(&stack, req().build().unwrap()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // responses are stored by host
+        let host = |host: &str| req().header("Host", host).build().unwrap();
+        get(&stack, host("a.example.com")).await;
+        get(&stack, host("A.example.com")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        get(&stack, host("b.example.com")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        let uri = "http://a.example.com/resource".parse().unwrap();
+        assert!(store.get(&cache_key(&Method::GET, &uri)).await.is_some());
+
+        // private responses are not stored
+        let req = || RhodRequest::builder().uri("/private");
+        get(&stack, req().build().unwrap()).await;
+        get(&stack, req().build().unwrap()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 7);
+
+        // larger bodies are sent without being stored
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(
+                CacheHandler::new(store.clone()).with_max_body_size(4),
+            ))],
+            Box::new(CountingService {
+                calls: calls.clone(),
+            }),
+        );
+        let req = || RhodRequest::builder().uri("/large").build().unwrap();
+        let mut res = get(&stack, req()).await;
+        assert_eq!(res.body().await.unwrap(), b"cached body".to_vec());
+        get(&stack, req()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 9);
+    }
+}
@@ -0,0 +1,118 @@
+use super::{CacheStore, CachedResponse};
+use crate::util::unix_now;
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, RedisError};
+use std::sync::Mutex;
+
+// Keys deleted by each DEL when clearing the cache
+const CLEAR_BATCH: usize = 1000;
+
+// Redis backed store. Responses are saved as json, expiring with the response.
+// Requests share a multiplexed connection, opened again after a connection error
+pub struct RedisCacheStore {
+    client: redis::Client,
+    conn: Mutex<Option<MultiplexedConnection>>,
+    prefix: String, // prepended to every key, used to clear only the cache keys
+}
+
+impl RedisCacheStore {
+    pub fn new(url: &str, prefix: &str) -> redis::RedisResult<RedisCacheStore> {
+        Ok(RedisCacheStore {
+            client: redis::Client::open(url)?,
+            conn: Mutex::new(None),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    async fn connection(&self) -> Option<MultiplexedConnection> {
+        if let Some(conn) = self.conn.lock().unwrap().as_ref() {
+            return Some(conn.clone());
+        }
+        match self.client.get_multiplexed_tokio_connection().await {
+            Ok(conn) => {
+                *self.conn.lock().unwrap() = Some(conn.clone());
+                Some(conn)
+            }
+            Err(e) => {
+                warn!("Cant connect to the redis cache. {}", e);
+                None
+            }
+        }
+    }
+
+    fn failed(&self, what: &str, e: RedisError) {
+        warn!("Cant {} the redis cache. {}", what, e);
+        if e.is_io_error() || e.is_connection_dropped() {
+            *self.conn.lock().unwrap() = None;
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisCacheStore {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut conn = self.connection().await?;
+        let value: Option<String> = match conn.get(format!("{}{}", self.prefix, key)).await {
+            Ok(value) => value,
+            Err(e) => {
+                self.failed("read from", e);
+                None
+            }
+        };
+        value.and_then(|v| serde_json::from_str(&v).ok())
+    }
+
+    async fn put(&self, key: &str, entry: CachedResponse) {
+        let ttl = entry.ttl(unix_now()).as_secs() as usize;
+        let value = match serde_json::to_string(&entry) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        if let Some(mut conn) = self.connection().await {
+            let result: redis::RedisResult<()> = conn
+                .set_ex(format!("{}{}", self.prefix, key), value, ttl.max(1))
+                .await;
+            if let Err(e) = result {
+                self.failed("write to", e);
+            }
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        if let Some(mut conn) = self.connection().await {
+            let result: redis::RedisResult<()> = conn.del(format!("{}{}", self.prefix, key)).await;
+            if let Err(e) = result {
+                self.failed("remove from", e);
+            }
+        }
+    }
+
+    // Lists the keys with SCAN, that doesnt block the server as KEYS does
+    async fn clear(&self) {
+        let mut conn = match self.connection().await {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut keys: Vec<String> = vec![];
+        {
+            let mut scan = match conn.scan_match(format!("{}*", self.prefix)).await {
+                Ok(scan) => scan,
+                Err(e) => {
+                    self.failed("list the keys of", e);
+                    return;
+                }
+            };
+            while let Some(key) = scan.next_item().await {
+                keys.push(key);
+            }
+        }
+        for batch in keys.chunks(CLEAR_BATCH) {
+            let result: redis::RedisResult<()> = conn.del(batch).await;
+            if let Err(e) = result {
+                self.failed("clear", e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::errors::{RhodError, RhodResult};
+use crate::handlers::TrafficVariant;
+use crate::request::{BodyProcessor, RhodRequest};
+use crate::response::RhodResponse;
+use crate::stack::{DynamicRhodHandler, RhodHandler};
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::Method;
+
+// Condition evaluated against the request to decide if a handler must run
+pub enum RequestPredicate {
+    PathPrefix(String), // matches whole path segments: "/api" matches "/api/x" but not "/apix"
+    Method(Method),
+    Header(String),              // header is present
+    HeaderValue(String, String), // header is present with exactly this value
+    ContentType(BodyProcessor),
+    Variant(String), // variant assigned by a TrafficSplitHandler
+    Custom(Box<dyn Fn(&RhodRequest) -> bool + Send + Sync>),
+    All(Vec<RequestPredicate>),
+    Any(Vec<RequestPredicate>),
+    Not(Box<RequestPredicate>),
+}
+
+impl RequestPredicate {
+    pub fn matches(&self, req: &RhodRequest) -> bool {
+        match self {
+            RequestPredicate::PathPrefix(prefix) => {
+                let path = req.uri().path();
+                match path.strip_prefix(prefix.as_str()) {
+                    Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+                    None => false,
+                }
+            }
+            RequestPredicate::Method(method) => req.method() == method,
+            RequestPredicate::Header(name) => req.headers().contains_key(name.as_str()),
+            RequestPredicate::HeaderValue(name, value) => req
+                .headers()
+                .get_all(name.as_str())
+                .iter()
+                .any(|v| v == value.as_str()),
+            RequestPredicate::ContentType(processor) => {
+                req.body_processor().as_ref() == Some(processor)
+            }
+            RequestPredicate::Variant(variant) => req
+                .context()
+                .get::<TrafficVariant>()
+                .is_some_and(|assigned| assigned.0 == *variant),
+            RequestPredicate::Custom(f) => f(req),
+            RequestPredicate::All(predicates) => predicates.iter().all(|p| p.matches(req)),
+            RequestPredicate::Any(predicates) => predicates.iter().any(|p| p.matches(req)),
+            RequestPredicate::Not(predicate) => !predicate.matches(req),
+        }
+    }
+
+    pub fn and(self, other: RequestPredicate) -> RequestPredicate {
+        RequestPredicate::All(vec![self, other])
+    }
+
+    pub fn or(self, other: RequestPredicate) -> RequestPredicate {
+        RequestPredicate::Any(vec![self, other])
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> RequestPredicate {
+        RequestPredicate::Not(Box::new(self))
+    }
+}
+
+// Handler that does nothing, used when a conditional handler is skipped
+pub struct PassThroughHandler {}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for PassThroughHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        Ok(())
+    }
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+// Runs the inner handler only when the predicate matches the request, otherwise passes through.
+// It is a dynamic handler, so the decision taken for the request is kept for the response.
+pub struct ConditionalHandler<C> {
+    predicate: RequestPredicate,
+    inner: Box<dyn RhodHandler<C>>,
+    pass_through: PassThroughHandler,
+}
+
+impl<C> ConditionalHandler<C> {
+    pub fn new(
+        predicate: RequestPredicate,
+        inner: Box<dyn RhodHandler<C>>,
+    ) -> ConditionalHandler<C> {
+        ConditionalHandler {
+            predicate,
+            inner,
+            pass_through: PassThroughHandler {},
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + 'static> DynamicRhodHandler<C> for ConditionalHandler<C> {
+    async fn get_handler<'a>(
+        &'a self,
+        _conn: &RhodConnInfo,
+        req: &RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<&'a dyn RhodHandler<C>> {
+        if self.predicate.matches(req) {
+            Ok(&*self.inner)
+        } else {
+            Ok(&self.pass_through)
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn needs_body(&self) -> bool {
+        self.inner.needs_body()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[test]
+    fn test_predicates() {
+        let req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri("https://www.rust-lang.org/api/users")
+            .header("Content-Type", "application/json")
+            .header("X-Tenant", "acme")
+            .build()
+            .unwrap();
+
+        assert!(RequestPredicate::PathPrefix("/api".to_string()).matches(&req));
+        assert!(RequestPredicate::PathPrefix("/api/".to_string()).matches(&req));
+        assert!(!RequestPredicate::PathPrefix("/ap".to_string()).matches(&req));
+        assert!(RequestPredicate::Method(Method::POST).matches(&req));
+        assert!(RequestPredicate::Header("x-tenant".to_string()).matches(&req));
+        assert!(
+            RequestPredicate::HeaderValue("X-Tenant".to_string(), "acme".to_string()).matches(&req)
+        );
+        assert!(
+            !RequestPredicate::HeaderValue("X-Tenant".to_string(), "other".to_string())
+                .matches(&req)
+        );
+        assert!(RequestPredicate::ContentType(BodyProcessor::JSON).matches(&req));
+        assert!(RequestPredicate::Custom(Box::new(|r| r.uri().query().is_none())).matches(&req));
+
+        let combined = RequestPredicate::Method(Method::GET)
+            .or(RequestPredicate::PathPrefix("/api".to_string()))
+            .and(RequestPredicate::Header("Cookie".to_string()).not());
+        assert!(combined.matches(&req));
+    }
+
+    struct Inner {}
+    #[async_trait]
+    impl RhodHandler<()> for Inner {
+        async fn handle_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &mut RhodRequest,
+            _comm: &mut (),
+        ) -> RhodResult<()> {
+            Ok(())
+        }
+        async fn catch_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &RhodRequest,
+            _err: &RhodError,
+            _comm: &(),
+        ) {
+        }
+        async fn handle_response(
+            &self,
+            _conn: &RhodConnInfo,
+            res: RhodResponse,
+            _comm: &mut (),
+        ) -> (RhodResponse, RhodResult<()>) {
+            (res, Ok(()))
+        }
+        async fn catch_response(
+            &self,
+            _conn: &RhodConnInfo,
+            _res: &RhodResponse,
+            _err: &RhodError,
+            _comm: &(),
+        ) {
+        }
+        fn name(&self) -> &str {
+            "inner"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_handler() {
+        let handler =
+            ConditionalHandler::new(RequestPredicate::Method(Method::DELETE), Box::new(Inner {}));
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        assert_eq!(DynamicRhodHandler::name(&handler), "inner");
+
+        let req = RhodRequest::builder()
+            .method(Method::DELETE)
+            .build()
+            .unwrap();
+        let selected = handler.get_handler(&conn, &req, &mut ()).await.unwrap();
+        assert_eq!(selected.name(), "inner");
+
+        let req = RhodRequest::builder().build().unwrap();
+        let selected = handler.get_handler(&conn, &req, &mut ()).await.unwrap();
+        assert_ne!(selected.name(), "inner");
+    }
+}
@@ -0,0 +1,381 @@
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, VARY,
+};
+use http::Response as HyperResponse;
+use http::{Method, StatusCode};
+use std::time::UNIX_EPOCH;
+
+// Headers sent with a 304 response (RFC 7232, section 4.1)
+pub(crate) const NOT_MODIFIED_HEADERS: [HeaderName; 6] =
+    [CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, VARY];
+
+pub(crate) fn parse_http_date(value: Option<&HeaderValue>) -> Option<u64> {
+    let value = value?.to_str().ok()?;
+    let date = httpdate::parse_http_date(value).ok()?;
+    date.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+// True if the validators of the request (If-None-Match, If-Modified-Since) match the response ones,
+// so a 304 can be sent instead (RFC 7232, section 6)
+pub(crate) fn not_modified(
+    req_headers: &HeaderMap,
+    etag: Option<&[u8]>,
+    last_modified: Option<&[u8]>,
+) -> bool {
+    if req_headers.contains_key(IF_NONE_MATCH) {
+        let etag = etag.map(weak_etag);
+        return req_headers.get_all(IF_NONE_MATCH).iter().any(|value| {
+            value
+                .as_bytes()
+                .split(|b| *b == b',')
+                .map(trim_bytes)
+                .any(|tag| tag == b"*" || Some(weak_etag(tag)) == etag)
+        });
+    }
+
+    let last_modified = last_modified
+        .and_then(|v| HeaderValue::from_bytes(v).ok())
+        .and_then(|v| parse_http_date(Some(&v)));
+    match (
+        parse_http_date(req_headers.get(IF_MODIFIED_SINCE)),
+        last_modified,
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// Weak comparison ignores the W/ prefix
+fn weak_etag(tag: &[u8]) -> &[u8] {
+    tag.strip_prefix(b"W/").unwrap_or(tag)
+}
+
+fn trim_bytes(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |p| p + 1);
+    &value[start..end]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtagKind {
+    Strong, // "<hash>": the body is byte for byte the same
+    Weak,   // W/"<hash>": the body is semantically the same
+}
+
+// ETag for a body, using the 64 bits FNV-1a hash of its bytes
+pub fn compute_etag(body: &[u8], kind: EtagKind) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in body {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    match kind {
+        EtagKind::Strong => format!("\"{:016x}\"", hash),
+        EtagKind::Weak => format!("W/\"{:016x}\"", hash),
+    }
+}
+
+// Request validators, saved in the request context for the response
+#[derive(Clone)]
+struct RequestValidators(HeaderMap);
+
+// Answers 304 Not Modified (dropping the body) when the validators of a GET/HEAD request
+// match the ETag or Last-Modified of a 200 response.
+// ETags provided by the service are kept. Otherwise, an ETag is computed for bodies with a known size up to max_body_size.
+pub struct ConditionalGetHandler {
+    generate: Option<EtagKind>, // None: only ETags provided by the service are used
+    max_body_size: u64,
+}
+
+impl ConditionalGetHandler {
+    pub fn new() -> ConditionalGetHandler {
+        ConditionalGetHandler {
+            generate: Some(EtagKind::Strong),
+            max_body_size: 1024 * 1024,
+        }
+    }
+
+    pub fn with_etag_kind(self, kind: EtagKind) -> ConditionalGetHandler {
+        ConditionalGetHandler {
+            generate: Some(kind),
+            ..self
+        }
+    }
+
+    // Only uses the ETags provided by the service
+    pub fn service_etags_only(self) -> ConditionalGetHandler {
+        ConditionalGetHandler {
+            generate: None,
+            ..self
+        }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: u64) -> ConditionalGetHandler {
+        ConditionalGetHandler {
+            max_body_size,
+            ..self
+        }
+    }
+}
+
+impl Default for ConditionalGetHandler {
+    fn default() -> ConditionalGetHandler {
+        ConditionalGetHandler::new()
+    }
+}
+
+fn not_modified_response(res: &RhodResponse) -> RhodResult<RhodResponse> {
+    let mut builder = HyperResponse::builder().status(StatusCode::NOT_MODIFIED);
+    for name in NOT_MODIFIED_HEADERS.iter().chain([LAST_MODIFIED].iter()) {
+        for value in res.headers().get_all(name).iter() {
+            builder = builder.header(name, value);
+        }
+    }
+    match builder.body(RhodBody::empty()) {
+        Ok(new_res) => {
+            let mut new_res = RhodResponse::new(new_res);
+            new_res.attach_context(res.context());
+            Ok(new_res)
+        }
+        Err(e) => Err(RhodError::from_string(
+            format!("Cant build not modified response. {}", e),
+            RhodErrorLevel::Error,
+        )),
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for ConditionalGetHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Ok(());
+        }
+
+        let mut validators = HeaderMap::new();
+        for name in [IF_NONE_MATCH, IF_MODIFIED_SINCE].iter() {
+            for value in req.headers().get_all(name).iter() {
+                validators.append(name, value.clone());
+            }
+        }
+        req.context().insert(RequestValidators(validators));
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let validators = match res.context().remove::<RequestValidators>() {
+            Some(RequestValidators(validators)) => validators,
+            None => return (res, Ok(())),
+        };
+        if res.status_as_int() != 200 {
+            return (res, Ok(()));
+        }
+
+        if let Some(kind) = self.generate {
+            let known_size = res
+                .body_size_hint()
+                .is_some_and(|s| s <= self.max_body_size);
+            if !res.headers().contains_key(ETAG) && known_size {
+                let body = match res.body_bytes().await {
+                    Ok(body) => body,
+                    Err(e) => return (res, Err(e)),
+                };
+                if let Ok(etag) = HeaderValue::from_str(&compute_etag(&body, kind)) {
+                    res.headers_mut().insert(ETAG, etag);
+                }
+            }
+        }
+
+        if validators.is_empty()
+            || !not_modified(
+                &validators,
+                res.headers().get(ETAG).map(|v| v.as_bytes()),
+                res.headers().get(LAST_MODIFIED).map(|v| v.as_bytes()),
+            )
+        {
+            return (res, Ok(()));
+        }
+
+        match not_modified_response(&res) {
+            Ok(new_res) => (new_res, Ok(())),
+            Err(e) => (res, Err(e)),
+        }
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandlerInStack, RhodService, RhodStack};
+    use crate::CommunicationChannel;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    struct Service {}
+    #[async_trait]
+    impl RhodService<Comm> for Service {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let builder = RhodResponse::builder()
+                .header("Cache-Control", "max-age=10")
+                .header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT");
+            let builder = if req.uri().path() == "/tagged" {
+                builder.header("ETag", "W/\"service\"")
+            } else {
+                builder
+            };
+            builder.body_str("hello").build()
+        }
+    }
+
+    async fn get(stack: &RhodStack<Comm>, req: RhodRequest) -> RhodResponse {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        stack.execute(&conn, req).await.unwrap()
+    }
+
+    #[test]
+    fn test_compute_etag() {
+        assert_eq!(compute_etag(b"", EtagKind::Strong), "\"cbf29ce484222325\"");
+        assert_eq!(compute_etag(b"a", EtagKind::Weak), "W/\"af63dc4c8601ec8c\"");
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let req = RhodRequest::builder()
+            .header("If-None-Match", "\"a\", W/\"b\"")
+            .build()
+            .unwrap();
+        assert!(not_modified(req.headers(), Some(b"\"b\""), None));
+        assert!(!not_modified(req.headers(), Some(b"\"c\""), None));
+        assert!(!not_modified(req.headers(), None, None));
+
+        let req = RhodRequest::builder()
+            .header("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .build()
+            .unwrap();
+        assert!(not_modified(
+            req.headers(),
+            None,
+            Some(b"Sun, 06 Nov 1994 08:49:37 GMT")
+        ));
+        assert!(!not_modified(
+            req.headers(),
+            None,
+            Some(b"Mon, 07 Nov 1994 08:49:37 GMT")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get_handler() {
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(
+                ConditionalGetHandler::new(),
+            ))],
+            Box::new(Service {}),
+        );
+
+        let res = get(&stack, RhodRequest::builder().build().unwrap()).await;
+        let etag = compute_etag(b"hello", EtagKind::Strong);
+        assert_eq!(res.headers().get("ETag").unwrap(), etag.as_str());
+
+        let mut res = get(
+            &stack,
+            RhodRequest::builder()
+                .header("If-None-Match", &etag)
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status_as_int(), 304);
+        assert_eq!(res.headers().get("Cache-Control").unwrap(), "max-age=10");
+        assert_eq!(res.body().await.unwrap(), Vec::<u8>::new());
+
+        let res = get(
+            &stack,
+            RhodRequest::builder()
+                .header("If-None-Match", "\"other\"")
+                .header("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status_as_int(), 200);
+
+        let res = get(
+            &stack,
+            RhodRequest::builder()
+                .header("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status_as_int(), 304);
+
+        let res = get(
+            &stack,
+            RhodRequest::builder()
+                .uri("/tagged")
+                .header("If-None-Match", "\"service\"")
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status_as_int(), 304);
+        assert_eq!(res.headers().get("ETag").unwrap(), "W/\"service\"");
+    }
+}
@@ -0,0 +1,196 @@
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::stack::{DynamicRhodHandler, RhodHandler};
+use crate::util::Lookups;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub type ResolutionKey = Box<dyn Fn(&RhodConnInfo, &RhodRequest) -> Option<String> + Send + Sync>;
+
+// Chooses which handler of a CachedDynamicHandler runs for a request
+#[async_trait]
+pub trait HandlerResolver<C>: Send + Sync {
+    // Index of the handler, in the order they were given to the CachedDynamicHandler
+    async fn resolve(
+        &self,
+        conn: &RhodConnInfo,
+        req: &RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<usize>;
+}
+
+// Runs one of its handlers, chosen by a resolver for every request.
+// The choice is cached by a key computed from the request (ie: the tenant header), so an expensive
+// resolution (ie: a tenant lookup) runs once per key. Requests without a key are always resolved,
+// and failed resolutions are not cached.
+pub struct CachedDynamicHandler<C> {
+    handlers: Vec<Box<dyn RhodHandler<C>>>,
+    resolver: Box<dyn HandlerResolver<C>>,
+    key: ResolutionKey,
+    ttl: Option<Duration>, // None keeps the choices until they are evicted
+    cache: Mutex<Lookups<usize>>, // handler index by key
+}
+
+impl<C> CachedDynamicHandler<C> {
+    pub fn new(
+        handlers: Vec<Box<dyn RhodHandler<C>>>,
+        resolver: Box<dyn HandlerResolver<C>>,
+        key: ResolutionKey,
+    ) -> CachedDynamicHandler<C> {
+        CachedDynamicHandler {
+            handlers,
+            resolver,
+            key,
+            ttl: None,
+            cache: Mutex::new(Lookups::new(1024)),
+        }
+    }
+
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        CachedDynamicHandler {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    // Max number of cached choices, the oldest one is evicted when it is reached
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        CachedDynamicHandler {
+            cache: Mutex::new(Lookups::new(capacity)),
+            ..self
+        }
+    }
+
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    fn lookup(&self, key: &str) -> Option<usize> {
+        self.cache.lock().unwrap().get(key, self.ttl)
+    }
+
+    fn store(&self, key: String, index: usize) {
+        self.cache.lock().unwrap().insert(key, index);
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + 'static> DynamicRhodHandler<C> for CachedDynamicHandler<C> {
+    async fn get_handler<'a>(
+        &'a self,
+        conn: &RhodConnInfo,
+        req: &RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<&'a dyn RhodHandler<C>> {
+        let key = (self.key)(conn, req);
+        let index = match key.as_deref().and_then(|key| self.lookup(key)) {
+            Some(index) => index,
+            None => {
+                let index = self.resolver.resolve(conn, req, comm).await?;
+                if index >= self.handlers.len() {
+                    return Err(RhodError::from_string(
+                        format!("Resolved handler {} doesnt exist", index),
+                        RhodErrorLevel::Error,
+                    ));
+                }
+                if let Some(key) = key {
+                    self.store(key, index);
+                }
+                index
+            }
+        };
+        Ok(&*self.handlers[index])
+    }
+
+    fn needs_body(&self) -> bool {
+        self.handlers.iter().any(|handler| handler.needs_body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::PassThroughHandler;
+    use crate::protocols::HttpProtocol;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Resolves the tenant by its header: "b" uses the second handler, unknown tenants fail
+    struct TenantResolver {
+        calls: Arc<AtomicUsize>,
+    }
+    #[async_trait]
+    impl HandlerResolver<()> for TenantResolver {
+        async fn resolve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: &RhodRequest,
+            _comm: &mut (),
+        ) -> RhodResult<usize> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match req.headers().get("x-tenant").map(|v| v.as_bytes()) {
+                Some(b"a") => Ok(0),
+                Some(b"b") => Ok(1),
+                _ => Err(RhodError::from_str(
+                    "unknown tenant",
+                    RhodErrorLevel::Warning,
+                )),
+            }
+        }
+    }
+
+    fn tenant(name: &str) -> RhodRequest {
+        RhodRequest::builder()
+            .header("x-tenant", name)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cached_dynamic_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler: CachedDynamicHandler<()> = CachedDynamicHandler::new(
+            vec![
+                Box::new(PassThroughHandler {}),
+                Box::new(PassThroughHandler {}),
+            ],
+            Box::new(TenantResolver {
+                calls: Arc::clone(&calls),
+            }),
+            Box::new(|_, req| {
+                req.headers()
+                    .get("x-tenant")
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from)
+            }),
+        )
+        .with_capacity(1);
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+
+        for _ in 0..3 {
+            assert!(handler
+                .get_handler(&conn, &tenant("a"), &mut ())
+                .await
+                .is_ok());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // failures are not cached, and the capacity is kept
+        assert!(handler
+            .get_handler(&conn, &tenant("c"), &mut ())
+            .await
+            .is_err());
+        assert!(handler
+            .get_handler(&conn, &tenant("c"), &mut ())
+            .await
+            .is_err());
+        assert!(handler
+            .get_handler(&conn, &tenant("b"), &mut ())
+            .await
+            .is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(handler.cached(), 1);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{CONNECTION, EXPECT};
+use http::{StatusCode, Version};
+
+// Rejects uploads before their body is sent.
+// A client sending Expect: 100-continue waits for 100 Continue before sending the body, and the server
+// sends it when the body is first read. So the handlers running before any handler (or the service)
+// reading the body can veto the upload (ie: auth) answering with the final response, and the body is
+// never sent. Place this handler first, and the handlers reading bodies after the ones that can reject.
+// - Expectations other than 100-continue are answered with 417 Expectation Failed
+// - Bodies with a Content-Length over the maximum are answered with 413 Payload Too Large
+pub struct ExpectContinueHandler {
+    max_body_size: Option<u64>,
+}
+
+impl Default for ExpectContinueHandler {
+    fn default() -> Self {
+        ExpectContinueHandler::new()
+    }
+}
+
+impl ExpectContinueHandler {
+    pub fn new() -> ExpectContinueHandler {
+        ExpectContinueHandler {
+            max_body_size: None,
+        }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: u64) -> Self {
+        ExpectContinueHandler {
+            max_body_size: Some(max_body_size),
+        }
+    }
+}
+
+fn reject(req: &RhodRequest, status: StatusCode, msg: String) -> RhodError {
+    let err = RhodError::from_string(msg, RhodErrorLevel::Debug);
+    let mut builder = RhodResponse::builder().status(status);
+    // the body may still be sent by clients that dont wait, so the connection isnt reused
+    if req.version() <= Version::HTTP_11 {
+        builder = builder.header(CONNECTION.as_str(), "close");
+    }
+    err.with_built(builder.build())
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for ExpectContinueHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if let Some(expect) = req.header_str(EXPECT) {
+            if !expect.trim().eq_ignore_ascii_case("100-continue") {
+                let msg = format!("Unsupported expectation {}", expect);
+                return Err(reject(req, StatusCode::EXPECTATION_FAILED, msg));
+            }
+        }
+        match (self.max_body_size, req.content_length()) {
+            (Some(max), Some(length)) if length > max => {
+                let msg = format!("Body of {} bytes, over the limit of {}", length, max);
+                Err(reject(req, StatusCode::PAYLOAD_TOO_LARGE, msg))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
@@ -0,0 +1,614 @@
+use super::RequestId;
+use crate::body::{read_with_trailers, BodyPassthrough, BoxError, RhodBody};
+use crate::client::RhodClient;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING,
+};
+use http::uri::PathAndQuery;
+use http::{HeaderMap, Method, StatusCode, Uri, Version};
+use http_body::{Body, Frame};
+use http_body_util::BodyExt;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+mod grpc;
+
+// Authorization service called by an ExtAuthzHandler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtAuthzService {
+    // The request is sent with its method to the uri path followed by the request path and query. A
+    // 2xx allows it, other statuses below 500 deny it and are sent to the client as they are
+    Http(Uri),
+    // Envoy external authorization API (envoy.service.auth.v3.Authorization/Check), over h2c or h2
+    Grpc(Uri),
+}
+
+// What happens to requests when the authorization service fails (unreachable, timed out, 5xx...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtAuthzFailure {
+    Allow, // fail open
+    Deny,  // fail closed, the request is answered 403
+}
+
+// Request metadata sent to the service
+struct CheckRequest {
+    id: String,
+    client: SocketAddr,
+    method: String,
+    scheme: String,
+    host: String,
+    path: String,
+    query: String,
+    protocol: String,
+    headers: Vec<(String, String)>,
+    size: Option<u64>,
+    body: Bytes, // prefix of the body, if sent
+}
+
+// Header to add, appended if true or set otherwise
+type HeaderOption = (HeaderName, HeaderValue, bool);
+
+// Answer of the service
+struct Decision {
+    allowed: bool,
+    headers: Vec<HeaderOption>, // added to the request if allowed
+    remove_headers: Vec<HeaderName>,
+    denied: Option<(StatusCode, Vec<HeaderOption>, Vec<u8>)>,
+}
+
+impl Decision {
+    fn allow() -> Decision {
+        Decision {
+            allowed: true,
+            headers: vec![],
+            remove_headers: vec![],
+            denied: None,
+        }
+    }
+
+    fn deny(status: StatusCode, headers: Vec<HeaderOption>, body: Vec<u8>) -> Decision {
+        Decision {
+            allowed: false,
+            headers: vec![],
+            remove_headers: vec![],
+            denied: Some((status, headers, body)),
+        }
+    }
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    name == CONNECTION
+        || name == TRANSFER_ENCODING
+        || name == CONTENT_LENGTH
+        || name == HOST
+        || name.as_str() == "keep-alive"
+        || name.as_str() == "te"
+        || name.as_str() == "upgrade"
+}
+
+// Body whose first frames were already read
+struct PrefixedBody {
+    read: VecDeque<Frame<Bytes>>,
+    rest: RhodBody,
+}
+
+impl Body for PrefixedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match self.read.pop_front() {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+}
+
+// Reads up to max bytes of the request body, which keeps the whole body
+async fn body_prefix(req: &mut RhodRequest, max: usize) -> RhodResult<Bytes> {
+    let mut body = req.take_body();
+    let mut read = VecDeque::new();
+    let mut prefix = BytesMut::new();
+    while prefix.len() < max {
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    let len = data.len().min(max - prefix.len());
+                    prefix.extend_from_slice(&data[..len]);
+                }
+                read.push_back(frame);
+            }
+            Some(Err(e)) => {
+                return Err(RhodError::from_string(
+                    format!("Cant read request body. {}", e),
+                    RhodErrorLevel::Error,
+                ))
+            }
+            None => break,
+        }
+    }
+    req.set_body(RhodBody::new(PrefixedBody { read, rest: body }));
+    Ok(prefix.freeze())
+}
+
+// Delegates the authorization of requests to an external service (ie: OPA or an Envoy ext_authz
+// server), sending their metadata and optionally a prefix of their body. The service allows the request,
+// possibly adding or removing request headers (ie: the identity of the user), or denies it with the
+// response to send. Failures of the service (unreachable, timeout, 5xx) allow or deny the request
+// according to on_failure, denying by default
+pub struct ExtAuthzHandler {
+    service: ExtAuthzService,
+    client: RhodClient,
+    timeout: Duration,
+    on_failure: ExtAuthzFailure,
+    max_body_size: usize,
+    forwarded_headers: Option<Vec<HeaderName>>,
+    upstream_headers: Vec<HeaderName>,
+}
+
+impl ExtAuthzHandler {
+    pub fn new(service: ExtAuthzService) -> ExtAuthzHandler {
+        let client = match service {
+            ExtAuthzService::Http(_) => RhodClient::new(),
+            ExtAuthzService::Grpc(_) => RhodClient::builder().http2_only(true).build(),
+        };
+        ExtAuthzHandler {
+            service,
+            client,
+            timeout: Duration::from_millis(500),
+            on_failure: ExtAuthzFailure::Deny,
+            max_body_size: 0,
+            forwarded_headers: None,
+            upstream_headers: vec![],
+        }
+    }
+
+    // The client must speak HTTP/2 (http2_only) for gRPC services
+    pub fn with_client(self, client: RhodClient) -> Self {
+        ExtAuthzHandler { client, ..self }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        ExtAuthzHandler { timeout, ..self }
+    }
+
+    pub fn with_on_failure(self, on_failure: ExtAuthzFailure) -> Self {
+        ExtAuthzHandler { on_failure, ..self }
+    }
+
+    // Sends up to max_body_size bytes of the request body, none by default. Skipped with body passthrough
+    pub fn with_body(self, max_body_size: usize) -> Self {
+        ExtAuthzHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    // Request headers sent to the service, all of them by default
+    pub fn with_forwarded_headers(self, names: Vec<HeaderName>) -> Self {
+        ExtAuthzHandler {
+            forwarded_headers: Some(names),
+            ..self
+        }
+    }
+
+    // Headers of an allowing HTTP service response set on the request (ie: x-user-id). gRPC services
+    // give the headers to add in their response
+    pub fn with_upstream_headers(self, names: Vec<HeaderName>) -> Self {
+        ExtAuthzHandler {
+            upstream_headers: names,
+            ..self
+        }
+    }
+
+    fn forwarded(&self, name: &HeaderName) -> bool {
+        match &self.forwarded_headers {
+            Some(names) => names.contains(name),
+            None => !is_hop_by_hop(name),
+        }
+    }
+
+    async fn check_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+    ) -> RhodResult<CheckRequest> {
+        let passthrough = req.context().get::<BodyPassthrough>().is_some();
+        let body = match self.max_body_size {
+            0 => Bytes::new(),
+            _ if passthrough => Bytes::new(),
+            max => body_prefix(req, max).await?,
+        };
+        let headers = req
+            .headers()
+            .iter()
+            .filter(|(name, _)| self.forwarded(name))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        Ok(CheckRequest {
+            id: req
+                .context()
+                .get::<RequestId>()
+                .map_or(String::new(), |id| id.id),
+            client: conn.addr,
+            method: req.method_str().to_string(),
+            scheme: req.uri().scheme_str().unwrap_or("http").to_string(),
+            host: req.host().unwrap_or("").to_string(),
+            path: req.uri().path().to_string(),
+            query: req.uri().query().unwrap_or("").to_string(),
+            protocol: req.version_string(),
+            headers,
+            size: req.content_length(),
+            body,
+        })
+    }
+
+    async fn check(&self, check: CheckRequest) -> RhodResult<Decision> {
+        let call = async {
+            match &self.service {
+                ExtAuthzService::Http(uri) => self.check_http(uri, check).await,
+                ExtAuthzService::Grpc(uri) => self.check_grpc(uri, check).await,
+            }
+        };
+        match runtime::timeout(self.timeout, call).await {
+            Ok(decision) => decision,
+            Err(_) => Err(service_error("timed out".to_string())),
+        }
+    }
+
+    async fn check_http(&self, uri: &Uri, check: CheckRequest) -> RhodResult<Decision> {
+        let path = format!(
+            "{}{}{}",
+            uri.path().trim_end_matches('/'),
+            check.path,
+            if check.query.is_empty() {
+                String::new()
+            } else {
+                format!("?{}", check.query)
+            }
+        );
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(
+            path.parse::<PathAndQuery>()
+                .map_err(|_| service_error(format!("invalid path {}", path)))?,
+        );
+        let target = Uri::from_parts(parts).map_err(|e| service_error(e.to_string()))?;
+        let mut builder = RhodRequest::builder()
+            .method(Method::from_bytes(check.method.as_bytes()).unwrap_or(Method::GET))
+            .uri(&target.to_string())
+            .header(HOST.as_str(), target.authority().map_or("", |a| a.as_str()))
+            .header("x-forwarded-for", &check.client.ip().to_string())
+            .header("x-forwarded-host", &check.host)
+            .header("x-forwarded-proto", &check.scheme);
+        // the forwarding headers of the client arent sent, the service would take them as set by us
+        for (name, value) in check
+            .headers
+            .iter()
+            .filter(|(name, _)| !is_forwarding(name))
+        {
+            builder = builder.header(name, value);
+        }
+        let mut req = builder.body_bytes(&check.body).build()?;
+        req.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(check.body.len()));
+
+        let mut res = self.client.send(req).await?;
+        let body = res.body().await?;
+        let status = StatusCode::from_u16(res.status_as_int()).unwrap_or(StatusCode::BAD_GATEWAY);
+        if status.is_server_error() {
+            return Err(service_error(format!("answered {}", status)));
+        }
+        if !status.is_success() {
+            let headers = res
+                .headers()
+                .iter()
+                .filter(|(name, _)| !is_hop_by_hop(name))
+                .map(|(name, value)| (name.clone(), value.clone(), true))
+                .collect();
+            return Ok(Decision::deny(status, headers, body));
+        }
+        let mut decision = Decision::allow();
+        for name in &self.upstream_headers {
+            match res.headers().get(name) {
+                Some(value) => decision.headers.push((name.clone(), value.clone(), false)),
+                // a header the service didnt give cant be sent by the client either
+                None => decision.remove_headers.push(name.clone()),
+            }
+        }
+        Ok(decision)
+    }
+
+    async fn check_grpc(&self, uri: &Uri, check: CheckRequest) -> RhodResult<Decision> {
+        let target = format!(
+            "{}{}",
+            uri.to_string().trim_end_matches('/'),
+            grpc::CHECK_PATH
+        );
+        let mut req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri(&target)
+            .version(Version::HTTP_2)
+            .header(CONTENT_TYPE.as_str(), "application/grpc")
+            .header("te", "trailers")
+            .body_bytes(&grpc::encode_check(&check))
+            .build()?;
+        req.headers_mut().remove(HOST);
+
+        let mut res = self.client.send(req).await?;
+        let (body, trailers) = read_with_trailers(res.take_body())
+            .await
+            .map_err(|e| service_error(e.to_string()))?;
+        // trailers-only responses have the status in the headers
+        let status = trailers
+            .as_ref()
+            .and_then(|t| t.get("grpc-status"))
+            .or_else(|| res.headers().get("grpc-status"))
+            .and_then(|s| s.to_str().ok());
+        if status != Some("0") {
+            return Err(service_error(format!(
+                "answered grpc-status {}",
+                status.unwrap_or("missing")
+            )));
+        }
+        grpc::decode_check(&body)
+            .ok_or_else(|| service_error("sent an invalid CheckResponse".to_string()))
+    }
+}
+
+fn service_error(msg: String) -> RhodError {
+    RhodError::from_string(
+        format!("External authorization service {}", msg),
+        RhodErrorLevel::Error,
+    )
+}
+
+fn forbidden(msg: String) -> RhodError {
+    let err = RhodError::from_string(msg, RhodErrorLevel::Warning);
+    err.with_built(
+        RhodResponse::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body_str("Forbidden")
+            .build(),
+    )
+}
+
+// Headers set by the handler for the HTTP service, names are lowercase
+fn is_forwarding(name: &str) -> bool {
+    name == HOST.as_str() || name == "forwarded" || name.starts_with("x-forwarded-")
+}
+
+fn apply_headers(headers: &mut HeaderMap, added: Vec<HeaderOption>) {
+    for (name, value, append) in added {
+        if append {
+            headers.append(name, value);
+        } else {
+            headers.insert(name, value);
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for ExtAuthzHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let check = self.check_request(conn, req).await?;
+        let decision = match self.check(check).await {
+            Ok(decision) => decision,
+            Err(e) => match self.on_failure {
+                ExtAuthzFailure::Allow => {
+                    warn!("{}, allowing {}", e, req.uri().path());
+                    return Ok(());
+                }
+                ExtAuthzFailure::Deny => {
+                    return Err(forbidden(format!("{}, denying {}", e, req.uri().path())))
+                }
+            },
+        };
+        if decision.allowed {
+            for name in &decision.remove_headers {
+                req.headers_mut().remove(name);
+            }
+            apply_headers(req.headers_mut(), decision.headers);
+            return Ok(());
+        }
+        let (status, headers, body) =
+            decision
+                .denied
+                .unwrap_or((StatusCode::FORBIDDEN, vec![], vec![]));
+        let mut res = RhodResponse::builder()
+            .status(status)
+            .body_bytes(&body)
+            .build()?;
+        apply_headers(res.headers_mut(), headers);
+        Err(RhodError::from_string(
+            format!(
+                "Denied by the external authorization service {}",
+                req.uri().path()
+            ),
+            RhodErrorLevel::Warning,
+        )
+        .with_response(res))
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        self.max_body_size > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use http::{Request, Response};
+    use hyper::body::Incoming;
+    use hyper::server::conn::{http1, http2};
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    // HTTP service allowing the bearer token good as alice
+    async fn http_authz(req: Request<Incoming>) -> Result<Response<RhodBody>, Infallible> {
+        let forwarded_for: Vec<_> = req.headers().get_all("x-forwarded-for").iter().collect();
+        let allowed = req.uri().path() == "/authz/orders"
+            && req.headers().get("authorization").map(|v| v.as_bytes()) == Some(b"Bearer good")
+            && forwarded_for == ["127.0.0.1"]
+            && !req.headers().contains_key("forwarded");
+        let res = match allowed {
+            true => Response::builder().header("x-user-id", "alice"),
+            false => Response::builder()
+                .status(401)
+                .header("www-authenticate", "Bearer"),
+        };
+        Ok(res.body(RhodBody::from(Bytes::from("denied"))).unwrap())
+    }
+
+    // gRPC service allowing the requests whose body starts with ok
+    async fn grpc_authz(req: Request<Incoming>) -> Result<Response<RhodBody>, Infallible> {
+        assert_eq!(req.uri().path(), grpc::CHECK_PATH);
+        let body = RhodBody::new(req.into_body()).to_bytes().await.unwrap();
+        let fields = grpc::decode_http_request(&body);
+        let allowed = fields.contains(&(12, b"ok".to_vec()));
+        let message = match allowed {
+            true => grpc::encode_response(0, &[("x-user-id", "bob")], ""),
+            false => grpc::encode_response(7, &[], "not ok"),
+        };
+        let (mut sender, body) = RhodBody::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from(message)).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        Ok(Response::builder()
+            .header("content-type", "application/grpc")
+            .body(body)
+            .unwrap())
+    }
+
+    async fn listen(http2: bool) -> Uri {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = match http2 {
+            true => format!("http://{}", listener.local_addr().unwrap()),
+            false => format!("http://{}/authz", listener.local_addr().unwrap()),
+        };
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let io = TokioIo::new(stream);
+                tokio::spawn(async move {
+                    let _ = match http2 {
+                        true => {
+                            http2::Builder::new(TokioExecutor::new())
+                                .serve_connection(io, service_fn(grpc_authz))
+                                .await
+                        }
+                        false => {
+                            http1::Builder::new()
+                                .serve_connection(io, service_fn(http_authz))
+                                .await
+                        }
+                    };
+                });
+            }
+        });
+        uri.parse().unwrap()
+    }
+
+    async fn handle(
+        handler: &ExtAuthzHandler,
+        authorization: &str,
+        body: &str,
+    ) -> Result<RhodRequest, RhodResponse> {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let mut req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri("/orders")
+            .header("authorization", authorization)
+            .header("x-user-id", "forged")
+            .header("x-forwarded-for", "10.0.0.1")
+            .header("forwarded", "for=10.0.0.1")
+            .body_str(body)
+            .build()
+            .unwrap();
+        match RhodHandler::<()>::handle_request(handler, &conn, &mut req, &mut ()).await {
+            Ok(()) => Ok(req),
+            Err(mut e) => Err(e.take_response().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ext_authz_handler() {
+        let handler = ExtAuthzHandler::new(ExtAuthzService::Http(listen(false).await))
+            .with_upstream_headers(vec![HeaderName::from_static("x-user-id")]);
+        let req = handle(&handler, "Bearer good", "").await.unwrap();
+        assert_eq!(req.headers()["x-user-id"], "alice");
+        let mut res = handle(&handler, "Bearer bad", "").await.unwrap_err();
+        assert_eq!(res.status_as_int(), 401);
+        assert_eq!(res.headers()["www-authenticate"], "Bearer");
+        assert_eq!(res.body().await.unwrap(), b"denied".to_vec());
+
+        // the body prefix is sent, and kept whole for the service
+        let handler = ExtAuthzHandler::new(ExtAuthzService::Grpc(listen(true).await)).with_body(2);
+        let mut req = handle(&handler, "", "ok, the rest").await.unwrap();
+        assert_eq!(req.headers()["x-user-id"], "bob");
+        assert_eq!(req.body().await.unwrap(), b"ok, the rest".to_vec());
+        let mut res = handle(&handler, "", "no").await.unwrap_err();
+        assert_eq!(res.status_as_int(), 401);
+        assert_eq!(res.body().await.unwrap(), b"not ok".to_vec());
+
+        // the service is down
+        let down = || ExtAuthzService::Http("http://127.0.0.1:1".parse().unwrap());
+        let res = handle(&ExtAuthzHandler::new(down()), "", "")
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 403);
+        let handler = ExtAuthzHandler::new(down()).with_on_failure(ExtAuthzFailure::Allow);
+        assert!(handle(&handler, "", "").await.is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+// Messages of the Envoy external authorization API (envoy.service.auth.v3.Authorization/Check), encoded
+// by hand: only the fields used are written and read, the others are skipped
+use super::{CheckRequest, Decision, HeaderOption};
+use http::header::{HeaderName, HeaderValue};
+use http::StatusCode;
+use std::convert::TryFrom;
+
+pub(super) const CHECK_PATH: &str = "/envoy.service.auth.v3.Authorization/Check";
+
+// Protobuf message being written
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.varint(u64::from(field) << 3);
+            self.varint(value);
+        }
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        if !value.is_empty() {
+            self.varint(u64::from(field) << 3 | 2);
+            self.varint(value.len() as u64);
+            self.0.extend_from_slice(value);
+        }
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+
+    // Entry of a map<string, string>
+    fn entry(&mut self, field: u32, key: &str, value: &str) {
+        let mut entry = Message::default();
+        entry.string(1, key);
+        entry.string(2, value);
+        self.message(field, entry);
+    }
+}
+
+// Value of a field of a message being read
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Fields of a message, None if it is malformed
+fn fields(mut data: &[u8]) -> Option<Vec<(u32, Value<'_>)>> {
+    let mut fields = vec![];
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let value = match key & 7 {
+            0 => Value::Varint(read_varint(&mut data)?),
+            1 | 5 => {
+                let len = if key & 7 == 1 { 8 } else { 4 };
+                data = data.get(len..)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = usize::try_from(read_varint(&mut data)?).ok()?;
+                let value = data.get(..len)?;
+                data = &data[len..];
+                Value::Bytes(value)
+            }
+            _ => return None,
+        };
+        fields.push((u32::try_from(key >> 3).ok()?, value));
+    }
+    Some(fields)
+}
+
+fn socket_address(addr: &str, port: u16) -> Message {
+    let mut socket = Message::default();
+    socket.string(2, addr);
+    socket.uint(3, u64::from(port));
+    let mut address = Message::default();
+    address.message(1, socket);
+    let mut peer = Message::default();
+    peer.message(1, address);
+    peer
+}
+
+// CheckRequest{attributes: AttributeContext{source, request: Request{http: HttpRequest}}}
+pub(super) fn encode_check(check: &CheckRequest) -> Vec<u8> {
+    let mut http = Message::default();
+    http.string(1, &check.id);
+    http.string(2, &check.method);
+    for (name, value) in &check.headers {
+        http.entry(3, name, value);
+    }
+    http.string(4, &check.path);
+    http.string(5, &check.host);
+    http.string(6, &check.scheme);
+    http.string(7, &check.query);
+    http.uint(9, check.size.unwrap_or(0));
+    http.string(10, &check.protocol);
+    http.bytes(12, &check.body);
+    let mut request = Message::default();
+    request.message(2, http);
+    let mut attributes = Message::default();
+    attributes.message(
+        1,
+        socket_address(&check.client.ip().to_string(), check.client.port()),
+    );
+    attributes.message(4, request);
+    let mut message = Message::default();
+    message.message(1, attributes);
+
+    // gRPC framing: not compressed, and the length
+    let mut framed = Vec::with_capacity(message.0.len() + 5);
+    framed.push(0);
+    framed.extend_from_slice(&(message.0.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&message.0);
+    framed
+}
+
+// HeaderValueOption{header: HeaderValue{key, value}, append: BoolValue}, as the header and whether it
+// is appended
+fn header_option(data: &[u8]) -> Option<HeaderOption> {
+    let (mut key, mut value, mut append) = (None, None, false);
+    for (field, v) in fields(data)? {
+        match (field, v) {
+            (1, Value::Bytes(header)) => {
+                for (field, v) in fields(header)? {
+                    match (field, v) {
+                        (1, Value::Bytes(k)) => key = Some(k),
+                        (2, Value::Bytes(v)) | (3, Value::Bytes(v)) => value = Some(v),
+                        _ => (),
+                    }
+                }
+            }
+            (2, Value::Bytes(wrapper)) => {
+                append = fields(wrapper)?
+                    .iter()
+                    .any(|(field, v)| *field == 1 && matches!(v, Value::Varint(1)));
+            }
+            _ => (),
+        }
+    }
+    let name = HeaderName::from_bytes(key?).ok()?;
+    let value = HeaderValue::from_bytes(value.unwrap_or(b"")).ok()?;
+    Some((name, value, append))
+}
+
+// Decision of a CheckResponse{status: Status{code}, denied_response, ok_response}, None if malformed
+pub(super) fn decode_check(body: &[u8]) -> Option<Decision> {
+    // the message of the gRPC frame
+    let (compressed, len) = match body.get(..5)? {
+        [compressed, a, b, c, d] => (*compressed, u32::from_be_bytes([*a, *b, *c, *d]) as usize),
+        _ => return None,
+    };
+    if compressed != 0 {
+        return None;
+    }
+    let message = body.get(5..5 + len)?;
+
+    let mut code = 0;
+    let mut denied = None;
+    let mut ok = None;
+    for (field, value) in fields(message)? {
+        match (field, value) {
+            (1, Value::Bytes(status)) => {
+                for (field, value) in fields(status)? {
+                    if let (1, Value::Varint(c)) = (field, value) {
+                        code = c;
+                    }
+                }
+            }
+            (2, Value::Bytes(response)) => denied = Some(response),
+            (3, Value::Bytes(response)) => ok = Some(response),
+            _ => (),
+        }
+    }
+
+    if code == 0 {
+        let mut decision = Decision::allow();
+        for (field, value) in ok.map_or(Some(vec![]), fields)? {
+            match (field, value) {
+                (2, Value::Bytes(option)) => decision.headers.push(header_option(option)?),
+                (5, Value::Bytes(name)) => {
+                    decision
+                        .remove_headers
+                        .push(HeaderName::from_bytes(name).ok()?);
+                }
+                _ => (),
+            }
+        }
+        return Some(decision);
+    }
+    let mut status = StatusCode::FORBIDDEN;
+    let mut headers = vec![];
+    let mut body = vec![];
+    for (field, value) in denied.map_or(Some(vec![]), fields)? {
+        match (field, value) {
+            (1, Value::Bytes(http_status)) => {
+                for (field, value) in fields(http_status)? {
+                    if let (1, Value::Varint(code)) = (field, value) {
+                        // 0 is Empty, the default status is kept
+                        if let Ok(code) = StatusCode::from_u16(code as u16) {
+                            status = code;
+                        }
+                    }
+                }
+            }
+            (2, Value::Bytes(option)) => headers.push(header_option(option)?),
+            (3, Value::Bytes(b)) => body = b.to_vec(),
+            _ => (),
+        }
+    }
+    Some(Decision::deny(status, headers, body))
+}
+
+// CheckResponse, framed, as written by an authorization service
+#[cfg(test)]
+pub(super) fn encode_response(code: u64, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+    let mut status = Message::default();
+    status.uint(1, code);
+    let mut response = Message::default();
+    for (name, value) in headers {
+        let mut header = Message::default();
+        header.string(1, name);
+        header.string(2, value);
+        let mut option = Message::default();
+        option.message(1, header);
+        response.message(2, option);
+    }
+    let mut message = Message::default();
+    message.message(1, status);
+    if code == 0 {
+        message.message(3, response);
+    } else {
+        response.string(3, body);
+        let mut http_status = Message::default();
+        http_status.uint(1, 401);
+        response.message(1, http_status);
+        message.message(2, response);
+    }
+    let mut framed = vec![0];
+    framed.extend_from_slice(&(message.0.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&message.0);
+    framed
+}
+
+// Fields of the HttpRequest of a framed CheckRequest, as read by an authorization service
+#[cfg(test)]
+pub(super) fn decode_http_request(body: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut found = vec![];
+    let mut message = &body[5..];
+    // attributes, request, http
+    for expected in [1, 4, 2].iter() {
+        message = fields(message)
+            .unwrap()
+            .into_iter()
+            .find_map(|(field, value)| match value {
+                Value::Bytes(b) if field == *expected => Some(b),
+                _ => None,
+            })
+            .unwrap();
+    }
+    for (field, value) in fields(message).unwrap() {
+        if let Value::Bytes(b) = value {
+            found.push((field, b.to_vec()));
+        }
+    }
+    found
+}
@@ -0,0 +1,375 @@
+use crate::background::BackgroundJob;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::reload::Swap;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::StatusCode;
+use maxminddb::{geoip2, Reader};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// Location of the client, saved in the request context by GeoIpHandler
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GeoInfo {
+    pub country: Option<String>, // ISO code
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+    pub tags: Vec<String>, // added by the tag lists matching the client
+}
+
+// Countries (ISO codes) or autonomous systems to block or tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeoList {
+    Countries(Vec<String>),
+    Asns(Vec<u32>),
+}
+
+impl GeoList {
+    fn matches(&self, info: &GeoInfo) -> bool {
+        match self {
+            GeoList::Countries(countries) => info
+                .country
+                .as_ref()
+                .is_some_and(|country| countries.iter().any(|c| c.eq_ignore_ascii_case(country))),
+            GeoList::Asns(asns) => info.asn.is_some_and(|asn| asns.contains(&asn)),
+        }
+    }
+}
+
+fn db_error(path: &Path, e: maxminddb::MaxMindDBError) -> RhodError {
+    RhodError::from_string(
+        format!("Couldnt read GeoIP database {}. {}", path.display(), e),
+        RhodErrorLevel::Error,
+    )
+}
+
+// MaxMind database (.mmdb) read from a file. It is hot reloadable: reload reads the file again (ie: after
+// a database update), and requests looked up after it use the new database. Clones share the database,
+// so one can be kept to reload the one of a handler
+#[derive(Clone)]
+pub struct GeoIpDb {
+    path: PathBuf,
+    reader: Arc<Swap<Reader<Vec<u8>>>>,
+    modified: Arc<Mutex<Option<SystemTime>>>, // of the file loaded
+}
+
+impl GeoIpDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> RhodResult<GeoIpDb> {
+        let path = path.as_ref().to_path_buf();
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let reader = Reader::open_readfile(&path).map_err(|e| db_error(&path, e))?;
+        Ok(GeoIpDb {
+            path,
+            reader: Arc::new(Swap::new(Arc::new(reader))),
+            modified: Arc::new(Mutex::new(modified)),
+        })
+    }
+
+    // Reads the file again. A file that cant be read is reported, and the database loaded is kept
+    pub async fn reload(&self) -> RhodResult<()> {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        let buf = tokio::fs::read(&self.path)
+            .await
+            .map_err(|e| db_error(&self.path, e.into()))?;
+        let reader = Reader::from_source(buf).map_err(|e| db_error(&self.path, e))?;
+        self.reader.store(Arc::new(reader));
+        *self.modified.lock().unwrap() = modified;
+        info!("GeoIP database {} reloaded", self.path.display());
+        Ok(())
+    }
+
+    // Job reloading the database every interval when the file was modified
+    pub fn reload_job(&self, interval: Duration) -> BackgroundJob {
+        let db = self.clone();
+        BackgroundJob::every(interval, move || {
+            let db = db.clone();
+            async move {
+                let modified = tokio::fs::metadata(&db.path)
+                    .await
+                    .and_then(|m| m.modified())
+                    .ok();
+                if modified.is_some() && modified != *db.modified.lock().unwrap() {
+                    if let Err(e) = db.reload().await {
+                        e.log();
+                    }
+                }
+            }
+        })
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.load();
+        let country: geoip2::Country = reader.lookup(ip).ok()?;
+        country
+            .country
+            .and_then(|c| c.iso_code)
+            .map(|code| code.to_string())
+    }
+
+    fn asn(&self, ip: IpAddr) -> Option<(Option<u32>, Option<String>)> {
+        let reader = self.reader.load();
+        let asn: geoip2::Asn = reader.lookup(ip).ok()?;
+        Some((
+            asn.autonomous_system_number,
+            asn.autonomous_system_organization.map(|o| o.to_string()),
+        ))
+    }
+}
+
+// Resolves the client address to its country and autonomous system, with a country database
+// (ie: GeoLite2-Country) and/or an ASN one (GeoLite2-ASN), saving them as a GeoInfo in the context.
+// Clients in a blocked list are answered with 403, and the ones in a tag list get its tag:
+//
+//  let countries = GeoIpDb::open("GeoLite2-Country.mmdb")?;
+//  rhodium.spawn_background("geoip reload", countries.reload_job(Duration::from_secs(3600)));
+//  let geoip = GeoIpHandler::new()
+//      .with_country_db(countries)
+//      .with_block(GeoList::Asns(vec![64496]))
+//      .with_tag(GeoList::Countries(vec!["US".into(), "CA".into()]), "north-america");
+#[derive(Default)]
+pub struct GeoIpHandler {
+    country_db: Option<GeoIpDb>,
+    asn_db: Option<GeoIpDb>,
+    blocked: Vec<GeoList>,
+    tags: Vec<(GeoList, String)>,
+}
+
+impl GeoIpHandler {
+    pub fn new() -> GeoIpHandler {
+        GeoIpHandler::default()
+    }
+
+    pub fn with_country_db(self, db: GeoIpDb) -> Self {
+        GeoIpHandler {
+            country_db: Some(db),
+            ..self
+        }
+    }
+
+    pub fn with_asn_db(self, db: GeoIpDb) -> Self {
+        GeoIpHandler {
+            asn_db: Some(db),
+            ..self
+        }
+    }
+
+    pub fn with_block(mut self, list: GeoList) -> Self {
+        self.blocked.push(list);
+        self
+    }
+
+    pub fn with_tag(mut self, list: GeoList, tag: &str) -> Self {
+        self.tags.push((list, tag.to_string()));
+        self
+    }
+
+    fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let mut info = GeoInfo {
+            country: self.country_db.as_ref().and_then(|db| db.country(ip)),
+            ..GeoInfo::default()
+        };
+        if let Some((asn, as_org)) = self.asn_db.as_ref().and_then(|db| db.asn(ip)) {
+            info.asn = asn;
+            info.as_org = as_org;
+        }
+        info.tags = self
+            .tags
+            .iter()
+            .filter(|(list, _)| list.matches(&info))
+            .map(|(_, tag)| tag.clone())
+            .collect();
+        info
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for GeoIpHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let info = self.lookup(conn.addr.ip());
+        let blocked = self.blocked.iter().any(|list| list.matches(&info));
+        req.context().insert(info);
+        if blocked {
+            let err = RhodError::from_string(
+                format!("Request of {} blocked by its location", conn.addr),
+                RhodErrorLevel::Warning,
+            );
+            let res = RhodResponse::builder()
+                .status(StatusCode::FORBIDDEN)
+                .build()?;
+            return Err(err.with_response(res));
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    // String of the MaxMind DB data format
+    fn string(s: &str) -> Vec<u8> {
+        let mut encoded = if s.len() < 29 {
+            vec![0x40 | s.len() as u8]
+        } else {
+            vec![0x40 | 29, (s.len() - 29) as u8]
+        };
+        encoded.extend_from_slice(s.as_bytes());
+        encoded
+    }
+
+    // uint16 (kind 5) or uint32 (kind 6)
+    fn uint(kind: u8, value: u32) -> Vec<u8> {
+        let size = if kind == 5 { 2 } else { 4 };
+        let mut encoded = vec![kind << 5 | size as u8];
+        encoded.extend_from_slice(&value.to_be_bytes()[4 - size..]);
+        encoded
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut encoded = vec![0xe0 | entries.len() as u8];
+        for (key, value) in entries {
+            encoded.extend(string(key));
+            encoded.extend_from_slice(value);
+        }
+        encoded
+    }
+
+    // IPv4 database with a record for 1.0.0.0/8: a search tree following its first 8 bits
+    fn database(record: Vec<u8>) -> Vec<u8> {
+        let nodes = 8u32;
+        let mut db = vec![];
+        for i in 0..nodes {
+            let (left, right) = match i {
+                7 => (nodes, nodes + 16), // data at offset 0
+                _ => (i + 1, nodes),
+            };
+            db.extend_from_slice(&left.to_be_bytes()[1..]);
+            db.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        db.extend_from_slice(&[0; 16]);
+        db.extend(record);
+        db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        let build_epoch = {
+            let mut encoded = vec![0x08, 0x02];
+            encoded.extend_from_slice(&0u64.to_be_bytes());
+            encoded
+        };
+        db.extend(map(&[
+            ("binary_format_major_version", uint(5, 2)),
+            ("binary_format_minor_version", uint(5, 0)),
+            ("build_epoch", build_epoch),
+            ("database_type", string("Test")),
+            ("description", map(&[])),
+            ("ip_version", uint(5, 4)),
+            ("languages", vec![0x00, 0x04]),
+            ("node_count", uint(6, nodes)),
+            ("record_size", uint(5, 24)),
+        ]));
+        db
+    }
+
+    #[tokio::test]
+    async fn test_geoip() {
+        let dir = std::env::temp_dir();
+        let id = fastrand::u64(..);
+        let country_path = dir.join(format!("rhodium-geoip-country-{}.mmdb", id));
+        let asn_path = dir.join(format!("rhodium-geoip-asn-{}.mmdb", id));
+        let country =
+            |code: &str| database(map(&[("country", map(&[("iso_code", string(code))]))]));
+        std::fs::write(&country_path, country("AU")).unwrap();
+        std::fs::write(
+            &asn_path,
+            database(map(&[
+                ("autonomous_system_number", uint(6, 13335)),
+                (
+                    "autonomous_system_organization",
+                    string("Example Networks Incorporated"),
+                ),
+            ])),
+        )
+        .unwrap();
+
+        let countries = GeoIpDb::open(&country_path).unwrap();
+        let handler = GeoIpHandler::new()
+            .with_country_db(countries.clone())
+            .with_asn_db(GeoIpDb::open(&asn_path).unwrap())
+            .with_tag(GeoList::Countries(vec!["au".to_string()]), "oceania")
+            .with_block(GeoList::Countries(vec!["NZ".to_string()]));
+
+        let request = |addr: &str| {
+            let conn = RhodConnInfo::new(addr.parse().unwrap(), HttpProtocol::HTTP);
+            let mut req = RhodRequest::builder().build().unwrap();
+            let handler = &handler;
+            async move {
+                let result =
+                    RhodHandler::<()>::handle_request(handler, &conn, &mut req, &mut ()).await;
+                (result.is_ok(), req.context().get::<GeoInfo>().unwrap())
+            }
+        };
+        let (passed, info) = request("1.2.3.4:4000").await;
+        assert!(passed);
+        assert_eq!(info.country.as_deref(), Some("AU"));
+        assert_eq!(info.asn, Some(13335));
+        assert_eq!(
+            info.as_org.as_deref(),
+            Some("Example Networks Incorporated")
+        );
+        assert_eq!(info.tags, vec!["oceania"]);
+        // not in the databases
+        let (passed, info) = request("10.0.0.1:4000").await;
+        assert!(passed);
+        assert_eq!(info, GeoInfo::default());
+
+        // reloaded with a blocked country
+        std::fs::write(&country_path, country("NZ")).unwrap();
+        countries.reload().await.unwrap();
+        let (passed, info) = request("1.2.3.4:4000").await;
+        assert!(!passed);
+        assert_eq!(info.country.as_deref(), Some("NZ"));
+
+        std::fs::remove_file(&country_path).unwrap();
+        std::fs::remove_file(&asn_path).unwrap();
+    }
+}
@@ -0,0 +1,544 @@
+mod document;
+
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::{BodyProcessor, RhodRequest};
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::util::sha256_hex;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use document::Document;
+use http::{Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+// Query of a GraphQL request checked by GraphQlHandler. They are saved in the context as a
+// Vec<GraphQlQuery>, with one query per operation of a batch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphQlQuery {
+    pub operation_name: Option<String>,
+    pub kind: Option<String>, // query, mutation or subscription. None for persisted queries sent by hash
+    pub hash: String,         // hex SHA-256 of the query, as in persisted queries
+    pub depth: usize,
+    pub aliases: usize,
+}
+
+// Query, operation name and persisted query hash of an operation
+struct GraphQlOperation {
+    query: Option<String>,
+    operation_name: Option<String>,
+    hash: Option<String>,
+}
+
+impl GraphQlOperation {
+    fn from_json(value: &Value) -> Result<GraphQlOperation, Rejection> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| Rejection::bad_request("Expected an object with the query"))?;
+        let text = |name: &str| object.get(name).and_then(Value::as_str).map(String::from);
+        Ok(GraphQlOperation {
+            query: text("query"),
+            operation_name: text("operationName"),
+            hash: object
+                .get("extensions")
+                .and_then(|e| e.pointer("/persistedQuery/sha256Hash"))
+                .and_then(Value::as_str)
+                .map(String::from),
+        })
+    }
+}
+
+// Why a request is rejected, answered as a GraphQL error
+struct Rejection {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl Rejection {
+    fn new(code: &'static str, message: String) -> Rejection {
+        Rejection {
+            status: StatusCode::BAD_REQUEST,
+            code,
+            message,
+        }
+    }
+
+    fn bad_request(message: &str) -> Rejection {
+        Rejection::new("BAD_REQUEST", message.to_string())
+    }
+
+    fn into_error(self, conn: &RhodConnInfo) -> RhodResult<RhodError> {
+        let err = RhodError::from_string(
+            format!(
+                "GraphQL request of {} rejected. {}",
+                conn.addr, self.message
+            ),
+            RhodErrorLevel::Warning,
+        );
+        let body = json!({
+            "errors": [{"message": self.message, "extensions": {"code": self.code}}]
+        });
+        let res = RhodResponse::builder()
+            .status(self.status)
+            .body_json(&body)
+            .build()?;
+        Ok(err.with_response(res))
+    }
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+// Protects a GraphQL upstream from abusive queries, before proxying them. It reads GraphQL over HTTP
+// requests: GET with the query in the query string, and POST with an application/graphql body or a
+// JSON one (batches included). Requests are rejected with a 400 GraphQL error when:
+// - their operation is nested deeper than max_depth fields (fragments are followed)
+// - their document has more than max_aliases aliases, the usual way of repeating an expensive field
+// - a batch has more than max_batch operations
+// - their body is larger than max_body_size (1MiB by default), answered with a 413
+// - they query the schema (__schema or __type), without_introspection
+// - they arent persisted queries, with_persisted_query or with_persisted_hash: only the queries (or
+//   the SHA-256 of the queries) of the allowlist are accepted, sent in full or by hash
+// Only the requests to the path (with_path) are checked, every request if not set.
+pub struct GraphQlHandler {
+    path: Option<String>,
+    max_depth: usize,
+    max_aliases: usize,
+    max_batch: usize,
+    max_body_size: usize,
+    introspection: bool,
+    persisted: HashSet<String>, // hex SHA-256 of the allowed queries, any query allowed if empty
+}
+
+impl Default for GraphQlHandler {
+    fn default() -> Self {
+        GraphQlHandler::new()
+    }
+}
+
+impl GraphQlHandler {
+    pub fn new() -> GraphQlHandler {
+        GraphQlHandler {
+            path: None,
+            max_depth: 15,
+            max_aliases: 30,
+            max_batch: 10,
+            max_body_size: 1 << 20,
+            introspection: true,
+            persisted: HashSet::new(),
+        }
+    }
+
+    pub fn with_path(self, path: &str) -> Self {
+        GraphQlHandler {
+            path: Some(path.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        GraphQlHandler { max_depth, ..self }
+    }
+
+    pub fn with_max_aliases(self, max_aliases: usize) -> Self {
+        GraphQlHandler {
+            max_aliases,
+            ..self
+        }
+    }
+
+    pub fn with_max_batch(self, max_batch: usize) -> Self {
+        GraphQlHandler { max_batch, ..self }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+        GraphQlHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    pub fn without_introspection(self) -> Self {
+        GraphQlHandler {
+            introspection: false,
+            ..self
+        }
+    }
+
+    // Allows the query, restricting the requests to the persisted queries
+    pub fn with_persisted_query(mut self, query: &str) -> Self {
+        self.persisted.insert(sha256_hex(query.as_bytes()));
+        self
+    }
+
+    // Allows the query with this hex SHA-256, restricting the requests to the persisted queries
+    pub fn with_persisted_hash(mut self, hash: &str) -> Self {
+        self.persisted.insert(hash.to_ascii_lowercase());
+        self
+    }
+
+    // Operations of the request, or why it isnt a GraphQL request
+    async fn operations(
+        &self,
+        req: &mut RhodRequest,
+    ) -> RhodResult<Result<Vec<GraphQlOperation>, Rejection>> {
+        if *req.method() == Method::GET {
+            let mut operation = GraphQlOperation {
+                query: None,
+                operation_name: None,
+                hash: None,
+            };
+            for pair in req.uri().query().unwrap_or("").split('&') {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                match name {
+                    "query" => operation.query = Some(decode(value)),
+                    "operationName" => operation.operation_name = Some(decode(value)),
+                    "extensions" => {
+                        operation.hash = serde_json::from_str::<Value>(&decode(value))
+                            .ok()
+                            .and_then(|e| {
+                                e.pointer("/persistedQuery/sha256Hash")
+                                    .and_then(Value::as_str)
+                                    .map(String::from)
+                            })
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(Ok(vec![operation]));
+        }
+
+        let graphql = req
+            .media_type()
+            .is_some_and(|m| m.mime().essence_str() == "application/graphql");
+        let json = req.body_processor() == Some(BodyProcessor::JSON);
+        let body = match req.body_bytes_limited(self.max_body_size).await? {
+            Some(body) => body,
+            None => {
+                return Ok(Err(Rejection {
+                    status: StatusCode::PAYLOAD_TOO_LARGE,
+                    ..Rejection::new(
+                        "PAYLOAD_TOO_LARGE",
+                        format!("Body larger than {} bytes", self.max_body_size),
+                    )
+                }))
+            }
+        };
+        if graphql {
+            return Ok(Ok(vec![GraphQlOperation {
+                query: Some(String::from_utf8_lossy(&body).into_owned()),
+                operation_name: None,
+                hash: None,
+            }]));
+        }
+        if !json {
+            return Ok(Err(Rejection::bad_request(
+                "Expected an application/json or application/graphql body",
+            )));
+        }
+        let value: Value = match serde_json::from_slice(&body) {
+            Ok(value) => value,
+            Err(e) => return Ok(Err(Rejection::bad_request(&format!("Invalid JSON. {}", e)))),
+        };
+        Ok(match &value {
+            Value::Array(batch) => batch.iter().map(GraphQlOperation::from_json).collect(),
+            single => GraphQlOperation::from_json(single).map(|operation| vec![operation]),
+        })
+    }
+
+    fn check(&self, operation: GraphQlOperation) -> Result<GraphQlQuery, Rejection> {
+        let hash = match (&operation.query, &operation.hash) {
+            (Some(query), Some(hash))
+                if !sha256_hex(query.as_bytes()).eq_ignore_ascii_case(hash) =>
+            {
+                return Err(Rejection::bad_request(
+                    "The persisted query hash doesnt match the query",
+                ))
+            }
+            (Some(query), _) => sha256_hex(query.as_bytes()),
+            (None, Some(hash)) => hash.to_ascii_lowercase(),
+            (None, None) => return Err(Rejection::bad_request("Missing query")),
+        };
+        if !self.persisted.is_empty() && !self.persisted.contains(&hash) {
+            return Err(Rejection::new(
+                "PERSISTED_QUERY_NOT_ALLOWED",
+                "Only persisted queries are allowed".to_string(),
+            ));
+        }
+        let query = match operation.query {
+            Some(query) => query,
+            // sent by hash, the upstream has the query
+            None => {
+                return Ok(GraphQlQuery {
+                    operation_name: operation.operation_name,
+                    kind: None,
+                    hash,
+                    depth: 0,
+                    aliases: 0,
+                })
+            }
+        };
+
+        let document = Document::parse(&query)
+            .map_err(|e| Rejection::new("GRAPHQL_PARSE_FAILED", format!("Invalid query. {}", e)))?;
+        let selected = match &operation.operation_name {
+            Some(name) => document
+                .operations
+                .iter()
+                .find(|o| o.name.as_ref() == Some(name))
+                .ok_or_else(|| Rejection::bad_request(&format!("Unknown operation {}", name)))?,
+            None => &document.operations[0],
+        };
+        let mut depth = 0;
+        for op in &document.operations {
+            if operation.operation_name.is_none() || std::ptr::eq(op, selected) {
+                let op_depth = document.depth(op).map_err(|e| {
+                    Rejection::new("GRAPHQL_VALIDATION_FAILED", format!("Invalid query. {}", e))
+                })?;
+                depth = depth.max(op_depth);
+            }
+        }
+        if depth > self.max_depth {
+            return Err(Rejection::new(
+                "QUERY_TOO_DEEP",
+                format!(
+                    "Query depth {} exceeds the maximum {}",
+                    depth, self.max_depth
+                ),
+            ));
+        }
+        let aliases = document.aliases();
+        if aliases > self.max_aliases {
+            return Err(Rejection::new(
+                "TOO_MANY_ALIASES",
+                format!(
+                    "Query has {} aliases, more than the maximum {}",
+                    aliases, self.max_aliases
+                ),
+            ));
+        }
+        if !self.introspection && document.introspects() {
+            return Err(Rejection::new(
+                "INTROSPECTION_DISABLED",
+                "Introspection is disabled".to_string(),
+            ));
+        }
+        Ok(GraphQlQuery {
+            operation_name: operation.operation_name,
+            kind: Some(selected.kind.clone()),
+            hash,
+            depth,
+            aliases,
+        })
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for GraphQlHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if self
+            .path
+            .as_ref()
+            .is_some_and(|path| path != req.uri().path())
+        {
+            return Ok(());
+        }
+        let checked = match self.operations(req).await? {
+            Ok(operations) if operations.len() > self.max_batch => Err(Rejection::new(
+                "BATCH_TOO_LARGE",
+                format!(
+                    "Batch of {} operations, more than the maximum {}",
+                    operations.len(),
+                    self.max_batch
+                ),
+            )),
+            Ok(operations) => operations
+                .into_iter()
+                .map(|operation| self.check(operation))
+                .collect::<Result<Vec<GraphQlQuery>, Rejection>>(),
+            Err(rejection) => Err(rejection),
+        };
+        match checked {
+            Ok(queries) => {
+                req.context().insert(queries);
+                Ok(())
+            }
+            Err(rejection) => Err(rejection.into_error(conn)?),
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    async fn check(
+        handler: &GraphQlHandler,
+        content_type: &str,
+        body: &str,
+    ) -> Result<Vec<GraphQlQuery>, String> {
+        let mut req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri("/graphql")
+            .header("content-type", content_type)
+            .body_str(body)
+            .build()
+            .unwrap();
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        match RhodHandler::<()>::handle_request(handler, &conn, &mut req, &mut ()).await {
+            Ok(()) => Ok(req.context().get::<Vec<GraphQlQuery>>().unwrap()),
+            Err(mut e) => {
+                let mut res = e.take_response().unwrap();
+                let body: Value = serde_json::from_slice(&res.body().await.unwrap()).unwrap();
+                let code = body["errors"][0]["extensions"]["code"].as_str().unwrap();
+                let status = match code {
+                    "PAYLOAD_TOO_LARGE" => 413,
+                    _ => 400,
+                };
+                assert_eq!(res.status_as_int(), status);
+                Err(code.to_string())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graphql() {
+        let handler = GraphQlHandler::new()
+            .with_path("/graphql")
+            .with_max_depth(3)
+            .with_max_aliases(2)
+            .with_max_batch(2)
+            .without_introspection();
+        let json = "application/json";
+
+        let query = r#"
+            # fragments count their fields, not themselves
+            query Pets($first: Int = 10) {
+              pets(first: $first, filter: {kind: "dog}"}) {
+                ...PetFields
+                ... on Dog @include(if: true) { breed }
+                __typename
+              }
+            }
+            fragment PetFields on Pet { name owner { name } }
+        "#;
+        let queries = check(&handler, "application/graphql", query).await.unwrap();
+        assert_eq!(queries[0].kind.as_deref(), Some("query"));
+        assert_eq!(queries[0].depth, 3);
+        assert_eq!(queries[0].hash, sha256_hex(query.as_bytes()));
+
+        let deep = r#"{"query": "{ a { b { c { d } } } }"}"#;
+        assert_eq!(
+            check(&handler, json, deep).await.unwrap_err(),
+            "QUERY_TOO_DEEP"
+        );
+        let aliases = r#"{"query": "{ a1: pets { name } a2: pets { name } a3: pets { name } }"}"#;
+        assert_eq!(
+            check(&handler, json, aliases).await.unwrap_err(),
+            "TOO_MANY_ALIASES"
+        );
+        let introspection = r#"{"query": "{ __schema { types { name } } }"}"#;
+        assert_eq!(
+            check(&handler, json, introspection).await.unwrap_err(),
+            "INTROSPECTION_DISABLED"
+        );
+        let cycle = r#"{"query": "{ ...A } fragment A on Query { a { ...A } }"}"#;
+        assert_eq!(
+            check(&handler, json, cycle).await.unwrap_err(),
+            "GRAPHQL_VALIDATION_FAILED"
+        );
+        // fragments spreading fragments are nested too
+        let mut chain = "{ ...F0 }".to_string();
+        for i in 0..20_000 {
+            chain.push_str(&format!(" fragment F{} on Q {{ ...F{} }}", i, i + 1));
+        }
+        chain.push_str(" fragment F20000 on Q { a }");
+        let handler = handler.with_max_body_size(1 << 20);
+        assert_eq!(
+            check(&handler, "application/graphql", &chain)
+                .await
+                .unwrap_err(),
+            "GRAPHQL_VALIDATION_FAILED"
+        );
+        let handler = handler.with_max_body_size(1000);
+        assert_eq!(
+            check(&handler, "application/graphql", &chain)
+                .await
+                .unwrap_err(),
+            "PAYLOAD_TOO_LARGE"
+        );
+        assert_eq!(
+            check(&handler, json, r#"{"query": "{ pets { "}"#)
+                .await
+                .unwrap_err(),
+            "GRAPHQL_PARSE_FAILED"
+        );
+        let batch = r#"[{"query": "{ a }"}, {"query": "{ b }"}, {"query": "{ c }"}]"#;
+        assert_eq!(
+            check(&handler, json, batch).await.unwrap_err(),
+            "BATCH_TOO_LARGE"
+        );
+        let batch = r#"[{"query": "{ a }"}, {"query": "mutation M { b }", "operationName": "M"}]"#;
+        let queries = check(&handler, json, batch).await.unwrap();
+        assert_eq!(queries[1].kind.as_deref(), Some("mutation"));
+
+        // persisted queries
+        let handler = GraphQlHandler::new().with_persisted_query("{ pets { name } }");
+        assert!(check(&handler, json, r#"{"query": "{ pets { name } }"}"#)
+            .await
+            .is_ok());
+        let by_hash = json!({
+            "extensions": {"persistedQuery": {"version": 1, "sha256Hash": sha256_hex(b"{ pets { name } }")}}
+        });
+        assert!(check(&handler, json, &by_hash.to_string()).await.is_ok());
+        assert_eq!(
+            check(&handler, json, r#"{"query": "{ users { password } }"}"#)
+                .await
+                .unwrap_err(),
+            "PERSISTED_QUERY_NOT_ALLOWED"
+        );
+    }
+}
@@ -0,0 +1,380 @@
+// Just enough of a GraphQL parser to measure documents: operations, fragments and their selection
+// sets. Arguments, variable definitions and directives are skipped without being checked, the upstream
+// server validates the document anyway.
+use std::collections::{HashMap, HashSet};
+
+const MAX_NESTING: usize = 256; // of the parser, documents nesting deeper are rejected
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Value, // strings and numbers
+}
+
+#[derive(Debug)]
+pub(crate) enum Selection {
+    Field {
+        name: String,
+        alias: bool,
+        children: Vec<Selection>,
+    },
+    Spread(String),
+    Inline(Vec<Selection>),
+}
+
+#[derive(Debug)]
+pub(crate) struct Operation {
+    pub(crate) kind: String, // query, mutation or subscription
+    pub(crate) name: Option<String>,
+    pub(crate) selections: Vec<Selection>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Document {
+    pub(crate) operations: Vec<Operation>,
+    fragments: HashMap<String, Vec<Selection>>,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {}
+            '#' => {
+                while chars.peek().is_some_and(|(_, c)| *c != '\n' && *c != '\r') {
+                    chars.next();
+                }
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '@' | '$' | '=' | '!' | '|' | '&' => {
+                tokens.push(Token::Punct(c))
+            }
+            '.' => {
+                if source[i..].starts_with("...") {
+                    chars.next();
+                    chars.next();
+                    tokens.push(Token::Spread);
+                } else {
+                    return Err(format!("Unexpected . at {}", i));
+                }
+            }
+            '"' => {
+                if source[i..].starts_with("\"\"\"") {
+                    // block string, until the closing """ not escaped
+                    let rest = &source[i + 3..];
+                    let mut end = None;
+                    let mut from = 0;
+                    while let Some(found) = rest[from..].find("\"\"\"") {
+                        let at = from + found;
+                        if !rest[..at].ends_with('\\') {
+                            end = Some(at);
+                            break;
+                        }
+                        from = at + 3;
+                    }
+                    let end = end.ok_or_else(|| "Unterminated block string".to_string())?;
+                    while chars.peek().is_some_and(|(j, _)| *j < i + 3 + end + 3) {
+                        chars.next();
+                    }
+                } else {
+                    loop {
+                        match chars.next() {
+                            Some((_, '\\')) => {
+                                chars.next();
+                            }
+                            Some((_, '"')) => break,
+                            Some((_, '\n')) | None => return Err("Unterminated string".to_string()),
+                            _ => {}
+                        }
+                    }
+                }
+                tokens.push(Token::Value);
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.peek() {
+                    if *c == '_' || c.is_ascii_alphanumeric() {
+                        end = j + c.len_utf8();
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Name(source[i..end].to_string()));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                while chars
+                    .peek()
+                    .is_some_and(|(_, c)| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-'))
+                {
+                    chars.next();
+                }
+                tokens.push(Token::Value);
+            }
+            c => return Err(format!("Unexpected {} at {}", c, i)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            other => Err(format!("Expected a name, got {:?}", other)),
+        }
+    }
+
+    // Skips a balanced group opened by the current token, ie: (arguments) or (variable definitions)
+    fn skip_group(&mut self, open: char, close: char) -> Result<(), String> {
+        let mut level = 0;
+        loop {
+            match self.next() {
+                Some(Token::Punct(c)) if c == open => level += 1,
+                Some(Token::Punct(c)) if c == close => {
+                    level -= 1;
+                    if level == 0 {
+                        return Ok(());
+                    }
+                }
+                Some(_) => {}
+                None => return Err(format!("Unclosed {}", open)),
+            }
+        }
+    }
+
+    fn skip_directives(&mut self) -> Result<(), String> {
+        while self.eat('@') {
+            self.name()?;
+            if self.peek() == Some(&Token::Punct('(')) {
+                self.skip_group('(', ')')?;
+            }
+        }
+        Ok(())
+    }
+
+    fn selection_set(&mut self, nesting: usize) -> Result<Vec<Selection>, String> {
+        if nesting > MAX_NESTING {
+            return Err("Document nested too deep".to_string());
+        }
+        if !self.eat('{') {
+            return Err("Expected a selection set".to_string());
+        }
+        let mut selections = vec![];
+        while !self.eat('}') {
+            match self.peek() {
+                Some(Token::Spread) => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(Token::Name(name)) if name != "on" => {
+                            let name = self.name()?;
+                            self.skip_directives()?;
+                            selections.push(Selection::Spread(name));
+                        }
+                        _ => {
+                            if self.peek() == Some(&Token::Name("on".to_string())) {
+                                self.pos += 1;
+                                self.name()?;
+                            }
+                            self.skip_directives()?;
+                            selections.push(Selection::Inline(self.selection_set(nesting + 1)?));
+                        }
+                    }
+                }
+                Some(Token::Name(_)) => {
+                    let mut name = self.name()?;
+                    let alias = self.eat(':');
+                    if alias {
+                        name = self.name()?;
+                    }
+                    if self.peek() == Some(&Token::Punct('(')) {
+                        self.skip_group('(', ')')?;
+                    }
+                    self.skip_directives()?;
+                    let children = match self.peek() {
+                        Some(Token::Punct('{')) => self.selection_set(nesting + 1)?,
+                        _ => vec![],
+                    };
+                    selections.push(Selection::Field {
+                        name,
+                        alias,
+                        children,
+                    });
+                }
+                other => return Err(format!("Unexpected {:?} in a selection set", other)),
+            }
+        }
+        Ok(selections)
+    }
+}
+
+impl Document {
+    pub(crate) fn parse(source: &str) -> Result<Document, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let mut document = Document::default();
+        while let Some(token) = parser.peek() {
+            match token {
+                Token::Punct('{') => document.operations.push(Operation {
+                    kind: "query".to_string(),
+                    name: None,
+                    selections: parser.selection_set(0)?,
+                }),
+                Token::Name(keyword) if keyword == "fragment" => {
+                    parser.pos += 1;
+                    let name = parser.name()?;
+                    parser.name()?; // on
+                    parser.name()?; // type
+                    parser.skip_directives()?;
+                    let selections = parser.selection_set(0)?;
+                    document.fragments.insert(name, selections);
+                }
+                Token::Name(keyword)
+                    if matches!(keyword.as_str(), "query" | "mutation" | "subscription") =>
+                {
+                    let kind = parser.name()?;
+                    let name = match parser.peek() {
+                        Some(Token::Name(_)) => Some(parser.name()?),
+                        _ => None,
+                    };
+                    if parser.peek() == Some(&Token::Punct('(')) {
+                        parser.skip_group('(', ')')?;
+                    }
+                    parser.skip_directives()?;
+                    document.operations.push(Operation {
+                        kind,
+                        name,
+                        selections: parser.selection_set(0)?,
+                    });
+                }
+                other => return Err(format!("Unexpected {:?} in the document", other)),
+            }
+        }
+        if document.operations.is_empty() {
+            return Err("Document without operations".to_string());
+        }
+        Ok(document)
+    }
+
+    // Deepest field of the operation, counting fields and not fragments. Err on fragment cycles, and
+    // on fragments spreading fragments nested deeper than MAX_NESTING
+    pub(crate) fn depth(&self, operation: &Operation) -> Result<usize, String> {
+        self.depth_of(
+            &operation.selections,
+            0,
+            &mut HashSet::new(),
+            &mut HashMap::new(),
+        )
+    }
+
+    // Fragments are measured once (memo), so spreading them many times doesnt blow up. Nesting counts
+    // the selection sets followed, fragments included, as the parser only limits each one
+    fn depth_of<'a>(
+        &'a self,
+        selections: &'a [Selection],
+        nesting: usize,
+        visiting: &mut HashSet<&'a str>,
+        memo: &mut HashMap<&'a str, usize>,
+    ) -> Result<usize, String> {
+        if nesting > MAX_NESTING {
+            return Err("Document nested too deep".to_string());
+        }
+        let mut depth = 0;
+        for selection in selections {
+            let selection_depth = match selection {
+                Selection::Field { children, .. } => {
+                    1 + self.depth_of(children, nesting + 1, visiting, memo)?
+                }
+                Selection::Inline(children) => {
+                    self.depth_of(children, nesting + 1, visiting, memo)?
+                }
+                Selection::Spread(name) => match memo.get(name.as_str()) {
+                    Some(depth) => *depth,
+                    None => {
+                        let fragment = self
+                            .fragments
+                            .get(name)
+                            .ok_or_else(|| format!("Unknown fragment {}", name))?;
+                        if !visiting.insert(name) {
+                            return Err(format!("Fragment {} spreads itself", name));
+                        }
+                        let fragment_depth =
+                            self.depth_of(fragment, nesting + 1, visiting, memo)?;
+                        visiting.remove(name.as_str());
+                        memo.insert(name, fragment_depth);
+                        fragment_depth
+                    }
+                },
+            };
+            depth = depth.max(selection_depth);
+        }
+        Ok(depth)
+    }
+
+    // Fields of the whole document, operations and fragments
+    fn fields(&self) -> Vec<(&str, bool)> {
+        fn collect<'a>(selections: &'a [Selection], fields: &mut Vec<(&'a str, bool)>) {
+            for selection in selections {
+                match selection {
+                    Selection::Field {
+                        name,
+                        alias,
+                        children,
+                    } => {
+                        fields.push((name, *alias));
+                        collect(children, fields);
+                    }
+                    Selection::Inline(children) => collect(children, fields),
+                    Selection::Spread(_) => {}
+                }
+            }
+        }
+        let mut fields = vec![];
+        for operation in &self.operations {
+            collect(&operation.selections, &mut fields);
+        }
+        for fragment in self.fragments.values() {
+            collect(fragment, &mut fields);
+        }
+        fields
+    }
+
+    pub(crate) fn aliases(&self) -> usize {
+        self.fields().iter().filter(|(_, alias)| *alias).count()
+    }
+
+    // Whether it queries the schema (__schema or __type, __typename is allowed)
+    pub(crate) fn introspects(&self) -> bool {
+        self.fields()
+            .iter()
+            .any(|(name, _)| *name == "__schema" || *name == "__type")
+    }
+}
@@ -0,0 +1,302 @@
+use super::{Principal, RequestId, RequestPredicate};
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::tenancy::TenantId;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{HeaderName, HeaderValue};
+use http::HeaderMap;
+
+// Change of a header. Values are templates that can use {{client_ip}}, {{request_id}}, {{tenant}},
+// {{principal}}, {{host}}, {{method}} and {{path}}, replaced by the values of the request (empty if
+// unknown)
+pub enum HeaderAction {
+    Add(HeaderName, String), // appended to the values it has
+    Set(HeaderName, String),
+    Remove(HeaderName),
+    Rename(HeaderName, HeaderName), // the values are appended to the new name
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Request,
+    Response,
+}
+
+// Action on the request or the response headers, applied if the request matches the condition
+pub struct HeaderRule {
+    target: Target,
+    action: HeaderAction,
+    condition: Option<RequestPredicate>,
+}
+
+impl HeaderRule {
+    pub fn request(action: HeaderAction) -> HeaderRule {
+        HeaderRule {
+            target: Target::Request,
+            action,
+            condition: None,
+        }
+    }
+
+    pub fn response(action: HeaderAction) -> HeaderRule {
+        HeaderRule {
+            target: Target::Response,
+            action,
+            condition: None,
+        }
+    }
+
+    pub fn when(self, condition: RequestPredicate) -> Self {
+        HeaderRule {
+            condition: Some(condition),
+            ..self
+        }
+    }
+}
+
+// Action with its value rendered
+enum Rendered {
+    Add(HeaderName, HeaderValue),
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+    Rename(HeaderName, HeaderName),
+}
+
+impl Rendered {
+    fn apply(self, headers: &mut HeaderMap) {
+        match self {
+            Rendered::Add(name, value) => {
+                headers.append(name, value);
+            }
+            Rendered::Set(name, value) => {
+                headers.insert(name, value);
+            }
+            Rendered::Remove(name) => {
+                headers.remove(name);
+            }
+            Rendered::Rename(from, to) => {
+                if let http::header::Entry::Occupied(entry) = headers.entry(from) {
+                    let (_, values) = entry.remove_entry_mult();
+                    let values: Vec<HeaderValue> = values.collect();
+                    for value in values {
+                        headers.append(&to, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Actions of the response rules matching the request, rendered with its values
+struct PendingResponseRules(Vec<Rendered>);
+
+// Variables of the templates, taken from the request
+struct Variables {
+    client_ip: String,
+    request_id: String,
+    tenant: String,
+    principal: String,
+    host: String,
+    method: String,
+    path: String,
+}
+
+impl Variables {
+    fn of(conn: &RhodConnInfo, req: &RhodRequest) -> Variables {
+        Variables {
+            client_ip: conn.addr.ip().to_string(),
+            request_id: req
+                .context()
+                .get::<RequestId>()
+                .map_or(String::new(), |id| id.id),
+            tenant: req
+                .context()
+                .get::<TenantId>()
+                .map_or(String::new(), |tenant| tenant.0),
+            principal: req
+                .context()
+                .get::<Principal>()
+                .map_or(String::new(), |principal| principal.id),
+            host: req.host().unwrap_or("").to_string(),
+            method: req.method_str().to_string(),
+            path: req.uri().path().to_string(),
+        }
+    }
+
+    fn render(&self, template: &str) -> String {
+        if !template.contains("{{") {
+            return template.to_string();
+        }
+        template
+            .replace("{{client_ip}}", &self.client_ip)
+            .replace("{{request_id}}", &self.request_id)
+            .replace("{{tenant}}", &self.tenant)
+            .replace("{{principal}}", &self.principal)
+            .replace("{{host}}", &self.host)
+            .replace("{{method}}", &self.method)
+            .replace("{{path}}", &self.path)
+    }
+}
+
+// Adds, sets, removes and renames request and response headers with ordered rules, applied in order.
+// Conditions and template values are evaluated on the request, before the next handlers, so response
+// rules see the request as it arrived to this handler. Values that arent valid header values are
+// skipped.
+pub struct HeaderRulesHandler {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRulesHandler {
+    pub fn new(rules: Vec<HeaderRule>) -> HeaderRulesHandler {
+        HeaderRulesHandler { rules }
+    }
+
+    fn render(&self, target: Target, vars: &Variables, req: &RhodRequest) -> Vec<Rendered> {
+        let mut rendered = vec![];
+        for rule in self.rules.iter().filter(|rule| rule.target == target) {
+            if !rule.condition.as_ref().is_none_or(|c| c.matches(req)) {
+                continue;
+            }
+            let value = |name: &HeaderName, template: &str| {
+                let value = vars.render(template);
+                match HeaderValue::from_str(&value) {
+                    Ok(value) => Some(value),
+                    Err(_) => {
+                        warn!("Invalid value {:?} for header {}, skipped", value, name);
+                        None
+                    }
+                }
+            };
+            rendered.push(match &rule.action {
+                HeaderAction::Add(name, template) => match value(name, template) {
+                    Some(value) => Rendered::Add(name.clone(), value),
+                    None => continue,
+                },
+                HeaderAction::Set(name, template) => match value(name, template) {
+                    Some(value) => Rendered::Set(name.clone(), value),
+                    None => continue,
+                },
+                HeaderAction::Remove(name) => Rendered::Remove(name.clone()),
+                HeaderAction::Rename(from, to) => Rendered::Rename(from.clone(), to.clone()),
+            });
+        }
+        rendered
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for HeaderRulesHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let vars = Variables::of(conn, req);
+        let response = self.render(Target::Response, &vars, req);
+        for action in self.render(Target::Request, &vars, req) {
+            action.apply(req.headers_mut());
+        }
+        if !response.is_empty() {
+            req.context().insert(PendingResponseRules(response));
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if let Some(PendingResponseRules(actions)) = res.context().remove::<PendingResponseRules>()
+        {
+            for action in actions {
+                action.apply(res.headers_mut());
+            }
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use http::Method;
+
+    #[tokio::test]
+    async fn test_header_rules_handler() {
+        let name = HeaderName::from_static;
+        let handler = HeaderRulesHandler::new(vec![
+            HeaderRule::request(HeaderAction::Remove(name("x-internal"))),
+            HeaderRule::request(HeaderAction::Rename(name("x-old"), name("x-new"))),
+            HeaderRule::request(HeaderAction::Set(
+                name("x-client"),
+                "{{client_ip}} via {{host}}".to_string(),
+            )),
+            HeaderRule::request(HeaderAction::Add(
+                name("x-tenant"),
+                "{{tenant}}".to_string(),
+            ))
+            .when(RequestPredicate::PathPrefix("/api".to_string())),
+            HeaderRule::response(HeaderAction::Set(
+                name("x-served-path"),
+                "{{method}} {{path}}".to_string(),
+            ))
+            .when(RequestPredicate::Method(Method::GET)),
+            HeaderRule::response(HeaderAction::Remove(name("server"))),
+        ]);
+        let conn = RhodConnInfo::new("10.0.0.7:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let mut req = RhodRequest::builder()
+            .uri("/api/orders")
+            .header("host", "shop.example.com")
+            .header("x-internal", "1")
+            .header("x-old", "a")
+            .header("x-old", "b")
+            .build()
+            .unwrap();
+        req.context().insert(TenantId("acme".to_string()));
+        RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap();
+        let headers = req.headers();
+        assert!(headers.get("x-internal").is_none() && headers.get("x-old").is_none());
+        let renamed: Vec<_> = headers.get_all("x-new").iter().collect();
+        assert_eq!(renamed, vec!["a", "b"]);
+        assert_eq!(headers["x-client"], "10.0.0.7 via shop.example.com");
+        assert_eq!(headers["x-tenant"], "acme");
+
+        // the response shares the context of the request
+        let res = RhodResponse::builder()
+            .header("server", "upstream")
+            .build()
+            .unwrap();
+        res.context()
+            .insert(req.context().remove::<PendingResponseRules>().unwrap());
+        let (res, _) = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(res.headers().get("server").is_none());
+        assert_eq!(res.headers()["x-served-path"], "GET /api/orders");
+    }
+}
@@ -0,0 +1,342 @@
+use super::body_rewrite::fix_length_headers;
+use crate::body::{BoxError, RhodBody};
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG};
+use http::{Method, StatusCode};
+use http_body::{Body, Frame};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Where the snippet goes, before the first closing tag found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectPosition {
+    HeadEnd, // </head>
+    BodyEnd, // </body>
+}
+
+impl InjectPosition {
+    fn marker(self) -> &'static [u8] {
+        match self {
+            InjectPosition::HeadEnd => b"</head",
+            InjectPosition::BodyEnd => b"</body",
+        }
+    }
+}
+
+// Saved in the context of the requests whose responses can get the snippet
+#[derive(Clone, Copy)]
+struct Injectable;
+
+// Injects a snippet (ie: analytics tags, a status banner) in the HTML responses, streaming them: the
+// body is written as it arrives, only the bytes that may be the start of the closing tag are held.
+// Responses without the closing tag are left as they are.
+// Compressed responses cant be changed, so requests are sent with Accept-Encoding: identity (see
+// with_accept_encoding). Responses compressed anyway are left as they are
+pub struct HtmlInjectionHandler {
+    snippet: Bytes,
+    position: InjectPosition,
+    accept_encoding: bool,
+}
+
+impl HtmlInjectionHandler {
+    pub fn new(snippet: &str, position: InjectPosition) -> HtmlInjectionHandler {
+        HtmlInjectionHandler {
+            snippet: Bytes::copy_from_slice(snippet.as_bytes()),
+            position,
+            accept_encoding: false,
+        }
+    }
+
+    // Forwards the Accept-Encoding of the clients, so only the responses that arent compressed get
+    // the snippet
+    pub fn with_accept_encoding(self) -> Self {
+        HtmlInjectionHandler {
+            accept_encoding: true,
+            ..self
+        }
+    }
+}
+
+fn is_html(res: &RhodResponse) -> bool {
+    let status = res.status_as_int();
+    let no_body =
+        status < 200 || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED;
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let encoding = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+    !no_body
+        && content_type.is_some_and(|t| {
+            t.split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .eq_ignore_ascii_case("text/html")
+        })
+        && encoding.is_none_or(|e| e.trim().eq_ignore_ascii_case("identity"))
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for HtmlInjectionHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if req.method() == Method::HEAD {
+            return Ok(());
+        }
+        if !self.accept_encoding {
+            req.headers_mut()
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        }
+        req.context().insert(Injectable);
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if res.context().remove::<Injectable>().is_none() || !is_html(&res) {
+            return (res, Ok(()));
+        }
+        let body = res.take_body();
+        res.set_body(RhodBody::new(InjectBody::new(
+            body,
+            self.position.marker(),
+            self.snippet.clone(),
+        )));
+        let headers = res.headers_mut();
+        fix_length_headers(headers, None);
+        // the body isnt the same byte for byte anymore
+        let weak = match headers.get(ETAG).map(HeaderValue::as_bytes) {
+            Some(etag) if etag.starts_with(b"\"") => {
+                HeaderValue::from_bytes(&[b"W/", etag].concat()).ok()
+            }
+            _ => None,
+        };
+        if let Some(weak) = weak {
+            headers.insert(ETAG, weak);
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+// Body with the snippet inserted before the first marker (case insensitive)
+struct InjectBody {
+    inner: RhodBody,
+    marker: &'static [u8],
+    snippet: Option<Bytes>, // until inserted
+    tail: BytesMut,         // last bytes read, that may be the start of the marker
+    trailers: Option<Frame<Bytes>>,
+    ended: bool,
+}
+
+impl InjectBody {
+    fn new(inner: RhodBody, marker: &'static [u8], snippet: Bytes) -> InjectBody {
+        InjectBody {
+            inner,
+            marker,
+            snippet: Some(snippet),
+            tail: BytesMut::new(),
+            trailers: None,
+            ended: false,
+        }
+    }
+
+    // Data that can be sent after reading the chunk
+    fn push(&mut self, chunk: Bytes) -> Bytes {
+        let snippet = match &self.snippet {
+            Some(snippet) => snippet,
+            None => return chunk,
+        };
+        self.tail.extend_from_slice(&chunk);
+        let marker = self.marker;
+        let found = self
+            .tail
+            .windows(marker.len())
+            .position(|window| window.eq_ignore_ascii_case(marker));
+        match found {
+            Some(i) => {
+                let mut data = BytesMut::with_capacity(self.tail.len() + snippet.len());
+                data.extend_from_slice(&self.tail[..i]);
+                data.extend_from_slice(snippet);
+                data.extend_from_slice(&self.tail[i..]);
+                self.tail.clear();
+                self.snippet = None;
+                data.freeze()
+            }
+            None => {
+                let keep = self.tail.len().min(marker.len() - 1);
+                self.tail.split_to(self.tail.len() - keep).freeze()
+            }
+        }
+    }
+}
+
+impl Body for InjectBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(trailers) = this.trailers.take() {
+                return Poll::Ready(Some(Ok(trailers)));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    this.ended = true;
+                    Frame::trailers(Default::default())
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            let data = match frame.into_data() {
+                Ok(chunk) => this.push(chunk),
+                Err(trailers) => {
+                    // the end of the body, the bytes held arent the marker
+                    if !this.ended {
+                        this.trailers = Some(trailers);
+                    }
+                    this.tail.split().freeze()
+                }
+            };
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandlerInStack, RhodService, RhodStack};
+    use crate::CommunicationChannel;
+    use std::convert::Infallible;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // HTML page streamed in chunks splitting the closing tags, compressed if the path is /gzip
+    struct PageService {}
+    #[async_trait]
+    impl RhodService<Comm> for PageService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let chunks = vec!["<html><head></he", "ad><body>hi</BO", "DY></html>"];
+            let body = RhodBody::wrap_stream(futures_util::stream::iter(
+                chunks.into_iter().map(Ok::<_, Infallible>),
+            ));
+            let mut res = RhodResponse::builder()
+                .header("content-type", "text/html; charset=utf-8")
+                .header("content-length", "43")
+                .header("etag", "\"v1\"")
+                .header(
+                    "x-accept-encoding",
+                    req.header_str("accept-encoding").unwrap_or(""),
+                )
+                .build()?;
+            if req.uri().path() == "/gzip" {
+                res.headers_mut()
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            }
+            res.set_body(body);
+            Ok(res)
+        }
+    }
+
+    async fn run(handler: HtmlInjectionHandler, path: &str) -> (RhodResponse, String) {
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(handler))],
+            Box::new(PageService {}),
+        );
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .uri(path)
+            .header("accept-encoding", "gzip, br")
+            .build()
+            .unwrap();
+        let mut res = stack.execute(&conn, req).await.unwrap();
+        let body = String::from_utf8(res.body().await.unwrap()).unwrap();
+        (res, body)
+    }
+
+    #[tokio::test]
+    async fn test_html_injection_handler() {
+        let snippet = "<script src=\"/tag.js\"></script>";
+        let handler = HtmlInjectionHandler::new(snippet, InjectPosition::BodyEnd);
+        let (res, body) = run(handler, "/").await;
+        assert_eq!(
+            body,
+            "<html><head></head><body>hi<script src=\"/tag.js\"></script></BODY></html>"
+        );
+        assert_eq!(res.headers()["x-accept-encoding"], "identity");
+        assert!(res.headers().get("content-length").is_none());
+        assert_eq!(res.headers()["etag"], "W/\"v1\"");
+
+        let handler = HtmlInjectionHandler::new(snippet, InjectPosition::HeadEnd);
+        let (_, body) = run(handler, "/").await;
+        assert!(body.starts_with("<html><head><script src=\"/tag.js\"></script></head>"));
+
+        // compressed responses are left as they are
+        let handler =
+            HtmlInjectionHandler::new(snippet, InjectPosition::BodyEnd).with_accept_encoding();
+        let (res, body) = run(handler, "/gzip").await;
+        assert_eq!(res.headers()["x-accept-encoding"], "gzip, br");
+        assert!(!body.contains("tag.js"));
+    }
+}
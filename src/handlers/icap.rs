@@ -0,0 +1,379 @@
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderValue, Request, Response, StatusCode};
+
+mod client;
+pub use client::{IcapAdaptation, IcapClient};
+
+// What happens to messages when the ICAP service fails (or they are too big to be sent)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcapFailure {
+    Bypass, // they go on unadapted
+    Reject, // the request is answered 502
+}
+
+// Head of the request, sent with its response to RESPMOD
+#[derive(Clone)]
+struct IcapRequest(Request<()>);
+
+// Delegates the adaptation of requests (REQMOD) and/or responses (RESPMOD) to ICAP services, ie:
+// enterprise antivirus or DLP scanners. The service may leave the message as it is, replace it, or
+// answer the request itself (ie: a block page). Bodies are read whole to be sent.
+pub struct IcapHandler {
+    reqmod: Option<IcapClient>,
+    respmod: Option<IcapClient>,
+    on_failure: IcapFailure,
+    max_body_size: usize,
+}
+
+fn adapted_length(headers: &mut http::HeaderMap, body: &Bytes) {
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+}
+
+impl IcapHandler {
+    pub fn new() -> IcapHandler {
+        IcapHandler {
+            reqmod: None,
+            respmod: None,
+            on_failure: IcapFailure::Reject,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+
+    pub fn with_reqmod(self, client: IcapClient) -> Self {
+        IcapHandler {
+            reqmod: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_respmod(self, client: IcapClient) -> Self {
+        IcapHandler {
+            respmod: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_on_failure(self, on_failure: IcapFailure) -> Self {
+        IcapHandler { on_failure, ..self }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+        IcapHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    // Ok(None) when the failure is bypassed
+    fn failed(&self, err: RhodError) -> RhodResult<Option<IcapAdaptation>> {
+        match self.on_failure {
+            IcapFailure::Bypass => {
+                warn!("ICAP adaptation bypassed. {}", err);
+                Ok(None)
+            }
+            IcapFailure::Reject => {
+                let res = RhodResponse::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .build()?;
+                Err(err.with_response(res))
+            }
+        }
+    }
+
+    fn checked_size(&self, len: usize) -> RhodResult<()> {
+        if len > self.max_body_size {
+            return Err(RhodError::from_string(
+                format!("Body of {} bytes too large for ICAP", len),
+                RhodErrorLevel::Warning,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for IcapHandler {
+    fn default() -> IcapHandler {
+        IcapHandler::new()
+    }
+}
+
+fn to_response(res: Response<Bytes>) -> RhodResponse {
+    RhodResponse::new(res.map(RhodBody::from))
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for IcapHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let mut head = Request::new(());
+        *head.method_mut() = req.method().clone();
+        *head.uri_mut() = req.uri().clone();
+        *head.headers_mut() = req.headers().clone();
+        if self.respmod.is_some() {
+            req.context().insert(IcapRequest(head.clone()));
+        }
+        let client = match &self.reqmod {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        // known too large bodies arent read
+        let adapted = match self.checked_size(req.content_length().unwrap_or(0) as usize) {
+            Ok(()) => {
+                let body = req.body_bytes().await?;
+                match self.checked_size(body.len()) {
+                    Ok(()) => client.reqmod(&head, &body).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let adapted = match adapted {
+            Ok(adapted) => Some(adapted),
+            Err(e) => self.failed(e)?,
+        };
+
+        match adapted {
+            None | Some(IcapAdaptation::Unmodified) => Ok(()),
+            Some(IcapAdaptation::Request(adapted)) => {
+                let (mut parts, body) = adapted.into_parts();
+                adapted_length(&mut parts.headers, &body);
+                *req.method_mut() = parts.method;
+                *req.uri_mut() = parts.uri;
+                *req.headers_mut() = parts.headers;
+                req.set_body(RhodBody::from(body));
+                Ok(())
+            }
+            Some(IcapAdaptation::Response(res)) => {
+                debug!("Request answered by ICAP, {}", res.status());
+                Err(RhodError::from_response(to_response(res)))
+            }
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let (client, req) = match (&self.respmod, res.context().remove::<IcapRequest>()) {
+            (Some(client), Some(IcapRequest(req))) => (client, req),
+            _ => return (res, Ok(())),
+        };
+        let mut head = Response::new(());
+        *head.status_mut() = StatusCode::from_u16(res.status_as_int()).unwrap_or_default();
+        *head.headers_mut() = res.headers().clone();
+
+        let adapted = match res.body_bytes().await {
+            Ok(body) => match self.checked_size(body.len()) {
+                Ok(()) => client.respmod(&req, &head, &body).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => return (res, Err(e)),
+        };
+        let adapted = match adapted {
+            Ok(adapted) => adapted,
+            Err(e) => match self.failed(e) {
+                Ok(_) => return (res, Ok(())),
+                Err(e) => return (res, Err(e)),
+            },
+        };
+
+        match adapted {
+            IcapAdaptation::Unmodified => (res, Ok(())),
+            IcapAdaptation::Response(mut adapted) => {
+                let body = adapted.body().clone();
+                adapted_length(adapted.headers_mut(), &body);
+                let mut adapted = to_response(adapted);
+                adapted.attach_context(res.context());
+                (adapted, Ok(()))
+            }
+            IcapAdaptation::Request(_) => {
+                let err = RhodError::from_string(
+                    "ICAP service answered RESPMOD with a request".to_string(),
+                    RhodErrorLevel::Error,
+                );
+                match self.failed(err) {
+                    Ok(_) => (res, Ok(())),
+                    Err(e) => (res, Err(e)),
+                }
+            }
+        }
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // ICAP service replacing bodies containing "virus" with a block page, asking for the rest of
+    // previews without it
+    async fn spawn_service() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(stream);
+                    loop {
+                        let mut head = String::new();
+                        loop {
+                            let mut line = String::new();
+                            if conn.read_line(&mut line).await.unwrap() == 0 {
+                                return;
+                            }
+                            head.push_str(&line);
+                            if line == "\r\n" {
+                                break;
+                            }
+                        }
+                        let offsets: Vec<usize> = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Encapsulated: "))
+                            .unwrap()
+                            .split(", ")
+                            .map(|e| e.split('=').nth(1).unwrap().parse().unwrap())
+                            .collect();
+                        let mut heads = vec![0; *offsets.last().unwrap()];
+                        conn.read_exact(&mut heads).await.unwrap();
+                        let mut body = vec![];
+                        let mut previewed = head.contains("Preview");
+                        loop {
+                            let mut line = String::new();
+                            conn.read_line(&mut line).await.unwrap();
+                            let size = line.split(';').next().unwrap().trim();
+                            let size = usize::from_str_radix(size, 16).unwrap();
+                            if size > 0 {
+                                let mut chunk = vec![0; size + 2];
+                                conn.read_exact(&mut chunk).await.unwrap();
+                                body.extend_from_slice(&chunk[..size]);
+                                continue;
+                            }
+                            let ieof = line.contains("ieof");
+                            conn.read_line(&mut line).await.unwrap();
+                            if !previewed || ieof || body.windows(5).any(|w| w == b"virus") {
+                                break;
+                            }
+                            let answer = b"ICAP/1.0 100 Continue\r\n\r\n";
+                            conn.get_mut().write_all(answer).await.unwrap();
+                            previewed = false;
+                        }
+                        let answer = if body.windows(5).any(|w| w == b"virus") {
+                            let res_hdr =
+                                "HTTP/1.1 403 Forbidden\r\ncontent-type: text/plain\r\n\r\n";
+                            format!(
+                                "ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, res-body={}\r\n\r\n{}7\r\nblocked\r\n0\r\n\r\n",
+                                res_hdr.len(),
+                                res_hdr
+                            )
+                        } else {
+                            "ICAP/1.0 204 No Content\r\nEncapsulated: null-body=0\r\n\r\n"
+                                .to_string()
+                        };
+                        conn.get_mut().write_all(answer.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_icap() {
+        let addr = spawn_service().await;
+        let uri = format!("icap://{}/reqmod", addr);
+        let handler = IcapHandler::new()
+            .with_reqmod(IcapClient::new(&uri).unwrap().with_preview(4))
+            .with_respmod(IcapClient::new(&format!("icap://{}/respmod", addr)).unwrap());
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let run = |body: &'static str| {
+            let handler = &handler;
+            let conn = &conn;
+            async move {
+                let mut req = RhodRequest::builder()
+                    .method(http::Method::POST)
+                    .uri("/upload")
+                    .body_str(body)
+                    .build()
+                    .unwrap();
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ())
+                    .await
+                    .map(|_| req)
+                    .map_err(|mut e| e.take_response().unwrap())
+            }
+        };
+
+        // clean, sent after the preview, and reusing the connection
+        let mut req = run("clean content").await.unwrap();
+        assert_eq!(req.body().await.unwrap(), b"clean content");
+        assert!(run("tiny").await.is_ok());
+        let mut blocked = run("with a virus").await.unwrap_err();
+        assert_eq!(blocked.status_as_int(), 403);
+        assert_eq!(blocked.body().await.unwrap(), b"blocked");
+
+        let mut res = RhodResponse::builder().body_str("virus!").build().unwrap();
+        res.attach_context(req.context());
+        let (mut res, result) =
+            RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(result.is_ok());
+        assert_eq!(res.status_as_int(), 403);
+        assert_eq!(res.headers()["content-length"], "7");
+        assert_eq!(res.body().await.unwrap(), b"blocked");
+
+        // unreachable service
+        let handler = IcapHandler::new()
+            .with_reqmod(IcapClient::new("icap://127.0.0.1:1/reqmod").unwrap())
+            .with_on_failure(IcapFailure::Bypass);
+        let mut req = RhodRequest::builder().body_str("data").build().unwrap();
+        assert!(
+            RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+                .await
+                .is_ok()
+        );
+        let handler = handler.with_on_failure(IcapFailure::Reject);
+        let mut err = RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 502);
+    }
+}
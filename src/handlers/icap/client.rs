@@ -0,0 +1,442 @@
+// ICAP client (RFC 3507), sending REQMOD and RESPMOD requests to content adaptation services
+// (antivirus, DLP, URL filtering). Bodies are sent chunked after a preview when configured: the
+// service can answer from the preview alone, asking for the rest with 100 Continue. Connections are
+// kept alive and reused, a reused connection closed by the service is retried once on a new one.
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const DEFAULT_PORT: u16 = 1344;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+type Connection = BufReader<TcpStream>;
+
+// What the service made of a message
+#[derive(Debug)]
+pub enum IcapAdaptation {
+    Unmodified,                // 204, the message is fine as it is
+    Request(Request<Bytes>),   // replaces the request (REQMOD)
+    Response(Response<Bytes>), // replaces the response, or answers the request (REQMOD)
+}
+
+pub struct IcapClient {
+    uri: String, // icap://host:port/service
+    host: String,
+    addr: String,
+    preview: Option<usize>,
+    timeout: Duration,
+    max_idle: usize,
+    idle: Mutex<Vec<Connection>>,
+}
+
+fn icap_error(msg: String) -> RhodError {
+    RhodError::from_string(msg, RhodErrorLevel::Error)
+}
+
+impl IcapClient {
+    // Client of the service at uri, ie: icap://scanner:1344/respmod
+    pub fn new(uri: &str) -> RhodResult<IcapClient> {
+        let parsed: Uri = uri
+            .parse()
+            .map_err(|e| icap_error(format!("Invalid ICAP uri {}. {}", uri, e)))?;
+        let host = match (parsed.scheme_str(), parsed.host()) {
+            (Some("icap"), Some(host)) => host.to_string(),
+            _ => return Err(icap_error(format!("Invalid ICAP uri {}", uri))),
+        };
+        let port = parsed.port_u16().unwrap_or(DEFAULT_PORT);
+        Ok(IcapClient {
+            uri: uri.to_string(),
+            addr: format!("{}:{}", host, port),
+            host,
+            preview: None,
+            timeout: Duration::from_secs(30),
+            max_idle: 16,
+            idle: Mutex::new(vec![]),
+        })
+    }
+
+    // Bytes of the body sent first, as announced by the service OPTIONS (Preview header)
+    pub fn with_preview(self, preview: usize) -> Self {
+        IcapClient {
+            preview: Some(preview),
+            ..self
+        }
+    }
+
+    // Of each exchange with the service, connecting included
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        IcapClient { timeout, ..self }
+    }
+
+    pub fn with_max_idle(self, max_idle: usize) -> Self {
+        IcapClient { max_idle, ..self }
+    }
+
+    pub async fn reqmod(&self, req: &Request<()>, body: &Bytes) -> RhodResult<IcapAdaptation> {
+        let req_hdr = request_head(req);
+        self.exchange("REQMOD", vec![("req-hdr", req_hdr)], "req-body", body)
+            .await
+    }
+
+    pub async fn respmod(
+        &self,
+        req: &Request<()>,
+        res: &Response<()>,
+        body: &Bytes,
+    ) -> RhodResult<IcapAdaptation> {
+        let heads = vec![
+            ("req-hdr", request_head(req)),
+            ("res-hdr", response_head(res)),
+        ];
+        self.exchange("RESPMOD", heads, "res-body", body).await
+    }
+
+    async fn exchange(
+        &self,
+        method: &str,
+        heads: Vec<(&str, Vec<u8>)>,
+        body_name: &str,
+        body: &Bytes,
+    ) -> RhodResult<IcapAdaptation> {
+        let mut encapsulated = vec![];
+        let mut offset = 0;
+        for (name, head) in heads.iter() {
+            encapsulated.push(format!("{}={}", name, offset));
+            offset += head.len();
+        }
+        let body_name = if body.is_empty() {
+            "null-body"
+        } else {
+            body_name
+        };
+        encapsulated.push(format!("{}={}", body_name, offset));
+
+        let preview = self.preview.filter(|_| !body.is_empty());
+        let mut message = format!(
+            "{} {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: {}\r\n",
+            method,
+            self.uri,
+            self.host,
+            encapsulated.join(", ")
+        )
+        .into_bytes();
+        if let Some(preview) = preview {
+            message
+                .extend_from_slice(format!("Preview: {}\r\n", preview.min(body.len())).as_bytes());
+        }
+        message.extend_from_slice(b"\r\n");
+        for (_, head) in heads {
+            message.extend_from_slice(&head);
+        }
+
+        let exchange = async {
+            let (conn, reused) = self.connect().await?;
+            match self.send(conn, &message, preview, body).await {
+                Err(e) if reused => {
+                    debug!("Reused ICAP connection failed, retrying. {}", e);
+                    let (conn, _) = self.connect_new().await?;
+                    self.send(conn, &message, preview, body).await
+                }
+                result => result,
+            }
+        };
+        match tokio::time::timeout(self.timeout, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(icap_error(format!(
+                "ICAP request to {} timed out",
+                self.uri
+            ))),
+        }
+    }
+
+    async fn connect(&self) -> RhodResult<(Connection, bool)> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(conn) => Ok((conn, true)),
+            None => self.connect_new().await,
+        }
+    }
+
+    async fn connect_new(&self) -> RhodResult<(Connection, bool)> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(|e| icap_error(format!("Couldnt connect to ICAP {}. {}", self.addr, e)))?;
+        Ok((BufReader::new(stream), false))
+    }
+
+    fn checkin(&self, conn: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(conn);
+        }
+    }
+
+    async fn send(
+        &self,
+        mut conn: Connection,
+        message: &[u8],
+        preview: Option<usize>,
+        body: &Bytes,
+    ) -> RhodResult<IcapAdaptation> {
+        let io = |e: std::io::Error| icap_error(format!("ICAP exchange failed. {}", e));
+        let mut out = message.to_vec();
+        let head = match preview {
+            Some(preview) if preview < body.len() => {
+                write_chunk(&mut out, &body[..preview]);
+                out.extend_from_slice(b"0\r\n\r\n");
+                conn.get_mut().write_all(&out).await.map_err(io)?;
+                let head = read_head(&mut conn).await?;
+                if head.status != 100 {
+                    head
+                } else {
+                    out.clear();
+                    write_chunk(&mut out, &body[preview..]);
+                    out.extend_from_slice(b"0\r\n\r\n");
+                    conn.get_mut().write_all(&out).await.map_err(io)?;
+                    read_head(&mut conn).await?
+                }
+            }
+            Some(_) => {
+                // the whole body fits in the preview
+                write_chunk(&mut out, body);
+                out.extend_from_slice(b"0; ieof\r\n\r\n");
+                conn.get_mut().write_all(&out).await.map_err(io)?;
+                read_head(&mut conn).await?
+            }
+            None => {
+                if !body.is_empty() {
+                    write_chunk(&mut out, body);
+                    out.extend_from_slice(b"0\r\n\r\n");
+                }
+                conn.get_mut().write_all(&out).await.map_err(io)?;
+                read_head(&mut conn).await?
+            }
+        };
+
+        let adaptation = match head.status {
+            204 => IcapAdaptation::Unmodified,
+            200 => read_adaptation(&mut conn, &head).await?,
+            status => {
+                return Err(icap_error(format!(
+                    "ICAP service {} answered {}",
+                    self.uri, status
+                )))
+            }
+        };
+        let close = head
+            .headers
+            .get("connection")
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"close"));
+        if !close {
+            self.checkin(conn);
+        }
+        Ok(adaptation)
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_headers(out: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers.iter() {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+fn request_head(req: &Request<()>) -> Vec<u8> {
+    let target = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method(), target).into_bytes();
+    write_headers(&mut head, req.headers());
+    head
+}
+
+fn response_head(res: &Response<()>) -> Vec<u8> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        res.status().as_u16(),
+        res.status().canonical_reason().unwrap_or("")
+    )
+    .into_bytes();
+    write_headers(&mut head, res.headers());
+    head
+}
+
+// Start line and headers of an ICAP or HTTP message
+struct Head {
+    status: u16, // 0 for requests
+    start: Vec<String>,
+    headers: HeaderMap,
+}
+
+fn parse_head(text: &str) -> RhodResult<Head> {
+    let mut lines = text.split("\r\n").filter(|l| !l.is_empty());
+    let start: Vec<String> = lines
+        .next()
+        .ok_or_else(|| icap_error("Empty ICAP message".to_string()))?
+        .splitn(3, ' ')
+        .map(str::to_string)
+        .collect();
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| icap_error(format!("Invalid header line {}", line)))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| icap_error(format!("Invalid header name {}", name)))?;
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| icap_error(format!("Invalid value of {}", name)))?;
+        headers.append(name, value);
+    }
+    let status = start
+        .get(1)
+        .filter(|_| start[0].starts_with("ICAP/") || start[0].starts_with("HTTP/"))
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    Ok(Head {
+        status,
+        start,
+        headers,
+    })
+}
+
+async fn read_head(conn: &mut Connection) -> RhodResult<Head> {
+    let io = |e: std::io::Error| icap_error(format!("Couldnt read the ICAP response. {}", e));
+    let mut text = String::new();
+    loop {
+        let read = conn.read_line(&mut text).await.map_err(io)?;
+        if read == 0 {
+            return Err(icap_error("ICAP connection closed".to_string()));
+        }
+        if text == "\r\n" {
+            // blank line before the status line
+            text.clear();
+        } else if text.ends_with("\r\n\r\n") {
+            return parse_head(&text);
+        } else if text.len() > MAX_HEAD_SIZE {
+            return Err(icap_error("ICAP response head too large".to_string()));
+        }
+    }
+}
+
+async fn read_chunked(conn: &mut Connection) -> RhodResult<Vec<u8>> {
+    let io = |e: std::io::Error| icap_error(format!("Couldnt read the ICAP body. {}", e));
+    let mut body = vec![];
+    loop {
+        let mut line = String::new();
+        conn.read_line(&mut line).await.map_err(io)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| icap_error(format!("Invalid ICAP chunk size {}", size)))?;
+        if size == 0 {
+            // trailers, until the empty line
+            loop {
+                line.clear();
+                let read = conn.read_line(&mut line).await.map_err(io)?;
+                if read == 0 || line == "\r\n" {
+                    return Ok(body);
+                }
+            }
+        }
+        let start = body.len();
+        body.resize(start + size + 2, 0);
+        conn.read_exact(&mut body[start..]).await.map_err(io)?;
+        body.truncate(start + size);
+    }
+}
+
+async fn read_adaptation(conn: &mut Connection, head: &Head) -> RhodResult<IcapAdaptation> {
+    let encapsulated = head
+        .headers
+        .get("encapsulated")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| icap_error("ICAP response without Encapsulated".to_string()))?;
+    let mut sections = vec![];
+    for entry in encapsulated.split(',') {
+        let (name, offset) = entry
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| icap_error(format!("Invalid Encapsulated {}", encapsulated)))?;
+        let offset: usize = offset
+            .parse()
+            .map_err(|_| icap_error(format!("Invalid Encapsulated {}", encapsulated)))?;
+        sections.push((name.to_string(), offset));
+    }
+
+    let io = |e: std::io::Error| icap_error(format!("Couldnt read the ICAP response. {}", e));
+    let mut req_hdr = None;
+    let mut res_hdr = None;
+    let mut body = Bytes::new();
+    for (i, (name, offset)) in sections.iter().enumerate() {
+        match name.as_str() {
+            "req-hdr" | "res-hdr" => {
+                let end = sections.get(i + 1).map(|(_, o)| *o).unwrap_or(*offset);
+                if end < *offset || end - offset > MAX_HEAD_SIZE {
+                    return Err(icap_error(format!("Invalid Encapsulated {}", encapsulated)));
+                }
+                let mut raw = vec![0; end - offset];
+                conn.read_exact(&mut raw).await.map_err(io)?;
+                let parsed = parse_head(&String::from_utf8_lossy(&raw))?;
+                if name == "req-hdr" {
+                    req_hdr = Some(parsed);
+                } else {
+                    res_hdr = Some(parsed);
+                }
+            }
+            "req-body" | "res-body" => body = Bytes::from(read_chunked(conn).await?),
+            _ => {} // null-body, opt-body isnt used
+        }
+    }
+
+    match (res_hdr, req_hdr) {
+        (Some(res), _) => {
+            let status = StatusCode::from_u16(res.status)
+                .map_err(|_| icap_error(format!("Invalid adapted status {}", res.status)))?;
+            let mut adapted = Response::new(body);
+            *adapted.status_mut() = status;
+            *adapted.headers_mut() = res.headers;
+            Ok(IcapAdaptation::Response(adapted))
+        }
+        (None, Some(req)) => {
+            let invalid = || icap_error(format!("Invalid adapted request {:?}", req.start));
+            let method = req
+                .start
+                .first()
+                .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+                .ok_or_else(invalid)?;
+            let uri: Uri = req
+                .start
+                .get(1)
+                .and_then(|u| u.parse().ok())
+                .ok_or_else(invalid)?;
+            // absolute uris are turned into paths, the host stays in the Host header
+            let uri = match uri.path_and_query() {
+                Some(p) if uri.scheme().is_some() => p.as_str().parse().map_err(|_| invalid())?,
+                _ => uri,
+            };
+            let mut adapted = Request::new(body);
+            *adapted.method_mut() = method;
+            *adapted.uri_mut() = uri;
+            *adapted.headers_mut() = req.headers;
+            Ok(IcapAdaptation::Request(adapted))
+        }
+        (None, None) => Err(icap_error("ICAP response without message".to_string())),
+    }
+}
@@ -0,0 +1,505 @@
+use super::{CachedResponse, Principal};
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::util::{hex, unix_now};
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::Response as HyperResponse;
+use http::StatusCode;
+use ring::digest::{Context, SHA256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const MAX_KEY_LEN: usize = 255;
+
+// What an IdempotencyStore keeps for a key
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdempotencyEntry {
+    InProgress {
+        fingerprint: String,
+    }, // the first request is still running
+    Completed {
+        fingerprint: String,
+        response: CachedResponse,
+    },
+}
+
+// Backend where the IdempotencyHandler keeps the keys and their responses. claim must be atomic, so two
+// requests with the same key never run at once
+#[async_trait]
+pub trait IdempotencyStore: Send + Sync {
+    // Claims the key for the request if it has no entry, returning None, or returns the entry. The claim
+    // expires after lock_ttl, in case the request never completes. Fails if the key cant be claimed
+    // (ie: a full store), the error is answered
+    async fn claim(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        lock_ttl: Duration,
+    ) -> RhodResult<Option<IdempotencyEntry>>;
+    // Keeps the response of the claimed key for ttl
+    async fn complete(&self, key: &str, fingerprint: &str, response: CachedResponse, ttl: Duration);
+    // Removes the claim of a request that failed, so it can be retried
+    async fn release(&self, key: &str);
+}
+
+// In memory store. When full, the completed entries closer to expire are evicted first. The claims of
+// running requests are never evicted, new claims are answered 503 when only those are left
+pub struct MemoryIdempotencyStore {
+    capacity: usize,
+    entries: Mutex<HashMap<String, (IdempotencyEntry, Instant)>>, // entry, expiration
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new(capacity: usize) -> MemoryIdempotencyStore {
+        MemoryIdempotencyStore {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn claim(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        lock_ttl: Duration,
+    ) -> RhodResult<Option<IdempotencyEntry>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((entry, expires_at)) = entries.get(key) {
+            if *expires_at > now {
+                return Ok(Some(entry.clone()));
+            }
+        }
+        if entries.len() >= self.capacity {
+            entries.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        if entries.len() >= self.capacity {
+            let closest = entries
+                .iter()
+                .filter(|(_, (entry, _))| matches!(entry, IdempotencyEntry::Completed { .. }))
+                .min_by_key(|(_, (_, expires_at))| *expires_at)
+                .map(|(k, _)| k.clone());
+            match closest {
+                Some(closest) => entries.remove(&closest),
+                None => {
+                    return Err(RhodError::with_status(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Too many running requests with an Idempotency-Key".to_string(),
+                        RhodErrorLevel::Warning,
+                    ))
+                }
+            };
+        }
+        let claim = IdempotencyEntry::InProgress {
+            fingerprint: fingerprint.to_string(),
+        };
+        entries.insert(key.to_string(), (claim, now + lock_ttl));
+        Ok(None)
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) {
+        let completed = IdempotencyEntry::Completed {
+            fingerprint: fingerprint.to_string(),
+            response,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (completed, Instant::now() + ttl));
+    }
+
+    async fn release(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+// Key claimed by a request, completed with its response
+#[derive(Clone)]
+struct PendingIdempotency {
+    key: String,
+    fingerprint: String,
+}
+
+fn rejected(status: StatusCode, msg: String) -> RhodResult<RhodError> {
+    let res = RhodResponse::builder()
+        .status(status)
+        .body_str(&msg)
+        .build()?;
+    Ok(RhodError::from_string(msg, RhodErrorLevel::Warning).with_response(res))
+}
+
+fn replay(response: &CachedResponse) -> RhodResult<RhodResponse> {
+    let mut builder = HyperResponse::builder().status(response.status);
+    for (name, value) in response.headers.iter() {
+        builder = builder.header(name.as_str(), value.as_slice());
+    }
+    builder = builder.header("idempotent-replayed", "true");
+    match builder.body(RhodBody::from(response.body.clone())) {
+        Ok(res) => Ok(RhodResponse::new(res)),
+        Err(e) => Err(RhodError::from_string(
+            format!("Cant build the replayed response. {}", e),
+            RhodErrorLevel::Error,
+        )),
+    }
+}
+
+// Makes the retries of unsafe requests (POST, PATCH...) safe: a request with an Idempotency-Key header
+// runs once, and the retries with the same key get its response (with an Idempotent-Replayed header)
+// until ttl, so clients retrying after a timeout dont repeat its side effects.
+// - a retry while the first request is still running is answered 409
+// - reusing a key for a different request (method, path, query or body) is answered 422
+// - 5xx responses and failures arent kept, the key is released to be retried
+// Keys are scoped by the Principal in the context, if an auth handler ran before.
+pub struct IdempotencyHandler {
+    store: Box<dyn IdempotencyStore>,
+    ttl: Duration,
+    lock_ttl: Duration,
+    max_body_size: usize, // bigger requests are answered 413, bigger responses arent kept
+}
+
+impl IdempotencyHandler {
+    pub fn new(store: Box<dyn IdempotencyStore>) -> IdempotencyHandler {
+        IdempotencyHandler {
+            store,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            lock_ttl: Duration::from_secs(60),
+            max_body_size: 1024 * 1024,
+        }
+    }
+
+    // How long responses are replayed
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        IdempotencyHandler { ttl, ..self }
+    }
+
+    // How long a running request holds its key, released earlier when it completes or fails
+    pub fn with_lock_ttl(self, lock_ttl: Duration) -> Self {
+        IdempotencyHandler { lock_ttl, ..self }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+        IdempotencyHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    // None if the body is larger than max_body_size
+    async fn fingerprint(&self, req: &mut RhodRequest) -> RhodResult<Option<String>> {
+        let body = match req.body_bytes_limited(self.max_body_size).await? {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+        let mut hash = Context::new(&SHA256);
+        hash.update(req.method_str().as_bytes());
+        hash.update(b"\n");
+        hash.update(req.uri().path().as_bytes());
+        hash.update(b"?");
+        hash.update(req.uri().query().unwrap_or("").as_bytes());
+        hash.update(b"\n");
+        hash.update(&body);
+        Ok(Some(hex(hash.finish().as_ref())))
+    }
+
+    async fn release(&self, pending: Option<PendingIdempotency>) {
+        if let Some(pending) = pending {
+            self.store.release(&pending.key).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for IdempotencyHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if req.method().is_safe() {
+            return Ok(());
+        }
+        let key = match req.header_str(IDEMPOTENCY_KEY) {
+            Some(key) => key.trim().to_string(),
+            None => return Ok(()),
+        };
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(rejected(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Invalid Idempotency-Key, expected 1 to {} characters",
+                    MAX_KEY_LEN
+                ),
+            )?);
+        }
+        let scope = req
+            .context()
+            .get::<Principal>()
+            .map(|principal| principal.id)
+            .unwrap_or_default();
+        let key = format!("{}:{}", scope, key);
+        let fingerprint = match self.fingerprint(req).await? {
+            Some(fingerprint) => fingerprint,
+            None => {
+                return Err(rejected(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Request too large for an Idempotency-Key".to_string(),
+                )?)
+            }
+        };
+
+        match self.store.claim(&key, &fingerprint, self.lock_ttl).await? {
+            None => {
+                req.context()
+                    .insert(PendingIdempotency { key, fingerprint });
+                Ok(())
+            }
+            Some(IdempotencyEntry::InProgress { fingerprint: used })
+            | Some(IdempotencyEntry::Completed {
+                fingerprint: used, ..
+            }) if used != fingerprint => Err(rejected(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The Idempotency-Key was used for a different request".to_string(),
+            )?),
+            Some(IdempotencyEntry::InProgress { .. }) => Err(rejected(
+                StatusCode::CONFLICT,
+                "A request with the same Idempotency-Key is still running".to_string(),
+            )?),
+            Some(IdempotencyEntry::Completed { response, .. }) => {
+                debug!("Replaying the response of Idempotency-Key {}", key);
+                Err(RhodError::from_response(replay(&response)?))
+            }
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+        self.release(req.context().remove::<PendingIdempotency>())
+            .await;
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let pending = match res.context().remove::<PendingIdempotency>() {
+            Some(pending) => pending,
+            None => return (res, Ok(())),
+        };
+        if res.status_as_int() >= 500 {
+            self.store.release(&pending.key).await;
+            return (res, Ok(()));
+        }
+        // larger bodies are streamed as they are
+        let body = match res.body_bytes_limited(self.max_body_size).await {
+            Ok(Some(body)) => body.to_vec(),
+            Ok(None) => {
+                self.store.release(&pending.key).await;
+                return (res, Ok(()));
+            }
+            Err(e) => {
+                self.store.release(&pending.key).await;
+                return (res, Err(e));
+            }
+        };
+        let now = unix_now();
+        let response = CachedResponse {
+            status: res.status_as_int(),
+            headers: res
+                .headers()
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            body,
+            stored_at: now,
+            expires_at: now + self.ttl.as_secs(),
+            vary: vec![],
+            variants: vec![],
+        };
+        self.store
+            .complete(&pending.key, &pending.fingerprint, response, self.ttl)
+            .await;
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+        self.release(res.context().remove::<PendingIdempotency>())
+            .await;
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use http::Method;
+
+    fn request(key: &str, body: &str) -> RhodRequest {
+        RhodRequest::builder()
+            .method(Method::POST)
+            .uri("/payments")
+            .header("idempotency-key", key)
+            .body_str(body)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_idempotency() {
+        let handler = IdempotencyHandler::new(Box::new(MemoryIdempotencyStore::new(100)));
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let handle = |mut req: RhodRequest| {
+            let handler = &handler;
+            let conn = &conn;
+            async move {
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ())
+                    .await
+                    .map(|_| req)
+                    .map_err(|mut e| e.take_response().unwrap())
+            }
+        };
+
+        // first request runs, a concurrent retry conflicts
+        let first = handle(request("k1", "{\"amount\":10}")).await.unwrap();
+        let conflict = handle(request("k1", "{\"amount\":10}")).await.unwrap_err();
+        assert_eq!(conflict.status_as_int(), 409);
+
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::CREATED)
+            .body_str("payment 1")
+            .build()
+            .unwrap();
+        res.attach_context(first.context());
+        let (_, result) = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(result.is_ok());
+
+        // retries get the same response
+        let mut replayed = handle(request("k1", "{\"amount\":10}")).await.unwrap_err();
+        assert_eq!(replayed.status_as_int(), 201);
+        assert_eq!(replayed.body().await.unwrap(), b"payment 1");
+        assert_eq!(replayed.headers()["idempotent-replayed"], "true");
+        // unless they arent the same request
+        let reused = handle(request("k1", "{\"amount\":99}")).await.unwrap_err();
+        assert_eq!(reused.status_as_int(), 422);
+
+        // failed requests release their key
+        let failed = handle(request("k2", "{}")).await.unwrap();
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .build()
+            .unwrap();
+        res.attach_context(failed.context());
+        let _ = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(handle(request("k2", "{}")).await.is_ok());
+
+        // without a key, or safe methods, nothing is checked
+        let get = RhodRequest::builder()
+            .header("idempotency-key", "k1")
+            .build()
+            .unwrap();
+        assert!(handle(get).await.is_ok());
+
+        // requests with larger responses release their key too, the responses are sent as they are
+        let handler = IdempotencyHandler::new(Box::new(MemoryIdempotencyStore::new(100)))
+            .with_max_body_size(4);
+        let mut large = request("k3", "{}");
+        assert!(
+            RhodHandler::<()>::handle_request(&handler, &conn, &mut large, &mut ())
+                .await
+                .is_ok()
+        );
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::CREATED)
+            .body_str("payment 3")
+            .build()
+            .unwrap();
+        res.attach_context(large.context());
+        let (mut res, result) =
+            RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(result.is_ok());
+        assert_eq!(res.body().await.unwrap(), b"payment 3");
+        let mut req = request("k3", "{}");
+        assert!(
+            RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+                .await
+                .is_ok()
+        );
+        // larger requests are refused
+        let mut req = request("k4", "{\"amount\":10}");
+        let mut err = RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 413);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_keeps_running_claims() {
+        let store = MemoryIdempotencyStore::new(2);
+        let lock_ttl = Duration::from_secs(60);
+        let response = CachedResponse {
+            status: 201,
+            headers: vec![],
+            body: vec![],
+            stored_at: 0,
+            expires_at: 0,
+            vary: vec![],
+            variants: vec![],
+        };
+        assert!(store.claim("a", "fa", lock_ttl).await.unwrap().is_none());
+        assert!(store.claim("b", "fb", lock_ttl).await.unwrap().is_none());
+        store
+            .complete("b", "fb", response, Duration::from_secs(3600))
+            .await;
+        // the completed entry is evicted, even if it expires after the claims
+        assert!(store.claim("c", "fc", lock_ttl).await.unwrap().is_none());
+        // only claims are left
+        let mut err = store.claim("d", "fd", lock_ttl).await.unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 503);
+        assert_eq!(
+            store.claim("a", "fa", lock_ttl).await.unwrap(),
+            Some(IdempotencyEntry::InProgress {
+                fingerprint: "fa".to_string()
+            })
+        );
+    }
+}
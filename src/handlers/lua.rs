@@ -0,0 +1,606 @@
+use super::body_rewrite::fix_length_headers;
+use crate::background::BackgroundJob;
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::reload::Swap;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use mlua::{
+    AnyUserData, Function, HookTriggers, Lua, LuaOptions, StdLib, Table, UserData, UserDataMethods,
+    Value,
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+
+const HOOK_INSTRUCTIONS: u32 = 10_000; // between checks of the time limit
+
+// Limits of the scripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LuaLimits {
+    pub memory: usize,  // bytes of each Lua state
+    pub time: Duration, // of each call
+    pub states: usize,  // scripts running at once
+}
+
+impl Default for LuaLimits {
+    fn default() -> LuaLimits {
+        LuaLimits {
+            memory: 16 * 1024 * 1024,
+            time: Duration::from_millis(100),
+            states: 4,
+        }
+    }
+}
+
+fn script_error(path: &Path, e: mlua::Error) -> RhodError {
+    RhodError::from_string(
+        format!("Lua script {} failed. {}", path.display(), e),
+        RhodErrorLevel::Error,
+    )
+}
+
+// Deadline of the running call, checked by the hook
+struct Deadline(Instant);
+
+// Lua states sandboxed (no io, os, package nor debug libraries) with the script loaded
+struct LuaStates {
+    states: Vec<Mutex<Lua>>,
+    free: Semaphore, // a permit by state, calls wait for one instead of blocking on a busy state
+}
+
+impl LuaStates {
+    fn load(path: &Path, source: &str, limits: &LuaLimits) -> RhodResult<LuaStates> {
+        let mut states = vec![];
+        for _ in 0..limits.states.max(1) {
+            let lua = Lua::new_with(
+                StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+                LuaOptions::new(),
+            )
+            .map_err(|e| script_error(path, e))?;
+            lua.set_memory_limit(limits.memory)
+                .map_err(|e| script_error(path, e))?;
+            lua.set_hook(
+                HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+                |lua, _| match lua.app_data_ref::<Deadline>() {
+                    Some(deadline) if Instant::now() > deadline.0 => Err(
+                        mlua::Error::RuntimeError("script time limit exceeded".to_string()),
+                    ),
+                    _ => Ok(()),
+                },
+            );
+            lua.set_app_data(Deadline(Instant::now() + limits.time));
+            lua.load(source)
+                .set_name(path.display().to_string())
+                .exec()
+                .map_err(|e| script_error(path, e))?;
+            states.push(Mutex::new(lua));
+        }
+        Ok(LuaStates {
+            free: Semaphore::new(states.len()),
+            states,
+        })
+    }
+
+    // A free state. Taken with a permit of free, so one of them is free
+    fn acquire(&self) -> std::sync::MutexGuard<'_, Lua> {
+        for state in self.states.iter() {
+            if let Ok(lua) = state.try_lock() {
+                return lua;
+            }
+        }
+        self.states[0].lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Lua script implementing handle_request(req) and/or handle_response(res). It is hot reloadable:
+// reload reads the file again, and requests arriving after it run the new script. Clones share the
+// script, so one can be kept to reload the one of a handler
+#[derive(Clone)]
+pub struct LuaScript {
+    path: PathBuf,
+    limits: LuaLimits,
+    states: Arc<Swap<LuaStates>>,
+    modified: Arc<Mutex<Option<SystemTime>>>, // of the file loaded
+}
+
+impl LuaScript {
+    pub fn open<P: AsRef<Path>>(path: P) -> RhodResult<LuaScript> {
+        LuaScript::open_with(path, LuaLimits::default())
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, limits: LuaLimits) -> RhodResult<LuaScript> {
+        let path = path.as_ref().to_path_buf();
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| script_error(&path, mlua::Error::external(e)))?;
+        let states = LuaStates::load(&path, &source, &limits)?;
+        Ok(LuaScript {
+            path,
+            limits,
+            states: Arc::new(Swap::new(Arc::new(states))),
+            modified: Arc::new(Mutex::new(modified)),
+        })
+    }
+
+    // Reads the file again. A script that cant be loaded is reported, and the script loaded is kept
+    pub async fn reload(&self) -> RhodResult<()> {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        let source = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| script_error(&self.path, mlua::Error::external(e)))?;
+        let states = LuaStates::load(&self.path, &source, &self.limits)?;
+        self.states.store(Arc::new(states));
+        *self.modified.lock().unwrap() = modified;
+        info!("Lua script {} reloaded", self.path.display());
+        Ok(())
+    }
+
+    // Job reloading the script every interval when the file was modified
+    pub fn reload_job(&self, interval: Duration) -> BackgroundJob {
+        let script = self.clone();
+        BackgroundJob::every(interval, move || {
+            let script = script.clone();
+            async move {
+                let modified = tokio::fs::metadata(&script.path)
+                    .await
+                    .and_then(|m| m.modified())
+                    .ok();
+                if modified.is_some() && modified != *script.modified.lock().unwrap() {
+                    if let Err(e) = script.reload().await {
+                        e.log();
+                    }
+                }
+            }
+        })
+    }
+
+    // Calls the function with the message, returning it (maybe modified) and the response the
+    // function answered, if any. Calls wait for a free state, and run on the blocking threads so
+    // scripts dont hold the workers of the runtime
+    async fn call(
+        &self,
+        function: &'static str,
+        message: LuaMessage,
+    ) -> RhodResult<(LuaMessage, Option<RhodResponse>)> {
+        let states = self.states.load();
+        let _permit = states
+            .free
+            .acquire()
+            .await
+            .map_err(|e| script_error(&self.path, mlua::Error::RuntimeError(e.to_string())))?;
+        let (script, states) = (self.clone(), Arc::clone(&states));
+        tokio::task::spawn_blocking(move || script.call_blocking(&states, function, message))
+            .await
+            .map_err(|e| script_error(&self.path, mlua::Error::external(e)))?
+    }
+
+    fn call_blocking(
+        &self,
+        states: &LuaStates,
+        function: &str,
+        message: LuaMessage,
+    ) -> RhodResult<(LuaMessage, Option<RhodResponse>)> {
+        let lua = states.acquire();
+        let run = || -> mlua::Result<(LuaMessage, Option<RhodResponse>)> {
+            let function: Function = match lua.globals().get::<_, Option<Function>>(function)? {
+                Some(function) => function,
+                None => return Ok((message, None)),
+            };
+            lua.set_app_data(Deadline(Instant::now() + self.limits.time));
+            let userdata: AnyUserData = lua.create_userdata(message)?;
+            let answer: Value = function.call(userdata.clone())?;
+            let message = userdata.take::<LuaMessage>()?;
+            let answer = match answer {
+                Value::Nil => None,
+                Value::Table(answer) => Some(to_response(answer)?),
+                _ => {
+                    return Err(mlua::Error::RuntimeError(
+                        "expected nil or a response table".to_string(),
+                    ))
+                }
+            };
+            Ok((message, answer))
+        };
+        let result = run().map_err(|e| script_error(&self.path, e));
+        // releases the memory of the call
+        let _ = lua.gc_collect();
+        result
+    }
+}
+
+// Method and uri of the request, given to handle_response
+#[derive(Clone)]
+struct LuaRequest(Method, Uri);
+
+// Request or response given to the scripts
+struct LuaMessage {
+    method: Method,
+    uri: Uri,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Option<Bytes>, // None when bodies arent buffered
+    body_set: bool,
+}
+
+fn invalid<E: std::fmt::Display>(what: &str) -> impl Fn(E) -> mlua::Error + '_ {
+    move |e| mlua::Error::RuntimeError(format!("invalid {}. {}", what, e))
+}
+
+fn not_buffered() -> mlua::Error {
+    mlua::Error::RuntimeError(
+        "body isnt buffered, see LuaHandler::with_bodies and with_max_body_size".to_string(),
+    )
+}
+
+impl UserData for LuaMessage {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("method", |_, this, ()| Ok(this.method.to_string()));
+        methods.add_method_mut("set_method", |_, this, method: String| {
+            this.method = Method::from_bytes(method.as_bytes()).map_err(invalid("method"))?;
+            Ok(())
+        });
+        methods.add_method("uri", |_, this, ()| Ok(this.uri.to_string()));
+        methods.add_method("path", |_, this, ()| Ok(this.uri.path().to_string()));
+        methods.add_method("query", |_, this, ()| {
+            Ok(this.uri.query().map(str::to_string))
+        });
+        methods.add_method_mut("set_uri", |_, this, uri: String| {
+            this.uri = uri.parse().map_err(invalid("uri"))?;
+            Ok(())
+        });
+        methods.add_method("status", |_, this, ()| Ok(this.status.as_u16()));
+        methods.add_method_mut("set_status", |_, this, status: u16| {
+            this.status = StatusCode::from_u16(status).map_err(invalid("status"))?;
+            Ok(())
+        });
+        methods.add_method("header", |lua, this, name: String| {
+            match this.headers.get(name.as_str()) {
+                Some(value) => Ok(Some(lua.create_string(value.as_bytes())?)),
+                None => Ok(None),
+            }
+        });
+        methods.add_method("headers", |lua, this, ()| {
+            let headers = lua.create_table()?;
+            for name in this.headers.keys() {
+                let values: Vec<&[u8]> = this
+                    .headers
+                    .get_all(name)
+                    .iter()
+                    .map(|v| v.as_bytes())
+                    .collect();
+                headers.set(name.as_str(), lua.create_string(values.join(&b", "[..]))?)?;
+            }
+            Ok(headers)
+        });
+        methods.add_method_mut(
+            "set_header",
+            |_, this, (name, value): (String, mlua::String)| {
+                let name =
+                    HeaderName::from_bytes(name.as_bytes()).map_err(invalid("header name"))?;
+                let value =
+                    HeaderValue::from_bytes(value.as_bytes()).map_err(invalid("header value"))?;
+                this.headers.insert(name, value);
+                Ok(())
+            },
+        );
+        methods.add_method_mut("remove_header", |_, this, name: String| {
+            this.headers.remove(name.as_str());
+            Ok(())
+        });
+        methods.add_method("body", |lua, this, ()| match &this.body {
+            Some(body) => Ok(lua.create_string(body)?),
+            None => Err(not_buffered()),
+        });
+        methods.add_method_mut("set_body", |_, this, body: mlua::String| {
+            if this.body.is_none() {
+                return Err(not_buffered());
+            }
+            this.body = Some(Bytes::copy_from_slice(body.as_bytes()));
+            this.body_set = true;
+            Ok(())
+        });
+    }
+}
+
+// Response table answered by handle_request: {status = 403, headers = {...}, body = "..."}
+fn to_response(answer: Table) -> mlua::Result<RhodResponse> {
+    let status: Option<u16> = answer.get("status")?;
+    let status = StatusCode::from_u16(status.unwrap_or(200)).map_err(invalid("status"))?;
+    let mut res = RhodResponse::builder().status(status);
+    if let Some(headers) = answer.get::<_, Option<Table>>("headers")? {
+        for pair in headers.pairs::<String, String>() {
+            let (name, value) = pair?;
+            res = res.header(&name, &value);
+        }
+    }
+    if let Some(body) = answer.get::<_, Option<mlua::String>>("body")? {
+        res = res.body_bytes(body.as_bytes());
+    }
+    res.build()
+        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+}
+
+// Runs a Lua script as a handler, so operators can add logic without recompiling. The script defines
+// handle_request(req) and/or handle_response(res), getting the message with methods to read and
+// change it (responses read the method and uri of their request): method/set_method, uri/path/query/set_uri, status/set_status, header/headers/set_header/
+// remove_header, and body/set_body when bodies are buffered (with_bodies). handle_request may return a
+// table {status, headers, body} to answer the request:
+//
+//  function handle_request(req)
+//      if req:header("x-api-version") == "1" then
+//          return {status = 410, body = "v1 is gone"}
+//      end
+//      req:set_header("x-via-lua", "yes")
+//  end
+//
+// Scripts run sandboxed: without the io, os, package and debug libraries, with limited memory and
+// time (LuaLimits), failing their request with 500 when they fail.
+// Buffered request bodies larger than max_body_size (1MiB by default) are answered 413, larger
+// response bodies are streamed as they are, without a body for the script.
+pub struct LuaHandler {
+    script: LuaScript,
+    bodies: bool,
+    max_body_size: usize,
+}
+
+impl LuaHandler {
+    pub fn new(script: LuaScript) -> LuaHandler {
+        LuaHandler {
+            script,
+            bodies: false,
+            max_body_size: 1 << 20,
+        }
+    }
+
+    // Buffers the bodies, so scripts can read and replace them
+    pub fn with_bodies(self) -> Self {
+        LuaHandler {
+            bodies: true,
+            ..self
+        }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+        LuaHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    fn failed(err: RhodError) -> RhodError {
+        err.with_built(
+            RhodResponse::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .build(),
+        )
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for LuaHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let body = match self.bodies {
+            true => match req.body_bytes_limited(self.max_body_size).await? {
+                Some(body) => Some(body),
+                None => {
+                    return Err(RhodError::with_status(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        format!("Request body larger than {} bytes", self.max_body_size),
+                        RhodErrorLevel::Warning,
+                    ))
+                }
+            },
+            false => None,
+        };
+        let message = LuaMessage {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            status: StatusCode::OK,
+            headers: req.headers().clone(),
+            body,
+            body_set: false,
+        };
+        let (message, answer) = self
+            .script
+            .call("handle_request", message)
+            .await
+            .map_err(LuaHandler::failed)?;
+        if let Some(answer) = answer {
+            return Err(RhodError::from_response(answer));
+        }
+        req.context()
+            .insert(LuaRequest(message.method.clone(), message.uri.clone()));
+        *req.method_mut() = message.method;
+        *req.uri_mut() = message.uri;
+        *req.headers_mut() = message.headers;
+        if let (true, Some(body)) = (message.body_set, message.body) {
+            fix_length_headers(req.headers_mut(), Some(body.len()));
+            req.set_body(RhodBody::from(body));
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let body = match self.bodies {
+            true => match res.body_bytes_limited(self.max_body_size).await {
+                Ok(body) => body,
+                Err(e) => return (res, Err(e)),
+            },
+            false => None,
+        };
+        let LuaRequest(method, uri) = res
+            .context()
+            .remove::<LuaRequest>()
+            .unwrap_or(LuaRequest(Method::GET, Uri::default()));
+        let message = LuaMessage {
+            method,
+            uri,
+            status: StatusCode::from_u16(res.status_as_int()).unwrap_or_default(),
+            headers: res.headers().clone(),
+            body,
+            body_set: false,
+        };
+        let message = match self.script.call("handle_response", message).await {
+            Ok((message, _)) => message,
+            Err(e) => return (res, Err(LuaHandler::failed(e))),
+        };
+        let mut hyper_res = res.into_hyper_response();
+        *hyper_res.status_mut() = message.status;
+        *hyper_res.headers_mut() = message.headers;
+        let mut new_res = RhodResponse::new(hyper_res);
+        if let (true, Some(body)) = (message.body_set, message.body) {
+            fix_length_headers(new_res.headers_mut(), Some(body.len()));
+            new_res.set_body(RhodBody::from(body));
+        }
+        (new_res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        self.bodies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    const SCRIPT: &str = r#"
+        function handle_request(req)
+            if req:header("x-api-version") == "1" then
+                return {status = 410, headers = {["x-reason"] = "sunset"}, body = "v1 is gone"}
+            end
+            if req:path() == "/loop" then
+                while true do end
+            end
+            req:set_uri("/v2" .. req:path())
+            req:set_header("x-sandboxed", tostring(os == nil and io == nil))
+            req:set_body(string.upper(req:body()))
+        end
+
+        function handle_response(res)
+            if res:path() == "/v2/users" then res:set_status(202) end
+            res:remove_header("server")
+            res:set_body(res:body() .. "!")
+        end
+    "#;
+
+    #[tokio::test]
+    async fn test_lua() {
+        let path = std::env::temp_dir().join(format!("rhodium-{}.lua", fastrand::u64(..)));
+        std::fs::write(&path, SCRIPT).unwrap();
+        let script = LuaScript::open(&path).unwrap();
+        let handler = LuaHandler::new(script.clone()).with_bodies();
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let run = |uri: &str, version: &str| {
+            let mut req = RhodRequest::builder()
+                .uri(uri)
+                .header("x-api-version", version)
+                .body_str("hello")
+                .build()
+                .unwrap();
+            let handler = &handler;
+            let conn = &conn;
+            async move {
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ())
+                    .await
+                    .map(|_| req)
+                    .map_err(|mut e| e.take_response().unwrap())
+            }
+        };
+
+        let mut req = run("/users", "2").await.unwrap();
+        assert_eq!(req.uri(), "/v2/users");
+        assert_eq!(req.headers()["x-sandboxed"], "true");
+        assert_eq!(req.headers()["content-length"], "5");
+        assert_eq!(req.body().await.unwrap(), b"HELLO");
+
+        let mut answered = run("/users", "1").await.unwrap_err();
+        assert_eq!(answered.status_as_int(), 410);
+        assert_eq!(answered.headers()["x-reason"], "sunset");
+        assert_eq!(answered.body().await.unwrap(), b"v1 is gone");
+
+        // scripts running too long fail their request, without holding the runtime meanwhile
+        let started = Instant::now();
+        let (failed, slept) = tokio::join!(run("/loop", "2"), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            started.elapsed()
+        });
+        assert_eq!(failed.unwrap_err().status_as_int(), 500);
+        assert!(slept < Duration::from_millis(90));
+
+        let mut res = RhodResponse::builder()
+            .header("server", "upstream")
+            .body_str("done")
+            .build()
+            .unwrap();
+        res.attach_context(req.context());
+        let (mut res, result) =
+            RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(result.is_ok());
+        assert_eq!(res.status_as_int(), 202);
+        assert!(res.headers().get("server").is_none());
+        assert_eq!(res.body().await.unwrap(), b"done!");
+
+        // larger request bodies are refused
+        let small = LuaHandler::new(script.clone())
+            .with_bodies()
+            .with_max_body_size(4);
+        let mut req = RhodRequest::builder().body_str("hello").build().unwrap();
+        let mut err = RhodHandler::<()>::handle_request(&small, &conn, &mut req, &mut ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 413);
+
+        // reloaded scripts replace the loaded one, broken ones dont
+        std::fs::write(
+            &path,
+            "function handle_request(req) req:set_uri('/new') end",
+        )
+        .unwrap();
+        script.reload().await.unwrap();
+        assert_eq!(run("/users", "1").await.unwrap().uri(), "/new");
+        std::fs::write(&path, "function handle_request(").unwrap();
+        assert!(script.reload().await.is_err());
+        assert_eq!(run("/users", "1").await.unwrap().uri(), "/new");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
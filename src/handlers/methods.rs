@@ -0,0 +1,185 @@
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{HeaderValue, ACCESS_CONTROL_REQUEST_METHOD, ALLOW, CONTENT_LENGTH};
+use http::{Method, StatusCode};
+
+// Marks a HEAD request served as a GET, saved in the request context
+#[derive(Debug, Clone, Copy)]
+struct HeadRequest;
+
+// Answers HEAD and OPTIONS so the next handlers and the service only deal with the other methods:
+// - HEAD runs the GET flow, and the body of the response is dropped (keeping its Content-Length)
+// - OPTIONS is answered with 204 and an Allow header listing the methods of the path.
+//   CORS preflights (with Access-Control-Request-Method) are passed on.
+// Methods are configured for the whole server, or by path prefix (the longest matching prefix wins).
+pub struct MethodsHandler {
+    methods: Vec<Method>,
+    paths: Vec<(String, Vec<Method>)>,
+}
+
+impl MethodsHandler {
+    pub fn new(methods: &[Method]) -> MethodsHandler {
+        MethodsHandler {
+            methods: methods.to_vec(),
+            paths: vec![],
+        }
+    }
+
+    // Methods of the paths under the prefix
+    pub fn with_path(mut self, prefix: &str, methods: &[Method]) -> Self {
+        self.paths.push((prefix.to_string(), methods.to_vec()));
+        self
+    }
+
+    // Value of Allow for the path. HEAD is allowed with GET, and OPTIONS always
+    fn allow(&self, path: &str) -> String {
+        let methods = self
+            .paths
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(&self.methods, |(_, methods)| methods);
+        let mut allow: Vec<&str> = vec![];
+        for method in methods {
+            allow.push(method.as_str());
+            if method == Method::GET {
+                allow.push(Method::HEAD.as_str());
+            }
+        }
+        allow.push(Method::OPTIONS.as_str());
+        let mut unique = vec![];
+        for method in allow {
+            if !unique.contains(&method) {
+                unique.push(method);
+            }
+        }
+        unique.join(", ")
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for MethodsHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if req.method() == Method::HEAD {
+            *req.method_mut() = Method::GET;
+            req.context().insert(HeadRequest);
+        } else if req.method() == Method::OPTIONS
+            && !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            let err = RhodError::from_str("Answered OPTIONS", RhodErrorLevel::Debug);
+            let res = RhodResponse::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(ALLOW.as_str(), &self.allow(req.uri().path()))
+                .build();
+            return Err(err.with_built(res));
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if res.context().remove::<HeadRequest>().is_none() {
+            return (res, Ok(()));
+        }
+        if !res.headers().contains_key(CONTENT_LENGTH) {
+            if let Some(size) = res.body_size_hint() {
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(size));
+            }
+        }
+        drop(res.take_body());
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandlerInStack, RhodService, RhodStack};
+    use crate::CommunicationChannel;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // Answers GET requests with a body
+    struct GetService {}
+    #[async_trait]
+    impl RhodService<Comm> for GetService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            assert_eq!(req.method(), Method::GET);
+            RhodResponse::builder().body_str("hello").build()
+        }
+    }
+
+    async fn run(stack: &RhodStack<Comm>, method: Method, uri: &str) -> RhodResponse {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .method(method)
+            .uri(uri)
+            .build()
+            .unwrap();
+        stack.execute(&conn, req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_methods() {
+        let handler =
+            MethodsHandler::new(&[Method::GET, Method::POST]).with_path("/static", &[Method::GET]);
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(handler))],
+            Box::new(GetService {}),
+        );
+
+        let mut res = run(&stack, Method::HEAD, "/").await;
+        assert_eq!(res.status_as_int(), 200);
+        assert_eq!(res.headers()[CONTENT_LENGTH], "5");
+        assert!(res.body().await.unwrap().is_empty());
+
+        let res = run(&stack, Method::OPTIONS, "/api").await;
+        assert_eq!(res.status_as_int(), 204);
+        assert_eq!(res.headers()[ALLOW], "GET, HEAD, POST, OPTIONS");
+        let res = run(&stack, Method::OPTIONS, "/static/app.js").await;
+        assert_eq!(res.headers()[ALLOW], "GET, HEAD, OPTIONS");
+    }
+}
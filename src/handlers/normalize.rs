@@ -0,0 +1,249 @@
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{HeaderValue, HOST};
+use http::uri::{Authority, PathAndQuery};
+use http::{StatusCode, Uri};
+
+// Uri of the request before normalizing it, saved in the request context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalUri(pub Uri);
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_' || b == b'~'
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+// Decodes the percent-encoded unreserved characters and uppercases the remaining escapes (RFC 3986, section 6.2.2)
+fn normalize_escapes(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut normalized = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let decoded = h * 16 + l;
+                if is_unreserved(decoded) {
+                    normalized.push(decoded as char);
+                } else {
+                    normalized.push('%');
+                    normalized.push((bytes[i + 1] as char).to_ascii_uppercase());
+                    normalized.push((bytes[i + 2] as char).to_ascii_uppercase());
+                }
+                i += 3;
+                continue;
+            }
+        }
+        normalized.push(bytes[i] as char);
+        i += 1;
+    }
+    normalized
+}
+
+// Canonical form of a path: escapes normalized, empty and "." segments removed, ".." segments resolved.
+// Returns None if a ".." segment goes above the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let decoded = normalize_escapes(path);
+    let mut segments: Vec<&str> = vec![];
+    let mut trailing_slash = false;
+    for segment in decoded.split('/') {
+        match segment {
+            "" => (),
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop()?;
+                trailing_slash = true;
+            }
+            segment => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+    if decoded.ends_with('/') {
+        trailing_slash = true;
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+// Canonicalizes the request uri before the next handlers see it: normalizes percent-encoding,
+// collapses "//" and "/./", resolves "/.." (rejecting with 400 paths going above the root),
+// and optionally lowercases the host. The original uri is saved in the context (OriginalUri).
+pub struct NormalizeHandler {
+    lowercase_host: bool,
+}
+
+impl NormalizeHandler {
+    pub fn new() -> NormalizeHandler {
+        NormalizeHandler {
+            lowercase_host: false,
+        }
+    }
+
+    pub fn lowercase_host(self) -> NormalizeHandler {
+        NormalizeHandler {
+            lowercase_host: true,
+        }
+    }
+}
+
+impl Default for NormalizeHandler {
+    fn default() -> NormalizeHandler {
+        NormalizeHandler::new()
+    }
+}
+
+fn bad_request(msg: String) -> RhodError {
+    RhodError::with_status(StatusCode::BAD_REQUEST, msg, RhodErrorLevel::Warning)
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for NormalizeHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let original = req.uri().clone();
+        let path = match normalize_path(original.path()) {
+            Some(path) => path,
+            None => {
+                return Err(bad_request(format!(
+                    "Path traversal above the root: {}",
+                    original.path()
+                )))
+            }
+        };
+
+        let mut parts = original.clone().into_parts();
+        let path_and_query = match original.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        parts.path_and_query = match path_and_query.parse::<PathAndQuery>() {
+            Ok(pq) => Some(pq),
+            Err(e) => return Err(bad_request(format!("Invalid normalized path. {}", e))),
+        };
+        if self.lowercase_host {
+            if let Some(authority) = parts.authority.take() {
+                parts.authority = authority.as_str().to_lowercase().parse::<Authority>().ok();
+            }
+            let host = req
+                .headers()
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_lowercase());
+            if let Some(host) = host.and_then(|h| HeaderValue::from_str(&h).ok()) {
+                req.headers_mut().insert(HOST, host);
+            }
+        }
+
+        match Uri::from_parts(parts) {
+            Ok(uri) => {
+                if uri != original {
+                    debug!("Uri normalized from {} to {}", original, uri);
+                }
+                *req.uri_mut() = uri;
+                req.context().insert(OriginalUri(original));
+                Ok(())
+            }
+            Err(e) => Err(bad_request(format!("Invalid normalized uri. {}", e))),
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("//a//b/").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("/a/./b/.").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("/a/b/../c").unwrap(), "/a/c");
+        assert_eq!(normalize_path("/a/b/..").unwrap(), "/a/");
+        assert_eq!(
+            normalize_path("/%7euser/%61%2fb%2f").unwrap(),
+            "/~user/a%2Fb%2F"
+        );
+        assert_eq!(normalize_path("/a/%2e%2E/b").unwrap(), "/b");
+        assert_eq!(normalize_path("/a/..").unwrap(), "/");
+        assert!(normalize_path("/../etc/passwd").is_none());
+        assert!(normalize_path("/a/%2e%2e/../etc").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_normalize_handler() {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let handler = NormalizeHandler::new().lowercase_host();
+
+        let mut req = RhodRequest::builder()
+            .uri("http://WWW.Example.com//static/./../api/%75sers?q=%2e%2e")
+            .header("Host", "WWW.Example.com")
+            .build()
+            .unwrap();
+        RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap();
+        assert_eq!(
+            req.uri().to_string(),
+            "http://www.example.com/api/users?q=%2e%2e"
+        );
+        assert_eq!(req.headers().get("Host").unwrap(), "www.example.com");
+        assert_eq!(
+            req.context().get::<OriginalUri>().unwrap().0.path(),
+            "//static/./../api/%75sers"
+        );
+
+        let mut req = RhodRequest::builder()
+            .uri("/static/../../etc/passwd")
+            .build()
+            .unwrap();
+        let err = RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.response().unwrap().status_as_int(), 400);
+    }
+}
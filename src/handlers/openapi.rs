@@ -0,0 +1,919 @@
+mod schema;
+
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::{BodyProcessor, MediaType, RhodRequest};
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use http::header::{ALLOW, CONTENT_TYPE};
+use http::{Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use schema::{resolve, Validator};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+
+// What to do with responses that dont match the spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseValidation {
+    Log,    // logged as a warning, the response is sent anyway
+    Reject, // replaced by a 502
+}
+
+// Operation of the spec matched by the request, saved in the context (ie: to label metrics by route)
+#[derive(Debug, Clone)]
+pub struct OpenApiOperation {
+    pub operation_id: Option<String>,
+    pub method: Method,
+    pub path: String, // template, ie: /pets/{id}
+    operation: Arc<Operation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+#[derive(Debug, Clone)]
+struct Parameter {
+    name: String,
+    location: Location,
+    required: bool,
+    schema: Option<Value>,
+}
+
+// Media types of a request or response body, with their schemas
+type Content = Vec<(String, Option<Value>)>;
+
+#[derive(Debug)]
+struct Operation {
+    parameters: Vec<Parameter>,
+    body: Option<(bool, Content)>,     // required, content
+    responses: Vec<(String, Content)>, // status ("200", "2XX" or "default")
+}
+
+#[derive(Debug)]
+struct PathItem {
+    template: String,
+    regex: Regex,
+    names: Vec<String>, // of the path parameters, in order
+    operations: Vec<(Method, Option<String>, Arc<Operation>)>, // method, operationId
+}
+
+// Validates requests against an OpenAPI 3 spec (YAML or JSON), for API gateways exposing only what the
+// spec describes:
+// - the path and method must be an operation of the spec (404 and 405 otherwise)
+// - path, query and header parameters must be there if required, and match their schemas. Their values
+//   are converted to the type of the schema first, and arrays are comma separated or repeated
+// - the body must be there if required, of one of the media types of the operation (415 otherwise), and
+//   JSON bodies must match their schema
+// Invalid requests are answered 400 with a problem detail (application/problem+json) listing the errors.
+// Responses are only validated with with_response_validation: their status must be in the spec, and
+// JSON bodies must match their schema.
+// The matched operation is saved as an OpenApiOperation in the context. Requests outside the base path
+// (with_base_path) arent validated.
+pub struct OpenApiHandler {
+    document: Value,
+    paths: Vec<PathItem>, // concrete paths before templated ones
+    base_path: String,
+    responses: Option<ResponseValidation>,
+    patterns: DashMap<String, Option<Regex>>,
+}
+
+fn invalid_spec(msg: String) -> RhodError {
+    RhodError::from_string(
+        format!("Invalid OpenAPI spec. {}", msg),
+        RhodErrorLevel::Error,
+    )
+}
+
+fn content(document: &Value, content: Option<&Value>) -> Content {
+    content
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(media_type, media)| {
+            let schema = media.get("schema").cloned();
+            (
+                media_type.to_ascii_lowercase(),
+                schema.map(|s| resolve_or(document, s)),
+            )
+        })
+        .collect()
+}
+
+// Schema with its top $ref resolved, the schema itself if it cant be
+fn resolve_or(document: &Value, schema: Value) -> Value {
+    resolve(document, &schema).cloned().unwrap_or(schema)
+}
+
+fn parameters(document: &Value, list: Option<&Value>) -> RhodResult<Vec<Parameter>> {
+    let mut parameters = vec![];
+    for parameter in list.and_then(Value::as_array).into_iter().flatten() {
+        let parameter = resolve(document, parameter)
+            .ok_or_else(|| invalid_spec("Unresolved parameter reference".to_string()))?;
+        let name = parameter.get("name").and_then(Value::as_str).unwrap_or("");
+        let location = match parameter.get("in").and_then(Value::as_str) {
+            Some("path") => Location::Path,
+            Some("query") => Location::Query,
+            Some("header") => Location::Header,
+            _ => continue, // cookies arent validated
+        };
+        parameters.push(Parameter {
+            name: match location {
+                Location::Header => name.to_ascii_lowercase(),
+                _ => name.to_string(),
+            },
+            location,
+            required: location == Location::Path
+                || parameter.get("required") == Some(&Value::Bool(true)),
+            schema: parameter
+                .get("schema")
+                .cloned()
+                .map(|s| resolve_or(document, s)),
+        });
+    }
+    Ok(parameters)
+}
+
+// Regex matching the paths of a template, and the names of its parameters
+fn path_regex(template: &str) -> RhodResult<(Regex, Vec<String>)> {
+    let mut regex = String::from("^");
+    let mut names = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| invalid_spec(format!("Unclosed parameter in path {}", template)))?;
+        regex.push_str(&regex::escape(&rest[..start]));
+        regex.push_str("([^/]+)");
+        names.push(rest[start + 1..end].to_string());
+        rest = &rest[end + 1..];
+    }
+    regex.push_str(&regex::escape(rest));
+    regex.push('$');
+    let regex = Regex::new(&regex).map_err(|e| invalid_spec(e.to_string()))?;
+    Ok((regex, names))
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+// Value of a parameter, converted to the type of its schema. Values that cant be converted stay
+// strings, failing the type check
+fn coerce(document: &Value, schema: &Value, raw: &[String]) -> Value {
+    fn schema_type(schema: &Value) -> Option<&str> {
+        match schema.get("type") {
+            Some(Value::String(t)) => Some(t),
+            Some(Value::Array(types)) => types
+                .iter()
+                .filter_map(Value::as_str)
+                .find(|t| *t != "null"),
+            _ => None,
+        }
+    }
+    fn scalar(t: Option<&str>, s: &str) -> Value {
+        let converted = match t {
+            Some("integer") => s.parse::<i64>().ok().map(Value::from),
+            Some("number") => s
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            Some("boolean") => s.parse::<bool>().ok().map(Value::Bool),
+            _ => None,
+        };
+        converted.unwrap_or_else(|| Value::String(s.to_string()))
+    }
+
+    let schema = resolve(document, schema).unwrap_or(schema);
+    match schema_type(schema) {
+        Some("array") => {
+            let items = schema
+                .get("items")
+                .map(|items| resolve(document, items).unwrap_or(items));
+            let item_type = items.and_then(schema_type);
+            let values: Vec<&str> = match raw {
+                [single] => single.split(',').collect(),
+                _ => raw.iter().map(String::as_str).collect(),
+            };
+            Value::Array(values.into_iter().map(|v| scalar(item_type, v)).collect())
+        }
+        t => scalar(t, raw.first().map_or("", String::as_str)),
+    }
+}
+
+// Whether the media type of the spec (maybe a range like image/*) accepts the one of the body
+fn media_matches(range: &str, essence: &str) -> bool {
+    match range.split(';').next().unwrap_or("").trim() {
+        "*/*" => true,
+        range => match range.strip_suffix("/*") {
+            Some(kind) => essence.split('/').next() == Some(kind),
+            None => range == essence,
+        },
+    }
+}
+
+fn problem(status: StatusCode, detail: &str, errors: &[String]) -> RhodResult<RhodError> {
+    let mut problem = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "detail": detail,
+    });
+    if !errors.is_empty() {
+        problem["errors"] = json!(errors);
+    }
+    let err = RhodError::from_string(
+        format!("{}. {}", detail, errors.join(", ")),
+        RhodErrorLevel::Warning,
+    );
+    let res = RhodResponse::builder()
+        .status(status)
+        .header(CONTENT_TYPE.as_str(), "application/problem+json")
+        .body_json(&problem)
+        .build()?;
+    Ok(err.with_response(res))
+}
+
+impl OpenApiHandler {
+    // Reads a .yaml, .yml or .json spec
+    pub fn from_file<P: AsRef<Path>>(path: P) -> RhodResult<OpenApiHandler> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            RhodError::from_string(
+                format!("Couldnt read {}. {}", path.display(), e),
+                RhodErrorLevel::Error,
+            )
+        })?;
+        OpenApiHandler::from_yaml(&content)
+    }
+
+    // Spec in YAML or JSON (a JSON document is YAML too)
+    pub fn from_yaml(spec: &str) -> RhodResult<OpenApiHandler> {
+        let document: Value =
+            serde_yaml::from_str(spec).map_err(|e| invalid_spec(e.to_string()))?;
+        let version = document
+            .get("openapi")
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        if !version.starts_with("3.") {
+            return Err(invalid_spec(format!(
+                "Only OpenAPI 3 is supported, got version '{}'",
+                version
+            )));
+        }
+
+        let mut paths = vec![];
+        for (template, item) in document
+            .get("paths")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            let item = resolve(&document, item)
+                .ok_or_else(|| invalid_spec(format!("Unresolved path item {}", template)))?;
+            let (regex, names) = path_regex(template)?;
+            let shared = parameters(&document, item.get("parameters"))?;
+            let mut operations = vec![];
+            for method in [
+                "get", "put", "post", "delete", "options", "head", "patch", "trace",
+            ] {
+                let operation = match item.get(method) {
+                    Some(operation) => operation,
+                    None => continue,
+                };
+                // parameters of the operation override the ones of the path with the same name and location
+                let mut parameters = parameters(&document, operation.get("parameters"))?;
+                for parameter in &shared {
+                    if !parameters
+                        .iter()
+                        .any(|p| p.name == parameter.name && p.location == parameter.location)
+                    {
+                        parameters.push(parameter.clone());
+                    }
+                }
+                let body = match operation.get("requestBody") {
+                    Some(body) => {
+                        let body = resolve(&document, body).ok_or_else(|| {
+                            invalid_spec(format!("Unresolved request body of {}", template))
+                        })?;
+                        let required = body.get("required") == Some(&Value::Bool(true));
+                        Some((required, content(&document, body.get("content"))))
+                    }
+                    None => None,
+                };
+                let responses = operation
+                    .get("responses")
+                    .and_then(Value::as_object)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(status, response)| {
+                        let response = resolve(&document, response)?;
+                        Some((
+                            status.to_ascii_uppercase(),
+                            content(&document, response.get("content")),
+                        ))
+                    })
+                    .collect();
+                let operation_id = operation
+                    .get("operationId")
+                    .and_then(Value::as_str)
+                    .map(String::from);
+                operations.push((
+                    method
+                        .to_ascii_uppercase()
+                        .parse::<Method>()
+                        .unwrap_or(Method::GET),
+                    operation_id,
+                    Arc::new(Operation {
+                        parameters,
+                        body,
+                        responses,
+                    }),
+                ));
+            }
+            paths.push(PathItem {
+                template: template.clone(),
+                regex,
+                names,
+                operations,
+            });
+        }
+        paths.sort_by_key(|path| path.names.len());
+
+        Ok(OpenApiHandler {
+            document,
+            paths,
+            base_path: String::new(),
+            responses: None,
+            patterns: DashMap::new(),
+        })
+    }
+
+    // Prefix of the paths of the spec, ie: /api/v1
+    pub fn with_base_path(self, base_path: &str) -> Self {
+        OpenApiHandler {
+            base_path: base_path.trim_end_matches('/').to_string(),
+            ..self
+        }
+    }
+
+    pub fn with_response_validation(self, validation: ResponseValidation) -> Self {
+        OpenApiHandler {
+            responses: Some(validation),
+            ..self
+        }
+    }
+
+    fn validate(&self, schema: &Value, value: &Value, path: &str) -> Vec<String> {
+        Validator::new(&self.document, &self.patterns).validate(schema, value, path)
+    }
+
+    // Errors of the parameters of the request
+    fn check_parameters(
+        &self,
+        operation: &Operation,
+        req: &RhodRequest,
+        path_values: &[(String, String)],
+    ) -> Vec<String> {
+        let query: Vec<(String, String)> = req
+            .uri()
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(name), decode(value))
+            })
+            .collect();
+
+        let mut errors = vec![];
+        for parameter in &operation.parameters {
+            let raw: Vec<String> = match parameter.location {
+                Location::Path => path_values
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                Location::Query => query
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                Location::Header => req
+                    .headers()
+                    .get_all(parameter.name.as_str())
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .map(|v| v.trim().to_string())
+                    .collect(),
+            };
+            let location = match parameter.location {
+                Location::Path => "path",
+                Location::Query => "query",
+                Location::Header => "header",
+            };
+            let path = format!("{}.{}", location, parameter.name);
+            if raw.is_empty() {
+                if parameter.required {
+                    errors.push(format!("{}: missing required parameter", path));
+                }
+                continue;
+            }
+            if let Some(schema) = &parameter.schema {
+                let value = coerce(&self.document, schema, &raw);
+                errors.extend(self.validate(schema, &value, &path));
+            }
+        }
+        errors
+    }
+
+    // Errors of the body of the request, or the 415 error of an unexpected media type
+    async fn check_body(
+        &self,
+        required: bool,
+        content: &Content,
+        req: &mut RhodRequest,
+    ) -> RhodResult<Vec<String>> {
+        let body = req.body_bytes().await?;
+        if body.is_empty() {
+            return Ok(match required {
+                true => vec!["body: missing required body".to_string()],
+                false => vec![],
+            });
+        }
+        if content.is_empty() {
+            return Ok(vec![]);
+        }
+        let media_type = req.media_type();
+        let essence = media_type
+            .as_ref()
+            .map(|m| m.mime().essence_str().to_ascii_lowercase())
+            .unwrap_or_default();
+        let schema = match content
+            .iter()
+            .find(|(range, _)| media_matches(range, &essence))
+        {
+            Some((_, schema)) => schema,
+            None => {
+                let expected: Vec<&str> = content.iter().map(|(m, _)| m.as_str()).collect();
+                return Err(problem(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    &format!("Expected a body of type {}", expected.join(", ")),
+                    &[],
+                )?);
+            }
+        };
+        match (schema, media_type.map(|m| m.processor())) {
+            (Some(schema), Some(BodyProcessor::JSON)) => match serde_json::from_slice(&body) {
+                Ok(value) => Ok(self.validate(schema, &value, "body")),
+                Err(e) => Ok(vec![format!("body: invalid JSON. {}", e)]),
+            },
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Errors of a response to the operation
+    async fn check_response(
+        &self,
+        operation: &Operation,
+        res: &mut RhodResponse,
+    ) -> RhodResult<Vec<String>> {
+        let status = res.status_as_int().to_string();
+        let class = format!("{}XX", &status[..1]);
+        let content = [status.as_str(), class.as_str(), "DEFAULT"]
+            .iter()
+            .find_map(|key| operation.responses.iter().find(|(s, _)| s == key));
+        let content = match content {
+            Some((_, content)) => content,
+            None => return Ok(vec![format!("status: {} isnt documented", status)]),
+        };
+        let media_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(MediaType::parse);
+        let media_type = match media_type {
+            Some(media_type) => media_type,
+            None => return Ok(vec![]),
+        };
+        let essence = media_type.mime().essence_str().to_ascii_lowercase();
+        let schema = content
+            .iter()
+            .find(|(range, _)| media_matches(range, &essence))
+            .and_then(|(_, schema)| schema.as_ref());
+        match (schema, media_type.processor()) {
+            (Some(schema), BodyProcessor::JSON) => {
+                let body = res.body_bytes().await?;
+                match serde_json::from_slice(&body) {
+                    Ok(value) => Ok(self.validate(schema, &value, "body")),
+                    Err(e) => Ok(vec![format!("body: invalid JSON. {}", e)]),
+                }
+            }
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for OpenApiHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let path = match req.uri().path().strip_prefix(self.base_path.as_str()) {
+            Some("") => "/".to_string(),
+            Some(path) if path.starts_with('/') => path.to_string(),
+            _ => return Ok(()),
+        };
+        let (item, captures) = match self
+            .paths
+            .iter()
+            .find_map(|item| item.regex.captures(&path).map(|c| (item, c)))
+        {
+            Some(found) => found,
+            None => {
+                return Err(problem(
+                    StatusCode::NOT_FOUND,
+                    &format!("No operation for {}", path),
+                    &[],
+                )?)
+            }
+        };
+        let path_values: Vec<(String, String)> = item
+            .names
+            .iter()
+            .zip(captures.iter().skip(1))
+            .filter_map(|(name, value)| Some((name.clone(), decode(value?.as_str()))))
+            .collect();
+
+        let method = match req.method() {
+            &Method::HEAD if !item.operations.iter().any(|(m, _, _)| *m == Method::HEAD) => {
+                Method::GET
+            }
+            method => method.clone(),
+        };
+        let (method, operation_id, operation) =
+            match item.operations.iter().find(|(m, _, _)| *m == method) {
+                Some(operation) => operation,
+                None => {
+                    let allowed: Vec<&str> =
+                        item.operations.iter().map(|(m, _, _)| m.as_str()).collect();
+                    let mut err = problem(
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        &format!("{} isnt allowed on {}", req.method(), item.template),
+                        &[],
+                    )?;
+                    if let Some(mut res) = err.take_response() {
+                        if let Ok(allow) = allowed.join(", ").parse() {
+                            res.headers_mut().insert(ALLOW, allow);
+                        }
+                        err = err.with_response(res);
+                    }
+                    return Err(err);
+                }
+            };
+        req.context().insert(OpenApiOperation {
+            operation_id: operation_id.clone(),
+            method: method.clone(),
+            path: item.template.clone(),
+            operation: Arc::clone(operation),
+        });
+
+        let mut errors = self.check_parameters(operation, req, &path_values);
+        if let Some((required, content)) = &operation.body {
+            errors.extend(self.check_body(*required, content, req).await?);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(problem(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid request to {} {}", method, item.template),
+                &errors,
+            )?)
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let validation = match self.responses {
+            Some(validation) => validation,
+            None => return (res, Ok(())),
+        };
+        let operation = match res.context().get::<OpenApiOperation>() {
+            Some(operation) => operation,
+            None => return (res, Ok(())),
+        };
+        let errors = match self.check_response(&operation.operation, &mut res).await {
+            Ok(errors) if errors.is_empty() => return (res, Ok(())),
+            Ok(errors) => errors,
+            Err(e) => return (res, Err(e)),
+        };
+        let msg = format!(
+            "Invalid response of {} {}. {}",
+            operation.method,
+            operation.path,
+            errors.join(", ")
+        );
+        match validation {
+            ResponseValidation::Log => {
+                warn!("{}", msg);
+                (res, Ok(()))
+            }
+            ResponseValidation::Reject => {
+                let err = RhodError::from_string(msg, RhodErrorLevel::Error);
+                match RhodResponse::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .build()
+                {
+                    Ok(bad_gateway) => (res, Err(err.with_response(bad_gateway))),
+                    Err(e) => (res, Err(e)),
+                }
+            }
+        }
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    const SPEC: &str = r##"
+openapi: 3.0.3
+info: {title: Pets, version: "1"}
+paths:
+  /pets:
+    get:
+      operationId: listPets
+      parameters:
+        - {name: limit, in: query, schema: {type: integer, minimum: 1, maximum: 100}}
+        - {name: tags, in: query, schema: {type: array, items: {type: string}, maxItems: 2}}
+      responses:
+        "200":
+          description: Pets
+          content:
+            application/json:
+              schema: {type: array, items: {$ref: "#/components/schemas/Pet"}}
+    post:
+      operationId: createPet
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema: {$ref: "#/components/schemas/Pet"}
+      responses:
+        "201": {description: Created}
+  /pets/mine:
+    get:
+      responses:
+        default: {description: Mine}
+  /pets/{id}:
+    parameters:
+      - {name: id, in: path, required: true, schema: {type: integer}}
+    get:
+      operationId: getPet
+      parameters:
+        - {name: X-Tenant, in: header, required: true, schema: {type: string, pattern: "^[a-z]+$"}}
+      responses:
+        "2XX": {description: Pet}
+components:
+  schemas:
+    Pet:
+      type: object
+      required: [name]
+      additionalProperties: false
+      properties:
+        name: {type: string, minLength: 1}
+        tag: {type: string, nullable: true}
+        age: {type: integer, minimum: 0}
+"##;
+
+    async fn check(
+        handler: &OpenApiHandler,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Result<Option<String>, (u16, Value)> {
+        let mut builder = RhodRequest::builder()
+            .method(method)
+            .uri(uri)
+            .body_str(body);
+        for (name, value) in headers {
+            builder = builder.header(name, value);
+        }
+        let mut req = builder.build().unwrap();
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        match RhodHandler::<()>::handle_request(handler, &conn, &mut req, &mut ()).await {
+            Ok(()) => Ok(req
+                .context()
+                .get::<OpenApiOperation>()
+                .and_then(|o| o.operation_id)),
+            Err(mut e) => {
+                let mut res = e.take_response().unwrap();
+                let body = res.body().await.unwrap();
+                Err((
+                    res.status_as_int(),
+                    serde_json::from_slice(&body).unwrap_or(Value::Null),
+                ))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let handler = OpenApiHandler::from_yaml(SPEC)
+            .unwrap()
+            .with_base_path("/api")
+            .with_response_validation(ResponseValidation::Reject);
+        let json = [("content-type", "application/json")];
+        let tenant = [("x-tenant", "acme")];
+
+        assert_eq!(
+            check(
+                &handler,
+                Method::GET,
+                "/api/pets?limit=10&tags=a,b",
+                &[],
+                ""
+            )
+            .await,
+            Ok(Some("listPets".to_string()))
+        );
+        // concrete paths before templated ones
+        assert_eq!(
+            check(&handler, Method::GET, "/api/pets/mine", &[], "").await,
+            Ok(None)
+        );
+        assert_eq!(
+            check(&handler, Method::GET, "/api/pets/7", &tenant, "").await,
+            Ok(Some("getPet".to_string()))
+        );
+        assert_eq!(
+            check(
+                &handler,
+                Method::POST,
+                "/api/pets",
+                &json,
+                r#"{"name":"Rex","tag":null}"#
+            )
+            .await,
+            Ok(Some("createPet".to_string()))
+        );
+        // outside the base path
+        assert_eq!(
+            check(&handler, Method::GET, "/other", &[], "").await,
+            Ok(None)
+        );
+
+        let (status, problem) = check(
+            &handler,
+            Method::GET,
+            "/api/pets?limit=0&tags=a,b,c",
+            &[],
+            "",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, 400);
+        assert_eq!(
+            problem["errors"],
+            json!([
+                "query.limit: 0 is less than the minimum 1",
+                "query.tags: more than 2 items"
+            ])
+        );
+        let (status, problem) = check(&handler, Method::GET, "/api/pets/abc", &[], "")
+            .await
+            .unwrap_err();
+        assert_eq!(status, 400);
+        assert_eq!(
+            problem["errors"],
+            json!([
+                "header.x-tenant: missing required parameter",
+                "path.id: expected integer, got string"
+            ])
+        );
+        let (status, problem) = check(
+            &handler,
+            Method::POST,
+            "/api/pets",
+            &json,
+            r#"{"name":"","age":-1,"owner":"me"}"#,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, 400);
+        assert_eq!(
+            problem["errors"],
+            json!([
+                "body.age: -1 is less than the minimum 0",
+                "body.name: shorter than 1 characters",
+                "body: unexpected property owner"
+            ])
+        );
+        assert_eq!(
+            check(&handler, Method::POST, "/api/pets", &json, "")
+                .await
+                .unwrap_err()
+                .0,
+            400
+        );
+        assert_eq!(
+            check(
+                &handler,
+                Method::POST,
+                "/api/pets",
+                &[("content-type", "text/plain")],
+                "Rex"
+            )
+            .await
+            .unwrap_err()
+            .0,
+            415
+        );
+        assert_eq!(
+            check(&handler, Method::GET, "/api/owners", &[], "")
+                .await
+                .unwrap_err()
+                .0,
+            404
+        );
+        assert_eq!(
+            check(&handler, Method::DELETE, "/api/pets", &[], "")
+                .await
+                .unwrap_err()
+                .0,
+            405
+        );
+
+        // responses
+        let operation = OpenApiOperation {
+            operation_id: None,
+            method: Method::GET,
+            path: "/pets".to_string(),
+            operation: Arc::clone(&handler.paths[0].operations[0].2),
+        };
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        for (status, body, valid) in [
+            (200, r#"[{"name":"Rex"}]"#, true),
+            (200, r#"[{"age":3}]"#, false),
+            (404, "", false),
+        ] {
+            let res = RhodResponse::builder()
+                .status(StatusCode::from_u16(status).unwrap())
+                .header("content-type", "application/json")
+                .body_str(body)
+                .build()
+                .unwrap();
+            res.context().insert(operation.clone());
+            let (_, result) =
+                RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+            assert_eq!(result.is_ok(), valid);
+            if let Err(mut e) = result {
+                assert_eq!(e.take_response().unwrap().status_as_int(), 502);
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+// Validation of values against the schemas of an OpenAPI document. Covers the JSON Schema keywords
+// used by OpenAPI 3.0 and 3.1 (type, nullable, enum, const, numeric and length bounds, pattern, items,
+// properties, required, additionalProperties, allOf, anyOf, oneOf, not) and $ref pointers into the
+// document. Formats and discriminators are not checked.
+use dashmap::DashMap;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+const MAX_REF_DEPTH: usize = 64; // recursive schemas are followed as deep as the value, with a limit
+const MAX_ERRORS: usize = 10;
+
+pub(crate) struct Validator<'a> {
+    document: &'a Value,
+    patterns: &'a DashMap<String, Option<Regex>>, // compiled once, None if invalid
+    errors: Vec<String>,
+}
+
+impl<'a> Validator<'a> {
+    pub(crate) fn new(
+        document: &'a Value,
+        patterns: &'a DashMap<String, Option<Regex>>,
+    ) -> Validator<'a> {
+        Validator {
+            document,
+            patterns,
+            errors: vec![],
+        }
+    }
+
+    // Errors of the value, each one prefixed by the path of the invalid part, ie: body.items[2].name
+    pub(crate) fn validate(mut self, schema: &Value, value: &Value, path: &str) -> Vec<String> {
+        self.check(schema, value, path, 0);
+        self.errors
+    }
+
+    fn error(&mut self, path: &str, msg: String) {
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(format!("{}: {}", path, msg));
+        }
+    }
+
+    // Whether the value is valid, without keeping its errors (anyOf, oneOf, not)
+    fn matches(&self, schema: &Value, value: &Value, depth: usize) -> bool {
+        let mut validator = Validator::new(self.document, self.patterns);
+        validator.check(schema, value, "", depth);
+        validator.errors.is_empty()
+    }
+
+    fn check(&mut self, schema: &Value, value: &Value, path: &str, depth: usize) {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(false) => return self.error(path, "not allowed".to_string()),
+            _ => return,
+        };
+        if let Some(Value::String(pointer)) = schema.get("$ref") {
+            if depth >= MAX_REF_DEPTH {
+                return self.error(path, format!("too deep following {}", pointer));
+            }
+            match resolve_pointer(self.document, pointer) {
+                Some(target) => self.check(target, value, path, depth + 1),
+                None => self.error(path, format!("unresolved reference {}", pointer)),
+            }
+            // 3.0 ignores the siblings of $ref, 3.1 doesnt
+            if schema.len() == 1 {
+                return;
+            }
+        }
+
+        if value.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+            return;
+        }
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match types {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+            if !allowed.is_empty() && !allowed.iter().any(|t| is_type(value, t)) {
+                return self.error(
+                    path,
+                    format!("expected {}, got {}", allowed.join(" or "), type_of(value)),
+                );
+            }
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            if !values.contains(value) {
+                self.error(
+                    path,
+                    format!("{} is not one of {}", value, Value::from(values.clone())),
+                );
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                self.error(path, format!("expected {}", expected));
+            }
+        }
+
+        match value {
+            Value::Number(n) => self.check_number(schema, n.as_f64().unwrap_or(0.0), path),
+            Value::String(s) => self.check_string(schema, s, path),
+            Value::Array(items) => self.check_array(schema, items, path, depth),
+            Value::Object(object) => self.check_object(schema, object, path, depth),
+            _ => {}
+        }
+
+        if let Some(Value::Array(all)) = schema.get("allOf") {
+            for sub in all {
+                self.check(sub, value, path, depth + 1);
+            }
+        }
+        if let Some(Value::Array(any)) = schema.get("anyOf") {
+            if !any.iter().any(|sub| self.matches(sub, value, depth + 1)) {
+                self.error(path, "doesnt match any of the anyOf schemas".to_string());
+            }
+        }
+        if let Some(Value::Array(one)) = schema.get("oneOf") {
+            let matched = one
+                .iter()
+                .filter(|sub| self.matches(sub, value, depth + 1))
+                .count();
+            if matched != 1 {
+                self.error(
+                    path,
+                    format!("matches {} of the oneOf schemas instead of 1", matched),
+                );
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.matches(not, value, depth + 1) {
+                self.error(path, "matches the not schema".to_string());
+            }
+        }
+    }
+
+    fn check_number(&mut self, schema: &Map<String, Value>, n: f64, path: &str) {
+        let bound = |name: &str| schema.get(name).and_then(Value::as_f64);
+        // exclusiveMinimum/Maximum are flags of minimum/maximum in 3.0, and bounds in 3.1
+        let exclusive = |name: &str| schema.get(name) == Some(&Value::Bool(true));
+        if let Some(min) = bound("minimum") {
+            if n < min || (exclusive("exclusiveMinimum") && n == min) {
+                self.error(path, format!("{} is less than the minimum {}", n, min));
+            }
+        }
+        if let Some(max) = bound("maximum") {
+            if n > max || (exclusive("exclusiveMaximum") && n == max) {
+                self.error(path, format!("{} is greater than the maximum {}", n, max));
+            }
+        }
+        if let Some(min) = bound("exclusiveMinimum") {
+            if n <= min {
+                self.error(path, format!("{} is not greater than {}", n, min));
+            }
+        }
+        if let Some(max) = bound("exclusiveMaximum") {
+            if n >= max {
+                self.error(path, format!("{} is not less than {}", n, max));
+            }
+        }
+        if let Some(multiple) = bound("multipleOf") {
+            if multiple > 0.0
+                && ((n / multiple).round() * multiple - n).abs() > f64::EPSILON * n.abs().max(1.0)
+            {
+                self.error(path, format!("{} is not a multiple of {}", n, multiple));
+            }
+        }
+    }
+
+    fn check_string(&mut self, schema: &Map<String, Value>, s: &str, path: &str) {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                self.error(path, format!("shorter than {} characters", min));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                self.error(path, format!("longer than {} characters", max));
+            }
+        }
+        if let Some(Value::String(pattern)) = schema.get("pattern") {
+            let matched = self
+                .patterns
+                .entry(pattern.clone())
+                .or_insert_with(|| Regex::new(pattern).ok())
+                .as_ref()
+                .map(|regex| regex.is_match(s));
+            if matched == Some(false) {
+                self.error(path, format!("doesnt match the pattern {}", pattern));
+            }
+        }
+    }
+
+    fn check_array(
+        &mut self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+        depth: usize,
+    ) {
+        let len = items.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if len < min {
+                self.error(path, format!("fewer than {} items", min));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if len > max {
+                self.error(path, format!("more than {} items", max));
+            }
+        }
+        if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+            let duplicated = items
+                .iter()
+                .enumerate()
+                .any(|(i, item)| items[..i].contains(item));
+            if duplicated {
+                self.error(path, "items are not unique".to_string());
+            }
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                self.check(item_schema, item, &format!("{}[{}]", path, i), depth + 1);
+            }
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+    ) {
+        let len = object.len() as u64;
+        if let Some(min) = schema.get("minProperties").and_then(Value::as_u64) {
+            if len < min {
+                self.error(path, format!("fewer than {} properties", min));
+            }
+        }
+        if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64) {
+            if len > max {
+                self.error(path, format!("more than {} properties", max));
+            }
+        }
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    self.error(path, format!("missing required property {}", name));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, value) in object {
+            let property_path = format!("{}.{}", path, name);
+            match properties.and_then(|p| p.get(name)) {
+                Some(property) => self.check(property, value, &property_path, depth + 1),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        self.error(path, format!("unexpected property {}", name))
+                    }
+                    Some(additional) => self.check(additional, value, &property_path, depth + 1),
+                    None => {}
+                },
+            }
+        }
+    }
+}
+
+fn is_type(value: &Value, t: &str) -> bool {
+    match t {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// Part of the document pointed by a local reference, ie: #/components/schemas/Pet
+pub(crate) fn resolve_pointer<'a>(document: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    document.pointer(pointer)
+}
+
+// Follows the $ref of parameters, request bodies and responses until the actual object
+pub(crate) fn resolve<'a>(document: &'a Value, mut value: &'a Value) -> Option<&'a Value> {
+    for _ in 0..MAX_REF_DEPTH {
+        match value.get("$ref").and_then(Value::as_str) {
+            Some(reference) => value = resolve_pointer(document, reference)?,
+            None => return Some(value),
+        }
+    }
+    None
+}
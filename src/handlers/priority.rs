@@ -0,0 +1,116 @@
+use crate::admission::Priority;
+use crate::errors::{RhodError, RhodResult};
+use crate::handlers::RequestPredicate;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+
+// Gives requests a Priority for the admission control of the stack (RhodStack::with_admission),
+// saved in the request context. The first rule matching the request gives its priority, else the
+// default one:
+//
+//  let priority = PriorityHandler::new(Priority::Interactive)
+//      .with_rule(RequestPredicate::PathPrefix("/health".into()), Priority::Critical)
+//      .with_rule(RequestPredicate::PathPrefix("/export".into()), Priority::Batch);
+pub struct PriorityHandler {
+    rules: Vec<(RequestPredicate, Priority)>,
+    default: Priority,
+}
+
+impl PriorityHandler {
+    pub fn new(default: Priority) -> PriorityHandler {
+        PriorityHandler {
+            rules: vec![],
+            default,
+        }
+    }
+
+    pub fn with_rule(mut self, predicate: RequestPredicate, priority: Priority) -> Self {
+        self.rules.push((predicate, priority));
+        self
+    }
+
+    fn priority(&self, req: &RhodRequest) -> Priority {
+        self.rules
+            .iter()
+            .find(|(predicate, _)| predicate.matches(req))
+            .map_or(self.default, |(_, priority)| *priority)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for PriorityHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let priority = self.priority(req);
+        req.context().insert(priority);
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use http::Method;
+
+    #[tokio::test]
+    async fn test_priority() {
+        let handler = PriorityHandler::new(Priority::Interactive)
+            .with_rule(
+                RequestPredicate::PathPrefix("/health".to_string()),
+                Priority::Critical,
+            )
+            .with_rule(RequestPredicate::Method(Method::POST), Priority::Batch);
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        for (method, path, priority) in [
+            (Method::GET, "/health", Priority::Critical),
+            (Method::POST, "/health", Priority::Critical),
+            (Method::POST, "/jobs", Priority::Batch),
+            (Method::GET, "/", Priority::Interactive),
+        ] {
+            let mut req = RhodRequest::builder()
+                .method(method)
+                .uri(path)
+                .build()
+                .unwrap();
+            RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+                .await
+                .unwrap();
+            assert_eq!(req.context().get::<Priority>(), Some(priority));
+        }
+    }
+}
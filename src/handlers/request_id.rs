@@ -0,0 +1,247 @@
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{HeaderName, HeaderValue};
+use std::net::IpAddr;
+
+const TRACEPARENT: &str = "traceparent";
+const MAX_ID_LEN: usize = 200;
+
+// Correlation ids of a request, saved in the request context by RequestIdHandler (ie: for logging)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId {
+    pub id: String,
+    pub trace_id: Option<String>, // W3C trace id, when trace context is propagated
+}
+
+// Whose ids are kept, the ones sent by other clients are replaced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestIdTrust {
+    All,
+    Nobody,
+    Peers(Vec<IpAddr>), // ie: the load balancer in front
+}
+
+impl RequestIdTrust {
+    fn trusts(&self, peer: IpAddr) -> bool {
+        match self {
+            RequestIdTrust::All => true,
+            RequestIdTrust::Nobody => false,
+            RequestIdTrust::Peers(peers) => peers.contains(&peer),
+        }
+    }
+}
+
+// Gives every request an id, kept when sent by a trusted peer and generated otherwise. The id is set on
+// the request (so proxy services forward it upstream), saved in the context as RequestId, and echoed
+// on the response.
+// With trace context (default), the W3C traceparent is propagated too: a trusted one keeps its trace id
+// and gets a new parent id (this hop), otherwise a new trace is started. Generated request ids are the
+// trace id, so both can be correlated.
+pub struct RequestIdHandler {
+    header: HeaderName,
+    trust: RequestIdTrust,
+    trace_context: bool,
+}
+
+impl Default for RequestIdHandler {
+    fn default() -> Self {
+        RequestIdHandler::new()
+    }
+}
+
+impl RequestIdHandler {
+    // X-Request-Id, trusting every peer
+    pub fn new() -> RequestIdHandler {
+        RequestIdHandler {
+            header: HeaderName::from_static("x-request-id"),
+            trust: RequestIdTrust::All,
+            trace_context: true,
+        }
+    }
+
+    pub fn with_header(self, header: HeaderName) -> Self {
+        RequestIdHandler { header, ..self }
+    }
+
+    pub fn with_trust(self, trust: RequestIdTrust) -> Self {
+        RequestIdHandler { trust, ..self }
+    }
+
+    pub fn without_trace_context(self) -> Self {
+        RequestIdHandler {
+            trace_context: false,
+            ..self
+        }
+    }
+}
+
+fn random_hex(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", fastrand::u8(..)))
+        .collect()
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// (trace id, flags) of a valid traceparent: version-traceid-parentid-flags
+fn parse_traceparent(value: &str) -> Option<(&str, &str)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let valid = is_hex(version, 2)
+        && version != "ff"
+        && is_hex(trace_id, 32)
+        && trace_id.bytes().any(|b| b != b'0')
+        && is_hex(parent_id, 16)
+        && parent_id.bytes().any(|b| b != b'0')
+        && is_hex(flags, 2);
+    // later versions may append fields
+    if !valid || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    Some((trace_id, flags))
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for RequestIdHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let trusted = self.trust.trusts(conn.addr.ip());
+        let mut trace_id = None;
+        if self.trace_context {
+            let incoming = req
+                .header_str(TRACEPARENT)
+                .filter(|_| trusted)
+                .and_then(parse_traceparent)
+                .map(|(trace_id, flags)| (trace_id.to_string(), flags.to_string()));
+            // sampling is left to the tracing backend for new traces
+            let (id, flags) = incoming.unwrap_or_else(|| (random_hex(16), "00".to_string()));
+            let traceparent = format!("00-{}-{}-{}", id, random_hex(8), flags);
+            if let Ok(value) = HeaderValue::from_str(&traceparent) {
+                req.headers_mut()
+                    .insert(HeaderName::from_static(TRACEPARENT), value);
+            }
+            trace_id = Some(id);
+        }
+
+        let incoming = req
+            .header_str(&self.header)
+            .filter(|id| trusted && valid_id(id))
+            .map(str::to_string);
+        let id = incoming
+            .or_else(|| trace_id.clone())
+            .unwrap_or_else(|| random_hex(16));
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            req.headers_mut().insert(self.header.clone(), value);
+        }
+        req.context().insert(RequestId { id, trace_id });
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if let Some(request_id) = res.context().get::<RequestId>() {
+            if let Ok(value) = HeaderValue::from_str(&request_id.id) {
+                res.headers_mut().insert(self.header.clone(), value);
+            }
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    async fn request_id(handler: &RequestIdHandler, req: &mut RhodRequest) -> RequestId {
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        RhodHandler::<()>::handle_request(handler, &conn, req, &mut ())
+            .await
+            .unwrap();
+        req.context().get::<RequestId>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let handler = RequestIdHandler::new();
+        let mut req = RhodRequest::builder()
+            .header("X-Request-Id", "abc-123")
+            .header("traceparent", traceparent)
+            .build()
+            .unwrap();
+        let ids = request_id(&handler, &mut req).await;
+        assert_eq!(ids.id, "abc-123");
+        assert_eq!(
+            ids.trace_id.as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        let forwarded = req.header_str("traceparent").unwrap();
+        assert!(forwarded.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(forwarded.ends_with("-01"));
+        assert_ne!(forwarded, traceparent);
+
+        let mut res = RhodResponse::builder().build().unwrap();
+        res.attach_context(req.context());
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let (res, _) = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert_eq!(res.headers()["x-request-id"], "abc-123");
+
+        // ids of untrusted peers are replaced
+        let handler = RequestIdHandler::new()
+            .with_header(HeaderName::from_static("x-correlation-id"))
+            .with_trust(RequestIdTrust::Peers(vec!["10.0.0.2".parse().unwrap()]));
+        let mut req = RhodRequest::builder()
+            .header("X-Correlation-Id", "abc-123")
+            .header("traceparent", traceparent)
+            .build()
+            .unwrap();
+        let ids = request_id(&handler, &mut req).await;
+        assert_eq!(ids.trace_id.as_deref(), Some(ids.id.as_str()));
+        assert_eq!(ids.id.len(), 32);
+        assert_eq!(req.header_str("x-correlation-id"), Some(ids.id.as_str()));
+        assert!(parse_traceparent(req.header_str("traceparent").unwrap()).is_some());
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+    }
+}
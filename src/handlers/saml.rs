@@ -0,0 +1,874 @@
+use super::auth::Principal;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::log_sink::iso8601;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL};
+use base64::Engine;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use http::{Method, StatusCode};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use roxmltree::Document;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::CertificateDer;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+mod assertion;
+mod dsig;
+use crate::util::{hex, unix_now};
+use assertion::{Assertion, Expected, ASSERTION_NS, PROTOCOL_NS};
+use dsig::{escape_attribute, DSIG_NS};
+
+const METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const HTTP_POST: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const HTTP_REDIRECT: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+
+// Paths served by the handler on every protected host
+const METADATA_PATH: &str = "/saml/metadata";
+const ACS_PATH: &str = "/saml/acs";
+
+// How long a login waits for the response of the IdP
+const PENDING_TTL: Duration = Duration::from_secs(600);
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+fn config_error(msg: String) -> RhodError {
+    RhodError::from_string(msg, RhodErrorLevel::Error)
+}
+
+// Identity provider trusted by a service provider. Its assertions must be signed by one of the
+// certificates (DER)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlIdp {
+    pub entity_id: String,
+    pub sso_url: String, // HTTP-Redirect endpoint of its single sign-on service
+    pub certificates: Vec<Vec<u8>>,
+}
+
+impl SamlIdp {
+    pub fn new(entity_id: &str, sso_url: &str) -> SamlIdp {
+        SamlIdp {
+            entity_id: entity_id.to_string(),
+            sso_url: sso_url.to_string(),
+            certificates: vec![],
+        }
+    }
+
+    pub fn with_certificate(mut self, der: Vec<u8>) -> Self {
+        self.certificates.push(der);
+        self
+    }
+
+    pub fn with_certificate_pem(mut self, pem: &[u8]) -> RhodResult<Self> {
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            let cert =
+                cert.map_err(|e| config_error(format!("Couldnt parse IdP certificate. {}", e)))?;
+            self.certificates.push(cert.to_vec());
+        }
+        Ok(self)
+    }
+
+    // Reads the entity id, the HTTP-Redirect SSO endpoint and the signing certificates of the
+    // EntityDescriptor of the IdP metadata
+    pub fn from_metadata(xml: &str) -> RhodResult<SamlIdp> {
+        let document = Document::parse(xml)
+            .map_err(|e| config_error(format!("Invalid IdP metadata. {}", e)))?;
+        let entity = document
+            .descendants()
+            .find(|n| n.has_tag_name((METADATA_NS, "EntityDescriptor")))
+            .ok_or_else(|| config_error("IdP metadata without EntityDescriptor".to_string()))?;
+        let entity_id = entity
+            .attribute("entityID")
+            .ok_or_else(|| config_error("IdP metadata without entityID".to_string()))?;
+        let descriptor = entity
+            .children()
+            .find(|n| n.has_tag_name((METADATA_NS, "IDPSSODescriptor")))
+            .ok_or_else(|| config_error("IdP metadata without IDPSSODescriptor".to_string()))?;
+        let sso_url = descriptor
+            .children()
+            .filter(|n| n.has_tag_name((METADATA_NS, "SingleSignOnService")))
+            .find(|n| n.attribute("Binding") == Some(HTTP_REDIRECT))
+            .and_then(|n| n.attribute("Location"))
+            .ok_or_else(|| config_error("IdP metadata without HTTP-Redirect SSO".to_string()))?;
+        let mut idp = SamlIdp::new(entity_id, sso_url);
+        for key in descriptor
+            .children()
+            .filter(|n| n.has_tag_name((METADATA_NS, "KeyDescriptor")))
+            .filter(|n| n.attribute("use").is_none_or(|u| u == "signing"))
+        {
+            for cert in key
+                .descendants()
+                .filter(|n| n.has_tag_name((DSIG_NS, "X509Certificate")))
+            {
+                let value: String = cert.text().unwrap_or("").split_whitespace().collect();
+                let der = BASE64
+                    .decode(value)
+                    .map_err(|_| config_error("Invalid certificate in IdP metadata".to_string()))?;
+                idp.certificates.push(der);
+            }
+        }
+        if idp.certificates.is_empty() {
+            return Err(config_error(
+                "IdP metadata without signing certificates".to_string(),
+            ));
+        }
+        Ok(idp)
+    }
+}
+
+// Service provider of a virtual host. base_url is the external url of the host (ie:
+// https://app.example.com), the assertion consumer service is served at /saml/acs and the metadata
+// at /saml/metadata
+pub struct SamlServiceProvider {
+    entity_id: String,
+    base_url: String,
+    idp: SamlIdp,
+    session_ttl: Duration,
+    cookie: String,
+    role_attribute: Option<String>,
+}
+
+impl SamlServiceProvider {
+    pub fn new(entity_id: &str, base_url: &str, idp: SamlIdp) -> SamlServiceProvider {
+        SamlServiceProvider {
+            entity_id: entity_id.to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            idp,
+            session_ttl: Duration::from_secs(8 * 3600),
+            cookie: "rhod_saml".to_string(),
+            role_attribute: None,
+        }
+    }
+
+    // Max duration of the sessions, shortened by the SessionNotOnOrAfter of the IdP
+    pub fn with_session_ttl(self, session_ttl: Duration) -> Self {
+        SamlServiceProvider {
+            session_ttl,
+            ..self
+        }
+    }
+
+    pub fn with_cookie(self, cookie: &str) -> Self {
+        SamlServiceProvider {
+            cookie: cookie.to_string(),
+            ..self
+        }
+    }
+
+    // Attribute of the assertion whose values are the roles of the Principal
+    pub fn with_role_attribute(self, attribute: &str) -> Self {
+        SamlServiceProvider {
+            role_attribute: Some(attribute.to_string()),
+            ..self
+        }
+    }
+
+    fn acs_url(&self) -> String {
+        format!("{}{}", self.base_url, ACS_PATH)
+    }
+
+    pub fn metadata(&self) -> String {
+        let mut xml = String::from("<md:EntityDescriptor xmlns:md=\"");
+        xml.push_str(METADATA_NS);
+        xml.push_str("\" entityID=\"");
+        escape_attribute(&self.entity_id, &mut xml);
+        xml.push_str("\"><md:SPSSODescriptor AuthnRequestsSigned=\"false\" WantAssertionsSigned=\"true\" protocolSupportEnumeration=\"");
+        xml.push_str(PROTOCOL_NS);
+        xml.push_str("\"><md:AssertionConsumerService Binding=\"");
+        xml.push_str(HTTP_POST);
+        xml.push_str("\" Location=\"");
+        escape_attribute(&self.acs_url(), &mut xml);
+        xml.push_str(
+            "\" index=\"0\" isDefault=\"true\"/></md:SPSSODescriptor></md:EntityDescriptor>",
+        );
+        xml
+    }
+
+    fn authn_request(&self, id: &str) -> String {
+        let mut xml = String::from("<samlp:AuthnRequest xmlns:samlp=\"");
+        xml.push_str(PROTOCOL_NS);
+        xml.push_str("\" xmlns:saml=\"");
+        xml.push_str(ASSERTION_NS);
+        let _ = write!(
+            xml,
+            "\" ID=\"{}\" Version=\"2.0\" IssueInstant=\"{}\" Destination=\"",
+            id,
+            &iso8601(unix_now() * 1000)[..19]
+        );
+        escape_attribute(&self.idp.sso_url, &mut xml);
+        xml.push_str("\" AssertionConsumerServiceURL=\"");
+        escape_attribute(&self.acs_url(), &mut xml);
+        xml.push_str("\" ProtocolBinding=\"");
+        xml.push_str(HTTP_POST);
+        xml.push_str("\"><saml:Issuer>");
+        escape_attribute(&self.entity_id, &mut xml);
+        xml.push_str(
+            "</saml:Issuer><samlp:NameIDPolicy AllowCreate=\"true\"/></samlp:AuthnRequest>",
+        );
+        xml
+    }
+
+    // Url of the IdP with the AuthnRequest, for the HTTP-Redirect binding
+    fn login_url(&self, id: &str) -> RhodResult<String> {
+        let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflate
+            .write_all(self.authn_request(id).as_bytes())
+            .and_then(|_| deflate.finish())
+            .map(|deflated| {
+                let request = BASE64.encode(deflated);
+                let separator = if self.idp.sso_url.contains('?') {
+                    '&'
+                } else {
+                    '?'
+                };
+                format!(
+                    "{}{}SAMLRequest={}",
+                    self.idp.sso_url,
+                    separator,
+                    utf8_percent_encode(&request, NON_ALPHANUMERIC)
+                )
+            })
+            .map_err(|e| config_error(format!("Couldnt deflate AuthnRequest. {}", e)))
+    }
+
+    fn secure(&self) -> bool {
+        self.base_url.starts_with("https://")
+    }
+
+    fn login_cookie_name(&self) -> String {
+        format!("{}_login", self.cookie)
+    }
+
+    // The IdP posts the response cross-site, so the cookie must be SameSite=None to be sent with it
+    fn login_cookie(&self, value: &str, max_age: u64) -> String {
+        format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly{}",
+            self.login_cookie_name(),
+            value,
+            ACS_PATH,
+            max_age,
+            if self.secure() {
+                "; SameSite=None; Secure"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+// Session established by a SAML login, saved in the request context by SamlHandler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlSession {
+    pub name_id: String,
+    pub session_index: Option<String>,
+    pub attributes: HashMap<String, Vec<String>>,
+    pub expires: u64, // unix secs
+}
+
+// AuthnRequest waiting for the response of the IdP. It is kept by the client in a cookie signed by the
+// handler, so the response is only accepted from the browser that started the login
+struct PendingLogin {
+    id: String, // of the AuthnRequest
+    return_to: String,
+    expires: u64, // unix secs
+}
+
+impl PendingLogin {
+    fn signed(&self, key: &hmac::Key, sp: &SamlServiceProvider) -> String {
+        let value = format!(
+            "{}.{}.{}",
+            self.id,
+            self.expires,
+            BASE64_URL.encode(&self.return_to)
+        );
+        let tag = hmac::sign(key, format!("{}\n{}", sp.entity_id, value).as_bytes());
+        format!("{}.{}", value, BASE64_URL.encode(tag))
+    }
+
+    fn verify(signed: &str, key: &hmac::Key, sp: &SamlServiceProvider) -> Option<PendingLogin> {
+        let (value, tag) = signed.rsplit_once('.')?;
+        let tag = BASE64_URL.decode(tag).ok()?;
+        hmac::verify(key, format!("{}\n{}", sp.entity_id, value).as_bytes(), &tag).ok()?;
+        let mut parts = value.splitn(3, '.');
+        let id = parts.next()?.to_string();
+        let expires = parts.next()?.parse().ok()?;
+        let return_to = String::from_utf8(BASE64_URL.decode(parts.next()?).ok()?).ok()?;
+        Some(PendingLogin {
+            id,
+            return_to,
+            expires,
+        })
+    }
+}
+
+// AuthnRequests already answered, so each response is consumed once. They are kept for PENDING_TTL
+// from their answer, longer than their login lasts, so they expire in the order they were added
+#[derive(Default)]
+struct Answered {
+    ids: HashSet<String>,
+    order: VecDeque<(Instant, String)>, // with the time they can be forgotten
+}
+
+impl Answered {
+    // false if id was already answered
+    fn insert(&mut self, id: &str) -> bool {
+        let now = Instant::now();
+        while self.order.front().is_some_and(|(until, _)| *until <= now) {
+            if let Some((_, expired)) = self.order.pop_front() {
+                self.ids.remove(&expired);
+            }
+        }
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back((now + PENDING_TTL, id.to_string()));
+        true
+    }
+}
+
+// Path the client asked for, sent back as the Location after the login. Only local paths are kept,
+// "//host" and "/\host" would redirect to another site
+fn return_path(req: &RhodRequest) -> String {
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    if path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\") {
+        path.to_string()
+    } else {
+        "/".to_string()
+    }
+}
+
+// SAML 2.0 service provider (SP-initiated, HTTP-Redirect to the IdP, HTTP-POST back) for the hosts it
+// has a SamlServiceProvider for. Requests of other hosts pass through.
+// Requests without a session are redirected to the IdP if they are GET or HEAD, otherwise answered
+// with a 401. After the login, the client is sent back to the path it asked for, with a session
+// cookie. Sessions are kept in memory; the session and a Principal with the NameID are saved in the
+// request context. Pending logins are kept by the clients, in a signed cookie for the ACS path.
+// IdP-initiated logins and encrypted assertions arent supported
+pub struct SamlHandler {
+    providers: HashMap<String, SamlServiceProvider>, // by host
+    default: Option<SamlServiceProvider>,
+    max_sessions: usize,
+    sessions: Mutex<HashMap<String, (String, SamlSession)>>, // by session id, with the SP entity id
+    answered: Mutex<Answered>,
+    login_key: hmac::Key, // signs the pending logins
+    random: SystemRandom,
+}
+
+impl Default for SamlHandler {
+    fn default() -> Self {
+        SamlHandler::new()
+    }
+}
+
+impl SamlHandler {
+    pub fn new() -> SamlHandler {
+        SamlHandler {
+            providers: HashMap::new(),
+            default: None,
+            max_sessions: 100_000,
+            sessions: Mutex::new(HashMap::new()),
+            answered: Mutex::new(Answered::default()),
+            login_key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .expect("the system random generator is available"),
+            random: SystemRandom::new(),
+        }
+    }
+
+    pub fn with_host(mut self, host: &str, provider: SamlServiceProvider) -> Self {
+        self.providers.insert(host.to_ascii_lowercase(), provider);
+        self
+    }
+
+    // Provider of the hosts without one of their own
+    pub fn with_default(self, provider: SamlServiceProvider) -> Self {
+        SamlHandler {
+            default: Some(provider),
+            ..self
+        }
+    }
+
+    // Max number of sessions, new logins are rejected when reached
+    pub fn with_max_sessions(self, max_sessions: usize) -> Self {
+        SamlHandler {
+            max_sessions,
+            ..self
+        }
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    fn provider(&self, req: &RhodRequest) -> Option<&SamlServiceProvider> {
+        let host = req.host().unwrap_or("").to_ascii_lowercase();
+        self.providers.get(&host).or(self.default.as_ref())
+    }
+
+    fn random_id(&self) -> RhodResult<String> {
+        let mut bytes = [0u8; 20];
+        self.random
+            .fill(&mut bytes)
+            .map_err(|_| config_error("Couldnt generate a random id".to_string()))?;
+        Ok(format!("_{}", hex(&bytes)))
+    }
+
+    fn session(&self, sp: &SamlServiceProvider, req: &RhodRequest) -> Option<SamlSession> {
+        let id = req.cookie(&sp.cookie)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some((entity_id, session)) if *entity_id == sp.entity_id => {
+                if session.expires > unix_now() {
+                    return Some(session.clone());
+                }
+                sessions.remove(id);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // Starts the login, redirecting to the IdP
+    fn login(&self, sp: &SamlServiceProvider, req: &RhodRequest) -> RhodResult<RhodResponse> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Err(rejected(StatusCode::UNAUTHORIZED, "Missing SAML session"));
+        }
+        let login = PendingLogin {
+            id: self.random_id()?,
+            return_to: return_path(req),
+            expires: unix_now() + PENDING_TTL.as_secs(),
+        };
+        let cookie = sp.login_cookie(&login.signed(&self.login_key, sp), PENDING_TTL.as_secs());
+        RhodResponse::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION.as_str(), &sp.login_url(&login.id)?)
+            .header(SET_COOKIE.as_str(), &cookie)
+            .header(CACHE_CONTROL.as_str(), "no-store")
+            .build()
+    }
+
+    // Validates the response posted by the IdP, establishing the session
+    async fn consume(
+        &self,
+        sp: &SamlServiceProvider,
+        req: &mut RhodRequest,
+    ) -> RhodResult<RhodResponse> {
+        // bounded read, with or without Content-Length. The body read is kept for body_text
+        if req.body_bytes_limited(MAX_RESPONSE_SIZE).await?.is_none() {
+            return Err(rejected(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "SAML response too large",
+            ));
+        }
+        let form = req.body_text().await?;
+        let encoded = form_value(&form, "SAMLResponse")
+            .ok_or_else(|| rejected(StatusCode::BAD_REQUEST, "Missing SAMLResponse"))?;
+        let xml = BASE64
+            .decode(encoded.split_whitespace().collect::<String>())
+            .ok()
+            .and_then(|xml| String::from_utf8(xml).ok())
+            .ok_or_else(|| rejected(StatusCode::BAD_REQUEST, "Invalid SAMLResponse"))?;
+        let acs_url = sp.acs_url();
+        let expected = Expected {
+            sp_entity_id: &sp.entity_id,
+            acs_url: &acs_url,
+            idp_entity_id: &sp.idp.entity_id,
+            certificates: &sp.idp.certificates,
+            now: unix_now(),
+        };
+        let assertion = assertion::validate(&xml, &expected).map_err(|e| invalid_response(&e))?;
+
+        // the request must be pending for this client, and answered once
+        let login = req
+            .cookie(&sp.login_cookie_name())
+            .and_then(|signed| PendingLogin::verify(signed, &self.login_key, sp));
+        let login = match login {
+            Some(login) if login.id == assertion.in_response_to && login.expires > unix_now() => {
+                login
+            }
+            _ => return Err(invalid_response("Response to an unknown AuthnRequest")),
+        };
+        if !self.answered.lock().unwrap().insert(&login.id) {
+            return Err(invalid_response("Response to an answered AuthnRequest"));
+        }
+        let session_id = self.random_id()?;
+        let session = self.new_session(sp, assertion);
+        let max_age = session.expires.saturating_sub(unix_now());
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.len() >= self.max_sessions {
+                let now = unix_now();
+                sessions.retain(|_, (_, session)| session.expires > now);
+                if sessions.len() >= self.max_sessions {
+                    return Err(rejected(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Too many SAML sessions",
+                    ));
+                }
+            }
+            sessions.insert(session_id.clone(), (sp.entity_id.clone(), session));
+        }
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            sp.cookie,
+            session_id,
+            max_age,
+            if sp.secure() { "; Secure" } else { "" }
+        );
+        RhodResponse::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION.as_str(), &login.return_to)
+            .header(SET_COOKIE.as_str(), &cookie)
+            .header(SET_COOKIE.as_str(), &sp.login_cookie("", 0))
+            .header(CACHE_CONTROL.as_str(), "no-store")
+            .build()
+    }
+
+    fn new_session(&self, sp: &SamlServiceProvider, assertion: Assertion) -> SamlSession {
+        let expires = unix_now() + sp.session_ttl.as_secs();
+        SamlSession {
+            name_id: assertion.name_id,
+            session_index: assertion.session_index,
+            attributes: assertion.attributes,
+            expires: assertion
+                .session_expires
+                .map_or(expires, |idp_expires| idp_expires.min(expires)),
+        }
+    }
+}
+
+// Value of a field of an urlencoded form
+fn form_value(form: &str, name: &str) -> Option<String> {
+    form.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key != name {
+            return None;
+        }
+        percent_decode_str(&value.replace('+', " "))
+            .decode_utf8()
+            .map(|v| v.into_owned())
+            .ok()
+    })
+}
+
+fn rejected(status: StatusCode, msg: &str) -> RhodError {
+    rejected_with_reason(status, msg, msg)
+}
+
+// The reason is only logged, so clients dont learn why their response was rejected
+fn invalid_response(reason: &str) -> RhodError {
+    rejected_with_reason(StatusCode::FORBIDDEN, "Invalid SAML response", reason)
+}
+
+fn rejected_with_reason(status: StatusCode, msg: &str, reason: &str) -> RhodError {
+    let err = RhodError::from_string(format!("SAML. {}", reason), RhodErrorLevel::Warning);
+    err.with_built(RhodResponse::builder().status(status).body_str(msg).build())
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for SamlHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let sp = match self.provider(req) {
+            Some(sp) => sp,
+            None => return Ok(()),
+        };
+        let res = match req.uri().path() {
+            METADATA_PATH => RhodResponse::builder()
+                .header(CONTENT_TYPE.as_str(), "application/samlmetadata+xml")
+                .body_str(&sp.metadata())
+                .build()?,
+            ACS_PATH if req.method() == Method::POST => self.consume(sp, req).await?,
+            _ => match self.session(sp, req) {
+                Some(session) => {
+                    let roles: Vec<&str> = sp
+                        .role_attribute
+                        .as_ref()
+                        .and_then(|name| session.attributes.get(name))
+                        .map_or(vec![], |values| values.iter().map(String::as_str).collect());
+                    req.context()
+                        .insert(Principal::new(&session.name_id).with_roles(&roles));
+                    req.context().insert(session);
+                    return Ok(());
+                }
+                None => self.login(sp, req)?,
+            },
+        };
+        Err(RhodError::from_response(res))
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use ring::digest::{digest, SHA256};
+    use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+    use rustls_pki_types::PrivatePkcs1KeyDer;
+
+    const IDP: &str = "https://idp.example.com";
+
+    fn time(offset: i64) -> String {
+        iso8601((unix_now() as i64 + offset) as u64 * 1000)
+    }
+
+    // Response to the request, with an assertion for the signed NameID signed by the key of the test
+    // certificate, sent with name_id. The assertion is written already canonicalized, so its digest is
+    // the SHA-256 of the string
+    fn signed_response(request_id: &str, signed: &str, name_id: &str) -> String {
+        let ns = "xmlns:saml=\"urn:oasis:names:tc:SAML:2.0:assertion\"";
+        let assertion = |signature: &str| {
+            format!(
+                "<saml:Assertion {ns} ID=\"_a1\" IssueInstant=\"{now}\" Version=\"2.0\">\
+                <saml:Issuer>{idp}</saml:Issuer>{signature}\
+                <saml:Subject><saml:NameID>{name_id}</saml:NameID>\
+                <saml:SubjectConfirmation Method=\"urn:oasis:names:tc:SAML:2.0:cm:bearer\">\
+                <saml:SubjectConfirmationData InResponseTo=\"{id}\" NotOnOrAfter=\"{later}\" \
+                Recipient=\"https://app.example.com/saml/acs\"></saml:SubjectConfirmationData>\
+                </saml:SubjectConfirmation></saml:Subject>\
+                <saml:Conditions NotBefore=\"{before}\" NotOnOrAfter=\"{later}\">\
+                <saml:AudienceRestriction><saml:Audience>https://app.example.com</saml:Audience>\
+                </saml:AudienceRestriction></saml:Conditions>\
+                <saml:AuthnStatement AuthnInstant=\"{now}\" SessionIndex=\"s-1\"></saml:AuthnStatement>\
+                <saml:AttributeStatement><saml:Attribute Name=\"groups\">\
+                <saml:AttributeValue>admins</saml:AttributeValue></saml:Attribute>\
+                </saml:AttributeStatement></saml:Assertion>",
+                ns = ns,
+                now = time(0),
+                before = time(-60),
+                later = time(300),
+                idp = IDP,
+                id = request_id,
+                name_id = signed,
+                signature = signature,
+            )
+        };
+        let digest_value = BASE64.encode(digest(&SHA256, assertion("").as_bytes()));
+        let signed_info = format!(
+            "<ds:SignedInfo xmlns:ds=\"{ds}\">\
+            <ds:CanonicalizationMethod Algorithm=\"{c14n}\"></ds:CanonicalizationMethod>\
+            <ds:SignatureMethod Algorithm=\"http://www.w3.org/2001/04/xmldsig-more#rsa-sha256\">\
+            </ds:SignatureMethod><ds:Reference URI=\"#_a1\"><ds:Transforms>\
+            <ds:Transform Algorithm=\"{ds}enveloped-signature\"></ds:Transform>\
+            <ds:Transform Algorithm=\"{c14n}\"></ds:Transform></ds:Transforms>\
+            <ds:DigestMethod Algorithm=\"http://www.w3.org/2001/04/xmlenc#sha256\"></ds:DigestMethod>\
+            <ds:DigestValue>{digest}</ds:DigestValue></ds:Reference></ds:SignedInfo>",
+            ds = DSIG_NS,
+            c14n = "http://www.w3.org/2001/10/xml-exc-c14n#",
+            digest = digest_value,
+        );
+        let key = PrivatePkcs1KeyDer::from_pem_slice(include_bytes!(
+            "../../tests/assets/certs/server.key"
+        ))
+        .unwrap();
+        let key = RsaKeyPair::from_der(key.secret_pkcs1_der()).unwrap();
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            signed_info.as_bytes(),
+            &mut signature,
+        )
+        .unwrap();
+        let signature = format!(
+            "<ds:Signature xmlns:ds=\"{}\">{}<ds:SignatureValue>{}</ds:SignatureValue></ds:Signature>",
+            DSIG_NS,
+            signed_info,
+            BASE64.encode(signature)
+        );
+        let assertion = assertion(&signature).replace(signed, name_id);
+        format!(
+            "<samlp:Response xmlns:samlp=\"{}\" Destination=\"https://app.example.com/saml/acs\" \
+            ID=\"_r1\" InResponseTo=\"{}\" Version=\"2.0\"><samlp:Status><samlp:StatusCode \
+            Value=\"urn:oasis:names:tc:SAML:2.0:status:Success\"/></samlp:Status>{}</samlp:Response>",
+            PROTOCOL_NS, request_id, assertion
+        )
+    }
+
+    #[tokio::test]
+    async fn test_saml_handler() {
+        let idp = SamlIdp::new(IDP, "https://idp.example.com/sso")
+            .with_certificate_pem(include_bytes!("../../tests/assets/certs/server.crt"))
+            .unwrap();
+        let sp =
+            SamlServiceProvider::new("https://app.example.com", "https://app.example.com", idp)
+                .with_role_attribute("groups");
+        let handler = SamlHandler::new().with_host("app.example.com", sp);
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let handle = |method: Method, uri: &str, cookie: &str, body: &str| {
+            let mut req = RhodRequest::builder()
+                .method(method)
+                .uri(uri)
+                .header("host", "app.example.com")
+                .header("cookie", cookie)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body_str(body)
+                .build()
+                .unwrap();
+            let (handler, conn) = (&handler, &conn);
+            async move {
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ())
+                    .await
+                    .map(|_| req)
+                    .map_err(|mut e| e.take_response().unwrap())
+            }
+        };
+
+        let res = handle(Method::GET, "/saml/metadata", "", "")
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 200);
+        let res = handle(Method::POST, "/orders", "", "").await.unwrap_err();
+        assert_eq!(res.status_as_int(), 401);
+
+        // login: redirected to the IdP, which posts back the response
+        let res = handle(Method::GET, "/orders?page=2", "", "")
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 302);
+        assert!(res.headers()[LOCATION]
+            .to_str()
+            .unwrap()
+            .starts_with("https://idp.example.com/sso?SAMLRequest="));
+        // the pending login is kept by the client, with the id of the AuthnRequest
+        let pending = |res: &RhodResponse| {
+            let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+            assert!(cookie.contains("; Path=/saml/acs;"));
+            let cookie = cookie.split(';').next().unwrap().to_string();
+            let id = cookie["rhod_saml_login=".len()..]
+                .split('.')
+                .next()
+                .unwrap()
+                .to_string();
+            (cookie, id)
+        };
+        let (login, id) = pending(&res);
+        let post = |id: &str, signed: &str, name_id: &str| {
+            let response = BASE64.encode(signed_response(id, signed, name_id));
+            format!(
+                "SAMLResponse={}",
+                utf8_percent_encode(&response, NON_ALPHANUMERIC)
+            )
+        };
+        let tampered = post(&id, "alice@example.com", "admin@example.com");
+        let res = handle(Method::POST, ACS_PATH, &login, &tampered)
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 403);
+        let valid = post(&id, "alice@example.com", "alice@example.com");
+        // only the client that started the login can end it
+        let res = handle(Method::POST, ACS_PATH, "", &valid)
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 403);
+        let mut parts: Vec<String> = login.split('.').map(String::from).collect();
+        parts[2] = BASE64_URL.encode("https://evil.example");
+        let forged = parts.join(".");
+        let res = handle(Method::POST, ACS_PATH, &forged, &valid)
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 403);
+        let res = handle(Method::POST, ACS_PATH, &login, &valid)
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 303);
+        assert_eq!(res.headers()[LOCATION], "/orders?page=2");
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(cookie.ends_with("; HttpOnly; SameSite=Lax; Secure"));
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        // responses are consumed once
+        let res = handle(Method::POST, ACS_PATH, &login, &valid)
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 403);
+
+        let req = handle(Method::POST, "/orders", &cookie, "").await.unwrap();
+        let principal = req.context().get::<Principal>().unwrap();
+        assert_eq!(principal.id, "alice@example.com");
+        assert!(principal.has_role("admins"));
+        let session = req.context().get::<SamlSession>().unwrap();
+        assert_eq!(session.session_index.as_deref(), Some("s-1"));
+        assert_eq!(handler.sessions(), 1);
+
+        // a comment in the signed NameID doesnt truncate it. Paths of other sites arent returned to
+        let res = handle(Method::GET, "//evil.example/x", "", "")
+            .await
+            .unwrap_err();
+        let (login, id) = pending(&res);
+        let split = post(
+            &id,
+            "admin@example.com.evil.com",
+            "admin@example.com<!---->.evil.com",
+        );
+        let res = handle(Method::POST, ACS_PATH, &login, &split)
+            .await
+            .unwrap_err();
+        assert_eq!(res.status_as_int(), 303);
+        assert_eq!(res.headers()[LOCATION], "/");
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let req = handle(Method::POST, "/orders", &cookie, "").await.unwrap();
+        let principal = req.context().get::<Principal>().unwrap();
+        assert_eq!(principal.id, "admin@example.com.evil.com");
+
+        // larger responses are refused, with or without Content-Length
+        let chunks = vec![Ok::<_, std::convert::Infallible>(vec![b'a'; 64 * 1024]); 20];
+        let mut req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri(ACS_PATH)
+            .header("host", "app.example.com")
+            .build()
+            .unwrap();
+        req.set_body(crate::body::RhodBody::wrap_stream(
+            futures_util::stream::iter(chunks),
+        ));
+        let mut err = RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 413);
+    }
+}
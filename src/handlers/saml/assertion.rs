@@ -0,0 +1,263 @@
+// Validation of the SAML Responses posted to the assertion consumer service. The Response or its
+// Assertion must be signed by the IdP, and only the signed element is read, so unsigned assertions
+// wrapped around it are ignored. Encrypted assertions arent supported.
+use super::dsig::{self, DSIG_NS};
+use crate::log_sink::days_from_civil;
+use roxmltree::{Document, Node, ParsingOptions};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+pub(super) const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+pub(super) const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+
+// Allowed difference between the clocks of the IdP and the SP
+const CLOCK_SKEW: u64 = 120;
+
+// What the SP expects of a Response
+pub(super) struct Expected<'a> {
+    pub sp_entity_id: &'a str,
+    pub acs_url: &'a str,
+    pub idp_entity_id: &'a str,
+    pub certificates: &'a [Vec<u8>],
+    pub now: u64, // unix secs
+}
+
+// Assertion of a valid Response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Assertion {
+    pub in_response_to: String,
+    pub name_id: String,
+    pub session_index: Option<String>,
+    pub session_expires: Option<u64>, // SessionNotOnOrAfter, unix secs
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+// xs:dateTime (ie: 2024-05-01T10:00:00Z or 2024-05-01T10:00:00.123+02:00) as unix secs
+pub(super) fn parse_datetime(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: i64 = date.next()?.parse().ok()?;
+    let day: i64 = date.next()?.parse().ok()?;
+    let (time, offset) = match time.find(['Z', '+', '-']) {
+        Some(i) => time.split_at(i),
+        None => (time, "Z"),
+    };
+    let mut clock = time.splitn(3, ':');
+    let hours: i64 = clock.next()?.parse().ok()?;
+    let minutes: i64 = clock.next()?.parse().ok()?;
+    let seconds: f64 = clock.next()?.parse().ok()?;
+    let offset = match offset {
+        "Z" => 0,
+        offset => {
+            let (hh, mm) = offset[1..].split_once(':')?;
+            let secs = hh.parse::<i64>().ok()? * 3600 + mm.parse::<i64>().ok()? * 60;
+            if offset.starts_with('-') {
+                -secs
+            } else {
+                secs
+            }
+        }
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds as i64
+            - offset;
+    u64::try_from(secs).ok()
+}
+
+fn element<'a, 'input>(node: Node<'a, 'input>, ns: &str, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|c| c.is_element() && c.has_tag_name((ns, name)))
+}
+
+fn elements<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    ns: &'a str,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |c| c.is_element() && c.has_tag_name((ns, name)))
+}
+
+// All the text of the element: comments arent part of the signed content, so the text nodes around
+// them are read together (ie: a NameID split by a comment isnt truncated)
+fn text(node: Node) -> String {
+    let text: String = node
+        .descendants()
+        .filter(|n| n.is_text())
+        .filter_map(|n| n.text())
+        .collect();
+    text.trim().to_string()
+}
+
+fn time(node: Node, name: &str) -> Result<Option<u64>, String> {
+    match node.attribute(name) {
+        Some(value) => parse_datetime(value)
+            .map(Some)
+            .ok_or(format!("Invalid {} {}", name, value)),
+        None => Ok(None),
+    }
+}
+
+// Checks the signatures of the document: IDs must be unique, so a signed ID cant point to another element
+fn unique_ids(document: &Document) -> Result<(), String> {
+    let mut ids: Vec<&str> = document
+        .descendants()
+        .filter_map(|n| n.attribute("ID"))
+        .collect();
+    let count = ids.len();
+    ids.sort_unstable();
+    ids.dedup();
+    match ids.len() == count {
+        true => Ok(()),
+        false => Err("Duplicated IDs in the response".to_string()),
+    }
+}
+
+pub(super) fn validate(xml: &str, expected: &Expected) -> Result<Assertion, String> {
+    // DTDs are rejected (no entity expansion)
+    let options = ParsingOptions {
+        allow_dtd: false,
+        ..ParsingOptions::default()
+    };
+    let document = Document::parse_with_options(xml, options)
+        .map_err(|e| format!("Invalid response XML. {}", e))?;
+    unique_ids(&document)?;
+    let response = document.root_element();
+    if !response.has_tag_name((PROTOCOL_NS, "Response")) {
+        return Err("Not a SAML Response".to_string());
+    }
+    let status = element(response, PROTOCOL_NS, "Status")
+        .and_then(|s| element(s, PROTOCOL_NS, "StatusCode"))
+        .and_then(|s| s.attribute("Value"));
+    if status != Some(SUCCESS) {
+        return Err(format!("Response status {}", status.unwrap_or("missing")));
+    }
+    if let Some(destination) = response.attribute("Destination") {
+        if destination != expected.acs_url {
+            return Err(format!("Response destination {}", destination));
+        }
+    }
+    if element(response, ASSERTION_NS, "EncryptedAssertion").is_some() {
+        return Err("Encrypted assertions arent supported".to_string());
+    }
+    let assertion = match elements(response, ASSERTION_NS, "Assertion")
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [assertion] => *assertion,
+        _ => return Err("Responses must have one assertion".to_string()),
+    };
+    // the assertion is signed itself, or through the response
+    let signed = match element(assertion, DSIG_NS, "Signature") {
+        Some(_) => assertion,
+        None => response,
+    };
+    dsig::verify(signed, expected.certificates)?;
+    if signed == response {
+        if let Some(issuer) = element(response, ASSERTION_NS, "Issuer") {
+            if text(issuer) != expected.idp_entity_id {
+                return Err(format!("Response issuer {}", text(issuer)));
+            }
+        }
+    }
+    let in_response_to = response
+        .attribute("InResponseTo")
+        .ok_or("Unsolicited response")?
+        .to_string();
+    read_assertion(assertion, in_response_to, expected)
+}
+
+fn read_assertion(
+    assertion: Node,
+    in_response_to: String,
+    expected: &Expected,
+) -> Result<Assertion, String> {
+    let now = expected.now;
+    let issuer = element(assertion, ASSERTION_NS, "Issuer").map(text);
+    if issuer.as_deref() != Some(expected.idp_entity_id) {
+        return Err(format!(
+            "Assertion issuer {}",
+            issuer.unwrap_or_else(|| "missing".to_string())
+        ));
+    }
+
+    let subject = element(assertion, ASSERTION_NS, "Subject").ok_or("Assertion without subject")?;
+    let name_id = element(subject, ASSERTION_NS, "NameID")
+        .map(text)
+        .filter(|name_id| !name_id.is_empty())
+        .ok_or("Assertion without NameID")?;
+    let confirmed = elements(subject, ASSERTION_NS, "SubjectConfirmation")
+        .filter(|c| c.attribute("Method") == Some(BEARER))
+        .filter_map(|c| element(c, ASSERTION_NS, "SubjectConfirmationData"))
+        .any(|data| {
+            data.attribute("Recipient") == Some(expected.acs_url)
+                && data
+                    .attribute("InResponseTo")
+                    .is_none_or(|id| id == in_response_to)
+                && matches!(time(data, "NotOnOrAfter"), Ok(Some(t)) if now < t + CLOCK_SKEW)
+        });
+    if !confirmed {
+        return Err("Assertion without a valid bearer confirmation".to_string());
+    }
+
+    let conditions =
+        element(assertion, ASSERTION_NS, "Conditions").ok_or("Assertion without conditions")?;
+    if let Some(not_before) = time(conditions, "NotBefore")? {
+        if now + CLOCK_SKEW < not_before {
+            return Err("Assertion not valid yet".to_string());
+        }
+    }
+    if let Some(not_on_or_after) = time(conditions, "NotOnOrAfter")? {
+        if now >= not_on_or_after + CLOCK_SKEW {
+            return Err("Assertion expired".to_string());
+        }
+    }
+    // every audience restriction must include the SP
+    let mut restrictions = elements(conditions, ASSERTION_NS, "AudienceRestriction").peekable();
+    if restrictions.peek().is_none() {
+        return Err("Assertion without audience".to_string());
+    }
+    for restriction in restrictions {
+        if !elements(restriction, ASSERTION_NS, "Audience")
+            .any(|a| text(a) == expected.sp_entity_id)
+        {
+            return Err("Assertion for another audience".to_string());
+        }
+    }
+
+    let statement = element(assertion, ASSERTION_NS, "AuthnStatement");
+    let session_index = statement
+        .and_then(|s| s.attribute("SessionIndex"))
+        .map(String::from);
+    let session_expires = match statement {
+        Some(statement) => time(statement, "SessionNotOnOrAfter")?,
+        None => None,
+    };
+    let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+    for statement in elements(assertion, ASSERTION_NS, "AttributeStatement") {
+        for attribute in elements(statement, ASSERTION_NS, "Attribute") {
+            let name = match attribute.attribute("Name") {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            attributes
+                .entry(name)
+                .or_default()
+                .extend(elements(attribute, ASSERTION_NS, "AttributeValue").map(text));
+        }
+    }
+    Ok(Assertion {
+        in_response_to,
+        name_id,
+        session_index,
+        session_expires,
+        attributes,
+    })
+}
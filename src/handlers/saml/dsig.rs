@@ -0,0 +1,261 @@
+// XML signatures (XML-DSig) of SAML messages, limited to what SAML IdPs use: one enveloped signature per
+// element, referencing it by ID, with exclusive canonicalization (exc-c14n, without comments), SHA-256
+// digests and RSA SHA-256/SHA-512 signatures. Anything else is rejected.
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use roxmltree::{Node, NodeId, NodeType};
+use rustls_pki_types::CertificateDer;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use webpki::EndEntityCert;
+
+pub(super) const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const SHA256_DIGEST: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const RSA_SHA512: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512";
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|c| c.is_element() && c.has_tag_name((DSIG_NS, name)))
+}
+
+fn algorithm<'a>(node: Node<'a, '_>) -> Option<&'a str> {
+    node.attribute("Algorithm")
+}
+
+// Prefixes of the InclusiveNamespaces of a transform, #default standing for the default namespace
+fn inclusive_prefixes(node: Node) -> Vec<String> {
+    node.children()
+        .find(|c| c.is_element() && c.has_tag_name((EXC_C14N, "InclusiveNamespaces")))
+        .and_then(|c| c.attribute("PrefixList"))
+        .map_or(vec![], |list| {
+            list.split_whitespace().map(String::from).collect()
+        })
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, String> {
+    let value: String = value.split_whitespace().collect();
+    BASE64
+        .decode(value)
+        .map_err(|_| "Invalid base64 in signature".to_string())
+}
+
+// Checks the enveloped signature of the element against the certificates (DER), failing if it has none
+pub(super) fn verify(element: Node, certificates: &[Vec<u8>]) -> Result<(), String> {
+    let signature = child(element, "Signature").ok_or("Element not signed")?;
+    let signed_info = child(signature, "SignedInfo").ok_or("Signature without SignedInfo")?;
+    let c14n = child(signed_info, "CanonicalizationMethod")
+        .ok_or("Signature without CanonicalizationMethod")?;
+    if algorithm(c14n) != Some(EXC_C14N) {
+        return Err("Unsupported canonicalization".to_string());
+    }
+    let method = child(signed_info, "SignatureMethod")
+        .and_then(algorithm)
+        .ok_or("Signature without SignatureMethod")?;
+    let references: Vec<Node> = signed_info
+        .children()
+        .filter(|c| c.is_element() && c.has_tag_name((DSIG_NS, "Reference")))
+        .collect();
+    let reference = match references.as_slice() {
+        [reference] => *reference,
+        _ => return Err("Signatures must have one reference".to_string()),
+    };
+
+    // the reference must be the signed element, so other elements cant be slipped in as signed
+    let id = element.attribute("ID").ok_or("Signed element without ID")?;
+    if reference.attribute("URI") != Some(&format!("#{}", id)) {
+        return Err("Signature reference isnt the signed element".to_string());
+    }
+    let mut inclusive = vec![];
+    if let Some(transforms) = child(reference, "Transforms") {
+        for transform in transforms.children().filter(|c| c.is_element()) {
+            match algorithm(transform) {
+                Some(ENVELOPED) => (),
+                Some(EXC_C14N) => inclusive = inclusive_prefixes(transform),
+                _ => return Err("Unsupported signature transform".to_string()),
+            }
+        }
+    }
+    if child(reference, "DigestMethod").and_then(algorithm) != Some(SHA256_DIGEST) {
+        return Err("Unsupported digest method".to_string());
+    }
+    let expected = child(reference, "DigestValue")
+        .and_then(|n| n.text())
+        .ok_or("Reference without DigestValue")?;
+    let canonical = canonicalize(element, Some(signature.id()), &inclusive);
+    if digest(&SHA256, canonical.as_bytes()).as_ref() != decode(expected)?.as_slice() {
+        return Err("Digest of the signed element doesnt match".to_string());
+    }
+
+    let value = child(signature, "SignatureValue")
+        .and_then(|n| n.text())
+        .ok_or("Signature without SignatureValue")?;
+    let value = decode(value)?;
+    let signed = canonicalize(signed_info, None, &inclusive_prefixes(c14n));
+    let algorithm = match method {
+        RSA_SHA256 => webpki::ring::RSA_PKCS1_2048_8192_SHA256,
+        RSA_SHA512 => webpki::ring::RSA_PKCS1_2048_8192_SHA512,
+        _ => return Err(format!("Unsupported signature method {}", method)),
+    };
+    for certificate in certificates {
+        let der = CertificateDer::from(certificate.as_slice());
+        let cert = match EndEntityCert::try_from(&der) {
+            Ok(cert) => cert,
+            Err(_) => continue,
+        };
+        if cert
+            .verify_signature(algorithm, signed.as_bytes(), &value)
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+    Err("Invalid signature".to_string())
+}
+
+// Qualified name of an element as written in the document
+fn qname<'a>(node: Node<'_, 'a>) -> &'a str {
+    let text = &node.document().input_text()[node.range()];
+    let end = text[1..]
+        .find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+        .map_or(text.len(), |end| end + 1);
+    &text[1..end]
+}
+
+fn prefix(qname: &str) -> Option<&str> {
+    qname.split_once(':').map(|(prefix, _)| prefix)
+}
+
+fn escape_text(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+pub(super) fn escape_attribute(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+// Exclusive XML canonicalization (without comments) of the element, leaving out the excluded node.
+// Namespaces are rendered where they are visibly used (or listed as inclusive) and werent rendered by
+// an output ancestor with the same value. Keys of the rendered namespaces are the prefixes, "" for the
+// default one
+pub(super) fn canonicalize(element: Node, exclude: Option<NodeId>, inclusive: &[String]) -> String {
+    let mut out = String::new();
+    write_element(element, exclude, inclusive, &BTreeMap::new(), &mut out);
+    out
+}
+
+fn write_element(
+    node: Node,
+    exclude: Option<NodeId>,
+    inclusive: &[String],
+    rendered: &BTreeMap<String, String>,
+    out: &mut String,
+) {
+    let name = qname(node);
+    let document = node.document();
+    let attributes: Vec<(&str, roxmltree::Attribute)> = node
+        .attributes()
+        .map(|a| (&document.input_text()[a.range_qname()], a))
+        .collect();
+
+    let mut used: Vec<&str> = vec![prefix(name).unwrap_or("")];
+    used.extend(attributes.iter().filter_map(|(qname, _)| prefix(qname)));
+    for prefix in inclusive {
+        match prefix.as_str() {
+            "#default" => used.push(""),
+            prefix => used.push(prefix),
+        }
+    }
+    let mut declared = rendered.clone();
+    let mut namespaces: BTreeMap<&str, &str> = BTreeMap::new();
+    for prefix in used {
+        if prefix == "xml" {
+            continue;
+        }
+        let uri = match prefix {
+            "" => node.default_namespace().unwrap_or(""),
+            prefix => match node.lookup_namespace_uri(Some(prefix)) {
+                Some(uri) => uri,
+                None => continue,
+            },
+        };
+        let current = rendered.get(prefix).map_or("", String::as_str);
+        if current != uri {
+            namespaces.insert(prefix, uri);
+            declared.insert(prefix.to_string(), uri.to_string());
+        }
+    }
+
+    out.push('<');
+    out.push_str(name);
+    for (prefix, uri) in namespaces {
+        match prefix {
+            "" => out.push_str(" xmlns=\""),
+            prefix => {
+                out.push_str(" xmlns:");
+                out.push_str(prefix);
+                out.push_str("=\"");
+            }
+        }
+        escape_attribute(uri, out);
+        out.push('"');
+    }
+    let mut attributes = attributes;
+    attributes.sort_by(|(_, a), (_, b)| {
+        (a.namespace().unwrap_or(""), a.name()).cmp(&(b.namespace().unwrap_or(""), b.name()))
+    });
+    for (qname, attribute) in attributes {
+        out.push(' ');
+        out.push_str(qname);
+        out.push_str("=\"");
+        escape_attribute(attribute.value(), out);
+        out.push('"');
+    }
+    out.push('>');
+
+    for child in node.children() {
+        if Some(child.id()) == exclude {
+            continue;
+        }
+        match child.node_type() {
+            NodeType::Element => write_element(child, exclude, inclusive, &declared, out),
+            NodeType::Text => escape_text(child.text().unwrap_or(""), out),
+            NodeType::PI => {
+                if let Some(pi) = child.pi() {
+                    out.push_str("<?");
+                    out.push_str(pi.target);
+                    if let Some(value) = pi.value {
+                        out.push(' ');
+                        out.push_str(value);
+                    }
+                    out.push_str("?>");
+                }
+            }
+            NodeType::Comment | NodeType::Root => (),
+        }
+    }
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
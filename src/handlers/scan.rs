@@ -0,0 +1,240 @@
+use super::body_rewrite::fix_length_headers;
+use crate::body::{BodyScanner, ScanAction, ScanVerdict};
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::HeaderMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Creates the scanner of a body given its headers, or None to let it through unscanned
+// (ie: by content type)
+pub type ScannerFactory = Box<dyn Fn(&HeaderMap) -> Option<Box<dyn BodyScanner>> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    pub scanned: u64,
+    pub blocked: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    scanned: AtomicU64,
+    blocked: AtomicU64,
+}
+
+// Counts the blocked verdicts of a scanner
+struct CountedScanner {
+    inner: Box<dyn BodyScanner>,
+    counters: Arc<Counters>,
+}
+
+impl CountedScanner {
+    fn count(&self, verdict: ScanVerdict) -> ScanVerdict {
+        if let ScanVerdict::Blocked(_) = verdict {
+            self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        verdict
+    }
+}
+
+#[async_trait]
+impl BodyScanner for CountedScanner {
+    async fn feed(&mut self, chunk: &Bytes) -> ScanVerdict {
+        let verdict = self.inner.feed(chunk).await;
+        self.count(verdict)
+    }
+
+    async fn finish(&mut self) -> ScanVerdict {
+        let verdict = self.inner.finish().await;
+        self.count(verdict)
+    }
+}
+
+// Attaches BodyScanners (antivirus, DLP) to request and/or response bodies, which are scanned as they
+// are streamed. Blocked bodies get the action: aborted (default), replaced or only logged. Replacing
+// bodies drops their Content-Length, as the replacement length isnt known in advance.
+pub struct ScanHandler {
+    request: Option<ScannerFactory>,
+    response: Option<ScannerFactory>,
+    action: ScanAction,
+    counters: Arc<Counters>,
+}
+
+impl ScanHandler {
+    pub fn new() -> ScanHandler {
+        ScanHandler {
+            request: None,
+            response: None,
+            action: ScanAction::Abort,
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    pub fn on_request(self, factory: ScannerFactory) -> Self {
+        ScanHandler {
+            request: Some(factory),
+            ..self
+        }
+    }
+
+    pub fn on_response(self, factory: ScannerFactory) -> Self {
+        ScanHandler {
+            response: Some(factory),
+            ..self
+        }
+    }
+
+    pub fn with_action(self, action: ScanAction) -> Self {
+        ScanHandler { action, ..self }
+    }
+
+    pub fn stats(&self) -> ScanStats {
+        ScanStats {
+            scanned: self.counters.scanned.load(Ordering::Relaxed),
+            blocked: self.counters.blocked.load(Ordering::Relaxed),
+        }
+    }
+
+    fn scanner(
+        &self,
+        factory: &Option<ScannerFactory>,
+        headers: &mut HeaderMap,
+    ) -> Option<Box<dyn BodyScanner>> {
+        let scanner = factory.as_ref()?(headers)?;
+        self.counters.scanned.fetch_add(1, Ordering::Relaxed);
+        if let ScanAction::Replace(_) = self.action {
+            fix_length_headers(headers, None);
+        }
+        Some(Box::new(CountedScanner {
+            inner: scanner,
+            counters: Arc::clone(&self.counters),
+        }))
+    }
+}
+
+impl Default for ScanHandler {
+    fn default() -> ScanHandler {
+        ScanHandler::new()
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for ScanHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        if let Some(scanner) = self.scanner(&self.request, req.headers_mut()) {
+            let body = req.take_body();
+            req.set_body(body.scan(scanner, self.action.clone()));
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if let Some(scanner) = self.scanner(&self.response, res.headers_mut()) {
+            let body = res.take_body();
+            res.set_body(body.scan(scanner, self.action.clone()));
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    struct Eicar;
+
+    #[async_trait]
+    impl BodyScanner for Eicar {
+        async fn feed(&mut self, chunk: &Bytes) -> ScanVerdict {
+            if chunk.starts_with(b"X5O!") {
+                ScanVerdict::Blocked("EICAR test file".to_string())
+            } else {
+                ScanVerdict::Clean
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_handler() {
+        let handler = ScanHandler::new()
+            .on_response(Box::new(|headers| {
+                match headers.get("content-type").map(|v| v.as_bytes()) {
+                    Some(b"application/octet-stream") => {
+                        Some(Box::new(Eicar) as Box<dyn BodyScanner>)
+                    }
+                    _ => None,
+                }
+            }))
+            .with_action(ScanAction::Replace(Bytes::from("blocked")));
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let respond = |content_type: &str| {
+            RhodResponse::builder()
+                .header("content-type", content_type)
+                .header("content-length", "8")
+                .body_str("X5O!P%@A")
+                .build()
+                .unwrap()
+        };
+
+        let (mut res, _) = RhodHandler::<()>::handle_response(
+            &handler,
+            &conn,
+            respond("application/octet-stream"),
+            &mut (),
+        )
+        .await;
+        assert!(res.headers().get("content-length").is_none());
+        assert_eq!(res.body().await.unwrap(), b"blocked");
+
+        // not scanned
+        let (mut res, _) =
+            RhodHandler::<()>::handle_response(&handler, &conn, respond("text/plain"), &mut ())
+                .await;
+        assert_eq!(res.body().await.unwrap(), b"X5O!P%@A");
+        assert_eq!(
+            handler.stats(),
+            ScanStats {
+                scanned: 1,
+                blocked: 1
+            }
+        );
+    }
+}
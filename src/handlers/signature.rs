@@ -0,0 +1,373 @@
+use super::Principal;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::util::{hex, unix_now};
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use base64::Engine;
+use http::header::{HeaderName, HeaderValue};
+use http::StatusCode;
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use std::fmt::Write;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureEncoding {
+    Hex,
+    Base64,
+}
+
+// Headers carrying the signature, and how it is computed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureScheme {
+    pub signature_header: String,
+    pub signature_prefix: String, // before the encoded signature, ie: "sha256="
+    pub timestamp_header: String, // unix time in seconds
+    pub key_id_header: Option<String>, // without it, every key is tried (ie: while rotating them)
+    pub signed_headers: Vec<String>,
+    pub algorithm: SignatureAlgorithm,
+    pub encoding: SignatureEncoding,
+}
+
+impl Default for SignatureScheme {
+    fn default() -> Self {
+        SignatureScheme {
+            signature_header: "x-signature".to_string(),
+            signature_prefix: String::new(),
+            timestamp_header: "x-signature-timestamp".to_string(),
+            key_id_header: Some("x-signature-key".to_string()),
+            signed_headers: vec!["host".to_string(), "content-type".to_string()],
+            algorithm: SignatureAlgorithm::HmacSha256,
+            encoding: SignatureEncoding::Hex,
+        }
+    }
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn unauthorized(msg: &str) -> RhodError {
+    RhodError::with_status(
+        StatusCode::UNAUTHORIZED,
+        format!("Invalid request signature. {}", msg),
+        RhodErrorLevel::Warning,
+    )
+}
+
+// Verifies requests signed with a shared key (webhooks, machine-to-machine APIs), answering 401 when the
+// signature is missing or doesnt match, or its timestamp is out of the allowed skew. The signature is the
+// HMAC of the canonical request, made of these lines:
+//
+//  METHOD
+//  /path
+//  query parameters sorted
+//  name:value of every signed header (lowercase name, trimmed value, empty if missing), in order
+//  timestamp
+//  hex SHA-256 of the body
+//
+// Bodies larger than max_body_size are answered with 413 without reading them whole.
+// The key id of a valid request is saved as the Principal in the context. sign adds the headers to a
+// request sent to a server verifying them.
+pub struct SignatureHandler {
+    scheme: SignatureScheme,
+    keys: Vec<(String, hmac::Key)>,
+    max_skew: Duration,
+    max_body_size: usize, // larger bodies are answered with 413
+}
+
+impl SignatureHandler {
+    pub fn new(scheme: SignatureScheme) -> SignatureHandler {
+        SignatureHandler {
+            scheme,
+            keys: vec![],
+            max_skew: Duration::from_secs(300),
+            max_body_size: 1 << 20,
+        }
+    }
+
+    pub fn with_key(mut self, key_id: &str, secret: &[u8]) -> Self {
+        let algorithm = match self.scheme.algorithm {
+            SignatureAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            SignatureAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        };
+        self.keys
+            .push((key_id.to_string(), hmac::Key::new(algorithm, secret)));
+        self
+    }
+
+    // Difference allowed between the timestamp of a request and the clock of the server
+    pub fn with_max_skew(self, max_skew: Duration) -> Self {
+        SignatureHandler { max_skew, ..self }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+        SignatureHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    async fn canonical_request(
+        &self,
+        req: &mut RhodRequest,
+        timestamp: &str,
+    ) -> RhodResult<String> {
+        let body_hash = hex(digest(&SHA256, &req.body_bytes().await?).as_ref());
+        let mut query: Vec<&str> = req
+            .uri()
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .collect();
+        query.sort_unstable();
+        let mut canonical = format!(
+            "{}\n{}\n{}\n",
+            req.method_str(),
+            req.uri().path(),
+            query.join("&")
+        );
+        for name in &self.scheme.signed_headers {
+            let value = req.header_str(name.as_str()).unwrap_or("").trim();
+            let _ = writeln!(canonical, "{}:{}", name.to_lowercase(), value);
+        }
+        let _ = write!(canonical, "{}\n{}", timestamp, body_hash);
+        Ok(canonical)
+    }
+
+    fn decode(&self, signature: &str) -> Option<Vec<u8>> {
+        let signature = signature.strip_prefix(self.scheme.signature_prefix.as_str())?;
+        match self.scheme.encoding {
+            SignatureEncoding::Hex => unhex(&signature.to_ascii_lowercase()),
+            SignatureEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(signature)
+                .ok(),
+        }
+    }
+
+    fn encode(&self, tag: &[u8]) -> String {
+        let encoded = match self.scheme.encoding {
+            SignatureEncoding::Hex => hex(tag),
+            SignatureEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(tag),
+        };
+        format!("{}{}", self.scheme.signature_prefix, encoded)
+    }
+
+    // Signs the request with the key, setting the timestamp, key id and signature headers
+    pub async fn sign(&self, req: &mut RhodRequest, key_id: &str) -> RhodResult<()> {
+        let key = match self.keys.iter().find(|(id, _)| id == key_id) {
+            Some((_, key)) => key,
+            None => {
+                return Err(RhodError::from_string(
+                    format!("Unknown signing key {}", key_id),
+                    RhodErrorLevel::Error,
+                ))
+            }
+        };
+        let timestamp = unix_now().to_string();
+        let canonical = self.canonical_request(req, &timestamp).await?;
+        let signature = self.encode(hmac::sign(key, canonical.as_bytes()).as_ref());
+        let invalid = || RhodError::from_str("Invalid signature header", RhodErrorLevel::Error);
+        let key_id_header = self.scheme.key_id_header.as_ref();
+        let headers = vec![
+            Some((&self.scheme.timestamp_header, timestamp)),
+            key_id_header.map(|name| (name, key_id.to_string())),
+            Some((&self.scheme.signature_header, signature)),
+        ];
+        for (name, value) in headers.into_iter().flatten() {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+            let value = HeaderValue::from_str(&value).map_err(|_| invalid())?;
+            req.headers_mut().insert(name, value);
+        }
+        Ok(())
+    }
+
+    // Key id of the valid signature of the request
+    async fn verify(&self, req: &mut RhodRequest) -> Result<String, String> {
+        let signature = req
+            .header_str(self.scheme.signature_header.as_str())
+            .ok_or("Missing signature")?;
+        let signature = self.decode(signature.trim()).ok_or("Malformed signature")?;
+        let timestamp = req
+            .header_str(self.scheme.timestamp_header.as_str())
+            .ok_or("Missing timestamp")?
+            .trim()
+            .to_string();
+        let signed_at = timestamp
+            .parse::<u64>()
+            .map_err(|_| "Malformed timestamp")?;
+        if unix_now().abs_diff(signed_at) > self.max_skew.as_secs() {
+            return Err(format!("Timestamp {} out of the allowed skew", signed_at));
+        }
+        let key_id = match &self.scheme.key_id_header {
+            Some(name) => Some(
+                req.header_str(name.as_str())
+                    .ok_or("Missing key id")?
+                    .to_string(),
+            ),
+            None => None,
+        };
+
+        let canonical = self
+            .canonical_request(req, &timestamp)
+            .await
+            .map_err(|e| format!("Couldnt read the body. {}", e))?;
+        self.keys
+            .iter()
+            .filter(|(id, _)| key_id.as_ref().is_none_or(|key_id| key_id == id))
+            .find(|(_, key)| hmac::verify(key, canonical.as_bytes(), &signature).is_ok())
+            .map(|(id, _)| id.clone())
+            .ok_or_else(|| "Signature mismatch".to_string())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for SignatureHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        // bounded read, the body is kept for the canonical request
+        if req.body_bytes_limited(self.max_body_size).await?.is_none() {
+            return Err(RhodError::with_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Signed request body too large from {}", conn.addr),
+                RhodErrorLevel::Warning,
+            ));
+        }
+        match self.verify(req).await {
+            Ok(key_id) => {
+                req.context().insert(Principal::new(&key_id));
+                Ok(())
+            }
+            Err(reason) => Err(unauthorized(&format!("{} from {}", reason, conn.addr))),
+        }
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use http::Method;
+
+    fn request(body: &str) -> RhodRequest {
+        RhodRequest::builder()
+            .method(Method::POST)
+            .uri("/hooks?b=2&a=1")
+            .header("host", "api.example.com")
+            .header("content-type", "application/json")
+            .body_str(body)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_signature() {
+        let scheme = SignatureScheme {
+            signature_prefix: "sha256=".to_string(),
+            ..SignatureScheme::default()
+        };
+        let handler = SignatureHandler::new(scheme)
+            .with_key("old", b"old secret")
+            .with_key("new", b"new secret");
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let verify = |mut req: RhodRequest| {
+            let handler = &handler;
+            let conn = &conn;
+            async move {
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ())
+                    .await
+                    .map(|_| req.context().get::<Principal>().unwrap().id)
+                    .map_err(|mut e| e.take_response().unwrap().status_as_int())
+            }
+        };
+
+        let mut req = request("{}");
+        handler.sign(&mut req, "new").await.unwrap();
+        assert!(req
+            .header_str("x-signature")
+            .unwrap()
+            .starts_with("sha256="));
+        // the body can still be read by the next handlers
+        assert_eq!(req.body().await.unwrap(), b"{}");
+        assert_eq!(verify(req).await, Ok("new".to_string()));
+
+        // tampered body
+        let mut req = request("{}");
+        handler.sign(&mut req, "old").await.unwrap();
+        let signed = req.headers().clone();
+        let mut tampered = request("{\"admin\":true}");
+        *tampered.headers_mut() = signed;
+        assert_eq!(verify(tampered).await, Err(401));
+
+        // expired
+        let mut req = request("{}");
+        handler.sign(&mut req, "old").await.unwrap();
+        req.headers_mut()
+            .insert("x-signature-timestamp", HeaderValue::from_static("1000"));
+        assert_eq!(verify(req).await, Err(401));
+
+        assert_eq!(verify(request("{}")).await, Err(401));
+
+        // larger bodies arent read whole
+        let handler = SignatureHandler::new(SignatureScheme::default())
+            .with_key("new", b"new secret")
+            .with_max_body_size(8);
+        let mut req = request("{\"amount\":1000}");
+        handler.sign(&mut req, "new").await.unwrap();
+        let mut err = RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 413);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::body::{RhodBody, TeePolicy};
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::{HeaderMap, Method, StatusCode, Uri};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// Response given to a TeeConsumer, with the request it answers
+#[derive(Debug, Clone)]
+pub struct TeedResponse {
+    pub method: Method,
+    pub uri: Uri,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+}
+
+// Receives copies of the responses, ie: DLP scanners or analytics pipelines. The copy of the body
+// ends with an error if it was given up (TeePolicy::Drop) or the response wasnt fully sent
+#[async_trait]
+pub trait TeeConsumer: Send + Sync {
+    async fn consume(&self, res: TeedResponse, body: RhodBody);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TeeStats {
+    pub teed: u64,
+    pub skipped: u64, // too many consumers running
+}
+
+// Request saved to build the TeedResponse
+#[derive(Clone)]
+struct TeedRequest {
+    method: Method,
+    uri: Uri,
+}
+
+// Sends a copy of every response body to a consumer running in its own task, while the body is
+// streamed to the client. The copy buffers up to buffer chunks, and the policy decides what happens
+// when the consumer falls behind: TeePolicy::Drop (default) gives up the copy, never delaying the
+// client, and TeePolicy::Backpressure slows down the response to the pace of the consumer.
+// Combine it with a ConditionalHandler to tee only some responses (ie: by content type).
+pub struct TeeHandler {
+    consumer: Arc<dyn TeeConsumer>,
+    buffer: usize,
+    policy: TeePolicy,
+    running: Arc<Semaphore>, // consumers running at once, responses beyond it arent teed
+    teed: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl TeeHandler {
+    pub fn new(consumer: Arc<dyn TeeConsumer>) -> TeeHandler {
+        TeeHandler {
+            consumer,
+            buffer: 16,
+            policy: TeePolicy::Drop,
+            running: Arc::new(Semaphore::new(256)),
+            teed: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    // Chunks buffered for the consumer
+    pub fn with_buffer(self, buffer: usize) -> Self {
+        TeeHandler { buffer, ..self }
+    }
+
+    pub fn with_policy(self, policy: TeePolicy) -> Self {
+        TeeHandler { policy, ..self }
+    }
+
+    pub fn with_max_consumers(self, max_consumers: usize) -> Self {
+        TeeHandler {
+            running: Arc::new(Semaphore::new(max_consumers)),
+            ..self
+        }
+    }
+
+    pub fn stats(&self) -> TeeStats {
+        TeeStats {
+            teed: self.teed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for TeeHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        req.context().insert(TeedRequest {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+        });
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let req = match res.context().remove::<TeedRequest>() {
+            Some(req) => req,
+            None => return (res, Ok(())),
+        };
+        let permit = match Arc::clone(&self.running).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.skipped.fetch_add(1, Ordering::Relaxed);
+                debug!("Too many tee consumers running, {} not teed", req.uri);
+                return (res, Ok(()));
+            }
+        };
+        self.teed.fetch_add(1, Ordering::Relaxed);
+
+        let (body, copy) = res.take_body().tee(self.buffer, self.policy);
+        res.set_body(body);
+        let teed = TeedResponse {
+            method: req.method,
+            uri: req.uri,
+            status: StatusCode::from_u16(res.status_as_int()).unwrap_or_default(),
+            headers: res.headers().clone(),
+        };
+        let consumer = Arc::clone(&self.consumer);
+        // detached, the consumer ends on its own
+        runtime::spawn(async move {
+            consumer.consume(teed, copy).await;
+            drop(permit);
+        });
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use tokio::sync::mpsc;
+
+    struct Collector(mpsc::UnboundedSender<(TeedResponse, Vec<u8>)>);
+
+    #[async_trait]
+    impl TeeConsumer for Collector {
+        async fn consume(&self, res: TeedResponse, body: RhodBody) {
+            let body = body.to_bytes().await.unwrap();
+            self.0.send((res, body.to_vec())).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let handler = TeeHandler::new(Arc::new(Collector(tx))).with_max_consumers(1);
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+
+        let respond = |req: &RhodRequest| {
+            let mut res = RhodResponse::builder()
+                .status(StatusCode::CREATED)
+                .header("content-type", "text/plain")
+                .body_str("secret")
+                .build()
+                .unwrap();
+            res.attach_context(req.context());
+            res
+        };
+        let mut req = RhodRequest::builder().uri("/a").build().unwrap();
+        RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap();
+        let (mut res, result) =
+            RhodHandler::<()>::handle_response(&handler, &conn, respond(&req), &mut ()).await;
+        assert!(result.is_ok());
+
+        // the consumer is running, so the next response isnt teed
+        let mut other = RhodRequest::builder().uri("/b").build().unwrap();
+        RhodHandler::<()>::handle_request(&handler, &conn, &mut other, &mut ())
+            .await
+            .unwrap();
+        let (_, result) =
+            RhodHandler::<()>::handle_response(&handler, &conn, respond(&other), &mut ()).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            handler.stats(),
+            TeeStats {
+                teed: 1,
+                skipped: 1
+            }
+        );
+
+        // the client gets the body, and the consumer a copy
+        assert_eq!(res.body().await.unwrap(), b"secret");
+        let (teed, body) = rx.recv().await.unwrap();
+        assert_eq!(teed.uri, "/a");
+        assert_eq!(teed.status, StatusCode::CREATED);
+        assert_eq!(teed.headers["content-type"], "text/plain");
+        assert_eq!(body, b"secret");
+    }
+}
@@ -0,0 +1,228 @@
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::{HeaderValue, SET_COOKIE};
+use std::net::IpAddr;
+use std::time::Duration;
+
+// Variant assigned to a request by a TrafficSplitHandler, saved in the request context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficVariant(pub String);
+
+// Cookie to send with the response, for clients assigned a variant without having the cookie
+struct PendingCookie(String);
+
+// How a client keeps its variant between requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stickiness {
+    ClientIp,       // hash of the client address
+    Cookie(String), // random on the first request, then kept in the cookie
+    None,           // random on every request
+}
+
+// Splits the traffic between variants (ie: stable and canary) by weight, for canary releases and
+// experiments. The variant is saved in the context as TrafficVariant, so the handlers and the service
+// of the variant can be selected with RequestPredicate::Variant:
+//
+//  let split = TrafficSplitHandler::new(vec![("stable", 90), ("canary", 10)]);
+//  let canary = RhodLayerGroup::new("canary", handlers).when(RequestPredicate::Variant("canary".into()));
+pub struct TrafficSplitHandler {
+    variants: Vec<(String, u32)>, // name and weight
+    stickiness: Stickiness,
+    cookie_max_age: Duration,
+}
+
+impl TrafficSplitHandler {
+    // Weights are relative, percentages when they add up to 100. Sticky by client IP
+    pub fn new(variants: Vec<(&str, u32)>) -> TrafficSplitHandler {
+        TrafficSplitHandler {
+            variants: variants
+                .into_iter()
+                .map(|(name, weight)| (name.to_string(), weight))
+                .collect(),
+            stickiness: Stickiness::ClientIp,
+            cookie_max_age: Duration::from_secs(86400),
+        }
+    }
+
+    pub fn with_stickiness(self, stickiness: Stickiness) -> Self {
+        TrafficSplitHandler { stickiness, ..self }
+    }
+
+    pub fn with_cookie_max_age(self, cookie_max_age: Duration) -> Self {
+        TrafficSplitHandler {
+            cookie_max_age,
+            ..self
+        }
+    }
+
+    // Variant of the bucket, a number below the total weight
+    fn variant(&self, bucket: u64) -> Option<&str> {
+        let mut bucket = bucket;
+        for (name, weight) in &self.variants {
+            if bucket < *weight as u64 {
+                return Some(name);
+            }
+            bucket -= *weight as u64;
+        }
+        None
+    }
+
+    fn pick(&self, hash: Option<u64>) -> Option<&str> {
+        let total: u64 = self.variants.iter().map(|(_, w)| *w as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let bucket = hash.unwrap_or_else(|| fastrand::u64(..)) % total;
+        self.variant(bucket)
+    }
+
+    fn is_variant(&self, name: &str) -> bool {
+        self.variants.iter().any(|(v, w)| v == name && *w > 0)
+    }
+}
+
+// FNV-1a, stable between runs so clients keep their variant after restarts
+fn hash_ip(ip: IpAddr) -> u64 {
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    octets.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for TrafficSplitHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let variant = match &self.stickiness {
+            Stickiness::ClientIp => self.pick(Some(hash_ip(conn.addr.ip()))),
+            Stickiness::None => self.pick(None),
+            Stickiness::Cookie(cookie) => match req.cookie(cookie) {
+                // a variant removed (or set to 0) since the cookie was sent is assigned again
+                Some(variant) if self.is_variant(variant) => Some(variant),
+                _ => {
+                    let variant = self.pick(None);
+                    if let Some(variant) = variant {
+                        req.context().insert(PendingCookie(format!(
+                            "{}={}; Path=/; Max-Age={}",
+                            cookie,
+                            variant,
+                            self.cookie_max_age.as_secs()
+                        )));
+                    }
+                    variant
+                }
+            },
+        };
+        if let Some(variant) = variant {
+            debug!("Request assigned to variant {}", variant);
+            req.context().insert(TrafficVariant(variant.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if let Some(PendingCookie(cookie)) = res.context().remove::<PendingCookie>() {
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                res.headers_mut().append(SET_COOKIE, value);
+            }
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::RequestPredicate;
+    use crate::protocols::HttpProtocol;
+
+    async fn assign(
+        handler: &TrafficSplitHandler,
+        addr: &str,
+        req: &mut RhodRequest,
+    ) -> Option<String> {
+        let conn = RhodConnInfo::new(addr.parse().unwrap(), HttpProtocol::HTTP);
+        RhodHandler::<()>::handle_request(handler, &conn, req, &mut ())
+            .await
+            .unwrap();
+        req.context().get::<TrafficVariant>().map(|v| v.0)
+    }
+
+    #[tokio::test]
+    async fn test_traffic_split() {
+        // sticky by IP: same client, same variant. About 10% goes to canary
+        let handler = TrafficSplitHandler::new(vec![("stable", 90), ("canary", 10)]);
+        let mut canary = 0;
+        for n in 0..1000 {
+            let addr = format!("10.0.{}.{}:4000", n / 256, n % 256);
+            let mut req = RhodRequest::builder().build().unwrap();
+            let variant = assign(&handler, &addr, &mut req).await.unwrap();
+            let mut again = RhodRequest::builder().build().unwrap();
+            assert_eq!(assign(&handler, &addr, &mut again).await.unwrap(), variant);
+            if variant == "canary" {
+                canary += 1;
+                assert!(RequestPredicate::Variant("canary".to_string()).matches(&req));
+            }
+        }
+        assert!((50..150).contains(&canary));
+
+        // sticky by cookie: the cookie is set once
+        let handler = TrafficSplitHandler::new(vec![("a", 1), ("b", 1)])
+            .with_stickiness(Stickiness::Cookie("ab".to_string()));
+        let mut req = RhodRequest::builder()
+            .header("Cookie", "session=1; ab=b")
+            .build()
+            .unwrap();
+        assert_eq!(
+            assign(&handler, "10.0.0.1:4000", &mut req).await.as_deref(),
+            Some("b")
+        );
+        let mut req = RhodRequest::builder()
+            .header("Cookie", "ab=removed")
+            .build()
+            .unwrap();
+        let variant = assign(&handler, "10.0.0.1:4000", &mut req).await.unwrap();
+        let mut res = RhodResponse::builder().build().unwrap();
+        res.attach_context(req.context());
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let (res, _) = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert_eq!(
+            res.headers()[SET_COOKIE],
+            format!("ab={}; Path=/; Max-Age=86400", variant).as_str()
+        );
+    }
+}
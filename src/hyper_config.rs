@@ -1,4 +1,6 @@
 mod hyper_tls_conf;
-pub use hyper_tls_conf::HyperTlsAcceptor;
+pub use hyper_tls_conf::{get_configuration, HyperTlsAcceptor};
 mod hyper_service;
 pub use hyper_service::RhodHyperService;
+mod rhod_conn;
+pub use rhod_conn::RhodConn;
@@ -1,131 +1,81 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::Context;
-use std::task::Poll;
 
-use hyper;
-use hyper::body::Body as HyperBody;
-use hyper::http::Request as HyperRequest;
-use hyper::http::Response as HyperResponse;
+use http::Request as HyperRequest;
+use http::Response as HyperResponse;
+use http::Version;
+use hyper::body::Incoming;
 use hyper::service::Service as HyperService;
 
+use super::rhod_conn::{ConnState, InFlightGuard, ReadTimeoutBody};
+use crate::body::RhodBody;
+use crate::drain::Drain;
+use crate::reload::LiveSettings;
+use crate::stats::ServerStats;
 use crate::CommunicationChannel;
-use crate::{errors::RhodError, RhodConnInfo, RhodHandlerInStack, RhodRequest, RhodStack};
+use crate::{errors::RhodError, RhodConnInfo, RhodRequest};
 
 type SecureFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
 pub struct RhodHyperService<C> {
-    stack: Arc<RhodStack<C>>,
+    live: Arc<LiveSettings<C>>, // every request is served by the current stack
+    stats: Arc<ServerStats>,
+    drain: Drain,
     conn: RhodConnInfo,
+    conn_state: Arc<ConnState>,
 }
 
 impl<C> RhodHyperService<C> {
-    pub fn new(stack: Arc<RhodStack<C>>, conn: RhodConnInfo) -> RhodHyperService<C> {
-        RhodHyperService { stack, conn }
+    pub(crate) fn new(
+        live: Arc<LiveSettings<C>>,
+        stats: Arc<ServerStats>,
+        drain: Drain,
+        conn: RhodConnInfo,
+        conn_state: Arc<ConnState>,
+    ) -> RhodHyperService<C> {
+        RhodHyperService {
+            live,
+            stats,
+            drain,
+            conn,
+            conn_state,
+        }
     }
 }
 
-impl<C: CommunicationChannel> HyperService<HyperRequest<HyperBody>> for RhodHyperService<C> {
-    type Response = HyperResponse<HyperBody>;
+impl<C: CommunicationChannel> HyperService<HyperRequest<Incoming>> for RhodHyperService<C> {
+    type Response = HyperResponse<RhodBody>;
     type Error = RhodError;
     type Future = SecureFuture<Result<Self::Response, Self::Error>>;
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
-    }
-
-    fn call(&mut self, h_req: HyperRequest<HyperBody>) -> Self::Future {
-        let stack = Arc::clone(&self.stack);
+    fn call(&self, h_req: HyperRequest<Incoming>) -> Self::Future {
+        let stack = self.live.stack.load();
         let conn = self.conn.clone();
+        let in_flight = InFlightGuard::new(Arc::clone(&self.conn_state));
+        let counted = self.stats.request();
+        let rejected = self.drain.reject(h_req.version(), h_req.uri().path());
+        let body_read_timeout = self.live.conn_conf.load().body_read_timeout;
+        // informational responses can be sent until the final response, only on HTTP/1.1
+        let informational = match h_req.version() {
+            Version::HTTP_11 if rejected.is_none() => Some(self.conn_state.informational().open()),
+            _ => None,
+        };
         Box::pin(async move {
-            let mut req = RhodRequest::new(h_req);
-            let mut err = None;
-
-            let mut dyn_handlers = vec![];
-            let mut counter: usize = 0;
-
-            let mut communication = C::new();
-
-            // call handle_request from handlers in order:
-            for handler in stack.handlers.iter() {
-                let handler = match handler {
-                    // if is dynamic handler, gets it and saves in dyn handlers array
-                    RhodHandlerInStack::DynamicRhodHandler(dyn_handler) => {
-                        let aux = dyn_handler
-                            .get_handler(&conn, &req, &mut communication)
-                            .await;
-                        dyn_handlers.push(aux);
-                        counter += 1;
-                        aux
-                    }
-                    RhodHandlerInStack::RhodHandler(handler) => &**handler,
-                };
-
-                match &err {
-                    None => match handler
-                        .handle_request(&conn, &mut req, &mut communication)
-                        .await
-                    {
-                        Ok(()) => (),
-                        Err(e) => {
-                            e.log();
-                            err = Some(e);
-                        }
-                    },
-                    Some(e) => {
-                        handler.catch_request(&conn, &req, e, &communication).await;
-                    }
-                }
-            }
-
-            if let Some(e) = err {
-                return Err(e);
-            }
-
-            // call rhodium service:
-            match stack.service.serve(&conn, req, &mut communication).await {
-                Ok(mut res) => {
-                    // call handle_response from handlers in reverse order:
-                    for handler in stack.handlers.iter().rev() {
-                        // if handler is dynamic, gets the handler from dyn handlers array
-                        let handler = match handler {
-                            RhodHandlerInStack::DynamicRhodHandler(_) => {
-                                counter -= 1;
-                                dyn_handlers[counter]
-                            }
-                            RhodHandlerInStack::RhodHandler(handler) => &**handler,
-                        };
-
-                        match &err {
-                            None => match handler
-                                .handle_response(&conn, res, &mut communication)
-                                .await
-                            {
-                                (new_res, Ok(())) => res = new_res,
-                                (new_res, Err(e)) => {
-                                    res = new_res;
-                                    e.log();
-                                    err = Some(e);
-                                }
-                            },
-                            Some(e) => {
-                                handler.catch_response(&conn, &res, e, &communication).await;
-                            }
-                        }
-                    }
-
-                    if let Some(e) = err {
-                        return Err(e);
-                    }
-
-                    Ok(res.into_hyper_response())
-                }
-                Err(e) => {
-                    e.log();
-                    Err(e)
-                }
+            if let Some(res) = rejected {
+                return Ok(res.into_hyper_response());
             }
+            let _in_flight = (in_flight, counted);
+            let req = RhodRequest::new(h_req.map(|body| match body_read_timeout {
+                Some(timeout) => RhodBody::new(ReadTimeoutBody::new(RhodBody::new(body), timeout)),
+                None => RhodBody::new(body),
+            }));
+            let _informational = informational.map(|(informational, guard)| {
+                req.context().insert(informational);
+                guard
+            });
+            let res = stack.execute(&conn, req).await?;
+            Ok(res.into_hyper_response())
         })
     }
 }
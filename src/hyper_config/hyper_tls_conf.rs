@@ -1,45 +1,156 @@
 mod certs;
-use self::certs::get_configuration;
+pub use self::certs::get_configuration;
+mod hello;
+pub use self::hello::HelloRecorder;
 
-use core::task::{Context, Poll};
 use std::io;
-use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures_util::stream::*;
-use hyper::server::accept::Accept;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
-use tokio_stream::wrappers::TcpListenerStream;
 
-pub struct HyperTlsAcceptor<'a> {
-    tls_stream: Pin<Box<dyn Stream<Item = Result<TlsStream<TcpStream>, io::Error>> + 'a>>,
-}
+use crate::reload::{Swap, TlsListenerConf};
+use crate::runtime::{self, Task, TaskHandle};
+use crate::stats::ServerStats;
+
+// handshaked connections waiting to be served
+const HANDSHAKED_QUEUE: usize = 128;
 
-impl Accept for HyperTlsAcceptor<'_> {
-    type Conn = TlsStream<TcpStream>;
-    type Error = io::Error;
+// TLS connection, with the fingerprint of its ClientHello
+pub type ServerTlsStream = TlsStream<HelloRecorder<TcpStream>>;
 
-    fn poll_accept(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context,
-    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        Pin::new(&mut self.tls_stream).poll_next(cx)
+// Accepts TCP connections in a background task, and spawns a task for every TLS handshake,
+// so a slow client can't block other incoming connections.
+// The TLS settings are read for every handshake, so reloaded certificates are used by the next connections.
+pub struct HyperTlsAcceptor {
+    handshaked: mpsc::Receiver<ServerTlsStream>,
+    accept_task: TaskHandle,
+}
+
+impl Drop for HyperTlsAcceptor {
+    fn drop(&mut self) {
+        Task::abort(&self.accept_task);
     }
 }
 
-impl HyperTlsAcceptor<'_> {
-    pub fn new<'a>(
+impl HyperTlsAcceptor {
+    // Must be called inside the runtime
+    pub(crate) fn new(
         tcp: TcpListener,
-        crt_file: &'a str,
-        key_file: &'a str,
-    ) -> io::Result<HyperTlsAcceptor<'a>> {
-        let server_config = get_configuration(crt_file, key_file)?;
-        let tls_acceptor = TlsAcceptor::from(server_config);
-        let tls_stream = TcpListenerStream::new(tcp)
-            .and_then(move |s| tls_acceptor.accept(s))
-            .boxed();
-
-        Ok(HyperTlsAcceptor { tls_stream })
+        tls: Arc<Swap<TlsListenerConf>>,
+        stats: Arc<ServerStats>,
+    ) -> io::Result<HyperTlsAcceptor> {
+        let (sender, handshaked) = mpsc::channel(HANDSHAKED_QUEUE);
+        let accept_task = runtime::spawn(accept_loop(tcp, tls, stats, sender));
+
+        Ok(HyperTlsAcceptor {
+            handshaked,
+            accept_task,
+        })
+    }
+
+    // Next handshaked connection
+    pub async fn accept(&mut self) -> Option<ServerTlsStream> {
+        self.handshaked.recv().await
+    }
+}
+
+async fn accept_loop(
+    tcp: TcpListener,
+    tls: Arc<Swap<TlsListenerConf>>,
+    stats: Arc<ServerStats>,
+    sender: mpsc::Sender<ServerTlsStream>,
+) {
+    loop {
+        let (stream, addr) = match tcp.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // usually too many open files, wait for some connections to close
+                error!("Error when accepting TCP connection. {}", e);
+                stats.accept_error();
+                runtime::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let tls = tls.load();
+        let sender = sender.clone();
+        let stats = Arc::clone(&stats);
+        stats.handshake_started();
+        runtime::spawn(async move {
+            let start = Instant::now();
+            let handshake = TlsAcceptor::from(Arc::clone(&tls.server_config))
+                .accept(HelloRecorder::new(stream));
+            let handshake = match tls.handshake_timeout {
+                Some(timeout) => match runtime::timeout(timeout, handshake).await {
+                    Ok(handshake) => handshake,
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake timeout")),
+                },
+                None => handshake.await,
+            };
+            stats.handshake_finished(start.elapsed(), &handshake);
+
+            // a failed handshake (scanners, plain http, bad ClientHello) only drops its connection
+            match handshake {
+                Ok(tls_stream) => {
+                    let _ = sender.send(tls_stream).await;
+                }
+                Err(e) => warn!("TLS handshake with {} failed. {}", addr, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::{HttpProtocolConf, TlsConfig};
+    use native_tls::{Certificate, TlsConnector};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_handshake_failure() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let protocol = HttpProtocolConf::HTTPS {
+            cert_file: "tests/assets/certs/server.crt".to_string(),
+            key_file: "tests/assets/certs/server.key".to_string(),
+        };
+        let server_config =
+            get_configuration(&protocol, &TlsConfig::default(), &Default::default()).unwrap();
+        let mut acceptor = HyperTlsAcceptor::new(
+            tcp,
+            TlsListenerConf::shared(server_config, None),
+            Arc::new(ServerStats::new()),
+        )
+        .unwrap();
+        let accepted = tokio::spawn(async move { acceptor.accept().await });
+
+        // a stalled client doesn't block the next ones
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+
+        // plain http to the tls port
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        drop(plain);
+
+        let client = tokio::task::spawn_blocking(move || {
+            let ca = include_bytes!("../../tests/assets/certs/CA.pem");
+            let connector = TlsConnector::builder()
+                .add_root_certificate(Certificate::from_pem(ca).unwrap())
+                .build()
+                .unwrap();
+            let tcp = std::net::TcpStream::connect(addr).unwrap();
+            connector.connect("localhost", tcp).is_ok()
+        });
+
+        let accepted = accepted.await.unwrap().unwrap();
+        assert!(client.await.unwrap());
+        // the ClientHello of the client was fingerprinted
+        let fingerprint = accepted.get_ref().0.fingerprint().unwrap();
+        assert!(fingerprint.ja4.starts_with("t1"));
     }
 }
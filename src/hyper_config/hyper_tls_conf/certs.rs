@@ -1,54 +1,263 @@
-use std::{fs, io};
-
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind};
 use std::sync::Arc;
 
-use io::{BufReader, ErrorKind};
+use rustls_pki_types::pem::{self, PemObject};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::{version, ServerConfig, SupportedProtocolVersion};
 
-use fs::File;
+use crate::protocols::{ConnectionConf, HttpProtocolConf, TlsConfig, TlsVersion};
 
-use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+fn pem_error(what: &str, e: pem::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Couldn't parse {}. {}", what, e),
+    )
+}
 
-fn load_certs(filename: &str) -> io::Result<Vec<Certificate>> {
-    let cert_file = File::open(filename)?;
-    let mut reader = BufReader::new(cert_file);
+fn check_certs(certs: Vec<CertificateDer<'static>>) -> io::Result<Vec<CertificateDer<'static>>> {
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Expected at least one certificate",
+        ));
+    }
+    Ok(certs)
+}
 
-    pemfile::certs(&mut reader)
-        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Couldn't parse certificates"))
+fn load_certs(filename: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(filename)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error("certificates", e))?;
+    check_certs(certs)
 }
 
-fn load_private_key(filename: &str) -> io::Result<PrivateKey> {
-    let key_file = fs::File::open(filename)?;
-    let mut reader = io::BufReader::new(key_file);
+// Loads a single private key (PKCS#1, PKCS#8 or SEC1)
+fn load_private_key(filename: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(filename).map_err(|e| pem_error("key", e))
+}
 
-    // Load and return a single private key.
-    let keys = pemfile::rsa_private_keys(&mut reader)
-        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Couldn't parse key"))?;
+// Certificate chain and private key of an HTTPS configuration
+fn load_identity(
+    protocol: &HttpProtocolConf,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    match protocol {
+        HttpProtocolConf::HTTPS {
+            cert_file,
+            key_file,
+        } => Ok((load_certs(cert_file)?, load_private_key(key_file)?)),
+        HttpProtocolConf::HTTPSPem { cert, key } => {
+            let certs = CertificateDer::pem_slice_iter(cert)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| pem_error("certificates", e))?;
+            let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| pem_error("key", e))?;
+            Ok((check_certs(certs)?, key))
+        }
+        HttpProtocolConf::HTTPSDer { certs, key } => {
+            let certs = certs
+                .iter()
+                .map(|cert| CertificateDer::from(cert.clone()))
+                .collect();
+            let key = PrivateKeyDer::try_from(key.clone())
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+            Ok((check_certs(certs)?, key))
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Expected an HTTPS configuration with certificates",
+        )),
+    }
+}
 
-    if keys.len() != 1 {
+fn protocol_versions(tls: &TlsConfig) -> io::Result<Vec<&'static SupportedProtocolVersion>> {
+    let versions: Vec<_> = [
+        (TlsVersion::TLS12, &version::TLS12),
+        (TlsVersion::TLS13, &version::TLS13),
+    ]
+    .iter()
+    .filter(|(v, _)| *v >= tls.min_version && *v <= tls.max_version)
+    .map(|(_, supported)| *supported)
+    .collect();
+
+    if versions.is_empty() {
         return Err(io::Error::new(
             ErrorKind::InvalidInput,
-            "Expected just one key",
+            "Invalid TLS version range",
         ));
     }
 
-    Ok(keys[0].clone())
+    Ok(versions)
 }
 
-// Build TLS configuration.
-pub fn get_configuration(crt_file: &str, key_file: &str) -> io::Result<Arc<ServerConfig>> {
-    let certs = load_certs(crt_file)?;
-    let key = load_private_key(key_file)?;
+// Protocols offered with ALPN, as served by the connections
+fn alpn_protocols(conn_conf: &ConnectionConf) -> Vec<Vec<u8>> {
+    if conn_conf.http1_only {
+        vec![b"http/1.1".to_vec()]
+    } else if conn_conf.http2_only {
+        vec![b"h2".to_vec()]
+    } else {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    }
+}
 
-    // Do not use client certificate authentication.
-    let mut cfg = ServerConfig::new(NoClientAuth::new());
+// Build TLS configuration. A custom ServerConfig (HTTPSConfig) is used as it is, with its own ALPN
+pub fn get_configuration(
+    protocol: &HttpProtocolConf,
+    tls: &TlsConfig,
+    conn_conf: &ConnectionConf,
+) -> io::Result<Arc<ServerConfig>> {
+    if let HttpProtocolConf::HTTPSConfig(config) = protocol {
+        return Ok(Arc::clone(config));
+    }
+    let (certs, key) = load_identity(protocol)?;
 
-    cfg.set_single_cert(certs, key).map_err(|e| {
-        io::Error::new(
-            ErrorKind::InvalidInput,
-            format!("Certs and key don't match. {:?}", e),
-        )
-    })?;
+    let mut provider = ring::default_provider();
+    if let Some(names) = &tls.cipher_suites {
+        let mut suites = Vec::with_capacity(names.len());
+        for name in names {
+            match provider
+                .cipher_suites
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()) == *name)
+            {
+                Some(suite) => suites.push(*suite),
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Unknown cipher suite {}", name),
+                    ))
+                }
+            }
+        }
+        provider.cipher_suites = suites;
+    }
+
+    // Do not use client certificate authentication.
+    let mut cfg = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&protocol_versions(tls)?)
+        .map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid TLS configuration. {}", e),
+            )
+        })?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Certs and key don't match. {:?}", e),
+            )
+        })?;
+    cfg.alpn_protocols = alpn_protocols(conn_conf);
 
     Ok(Arc::new(cfg))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_files() -> HttpProtocolConf {
+        HttpProtocolConf::HTTPS {
+            cert_file: "tests/assets/certs/server.crt".to_string(),
+            key_file: "tests/assets/certs/server.key".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let tls = TlsConfig::new()
+            .with_versions(TlsVersion::TLS13, TlsVersion::TLS13)
+            .with_cipher_suites(vec!["TLS13_AES_256_GCM_SHA384"]);
+        let cfg = get_configuration(&from_files(), &tls, &ConnectionConf::default()).unwrap();
+        assert_eq!(cfg.crypto_provider().cipher_suites.len(), 1);
+
+        let tls = TlsConfig::new().with_cipher_suites(vec!["TLS_RSA_WITH_RC4_128_MD5"]);
+        assert!(get_configuration(&from_files(), &tls, &ConnectionConf::default()).is_err());
+
+        let tls = TlsConfig::new().with_versions(TlsVersion::TLS13, TlsVersion::TLS12);
+        assert!(get_configuration(&from_files(), &tls, &ConnectionConf::default()).is_err());
+
+        let swapped = HttpProtocolConf::HTTPS {
+            cert_file: "tests/assets/certs/server.key".to_string(),
+            key_file: "tests/assets/certs/server.key".to_string(),
+        };
+        assert!(
+            get_configuration(&swapped, &TlsConfig::default(), &ConnectionConf::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_in_memory_certs() {
+        let cert = include_bytes!("../../../tests/assets/certs/server.crt").to_vec();
+        let key = include_bytes!("../../../tests/assets/certs/server.key").to_vec();
+        let tls = TlsConfig::default();
+
+        let pem = HttpProtocolConf::HTTPSPem {
+            cert: cert.clone(),
+            key: key.clone(),
+        };
+        assert!(get_configuration(&pem, &tls, &ConnectionConf::default()).is_ok());
+
+        let der = HttpProtocolConf::HTTPSDer {
+            certs: vec![CertificateDer::from_pem_slice(&cert).unwrap().to_vec()],
+            key: PrivateKeyDer::from_pem_slice(&key)
+                .unwrap()
+                .secret_der()
+                .to_vec(),
+        };
+        assert!(get_configuration(&der, &tls, &ConnectionConf::default()).is_ok());
+
+        let config = get_configuration(&from_files(), &tls, &ConnectionConf::default()).unwrap();
+        let custom = HttpProtocolConf::HTTPSConfig(Arc::clone(&config));
+        assert!(Arc::ptr_eq(
+            &get_configuration(&custom, &tls, &ConnectionConf::default()).unwrap(),
+            &config
+        ));
+
+        assert!(
+            get_configuration(&HttpProtocolConf::HTTP, &tls, &ConnectionConf::default()).is_err()
+        );
+    }
+
+    // Protocol chosen by a client offering HTTP/2 and HTTP/1.1
+    async fn negotiate(conn_conf: &ConnectionConf) -> Option<Vec<u8>> {
+        use rustls_pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+        let server = get_configuration(&from_files(), &TlsConfig::default(), conn_conf).unwrap();
+        let mut roots = RootCertStore::empty();
+        let ca = include_bytes!("../../../tests/assets/certs/CA.pem");
+        roots
+            .add(CertificateDer::from_pem_slice(ca).unwrap())
+            .unwrap();
+        let mut client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let (client_io, server_io) = tokio::io::duplex(1 << 16);
+        let accept = TlsAcceptor::from(server).accept(server_io);
+        let connect = TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), client_io);
+        let (accepted, connected) = tokio::join!(accept, connect);
+        accepted.unwrap();
+        connected
+            .unwrap()
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .map(<[u8]>::to_vec)
+    }
+
+    #[tokio::test]
+    async fn test_alpn() {
+        let conn_conf = ConnectionConf::default();
+        assert_eq!(negotiate(&conn_conf).await.unwrap(), b"h2");
+        let http1 = conn_conf.clone().http1_only();
+        assert_eq!(negotiate(&http1).await.unwrap(), b"http/1.1");
+    }
+}
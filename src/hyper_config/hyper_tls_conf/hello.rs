@@ -0,0 +1,96 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::tls_fingerprint::{ClientHello, TlsFingerprint};
+
+// Stream keeping a copy of what the client sends until its ClientHello is read by the handshake, to
+// fingerprint it. Reads go straight to the inner stream afterwards
+pub struct HelloRecorder<S> {
+    inner: S,
+    recorded: Option<Vec<u8>>, // None once the ClientHello is read, or isnt valid
+    fingerprint: Option<Arc<TlsFingerprint>>,
+}
+
+impl<S> HelloRecorder<S> {
+    pub fn new(inner: S) -> HelloRecorder<S> {
+        HelloRecorder {
+            inner,
+            recorded: Some(Vec::new()),
+            fingerprint: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn fingerprint(&self) -> Option<Arc<TlsFingerprint>> {
+        self.fingerprint.clone()
+    }
+
+    fn record(&mut self, read: &[u8]) {
+        let recorded = match &mut self.recorded {
+            Some(recorded) => recorded,
+            None => return,
+        };
+        recorded.extend_from_slice(read);
+        match ClientHello::read(recorded) {
+            ClientHello::Incomplete => return,
+            ClientHello::Invalid => {}
+            ClientHello::Complete(message) => {
+                self.fingerprint = TlsFingerprint::from_client_hello(&message).map(Arc::new);
+            }
+        }
+        self.recorded = None;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HelloRecorder<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let polled = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            this.record(&buf.filled()[filled..]);
+        }
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HelloRecorder<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
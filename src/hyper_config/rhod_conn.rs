@@ -0,0 +1,360 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::body::{BoxError, RhodBody};
+use crate::conn_handler::ConnStats;
+use crate::informational::InformationalQueue;
+use crate::runtime::{sleep, Sleep};
+
+// State shared between a connection and the service handling its requests
+#[derive(Default)]
+pub struct ConnState {
+    in_flight: AtomicUsize, // requests being handled by the stack
+    requests: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    informational: Arc<InformationalQueue>,
+}
+
+impl ConnState {
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Informational responses of the request in flight, waiting to be written
+    pub(crate) fn informational(&self) -> Arc<InformationalQueue> {
+        Arc::clone(&self.informational)
+    }
+
+    pub fn stats(&self, duration: Duration) -> ConnStats {
+        ConnStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            duration,
+        }
+    }
+}
+
+// Counts a request as in flight while alive
+pub struct InFlightGuard(Arc<ConnState>);
+
+impl InFlightGuard {
+    pub fn new(state: Arc<ConnState>) -> InFlightGuard {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        state.requests.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(state)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Wraps every accepted connection.
+// If there is an idle timeout, the connection is closed when no request is in flight and
+// nothing is read or written during that time.
+// Informational responses are written before the next bytes written by hyper, or while hyper waits
+// for the response reading the connection (only when hyper has flushed what it wrote, so they arent
+// written in the middle of its writes).
+pub struct RhodConn<S> {
+    inner: S,
+    state: Arc<ConnState>,
+    idle_timeout: Option<Duration>,
+    idle_timer: Option<Sleep>,
+    flushed: bool,             // hyper flushed everything it wrote
+    informational_flush: bool, // informational responses written but not flushed
+}
+
+impl<S> RhodConn<S> {
+    pub fn new(inner: S, idle_timeout: Option<Duration>) -> RhodConn<S> {
+        RhodConn {
+            inner,
+            state: Arc::new(ConnState::default()),
+            idle_timeout,
+            idle_timer: None,
+            flushed: true,
+            informational_flush: false,
+        }
+    }
+
+    pub fn state(&self) -> Arc<ConnState> {
+        Arc::clone(&self.state)
+    }
+
+    fn activity(&mut self) {
+        self.idle_timer = None;
+    }
+
+    fn written(&mut self, written: usize) {
+        self.activity();
+        self.state
+            .bytes_written
+            .fetch_add(written as u64, Ordering::Relaxed);
+    }
+
+    // Error to return if the connection has been idle for too long
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Option<io::Error> {
+        let timeout = match self.idle_timeout {
+            Some(timeout) if self.state.in_flight() == 0 => timeout,
+            _ => {
+                self.idle_timer = None;
+                return None;
+            }
+        };
+
+        let timer = self.idle_timer.get_or_insert_with(|| sleep(timeout));
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Some(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Connection idle timeout",
+            )),
+            Poll::Pending => None,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> RhodConn<S> {
+    // Writes the informational responses queued while hyper waits for the response. Write errors
+    // show up again in the next read or write of hyper
+    fn poll_informational(&mut self, cx: &mut Context<'_>) {
+        let queue = Arc::clone(&self.state.informational);
+        queue.register(cx.waker());
+        if !self.flushed {
+            return;
+        }
+        if !queue.is_empty() {
+            let (written, result) = queue.poll_write(cx, &mut self.inner);
+            if written > 0 {
+                self.written(written);
+                self.informational_flush = true;
+            }
+            if let Poll::Ready(Err(e)) = result {
+                debug!("Error when writing informational response. {}", e);
+            }
+        }
+        if self.informational_flush {
+            if let Poll::Ready(result) = Pin::new(&mut self.inner).poll_flush(cx) {
+                self.informational_flush = false;
+                if let Err(e) = result {
+                    debug!("Error when flushing informational response. {}", e);
+                }
+            }
+        }
+    }
+
+    // Writes the queued informational responses before the bytes of hyper
+    fn poll_informational_first(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let (written, result) = self.state.informational.poll_write(cx, &mut self.inner);
+        if written > 0 {
+            self.written(written);
+        }
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for RhodConn<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_informational(cx);
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                let read = buf.filled().len() - filled;
+                if read > 0 {
+                    this.activity();
+                    this.state
+                        .bytes_read
+                        .fetch_add(read as u64, Ordering::Relaxed);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.poll_idle(cx) {
+                Some(e) => Poll::Ready(Err(e)),
+                None => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RhodConn<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_informational_first(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        this.flushed = false;
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.activity();
+                if let Ok(written) = result {
+                    this.written(written);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.poll_idle(cx) {
+                Some(e) => Poll::Ready(Err(e)),
+                None => Poll::Pending,
+            },
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_informational_first(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        this.flushed = false;
+        match Pin::new(&mut this.inner).poll_write_vectored(cx, bufs) {
+            Poll::Ready(result) => {
+                this.activity();
+                if let Ok(written) = result {
+                    this.written(written);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.poll_idle(cx) {
+                Some(e) => Poll::Ready(Err(e)),
+                None => Poll::Pending,
+            },
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let flushed = Pin::new(&mut this.inner).poll_flush(cx);
+        if let Poll::Ready(Ok(())) = flushed {
+            this.flushed = true;
+            this.informational_flush = false;
+        }
+        flushed
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Request body failing when the client doesnt send data for longer than the timeout,
+// so bodies trickled byte by byte dont hold the request forever
+pub struct ReadTimeoutBody {
+    inner: RhodBody,
+    timeout: Duration,
+    timer: Option<Sleep>, // running while waiting for a frame
+}
+
+impl ReadTimeoutBody {
+    pub fn new(inner: RhodBody, timeout: Duration) -> ReadTimeoutBody {
+        ReadTimeoutBody {
+            inner,
+            timeout,
+            timer: None,
+        }
+    }
+}
+
+impl Body for ReadTimeoutBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                this.timer = None;
+                Poll::Ready(frame)
+            }
+            Poll::Pending => {
+                let timeout = this.timeout;
+                let timer = this.timer.get_or_insert_with(|| sleep(timeout));
+                match timer.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Some(Err(Box::new(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Request body read timeout",
+                    ))))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut conn = RhodConn::new(server, Some(Duration::from_millis(50)));
+        let mut client = client;
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+
+        // a request in flight keeps the connection open
+        let guard = InFlightGuard::new(conn.state());
+        let read = tokio::time::timeout(Duration::from_millis(150), conn.read(&mut buf)).await;
+        assert!(read.is_err());
+        drop(guard);
+
+        let err = conn.read(&mut buf).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_body_read_timeout() {
+        let (mut tx, body) = RhodBody::channel();
+        let body = ReadTimeoutBody::new(body, Duration::from_millis(50));
+        tokio::spawn(async move {
+            tx.send_data(Bytes::from("a")).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let _ = tx.send_data(Bytes::from("b")).await;
+        });
+        let err = RhodBody::new(body).to_bytes().await.unwrap_err();
+        assert!(err.to_string().contains("timeout"));
+    }
+}
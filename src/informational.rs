@@ -0,0 +1,183 @@
+// 1xx informational responses (ie: 103 Early Hints) sent before the final response.
+// Hyper doesnt send them from servers, so they are written by RhodConn on the connection, before the
+// bytes hyper writes. Only HTTP/1.1 requests get an Informational in their context: HTTP/1.0 clients
+// dont expect them, and HTTP/2 streams are framed by hyper.
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use http::header::LINK;
+use http::{HeaderMap, HeaderValue, StatusCode};
+use tokio::io::AsyncWrite;
+
+// Heads waiting to be written on a connection, and the request allowed to send them
+#[derive(Default)]
+pub(crate) struct InformationalQueue(Mutex<Queue>);
+
+#[derive(Default)]
+struct Queue {
+    pending: Vec<u8>,
+    request: Option<u64>, // request in flight, its final response hasnt been sent
+    opened: u64,
+    waker: Option<Waker>, // of the connection, to write the heads while waiting for the response
+}
+
+impl InformationalQueue {
+    // Sender for the request being handled, until the guard is dropped
+    pub(crate) fn open(self: &Arc<Self>) -> (Informational, InformationalGuard) {
+        let mut queue = self.0.lock().unwrap();
+        queue.opened += 1;
+        queue.request = Some(queue.opened);
+        let informational = Informational {
+            queue: Arc::clone(self),
+            request: queue.opened,
+        };
+        (informational, InformationalGuard(Arc::clone(self)))
+    }
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut queue = self.0.lock().unwrap();
+        if !queue.waker.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            queue.waker = Some(waker.clone());
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().pending.is_empty()
+    }
+
+    // Writes the pending heads, returning the bytes written and Ready(Ok) once they are all written
+    pub(crate) fn poll_write<S: AsyncWrite + Unpin>(
+        &self,
+        cx: &mut Context<'_>,
+        io: &mut S,
+    ) -> (usize, Poll<io::Result<()>>) {
+        let mut queue = self.0.lock().unwrap();
+        let mut written = 0;
+        while !queue.pending.is_empty() {
+            match Pin::new(&mut *io).poll_write(cx, &queue.pending) {
+                Poll::Ready(Ok(0)) => {
+                    return (written, Poll::Ready(Err(io::ErrorKind::WriteZero.into())))
+                }
+                Poll::Ready(Ok(n)) => {
+                    queue.pending.drain(..n);
+                    written += n;
+                }
+                Poll::Ready(Err(e)) => return (written, Poll::Ready(Err(e))),
+                Poll::Pending => return (written, Poll::Pending),
+            }
+        }
+        (written, Poll::Ready(Ok(())))
+    }
+}
+
+// Closes the sender of the request, before its final response is written
+pub(crate) struct InformationalGuard(Arc<InformationalQueue>);
+
+impl Drop for InformationalGuard {
+    fn drop(&mut self) {
+        self.0 .0.lock().unwrap().request = None;
+    }
+}
+
+// Sends informational responses to the client of a request. Taken from the request context by the
+// services and handlers: req.context().get::<Informational>()
+#[derive(Clone)]
+pub struct Informational {
+    queue: Arc<InformationalQueue>,
+    request: u64,
+}
+
+impl Informational {
+    // Sends a 1xx response with the headers. 100 Continue (sent by hyper) and 101 Switching Protocols
+    // (the final response of upgrades) arent allowed. False if it isnt sent, because the status isnt
+    // allowed or the final response has already been sent
+    pub fn send(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if !status.is_informational()
+            || status == StatusCode::CONTINUE
+            || status == StatusCode::SWITCHING_PROTOCOLS
+        {
+            return false;
+        }
+        let mut queue = self.queue.0.lock().unwrap();
+        if queue.request != Some(self.request) {
+            return false;
+        }
+        let head = &mut queue.pending;
+        head.extend_from_slice(b"HTTP/1.1 ");
+        head.extend_from_slice(status.as_str().as_bytes());
+        head.push(b' ');
+        head.extend_from_slice(status.canonical_reason().unwrap_or("").as_bytes());
+        head.extend_from_slice(b"\r\n");
+        for (name, value) in headers {
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    // Sends a 103 Early Hints with a Link header for each link (ie: </app.css>; rel=preload; as=style)
+    pub fn early_hints(&self, links: &[&str]) -> bool {
+        let mut headers = HeaderMap::new();
+        for link in links {
+            match HeaderValue::from_str(link) {
+                Ok(value) => {
+                    headers.append(LINK, value);
+                }
+                Err(_) => warn!("Invalid early hint link {:?}, skipped", link),
+            }
+        }
+        !headers.is_empty() && self.send(StatusCode::EARLY_HINTS, &headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_config::RhodConn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_informational() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = RhodConn::new(server, None);
+        let queue = conn.state().informational();
+        let (informational, guard) = queue.open();
+
+        // the connection is reading while the request is handled
+        let reading = tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            let _ = conn.read(&mut buf).await;
+            conn
+        });
+        tokio::task::yield_now().await;
+        assert!(informational.early_hints(&["</app.css>; rel=preload; as=style"]));
+        assert!(!informational.send(StatusCode::SWITCHING_PROTOCOLS, &HeaderMap::new()));
+        let hints = "HTTP/1.1 103 Early Hints\r\nlink: </app.css>; rel=preload; as=style\r\n\r\n";
+        let mut buf = vec![0u8; hints.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, hints.as_bytes());
+
+        // queued heads are written before the final response
+        assert!(informational.early_hints(&["</app.js>; rel=preload; as=script"]));
+        client.write_all(b"x").await.unwrap();
+        let mut conn = reading.await.unwrap();
+        drop(guard);
+        assert!(!informational.early_hints(&["</late.js>; rel=preload"]));
+        conn.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        let mut buf = String::new();
+        drop(conn);
+        client.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(
+            buf,
+            "HTTP/1.1 103 Early Hints\r\nlink: </app.js>; rel=preload; as=script\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"
+        );
+    }
+}
@@ -21,33 +21,90 @@
 //      the flow is ended
 // If the Handler i returns an error while handling a response:
 //      catch_response functions are called for the next handlers (Handler i-1, i-2, ..., 1), and then the flow is ended.
+// If the error carries a response (RhodError::from_response), the flow is ended answering with it:
+//      when returned by Handler i while handling a request, handle_response functions are called for the previous handlers
+//      (Handler i-1, i-2, ..., 1) with that response.
 
 #[macro_use]
 extern crate log;
 
-use hyper;
-use hyper::server::conn::AddrIncoming;
-use hyper::server::conn::AddrStream;
-use hyper::Server as HyperServer;
+use hyper_util::rt::TokioIo;
 
+use async_trait::async_trait;
+use futures_util::stream::StreamExt;
 use std::clone::Clone;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::server::TlsStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 
+pub mod admin;
+pub mod admission;
+pub mod audit;
+pub mod auto_headers;
+pub mod background;
+pub mod body;
+pub mod channel_pool;
+pub mod client;
+pub mod config;
+pub mod conn_handler;
+pub mod context;
+pub mod drain;
+pub mod error_pages;
 pub mod errors;
+pub mod handlers;
 mod hyper_config;
+pub mod informational;
+pub mod limits;
+pub mod listeners;
+pub mod log_sink;
+pub mod metering;
+pub mod negotiation;
+#[cfg(feature = "plugins")]
+pub mod plugins;
 pub mod protocols;
+pub mod range;
+pub mod recorder;
+pub mod reload;
 pub mod request;
 pub mod response;
+mod runtime;
+pub mod services;
 pub mod stack;
+pub mod state;
+pub mod static_stack;
+pub mod stats;
+pub mod tarpit;
+pub mod tenancy;
+pub mod tls_fingerprint;
+pub mod tower_compat;
+mod util;
+pub mod waf;
+pub mod webhooks;
+
+// rustls used by HttpProtocolConf::HTTPSConfig
+use self::admin::AdminServer;
+use self::background::{BackgroundJob, BackgroundTasks};
+use self::config::{RhodConfig, ServerSettings};
+use self::conn_handler::RhodConnHandler;
+use self::drain::Drain;
 use self::errors::RhodHyperError; //Server errors (Hyper errors, bad certificates, etc)
+use self::errors::RhodResult;
 use self::hyper_config::*;
 use self::protocols::*;
+use self::reload::{ConfigReloader, LiveSettings, TlsListenerConf};
 use self::request::*;
 use self::stack::*;
+use self::state::StateMap;
+use self::stats::ServerStats;
+use self::tls_fingerprint::TlsFingerprint;
+pub use mime;
+pub use tokio_rustls::rustls;
 
 // =====================================================================
 // ||          Structs to share information between handlers          ||
@@ -57,18 +114,44 @@ use self::stack::*;
 pub struct RhodConnInfo {
     pub addr: SocketAddr,
     pub proto: HttpProtocol,
+    pub tls_fingerprint: Option<Arc<TlsFingerprint>>, // of the ClientHello, on HTTPS connections
 }
 
 impl RhodConnInfo {
     pub fn new(addr: SocketAddr, proto: HttpProtocol) -> RhodConnInfo {
-        RhodConnInfo { addr, proto }
+        RhodConnInfo {
+            addr,
+            proto,
+            tls_fingerprint: None,
+        }
+    }
+
+    pub fn with_tls_fingerprint(self, tls_fingerprint: Arc<TlsFingerprint>) -> Self {
+        RhodConnInfo {
+            tls_fingerprint: Some(tls_fingerprint),
+            ..self
+        }
     }
 }
 
 // A generic type that implements the CommunicationChannel trait will be used for communication between handlers and the service
 // Users of the library have to define their CommunicationChannel type, which have to implement this trait.
-pub trait CommunicationChannel: Send + Sync + 'static {
+// try_new is called for every request, and can be overriden to acquire per-request resources (lease a connection, read a session).
+// If it fails, the error is handled like an error returned by a handler: catch_request functions are called for
+// every handler (with a channel created by new), and then the flow is ended.
+#[async_trait]
+pub trait CommunicationChannel: Sized + Send + Sync + 'static {
     fn new() -> Self;
+
+    async fn try_new(_conn: &RhodConnInfo, _req: &RhodRequest) -> RhodResult<Self> {
+        Ok(Self::new())
+    }
+
+    // Clears the channel for another request, in stacks with a channel pool (RhodStack::with_channel_pool).
+    // Reused channels dont go through try_new again. Returning false drops the channel instead
+    fn reset(&mut self) -> bool {
+        false
+    }
 }
 
 // ==============================
@@ -77,11 +160,25 @@ pub trait CommunicationChannel: Send + Sync + 'static {
 
 // Rhodium: has all information needed to run a server
 pub struct Rhodium<C: CommunicationChannel> {
-    stack: Arc<RhodStack<C>>,   // stack of handlers and the service to execute
+    live: Arc<LiveSettings<C>>, // stack and connection tuning, replaced by config reloads
     addr: SocketAddr,           // address to listen
     protocol: HttpProtocolConf, // use http or https
+    socket_conf: SocketConf,    // listening socket options
+    tls_config: TlsConfig,      // tls versions and cipher suites (HTTPS)
+    acceptors: usize,           // listeners bound with SO_REUSEPORT, each accepting in its own task
+    inherited: Vec<std::net::TcpListener>, // served instead of binding (see listeners)
+    background: Mutex<Vec<(String, BackgroundJob)>>, // jobs spawned by run (the mutex keeps Rhodium Sync)
+    reloader: Option<Arc<ConfigReloader<C>>>,        // set when created from a config file
+    stats: Arc<ServerStats>,
+    admin: Option<AdminServer>, // started by run
+    drain: Drain,
+    hyper_builder: Option<CustomizeBuilder>, // applied to the connection builders
+    conn_handlers: Vec<Arc<dyn RhodConnHandler>>,
 }
 
+// Customization of the hyper connection builders (see with_hyper_builder)
+type CustomizeBuilder = Arc<dyn Fn(HyperServerBuilder) -> HyperServerBuilder + Send + Sync>;
+
 impl<C: CommunicationChannel> Rhodium<C> {
     pub fn new(
         stack: Arc<RhodStack<C>>,
@@ -89,98 +186,423 @@ impl<C: CommunicationChannel> Rhodium<C> {
         protocol: HttpProtocolConf,
     ) -> Rhodium<C> {
         Rhodium {
-            stack,
+            live: Arc::new(LiveSettings::new(stack)),
             addr,
             protocol,
+            socket_conf: SocketConf::default(),
+            tls_config: TlsConfig::default(),
+            acceptors: 1,
+            inherited: vec![],
+            background: Mutex::new(vec![]),
+            reloader: None,
+            stats: Arc::new(ServerStats::new()),
+            admin: None,
+            drain: Drain::new(),
+            hyper_builder: None,
+            conn_handlers: vec![],
+        }
+    }
+
+    // Runs the job while the server runs: it is spawned by run, and cancelled when run ends or is dropped
+    pub fn spawn_background(&mut self, name: &str, job: BackgroundJob) {
+        self.background
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((name.to_string(), job));
+    }
+
+    // Server settings and built-in handlers read from a config file (see config), followed by the service.
+    // The config can be reloaded while the server runs (see reloader)
+    pub fn from_config<P: AsRef<Path>>(
+        path: P,
+        service: Box<dyn RhodService<C>>,
+    ) -> Result<Rhodium<C>, RhodHyperError> {
+        let config = RhodConfig::from_file(&path)?;
+        if let Some(level) = config.log.level_filter()? {
+            log::set_max_level(level);
+        }
+        let service: Arc<dyn RhodService<C>> = Arc::from(service);
+        let state = Arc::new(StateMap::new());
+        let stack = ConfigReloader::stack(&config, &service, &state);
+        let mut rhod = Rhodium::configured(Arc::new(stack), &config.server);
+        rhod.reloader = Some(Arc::new(ConfigReloader::new(
+            path.as_ref().to_path_buf(),
+            service,
+            state,
+            Arc::clone(&rhod.live),
+            config,
+        )));
+        Ok(rhod)
+    }
+
+    // Switch of the maintenance mode, answering new requests with 503 while the in flight ones finish
+    pub fn drain(&self) -> Drain {
+        self.drain.clone()
+    }
+
+    // Drain with custom Retry-After or exempt paths
+    pub fn with_drain(self, drain: Drain) -> Self {
+        Rhodium { drain, ..self }
+    }
+
+    // Connection and request counters
+    pub fn stats(&self) -> Arc<ServerStats> {
+        Arc::clone(&self.stats)
+    }
+
+    // Reloads the config file of a server created with from_config
+    pub fn reloader(&self) -> Option<Arc<ConfigReloader<C>>> {
+        self.reloader.clone()
+    }
+
+    // Reloads the config file on SIGHUP while the server runs. Failed reloads are logged
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&mut self) {
+        let reloader = match self.reloader() {
+            Some(reloader) => reloader,
+            None => {
+                warn!("Only servers created from a config file can be reloaded");
+                return;
+            }
+        };
+        let job = BackgroundJob::once(async move {
+            let mut hangups = match runtime::hangups() {
+                Ok(hangups) => hangups,
+                Err(e) => return error!("Couldnt listen for SIGHUP. {}", e),
+            };
+            while hangups.next().await.is_some() {
+                if let Err(e) = reloader.reload() {
+                    error!("Configuration not reloaded. {}", e);
+                }
+            }
+        });
+        self.spawn_background("sighup reload", job);
+    }
+
+    // Server settings of a config file, serving a stack built in code
+    pub fn configured(stack: Arc<RhodStack<C>>, settings: &ServerSettings) -> Rhodium<C> {
+        Rhodium::new(stack, settings.listen, settings.protocol())
+            .with_connection_conf(settings.connection_conf())
+            .with_socket_conf(settings.socket_conf())
+            .with_tls_config(settings.tls_config())
+            .with_acceptors(settings.acceptors)
+    }
+
+    // Serves the admin endpoints (see admin) while the server runs
+    pub fn with_admin(self, admin: AdminServer) -> Self {
+        Rhodium {
+            admin: Some(admin),
+            ..self
+        }
+    }
+
+    pub fn with_connection_conf(self, conn_conf: ConnectionConf) -> Self {
+        self.live.conn_conf.store(Arc::new(conn_conf));
+        self
+    }
+
+    // Sets hyper options not wrapped by ConnectionConf, for HTTP and HTTPS connections.
+    // It is called after applying the ConnectionConf, and again when a config reload changes it
+    pub fn with_hyper_builder<F>(self, customize: F) -> Self
+    where
+        F: Fn(HyperServerBuilder) -> HyperServerBuilder + Send + Sync + 'static,
+    {
+        Rhodium {
+            hyper_builder: Some(Arc::new(customize)),
+            ..self
+        }
+    }
+
+    // Accepts connections with n listeners bound to the same address (forces SO_REUSEPORT)
+    pub fn with_acceptors(self, acceptors: usize) -> Self {
+        Rhodium { acceptors, ..self }
+    }
+
+    // Serves listeners already bound, ie: inherited from systemd or the previous process (see listeners),
+    // instead of binding addr. Each one is accepted in its own task
+    pub fn with_listeners(self, inherited: Vec<std::net::TcpListener>) -> Self {
+        Rhodium { inherited, ..self }
+    }
+
+    // Adds a handler called on every accepted connection (see conn_handler), after the ones already added
+    pub fn with_conn_handler(mut self, handler: Box<dyn RhodConnHandler>) -> Self {
+        self.conn_handlers.push(Arc::from(handler));
+        self
+    }
+
+    pub fn with_tls_config(self, tls_config: TlsConfig) -> Self {
+        Rhodium { tls_config, ..self }
+    }
+
+    pub fn with_socket_conf(self, socket_conf: SocketConf) -> Self {
+        Rhodium {
+            socket_conf,
+            ..self
         }
     }
 
     //Creates hyper server that runs the rhodium stack
-    pub async fn run(self) -> Result<(), RhodHyperError> {
+    pub async fn run(mut self) -> Result<(), RhodHyperError> {
         println!("Listening on {}://{}", self.protocol.to_string(), self.addr);
         info!("Listening on {}://{}", self.protocol.to_string(), self.addr);
 
-        match &self.protocol {
-            HttpProtocolConf::HTTP => {
-                match AddrIncoming::bind(&self.addr) {
-                    Ok(addr_incoming) => {
-                        let builder = HyperServer::builder(addr_incoming);
-
-                        // creating a service factory.
-                        // for each request, it will return a RhodHyperService with the rhodium stack, and the connection info (source addr + protocol used)
-                        let mk_service = hyper::service::make_service_fn(|socket: &AddrStream| {
-                            let stack = Arc::clone(&self.stack);
-                            let addr = socket.remote_addr();
-                            async move {
-                                Ok::<_, RhodHyperError>(RhodHyperService::new(
-                                    stack,
-                                    RhodConnInfo::new(addr, HttpProtocol::HTTP),
-                                ))
-                            }
-                        });
+        let mut listeners = Vec::with_capacity(self.acceptors.max(1));
+        for inherited in std::mem::take(&mut self.inherited) {
+            let tcp = inherited
+                .set_nonblocking(true)
+                .and_then(|_| TcpListener::from_std(inherited));
+            match tcp {
+                Ok(tcp) => listeners.push(tcp),
+                Err(e) => {
+                    return Err(RhodHyperError::ConfigError(format!(
+                        "Error when using an inherited listener. {}",
+                        e
+                    )))
+                }
+            }
+        }
 
-                        // starts a server with the created service factory
-                        // wrapps the Hyper result in a Rhod Hyper result
-                        RhodHyperError::from_hyper_error_result(builder.serve(mk_service).await)
-                    }
-                    Err(e) => Err(RhodHyperError::ConfigError(format!(
-                        "Error when binding (HTTP). {}",
+        // with many acceptors, every one binds its own listener to the same address
+        let bound = if listeners.is_empty() {
+            self.acceptors.max(1)
+        } else {
+            0
+        };
+        let socket_conf = if bound > 1 {
+            self.socket_conf.clone().with_reuse_port(true)
+        } else {
+            self.socket_conf.clone()
+        };
+        for _ in 0..bound {
+            match socket_conf.bind(&self.addr) {
+                Ok(tcp) => listeners.push(tcp),
+                Err(e) => {
+                    return Err(RhodHyperError::ConfigError(format!(
+                        "Error when binding ({}). {}",
+                        self.protocol.to_string().to_uppercase(),
                         e
-                    ))),
+                    )))
+                }
+            }
+        }
+        let acceptors = listeners.len();
+
+        if self.protocol != HttpProtocolConf::HTTP {
+            let conn_conf = self.live.conn_conf.load();
+            let server_config = get_configuration(&self.protocol, &self.tls_config, &conn_conf)
+                .map_err(|e| {
+                    RhodHyperError::ConfigError(format!("Error when creating TLS Acceptor. {}", e))
+                })?;
+            let handshake_timeout = conn_conf.tls_handshake_timeout;
+            *self.live.tls.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(TlsListenerConf::shared(server_config, handshake_timeout));
+        }
+
+        let mut jobs = std::mem::take(self.background.get_mut().unwrap_or_else(|e| e.into_inner()));
+        if let Some(admin) = self.admin.take() {
+            let job = admin.start(
+                Arc::clone(&self.live),
+                Arc::clone(&self.stats),
+                self.drain(),
+                self.reloader(),
+            )?;
+            jobs.push(("admin server".to_string(), job));
+        }
+        let _background = BackgroundTasks::spawn(jobs);
+
+        if acceptors == 1 {
+            return self.serve(listeners.remove(0)).await;
+        }
+
+        // each acceptor runs in its own task, sharing the stack
+        let rhod = Arc::new(self);
+        let tasks = listeners.into_iter().map(|tcp| {
+            let rhod = Arc::clone(&rhod);
+            let (result, served) = tokio::sync::oneshot::channel();
+            runtime::spawn(async move {
+                let _ = result.send(rhod.serve(tcp).await);
+            });
+            served
+        });
+        for result in futures_util::future::join_all(tasks).await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    return Err(RhodHyperError::ConfigError(
+                        "Acceptor task failed".to_string(),
+                    ))
                 }
             }
-            HttpProtocolConf::HTTPS {
-                cert_file,
-                key_file,
-            } => {
-                // Create a TCP listener via tokio.
-                match TcpListener::bind(&self.addr).await {
-                    Ok(tcp) => match HyperTlsAcceptor::new(tcp, &cert_file, &key_file) {
-                        Ok(tls_acceptor) => {
-                            let builder = HyperServer::builder(tls_acceptor);
-
-                            // creating a service factory.
-                            // for each request, it will return a RhodHyperService with the rhodium stack, and the connection info (source addr + protocol used)
-                            let mk_service =
-                                hyper::service::make_service_fn(|stream: &TlsStream<TcpStream>| {
-                                    let stack = Arc::clone(&self.stack);
-                                    let addr = stream.get_ref().0.peer_addr();
-                                    async move {
-                                        match addr {
-                                            Ok(peer_addr) => {
-                                                Ok::<_, RhodHyperError>(RhodHyperService::new(
-                                                    stack,
-                                                    RhodConnInfo::new(
-                                                        peer_addr,
-                                                        HttpProtocol::HTTPS,
-                                                    ),
-                                                ))
-                                            }
-                                            Err(e) => Err::<RhodHyperService<C>, RhodHyperError>(
-                                                RhodHyperError::ConfigError(format!(
-                                                    "Couldnt parse client IP. {}",
-                                                    e
-                                                )),
-                                            ),
-                                        }
-                                    }
-                                });
-
-                            // starts a server with the created service factory
-                            // wrapps the Hyper result in a Rhod Hyper result
-                            RhodHyperError::from_hyper_error_result(builder.serve(mk_service).await)
+        }
+        Ok(())
+    }
+
+    // Serves the stack on a bound listener
+    async fn serve(&self, tcp: TcpListener) -> Result<(), RhodHyperError> {
+        match self.live.tls() {
+            None => {
+                let mut builder =
+                    ConnBuilder::new(self.live.conn_conf.load(), true, self.hyper_builder.clone());
+                loop {
+                    let (stream, addr) = match tcp.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // usually too many open files, wait for some connections to close
+                            error!("Error when accepting TCP connection. {}", e);
+                            self.stats.accept_error();
+                            runtime::sleep(Duration::from_secs(1)).await;
+                            continue;
                         }
-                        Err(e) => Err(RhodHyperError::ConfigError(format!(
-                            "Error when creating TLS Acceptor. {}",
-                            e
-                        ))),
-                    },
-                    Err(e) => Err(RhodHyperError::ConfigError(format!(
-                        "Error when binding (HTTPS). {}",
-                        e
-                    ))),
+                    };
+                    if let Err(e) = stream.set_nodelay(self.socket_conf.nodelay) {
+                        warn!("Couldnt set TCP_NODELAY for {}. {}", addr, e);
+                    }
+                    builder.refresh(self.live.conn_conf.load());
+                    self.serve_connection(
+                        &builder,
+                        stream,
+                        RhodConnInfo::new(addr, HttpProtocol::HTTP),
+                    );
                 }
             }
+            Some(tls) => match HyperTlsAcceptor::new(tcp, tls, Arc::clone(&self.stats)) {
+                Ok(mut tls_acceptor) => {
+                    let mut builder = ConnBuilder::new(
+                        self.live.conn_conf.load(),
+                        false,
+                        self.hyper_builder.clone(),
+                    );
+                    while let Some(stream) = tls_acceptor.accept().await {
+                        let recorder = stream.get_ref().0;
+                        match recorder.get_ref().peer_addr() {
+                            Ok(addr) => {
+                                let mut conn = RhodConnInfo::new(addr, HttpProtocol::HTTPS);
+                                if let Some(fingerprint) = recorder.fingerprint() {
+                                    conn = conn.with_tls_fingerprint(fingerprint);
+                                }
+                                builder.refresh(self.live.conn_conf.load());
+                                self.serve_connection(&builder, stream, conn)
+                            }
+                            Err(e) => warn!("Couldnt parse client IP. {}", e),
+                        }
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(RhodHyperError::ConfigError(format!(
+                    "Error when creating TLS Acceptor. {}",
+                    e
+                ))),
+            },
+        }
+    }
+
+    // Serves the stack on a unix socket (the admin server), its clients are local
+    #[cfg(unix)]
+    async fn serve_unix(&self, listener: UnixListener) -> Result<(), RhodHyperError> {
+        let local = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut builder =
+            ConnBuilder::new(self.live.conn_conf.load(), true, self.hyper_builder.clone());
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Error when accepting unix connection. {}", e);
+                    self.stats.accept_error();
+                    runtime::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            builder.refresh(self.live.conn_conf.load());
+            self.serve_connection(
+                &builder,
+                stream,
+                RhodConnInfo::new(local, HttpProtocol::HTTP),
+            );
+        }
+    }
+
+    // Serves the requests of an accepted connection in its own task
+    fn serve_connection<S>(&self, builder: &ConnBuilder, stream: S, conn: RhodConnInfo)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let stream = RhodConn::new(stream, builder.conf.idle_timeout);
+        let state = stream.state();
+        let service = RhodHyperService::new(
+            Arc::clone(&self.live),
+            Arc::clone(&self.stats),
+            self.drain.clone(),
+            conn.clone(),
+            stream.state(),
+        );
+        let builder = Arc::clone(&builder.builder);
+        let counted = self.stats.connection();
+        let conn_handlers = self.conn_handlers.clone();
+        runtime::spawn(async move {
+            let _counted = counted;
+            let started = Instant::now();
+            if Self::connect(&conn_handlers, &conn).await {
+                // upgrades are needed by CONNECT tunnels
+                let served = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                if let Err(e) = served.await {
+                    debug!("Error when serving connection. {}", e);
+                }
+            }
+            if !conn_handlers.is_empty() {
+                let stats = state.stats(started.elapsed());
+                for handler in &conn_handlers {
+                    handler.on_disconnect(&conn, &stats).await;
+                }
+            }
+        });
+    }
+
+    // Calls on_connect on the connection handlers, false if one of them refuses the connection
+    async fn connect(conn_handlers: &[Arc<dyn RhodConnHandler>], conn: &RhodConnInfo) -> bool {
+        for handler in conn_handlers {
+            if let Err(e) = handler.on_connect(conn).await {
+                debug!("{} refused the connection of {}", handler.name(), conn.addr);
+                e.log();
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Hyper builder of the connection conf, rebuilt when the conf is reloaded
+struct ConnBuilder {
+    conf: Arc<ConnectionConf>,
+    builder: Arc<HyperServerBuilder>,
+    plain: bool, // plain HTTP listener
+    customize: Option<CustomizeBuilder>,
+}
+
+impl ConnBuilder {
+    fn new(
+        conf: Arc<ConnectionConf>,
+        plain: bool,
+        customize: Option<CustomizeBuilder>,
+    ) -> ConnBuilder {
+        let mut builder = conf.builder();
+        if plain && conf.h2c == Some(false) {
+            builder = builder.http1_only();
+        }
+        if let Some(customize) = &customize {
+            builder = customize(builder);
+        }
+        ConnBuilder {
+            conf,
+            builder: Arc::new(builder),
+            plain,
+            customize,
+        }
+    }
+
+    fn refresh(&mut self, conf: Arc<ConnectionConf>) {
+        if !Arc::ptr_eq(&self.conf, &conf) {
+            *self = ConnBuilder::new(conf, self.plain, self.customize.take());
         }
     }
 }
@@ -0,0 +1,229 @@
+// Limits of the responses produced by the service of a stack (ie: a proxied upstream), see
+// RhodStack::with_response_limits. A service that takes longer than the timeout is answered with 504,
+// and a body known to be larger than the maximum with 502: both flow back through the handlers as a
+// failed service with a response. Bodies streamed without a known size are cut when they cross the
+// maximum or the timeout, since their head was already sent.
+// The timeout is also the Deadline of the request, so upstream calls made by the service dont outlive it.
+use crate::body::{BoxError, RhodBody};
+use crate::context::RhodContext;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::response::RhodResponse;
+use crate::runtime::{self, Sleep};
+use bytes::Bytes;
+use http::StatusCode;
+use http_body::{Body, Frame, SizeHint};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// Time a request must be answered by, in the request context. The executor sets it from the
+// response timeout, and RhodClient limits the upstream calls sent with the request to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline(Instant::now() + timeout)
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    // Sets the deadline in the context, unless there is an earlier one
+    pub fn tighten(self, context: &RhodContext) {
+        match context.get::<Deadline>() {
+            Some(current) if current.0 <= self.0 => (),
+            _ => {
+                context.insert(self);
+            }
+        }
+    }
+}
+
+// Duration as a grpc-timeout header value (at most 8 digits), in the largest unit keeping the precision
+pub fn grpc_timeout(timeout: Duration) -> String {
+    let millis = timeout.as_millis();
+    if millis < 100_000_000 {
+        format!("{}m", millis)
+    } else {
+        format!("{}S", timeout.as_secs().min(99_999_999))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResponseLimits {
+    max_body_size: Option<u64>,
+    timeout: Option<Duration>, // from calling the service until the end of the body
+}
+
+impl ResponseLimits {
+    pub fn new() -> ResponseLimits {
+        ResponseLimits::default()
+    }
+
+    pub fn with_max_body_size(self, max_body_size: u64) -> Self {
+        ResponseLimits {
+            max_body_size: Some(max_body_size),
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        ResponseLimits {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    // Waits for the service response, enforcing the limits
+    pub(crate) async fn serve<F>(&self, served: F) -> RhodResult<RhodResponse>
+    where
+        F: Future<Output = RhodResult<RhodResponse>>,
+    {
+        if *self == ResponseLimits::default() {
+            return served.await;
+        }
+        let start = Instant::now();
+        let mut res = match self.timeout {
+            Some(timeout) => match runtime::timeout(timeout, served).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    return Err(exceeded(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        format!("Service didnt answer in {:?}", timeout),
+                    ))
+                }
+            },
+            None => served.await?,
+        };
+
+        if let (Some(max), Some(size)) = (self.max_body_size, res.body_size_hint()) {
+            if size > max {
+                return Err(exceeded(
+                    StatusCode::BAD_GATEWAY,
+                    format!(
+                        "Service response of {} bytes, over the limit of {}",
+                        size, max
+                    ),
+                ));
+            }
+            // the size is known and allowed, only the time is limited
+            if self.timeout.is_none() {
+                return Ok(res);
+            }
+        }
+        let deadline = self
+            .timeout
+            .map(|timeout| runtime::sleep(timeout.saturating_sub(start.elapsed())));
+        let body = LimitedBody {
+            inner: res.take_body(),
+            left: self.max_body_size,
+            deadline,
+        };
+        res.set_body(RhodBody::new(body));
+        Ok(res)
+    }
+}
+
+fn exceeded(status: StatusCode, msg: String) -> RhodError {
+    RhodError::with_status(status, msg, RhodErrorLevel::Warning)
+}
+
+// Body failing when it exceeds the size left or the deadline
+struct LimitedBody {
+    inner: RhodBody,
+    left: Option<u64>,
+    deadline: Option<Sleep>,
+}
+
+impl Body for LimitedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if let Some(deadline) = self.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Some(Err("Service response body timed out".into())));
+            }
+        }
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            other => return other,
+        };
+        let size = frame.data_ref().map_or(0, |data| data.len() as u64);
+        if let Some(left) = self.left.as_mut() {
+            if size > *left {
+                return Poll::Ready(Some(Err("Service response body too large".into())));
+            }
+            *left -= size;
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    fn streamed(chunks: Vec<&'static str>) -> RhodResult<RhodResponse> {
+        let mut res = RhodResponse::builder().build()?;
+        let chunks = stream::iter(chunks.into_iter().map(Ok::<_, BoxError>));
+        res.set_body(RhodBody::wrap_stream(chunks));
+        Ok(res)
+    }
+
+    #[tokio::test]
+    async fn test_response_limits() {
+        let limits = ResponseLimits::new()
+            .with_max_body_size(4)
+            .with_timeout(Duration::from_millis(50));
+
+        let res = limits
+            .serve(async { RhodResponse::builder().body_str("hello").build() })
+            .await;
+        let mut err = res.unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 502);
+
+        let res = limits
+            .serve(async {
+                runtime::sleep(Duration::from_millis(200)).await;
+                RhodResponse::builder().build()
+            })
+            .await;
+        let mut err = res.unwrap_err();
+        assert_eq!(err.take_response().unwrap().status_as_int(), 504);
+
+        let mut res = limits
+            .serve(async { streamed(vec!["ab", "cd"]) })
+            .await
+            .unwrap();
+        assert_eq!(res.body().await.unwrap(), b"abcd");
+        let mut res = limits
+            .serve(async { streamed(vec!["ab", "cde"]) })
+            .await
+            .unwrap();
+        assert!(res.body().await.is_err());
+    }
+}
@@ -0,0 +1,111 @@
+// Listening sockets inherited from another process, so the server can be upgraded without closing its port:
+// - systemd socket activation (LISTEN_FDS)
+// - a handover from the previous Rhodium process: it binds its listener (SocketConf::bind_listener),
+//   marks it inheritable with handover_env, and starts the new binary with that environment variable.
+//   The new process takes the listener with inherited, and the old one drains and exits (see drain).
+// Inherited listeners are served with Rhodium::with_listeners.
+#[cfg(unix)]
+use socket2::{SockRef, Type};
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+// Environment variable with the descriptor handed over by the previous process
+pub const HANDOVER_ENV: &str = "RHODIUM_LISTEN_FD";
+
+// First descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Listeners passed by systemd socket activation. Empty if the process wasnt activated by a socket
+#[cfg(unix)]
+pub fn systemd() -> io::Result<Vec<TcpListener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = match std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+    {
+        Some(count) if for_us => count,
+        _ => return Ok(vec![]),
+    };
+    // the descriptors arent passed to our children
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(take_fd)
+        .collect()
+}
+
+// Listener handed over by the previous process, if any
+#[cfg(unix)]
+pub fn inherited() -> io::Result<Option<TcpListener>> {
+    let fd = match std::env::var(HANDOVER_ENV) {
+        Ok(fd) => fd.parse::<RawFd>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isnt a file descriptor", HANDOVER_ENV),
+            )
+        })?,
+        Err(_) => return Ok(None),
+    };
+    std::env::remove_var(HANDOVER_ENV);
+    take_fd(fd).map(Some)
+}
+
+// Lets processes started by this one inherit the listener, returning the variable to add to their environment
+#[cfg(unix)]
+pub fn handover_env(listener: &TcpListener) -> io::Result<(&'static str, String)> {
+    SockRef::from(listener).set_cloexec(false)?;
+    Ok((HANDOVER_ENV, listener.as_raw_fd().to_string()))
+}
+
+// Takes ownership of an inherited descriptor, checking it is a bound stream socket
+#[cfg(unix)]
+fn take_fd(fd: RawFd) -> io::Result<TcpListener> {
+    // the descriptor was given to this process to be used as a listener, and nothing else owns it
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    let socket = SockRef::from(&listener);
+    if socket.r#type()? != Type::STREAM || listener.local_addr().is_err() {
+        // not a TCP listener, the descriptor is left open for its real owner
+        std::mem::forget(listener);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Inherited descriptor {} isnt a TCP listener", fd),
+        ));
+    }
+    socket.set_cloexec(true)?;
+    Ok(listener)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handover() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (name, fd) = handover_env(&listener).unwrap();
+        assert_eq!(name, HANDOVER_ENV);
+
+        // as the new process would, with a duplicated descriptor standing for the inherited one
+        let dup = listener.try_clone().unwrap();
+        let dup_fd = dup.as_raw_fd();
+        std::mem::forget(dup);
+        assert_ne!(fd, dup_fd.to_string());
+        std::env::set_var(HANDOVER_ENV, dup_fd.to_string());
+        let taken = inherited().unwrap().unwrap();
+        assert_eq!(taken.local_addr().unwrap(), addr);
+        assert!(std::env::var(HANDOVER_ENV).is_err());
+        assert!(inherited().unwrap().is_none());
+
+        // not activated by systemd
+        assert!(systemd().unwrap().is_empty());
+    }
+}
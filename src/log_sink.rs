@@ -0,0 +1,294 @@
+// Destinations of the structured records written by the built-in handlers (access log, audit log, WAF
+// matches), so deployments route them without patching each handler. A record is a JSON object.
+// Provided sinks: rolling files (one JSON per line), JSON lines on stdout, and syslog (RFC 5424).
+use async_trait::async_trait;
+use serde_json::Value;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[async_trait]
+pub trait LogSink: Send + Sync {
+    async fn write(&self, record: &Value) -> io::Result<()>;
+
+    // Makes the records written durable (ie: fsync). Sinks without buffering dont need it
+    async fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Appends records to a file, rotating it to path.1, path.2, ... when it would grow over a maximum size
+pub struct RollingFileSink {
+    path: PathBuf,
+    max_size: Option<u64>,
+    keep: usize,              // rotated files kept
+    file: Mutex<(File, u64)>, // file and its size
+}
+
+impl RollingFileSink {
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<RollingFileSink> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(RollingFileSink {
+            path,
+            max_size: None,
+            keep: 0,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    pub fn with_rotation(self, max_size: u64, keep: usize) -> Self {
+        RollingFileSink {
+            max_size: Some(max_size),
+            keep,
+            ..self
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // path.n-1 -> path.n, ..., path -> path.1
+    async fn rotate(&self, file: &mut File) -> io::Result<()> {
+        file.sync_data().await?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if tokio::fs::metadata(rotated(n)).await.is_ok() {
+                    tokio::fs::rename(rotated(n), rotated(n + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, rotated(1)).await?;
+        }
+        *file = open_append(&self.path).await?;
+        Ok(())
+    }
+}
+
+async fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+#[async_trait]
+impl LogSink for RollingFileSink {
+    async fn write(&self, record: &Value) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut guard = self.file.lock().await;
+        let (file, size) = &mut *guard;
+        if let Some(max_size) = self.max_size {
+            if *size > 0 && *size + line.len() as u64 > max_size {
+                self.rotate(file).await?;
+                *size = 0;
+            }
+        }
+        file.write_all(&line).await?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.file.lock().await.0.sync_data().await
+    }
+}
+
+// Writes records to stdout, one JSON per line (ie: collected by a container runtime)
+#[derive(Default)]
+pub struct StdoutJsonSink {
+    stdout: Mutex<()>, // lines of concurrent writes arent interleaved
+}
+
+impl StdoutJsonSink {
+    pub fn new() -> StdoutJsonSink {
+        StdoutJsonSink::default()
+    }
+}
+
+#[async_trait]
+impl LogSink for StdoutJsonSink {
+    async fn write(&self, record: &Value) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let _lock = self.stdout.lock().await;
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(&line).await?;
+        stdout.flush().await
+    }
+}
+
+enum SyslogTransport {
+    Udp(tokio::net::UdpSocket),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixDatagram),
+}
+
+// Sends records as RFC 5424 messages with the JSON as message, to a syslog server (UDP) or the local
+// daemon (unix socket, ie: /dev/log). Records are sent with severity informational
+pub struct SyslogSink {
+    transport: SyslogTransport,
+    app_name: String,
+    hostname: String,
+    facility: u8,
+}
+
+impl SyslogSink {
+    pub async fn udp(server: SocketAddr, app_name: &str) -> io::Result<SyslogSink> {
+        let local: SocketAddr = if server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = tokio::net::UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+        Ok(SyslogSink::with_transport(
+            SyslogTransport::Udp(socket),
+            app_name,
+        ))
+    }
+
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P, app_name: &str) -> io::Result<SyslogSink> {
+        let socket = tokio::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(SyslogSink::with_transport(
+            SyslogTransport::Unix(socket),
+            app_name,
+        ))
+    }
+
+    fn with_transport(transport: SyslogTransport, app_name: &str) -> SyslogSink {
+        SyslogSink {
+            transport,
+            app_name: app_name.to_string(),
+            hostname: std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string()),
+            facility: 16, // local0
+        }
+    }
+
+    pub fn with_facility(self, facility: u8) -> Self {
+        SyslogSink { facility, ..self }
+    }
+
+    fn message(&self, record: &Value) -> io::Result<Vec<u8>> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let header = format!(
+            "<{}>1 {} {} {} {} - - ",
+            self.facility as u32 * 8 + 6,
+            iso8601(millis),
+            self.hostname,
+            self.app_name,
+            std::process::id()
+        );
+        let mut message = header.into_bytes();
+        message.extend(serde_json::to_vec(record)?);
+        Ok(message)
+    }
+}
+
+#[async_trait]
+impl LogSink for SyslogSink {
+    async fn write(&self, record: &Value) -> io::Result<()> {
+        let message = self.message(record)?;
+        match &self.transport {
+            SyslogTransport::Udp(socket) => socket.send(&message).await?,
+            #[cfg(unix)]
+            SyslogTransport::Unix(socket) => socket.send(&message).await?,
+        };
+        Ok(())
+    }
+}
+
+// Days since 1970-01-01 of a civil date, and back (H. Hinnant)
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Unix millis as an ISO 8601 date (UTC)
+pub(crate) fn iso8601(millis: u64) -> String {
+    let secs = millis / 1000;
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_log_sinks() {
+        let path = std::env::temp_dir().join(format!("rhodium-sink-{}", std::process::id()));
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        let sink = RollingFileSink::open(&path)
+            .await
+            .unwrap()
+            .with_rotation(20, 1);
+        for n in 0..3 {
+            sink.write(&json!({ "n": n })).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap(),
+            "{\"n\":0}\n{\"n\":1}\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"n\":2}\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sink = SyslogSink::udp(server.local_addr().unwrap(), "rhodium")
+            .await
+            .unwrap();
+        sink.write(&json!({ "kind": "test" })).await.unwrap();
+        let mut buf = [0; 512];
+        let read = server.recv(&mut buf).await.unwrap();
+        let message = String::from_utf8_lossy(&buf[..read]);
+        assert!(message.starts_with("<134>1 "));
+        assert!(message.contains(" rhodium "));
+        assert!(message.ends_with(" - - {\"kind\":\"test\"}"));
+        assert_eq!(iso8601(1_700_000_000_123), "2023-11-14T22:13:20.123Z");
+    }
+}
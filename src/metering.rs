@@ -0,0 +1,569 @@
+// Usage accounting for billing and quotas. A MeteringHandler counts the requests and the bytes (request
+// plus response bodies, as they stream) of every key (ie: tenant or API key) on a Metering, which:
+//  - enforces daily and monthly quotas (UTC calendar periods): requests of a key over one of its quotas
+//    are answered with 429 and Retry-After until the period ends
+//  - keeps the usage not yet flushed, written to a UsageSink (file, HTTP endpoint, redis) by flush_job.
+//    Records failing to be written are kept for the next flush
+//
+//  let metering = Arc::new(Metering::new().with_quota(Quota::daily().with_max_requests(10_000)).with_sink(sink));
+//  let handler = MeteringHandler::new(Arc::clone(&metering), Box::new(|_, req| req.header_str("x-api-key").map(String::from)));
+//  rhodium.spawn_background("usage flush", metering.flush_job(Duration::from_secs(60)));
+//
+// Usage is kept in memory: quotas are per process, and restarting starts the periods over.
+use crate::background::BackgroundJob;
+use crate::body::{map_body, RhodBody};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::log_sink::{civil_from_days, days_from_civil};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::RETRY_AFTER;
+use http::{HeaderMap, StatusCode};
+use http_body::Body;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod sink;
+#[cfg(feature = "redis-metering")]
+pub use sink::RedisUsageSink;
+pub use sink::{HttpUsageSink, LogUsageSink, UsageSink};
+
+// Key a request is metered by, None to leave it unmetered
+pub type UsageKey = Box<dyn Fn(&RhodConnInfo, &RhodRequest) -> Option<String> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+}
+
+impl Usage {
+    pub fn bytes(&self) -> u64 {
+        self.request_bytes + self.response_bytes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.requests == 0 && self.bytes() == 0
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+    }
+}
+
+// Usage of a key between two flushes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub key: String,
+    pub from: u64, // unix millis of the first request counted
+    pub to: u64,   // unix millis of the flush
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub period: QuotaPeriod,
+    pub max_requests: Option<u64>,
+    pub max_bytes: Option<u64>, // request plus response bodies
+}
+
+impl Quota {
+    pub fn daily() -> Quota {
+        Quota {
+            period: QuotaPeriod::Daily,
+            max_requests: None,
+            max_bytes: None,
+        }
+    }
+
+    pub fn monthly() -> Quota {
+        Quota {
+            period: QuotaPeriod::Monthly,
+            ..Quota::daily()
+        }
+    }
+
+    pub fn with_max_requests(self, max_requests: u64) -> Self {
+        Quota {
+            max_requests: Some(max_requests),
+            ..self
+        }
+    }
+
+    pub fn with_max_bytes(self, max_bytes: u64) -> Self {
+        Quota {
+            max_bytes: Some(max_bytes),
+            ..self
+        }
+    }
+
+    fn exceeded_by(&self, usage: &Usage) -> bool {
+        self.max_requests.is_some_and(|max| usage.requests >= max)
+            || self.max_bytes.is_some_and(|max| usage.bytes() >= max)
+    }
+}
+
+impl QuotaPeriod {
+    // Start and end (unix secs) of the period containing secs
+    fn bounds(&self, secs: u64) -> (u64, u64) {
+        let day = secs / 86400;
+        match self {
+            QuotaPeriod::Daily => (day * 86400, (day + 1) * 86400),
+            QuotaPeriod::Monthly => {
+                let (year, month, _) = civil_from_days(day as i64);
+                let (next_year, next_month) = match month {
+                    12 => (year + 1, 1),
+                    _ => (year, month + 1),
+                };
+                (
+                    days_from_civil(year, month, 1) as u64 * 86400,
+                    days_from_civil(next_year, next_month, 1) as u64 * 86400,
+                )
+            }
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Default)]
+struct KeyUsage {
+    day: (u64, Usage), // start of the period (unix secs) and its usage
+    month: (u64, Usage),
+    pending: Usage, // not flushed yet
+    since: u64,     // unix millis of the first pending request, 0 without pending usage
+}
+
+impl KeyUsage {
+    // Usage of the periods containing now, starting them over when they ended
+    fn periods(&mut self, now: u64) -> [(QuotaPeriod, &mut Usage); 2] {
+        let secs = now / 1000;
+        for (period, (start, usage)) in [
+            (QuotaPeriod::Daily, &mut self.day),
+            (QuotaPeriod::Monthly, &mut self.month),
+        ] {
+            let (current, _) = period.bounds(secs);
+            if *start != current {
+                *start = current;
+                *usage = Usage::default();
+            }
+        }
+        [
+            (QuotaPeriod::Daily, &mut self.day.1),
+            (QuotaPeriod::Monthly, &mut self.month.1),
+        ]
+    }
+
+    fn add(&mut self, now: u64, usage: &Usage) {
+        for (_, period) in self.periods(now) {
+            period.add(usage);
+        }
+        self.pending.add(usage);
+        if self.since == 0 {
+            self.since = now;
+        }
+    }
+}
+
+pub struct Metering {
+    quotas: Vec<Quota>,
+    key_quotas: HashMap<String, Vec<Quota>>,
+    sink: Option<Arc<dyn UsageSink>>,
+    usage: Mutex<HashMap<String, KeyUsage>>,
+    pruned: AtomicU64, // start of the month (unix secs) the keys were last pruned in
+}
+
+impl Metering {
+    pub fn new() -> Metering {
+        Metering {
+            quotas: vec![],
+            key_quotas: HashMap::new(),
+            sink: None,
+            usage: Mutex::new(HashMap::new()),
+            pruned: AtomicU64::new(0),
+        }
+    }
+
+    // Quota of the keys without quotas of their own
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quotas.push(quota);
+        self
+    }
+
+    // Quota of a key, replacing the default ones for it
+    pub fn with_key_quota(mut self, key: &str, quota: Quota) -> Self {
+        self.key_quotas
+            .entry(key.to_string())
+            .or_default()
+            .push(quota);
+        self
+    }
+
+    pub fn with_sink(self, sink: Arc<dyn UsageSink>) -> Self {
+        Metering {
+            sink: Some(sink),
+            ..self
+        }
+    }
+
+    // Usage of the key in the current period
+    pub fn usage(&self, key: &str, period: QuotaPeriod) -> Usage {
+        let mut usage = self.usage.lock().unwrap();
+        match usage.get_mut(key) {
+            Some(usage) => {
+                let [(_, day), (_, month)] = usage.periods(now_millis());
+                match period {
+                    QuotaPeriod::Daily => *day,
+                    QuotaPeriod::Monthly => *month,
+                }
+            }
+            None => Usage::default(),
+        }
+    }
+
+    // Forgets the keys not seen this month, their usage isnt needed by the quotas anymore. Keys with
+    // usage to flush are kept for the sink
+    fn prune(&self, keys: &mut HashMap<String, KeyUsage>, now: u64) {
+        let (month, _) = QuotaPeriod::Monthly.bounds(now / 1000);
+        self.pruned.store(month, Ordering::Relaxed);
+        let sink = self.sink.is_some();
+        keys.retain(|_, usage| usage.month.0 == month || (sink && !usage.pending.is_empty()));
+    }
+
+    // Counts a request of the key, or returns the secs until the end of the exceeded quota
+    fn admit(&self, key: &str) -> Result<(), u64> {
+        let now = now_millis();
+        let quotas = self.key_quotas.get(key).unwrap_or(&self.quotas);
+        let mut usage = self.usage.lock().unwrap();
+        // once a month, so the keys are pruned without a flush job too
+        let (month, _) = QuotaPeriod::Monthly.bounds(now / 1000);
+        if self.pruned.load(Ordering::Relaxed) != month {
+            self.prune(&mut usage, now);
+        }
+        let entry = usage.entry(key.to_string()).or_default();
+        for (period, usage) in entry.periods(now) {
+            let exceeded = quotas
+                .iter()
+                .find(|quota| quota.period == period && quota.exceeded_by(usage));
+            if exceeded.is_some() {
+                let (_, end) = period.bounds(now / 1000);
+                return Err(end.saturating_sub(now / 1000).max(1));
+            }
+        }
+        entry.add(
+            now,
+            &Usage {
+                requests: 1,
+                ..Usage::default()
+            },
+        );
+        Ok(())
+    }
+
+    fn add_bytes(&self, key: &str, bytes: u64, response: bool) {
+        if bytes == 0 {
+            return;
+        }
+        let usage = match response {
+            true => Usage {
+                response_bytes: bytes,
+                ..Usage::default()
+            },
+            false => Usage {
+                request_bytes: bytes,
+                ..Usage::default()
+            },
+        };
+        let mut keys = self.usage.lock().unwrap();
+        keys.entry(key.to_string())
+            .or_default()
+            .add(now_millis(), &usage);
+    }
+
+    // Writes the pending usage to the sink, returning the number of records written
+    pub async fn flush(&self) -> RhodResult<usize> {
+        let now = now_millis();
+        let sink = match &self.sink {
+            Some(sink) => sink,
+            None => {
+                self.prune(&mut self.usage.lock().unwrap(), now);
+                return Ok(0);
+            }
+        };
+        let records: Vec<UsageRecord> = {
+            let mut keys = self.usage.lock().unwrap();
+            let records = keys
+                .iter_mut()
+                .filter(|(_, usage)| !usage.pending.is_empty())
+                .map(|(key, usage)| {
+                    let record = UsageRecord {
+                        key: key.clone(),
+                        from: usage.since,
+                        to: now,
+                        usage: usage.pending,
+                    };
+                    usage.pending = Usage::default();
+                    usage.since = 0;
+                    record
+                })
+                .collect();
+            self.prune(&mut keys, now);
+            records
+        };
+        if records.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = sink.write(&records).await {
+            // kept for the next flush
+            let mut keys = self.usage.lock().unwrap();
+            for record in records {
+                let usage = keys.entry(record.key).or_default();
+                usage.pending.add(&record.usage);
+                usage.since = match usage.since {
+                    0 => record.from,
+                    since => since.min(record.from),
+                };
+            }
+            return Err(e);
+        }
+        Ok(records.len())
+    }
+
+    pub fn flush_job(self: &Arc<Self>, interval: Duration) -> BackgroundJob {
+        let metering = Arc::clone(self);
+        BackgroundJob::every(interval, move || {
+            let metering = Arc::clone(&metering);
+            async move {
+                if let Err(e) = metering.flush().await {
+                    warn!("Usage records couldnt be flushed, keeping them");
+                    e.log();
+                }
+            }
+        })
+    }
+}
+
+impl Default for Metering {
+    fn default() -> Metering {
+        Metering::new()
+    }
+}
+
+// Key of a metered request, saved in the request context
+struct MeteredKey(String);
+
+// Meters the requests on a Metering, rejecting the ones over quota with 429
+pub struct MeteringHandler {
+    metering: Arc<Metering>,
+    key: UsageKey,
+}
+
+impl MeteringHandler {
+    pub fn new(metering: Arc<Metering>, key: UsageKey) -> MeteringHandler {
+        MeteringHandler { metering, key }
+    }
+
+    // Counts the bytes of a body: right away when its size is known, else as it streams
+    fn meter(&self, key: &str, body: RhodBody, response: bool) -> RhodBody {
+        if let Some(size) = body.size_hint().exact() {
+            self.metering.add_bytes(key, size, response);
+            return body;
+        }
+        let metering = Arc::clone(&self.metering);
+        let key = key.to_string();
+        map_body(
+            body,
+            move |chunk: Bytes| {
+                metering.add_bytes(&key, chunk.len() as u64, response);
+                chunk
+            },
+            HeaderMap::new(),
+        )
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for MeteringHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let key = match (self.key)(conn, req) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        if let Err(retry_after) = self.metering.admit(&key) {
+            let msg = format!("Quota of {} exceeded", key);
+            let res = RhodResponse::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER.as_str(), &retry_after.to_string())
+                .body_str(&msg)
+                .build()?;
+            return Err(RhodError::from_string(msg, RhodErrorLevel::Warning).with_response(res));
+        }
+        let body = req.take_body();
+        req.set_body(self.meter(&key, body, false));
+        req.context().insert(MeteredKey(key));
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        if let Some(MeteredKey(key)) = res.context().remove::<MeteredKey>() {
+            let body = res.take_body();
+            res.set_body(self.meter(&key, body, true));
+        }
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[derive(Default)]
+    struct MemorySink {
+        records: Mutex<Vec<UsageRecord>>,
+    }
+
+    #[async_trait]
+    impl UsageSink for MemorySink {
+        async fn write(&self, records: &[UsageRecord]) -> RhodResult<()> {
+            self.records.lock().unwrap().extend_from_slice(records);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metering_handler() {
+        let sink = Arc::new(MemorySink::default());
+        let metering = Arc::new(
+            Metering::new()
+                .with_quota(Quota::daily().with_max_requests(2))
+                .with_key_quota("gold", Quota::monthly().with_max_bytes(1000))
+                .with_sink(Arc::clone(&sink) as Arc<dyn UsageSink>),
+        );
+        let handler = MeteringHandler::new(
+            Arc::clone(&metering),
+            Box::new(|_, req| req.header_str("x-api-key").map(String::from)),
+        );
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let handle = |key: &str| {
+            let mut req = RhodRequest::builder()
+                .header("x-api-key", key)
+                .body_str("ping")
+                .build()
+                .unwrap();
+            let (handler, conn) = (&handler, &conn);
+            async move {
+                RhodHandler::<()>::handle_request(handler, conn, &mut req, &mut ()).await?;
+                let mut res = RhodResponse::builder().body_str("pong!").build().unwrap();
+                res.attach_context(req.context());
+                let (mut res, _) =
+                    RhodHandler::<()>::handle_response(handler, conn, res, &mut ()).await;
+                res.body().await?;
+                Ok::<_, RhodError>(())
+            }
+        };
+
+        assert!(handle("basic").await.is_ok());
+        assert!(handle("basic").await.is_ok());
+        let err = handle("basic").await.unwrap_err();
+        let res = err.response().unwrap();
+        assert_eq!(res.status_as_int(), 429);
+        assert!(res.headers().contains_key(RETRY_AFTER));
+        for _ in 0..3 {
+            assert!(handle("gold").await.is_ok());
+        }
+
+        assert_eq!(metering.flush().await.unwrap(), 2);
+        assert_eq!(metering.flush().await.unwrap(), 0);
+        let records = sink.records.lock().unwrap();
+        let basic = records.iter().find(|r| r.key == "basic").unwrap();
+        assert_eq!(
+            basic.usage,
+            Usage {
+                requests: 2,
+                request_bytes: 8,
+                response_bytes: 10
+            }
+        );
+        assert_eq!(metering.usage("gold", QuotaPeriod::Monthly).requests, 3);
+    }
+
+    #[tokio::test]
+    async fn test_metering_prunes_without_sink() {
+        let metering = Metering::new();
+        let last_month = || KeyUsage {
+            pending: Usage {
+                requests: 1,
+                ..Usage::default()
+            },
+            ..KeyUsage::default()
+        };
+        metering
+            .usage
+            .lock()
+            .unwrap()
+            .insert("old".to_string(), last_month());
+        assert_eq!(metering.flush().await.unwrap(), 0);
+        assert!(metering.usage.lock().unwrap().is_empty());
+
+        // requests prune them too, once a month
+        metering
+            .usage
+            .lock()
+            .unwrap()
+            .insert("old".to_string(), last_month());
+        metering.pruned.store(0, Ordering::Relaxed);
+        assert!(metering.admit("new").is_ok());
+        let keys = metering.usage.lock().unwrap();
+        assert_eq!(keys.keys().collect::<Vec<_>>(), vec!["new"]);
+    }
+}
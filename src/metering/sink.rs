@@ -0,0 +1,155 @@
+use super::UsageRecord;
+use crate::client::RhodClient;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::log_sink::LogSink;
+use crate::request::RhodRequest;
+use crate::runtime;
+use async_trait::async_trait;
+use http::{Method, Uri};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Destination of the usage records flushed by a Metering (ie: a billing pipeline). A failed write
+// keeps the records for the next flush, so sinks should write all of them or none
+#[async_trait]
+pub trait UsageSink: Send + Sync {
+    async fn write(&self, records: &[UsageRecord]) -> RhodResult<()>;
+}
+
+fn sink_error(msg: String) -> RhodError {
+    RhodError::from_string(msg, RhodErrorLevel::Error)
+}
+
+// Writes the records to a LogSink, one JSON per record (ie: a RollingFileSink for a file)
+pub struct LogUsageSink {
+    sink: Arc<dyn LogSink>,
+}
+
+impl LogUsageSink {
+    pub fn new(sink: Arc<dyn LogSink>) -> LogUsageSink {
+        LogUsageSink { sink }
+    }
+}
+
+#[async_trait]
+impl UsageSink for LogUsageSink {
+    async fn write(&self, records: &[UsageRecord]) -> RhodResult<()> {
+        for record in records {
+            let mut value = serde_json::to_value(record)
+                .map_err(|e| sink_error(format!("Couldnt serialize usage record. {}", e)))?;
+            value["type"] = serde_json::json!("usage");
+            self.sink
+                .write(&value)
+                .await
+                .map_err(|e| sink_error(format!("Couldnt write usage record. {}", e)))?;
+        }
+        self.sink
+            .flush()
+            .await
+            .map_err(|e| sink_error(format!("Couldnt flush usage records. {}", e)))
+    }
+}
+
+// POSTs the records as a JSON array to an endpoint, which accepts them answering 2xx
+pub struct HttpUsageSink {
+    url: Uri,
+    client: RhodClient,
+    timeout: Duration,
+}
+
+impl HttpUsageSink {
+    pub fn new(url: &str) -> RhodResult<HttpUsageSink> {
+        let url: Uri = url
+            .parse()
+            .map_err(|_| sink_error(format!("Invalid usage endpoint {}", url)))?;
+        if url.host().is_none() {
+            return Err(sink_error(format!("Usage endpoint {} without host", url)));
+        }
+        Ok(HttpUsageSink {
+            url,
+            client: RhodClient::new(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    pub fn with_client(self, client: RhodClient) -> Self {
+        HttpUsageSink { client, ..self }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        HttpUsageSink { timeout, ..self }
+    }
+}
+
+#[async_trait]
+impl UsageSink for HttpUsageSink {
+    async fn write(&self, records: &[UsageRecord]) -> RhodResult<()> {
+        let host = self.url.authority().map_or("", |a| a.as_str());
+        let req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri(&self.url.to_string())
+            .header("host", host)
+            .body_json(&records)
+            .build()?;
+        let sent = async {
+            let mut res = self.client.send(req).await?;
+            // read the whole answer, so the connection goes back to the pool
+            res.body().await?;
+            Ok::<_, RhodError>(res.status_as_int())
+        };
+        match runtime::timeout(self.timeout, sent).await {
+            Ok(Ok(status)) if (200..300).contains(&status) => Ok(()),
+            Ok(Ok(status)) => Err(sink_error(format!(
+                "Usage endpoint {} answered {}",
+                self.url, status
+            ))),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(sink_error(format!("Usage endpoint {} timed out", self.url))),
+        }
+    }
+}
+
+// Adds the records to redis hashes, one per key and UTC day of the record start:
+// prefix + key + ":" + yyyy-mm-dd, with the fields requests, request_bytes and response_bytes
+#[cfg(feature = "redis-metering")]
+pub struct RedisUsageSink {
+    client: redis::Client,
+    prefix: String,
+}
+
+#[cfg(feature = "redis-metering")]
+impl RedisUsageSink {
+    pub fn new(url: &str, prefix: &str) -> redis::RedisResult<RedisUsageSink> {
+        Ok(RedisUsageSink {
+            client: redis::Client::open(url)?,
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "redis-metering")]
+#[async_trait]
+impl UsageSink for RedisUsageSink {
+    async fn write(&self, records: &[UsageRecord]) -> RhodResult<()> {
+        let mut conn = self
+            .client
+            .get_async_connection()
+            .await
+            .map_err(|e| sink_error(format!("Cant connect to the redis usage store. {}", e)))?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for record in records {
+            let day = &crate::log_sink::iso8601(record.from)[..10];
+            let hash = format!("{}{}:{}", self.prefix, record.key, day);
+            pipe.hincr(&hash, "requests", record.usage.requests)
+                .ignore()
+                .hincr(&hash, "request_bytes", record.usage.request_bytes)
+                .ignore()
+                .hincr(&hash, "response_bytes", record.usage.response_bytes)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| sink_error(format!("Cant write to the redis usage store. {}", e)))
+    }
+}
@@ -0,0 +1,205 @@
+// Content negotiation: Accept, Accept-Language and Accept-Encoding parsed with their q-values, choosing the
+// best of the variants a service has. Ties are won by the variant listed first (the server preference).
+// Responses chosen this way should list the negotiated headers with RhodResponse::vary.
+use crate::request::RhodRequest;
+use http::header::{ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE};
+use mime::Mime;
+
+// Media ranges of an Accept header
+#[derive(Debug, Clone)]
+pub struct Accept {
+    ranges: Vec<(Mime, f32)>,
+}
+
+impl Accept {
+    pub fn parse(value: &str) -> Accept {
+        let ranges = value
+            .split(',')
+            .filter_map(|range| range.trim().parse::<Mime>().ok())
+            .map(|range| {
+                let q = range.get_param("q").map_or(1.0, |q| quality(q.as_str()));
+                (range, q)
+            })
+            .collect();
+        Accept { ranges }
+    }
+
+    // Accept of the request. Without the header every media type is accepted
+    pub fn from_request(req: &RhodRequest) -> Accept {
+        Accept::parse(req.header_str(ACCEPT).unwrap_or("*/*"))
+    }
+
+    // q-value of the most specific range matching the media type (type/subtype, then type/*, then */*), 0 if none does
+    pub fn quality(&self, mime: &Mime) -> f32 {
+        let mut best: Option<(u8, f32)> = None; // (specificity, q)
+        for (range, q) in &self.ranges {
+            let specificity = if range.type_() == mime::STAR {
+                0
+            } else if range.type_() != mime.type_() {
+                continue;
+            } else if range.subtype() == mime::STAR {
+                1
+            } else if range.subtype() == mime.subtype() {
+                2
+            } else {
+                continue;
+            };
+            if best.is_none_or(|(s, _)| specificity > s) {
+                best = Some((specificity, *q));
+            }
+        }
+        best.map_or(0.0, |(_, q)| q)
+    }
+
+    // Available media type with the highest q-value, None if none is acceptable
+    pub fn best_match(&self, available: &[Mime]) -> Option<Mime> {
+        best(available, |mime| self.quality(mime)).cloned()
+    }
+}
+
+// Language ranges of an Accept-Language header
+#[derive(Debug, Clone)]
+pub struct AcceptLanguage {
+    ranges: Vec<(String, f32)>,
+}
+
+impl AcceptLanguage {
+    pub fn parse(value: &str) -> AcceptLanguage {
+        AcceptLanguage {
+            ranges: weighted(value),
+        }
+    }
+
+    // Accept-Language of the request. Without the header every language is accepted
+    pub fn from_request(req: &RhodRequest) -> AcceptLanguage {
+        AcceptLanguage::parse(req.header_str(ACCEPT_LANGUAGE).unwrap_or("*"))
+    }
+
+    // q-value of the longest range matching the tag ("en" matches "en-US"), 0 if none does
+    pub fn quality(&self, tag: &str) -> f32 {
+        let tag = tag.to_ascii_lowercase();
+        let mut best: Option<(usize, f32)> = None; // (range length, q)
+        for (range, q) in &self.ranges {
+            let matches = range == "*"
+                || tag == *range
+                || (tag.starts_with(range.as_str()) && tag[range.len()..].starts_with('-'));
+            let length = if range == "*" { 0 } else { range.len() };
+            if matches && best.is_none_or(|(l, _)| length > l) {
+                best = Some((length, *q));
+            }
+        }
+        best.map_or(0.0, |(_, q)| q)
+    }
+
+    // Available language tag with the highest q-value, None if none is acceptable
+    pub fn best_match<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        best(available, |tag| self.quality(tag)).copied()
+    }
+}
+
+// Content codings of an Accept-Encoding header
+#[derive(Debug, Clone)]
+pub struct AcceptEncoding {
+    codings: Vec<(String, f32)>,
+}
+
+impl AcceptEncoding {
+    pub fn parse(value: &str) -> AcceptEncoding {
+        AcceptEncoding {
+            codings: weighted(value),
+        }
+    }
+
+    // Accept-Encoding of the request. Without the header only identity is expected
+    pub fn from_request(req: &RhodRequest) -> AcceptEncoding {
+        AcceptEncoding::parse(req.header_str(ACCEPT_ENCODING).unwrap_or(""))
+    }
+
+    // q-value of the coding, or of *. identity is acceptable unless it is excluded
+    pub fn quality(&self, coding: &str) -> f32 {
+        let coding = coding.to_ascii_lowercase();
+        let find = |name: &str| {
+            self.codings
+                .iter()
+                .find(|(c, _)| c == name)
+                .map(|(_, q)| *q)
+        };
+        match find(&coding).or_else(|| find("*")) {
+            Some(q) => q,
+            None if coding == "identity" => 1.0,
+            None => 0.0,
+        }
+    }
+
+    // Available coding with the highest q-value, None if none is acceptable (not even identity)
+    pub fn best_match<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        best(available, |coding| self.quality(coding)).copied()
+    }
+}
+
+// Lowercased values of a header with their q-values
+fn weighted(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .next()
+                .map_or(1.0, quality);
+            Some((name, q))
+        })
+        .collect()
+}
+
+// Invalid q-values are taken as 0
+fn quality(q: &str) -> f32 {
+    q.trim().parse::<f32>().map_or(0.0, |q| q.clamp(0.0, 1.0))
+}
+
+// First of the variants with the highest quality above 0
+fn best<T>(available: &[T], quality: impl Fn(&T) -> f32) -> Option<&T> {
+    let mut best: Option<(&T, f32)> = None;
+    for variant in available {
+        let q = quality(variant);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((variant, q));
+        }
+    }
+    best.map(|(variant, _)| variant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation() {
+        let accept = Accept::parse("text/*;q=0.5, text/csv;q=0, application/json, */*;q=0.1");
+        let available = [mime::TEXT_CSV, mime::TEXT_HTML, mime::APPLICATION_JSON];
+        assert_eq!(accept.best_match(&available), Some(mime::APPLICATION_JSON));
+        assert_eq!(accept.best_match(&available[..2]), Some(mime::TEXT_HTML));
+        assert_eq!(accept.best_match(&available[..1]), None);
+        assert_eq!(accept.quality(&mime::IMAGE_PNG), 0.1);
+
+        let languages = AcceptLanguage::parse("es-UY, es;q=0.8, en;q=0.5");
+        assert_eq!(languages.best_match(&["en-US", "es-AR"]), Some("es-AR"));
+        assert_eq!(languages.best_match(&["en-US", "es-UY"]), Some("es-UY"));
+        assert_eq!(languages.best_match(&["fr"]), None);
+
+        let encodings = AcceptEncoding::parse("gzip;q=0.5, br, identity;q=0");
+        assert_eq!(encodings.best_match(&["gzip", "br"]), Some("br"));
+        assert_eq!(encodings.best_match(&["identity", "gzip"]), Some("gzip"));
+        assert_eq!(encodings.best_match(&["identity", "zstd"]), None);
+        let none = AcceptEncoding::parse("");
+        assert_eq!(none.best_match(&["gzip", "identity"]), Some("identity"));
+
+        // ties are won by the first variant
+        let any = Accept::from_request(&RhodRequest::builder().build().unwrap());
+        assert_eq!(any.best_match(&available), Some(mime::TEXT_CSV));
+    }
+}
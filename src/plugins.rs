@@ -0,0 +1,602 @@
+// Handlers distributed as dynamic libraries (cdylib), loaded at startup and put in the stack by name,
+// so third-party middleware doesnt need the host to be recompiled. The ABI is C: a plugin exports
+//
+//  #[no_mangle]
+//  pub extern "C" fn rhodium_plugin() -> *const RhodPluginVTable
+//
+// returning a static vtable. Messages cross the boundary as JSON (PluginMessage in, PluginAction
+// out), so plugins dont depend on the Rust layout of the host types nor on its compiler version.
+// Calls are synchronous and run on the worker threads: plugins must be thread safe and quick, and
+// must not unwind across the boundary.
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::handlers::fix_length_headers;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::{RhodHandler, RhodHandlerInStack};
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use libloading::Library;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_void, CStr};
+use std::path::Path;
+use std::sync::Arc;
+
+// Version of the vtable layout, plugins built for another one arent loaded
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+// Symbol exported by the plugins
+pub const PLUGIN_SYMBOL: &[u8] = b"rhodium_plugin";
+
+// Bytes lent by one side to the other. Buffers returned by a plugin are given back to its free
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RhodPluginBuffer {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl RhodPluginBuffer {
+    pub fn empty() -> RhodPluginBuffer {
+        RhodPluginBuffer {
+            ptr: std::ptr::null(),
+            len: 0,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct RhodPluginVTable {
+    pub abi_version: u32,
+    pub name: *const c_char, // static, NUL terminated
+    pub needs_body: bool,    // whether messages carry the bodies
+    // Instance configured by the JSON config, or null if the config is invalid
+    pub create: unsafe extern "C" fn(config: RhodPluginBuffer) -> *mut c_void,
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void),
+    // Return a PluginAction, or an empty buffer to leave the message as it is
+    pub on_request:
+        unsafe extern "C" fn(instance: *mut c_void, message: RhodPluginBuffer) -> RhodPluginBuffer,
+    pub on_response:
+        unsafe extern "C" fn(instance: *mut c_void, message: RhodPluginBuffer) -> RhodPluginBuffer,
+    pub free: unsafe extern "C" fn(buffer: RhodPluginBuffer),
+}
+
+// The vtable is immutable, and plugins are required to be thread safe
+unsafe impl Sync for RhodPluginVTable {}
+
+// Request or response given to a plugin. Responses carry the method and uri of their request
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PluginMessage {
+    pub method: String,
+    pub uri: String,
+    pub status: Option<u16>, // responses
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>, // base64, if the plugin needs bodies
+}
+
+// Response a plugin answers a request with, or replaces a response with
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PluginResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>, // base64
+}
+
+// Changes a plugin makes to a message
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PluginAction {
+    pub uri: Option<String>,
+    pub status: Option<u16>,
+    pub set_headers: Vec<(String, String)>,
+    pub remove_headers: Vec<String>,
+    pub body: Option<String>, // base64
+    pub respond: Option<PluginResponse>,
+}
+
+fn plugin_error(msg: String) -> RhodError {
+    RhodError::from_string(msg, RhodErrorLevel::Error)
+}
+
+struct Plugin {
+    name: String,
+    vtable: &'static RhodPluginVTable, // valid while the library is loaded
+    _library: Option<Library>,         // None for plugins linked in the host
+}
+
+// Plugins loaded, by name
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: HashMap<String, Arc<Plugin>>,
+}
+
+// The safety requirements of the unsafe functions are in their comments
+#[allow(clippy::missing_safety_doc)]
+impl PluginRegistry {
+    pub fn new() -> PluginRegistry {
+        PluginRegistry::default()
+    }
+
+    // Loads every dynamic library (.so, .dylib, .dll) of the dir.
+    // Unsafe: loading a library runs its code, which must follow the ABI of RhodPluginVTable
+    pub unsafe fn load_dir<P: AsRef<Path>>(dir: P) -> RhodResult<PluginRegistry> {
+        let dir = dir.as_ref();
+        let entries = std::fs::read_dir(dir).map_err(|e| {
+            plugin_error(format!("Couldnt read plugins dir {}. {}", dir.display(), e))
+        })?;
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("so") | Some("dylib") | Some("dll")
+                )
+            })
+            .collect();
+        paths.sort();
+        let mut registry = PluginRegistry::new();
+        for path in paths {
+            registry.load(&path)?;
+        }
+        Ok(registry)
+    }
+
+    // Loads the plugin of a dynamic library, returning its name.
+    // Unsafe: loading a library runs its code, which must follow the ABI of RhodPluginVTable
+    pub unsafe fn load<P: AsRef<Path>>(&mut self, path: P) -> RhodResult<String> {
+        let path = path.as_ref();
+        let failed = |e: libloading::Error| {
+            plugin_error(format!("Couldnt load plugin {}. {}", path.display(), e))
+        };
+        let library = Library::new(path).map_err(failed)?;
+        let vtable = {
+            let entry: libloading::Symbol<unsafe extern "C" fn() -> *const RhodPluginVTable> =
+                library.get(PLUGIN_SYMBOL).map_err(failed)?;
+            entry()
+        };
+        if vtable.is_null() {
+            return Err(plugin_error(format!(
+                "Plugin {} without vtable",
+                path.display()
+            )));
+        }
+        let name = self.add(&*vtable, Some(library))?;
+        info!("Plugin {} loaded from {}", name, path.display());
+        Ok(name)
+    }
+
+    // Registers a plugin linked in the host, returning its name.
+    // Unsafe: the functions of the vtable must follow its ABI
+    pub unsafe fn register(&mut self, vtable: &'static RhodPluginVTable) -> RhodResult<String> {
+        self.add(vtable, None)
+    }
+
+    unsafe fn add(
+        &mut self,
+        vtable: &'static RhodPluginVTable,
+        library: Option<Library>,
+    ) -> RhodResult<String> {
+        if vtable.abi_version != PLUGIN_ABI_VERSION {
+            return Err(plugin_error(format!(
+                "Plugin ABI version {} isnt supported, expected {}",
+                vtable.abi_version, PLUGIN_ABI_VERSION
+            )));
+        }
+        if vtable.name.is_null() {
+            return Err(plugin_error("Plugin without name".to_string()));
+        }
+        let name = CStr::from_ptr(vtable.name).to_string_lossy().into_owned();
+        if self.plugins.contains_key(&name) {
+            return Err(plugin_error(format!("Plugin {} loaded twice", name)));
+        }
+        let plugin = Plugin {
+            name: name.clone(),
+            vtable,
+            _library: library,
+        };
+        self.plugins.insert(name.clone(), Arc::new(plugin));
+        Ok(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.plugins.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Handler of the plugin configured by config, to be put in the stack
+    pub fn handler(&self, name: &str, config: &serde_json::Value) -> RhodResult<PluginHandler> {
+        let plugin = self
+            .plugins
+            .get(name)
+            .ok_or_else(|| plugin_error(format!("Unknown plugin {}", name)))?;
+        let config = config.to_string();
+        let instance = unsafe {
+            (plugin.vtable.create)(RhodPluginBuffer {
+                ptr: config.as_ptr(),
+                len: config.len(),
+            })
+        };
+        if instance.is_null() {
+            return Err(plugin_error(format!(
+                "Plugin {} rejected its config {}",
+                name, config
+            )));
+        }
+        Ok(PluginHandler {
+            plugin: Arc::clone(plugin),
+            instance,
+        })
+    }
+
+    pub fn stack_handler<C: Send + Sync>(
+        &self,
+        name: &str,
+        config: &serde_json::Value,
+    ) -> RhodResult<RhodHandlerInStack<C>> {
+        Ok(RhodHandlerInStack::RhodHandler(Box::new(
+            self.handler(name, config)?,
+        )))
+    }
+}
+
+// Method and uri of the request, given to on_response
+#[derive(Clone)]
+struct PluginRequest(String, String);
+
+fn to_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(n, v)| {
+            (
+                n.to_string(),
+                String::from_utf8_lossy(v.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn invalid(plugin: &str, what: &str) -> RhodError {
+    plugin_error(format!("Plugin {} returned an invalid {}", plugin, what))
+}
+
+// Instance of a plugin in the stack, destroyed when dropped
+pub struct PluginHandler {
+    plugin: Arc<Plugin>,
+    instance: *mut c_void,
+}
+
+// Plugins are required to be thread safe
+unsafe impl Send for PluginHandler {}
+unsafe impl Sync for PluginHandler {}
+
+impl Drop for PluginHandler {
+    fn drop(&mut self) {
+        unsafe { (self.plugin.vtable.destroy)(self.instance) }
+    }
+}
+
+impl PluginHandler {
+    fn call(
+        &self,
+        function: unsafe extern "C" fn(*mut c_void, RhodPluginBuffer) -> RhodPluginBuffer,
+        message: &PluginMessage,
+    ) -> RhodResult<PluginAction> {
+        let message = serde_json::to_vec(message)
+            .map_err(|e| plugin_error(format!("Couldnt serialize plugin message. {}", e)))?;
+        let action = unsafe {
+            let out = function(
+                self.instance,
+                RhodPluginBuffer {
+                    ptr: message.as_ptr(),
+                    len: message.len(),
+                },
+            );
+            if out.ptr.is_null() {
+                return Ok(PluginAction::default());
+            }
+            let action = std::slice::from_raw_parts(out.ptr, out.len).to_vec();
+            (self.plugin.vtable.free)(out);
+            action
+        };
+        if action.is_empty() {
+            return Ok(PluginAction::default());
+        }
+        serde_json::from_slice(&action).map_err(|e| {
+            plugin_error(format!(
+                "Plugin {} returned an invalid action. {}",
+                self.plugin.name, e
+            ))
+        })
+    }
+
+    fn decode(&self, body: &str) -> RhodResult<Vec<u8>> {
+        BASE64
+            .decode(body)
+            .map_err(|_| invalid(&self.plugin.name, "body"))
+    }
+
+    fn apply_headers(&self, headers: &mut HeaderMap, action: &PluginAction) -> RhodResult<()> {
+        for name in action.remove_headers.iter() {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in action.set_headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| invalid(&self.plugin.name, "header name"))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| invalid(&self.plugin.name, "header value"))?;
+            headers.insert(name, value);
+        }
+        Ok(())
+    }
+
+    fn to_response(&self, answer: PluginResponse) -> RhodResult<RhodResponse> {
+        let status = StatusCode::from_u16(answer.status)
+            .map_err(|_| invalid(&self.plugin.name, "status"))?;
+        let mut res = RhodResponse::builder().status(status);
+        for (name, value) in answer.headers.iter() {
+            res = res.header(name, value);
+        }
+        if let Some(body) = answer.body {
+            res = res.body_bytes(&self.decode(&body)?);
+        }
+        res.build()
+    }
+
+    fn failed(err: RhodError) -> RhodError {
+        err.with_built(
+            RhodResponse::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .build(),
+        )
+    }
+
+    async fn handle(&self, req: &mut RhodRequest) -> RhodResult<()> {
+        let body = match self.plugin.vtable.needs_body {
+            true => Some(BASE64.encode(req.body_bytes().await?)),
+            false => None,
+        };
+        let message = PluginMessage {
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            status: None,
+            headers: to_headers(req.headers()),
+            body,
+        };
+        let action = self.call(self.plugin.vtable.on_request, &message)?;
+        if let Some(answer) = action.respond {
+            return Err(RhodError::from_response(self.to_response(answer)?));
+        }
+        if let Some(uri) = &action.uri {
+            *req.uri_mut() = uri
+                .parse::<Uri>()
+                .map_err(|_| invalid(&self.plugin.name, "uri"))?;
+        }
+        self.apply_headers(req.headers_mut(), &action)?;
+        if let Some(body) = &action.body {
+            let body = self.decode(body)?;
+            fix_length_headers(req.headers_mut(), Some(body.len()));
+            req.set_body(RhodBody::from(body));
+        }
+        req.context().insert(PluginRequest(
+            req.method().to_string(),
+            req.uri().to_string(),
+        ));
+        Ok(())
+    }
+
+    async fn handle_res(&self, mut res: RhodResponse) -> RhodResult<RhodResponse> {
+        let body = match self.plugin.vtable.needs_body {
+            true => Some(BASE64.encode(res.body_bytes().await?)),
+            false => None,
+        };
+        let PluginRequest(method, uri) = res
+            .context()
+            .remove::<PluginRequest>()
+            .unwrap_or(PluginRequest(Method::GET.to_string(), "/".to_string()));
+        let message = PluginMessage {
+            method,
+            uri,
+            status: Some(res.status_as_int()),
+            headers: to_headers(res.headers()),
+            body,
+        };
+        let action = self.call(self.plugin.vtable.on_response, &message)?;
+        if let Some(answer) = action.respond {
+            let mut replaced = self.to_response(answer)?;
+            replaced.attach_context(res.context());
+            return Ok(replaced);
+        }
+        let mut hyper_res = res.into_hyper_response();
+        if let Some(status) = action.status {
+            *hyper_res.status_mut() =
+                StatusCode::from_u16(status).map_err(|_| invalid(&self.plugin.name, "status"))?;
+        }
+        self.apply_headers(hyper_res.headers_mut(), &action)?;
+        let mut res = RhodResponse::new(hyper_res);
+        if let Some(body) = &action.body {
+            let body = self.decode(body)?;
+            fix_length_headers(res.headers_mut(), Some(body.len()));
+            res.set_body(RhodBody::from(body));
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for PluginHandler {
+    async fn handle_request(
+        &self,
+        _conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        self.handle(req).await.map_err(|e| match e.response() {
+            Some(_) => e,
+            None => PluginHandler::failed(e),
+        })
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        // failed plugins answer 500, the response was given to them
+        let fallback = match RhodResponse::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .build()
+        {
+            Ok(fallback) => fallback,
+            Err(e) => return (res, Err(e)),
+        };
+        match self.handle_res(res).await {
+            Ok(res) => (res, Ok(())),
+            Err(e) => (fallback, Err(e)),
+        }
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        self.plugin.vtable.needs_body
+    }
+
+    fn name(&self) -> &str {
+        &self.plugin.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    // Plugin blocking the uri given in its config, and tagging the other requests
+    struct Blocker {
+        blocked: String,
+    }
+
+    unsafe extern "C" fn create(config: RhodPluginBuffer) -> *mut c_void {
+        let config = std::slice::from_raw_parts(config.ptr, config.len);
+        match serde_json::from_slice::<serde_json::Value>(config) {
+            Ok(config) => match config["blocked"].as_str() {
+                Some(blocked) => Box::into_raw(Box::new(Blocker {
+                    blocked: blocked.to_string(),
+                })) as *mut c_void,
+                None => std::ptr::null_mut(),
+            },
+            Err(_) => std::ptr::null_mut(),
+        }
+    }
+
+    unsafe extern "C" fn destroy(instance: *mut c_void) {
+        drop(Box::from_raw(instance as *mut Blocker));
+    }
+
+    fn out(action: &PluginAction) -> RhodPluginBuffer {
+        let action = serde_json::to_vec(action).unwrap().into_boxed_slice();
+        let len = action.len();
+        RhodPluginBuffer {
+            ptr: Box::into_raw(action) as *const u8,
+            len,
+        }
+    }
+
+    unsafe extern "C" fn on_request(
+        instance: *mut c_void,
+        message: RhodPluginBuffer,
+    ) -> RhodPluginBuffer {
+        let blocker = &*(instance as *const Blocker);
+        let message: PluginMessage =
+            serde_json::from_slice(std::slice::from_raw_parts(message.ptr, message.len)).unwrap();
+        if message.uri == blocker.blocked {
+            return out(&PluginAction {
+                respond: Some(PluginResponse {
+                    status: 403,
+                    ..PluginResponse::default()
+                }),
+                ..PluginAction::default()
+            });
+        }
+        out(&PluginAction {
+            set_headers: vec![("x-plugin".to_string(), "blocker".to_string())],
+            ..PluginAction::default()
+        })
+    }
+
+    unsafe extern "C" fn on_response(
+        _instance: *mut c_void,
+        _message: RhodPluginBuffer,
+    ) -> RhodPluginBuffer {
+        RhodPluginBuffer::empty()
+    }
+
+    unsafe extern "C" fn free(buffer: RhodPluginBuffer) {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            buffer.ptr as *mut u8,
+            buffer.len,
+        )));
+    }
+
+    static VTABLE: RhodPluginVTable = RhodPluginVTable {
+        abi_version: PLUGIN_ABI_VERSION,
+        name: b"blocker\0".as_ptr() as *const c_char,
+        needs_body: false,
+        create,
+        destroy,
+        on_request,
+        on_response,
+        free,
+    };
+
+    #[tokio::test]
+    async fn test_plugin_handler() {
+        let mut registry = PluginRegistry::new();
+        assert_eq!(unsafe { registry.register(&VTABLE) }.unwrap(), "blocker");
+        assert!(unsafe { registry.register(&VTABLE) }.is_err());
+        assert!(registry.handler("blocker", &serde_json::json!({})).is_err());
+        let handler = registry
+            .handler("blocker", &serde_json::json!({"blocked": "/admin"}))
+            .unwrap();
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+
+        let mut req = RhodRequest::builder().uri("/users").build().unwrap();
+        RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap();
+        assert_eq!(req.headers().get("x-plugin").unwrap(), "blocker");
+
+        let mut req = RhodRequest::builder().uri("/admin").build().unwrap();
+        let err = RhodHandler::<()>::handle_request(&handler, &conn, &mut req, &mut ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 403);
+
+        let res = RhodResponse::builder().body_str("ok").build().unwrap();
+        let (res, result) = RhodHandler::<()>::handle_response(&handler, &conn, res, &mut ()).await;
+        assert!(result.is_ok());
+        assert_eq!(res.status_as_int(), 200);
+    }
+}
@@ -1,4 +1,15 @@
 use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper_util::server::conn::auto::Builder as HyperBuilder;
+use socket2::{Domain, Socket, TcpKeepalive, Type};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::runtime::{self, Executor};
 
 // Http Protocols
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,17 +34,61 @@ impl fmt::Display for HttpProtocol {
 }
 
 // Used to configurate the Hyper server
-#[derive(Debug, PartialEq, Eq)]
+// HTTPS certificates can be read from files, passed as PEM or DER bytes (certs fetched from a secrets manager),
+// or the whole rustls ServerConfig can be given (TlsConfig is ignored then).
 pub enum HttpProtocolConf {
     HTTP,
-    HTTPS { cert_file: String, key_file: String },
+    HTTPS {
+        cert_file: String,
+        key_file: String,
+    },
+    HTTPSPem {
+        cert: Vec<u8>, // certificate chain
+        key: Vec<u8>,
+    },
+    HTTPSDer {
+        certs: Vec<Vec<u8>>, // certificate chain
+        key: Vec<u8>,
+    },
+    HTTPSConfig(Arc<ServerConfig>),
 }
 
 impl HttpProtocolConf {
     pub fn to_string(&self) -> &str {
         match &self {
             HttpProtocolConf::HTTP => "http",
-            HttpProtocolConf::HTTPS { .. } => "https",
+            _ => "https",
+        }
+    }
+}
+
+// The private keys given as bytes arent written
+impl fmt::Debug for HttpProtocolConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = format_args!("<redacted>");
+        match self {
+            HttpProtocolConf::HTTP => f.write_str("HTTP"),
+            HttpProtocolConf::HTTPS {
+                cert_file,
+                key_file,
+            } => f
+                .debug_struct("HTTPS")
+                .field("cert_file", cert_file)
+                .field("key_file", key_file)
+                .finish(),
+            HttpProtocolConf::HTTPSPem { cert, .. } => f
+                .debug_struct("HTTPSPem")
+                .field("cert", &String::from_utf8_lossy(cert))
+                .field("key", &redacted)
+                .finish(),
+            HttpProtocolConf::HTTPSDer { certs, .. } => f
+                .debug_struct("HTTPSDer")
+                .field("certs", certs)
+                .field("key", &redacted)
+                .finish(),
+            HttpProtocolConf::HTTPSConfig(config) => {
+                f.debug_tuple("HTTPSConfig").field(config).finish()
+            }
         }
     }
 }
@@ -49,8 +104,388 @@ impl Clone for HttpProtocolConf {
                 cert_file: cert_file.clone(),
                 key_file: key_file.clone(),
             },
+            HttpProtocolConf::HTTPSPem { cert, key } => HttpProtocolConf::HTTPSPem {
+                cert: cert.clone(),
+                key: key.clone(),
+            },
+            HttpProtocolConf::HTTPSDer { certs, key } => HttpProtocolConf::HTTPSDer {
+                certs: certs.clone(),
+                key: key.clone(),
+            },
+            HttpProtocolConf::HTTPSConfig(config) => {
+                HttpProtocolConf::HTTPSConfig(Arc::clone(config))
+            }
+        }
+    }
+}
+
+// ServerConfigs are equal only if they are the same
+impl PartialEq for HttpProtocolConf {
+    fn eq(&self, other: &HttpProtocolConf) -> bool {
+        match (self, other) {
+            (HttpProtocolConf::HTTP, HttpProtocolConf::HTTP) => true,
+            (
+                HttpProtocolConf::HTTPS {
+                    cert_file,
+                    key_file,
+                },
+                HttpProtocolConf::HTTPS {
+                    cert_file: other_cert,
+                    key_file: other_key,
+                },
+            ) => cert_file == other_cert && key_file == other_key,
+            (
+                HttpProtocolConf::HTTPSPem { cert, key },
+                HttpProtocolConf::HTTPSPem {
+                    cert: other_cert,
+                    key: other_key,
+                },
+            ) => cert == other_cert && key == other_key,
+            (
+                HttpProtocolConf::HTTPSDer { certs, key },
+                HttpProtocolConf::HTTPSDer {
+                    certs: other_certs,
+                    key: other_key,
+                },
+            ) => certs == other_certs && key == other_key,
+            (HttpProtocolConf::HTTPSConfig(config), HttpProtocolConf::HTTPSConfig(other)) => {
+                Arc::ptr_eq(config, other)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for HttpProtocolConf {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    TLS12,
+    TLS13,
+}
+
+// TLS options used with HTTPS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub min_version: TlsVersion,
+    pub max_version: TlsVersion,
+    pub cipher_suites: Option<Vec<String>>, // IANA names (TLS13_AES_128_GCM_SHA256). None uses the defaults
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            min_version: TlsVersion::TLS12,
+            max_version: TlsVersion::TLS13,
+            cipher_suites: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    pub fn with_versions(self, min_version: TlsVersion, max_version: TlsVersion) -> Self {
+        TlsConfig {
+            min_version,
+            max_version,
+            ..self
+        }
+    }
+
+    pub fn with_cipher_suites(self, cipher_suites: Vec<&str>) -> Self {
+        TlsConfig {
+            cipher_suites: Some(cipher_suites.iter().map(|s| s.to_string()).collect()),
+            ..self
+        }
+    }
+}
+
+// Connection tuning of the Hyper server. None values keep Hyper defaults
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConnectionConf {
+    pub http1_keepalive: Option<bool>,
+    pub idle_timeout: Option<Duration>, // close connections without requests in flight nor activity
+    pub tls_handshake_timeout: Option<Duration>,
+    pub header_read_timeout: Option<Duration>, // receiving the head of an HTTP/1 request (hyper defaults to 30s)
+    pub body_read_timeout: Option<Duration>,   // between chunks of a request body
+    pub http1_max_buf_size: Option<usize>,     // max buffered bytes, bounds the size of headers
+    pub http1_half_close: Option<bool>,
+    pub http1_only: bool,
+    pub h2c: Option<bool>, // prior knowledge HTTP/2 on the plain listener, detected by default (Upgrade: h2c isn't supported)
+    pub http2_only: bool,
+    pub http2_initial_stream_window_size: Option<u32>,
+    pub http2_initial_connection_window_size: Option<u32>,
+    pub http2_adaptive_window: Option<bool>, // BDP based windows, the initial window sizes are ignored then
+    pub http2_max_concurrent_streams: Option<u32>, // per connection (hyper defaults to 200)
+    pub http2_max_frame_size: Option<u32>,
+    pub http2_keepalive_interval: Option<Duration>, // PING frames, None doesnt send them
+    pub http2_keepalive_timeout: Option<Duration>, // for the PING ack, closing the connection (hyper defaults to 20s)
+}
+
+impl ConnectionConf {
+    pub fn new() -> ConnectionConf {
+        ConnectionConf::default()
+    }
+
+    pub fn with_http1_keepalive(self, keepalive: bool) -> Self {
+        ConnectionConf {
+            http1_keepalive: Some(keepalive),
+            ..self
+        }
+    }
+
+    pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+        ConnectionConf {
+            idle_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn with_tls_handshake_timeout(self, timeout: Duration) -> Self {
+        ConnectionConf {
+            tls_handshake_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    // Closes HTTP/1 connections that dont send the whole head of a request in time (slow-loris)
+    pub fn with_header_read_timeout(self, timeout: Duration) -> Self {
+        ConnectionConf {
+            header_read_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    // Fails request bodies that go longer than the timeout without sending data
+    pub fn with_body_read_timeout(self, timeout: Duration) -> Self {
+        ConnectionConf {
+            body_read_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn with_http1_max_buf_size(self, size: usize) -> Self {
+        ConnectionConf {
+            http1_max_buf_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn with_http1_half_close(self, half_close: bool) -> Self {
+        ConnectionConf {
+            http1_half_close: Some(half_close),
+            ..self
+        }
+    }
+
+    pub fn with_h2c(self, h2c: bool) -> Self {
+        ConnectionConf {
+            h2c: Some(h2c),
+            ..self
+        }
+    }
+
+    pub fn http1_only(self) -> Self {
+        ConnectionConf {
+            http1_only: true,
+            http2_only: false,
+            ..self
+        }
+    }
+
+    pub fn http2_only(self) -> Self {
+        ConnectionConf {
+            http1_only: false,
+            http2_only: true,
+            ..self
+        }
+    }
+
+    pub fn with_http2_stream_window_size(self, size: u32) -> Self {
+        ConnectionConf {
+            http2_initial_stream_window_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn with_http2_connection_window_size(self, size: u32) -> Self {
+        ConnectionConf {
+            http2_initial_connection_window_size: Some(size),
+            ..self
+        }
+    }
+
+    // Lets the windows grow with the bandwidth-delay product, instead of the fixed window sizes
+    pub fn with_http2_adaptive_window(self, adaptive: bool) -> Self {
+        ConnectionConf {
+            http2_adaptive_window: Some(adaptive),
+            ..self
+        }
+    }
+
+    // Streams a client can open at once on a connection, raise it for many small concurrent requests
+    pub fn with_http2_max_concurrent_streams(self, max: u32) -> Self {
+        ConnectionConf {
+            http2_max_concurrent_streams: Some(max),
+            ..self
+        }
+    }
+
+    pub fn with_http2_max_frame_size(self, size: u32) -> Self {
+        ConnectionConf {
+            http2_max_frame_size: Some(size),
+            ..self
+        }
+    }
+
+    // Pings the client every interval, closing the connection if the ack doesnt arrive in the timeout.
+    // Detects dead peers of long lived connections (gRPC streams)
+    pub fn with_http2_keepalive(self, interval: Duration, timeout: Option<Duration>) -> Self {
+        ConnectionConf {
+            http2_keepalive_interval: Some(interval),
+            http2_keepalive_timeout: timeout,
+            ..self
+        }
+    }
+
+    // Hyper connection builder with these options
+    pub(crate) fn builder(&self) -> HyperBuilder<Executor> {
+        let mut builder = HyperBuilder::new(runtime::executor());
+        let mut http1 = builder.http1();
+        http1.timer(runtime::timer());
+        if let Some(keepalive) = self.http1_keepalive {
+            http1.keep_alive(keepalive);
+        }
+        if let Some(size) = self.http1_max_buf_size {
+            http1.max_buf_size(size);
+        }
+        if let Some(half_close) = self.http1_half_close {
+            http1.half_close(half_close);
+        }
+        if let Some(timeout) = self.header_read_timeout {
+            http1.header_read_timeout(timeout);
+        }
+        let mut http2 = builder.http2();
+        http2
+            .timer(runtime::timer())
+            .initial_stream_window_size(self.http2_initial_stream_window_size)
+            .initial_connection_window_size(self.http2_initial_connection_window_size)
+            .keep_alive_interval(self.http2_keepalive_interval);
+        if let Some(adaptive) = self.http2_adaptive_window {
+            http2.adaptive_window(adaptive);
+        }
+        if let Some(max) = self.http2_max_concurrent_streams {
+            http2.max_concurrent_streams(max);
+        }
+        if let Some(size) = self.http2_max_frame_size {
+            http2.max_frame_size(size);
+        }
+        if let Some(timeout) = self.http2_keepalive_timeout {
+            http2.keep_alive_timeout(timeout);
+        }
+
+        if self.http1_only {
+            builder = builder.http1_only();
+        }
+        if self.http2_only {
+            builder = builder.http2_only();
+        }
+        builder
+    }
+}
+
+// Hyper connection builder customized by Rhodium::with_hyper_builder (with the executor of the runtime)
+pub type HyperServerBuilder = HyperBuilder<hyper_util::rt::TokioExecutor>;
+
+// Options of the listening socket.
+// Keepalive options are set on the listener and inherited by the accepted sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketConf {
+    pub nodelay: bool,    // TCP_NODELAY on accepted sockets
+    pub reuse_port: bool, // SO_REUSEPORT, to bind many listeners to the same address
+    pub backlog: u32,
+    pub keepalive_time: Option<Duration>, // None disables TCP keepalive
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_retries: Option<u32>,
+}
+
+impl Default for SocketConf {
+    fn default() -> SocketConf {
+        SocketConf {
+            nodelay: false,
+            reuse_port: false,
+            backlog: 1024,
+            keepalive_time: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+        }
+    }
+}
+
+impl SocketConf {
+    pub fn new() -> SocketConf {
+        SocketConf::default()
+    }
+
+    pub fn with_nodelay(self, nodelay: bool) -> Self {
+        SocketConf { nodelay, ..self }
+    }
+
+    pub fn with_reuse_port(self, reuse_port: bool) -> Self {
+        SocketConf { reuse_port, ..self }
+    }
+
+    pub fn with_backlog(self, backlog: u32) -> Self {
+        SocketConf { backlog, ..self }
+    }
+
+    pub fn with_keepalive(
+        self,
+        time: Duration,
+        interval: Option<Duration>,
+        retries: Option<u32>,
+    ) -> Self {
+        SocketConf {
+            keepalive_time: Some(time),
+            keepalive_interval: interval,
+            keepalive_retries: retries,
+            ..self
         }
     }
+
+    // Creates a listener bound to addr with these options. Must be called inside a tokio runtime
+    pub(crate) fn bind(&self, addr: &SocketAddr) -> io::Result<TcpListener> {
+        TcpListener::from_std(self.bind_listener(addr)?)
+    }
+
+    // Std listener bound to addr with these options, ie: to be handed over to a new process (see listeners)
+    pub fn bind_listener(&self, addr: &SocketAddr) -> io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(self.reuse_port)?;
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive_time {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            #[cfg(unix)]
+            {
+                if let Some(interval) = self.keepalive_interval {
+                    keepalive = keepalive.with_interval(interval);
+                }
+                if let Some(retries) = self.keepalive_retries {
+                    keepalive = keepalive.with_retries(retries);
+                }
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        socket.bind(&(*addr).into())?;
+        socket.listen(self.backlog.min(i32::MAX as u32) as i32)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    }
 }
 
 #[cfg(test)]
@@ -75,5 +510,66 @@ mod tests {
         };
         assert_eq!(https.to_string(), "https");
         assert_eq!(https, https.clone());
+
+        let pem = HttpProtocolConf::HTTPSPem {
+            cert: b"cert".to_vec(),
+            key: b"key".to_vec(),
+        };
+        assert_eq!(pem.to_string(), "https");
+        assert_eq!(pem, pem.clone());
+        assert_ne!(pem, https);
+        let debug = format!("{:?}", pem);
+        assert!(debug.contains("cert") && debug.contains("<redacted>"));
+        assert!(!debug.contains("\"key\"") && !debug.contains("[107, 101, 121]"));
+        let der = HttpProtocolConf::HTTPSDer {
+            certs: vec![],
+            key: vec![42; 4],
+        };
+        assert!(!format!("{:?}", der).contains("42"));
+    }
+
+    #[test]
+    fn test_connection_conf() {
+        let conf = ConnectionConf::new()
+            .with_http1_keepalive(false)
+            .with_idle_timeout(Duration::from_secs(30))
+            .with_http2_max_concurrent_streams(1000)
+            .with_http2_keepalive(Duration::from_secs(10), None)
+            .http2_only()
+            .http1_only();
+        assert_eq!(conf.http1_keepalive, Some(false));
+        assert_eq!(conf.idle_timeout, Some(Duration::from_secs(30)));
+        assert!(conf.http1_only);
+        assert!(!conf.http2_only);
+        assert_eq!(conf.http1_max_buf_size, None);
+        assert_eq!(conf.http2_max_concurrent_streams, Some(1000));
+        assert_eq!(conf.http2_keepalive_interval, Some(Duration::from_secs(10)));
+        assert_eq!(conf.http2_keepalive_timeout, None);
+        conf.builder();
+    }
+
+    #[tokio::test]
+    async fn test_socket_conf() {
+        let conf = SocketConf::new()
+            .with_nodelay(true)
+            .with_reuse_port(true)
+            .with_keepalive(
+                Duration::from_secs(60),
+                Some(Duration::from_secs(5)),
+                Some(3),
+            );
+        let listener = conf.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // a second listener on the same address only works with reuse_port
+        #[cfg(unix)]
+        conf.bind(&addr).unwrap();
+
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+        assert!(stream.nodelay().unwrap());
     }
 }
@@ -0,0 +1,335 @@
+// Range requests (RFC 9110): parsing of the Range header and 206 Partial Content responses, from a body in
+// memory or from a seekable reader (ie: a file) streamed without reading the parts not requested.
+// Many ranges are answered as multipart/byteranges. Overlapping and adjacent ranges are coalesced, and
+// a Range header that isnt valid (or asks for too many ranges) is ignored, serving the whole body.
+use crate::body::RhodBody;
+use crate::errors::RhodResult;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use bytes::{Bytes, BytesMut};
+use http::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, RANGE};
+use http::{Method, StatusCode};
+use std::io::{self, SeekFrom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+// More ranges than this are ignored
+const MAX_RANGES: usize = 64;
+// Size of the chunks read from a reader
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+// Inclusive range of bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,                    // no valid Range, the whole body is sent
+    Partial(Vec<ByteRange>), // sorted and coalesced
+    Unsatisfiable,           // answered with 416
+}
+
+impl RangeRequest {
+    // Range header value for a body of size bytes
+    pub fn parse(value: &str, size: u64) -> RangeRequest {
+        let specs = match value.trim().split_once('=') {
+            Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+            _ => return RangeRequest::Full,
+        };
+
+        let mut ranges = vec![];
+        for (i, spec) in specs.split(',').enumerate() {
+            if i == MAX_RANGES {
+                return RangeRequest::Full;
+            }
+            let (first, last) = match spec.trim().split_once('-') {
+                Some(bounds) => bounds,
+                None => return RangeRequest::Full,
+            };
+            let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+                // last bytes
+                (Err(_), Ok(suffix)) if first.is_empty() => match suffix.min(size) {
+                    0 => None,
+                    suffix => Some(ByteRange {
+                        start: size - suffix,
+                        end: size - 1,
+                    }),
+                },
+                (Ok(start), Err(_)) if last.is_empty() => Some(ByteRange {
+                    start,
+                    end: size.saturating_sub(1),
+                }),
+                (Ok(start), Ok(end)) if start <= end => Some(ByteRange {
+                    start,
+                    end: end.min(size.saturating_sub(1)),
+                }),
+                _ => return RangeRequest::Full,
+            };
+            // ranges starting after the body arent satisfiable, but the others are still sent
+            ranges.extend(range.filter(|r| r.start < size));
+        }
+
+        if ranges.is_empty() {
+            return RangeRequest::Unsatisfiable;
+        }
+        ranges.sort_by_key(|r| r.start);
+        let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match coalesced.last_mut() {
+                Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+                _ => coalesced.push(range),
+            }
+        }
+        RangeRequest::Partial(coalesced)
+    }
+
+    // Range of a GET request. validator is the current ETag or Last-Modified of the resource:
+    // when the request has an If-Range with another value, the whole body is sent
+    pub fn from_request(req: &RhodRequest, size: u64, validator: Option<&str>) -> RangeRequest {
+        if req.method() != Method::GET {
+            return RangeRequest::Full;
+        }
+        let range = match req.header_str(RANGE) {
+            Some(range) => range,
+            None => return RangeRequest::Full,
+        };
+        match req.header_str(IF_RANGE) {
+            Some(if_range) if Some(if_range.trim()) != validator => RangeRequest::Full,
+            _ => RangeRequest::parse(range, size),
+        }
+    }
+}
+
+// Response to the request with the parts of the body it asks for
+pub fn ranged_response(
+    req: &RhodRequest,
+    body: Bytes,
+    content_type: &str,
+    validator: Option<&str>,
+) -> RhodResult<RhodResponse> {
+    let size = body.len() as u64;
+    let range = RangeRequest::from_request(req, size, validator);
+    let (mut res, parts) = prepare(&range, size, content_type)?;
+    let mut ranged = BytesMut::new();
+    for part in parts {
+        match part {
+            Part::Bytes(bytes) => ranged.extend_from_slice(&bytes),
+            Part::Range(r) => ranged.extend_from_slice(&body[r.start as usize..=r.end as usize]),
+        }
+    }
+    res.set_body(RhodBody::from(ranged.freeze()));
+    Ok(res)
+}
+
+// Response to the request with the parts of the reader it asks for, read while the body is sent.
+// size is the length of the reader content
+pub fn ranged_reader_response<R>(
+    req: &RhodRequest,
+    reader: R,
+    size: u64,
+    content_type: &str,
+    validator: Option<&str>,
+) -> RhodResult<RhodResponse>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let range = RangeRequest::from_request(req, size, validator);
+    let (mut res, parts) = prepare(&range, size, content_type)?;
+    res.set_body(stream_parts(reader, parts));
+    Ok(res)
+}
+
+// Pieces of a response body: framing of the multipart body, or a range of the content
+enum Part {
+    Bytes(Bytes),
+    Range(ByteRange),
+}
+
+// Response without body, and the parts of its body
+fn prepare(
+    range: &RangeRequest,
+    size: u64,
+    content_type: &str,
+) -> RhodResult<(RhodResponse, Vec<Part>)> {
+    let builder = RhodResponse::builder().header(ACCEPT_RANGES.as_str(), "bytes");
+    let (builder, parts) = match range {
+        RangeRequest::Full => {
+            let full = ByteRange {
+                start: 0,
+                end: size.saturating_sub(1),
+            };
+            let parts = if size > 0 {
+                vec![Part::Range(full)]
+            } else {
+                vec![]
+            };
+            (builder.header(CONTENT_TYPE.as_str(), content_type), parts)
+        }
+        RangeRequest::Unsatisfiable => {
+            let builder = builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE.as_str(), &format!("bytes */{}", size));
+            (builder, vec![])
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_TYPE.as_str(), content_type)
+                .header(CONTENT_RANGE.as_str(), &ranges[0].content_range(size));
+            (builder, vec![Part::Range(ranges[0])])
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..));
+            let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+            for (i, range) in ranges.iter().enumerate() {
+                let head = format!(
+                    "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    if i == 0 { "" } else { "\r\n" },
+                    boundary,
+                    content_type,
+                    range.content_range(size)
+                );
+                parts.push(Part::Bytes(Bytes::from(head)));
+                parts.push(Part::Range(*range));
+            }
+            parts.push(Part::Bytes(Bytes::from(format!(
+                "\r\n--{}--\r\n",
+                boundary
+            ))));
+            let multipart = format!("multipart/byteranges; boundary={}", boundary);
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_TYPE.as_str(), &multipart);
+            (builder, parts)
+        }
+    };
+    let length: u64 = parts
+        .iter()
+        .map(|part| match part {
+            Part::Bytes(bytes) => bytes.len() as u64,
+            Part::Range(range) => range.len(),
+        })
+        .sum();
+    let res = builder
+        .header(CONTENT_LENGTH.as_str(), &length.to_string())
+        .build()?;
+    Ok((res, parts))
+}
+
+// Body reading the ranges from the reader, in chunks
+fn stream_parts<R>(reader: R, parts: Vec<Part>) -> RhodBody
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let state = (reader, parts.into_iter(), 0u64); // bytes left of the range being read
+    let chunks =
+        futures_util::stream::try_unfold(state, |(mut reader, mut parts, mut left)| async move {
+            loop {
+                if left > 0 {
+                    let mut chunk = vec![0; left.min(CHUNK_SIZE) as usize];
+                    let read = reader.read(&mut chunk).await?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Content shorter than its size",
+                        ));
+                    }
+                    chunk.truncate(read);
+                    let left = left - read as u64;
+                    return Ok(Some((Bytes::from(chunk), (reader, parts, left))));
+                }
+                match parts.next() {
+                    Some(Part::Bytes(bytes)) => return Ok(Some((bytes, (reader, parts, 0)))),
+                    Some(Part::Range(range)) => {
+                        reader.seek(SeekFrom::Start(range.start)).await?;
+                        left = range.len();
+                    }
+                    None => return Ok(None),
+                }
+            }
+        });
+    RhodBody::wrap_stream(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        assert_eq!(
+            RangeRequest::parse("bytes=0-4, 20-, -5, 3-6", 100),
+            RangeRequest::Partial(vec![range(0, 6), range(20, 99)])
+        );
+        assert_eq!(
+            RangeRequest::parse("bytes=-500, 200-300", 100),
+            RangeRequest::Partial(vec![range(0, 99)])
+        );
+        assert_eq!(
+            RangeRequest::parse("bytes=100-", 100),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(RangeRequest::parse("bytes=5-1", 100), RangeRequest::Full);
+        assert_eq!(RangeRequest::parse("items=0-1", 100), RangeRequest::Full);
+
+        let content = Bytes::from_static(b"0123456789");
+        let req = RhodRequest::builder()
+            .header("Range", "bytes=2-4")
+            .build()
+            .unwrap();
+        let mut res = ranged_response(&req, content.clone(), "text/plain", None).unwrap();
+        assert_eq!(res.status_as_int(), 206);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(res.body().await.unwrap(), b"234");
+
+        // If-Range of an older version
+        let req = RhodRequest::builder()
+            .header("Range", "bytes=2-4")
+            .header("If-Range", "\"v1\"")
+            .build()
+            .unwrap();
+        let res = ranged_response(&req, content.clone(), "text/plain", Some("\"v2\"")).unwrap();
+        assert_eq!(res.status_as_int(), 200);
+
+        let req = RhodRequest::builder()
+            .header("Range", "bytes=0-1,-2")
+            .build()
+            .unwrap();
+        let reader = std::io::Cursor::new(content.to_vec());
+        let mut res = ranged_reader_response(&req, reader, 10, "text/plain", None).unwrap();
+        assert_eq!(res.status_as_int(), 206);
+        let content_type = res.headers()[CONTENT_TYPE].to_str().unwrap().to_string();
+        let boundary = content_type.split("boundary=").nth(1).unwrap().to_string();
+        let length: usize = res.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = String::from_utf8(res.body().await.unwrap()).unwrap();
+        assert_eq!(body.len(), length);
+        assert_eq!(
+            body,
+            format!(
+                "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{0}--\r\n",
+                boundary
+            )
+        );
+    }
+}
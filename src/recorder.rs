@@ -0,0 +1,529 @@
+// Recording of full transactions (request line, headers, bodies, timing and connection) as JSON lines,
+// and replay of the recorded requests against a RhodStack in-process, ie: for regression tests.
+// RecorderHandler captures the bodies while they stream, up to a maximum size (the rest is marked as
+// truncated), and writes a transaction when its response body is dropped. Only the part of the request
+// body read by the stack is recorded. Bodies are base64 encoded.
+use crate::body::{BoxError, RhodBody};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::protocols::HttpProtocol;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::{RhodHandler, RhodStack, StackTiming};
+use crate::{CommunicationChannel, RhodConnInfo};
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use http::{HeaderMap, Method, Version};
+use http_body::{Body, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod har;
+pub use har::{to_har, write_har};
+
+// Bodies captured by default, per transaction and direction
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedTransaction {
+    pub started_at: u64,  // unix millis
+    pub duration_ms: u64, // until the response body was sent
+    pub client: String,
+    pub protocol: String, // http or https
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: String, // base64
+    pub request_truncated: bool,
+    pub status: Option<u16>, // None when the stack failed without a response
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String, // base64
+    pub response_truncated: bool,
+    #[serde(default)]
+    pub stages: Vec<RecordedStage>, // time of the handlers and the service, in execution order
+}
+
+// Time spent by a handler or the service of the stack, see StackTiming
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedStage {
+    pub name: String,
+    pub stage: String, // handle_request, serve or handle_response
+    pub duration_us: u64,
+}
+
+impl RecordedTransaction {
+    pub fn request_body(&self) -> Vec<u8> {
+        decode(&self.request_body)
+    }
+
+    pub fn response_body(&self) -> Vec<u8> {
+        decode(&self.response_body)
+    }
+
+    // Request as it was received
+    pub fn request(&self) -> RhodResult<RhodRequest> {
+        let method = Method::from_bytes(self.method.as_bytes()).map_err(|e| {
+            RhodError::from_string(
+                format!("Invalid recorded method {}. {}", self.method, e),
+                RhodErrorLevel::Error,
+            )
+        })?;
+        let mut builder = RhodRequest::builder()
+            .method(method)
+            .uri(&self.uri)
+            .version(parse_version(&self.version));
+        for (name, value) in &self.request_headers {
+            builder = builder.header(name, value);
+        }
+        builder.body_bytes(&self.request_body()).build()
+    }
+
+    // Connection the request came from
+    pub fn conn_info(&self) -> Option<RhodConnInfo> {
+        let proto = match self.protocol.as_str() {
+            "https" => HttpProtocol::HTTPS,
+            _ => HttpProtocol::HTTP,
+        };
+        Some(RhodConnInfo::new(self.client.parse().ok()?, proto))
+    }
+}
+
+fn stages(timing: &StackTiming) -> Vec<RecordedStage> {
+    let stage = |name: &str, stage: &str, elapsed: &Duration| RecordedStage {
+        name: name.to_string(),
+        stage: stage.to_string(),
+        duration_us: elapsed.as_micros() as u64,
+    };
+    let mut stages: Vec<RecordedStage> = timing
+        .requests
+        .iter()
+        .map(|(name, elapsed)| stage(name, "handle_request", elapsed))
+        .collect();
+    stages.extend(
+        timing
+            .service
+            .iter()
+            .map(|elapsed| stage("service", "serve", elapsed)),
+    );
+    stages.extend(
+        timing
+            .responses
+            .iter()
+            .map(|(name, elapsed)| stage(name, "handle_response", elapsed)),
+    );
+    stages
+}
+
+fn decode(body: &str) -> Vec<u8> {
+    base64::engine::general_purpose::STANDARD
+        .decode(body)
+        .unwrap_or_default()
+}
+
+fn parse_version(version: &str) -> Version {
+    match version {
+        "HTTP/0.9" => Version::HTTP_09,
+        "HTTP/1.0" => Version::HTTP_10,
+        "HTTP/2.0" => Version::HTTP_2,
+        "HTTP/3.0" => Version::HTTP_3,
+        _ => Version::HTTP_11,
+    }
+}
+
+fn header_list(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+// Records every request of the stack. Added first, it sees the request as received and the
+// response as sent
+pub struct RecorderHandler {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    max_body_size: usize,
+}
+
+impl RecorderHandler {
+    // Writes the transactions, one JSON per line
+    pub fn new(out: Box<dyn Write + Send>) -> RecorderHandler {
+        RecorderHandler {
+            out: Arc::new(Mutex::new(out)),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    // Appends the transactions to the file, creating it if needed
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<RecorderHandler> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecorderHandler::new(Box::new(file)))
+    }
+
+    // Bytes of each body recorded, the rest is dropped from the record
+    pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+        RecorderHandler {
+            max_body_size,
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for RecorderHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let transaction = RecordedTransaction {
+            started_at,
+            duration_ms: 0,
+            client: conn.addr.to_string(),
+            protocol: conn.proto.to_string().to_string(),
+            method: req.method_str().to_string(),
+            uri: req.uri().to_string(),
+            version: req.version_string(),
+            request_headers: header_list(req.headers()),
+            request_body: String::new(),
+            request_truncated: false,
+            status: None,
+            response_headers: vec![],
+            response_body: String::new(),
+            response_truncated: false,
+            stages: vec![],
+        };
+        let request = Arc::new(Mutex::new(Capture::new(self.max_body_size)));
+        let body = CapturedBody {
+            inner: req.take_body(),
+            capture: Arc::clone(&request),
+            _recording: None,
+        };
+        req.set_body(RhodBody::new(body));
+        req.context().insert(Recording {
+            transaction,
+            started: Instant::now(),
+            request,
+            response: Arc::new(Mutex::new(Capture::new(self.max_body_size))),
+            out: Arc::clone(&self.out),
+        });
+        Ok(())
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        mut res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        let mut recording = match res.context().remove::<Recording>() {
+            Some(recording) => recording,
+            None => return (res, Ok(())),
+        };
+        recording.set_response(&res);
+        let body = CapturedBody {
+            inner: res.take_body(),
+            capture: Arc::clone(&recording.response),
+            _recording: Some(recording),
+        };
+        res.set_body(RhodBody::new(body));
+        (res, Ok(()))
+    }
+
+    // the body of a failed response isnt sent, so it isnt recorded
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+        if let Some(mut recording) = res.context().remove::<Recording>() {
+            recording.set_response(res);
+        }
+    }
+}
+
+// First bytes of a body
+struct Capture {
+    data: BytesMut,
+    max: usize,
+    truncated: bool,
+}
+
+impl Capture {
+    fn new(max: usize) -> Capture {
+        Capture {
+            data: BytesMut::new(),
+            max,
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, data: &Bytes) {
+        let left = self.max.saturating_sub(self.data.len());
+        if data.len() > left {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&data[..data.len().min(left)]);
+    }
+
+    fn encode(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.data)
+    }
+}
+
+fn lock(capture: &Mutex<Capture>) -> std::sync::MutexGuard<'_, Capture> {
+    capture.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// A transaction being recorded, saved in the request context. It is written when dropped
+struct Recording {
+    transaction: RecordedTransaction,
+    started: Instant,
+    request: Arc<Mutex<Capture>>,
+    response: Arc<Mutex<Capture>>,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Recording {
+    fn set_response(&mut self, res: &RhodResponse) {
+        self.transaction.status = Some(res.status_as_int());
+        self.transaction.response_headers = header_list(res.headers());
+        // the handle_response of the recorder isnt timed yet
+        if let Some(timing) = res.context().get::<StackTiming>() {
+            self.transaction.stages = stages(&timing);
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let transaction = &mut self.transaction;
+        transaction.duration_ms = self.started.elapsed().as_millis() as u64;
+        let request = lock(&self.request);
+        transaction.request_body = request.encode();
+        transaction.request_truncated = request.truncated;
+        let response = lock(&self.response);
+        transaction.response_body = response.encode();
+        transaction.response_truncated = response.truncated;
+
+        let mut line = match serde_json::to_vec(transaction) {
+            Ok(line) => line,
+            Err(e) => return warn!("Couldnt serialize recorded transaction. {}", e),
+        };
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = out.write_all(&line).and_then(|_| out.flush()) {
+            warn!("Couldnt write recorded transaction. {}", e);
+        }
+    }
+}
+
+// Body copying its data to a capture. A response body keeps the recording until it is dropped
+struct CapturedBody {
+    inner: RhodBody,
+    capture: Arc<Mutex<Capture>>,
+    _recording: Option<Recording>, // written when the body is dropped
+}
+
+impl Body for CapturedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &polled {
+            if let Some(data) = frame.data_ref() {
+                lock(&self.capture).push(data);
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Reads the transactions written by a RecorderHandler. Lines that arent transactions are skipped
+pub fn read_records<R: BufRead>(reader: R) -> io::Result<Vec<RecordedTransaction>> {
+    let mut records = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping invalid recorded transaction. {}", e),
+        }
+    }
+    Ok(records)
+}
+
+pub fn load_records<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedTransaction>> {
+    read_records(io::BufReader::new(std::fs::File::open(path)?))
+}
+
+// Result of replaying a recorded transaction
+#[derive(Debug)]
+pub struct ReplayOutcome {
+    pub recorded: RecordedTransaction,
+    pub status: Option<u16>,
+    pub body: Vec<u8>,
+    pub error: Option<String>,
+}
+
+impl ReplayOutcome {
+    // Same status and, if the recorded body is complete, same body
+    pub fn matches(&self) -> bool {
+        self.status == self.recorded.status
+            && (self.recorded.response_truncated || self.body == self.recorded.response_body())
+    }
+}
+
+// Submits the recorded requests to the stack, in order, as sent by the recorded connections
+pub async fn replay<C: CommunicationChannel>(
+    stack: &RhodStack<C>,
+    records: Vec<RecordedTransaction>,
+) -> Vec<ReplayOutcome> {
+    let mut outcomes = Vec::with_capacity(records.len());
+    for recorded in records {
+        let mut outcome = ReplayOutcome {
+            recorded,
+            status: None,
+            body: vec![],
+            error: None,
+        };
+        let conn = outcome
+            .recorded
+            .conn_info()
+            .unwrap_or_else(|| RhodConnInfo::new(([127, 0, 0, 1], 0).into(), HttpProtocol::HTTP));
+        let res = match outcome.recorded.request() {
+            Ok(req) => stack.execute(&conn, req).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(mut res) => {
+                outcome.status = Some(res.status_as_int());
+                match res.body().await {
+                    Ok(body) => outcome.body = body,
+                    Err(e) => outcome.error = Some(e.to_string()),
+                }
+            }
+            Err(mut e) => match e.take_response() {
+                Some(mut res) => {
+                    outcome.status = Some(res.status_as_int());
+                    outcome.body = res.body().await.unwrap_or_default();
+                }
+                None => outcome.error = Some(e.to_string()),
+            },
+        }
+        outcomes.push(outcome);
+    }
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack::{RhodHandlerInStack, RhodService};
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // Answers with the request body
+    struct EchoService {}
+    #[async_trait]
+    impl RhodService<Comm> for EchoService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            mut req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let body = req.body().await?;
+            RhodResponse::builder().body_bytes(&body).build()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("rhodium-recorder-{}", std::process::id()));
+        let recorder = RecorderHandler::to_file(&path)
+            .unwrap()
+            .with_max_body_size(8);
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(recorder))],
+            Box::new(EchoService {}),
+        );
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTPS);
+        for body in ["hello", "a longer body"] {
+            let req = RhodRequest::builder()
+                .method(Method::POST)
+                .uri("/echo?x=1")
+                .header("X-Test", "yes")
+                .body_str(body)
+                .build()
+                .unwrap();
+            let mut res = stack.execute(&conn, req).await.unwrap();
+            assert_eq!(res.body().await.unwrap(), body.as_bytes());
+        }
+
+        let records = load_records(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].client, "10.0.0.1:4000");
+        assert_eq!(records[0].protocol, "https");
+        assert_eq!(records[0].uri, "/echo?x=1");
+        assert_eq!(records[0].status, Some(200));
+        assert_eq!(records[0].request_body(), b"hello");
+        assert_eq!(records[0].response_body(), b"hello");
+        assert!(records[0].stages.iter().any(|s| s.stage == "serve"));
+        assert!(records[0]
+            .request_headers
+            .contains(&("x-test".to_string(), "yes".to_string())));
+        assert!(records[1].request_truncated && records[1].response_truncated);
+        assert_eq!(records[1].request_body(), b"a longer");
+
+        let stack = RhodStack::new(vec![], Box::new(EchoService {}));
+        let outcomes = replay(&stack, records).await;
+        assert!(outcomes[0].matches());
+        assert_eq!(outcomes[1].body, b"a longer");
+        assert!(outcomes[1].matches());
+    }
+}
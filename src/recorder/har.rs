@@ -0,0 +1,233 @@
+// Export of recorded transactions as an HTTP Archive (HAR 1.2), readable by browser devtools.
+// The time of the stack is reported as wait, and the rest of the transaction (sending the response
+// body) as receive. The time of every handler and the service is added as the custom field _stages.
+use super::RecordedTransaction;
+use crate::log_sink::iso8601;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+// HAR log of the transactions
+pub fn to_har(records: &[RecordedTransaction]) -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "rhodium", "version": env!("CARGO_PKG_VERSION") },
+            "entries": records.iter().map(entry).collect::<Vec<Value>>(),
+        }
+    })
+}
+
+pub fn write_har<W: Write>(out: W, records: &[RecordedTransaction]) -> io::Result<()> {
+    serde_json::to_writer_pretty(out, &to_har(records)).map_err(io::Error::from)
+}
+
+fn entry(record: &RecordedTransaction) -> Value {
+    let wait_us: u64 = record.stages.iter().map(|s| s.duration_us).sum();
+    let wait = wait_us as f64 / 1000.0;
+    let time = (record.duration_ms as f64).max(wait);
+    let stages: Vec<Value> = record
+        .stages
+        .iter()
+        .map(|s| json!({ "name": s.name, "stage": s.stage, "time": s.duration_us as f64 / 1000.0 }))
+        .collect();
+
+    let mut entry = json!({
+        "startedDateTime": iso8601(record.started_at),
+        "time": time,
+        "request": request(record),
+        "response": response(record),
+        "cache": {},
+        "timings": { "blocked": -1, "dns": -1, "connect": -1, "send": 0, "wait": wait, "receive": time - wait },
+        "_client": record.client,
+        "_stages": stages,
+    });
+    if record.status.is_none() {
+        entry["comment"] = json!("The stack failed without a response");
+    }
+    entry
+}
+
+fn request(record: &RecordedTransaction) -> Value {
+    let body = record.request_body();
+    let mut request = json!({
+        "method": record.method,
+        "url": url(record),
+        "httpVersion": record.version,
+        "cookies": cookies(&record.request_headers, "cookie"),
+        "headers": headers(&record.request_headers),
+        "queryString": query(&record.uri),
+        "headersSize": -1,
+        "bodySize": body.len(),
+    });
+    if !body.is_empty() {
+        let (text, encoding) = text(&body);
+        request["postData"] = json!({
+            "mimeType": header(&record.request_headers, "content-type").unwrap_or(""),
+            "text": text,
+        });
+        if let Some(encoding) = encoding {
+            request["postData"]["_encoding"] = json!(encoding);
+        }
+    }
+    if record.request_truncated {
+        request["comment"] = json!("Body truncated");
+    }
+    request
+}
+
+fn response(record: &RecordedTransaction) -> Value {
+    let body = record.response_body();
+    let status = record.status.unwrap_or(0);
+    let status_text = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("");
+    let (text, encoding) = text(&body);
+    let mut content = json!({
+        "size": body.len(),
+        "mimeType": header(&record.response_headers, "content-type").unwrap_or(""),
+        "text": text,
+    });
+    if let Some(encoding) = encoding {
+        content["encoding"] = json!(encoding);
+    }
+    let mut response = json!({
+        "status": status,
+        "statusText": status_text,
+        "httpVersion": record.version,
+        "cookies": cookies(&record.response_headers, "set-cookie"),
+        "headers": headers(&record.response_headers),
+        "content": content,
+        "redirectURL": header(&record.response_headers, "location").unwrap_or(""),
+        "headersSize": -1,
+        "bodySize": body.len(),
+    });
+    if record.response_truncated {
+        response["comment"] = json!("Body truncated");
+    }
+    response
+}
+
+// Absolute url: relative uris are completed with the Host header
+fn url(record: &RecordedTransaction) -> String {
+    if record.uri.contains("://") {
+        return record.uri.clone();
+    }
+    let host = header(&record.request_headers, "host").unwrap_or("localhost");
+    format!("{}://{}{}", record.protocol, host, record.uri)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn headers(headers: &[(String, String)]) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+// Cookies of the Cookie request headers or the Set-Cookie response headers
+fn cookies(headers: &[(String, String)], name: &str) -> Vec<Value> {
+    headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| {
+            let pairs: Vec<&str> = if name == "cookie" {
+                value.split(';').collect()
+            } else {
+                value.split(';').take(1).collect()
+            };
+            pairs.into_iter().filter_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                Some(json!({ "name": name, "value": value }))
+            })
+        })
+        .collect()
+}
+
+fn query(uri: &str) -> Vec<Value> {
+    let query = match uri.split_once('?') {
+        Some((_, query)) => query.split('#').next().unwrap_or(""),
+        None => return vec![],
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| {
+                percent_encoding::percent_decode_str(s)
+                    .decode_utf8_lossy()
+                    .into_owned()
+            };
+            json!({ "name": decode(name), "value": decode(value) })
+        })
+        .collect()
+}
+
+// Body as text, or base64 when it isnt utf-8
+fn text(body: &[u8]) -> (String, Option<&'static str>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(body);
+            (encoded, Some("base64"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RecordedStage;
+    use super::*;
+
+    #[test]
+    fn test_har() {
+        let record = RecordedTransaction {
+            started_at: 1_700_000_000_123,
+            duration_ms: 12,
+            client: "10.0.0.1:4000".to_string(),
+            protocol: "http".to_string(),
+            method: "POST".to_string(),
+            uri: "/search?q=a%20b&page=2".to_string(),
+            version: "HTTP/1.1".to_string(),
+            request_headers: vec![
+                ("host".to_string(), "example.com".to_string()),
+                ("cookie".to_string(), "a=1; b=2".to_string()),
+            ],
+            request_body: "aGVsbG8=".to_string(),
+            request_truncated: false,
+            status: Some(200),
+            response_headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            response_body: "/w==".to_string(),
+            response_truncated: false,
+            stages: vec![RecordedStage {
+                name: "service".to_string(),
+                stage: "serve".to_string(),
+                duration_us: 4000,
+            }],
+        };
+        let har = to_har(&[record]);
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(entry["startedDateTime"], "2023-11-14T22:13:20.123Z");
+        assert_eq!(
+            entry["request"]["url"],
+            "http://example.com/search?q=a%20b&page=2"
+        );
+        assert_eq!(entry["request"]["queryString"][0]["value"], "a b");
+        assert_eq!(entry["request"]["cookies"][1]["name"], "b");
+        assert_eq!(entry["request"]["postData"]["text"], "hello");
+        assert_eq!(entry["response"]["statusText"], "OK");
+        assert_eq!(entry["response"]["content"]["encoding"], "base64");
+        assert_eq!(entry["timings"]["wait"], 4.0);
+        assert_eq!(entry["timings"]["receive"], 8.0);
+        assert_eq!(entry["_stages"][0]["time"], 4.0);
+    }
+}
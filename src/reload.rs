@@ -0,0 +1,249 @@
+// Hot reload of the config file a server was created from (Rhodium::from_config), on SIGHUP or on demand.
+// Handlers, connection limits and timeouts, TLS material and the log level are replaced without restarting:
+// requests arriving after a reload are served by the new stack (sharing the service and the StateMap),
+// and connections accepted after it use the new limits and TLS material.
+// A config that doesnt parse, or whose TLS material cant be loaded, is reported and not applied.
+// The listen address, acceptors, socket options and switching between HTTP and HTTPS need a restart.
+use crate::config::RhodConfig;
+use crate::errors::RhodHyperError;
+use crate::hyper_config::get_configuration;
+use crate::protocols::ConnectionConf;
+use crate::stack::{RhodService, RhodStack};
+use crate::state::StateMap;
+use crate::CommunicationChannel;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
+
+// Value replaced while it is being used: readers keep the value they loaded
+pub(crate) struct Swap<T> {
+    value: RwLock<Arc<T>>,
+}
+
+impl<T> Swap<T> {
+    pub(crate) fn new(value: Arc<T>) -> Swap<T> {
+        Swap {
+            value: RwLock::new(value),
+        }
+    }
+
+    pub(crate) fn load(&self) -> Arc<T> {
+        Arc::clone(&self.value.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub(crate) fn store(&self, value: Arc<T>) {
+        *self.value.write().unwrap_or_else(|e| e.into_inner()) = value;
+    }
+}
+
+// TLS settings read by the acceptor for every handshake
+pub(crate) struct TlsListenerConf {
+    pub(crate) server_config: Arc<ServerConfig>,
+    pub(crate) handshake_timeout: Option<Duration>,
+}
+
+impl TlsListenerConf {
+    pub(crate) fn shared(
+        server_config: Arc<ServerConfig>,
+        handshake_timeout: Option<Duration>,
+    ) -> Arc<Swap<TlsListenerConf>> {
+        Arc::new(Swap::new(Arc::new(TlsListenerConf {
+            server_config,
+            handshake_timeout,
+        })))
+    }
+}
+
+// Settings of a running server that can be reloaded
+pub(crate) struct LiveSettings<C> {
+    pub(crate) stack: Swap<RhodStack<C>>,
+    pub(crate) conn_conf: Swap<ConnectionConf>,
+    pub(crate) tls: Mutex<Option<Arc<Swap<TlsListenerConf>>>>, // set when an HTTPS server starts
+}
+
+impl<C> LiveSettings<C> {
+    pub(crate) fn new(stack: Arc<RhodStack<C>>) -> LiveSettings<C> {
+        LiveSettings {
+            stack: Swap::new(stack),
+            conn_conf: Swap::new(Arc::new(ConnectionConf::default())),
+            tls: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn tls(&self) -> Option<Arc<Swap<TlsListenerConf>>> {
+        self.tls.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+pub struct ConfigReloader<C> {
+    path: PathBuf,
+    service: Arc<dyn RhodService<C>>,
+    state: Arc<StateMap>,
+    live: Arc<LiveSettings<C>>,
+    current: Mutex<RhodConfig>, // last applied config
+}
+
+impl<C: CommunicationChannel> ConfigReloader<C> {
+    pub(crate) fn new(
+        path: PathBuf,
+        service: Arc<dyn RhodService<C>>,
+        state: Arc<StateMap>,
+        live: Arc<LiveSettings<C>>,
+        current: RhodConfig,
+    ) -> ConfigReloader<C> {
+        ConfigReloader {
+            path,
+            service,
+            state,
+            live,
+            current: Mutex::new(current),
+        }
+    }
+
+    // Stack built from the handlers of the config, followed by the service
+    pub(crate) fn stack(
+        config: &RhodConfig,
+        service: &Arc<dyn RhodService<C>>,
+        state: &Arc<StateMap>,
+    ) -> RhodStack<C> {
+        RhodStack::new(config.stack_handlers(), Box::new(Arc::clone(service)))
+            .with_state(Arc::clone(state))
+            .with_response_limits(config.server.response_limits())
+            .with_auto_headers(config.server.auto_headers())
+    }
+
+    // Last applied config
+    pub fn current(&self) -> RhodConfig {
+        self.current
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Reads the config file again and applies it. A broken config is not applied
+    pub fn reload(&self) -> Result<(), RhodHyperError> {
+        let config = RhodConfig::from_file(&self.path)?;
+        self.apply(config)?;
+        info!("Configuration reloaded from {}", self.path.display());
+        Ok(())
+    }
+
+    fn apply(&self, config: RhodConfig) -> Result<(), RhodHyperError> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let server = &config.server;
+        if server.tls.is_some() != current.server.tls.is_some() {
+            return Err(RhodHyperError::ConfigError(
+                "Switching between HTTP and HTTPS needs a restart".to_string(),
+            ));
+        }
+
+        // everything is validated before changing anything
+        let conn_conf = server.connection_conf();
+        let tls = match server.tls {
+            Some(_) => Some(TlsListenerConf {
+                server_config: get_configuration(
+                    &server.protocol(),
+                    &server.tls_config(),
+                    &conn_conf,
+                )
+                .map_err(|e| RhodHyperError::ConfigError(format!("Invalid TLS material. {}", e)))?,
+                handshake_timeout: conn_conf.tls_handshake_timeout,
+            }),
+            None => None,
+        };
+        let level = config.log.level_filter()?;
+
+        let restart = server.listen != current.server.listen
+            || server.acceptors != current.server.acceptors
+            || server.socket != current.server.socket
+            || server.limits.backlog != current.server.limits.backlog;
+        if restart {
+            warn!("Listen address, acceptors and socket options are applied after a restart");
+        }
+
+        let stack = ConfigReloader::stack(&config, &self.service, &self.state);
+        self.live.stack.store(Arc::new(stack));
+        self.live.conn_conf.store(Arc::new(conn_conf));
+        if let (Some(tls), Some(live_tls)) = (tls, self.live.tls()) {
+            live_tls.store(Arc::new(tls));
+        }
+        if let Some(level) = level {
+            log::set_max_level(level);
+        }
+        *current = config;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::RhodResult;
+    use crate::protocols::HttpProtocol;
+    use crate::request::RhodRequest;
+    use crate::response::RhodResponse;
+    use crate::RhodConnInfo;
+    use async_trait::async_trait;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    struct Service {}
+    #[async_trait]
+    impl RhodService<Comm> for Service {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            RhodResponse::builder().build()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("rhodium-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[handlers]]\ntype = \"normalize\"\n").unwrap();
+
+        let config = RhodConfig::from_file(&path).unwrap();
+        let service: Arc<dyn RhodService<Comm>> = Arc::new(Service {});
+        let state = Arc::new(StateMap::new());
+        let stack = ConfigReloader::stack(&config, &service, &state);
+        let live = Arc::new(LiveSettings::new(Arc::new(stack)));
+        let reloader = ConfigReloader::new(path.clone(), service, state, Arc::clone(&live), config);
+        let serving = live.stack.load();
+        assert_eq!(serving.describe().handlers.len(), 1);
+
+        std::fs::write(
+            &path,
+            "[server.timeouts]\nidle_secs = 5\n[[handlers]]\ntype = \"normalize\"\n[[handlers]]\ntype = \"conditional_get\"\n",
+        )
+        .unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(live.stack.load().describe().handlers.len(), 2);
+        assert_eq!(
+            live.conn_conf.load().idle_timeout,
+            Some(Duration::from_secs(5))
+        );
+        // the state is kept, and requests in flight keep the stack they loaded
+        assert!(Arc::ptr_eq(live.stack.load().state(), serving.state()));
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder().build().unwrap();
+        assert!(serving.execute(&conn, req).await.is_ok());
+
+        // broken configs are not applied
+        std::fs::write(&path, "[[handlers]]\ntype = \"unknown\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        std::fs::write(&path, "[server.tls]\ncert_file = \"a\"\nkey_file = \"b\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(live.stack.load().describe().handlers.len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
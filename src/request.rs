@@ -1,26 +1,116 @@
+use crate::body::{prepend, read_limited, read_with_trailers, with_trailers, BodyBuffer, RhodBody};
+use crate::context::RhodContext;
 use crate::errors::*;
-use hyper::body::Body as HyperBody;
-use hyper::http::Request as HyperRequest;
-use hyper::{header::HeaderValue, HeaderMap, Method, Uri, Version};
-
-#[derive(Debug, PartialEq, Eq)]
+use crate::negotiation::Accept;
+use bytes::Bytes;
+use http::header::{
+    AsHeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST,
+    USER_AGENT,
+};
+use http::request::Builder as HyperRequestBuilder;
+use http::Request as HyperRequest;
+use http::{Extensions, HeaderMap, Method, Uri, Version};
+use http_body::Body as _;
+use mime::Mime;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BodyProcessor {
     URLENCODED,
-    XML,
-    JSON,
+    XML,  // application/xml, text/xml and +xml types
+    JSON, // application/json and +json types
     MULTIPART,
+    GRPC, // application/grpc(+proto, -web...), bodies are streams and must not be buffered
+    TEXT, // other text/* types
     Other,
 }
 
+impl BodyProcessor {
+    fn of(mime: &Mime) -> BodyProcessor {
+        let (kind, subtype) = (mime.type_().as_str(), mime.subtype().as_str());
+        let suffix = mime.suffix().map(|s| s.as_str());
+        match (kind, subtype) {
+            ("application", s) if s.starts_with("grpc") => BodyProcessor::GRPC,
+            ("application", "x-www-form-urlencoded") => BodyProcessor::URLENCODED,
+            ("application", "json") => BodyProcessor::JSON,
+            ("application", "xml") | ("text", "xml") => BodyProcessor::XML,
+            ("multipart", "form-data") => BodyProcessor::MULTIPART,
+            _ if suffix == Some("json") => BodyProcessor::JSON,
+            _ if suffix == Some("xml") => BodyProcessor::XML,
+            ("text", _) => BodyProcessor::TEXT,
+            _ => BodyProcessor::Other,
+        }
+    }
+}
+
+// Content-Type parsed with its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaType {
+    mime: Mime,
+    processor: BodyProcessor,
+}
+
+impl MediaType {
+    pub fn parse(value: &str) -> Option<MediaType> {
+        let mime = value.trim().parse::<Mime>().ok()?;
+        Some(MediaType {
+            processor: BodyProcessor::of(&mime),
+            mime,
+        })
+    }
+
+    pub fn mime(&self) -> &Mime {
+        &self.mime
+    }
+
+    pub fn processor(&self) -> BodyProcessor {
+        self.processor
+    }
+
+    // Lowercased charset parameter
+    pub fn charset(&self) -> Option<String> {
+        self.mime
+            .get_param(mime::CHARSET)
+            .map(|charset| charset.as_str().to_ascii_lowercase())
+    }
+
+    // Boundary of multipart bodies
+    pub fn boundary(&self) -> Option<&str> {
+        self.mime.get_param(mime::BOUNDARY).map(|b| b.as_str())
+    }
+}
+
 // Extends HyperRequest
 #[derive(Debug)]
 pub struct RhodRequest {
-    req: Option<HyperRequest<HyperBody>>, // Is allways Some(..)
+    req: Option<HyperRequest<RhodBody>>, // Is allways Some(..)
+    context: RhodContext,
+    trailers: Option<HeaderMap>, // read from the body or set by handlers, sent after the body
 }
 
 impl RhodRequest {
-    pub fn new(req: HyperRequest<HyperBody>) -> RhodRequest {
-        RhodRequest { req: Some(req) }
+    // The context inserted by into_hyper_request is taken back from the extensions
+    pub fn new(mut req: HyperRequest<RhodBody>) -> RhodRequest {
+        let context = req.extensions_mut().remove::<RhodContext>();
+        RhodRequest {
+            req: Some(req),
+            context: context.unwrap_or_default(),
+            trailers: None,
+        }
+    }
+
+    // Typed values shared between handlers, also available from the response
+    pub fn context(&self) -> &RhodContext {
+        &self.context
+    }
+
+    // Test-friendly constructor, avoids the http builder + body boilerplate
+    pub fn builder() -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            inner: HyperRequest::builder(),
+            body: RhodBody::empty(),
+            err: None,
+        }
     }
 
     pub fn uri(&self) -> &Uri {
@@ -35,6 +125,10 @@ impl RhodRequest {
         self.req.as_ref().unwrap().method()
     }
 
+    pub fn method_mut(&mut self) -> &mut Method {
+        self.req.as_mut().unwrap().method_mut()
+    }
+
     pub fn is_post(&self) -> bool {
         self.method() == Method::POST
     }
@@ -55,19 +149,41 @@ impl RhodRequest {
         self.req.as_mut().unwrap().headers_mut()
     }
 
+    // Extensions of the http request, set by hyper or by integrations (ie: tower layers)
+    pub fn extensions(&self) -> &Extensions {
+        self.req.as_ref().unwrap().extensions()
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        self.req.as_mut().unwrap().extensions_mut()
+    }
+
+    // Copy of the whole body, see body_bytes
     pub async fn body(&mut self) -> RhodResult<Vec<u8>> {
+        Ok(self.body_bytes().await?.to_vec())
+    }
+
+    // Reads the whole body. The request keeps it to be sent, sharing the buffer with the returned Bytes,
+    // so calling it again doesnt read nor copy it
+    pub async fn body_bytes(&mut self) -> RhodResult<Bytes> {
         let r = self.req.take().unwrap();
 
         let (header, body) = r.into_parts();
-        match hyper::body::to_bytes(body).await {
-            Ok(b) => {
-                let cloned = b.clone();
-                self.req = Some(HyperRequest::from_parts(header, HyperBody::from(b)));
-                Ok(cloned.to_vec())
+        match read_with_trailers(body).await {
+            Ok((b, trailers)) => {
+                if let Some(mut trailers) = trailers {
+                    // trailers set by handlers replace the ones of the body
+                    if let Some(set) = self.trailers.take() {
+                        trailers.extend(set);
+                    }
+                    self.trailers = Some(trailers);
+                }
+                self.req = Some(HyperRequest::from_parts(header, RhodBody::from(b.clone())));
+                Ok(b)
             }
             Err(e) => {
                 // If error, body cant be recovered.
-                self.req = Some(HyperRequest::from_parts(header, HyperBody::empty()));
+                self.req = Some(HyperRequest::from_parts(header, RhodBody::empty()));
 
                 Err(RhodError::from_string(
                     format!("Cant parse request body to bytes. {}", e),
@@ -77,31 +193,195 @@ impl RhodRequest {
         }
     }
 
-    pub fn body_processor(&self) -> Option<BodyProcessor> {
-        match self.headers().get("Content-Type") {
-            Some(c) => {
-                let value = c.to_str();
-                if let Ok(value) = value {
-                    let value = value.to_lowercase();
-                    if value.contains("application/x-www-form-urlencoded") {
-                        Some(BodyProcessor::URLENCODED)
-                    } else if value.contains("application/xml") {
-                        Some(BodyProcessor::XML)
-                    } else if value.contains("application/json") {
-                        Some(BodyProcessor::JSON)
-                    } else if value.contains("multipart/form-data") {
-                        Some(BodyProcessor::MULTIPART)
-                    } else {
-                        Some(BodyProcessor::Other)
+    // Reads the whole body, keeping up to threshold bytes in memory and the rest in a temporary file.
+    // The request keeps a body streaming the buffer, for the next handlers and the service
+    pub async fn buffer_body(&mut self, threshold: usize) -> RhodResult<BodyBuffer> {
+        let r = self.req.take().unwrap();
+
+        let (header, body) = r.into_parts();
+        match BodyBuffer::collect(body, threshold).await {
+            Ok(buffer) => {
+                if let Some(trailers) = buffer.trailers() {
+                    let mut trailers = trailers.clone();
+                    // trailers set by handlers replace the ones of the body
+                    if let Some(set) = self.trailers.take() {
+                        trailers.extend(set);
+                    }
+                    self.trailers = Some(trailers);
+                }
+                self.req = Some(HyperRequest::from_parts(header, buffer.to_body()));
+                Ok(buffer)
+            }
+            Err(e) => {
+                // If error, body cant be recovered.
+                self.req = Some(HyperRequest::from_parts(header, RhodBody::empty()));
+
+                Err(RhodError::from_string(
+                    format!("Cant buffer request body. {}", e),
+                    RhodErrorLevel::Error,
+                ))
+            }
+        }
+    }
+
+    // Reads the whole body like body_bytes if it isnt longer than limit bytes, None otherwise. Bodies
+    // known to be longer (ie: by their Content-Length) arent read, and the request keeps the whole body
+    pub async fn body_bytes_limited(&mut self, limit: usize) -> RhodResult<Option<Bytes>> {
+        if self.req.as_ref().unwrap().body().size_hint().lower() > limit as u64 {
+            return Ok(None);
+        }
+        Ok(self.read_limited(limit).await?.ok())
+    }
+
+    // Reads the body up to limit bytes: the whole body and true if it isnt longer, or its first limit
+    // bytes and false. The request keeps the whole body, the bytes read are streamed before the rest
+    pub async fn body_prefix(&mut self, limit: usize) -> RhodResult<(Bytes, bool)> {
+        match self.read_limited(limit).await? {
+            Ok(body) => Ok((body, true)),
+            Err(read) => Ok((read.slice(..read.len().min(limit)), false)),
+        }
+    }
+
+    // The whole body if it isnt longer than limit, or the bytes read of it
+    async fn read_limited(&mut self, limit: usize) -> RhodResult<Result<Bytes, Bytes>> {
+        let (header, body) = self.req.take().unwrap().into_parts();
+        match read_limited(body, limit).await {
+            Ok((b, trailers, None)) => {
+                if let Some(mut trailers) = trailers {
+                    // trailers set by handlers replace the ones of the body
+                    if let Some(set) = self.trailers.take() {
+                        trailers.extend(set);
                     }
-                } else {
-                    None
+                    self.trailers = Some(trailers);
                 }
+                self.req = Some(HyperRequest::from_parts(header, RhodBody::from(b.clone())));
+                Ok(Ok(b))
+            }
+            Ok((read, _, Some(rest))) => {
+                self.req = Some(HyperRequest::from_parts(
+                    header,
+                    prepend(read.clone(), rest),
+                ));
+                Ok(Err(read))
+            }
+            Err(e) => {
+                // If error, body cant be recovered.
+                self.req = Some(HyperRequest::from_parts(header, RhodBody::empty()));
+
+                Err(RhodError::from_string(
+                    format!("Cant parse request body to bytes. {}", e),
+                    RhodErrorLevel::Error,
+                ))
             }
-            None => None,
         }
     }
 
+    // Trailers read by body() or set by handlers. Trailers of a body that wasnt read are not available
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
+
+    // Trailers to send after the body, they replace the ones with the same name sent by the body
+    pub fn trailers_mut(&mut self) -> &mut HeaderMap {
+        self.trailers.get_or_insert_with(HeaderMap::new)
+    }
+
+    // Content-Type of the request, None if it is missing or cant be parsed
+    pub fn media_type(&self) -> Option<MediaType> {
+        MediaType::parse(self.header_str(CONTENT_TYPE)?)
+    }
+
+    // Other if the Content-Type cant be parsed, None if there is no Content-Type
+    pub fn body_processor(&self) -> Option<BodyProcessor> {
+        let value = self.header_str(CONTENT_TYPE)?;
+        match MediaType::parse(value) {
+            Some(media_type) => Some(media_type.processor()),
+            None => Some(BodyProcessor::Other),
+        }
+    }
+
+    // Body decoded with the charset of the Content-Type: UTF-8 (the default), US-ASCII or ISO-8859-1
+    pub async fn body_text(&mut self) -> RhodResult<String> {
+        let charset = self.media_type().and_then(|m| m.charset());
+        let body = self.body_bytes().await?;
+        match charset.as_deref() {
+            None | Some("utf-8") | Some("utf8") | Some("us-ascii") => {
+                let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&body[..]);
+                String::from_utf8(body.to_vec()).map_err(|e| {
+                    RhodError::from_string(
+                        format!("Request body isnt valid UTF-8. {}", e),
+                        RhodErrorLevel::Warning,
+                    )
+                })
+            }
+            Some("iso-8859-1") | Some("latin1") | Some("latin-1") => {
+                Ok(body.iter().map(|b| *b as char).collect())
+            }
+            Some(charset) => Err(RhodError::from_string(
+                format!("Unsupported request body charset {}", charset),
+                RhodErrorLevel::Warning,
+            )),
+        }
+    }
+
+    // Value of the header as a str. None if it is missing or isnt valid UTF-8
+    pub fn header_str<K: AsHeaderName>(&self, name: K) -> Option<&str> {
+        std::str::from_utf8(self.headers().get(name)?.as_bytes()).ok()
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header_str(CONTENT_LENGTH)?.trim().parse().ok()
+    }
+
+    pub fn content_type(&self) -> Option<Mime> {
+        self.header_str(CONTENT_TYPE)?.parse().ok()
+    }
+
+    // Whether the Accept header allows the media type (with a q-value above 0). A missing Accept allows everything
+    pub fn accepts(&self, mime: &Mime) -> bool {
+        match self.header_str(ACCEPT) {
+            Some(accept) => Accept::parse(accept).quality(mime) > 0.0,
+            None => true,
+        }
+    }
+
+    // Host the request was sent to, without the port: authority of the uri (HTTP/2) or Host header
+    pub fn host(&self) -> Option<&str> {
+        let host = match self.uri().host() {
+            Some(host) => host,
+            None => self.header_str(HOST)?.trim(),
+        };
+        let name = match host.rfind(':') {
+            // ipv6 literals contain colons, the port goes after the bracket
+            Some(i) if !host[i..].contains(']') => &host[..i],
+            _ => host,
+        };
+        Some(name).filter(|name| !name.is_empty())
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.header_str(USER_AGENT)
+    }
+
+    // Token of an Authorization: Bearer header (scheme is case insensitive)
+    pub fn bearer_token(&self) -> Option<&str> {
+        let value = self.header_str(AUTHORIZATION)?.trim();
+        let (scheme, token) = value.split_at(value.find(' ')?);
+        Some(token.trim()).filter(|t| scheme.eq_ignore_ascii_case("Bearer") && !t.is_empty())
+    }
+
+    // Value of a cookie, from any of the Cookie headers
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.trim_matches('"'))
+    }
+
     pub fn method_str(&self) -> &str {
         self.method().as_str()
     }
@@ -116,8 +396,111 @@ impl RhodRequest {
         format!("{} {} {}", method, path, &version)
     }
 
-    pub fn into_hyper_request(self) -> HyperRequest<HyperBody> {
-        self.req.unwrap()
+    // Takes the body, leaving an empty one
+    pub fn take_body(&mut self) -> RhodBody {
+        std::mem::take(self.req.as_mut().unwrap().body_mut())
+    }
+
+    // Replaces the body. Content-Length is not updated
+    pub fn set_body(&mut self, body: RhodBody) {
+        *self.req.as_mut().unwrap().body_mut() = body;
+    }
+
+    // Trailers set by handlers are added to the end of the body, and the context is kept in the extensions
+    pub fn into_hyper_request(self) -> HyperRequest<RhodBody> {
+        let mut req = self.req.unwrap();
+        req.extensions_mut().insert(self.context);
+        if let Some(trailers) = self.trailers.filter(|t| !t.is_empty()) {
+            let body = std::mem::take(req.body_mut());
+            *req.body_mut() = with_trailers(body, trailers);
+        }
+        req
+    }
+}
+
+// Builds a RhodRequest setting method, uri, headers and body
+pub struct RhodRequestBuilder {
+    inner: HyperRequestBuilder,
+    body: RhodBody,
+    err: Option<RhodError>, // first error found while building (ie: body serialization)
+}
+
+impl RhodRequestBuilder {
+    pub fn method(self, method: Method) -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            inner: self.inner.method(method),
+            ..self
+        }
+    }
+
+    pub fn uri(self, uri: &str) -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            inner: self.inner.uri(uri),
+            ..self
+        }
+    }
+
+    pub fn version(self, version: Version) -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            inner: self.inner.version(version),
+            ..self
+        }
+    }
+
+    pub fn header(self, name: &str, value: &str) -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            inner: self.inner.header(name, value),
+            ..self
+        }
+    }
+
+    pub fn body_str(self, body: &str) -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            body: RhodBody::from(body.to_string()),
+            ..self
+        }
+    }
+
+    pub fn body_bytes(self, body: &[u8]) -> RhodRequestBuilder {
+        RhodRequestBuilder {
+            body: RhodBody::from(body.to_vec()),
+            ..self
+        }
+    }
+
+    // Serializes the value as the body. Content-Type is set to application/json if it was not set before
+    pub fn body_json<T: Serialize>(mut self, value: &T) -> RhodRequestBuilder {
+        match serde_json::to_vec(value) {
+            Ok(b) => {
+                if let Some(headers) = self.inner.headers_mut() {
+                    headers
+                        .entry(CONTENT_TYPE)
+                        .or_insert_with(|| HeaderValue::from_static("application/json"));
+                }
+                self.body = RhodBody::from(b);
+            }
+            Err(e) => {
+                self.err.get_or_insert(RhodError::from_string(
+                    format!("Cant serialize request body to json. {}", e),
+                    RhodErrorLevel::Error,
+                ));
+            }
+        }
+        self
+    }
+
+    pub fn build(self) -> RhodResult<RhodRequest> {
+        if let Some(e) = self.err {
+            return Err(e);
+        }
+
+        match self.inner.body(self.body) {
+            Ok(req) => Ok(RhodRequest::new(req)),
+            Err(e) => Err(RhodError::from_string(
+                format!("Cant build request. {}", e),
+                RhodErrorLevel::Error,
+            )),
+        }
     }
 }
 
@@ -132,7 +515,7 @@ mod tests {
         let mut request = RhodRequest::new(
             HyperRequest::builder()
                 .uri(uri)
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
@@ -148,7 +531,7 @@ mod tests {
         let request = RhodRequest::new(
             HyperRequest::builder()
                 .uri("https://www.rust-lang.org/")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
         assert_eq!(request.method(), Method::GET);
@@ -156,7 +539,7 @@ mod tests {
 
         let request = RhodRequest::new(
             HyperRequest::post("https://www.rust-lang.org/")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
         assert_eq!(request.method(), Method::POST);
@@ -169,7 +552,7 @@ mod tests {
             HyperRequest::builder()
                 .version(Version::HTTP_2)
                 .uri("https://www.rust-lang.org/")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
         assert_eq!(request.version(), Version::HTTP_2);
@@ -183,7 +566,7 @@ mod tests {
             HyperRequest::builder()
                 .uri("https://www.rust.rs/")
                 .header("User-Agent", "my-awesome-agent/1.0")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
@@ -205,7 +588,7 @@ mod tests {
             HyperRequest::builder()
                 .uri("https://www.rust.rs/")
                 .header("User-Agent", "my-awesome-agent/1.0")
-                .body(HyperBody::from("key1=value1&key2=value2"))
+                .body(RhodBody::from("key1=value1&key2=value2"))
                 .unwrap(),
         );
 
@@ -213,16 +596,21 @@ mod tests {
             request.body().await.unwrap(),
             "key1=value1&key2=value2".as_bytes().to_vec()
         );
+        // the buffer is kept, reading again doesnt copy it
+        let first = request.body_bytes().await.unwrap();
+        let again = request.body_bytes().await.unwrap();
+        assert_eq!(first, "key1=value1&key2=value2");
+        assert_eq!(first.as_ptr(), again.as_ptr());
 
         let mut request = RhodRequest::new(
             HyperRequest::builder()
                 .uri("https://www.rust.rs/")
                 .header("User-Agent", "my-awesome-agent/1.0")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
-        assert_eq!(request.body().await.unwrap(), [])
+        assert_eq!(request.body().await.unwrap(), Vec::<u8>::new())
     }
 
     #[test]
@@ -231,7 +619,7 @@ mod tests {
             HyperRequest::builder()
                 .uri("https://www.rust.rs/")
                 .header("User-Agent", "my-awesome-agent/1.0")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
@@ -258,6 +646,11 @@ mod tests {
             .insert("content-type", "multipart/form-data".parse().unwrap());
         assert_eq!(request.body_processor().unwrap(), BodyProcessor::MULTIPART);
 
+        request
+            .headers_mut()
+            .insert("content-type", "application/grpc+proto".parse().unwrap());
+        assert_eq!(request.body_processor().unwrap(), BodyProcessor::GRPC);
+
         request
             .headers_mut()
             .insert("content-type", "idk".parse().unwrap());
@@ -270,7 +663,7 @@ mod tests {
             HyperRequest::builder()
                 .version(Version::HTTP_2)
                 .uri("https://www.rust-lang.org/folder/file.txt?query=val#2")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
@@ -281,4 +674,121 @@ mod tests {
             "GET /folder/file.txt HTTP/2.0".to_string()
         );
     }
+
+    #[test]
+    fn test_extensions() {
+        let mut h_req = HyperRequest::new(RhodBody::empty());
+        h_req.extensions_mut().insert(7u32);
+        let mut request = RhodRequest::new(h_req);
+        assert_eq!(request.extensions().get::<u32>(), Some(&7));
+
+        // the context survives the conversion to an http request
+        request.context().insert("tenant");
+        request.extensions_mut().insert(8u64);
+        let request = RhodRequest::new(request.into_hyper_request());
+        assert_eq!(request.context().get::<&str>(), Some("tenant"));
+        assert_eq!(request.extensions().get::<u64>(), Some(&8));
+        assert!(request.extensions().get::<RhodContext>().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let mut request = RhodRequest::builder()
+            .method(Method::POST)
+            .uri("https://www.rust-lang.org/form")
+            .header("User-Agent", "my-awesome-agent/1.0")
+            .body_str("key1=value1")
+            .build()
+            .unwrap();
+
+        assert!(request.is_post());
+        assert_eq!(request.uri().path(), "/form");
+        assert_eq!(
+            request.headers().get("User-Agent").unwrap(),
+            "my-awesome-agent/1.0"
+        );
+        assert_eq!(request.body().await.unwrap(), b"key1=value1".to_vec());
+
+        let mut request = RhodRequest::builder()
+            .uri("/api")
+            .body_json(&vec!["a", "b"])
+            .build()
+            .unwrap();
+        assert_eq!(request.body_processor().unwrap(), BodyProcessor::JSON);
+        assert_eq!(request.body().await.unwrap(), br#"["a","b"]"#.to_vec());
+
+        let mut request = RhodRequest::builder()
+            .header("Content-Type", "text/plain")
+            .body_bytes(&[0, 1, 2])
+            .build()
+            .unwrap();
+        assert_eq!(request.body_processor().unwrap(), BodyProcessor::TEXT);
+        assert_eq!(request.body().await.unwrap(), vec![0, 1, 2]);
+
+        assert!(RhodRequest::builder().uri("not a uri").build().is_err());
+    }
+
+    #[test]
+    fn test_header_helpers() {
+        let request = RhodRequest::builder()
+            .uri("/")
+            .header("Host", "example.com:8080")
+            .header("Content-Length", "12")
+            .header("Content-Type", "application/json; charset=utf-8")
+            .header("Accept", "text/*;q=0.5, text/csv;q=0, application/json")
+            .header("Authorization", "bearer t0k3n")
+            .header("User-Agent", "agent/1.0")
+            .build()
+            .unwrap();
+        assert_eq!(request.host(), Some("example.com"));
+        assert_eq!(request.content_length(), Some(12));
+        assert_eq!(
+            request.content_type().unwrap().essence_str(),
+            "application/json"
+        );
+        assert!(request.accepts(&mime::APPLICATION_JSON));
+        assert!(request.accepts(&mime::TEXT_HTML));
+        assert!(!request.accepts(&mime::TEXT_CSV));
+        assert!(!request.accepts(&mime::IMAGE_PNG));
+        assert_eq!(request.bearer_token(), Some("t0k3n"));
+        assert_eq!(request.user_agent(), Some("agent/1.0"));
+
+        let mut request = RhodRequest::builder()
+            .uri("https://[::1]:443/")
+            .build()
+            .unwrap();
+        assert_eq!(request.host(), Some("[::1]"));
+        assert!(request.accepts(&mime::IMAGE_PNG));
+        assert!(request.bearer_token().is_none());
+        request
+            .headers_mut()
+            .insert("X-Raw", HeaderValue::from_bytes(&[0xff, 0xfe]).unwrap());
+        assert!(request.header_str("X-Raw").is_none());
+        assert!(request.header_str("X-Missing").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_media_type() {
+        let mut request = RhodRequest::builder()
+            .header("Content-Type", "text/plain; charset=ISO-8859-1")
+            .body_bytes(&[0x63, 0x61, 0x66, 0xe9])
+            .build()
+            .unwrap();
+        assert_eq!(request.body_processor().unwrap(), BodyProcessor::TEXT);
+        assert_eq!(request.body_text().await.unwrap(), "caf\u{e9}");
+
+        let media_type = MediaType::parse("multipart/form-data; boundary=XyZ").unwrap();
+        assert_eq!(media_type.processor(), BodyProcessor::MULTIPART);
+        assert_eq!(media_type.boundary(), Some("XyZ"));
+        let media_type = MediaType::parse("application/problem+json").unwrap();
+        assert_eq!(media_type.processor(), BodyProcessor::JSON);
+        assert!(media_type.charset().is_none());
+
+        let mut request = RhodRequest::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body_bytes(&[0xff])
+            .build()
+            .unwrap();
+        assert!(request.body_text().await.is_err());
+    }
 }
@@ -1,16 +1,50 @@
+use crate::body::{prepend, read_limited, read_with_trailers, with_trailers, BodyWriter, RhodBody};
+use crate::context::RhodContext;
 use crate::errors::*;
-use hyper::body::Body as HyperBody;
-use hyper::http::Response as HyperResponse;
-use hyper::{header::HeaderValue, HeaderMap};
+use bytes::Bytes;
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use http::response::Builder as HyperResponseBuilder;
+use http::Response as HyperResponse;
+use http::{Extensions, HeaderMap, StatusCode};
+use http_body::Body as _;
+use serde::Serialize;
 
 // Extends HyperResponse
+#[derive(Debug)]
 pub struct RhodResponse {
-    res: Option<HyperResponse<HyperBody>>, // Is allways Some(..)
+    res: Option<HyperResponse<RhodBody>>, // Is allways Some(..)
+    context: RhodContext,
+    trailers: Option<HeaderMap>, // read from the body or set by handlers, sent after the body
 }
 
 impl RhodResponse {
-    pub fn new(res: HyperResponse<HyperBody>) -> RhodResponse {
-        RhodResponse { res: Some(res) }
+    // The context inserted by into_hyper_response is taken back from the extensions
+    pub fn new(mut res: HyperResponse<RhodBody>) -> RhodResponse {
+        let context = res.extensions_mut().remove::<RhodContext>();
+        RhodResponse {
+            res: Some(res),
+            context: context.unwrap_or_default(),
+            trailers: None,
+        }
+    }
+
+    // Typed values shared between handlers. Once the response leaves the service, it is the request context
+    pub fn context(&self) -> &RhodContext {
+        &self.context
+    }
+
+    // Makes ctx the context of the response, keeping the values already inserted in the response
+    pub(crate) fn attach_context(&mut self, ctx: &RhodContext) {
+        ctx.absorb(&mut self.context);
+    }
+
+    // Test-friendly constructor, avoids the http builder + body boilerplate
+    pub fn builder() -> RhodResponseBuilder {
+        RhodResponseBuilder {
+            inner: HyperResponse::builder(),
+            body: RhodBody::empty(),
+            err: None,
+        }
     }
 
     pub fn headers(&self) -> &HeaderMap<HeaderValue> {
@@ -21,27 +55,147 @@ impl RhodResponse {
         self.res.as_mut().unwrap().headers_mut()
     }
 
-    pub fn into_hyper_response(self) -> HyperResponse<HyperBody> {
-        self.res.unwrap()
+    // Extensions of the http response, set by hyper or by integrations (ie: tower layers)
+    pub fn extensions(&self) -> &Extensions {
+        self.res.as_ref().unwrap().extensions()
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        self.res.as_mut().unwrap().extensions_mut()
+    }
+
+    // Takes the body, leaving an empty one
+    pub fn take_body(&mut self) -> RhodBody {
+        std::mem::take(self.res.as_mut().unwrap().body_mut())
+    }
+
+    // Replaces the body. Content-Length is not updated
+    pub fn set_body(&mut self, body: RhodBody) {
+        *self.res.as_mut().unwrap().body_mut() = body;
+    }
+
+    // Replaces the body with one written through the returned writer (see BodyWriter), ie: from a
+    // task spawned by the service before returning the response
+    pub fn writer(&mut self) -> BodyWriter {
+        let (writer, body) = BodyWriter::new();
+        self.headers_mut().remove(CONTENT_LENGTH);
+        self.set_body(body);
+        writer
+    }
+
+    // Trailers set by handlers are added to the end of the body, and the context is kept in the extensions
+    pub fn into_hyper_response(self) -> HyperResponse<RhodBody> {
+        let mut res = self.res.unwrap();
+        res.extensions_mut().insert(self.context);
+        if let Some(trailers) = self.trailers.filter(|t| !t.is_empty()) {
+            let body = std::mem::take(res.body_mut());
+            *res.body_mut() = with_trailers(body, trailers);
+        }
+        res
+    }
+
+    // Size of the body, if it is known without reading it
+    pub fn body_size_hint(&self) -> Option<u64> {
+        self.res.as_ref().unwrap().body().size_hint().exact()
+    }
+
+    // Adds the request headers the response depends on (ie: negotiated ones) to Vary, once each
+    pub fn vary(&mut self, names: &[HeaderName]) {
+        let mut vary: Vec<String> = self
+            .headers()
+            .get_all(VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if vary.iter().any(|name| name == "*") {
+            return;
+        }
+        for name in names {
+            if !vary.iter().any(|v| v.eq_ignore_ascii_case(name.as_str())) {
+                vary.push(name.as_str().to_string());
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&vary.join(", ")) {
+            self.headers_mut().insert(VARY, value);
+        }
     }
 
     pub fn status_as_int(&self) -> u16 {
         self.res.as_ref().unwrap().status().as_u16()
     }
 
+    // Copy of the whole body, see body_bytes
     pub async fn body(&mut self) -> RhodResult<Vec<u8>> {
+        Ok(self.body_bytes().await?.to_vec())
+    }
+
+    // Reads the whole body. The response keeps it to be sent, sharing the buffer with the returned Bytes,
+    // so calling it again doesnt read nor copy it
+    pub async fn body_bytes(&mut self) -> RhodResult<Bytes> {
         let r = self.res.take().unwrap();
 
         let (header, body) = r.into_parts();
-        match hyper::body::to_bytes(body).await {
-            Ok(b) => {
-                let cloned = b.clone();
-                self.res = Some(HyperResponse::from_parts(header, HyperBody::from(b)));
-                Ok(cloned.to_vec())
+        match read_with_trailers(body).await {
+            Ok((b, trailers)) => {
+                if let Some(mut trailers) = trailers {
+                    // trailers set by handlers replace the ones of the body
+                    if let Some(set) = self.trailers.take() {
+                        trailers.extend(set);
+                    }
+                    self.trailers = Some(trailers);
+                }
+                self.res = Some(HyperResponse::from_parts(header, RhodBody::from(b.clone())));
+                Ok(b)
+            }
+            Err(e) => {
+                // If error, body cant be recovered.
+                self.res = Some(HyperResponse::from_parts(header, RhodBody::empty()));
+
+                Err(RhodError::from_string(
+                    format!("Cant parse response body to bytes. {}", e),
+                    RhodErrorLevel::Error,
+                ))
+            }
+        }
+    }
+
+    // Reads the whole body like body_bytes if it isnt longer than limit bytes, None otherwise. Bodies
+    // known to be longer (ie: by their Content-Length) arent read, and the response keeps the whole body
+    pub async fn body_bytes_limited(&mut self, limit: usize) -> RhodResult<Option<Bytes>> {
+        if self.res.as_ref().unwrap().body().size_hint().lower() > limit as u64 {
+            return Ok(None);
+        }
+        Ok(self.read_limited(limit).await?.ok())
+    }
+
+    // The whole body if it isnt longer than limit, or the bytes read of it
+    async fn read_limited(&mut self, limit: usize) -> RhodResult<Result<Bytes, Bytes>> {
+        let (header, body) = self.res.take().unwrap().into_parts();
+        match read_limited(body, limit).await {
+            Ok((b, trailers, None)) => {
+                if let Some(mut trailers) = trailers {
+                    // trailers set by handlers replace the ones of the body
+                    if let Some(set) = self.trailers.take() {
+                        trailers.extend(set);
+                    }
+                    self.trailers = Some(trailers);
+                }
+                self.res = Some(HyperResponse::from_parts(header, RhodBody::from(b.clone())));
+                Ok(Ok(b))
+            }
+            Ok((read, _, Some(rest))) => {
+                self.res = Some(HyperResponse::from_parts(
+                    header,
+                    prepend(read.clone(), rest),
+                ));
+                Ok(Err(read))
             }
             Err(e) => {
                 // If error, body cant be recovered.
-                self.res = Some(HyperResponse::from_parts(header, HyperBody::empty()));
+                self.res = Some(HyperResponse::from_parts(header, RhodBody::empty()));
 
                 Err(RhodError::from_string(
                     format!("Cant parse response body to bytes. {}", e),
@@ -50,6 +204,88 @@ impl RhodResponse {
             }
         }
     }
+
+    // Trailers read by body() or set by handlers. Trailers of a body that wasnt read are not available
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
+
+    // Trailers to send after the body, they replace the ones with the same name sent by the body
+    pub fn trailers_mut(&mut self) -> &mut HeaderMap {
+        self.trailers.get_or_insert_with(HeaderMap::new)
+    }
+}
+
+// Builds a RhodResponse setting status, headers and body
+pub struct RhodResponseBuilder {
+    inner: HyperResponseBuilder,
+    body: RhodBody,
+    err: Option<RhodError>, // first error found while building (ie: body serialization)
+}
+
+impl RhodResponseBuilder {
+    pub fn status(self, status: StatusCode) -> RhodResponseBuilder {
+        RhodResponseBuilder {
+            inner: self.inner.status(status),
+            ..self
+        }
+    }
+
+    pub fn header(self, name: &str, value: &str) -> RhodResponseBuilder {
+        RhodResponseBuilder {
+            inner: self.inner.header(name, value),
+            ..self
+        }
+    }
+
+    pub fn body_str(self, body: &str) -> RhodResponseBuilder {
+        RhodResponseBuilder {
+            body: RhodBody::from(body.to_string()),
+            ..self
+        }
+    }
+
+    pub fn body_bytes(self, body: &[u8]) -> RhodResponseBuilder {
+        RhodResponseBuilder {
+            body: RhodBody::from(body.to_vec()),
+            ..self
+        }
+    }
+
+    // Serializes the value as the body. Content-Type is set to application/json if it was not set before
+    pub fn body_json<T: Serialize>(mut self, value: &T) -> RhodResponseBuilder {
+        match serde_json::to_vec(value) {
+            Ok(b) => {
+                if let Some(headers) = self.inner.headers_mut() {
+                    headers
+                        .entry(CONTENT_TYPE)
+                        .or_insert_with(|| HeaderValue::from_static("application/json"));
+                }
+                self.body = RhodBody::from(b);
+            }
+            Err(e) => {
+                self.err.get_or_insert(RhodError::from_string(
+                    format!("Cant serialize response body to json. {}", e),
+                    RhodErrorLevel::Error,
+                ));
+            }
+        }
+        self
+    }
+
+    pub fn build(self) -> RhodResult<RhodResponse> {
+        if let Some(e) = self.err {
+            return Err(e);
+        }
+
+        match self.inner.body(self.body) {
+            Ok(res) => Ok(RhodResponse::new(res)),
+            Err(e) => Err(RhodError::from_string(
+                format!("Cant build response. {}", e),
+                RhodErrorLevel::Error,
+            )),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -60,7 +296,7 @@ mod tests {
         let mut res = RhodResponse::new(
             HyperResponse::builder()
                 .header("Foo", "Bar")
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
@@ -77,7 +313,7 @@ mod tests {
         let res = RhodResponse::new(
             HyperResponse::builder()
                 .status(404)
-                .body(HyperBody::empty())
+                .body(RhodBody::empty())
                 .unwrap(),
         );
 
@@ -88,7 +324,7 @@ mod tests {
     async fn test_body() {
         let mut response = RhodResponse::new(
             HyperResponse::builder()
-                .body(HyperBody::from("response bodyy %% #"))
+                .body(RhodBody::from("response bodyy %% #"))
                 .unwrap(),
         );
 
@@ -98,8 +334,70 @@ mod tests {
         );
 
         let mut response =
-            RhodResponse::new(HyperResponse::builder().body(HyperBody::empty()).unwrap());
+            RhodResponse::new(HyperResponse::builder().body(RhodBody::empty()).unwrap());
+
+        assert_eq!(response.body().await.unwrap(), Vec::<u8>::new())
+    }
+
+    #[tokio::test]
+    async fn test_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let body = with_trailers(RhodBody::from("data"), trailers);
+        let mut res = RhodResponse::new(HyperResponse::builder().body(body).unwrap());
+        assert!(res.trailers().is_none());
+
+        assert_eq!(res.body().await.unwrap(), b"data".to_vec());
+        assert_eq!(res.trailers().unwrap().get("grpc-status").unwrap(), "0");
+
+        res.trailers_mut()
+            .insert("grpc-message", HeaderValue::from_static("ok"));
+        let (_, body) = res.into_hyper_response().into_parts();
+        let (data, trailers) = read_with_trailers(body).await.unwrap();
+        let trailers = trailers.unwrap();
+        assert_eq!(data, "data");
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert_eq!(trailers.get("grpc-message").unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let mut res = RhodResponse::builder()
+            .status(StatusCode::CREATED)
+            .header("Foo", "Bar")
+            .body_str("created")
+            .build()
+            .unwrap();
+
+        assert_eq!(res.status_as_int(), 201);
+        assert_eq!(res.headers().get("Foo").unwrap(), "Bar");
+        assert_eq!(res.body().await.unwrap(), b"created".to_vec());
+
+        let mut res = RhodResponse::builder().body_json(&1).build().unwrap();
+        assert_eq!(res.status_as_int(), 200);
+        assert_eq!(
+            res.headers().get("Content-Type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(res.body().await.unwrap(), b"1".to_vec());
+
+        assert!(RhodResponse::builder()
+            .header("Foo", "Bar\n")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_vary() {
+        let mut res = RhodResponse::builder()
+            .header("Vary", "Origin, accept")
+            .build()
+            .unwrap();
+        res.vary(&[http::header::ACCEPT, http::header::ACCEPT_LANGUAGE]);
+        assert_eq!(res.headers()[VARY], "Origin, accept, accept-language");
 
-        assert_eq!(response.body().await.unwrap(), [])
+        let mut res = RhodResponse::builder().header("Vary", "*").build().unwrap();
+        res.vary(&[http::header::ACCEPT]);
+        assert_eq!(res.headers()[VARY], "*");
     }
 }
@@ -0,0 +1,151 @@
+// Runtime specific pieces used by the server and the client: spawning tasks, timers, and the
+// executor and timer given to hyper. Everything else only deals with futures.
+// Rhodium runs on tokio with the multi-thread or the current-thread scheduler: no task needs to
+// run in parallel with another one, so a single threaded runtime serves the same stack.
+// Another runtime can be plugged implementing Runtime and setting Rt, as long as it can drive
+// tokio IO types (tokio-rustls and hyper-util connections are built on them).
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::future::{select, Either};
+use futures_util::stream::Stream;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+pub(crate) trait Runtime {
+    type Task: Task;
+    type Executor: Clone + Send + Sync + 'static; // spawns the tasks of hyper connections
+    type Timer: hyper::rt::Timer + Send + Sync + 'static; // hyper timeouts and keepalives
+
+    fn spawn<F>(future: F) -> Self::Task
+    where
+        F: Future<Output = ()> + Send + 'static;
+
+    fn sleep(duration: Duration) -> Sleep;
+
+    fn executor() -> Self::Executor;
+
+    fn timer() -> Self::Timer;
+}
+
+// Handle of a spawned task. Dropping it detaches the task
+pub(crate) trait Task {
+    fn abort(&self);
+}
+
+pub(crate) struct Tokio;
+
+impl Runtime for Tokio {
+    type Task = tokio::task::JoinHandle<()>;
+    type Executor = TokioExecutor;
+    type Timer = TokioTimer;
+
+    fn spawn<F>(future: F) -> Self::Task
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(future)
+    }
+
+    fn sleep(duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn executor() -> TokioExecutor {
+        TokioExecutor::new()
+    }
+
+    fn timer() -> TokioTimer {
+        TokioTimer::new()
+    }
+}
+
+impl Task for tokio::task::JoinHandle<()> {
+    fn abort(&self) {
+        tokio::task::JoinHandle::abort(self)
+    }
+}
+
+// Runtime used by Rhodium
+pub(crate) type Rt = Tokio;
+pub(crate) type Executor = <Rt as Runtime>::Executor;
+pub(crate) type TaskHandle = <Rt as Runtime>::Task;
+
+pub(crate) fn spawn<F>(future: F) -> TaskHandle
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    Rt::spawn(future)
+}
+
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    Rt::sleep(duration)
+}
+
+pub(crate) fn executor() -> Executor {
+    Rt::executor()
+}
+
+pub(crate) fn timer() -> <Rt as Runtime>::Timer {
+    Rt::timer()
+}
+
+// Stream of the SIGHUP signals received by the process
+#[cfg(unix)]
+pub(crate) fn hangups() -> std::io::Result<Pin<Box<dyn Stream<Item = ()> + Send>>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let signals = signal(SignalKind::hangup())?;
+    Ok(Box::pin(futures_util::stream::unfold(
+        signals,
+        |mut signals| async move { signals.recv().await.map(|()| ((), signals)) },
+    )))
+}
+
+// Error of a future that didnt complete in time
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+// Runs the future, failing if it doesnt complete before the duration
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let future = Box::pin(future);
+    match select(future, sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_thread() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            spawn(async move {
+                sleep(Duration::from_millis(10)).await;
+                let _ = tx.send(1);
+            });
+            assert_eq!(
+                timeout(Duration::from_secs(1), rx).await.unwrap().unwrap(),
+                1
+            );
+
+            let never = futures_util::future::pending::<()>();
+            assert!(timeout(Duration::from_millis(10), never).await.is_err());
+
+            let aborted = spawn(futures_util::future::pending());
+            aborted.abort();
+            assert!(aborted.await.unwrap_err().is_cancelled());
+        });
+    }
+}
@@ -0,0 +1,10 @@
+// Built-in services ready to be used as the end of a RhodStack
+mod grpc_proxy;
+pub use grpc_proxy::GrpcProxyService;
+mod connect_tunnel;
+pub use connect_tunnel::ConnectTunnelService;
+mod proxy;
+pub use proxy::{
+    ProxyService, WebSocketSession, WebSocketStats, WsAction, WsDirection, WsMessage,
+    WsMessageHandler, WsMessageKind,
+};
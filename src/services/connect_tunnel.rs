@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::{Method, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+use crate::stack::RhodService;
+use crate::RhodConnInfo;
+
+// Answers CONNECT requests opening a tunnel to the requested host:port.
+// Policy checks (authentication, allowed targets) are done by the handlers of the stack.
+// Once the target is connected, it answers 200 and copies bytes in both directions until one side closes.
+pub struct ConnectTunnelService {
+    connect_timeout: Duration,
+}
+
+impl ConnectTunnelService {
+    pub fn new() -> ConnectTunnelService {
+        ConnectTunnelService {
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_connect_timeout(self, connect_timeout: Duration) -> Self {
+        ConnectTunnelService { connect_timeout }
+    }
+}
+
+impl Default for ConnectTunnelService {
+    fn default() -> ConnectTunnelService {
+        ConnectTunnelService::new()
+    }
+}
+
+fn answer(status: StatusCode, msg: String) -> RhodError {
+    RhodError::with_status(status, msg, RhodErrorLevel::Warning)
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodService<C> for ConnectTunnelService {
+    async fn serve(
+        &self,
+        _conn: &RhodConnInfo,
+        req: RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        if req.method() != Method::CONNECT {
+            return Err(answer(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("Tunnel service got a {} request", req.method()),
+            ));
+        }
+        let target = match req.uri().authority() {
+            Some(authority) if authority.port().is_some() => authority.to_string(),
+            _ => {
+                return Err(answer(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid CONNECT target {}", req.uri()),
+                ))
+            }
+        };
+
+        let upstream =
+            match runtime::timeout(self.connect_timeout, TcpStream::connect(&target)).await {
+                Ok(Ok(upstream)) => upstream,
+                Ok(Err(e)) => {
+                    return Err(answer(
+                        StatusCode::BAD_GATEWAY,
+                        format!("Couldnt connect to {}. {}", target, e),
+                    ))
+                }
+                Err(_) => {
+                    return Err(answer(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        format!("Timeout when connecting to {}", target),
+                    ))
+                }
+            };
+
+        // the connection is upgraded once the 200 is sent
+        let h_req = req.into_hyper_request();
+        runtime::spawn(async move {
+            match hyper::upgrade::on(h_req).await {
+                Ok(client) => {
+                    let mut client = TokioIo::new(client);
+                    let mut upstream = upstream;
+                    if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+                    {
+                        debug!("Tunnel to {} closed. {}", target, e);
+                    }
+                }
+                Err(e) => warn!("Couldnt upgrade CONNECT to {}. {}", target, e),
+            }
+        });
+
+        RhodResponse::builder().status(StatusCode::OK).build()
+    }
+}
@@ -0,0 +1,191 @@
+use async_trait::async_trait;
+use http::header::{HeaderValue, HOST};
+use http::uri::{Parts, PathAndQuery};
+use http::{StatusCode, Uri, Version};
+
+use crate::client::RhodClient;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::handlers::TrafficVariant;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::RhodService;
+use crate::RhodConnInfo;
+
+// gRPC status sent when the upstream can't be reached
+const GRPC_UNAVAILABLE: &str = "14";
+
+// Forwards requests to an upstream HTTP/2 endpoint (h2c by default, or a client speaking h2 over TLS).
+// Bodies are streamed in both directions and trailers (grpc-status, grpc-message) are kept,
+// so handlers must not read the bodies of gRPC calls (see BodyProcessor::GRPC).
+pub struct GrpcProxyService {
+    upstream: Uri, // scheme and authority of the upstream
+    variant_upstreams: Vec<(String, Uri)>,
+    client: RhodClient,
+}
+
+impl GrpcProxyService {
+    pub fn new(upstream: Uri) -> GrpcProxyService {
+        let client = RhodClient::builder().http2_only(true).build();
+        GrpcProxyService {
+            upstream,
+            variant_upstreams: vec![],
+            client,
+        }
+    }
+
+    // Upstream of the requests assigned to a variant by a TrafficSplitHandler (ie: a canary)
+    pub fn with_variant_upstream(mut self, variant: &str, upstream: Uri) -> Self {
+        self.variant_upstreams.push((variant.to_string(), upstream));
+        self
+    }
+
+    // The client must speak HTTP/2 (http2_only)
+    pub fn with_client(self, client: RhodClient) -> Self {
+        GrpcProxyService { client, ..self }
+    }
+
+    fn upstream(&self, req: &RhodRequest) -> &Uri {
+        let variant = req.context().get::<TrafficVariant>();
+        variant
+            .and_then(|variant| {
+                self.variant_upstreams
+                    .iter()
+                    .find(|(name, _)| *name == variant.0)
+            })
+            .map_or(&self.upstream, |(_, upstream)| upstream)
+    }
+
+    fn upstream_uri(&self, upstream: &Uri, uri: &Uri) -> RhodResult<Uri> {
+        let mut parts = Parts::default();
+        parts.scheme = upstream.scheme().cloned();
+        parts.authority = upstream.authority().cloned();
+        parts.path_and_query = Some(
+            uri.path_and_query()
+                .cloned()
+                .unwrap_or_else(|| PathAndQuery::from_static("/")),
+        );
+        Uri::from_parts(parts).map_err(|e| {
+            RhodError::from_string(
+                format!("Invalid upstream uri. {}", e),
+                RhodErrorLevel::Error,
+            )
+        })
+    }
+}
+
+fn unavailable(msg: String) -> RhodError {
+    let err = RhodError::from_string(msg, RhodErrorLevel::Error);
+    // gRPC clients expect a 200 with the status in the headers (trailers-only response)
+    err.with_built(
+        RhodResponse::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/grpc")
+            .header("grpc-status", GRPC_UNAVAILABLE)
+            .header("grpc-message", "upstream unavailable")
+            .build(),
+    )
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodService<C> for GrpcProxyService {
+    async fn serve(
+        &self,
+        _conn: &RhodConnInfo,
+        req: RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        let mut req = req;
+        *req.uri_mut() = self.upstream_uri(self.upstream(&req), req.uri())?;
+        *req.version_mut() = Version::HTTP_2;
+        // h2 carries the authority in the uri
+        req.headers_mut().remove(HOST);
+        req.headers_mut()
+            .insert("te", HeaderValue::from_static("trailers"));
+
+        match self.client.send(req).await {
+            Ok(res) => Ok(res),
+            Err(e) => Err(unavailable(format!("gRPC upstream error. {}", e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::{read_with_trailers, RhodBody};
+    use crate::protocols::HttpProtocol;
+    use crate::stack::RhodStack;
+    use crate::CommunicationChannel;
+    use http::{HeaderMap, Request, Response};
+    use hyper::body::Incoming;
+    use hyper::server::conn::http2;
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // h2c upstream echoing the request body, with grpc-status in the trailers
+    async fn echo(req: Request<Incoming>) -> Result<Response<RhodBody>, Infallible> {
+        let (mut sender, body) = RhodBody::channel();
+        tokio::spawn(async move {
+            let data = RhodBody::new(req.into_body()).to_bytes().await.unwrap();
+            sender.send_data(data).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        Ok(Response::builder()
+            .header("content-type", "application/grpc")
+            .body(body)
+            .unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_grpc_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service_fn(echo))
+                .await
+                .unwrap();
+        });
+
+        let stack: RhodStack<Comm> =
+            RhodStack::new(vec![], Box::new(GrpcProxyService::new(upstream)));
+        let conn = RhodConnInfo::new(SocketAddr::from(([127, 0, 0, 1], 1)), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .method(http::Method::POST)
+            .uri("http://localhost/helloworld.Greeter/SayHello")
+            .header("content-type", "application/grpc")
+            .body_bytes(b"\0\0\0\0\x02hi")
+            .build()
+            .unwrap();
+
+        let mut res = stack.execute(&conn, req).await.unwrap();
+        let (data, trailers) = read_with_trailers(res.take_body()).await.unwrap();
+        assert_eq!(data, b"\0\0\0\0\x02hi".to_vec());
+        let trailers = trailers.unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+
+        // the upstream is down
+        let stack: RhodStack<Comm> = RhodStack::new(
+            vec![],
+            Box::new(GrpcProxyService::new("http://127.0.0.1:1".parse().unwrap())),
+        );
+        let req = RhodRequest::builder().uri("/a.B/C").build().unwrap();
+        let res = stack.execute(&conn, req).await.unwrap();
+        assert_eq!(res.headers().get("grpc-status").unwrap(), GRPC_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,677 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures_util::future;
+use http::header::{HeaderName, HeaderValue, CONNECTION, HOST, UPGRADE};
+use http::uri::{Parts, PathAndQuery};
+use http::{HeaderMap, Method, Response as HyperResponse, StatusCode, Uri, Version};
+use hyper_util::rt::TokioIo;
+
+use crate::body::RhodBody;
+use crate::client::RhodClient;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+use crate::stack::RhodService;
+use crate::RhodConnInfo;
+
+mod sse;
+use sse::Fanout;
+mod websocket;
+use websocket::{Flow, Relay};
+pub use websocket::{WsAction, WsDirection, WsMessage, WsMessageHandler, WsMessageKind};
+
+// Headers of a single connection, not forwarded
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// WebSocket connections relayed by a ProxyService
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebSocketStats {
+    pub opened: u64,
+    pub open: u64,
+    pub bytes_from_client: u64, // frames read, on the wire
+    pub bytes_from_upstream: u64,
+}
+
+#[derive(Default)]
+struct WebSocketCounters {
+    opened: AtomicU64,
+    open: AtomicU64,
+    bytes_from_client: AtomicU64,
+    bytes_from_upstream: AtomicU64,
+}
+
+// Traffic of a WebSocket connection, given to the observer of the ProxyService when it ends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketSession {
+    pub client: SocketAddr,
+    pub path: String,
+    pub bytes_from_client: u64,
+    pub bytes_from_upstream: u64,
+    pub frames_from_client: u64,
+    pub frames_from_upstream: u64,
+    pub close_code: Option<u16>, // of the first close frame, None if a side left without closing
+    pub duration: Duration,
+}
+
+type WebSocketObserver = Arc<dyn Fn(&WebSocketSession) + Send + Sync>;
+
+// Reverse proxy forwarding requests to an upstream (http or https, with an optional base path),
+// streaming the bodies in both directions. Hop-by-hop headers are removed, and X-Forwarded-For,
+// X-Forwarded-Proto and X-Forwarded-Host are set.
+// WebSocket upgrades (HTTP/1.1 GET with Upgrade: websocket) get their own upstream connection: the
+// handshake of the upstream is answered to the client and, once both connections are upgraded, the
+// frames are relayed in both directions (pings and pongs included) until one side closes, the close
+// being relayed to the other. The client must speak HTTP/1.1 with https upstreams speaking h2.
+// WebSocket message handlers see the text and binary messages; extensions (ie: permessage-deflate)
+// arent negotiated with the upstream when there are handlers, so the messages can be read.
+// Server-sent events are streamed event by event, and Last-Event-ID is forwarded to the upstream. With
+// fan-out, the clients of the same event stream share one upstream request.
+pub struct ProxyService {
+    upstream: Uri,
+    client: RhodClient,
+    preserve_host: bool,
+    max_frame_size: usize,
+    ws_counters: Arc<WebSocketCounters>,
+    ws_observer: Option<WebSocketObserver>,
+    ws_handlers: Arc<Vec<Arc<dyn WsMessageHandler>>>,
+    sse_fanout: Option<Fanout>,
+}
+
+impl ProxyService {
+    pub fn new(upstream: Uri) -> ProxyService {
+        ProxyService {
+            upstream,
+            client: RhodClient::new(),
+            preserve_host: false,
+            max_frame_size: 16 << 20,
+            ws_counters: Arc::new(WebSocketCounters::default()),
+            ws_observer: None,
+            ws_handlers: Arc::new(vec![]),
+            sse_fanout: None,
+        }
+    }
+
+    pub fn with_client(self, client: RhodClient) -> Self {
+        ProxyService { client, ..self }
+    }
+
+    // Sends the Host of the client instead of the upstream authority
+    pub fn with_preserve_host(self) -> Self {
+        ProxyService {
+            preserve_host: true,
+            ..self
+        }
+    }
+
+    // Larger WebSocket frames (or messages, with message handlers) close the connection (1009),
+    // 16MiB by default
+    pub fn with_max_frame_size(self, max_frame_size: usize) -> Self {
+        ProxyService {
+            max_frame_size,
+            ..self
+        }
+    }
+
+    // Called with the traffic of every WebSocket connection when it ends
+    pub fn with_websocket_observer<F>(self, observer: F) -> Self
+    where
+        F: Fn(&WebSocketSession) + Send + Sync + 'static,
+    {
+        ProxyService {
+            ws_observer: Some(Arc::new(observer)),
+            ..self
+        }
+    }
+
+    // Adds a handler of the WebSocket messages, called after the ones already added
+    pub fn with_websocket_handler<H: WsMessageHandler + 'static>(self, handler: H) -> Self {
+        let mut ws_handlers = self.ws_handlers.to_vec();
+        ws_handlers.push(Arc::new(handler));
+        ProxyService {
+            ws_handlers: Arc::new(ws_handlers),
+            ..self
+        }
+    }
+
+    // Shares the upstream event stream (text/event-stream) of a uri among its clients, sent with the
+    // headers of the first client. The last replay events are kept for the clients reconnecting with
+    // Last-Event-ID, and the upstream is requested again (with the id of its last event) when its stream
+    // ends while there are clients
+    pub fn with_sse_fanout(self, replay: usize) -> Self {
+        ProxyService {
+            sse_fanout: Some(Fanout::new(replay)),
+            ..self
+        }
+    }
+
+    pub fn websocket_stats(&self) -> WebSocketStats {
+        let counters = &self.ws_counters;
+        WebSocketStats {
+            opened: counters.opened.load(Ordering::Relaxed),
+            open: counters.open.load(Ordering::Relaxed),
+            bytes_from_client: counters.bytes_from_client.load(Ordering::Relaxed),
+            bytes_from_upstream: counters.bytes_from_upstream.load(Ordering::Relaxed),
+        }
+    }
+
+    fn upstream_uri(&self, uri: &Uri) -> RhodResult<Uri> {
+        let base = self.upstream.path().trim_end_matches('/');
+        let path_and_query = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+        let mut parts = Parts::default();
+        parts.scheme = self.upstream.scheme().cloned();
+        parts.authority = self.upstream.authority().cloned();
+        parts.path_and_query = Some(
+            format!("{}{}", base, path_and_query)
+                .parse()
+                .map_err(|e| invalid_uri(&e))?,
+        );
+        Uri::from_parts(parts).map_err(|e| invalid_uri(&e))
+    }
+
+    fn prepare(&self, conn: &RhodConnInfo, req: &mut RhodRequest) -> RhodResult<()> {
+        *req.uri_mut() = self.upstream_uri(req.uri())?;
+        *req.version_mut() = Version::HTTP_11;
+        let host = req.header_str(HOST).map(str::to_string);
+        let headers = req.headers_mut();
+        remove_hop_by_hop(headers);
+        let forwarded_for = match headers.get("x-forwarded-for").map(|v| v.to_str()) {
+            Some(Ok(previous)) => format!("{}, {}", previous, conn.addr.ip()),
+            _ => conn.addr.ip().to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert("x-forwarded-for", value);
+        }
+        if let Ok(value) = HeaderValue::from_str(conn.proto.to_string()) {
+            headers.insert("x-forwarded-proto", value);
+        }
+        if let Some(Ok(value)) = host.as_deref().map(HeaderValue::from_str) {
+            headers.insert("x-forwarded-host", value);
+        }
+        if !self.preserve_host {
+            // set from the uri by the client
+            headers.remove(HOST);
+        }
+        Ok(())
+    }
+
+    // Relays the frames once the client and the upstream connections are upgraded
+    fn relay(
+        &self,
+        conn: &RhodConnInfo,
+        path: String,
+        client: hyper::upgrade::OnUpgrade,
+        upstream: hyper::upgrade::OnUpgrade,
+    ) {
+        let counters = Arc::clone(&self.ws_counters);
+        let observer = self.ws_observer.clone();
+        let handlers = Arc::clone(&self.ws_handlers);
+        let max_frame_size = self.max_frame_size;
+        let client_addr = conn.addr;
+        runtime::spawn(async move {
+            let (client, upstream) = match future::try_join(client, upstream).await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    warn!("Couldnt upgrade the WebSocket of {}. {}", path, e);
+                    return;
+                }
+            };
+            counters.opened.fetch_add(1, Ordering::Relaxed);
+            counters.open.fetch_add(1, Ordering::Relaxed);
+            let started = Instant::now();
+            let (from_client, from_upstream) = (Flow::default(), Flow::default());
+            let relay = Relay {
+                max_size: max_frame_size,
+                path: &path,
+                handlers: &handlers,
+            };
+            websocket::relay(
+                TokioIo::new(client),
+                TokioIo::new(upstream),
+                &relay,
+                &from_client,
+                &from_upstream,
+            )
+            .await;
+            counters.open.fetch_sub(1, Ordering::Relaxed);
+
+            let session = WebSocketSession {
+                client: client_addr,
+                path,
+                bytes_from_client: from_client.bytes.load(Ordering::Relaxed),
+                bytes_from_upstream: from_upstream.bytes.load(Ordering::Relaxed),
+                frames_from_client: from_client.frames.load(Ordering::Relaxed),
+                frames_from_upstream: from_upstream.frames.load(Ordering::Relaxed),
+                close_code: from_client.close_code().or(from_upstream.close_code()),
+                duration: started.elapsed(),
+            };
+            counters
+                .bytes_from_client
+                .fetch_add(session.bytes_from_client, Ordering::Relaxed);
+            counters
+                .bytes_from_upstream
+                .fetch_add(session.bytes_from_upstream, Ordering::Relaxed);
+            debug!(
+                "WebSocket {} of {} closed. {} bytes from the client, {} from the upstream",
+                session.path,
+                session.client,
+                session.bytes_from_client,
+                session.bytes_from_upstream
+            );
+            if let Some(observer) = observer {
+                observer(&session);
+            }
+        });
+    }
+}
+
+fn invalid_uri(e: &dyn std::fmt::Display) -> RhodError {
+    RhodError::from_string(
+        format!("Invalid upstream uri. {}", e),
+        RhodErrorLevel::Error,
+    )
+}
+
+// The header has the token in its comma separated values (case insensitive)
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+fn is_websocket(req: &RhodRequest) -> bool {
+    req.method() == Method::GET
+        && req.version() == Version::HTTP_11
+        && has_token(req.headers(), UPGRADE, "websocket")
+        && has_token(req.headers(), CONNECTION, "upgrade")
+}
+
+// Removes the hop-by-hop headers, and the ones named in Connection
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP.iter() {
+        headers.remove(*name);
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodService<C> for ProxyService {
+    async fn serve(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        let path = req.uri().path().to_string();
+        let (mut req, client_upgrade) = if is_websocket(&req) {
+            let mut h_req = req.into_hyper_request();
+            let client_upgrade = hyper::upgrade::on(&mut h_req);
+            (RhodRequest::new(h_req), Some(client_upgrade))
+        } else {
+            (req, None)
+        };
+        let fanout = match &self.sse_fanout {
+            Some(fanout) if sse::accepts_events(&req) => Some(fanout),
+            _ => None,
+        };
+        self.prepare(conn, &mut req)?;
+        let shared = match fanout {
+            Some(fanout) => {
+                let last_event_id = req.header_str(sse::LAST_EVENT_ID);
+                if let Some(res) = fanout.subscribe(req.uri(), last_event_id) {
+                    return Ok(res);
+                }
+                Some((fanout, req.uri().clone(), req.headers().clone()))
+            }
+            None => None,
+        };
+        if client_upgrade.is_some() {
+            let headers = req.headers_mut();
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+            if !self.ws_handlers.is_empty() {
+                headers.remove("sec-websocket-extensions");
+            }
+        }
+
+        let mut res = self.client.send(req).await?;
+        match client_upgrade {
+            Some(client_upgrade) if res.status_as_int() == StatusCode::SWITCHING_PROTOCOLS => {
+                // the client gets the handshake of the upstream (Sec-WebSocket-Accept, protocol...)
+                let mut h_res = res.into_hyper_response();
+                let upstream_upgrade = hyper::upgrade::on(&mut h_res);
+                self.relay(conn, path, client_upgrade, upstream_upgrade);
+                let (parts, _) = h_res.into_parts();
+                Ok(RhodResponse::new(HyperResponse::from_parts(
+                    parts,
+                    RhodBody::empty(),
+                )))
+            }
+            _ => {
+                remove_hop_by_hop(res.headers_mut());
+                if !sse::is_event_stream(&res) {
+                    return Ok(res);
+                }
+                Ok(match shared {
+                    Some((fanout, uri, headers)) => {
+                        fanout.start(self.client.clone(), uri, headers, res)
+                    }
+                    None => sse::stream_events(res),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::websocket::{read_frame, write_frame, Frame, OP_CLOSE};
+    use super::*;
+    use crate::body::RhodBodySender;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::RhodStack;
+    use crate::CommunicationChannel;
+    use base64::Engine;
+    use http::Request;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use std::convert::Infallible;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // Upstream echoing the WebSocket frames (answering pings and closes), and the path and
+    // X-Forwarded-For of HTTP requests
+    async fn upstream(mut req: Request<Incoming>) -> Result<HyperResponse<RhodBody>, Infallible> {
+        let key = match req.headers().get("sec-websocket-key") {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                let forwarded = req.headers()["x-forwarded-for"].to_str().unwrap();
+                let body = format!("{} {}", req.uri().path(), forwarded);
+                return Ok(HyperResponse::new(RhodBody::from(body)));
+            }
+        };
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        tokio::spawn(async move {
+            let mut io = TokioIo::new(on_upgrade.await.unwrap());
+            while let Ok(Some((mut frame, _))) = read_frame(&mut io, true, 1024).await {
+                if frame.opcode == 9 {
+                    frame.opcode = 10;
+                }
+                write_frame(&mut io, &frame, false).await.unwrap();
+                if frame.opcode == OP_CLOSE {
+                    break;
+                }
+            }
+        });
+        let accept = accept_key(&key);
+        Ok(HyperResponse::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-accept", accept)
+            .body(RhodBody::empty())
+            .unwrap())
+    }
+
+    fn accept_key(key: &[u8]) -> String {
+        let mut data = key.to_vec();
+        data.extend_from_slice(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+        let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+        base64::engine::general_purpose::STANDARD.encode(digest.as_ref())
+    }
+
+    // Serves the stack on HTTP/1.1 connections with upgrades, as Rhodium does
+    async fn listen(stack: Arc<RhodStack<Comm>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, client) = listener.accept().await.unwrap();
+                let stack = Arc::clone(&stack);
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let stack = Arc::clone(&stack);
+                    async move {
+                        let conn = RhodConnInfo::new(client, HttpProtocol::HTTP);
+                        let req = RhodRequest::new(req.map(RhodBody::new));
+                        let res = stack.execute(&conn, req).await.unwrap();
+                        Ok::<_, Infallible>(res.into_hyper_response())
+                    }
+                });
+                tokio::spawn(
+                    http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .with_upgrades(),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_proxy_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_uri = format!("http://{}/base", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service_fn(upstream))
+                        .with_upgrades(),
+                );
+            }
+        });
+        let (sessions, mut ended) = mpsc::unbounded_channel();
+        let proxy = ProxyService::new(upstream_uri.parse().unwrap())
+            .with_websocket_observer(move |session| sessions.send(session.clone()).unwrap());
+        let proxy_addr = listen(Arc::new(RhodStack::new(vec![], Box::new(proxy)))).await;
+
+        // plain requests
+        let client = RhodClient::new();
+        let req = RhodRequest::builder()
+            .uri(&format!("http://{}/orders?page=2", proxy_addr))
+            .header("x-forwarded-for", "10.0.0.1")
+            .build()
+            .unwrap();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(
+            res.body().await.unwrap(),
+            b"/base/orders 10.0.0.1, 127.0.0.1".to_vec()
+        );
+
+        // WebSocket handshake
+        let mut ws = TcpStream::connect(proxy_addr).await.unwrap();
+        ws.write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(ws.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap().to_lowercase();
+        assert!(head.starts_with("http/1.1 101"));
+        assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+
+        // messages and pings are relayed, and the close too
+        let text = Frame {
+            fin: true,
+            rsv: 0,
+            opcode: 1,
+            payload: b"hello".to_vec(),
+        };
+        write_frame(&mut ws, &text, true).await.unwrap();
+        let (echo, _) = read_frame(&mut ws, false, 1024)
+            .await
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(echo, text);
+        let ping = Frame {
+            opcode: 9,
+            payload: b"p".to_vec(),
+            ..text.clone()
+        };
+        write_frame(&mut ws, &ping, true).await.unwrap();
+        let (pong, _) = read_frame(&mut ws, false, 1024)
+            .await
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!((pong.opcode, pong.payload), (10, b"p".to_vec()));
+        write_frame(&mut ws, &Frame::close(1000), true)
+            .await
+            .unwrap();
+        let (close, _) = read_frame(&mut ws, false, 1024)
+            .await
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(close, Frame::close(1000));
+
+        let session = ended.recv().await.unwrap();
+        assert_eq!(session.path, "/chat");
+        assert_eq!(session.close_code, Some(1000));
+        assert_eq!(
+            (session.frames_from_client, session.frames_from_upstream),
+            (3, 3)
+        );
+        // 6 bytes of header, masked, and the payload
+        assert_eq!(session.bytes_from_client, 6 + 5 + 6 + 1 + 6 + 2);
+    }
+
+    type EventStreams = mpsc::UnboundedReceiver<(Option<String>, RhodBodySender)>;
+
+    // Upstream answering every request with an event stream, sent to the test with the Last-Event-ID of
+    // the request
+    async fn event_upstream() -> (Uri, EventStreams) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let (streams_tx, streams) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let streams_tx = streams_tx.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let last_event_id = req
+                        .headers()
+                        .get("last-event-id")
+                        .map(|v| v.to_str().unwrap().to_string());
+                    let (tx, body) = RhodBody::channel();
+                    streams_tx.send((last_event_id, tx)).unwrap();
+                    let res = HyperResponse::builder()
+                        .header("content-type", "text/event-stream")
+                        .body(body)
+                        .unwrap();
+                    async move { Ok::<_, Infallible>(res) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (uri.parse().unwrap(), streams)
+    }
+
+    async fn subscribe(proxy: SocketAddr, path: &str, last_event_id: Option<&str>) -> RhodBody {
+        let mut req = RhodRequest::builder()
+            .uri(&format!("http://{}{}", proxy, path))
+            .header("accept", "text/event-stream");
+        if let Some(id) = last_event_id {
+            req = req.header("last-event-id", id);
+        }
+        let res = RhodClient::new().send(req.build().unwrap()).await.unwrap();
+        res.into_hyper_response().into_body()
+    }
+
+    async fn next_event(body: &mut RhodBody) -> String {
+        let frame = body.frame().await.unwrap().unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sse_proxy() {
+        let (upstream_uri, mut streams) = event_upstream().await;
+        let proxy = ProxyService::new(upstream_uri.clone());
+        let proxy_addr = listen(Arc::new(RhodStack::new(vec![], Box::new(proxy)))).await;
+
+        // events are sent whole, as soon as they are complete
+        let mut events = subscribe(proxy_addr, "/events", Some("7")).await;
+        let (last_event_id, mut tx) = streams.recv().await.unwrap();
+        assert_eq!(last_event_id.as_deref(), Some("7"));
+        tx.send_data("id: 8\ndata: a".into()).await.unwrap();
+        let partial = tokio::time::timeout(Duration::from_millis(100), events.frame()).await;
+        assert!(partial.is_err());
+        tx.send_data("\n\ndata: b\n\n".into()).await.unwrap();
+        assert_eq!(next_event(&mut events).await, "id: 8\ndata: a\n\n");
+        assert_eq!(next_event(&mut events).await, "data: b\n\n");
+
+        // clients of the same stream share the upstream request
+        let proxy = ProxyService::new(upstream_uri).with_sse_fanout(10);
+        let proxy_addr = listen(Arc::new(RhodStack::new(vec![], Box::new(proxy)))).await;
+        let mut first = subscribe(proxy_addr, "/live", None).await;
+        let (last_event_id, mut tx) = streams.recv().await.unwrap();
+        assert_eq!(last_event_id, None);
+        tx.send_data("id: 1\ndata: x\n\nid: 2\ndata: y\n\n".into())
+            .await
+            .unwrap();
+        assert_eq!(next_event(&mut first).await, "id: 1\ndata: x\n\n");
+        assert_eq!(next_event(&mut first).await, "id: 2\ndata: y\n\n");
+
+        // reconnecting clients get the events they missed
+        let mut second = subscribe(proxy_addr, "/live", Some("1")).await;
+        assert_eq!(next_event(&mut second).await, "id: 2\ndata: y\n\n");
+        assert!(streams.try_recv().is_err());
+        tx.send_data("id: 3\nretry: 10\ndata: z\n\n".into())
+            .await
+            .unwrap();
+        for client in [&mut first, &mut second] {
+            assert_eq!(next_event(client).await, "id: 3\nretry: 10\ndata: z\n\n");
+        }
+
+        // the upstream is requested again from the last event when its stream ends
+        drop(tx);
+        let (last_event_id, mut tx) = streams.recv().await.unwrap();
+        assert_eq!(last_event_id.as_deref(), Some("3"));
+        tx.send_data("id: 4\ndata: w\n\n".into()).await.unwrap();
+        for client in [&mut first, &mut second] {
+            assert_eq!(next_event(client).await, "id: 4\ndata: w\n\n");
+        }
+    }
+}
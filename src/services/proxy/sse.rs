@@ -0,0 +1,317 @@
+// Server-sent events (text/event-stream) relayed by the proxy. The upstream body is cut into whole
+// events, each one sent to the client in its own frame as soon as its empty line arrives, so hyper
+// writes (and flushes) every event at once instead of waiting for more data.
+// With fan-out, the clients of the same stream share a single upstream request: the events are
+// broadcast to all of them, and the last ones are kept to be replayed to the clients reconnecting with
+// Last-Event-ID. When the upstream stream ends it is requested again (with the Last-Event-ID of the
+// last event, after the retry time of the stream) while there are clients.
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
+use http::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{Method, Request as HyperRequest, Response as HyperResponse, StatusCode, Uri};
+use http_body_util::BodyExt;
+use tokio::sync::broadcast;
+
+use crate::body::{BoxError, RhodBody};
+use crate::client::RhodClient;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::runtime;
+
+const EVENT_STREAM: &str = "text/event-stream";
+pub(crate) const LAST_EVENT_ID: &str = "last-event-id";
+
+// Larger events end the stream
+const MAX_EVENT_SIZE: usize = 1 << 20;
+// Time before requesting the upstream stream again, if the stream doesnt set it
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+// Events a client can fall behind before its stream is ended
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Event {
+    pub data: Bytes, // as received, with its empty line
+    pub id: Option<String>,
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    fn parse(data: Bytes) -> Event {
+        let (mut id, mut retry) = (None, None);
+        for line in data.split(|b| *b == b'\n' || *b == b'\r') {
+            let (field, value) = match line.iter().position(|b| *b == b':') {
+                Some(0) => continue, // comment
+                Some(i) => {
+                    let value = &line[i + 1..];
+                    (&line[..i], value.strip_prefix(b" ").unwrap_or(value))
+                }
+                None => (line, &b""[..]),
+            };
+            match field {
+                b"id" if !value.contains(&0) => {
+                    id = std::str::from_utf8(value).ok().map(str::to_string)
+                }
+                b"retry" => {
+                    retry = std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_millis)
+                }
+                _ => (),
+            }
+        }
+        Event { data, id, retry }
+    }
+}
+
+// Length of the first event of buf, up to the empty line ending it
+fn event_len(buf: &[u8]) -> Option<usize> {
+    let (mut line_start, mut i) = (0, 0);
+    while i < buf.len() {
+        let end = match buf[i] {
+            b'\n' => i + 1,
+            b'\r' => match buf.get(i + 1) {
+                Some(b'\n') => i + 2,
+                Some(_) => i + 1,
+                None => return None, // the \n may be in the next chunk
+            },
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        if i == line_start {
+            return Some(end);
+        }
+        line_start = end;
+        i = end;
+    }
+    None
+}
+
+// Events of the body, as they are complete
+fn events(body: RhodBody) -> impl Stream<Item = Result<Event, BoxError>> + Send {
+    stream::unfold(Some((body, BytesMut::new())), |state| async move {
+        let (mut body, mut buf) = state?;
+        loop {
+            if let Some(len) = event_len(&buf) {
+                let event = Event::parse(buf.split_to(len).freeze());
+                return Some((Ok(event), Some((body, buf))));
+            }
+            if buf.len() > MAX_EVENT_SIZE {
+                let e = format!("Event larger than {} bytes", MAX_EVENT_SIZE);
+                return Some((Err(e.into()), None));
+            }
+            match body.frame().await {
+                Some(Ok(frame)) => {
+                    if let Ok(data) = frame.into_data() {
+                        buf.extend_from_slice(&data);
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None if buf.is_empty() => return None,
+                // the end of the stream, without its empty line
+                None => return Some((Ok(Event::parse(buf.freeze())), None)),
+            }
+        }
+    })
+}
+
+// GET requests of clients accepting events
+pub(crate) fn accepts_events(req: &RhodRequest) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.contains(EVENT_STREAM))
+}
+
+pub(crate) fn is_event_stream(res: &RhodResponse) -> bool {
+    res.status_as_int() == StatusCode::OK
+        && res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim_start().starts_with(EVENT_STREAM))
+}
+
+// The response with its events streamed one by one
+pub(crate) fn stream_events(res: RhodResponse) -> RhodResponse {
+    let (mut parts, body) = res.into_hyper_response().into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    let body = RhodBody::wrap_stream(events(body).map_ok(|event| event.data));
+    RhodResponse::new(HyperResponse::from_parts(parts, body))
+}
+
+// Upstream stream shared by its clients
+struct Hub {
+    headers: HeaderMap, // of the upstream response
+    tx: broadcast::Sender<Event>,
+    history: Mutex<VecDeque<Event>>, // last events, to replay
+}
+
+impl Hub {
+    // The events after the last one the client got (if it is still in the history), then the new ones
+    fn subscribe(&self, last_event_id: Option<&str>) -> RhodResponse {
+        let history = self.history.lock().unwrap();
+        let replay: Vec<Bytes> = match last_event_id
+            .and_then(|id| history.iter().rposition(|e| e.id.as_deref() == Some(id)))
+        {
+            Some(i) => history.iter().skip(i + 1).map(|e| e.data.clone()).collect(),
+            None => vec![],
+        };
+        // subscribed with the history locked, so no event is missed or replayed twice
+        let rx = self.tx.subscribe();
+        drop(history);
+
+        let live = stream::unfold(rx, |mut rx| async move {
+            match rx.recv().await {
+                Ok(event) => Some((Ok(event.data), rx)),
+                // clients falling behind reconnect, getting the events they missed from the history
+                Err(_) => None,
+            }
+        });
+        let body = stream::iter(replay.into_iter().map(Ok::<_, BoxError>)).chain(live);
+        let mut res = HyperResponse::new(RhodBody::wrap_stream(body));
+        *res.headers_mut() = self.headers.clone();
+        RhodResponse::new(res)
+    }
+
+    fn publish(&self, event: Event, replay: usize) {
+        let mut history = self.history.lock().unwrap();
+        if replay > 0 {
+            if history.len() == replay {
+                history.pop_front();
+            }
+            history.push_back(event.clone());
+        }
+        let _ = self.tx.send(event);
+    }
+}
+
+type Hubs = Arc<Mutex<HashMap<String, Arc<Hub>>>>;
+
+// Upstream streams shared by the clients of the same upstream uri
+pub(crate) struct Fanout {
+    hubs: Hubs,
+    replay: usize, // events kept for the clients reconnecting
+}
+
+impl Fanout {
+    pub(crate) fn new(replay: usize) -> Fanout {
+        Fanout {
+            hubs: Arc::new(Mutex::new(HashMap::new())),
+            replay,
+        }
+    }
+
+    // Response of a client of a stream already requested to the upstream
+    pub(crate) fn subscribe(&self, uri: &Uri, last_event_id: Option<&str>) -> Option<RhodResponse> {
+        let hubs = self.hubs.lock().unwrap();
+        hubs.get(&uri.to_string())
+            .map(|hub| hub.subscribe(last_event_id))
+    }
+
+    // Shares the upstream response, requesting the stream again with the headers of this request while
+    // there are clients. Streams without clients are closed when they send their next event
+    pub(crate) fn start(
+        &self,
+        client: RhodClient,
+        uri: Uri,
+        headers: HeaderMap,
+        res: RhodResponse,
+    ) -> RhodResponse {
+        let (mut parts, body) = res.into_hyper_response().into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        let key = uri.to_string();
+        let hub = Arc::new(Hub {
+            headers: parts.headers,
+            tx: broadcast::channel(CHANNEL_CAPACITY).0,
+            history: Mutex::new(VecDeque::new()),
+        });
+        let res = hub.subscribe(None);
+        self.hubs
+            .lock()
+            .unwrap()
+            .insert(key.clone(), Arc::clone(&hub));
+
+        let hubs = Arc::clone(&self.hubs);
+        let replay = self.replay;
+        runtime::spawn(async move {
+            let mut last_event_id = headers
+                .get(LAST_EVENT_ID)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let mut retry = DEFAULT_RETRY;
+            let mut body = Some(body);
+            while let Some(stream) = body.take() {
+                let mut events = Box::pin(events(stream));
+                while let Some(event) = events.next().await {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(e) => {
+                            debug!("Event stream {} failed. {}", key, e);
+                            break;
+                        }
+                    };
+                    if event.id.is_some() {
+                        last_event_id = event.id.clone();
+                    }
+                    retry = event.retry.unwrap_or(retry);
+                    hub.publish(event, replay);
+                    if hub.tx.receiver_count() == 0 {
+                        break;
+                    }
+                }
+                if hub.tx.receiver_count() == 0 {
+                    break;
+                }
+                runtime::sleep(retry).await;
+                body = reconnect(&client, &uri, &headers, last_event_id.as_deref()).await;
+            }
+
+            // the clients left get the end of their streams, and reconnect
+            let mut hubs = hubs.lock().unwrap();
+            if hubs.get(&key).is_some_and(|h| Arc::ptr_eq(h, &hub)) {
+                hubs.remove(&key);
+            }
+        });
+        res
+    }
+}
+
+async fn reconnect(
+    client: &RhodClient,
+    uri: &Uri,
+    headers: &HeaderMap,
+    last_event_id: Option<&str>,
+) -> Option<RhodBody> {
+    let mut req = HyperRequest::new(RhodBody::empty());
+    *req.uri_mut() = uri.clone();
+    *req.headers_mut() = headers.clone();
+    match last_event_id.map(HeaderValue::from_str) {
+        Some(Ok(value)) => {
+            req.headers_mut().insert(LAST_EVENT_ID, value);
+        }
+        _ => {
+            req.headers_mut().remove(LAST_EVENT_ID);
+        }
+    }
+    match client.send(RhodRequest::new(req)).await {
+        Ok(res) if is_event_stream(&res) => Some(res.into_hyper_response().into_body()),
+        Ok(res) => {
+            debug!("Event stream {} ended, status {}", uri, res.status_as_int());
+            None
+        }
+        Err(e) => {
+            debug!("Event stream {} ended. {}", uri, e);
+            None
+        }
+    }
+}
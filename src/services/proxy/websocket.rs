@@ -0,0 +1,549 @@
+// WebSocket frames (RFC 6455) relayed between the client and the upstream. Frames are forwarded as they
+// are read (fragments and extension bits included), unmasked from the client and masked again with a
+// new key towards the upstream.
+// With message handlers, the text and binary messages are reassembled from their fragments and given
+// to the handlers before being forwarded as a single frame. A message is read, handled and written
+// before the next one is read, so a slow handler or a slow receiver holds the sender back.
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::future::{self, Either};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+use crate::runtime;
+
+const OP_CONTINUATION: u8 = 0;
+const OP_TEXT: u8 = 1;
+const OP_BINARY: u8 = 2;
+pub(crate) const OP_CLOSE: u8 = 8;
+
+// Close codes
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const TOO_BIG: u16 = 1009;
+
+// Time the other side has to answer a close frame, or to close after the first side is gone
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsDirection {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsMessageKind {
+    Text,
+    Binary,
+}
+
+// Complete text or binary message, its fragments joined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsMessage {
+    pub direction: WsDirection,
+    pub kind: WsMessageKind,
+    pub payload: Vec<u8>, // text messages must stay valid UTF-8
+}
+
+impl WsMessage {
+    pub fn text(&self) -> Option<&str> {
+        match self.kind {
+            WsMessageKind::Text => std::str::from_utf8(&self.payload).ok(),
+            WsMessageKind::Binary => None,
+        }
+    }
+
+    pub fn set_text(&mut self, text: String) {
+        self.kind = WsMessageKind::Text;
+        self.payload = text.into_bytes();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsAction {
+    Forward,    // the message as left by the handler, to the next handler or to the other side
+    Drop,       // blocked, the sender isnt told
+    Close(u16), // closes both sides with the code (ie: 1008 policy violation)
+}
+
+// Inspects, modifies or blocks the messages of the WebSocket connections relayed by a ProxyService.
+// Handlers are called in the order they were added, for the messages of both directions. Pings, pongs
+// and closes arent given to the handlers
+#[async_trait]
+pub trait WsMessageHandler: Send + Sync {
+    async fn on_message(&self, path: &str, message: &mut WsMessage) -> WsAction;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub fin: bool,
+    pub rsv: u8, // extension bits (ie: permessage-deflate), in the low 3 bits
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub(crate) fn close(code: u16) -> Frame {
+        Frame {
+            fin: true,
+            rsv: 0,
+            opcode: OP_CLOSE,
+            payload: code.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn close_code(&self) -> Option<u16> {
+        match self.payload.get(..2) {
+            Some([a, b]) if self.opcode == OP_CLOSE => Some(u16::from_be_bytes([*a, *b])),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) enum FrameError {
+    Io(io::Error),
+    TooBig,
+    Invalid(&'static str),
+}
+
+// Next frame and its size on the wire, None at the end of the connection. Frames of clients must be
+// masked and frames of servers must not
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    masked: bool,
+    max_size: usize,
+) -> Result<Option<(Frame, u64)>, FrameError> {
+    let mut head = [0u8; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(FrameError::Io(e)),
+    }
+    let (fin, rsv, opcode) = (head[0] & 0x80 != 0, (head[0] >> 4) & 7, head[0] & 0x0f);
+    if (head[1] & 0x80 != 0) != masked {
+        return Err(FrameError::Invalid("Unexpected frame masking"));
+    }
+    if matches!(opcode, 3..=7 | 11..=15) {
+        return Err(FrameError::Invalid("Unknown frame opcode"));
+    }
+    let mut wire = 2;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len).await.map_err(FrameError::Io)?;
+            wire += 2;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len).await.map_err(FrameError::Io)?;
+            wire += 8;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if opcode & 8 != 0 && (len > 125 || !fin) {
+        return Err(FrameError::Invalid("Invalid control frame"));
+    }
+    if len > max_size as u64 {
+        return Err(FrameError::TooBig);
+    }
+    let mut key = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut key).await.map_err(FrameError::Io)?;
+        wire += 4;
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(FrameError::Io)?;
+    if masked {
+        apply_mask(&mut payload, key);
+    }
+    let frame = Frame {
+        fin,
+        rsv,
+        opcode,
+        payload,
+    };
+    Ok(Some((frame, wire + len)))
+}
+
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+// Writes the frame, masked with a random key if masked, returning its size on the wire
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+    masked: bool,
+) -> io::Result<u64> {
+    let len = frame.payload.len();
+    let mut data = Vec::with_capacity(len + 14);
+    data.push(u8::from(frame.fin) << 7 | (frame.rsv & 7) << 4 | frame.opcode);
+    let mask_bit = u8::from(masked) << 7;
+    if len < 126 {
+        data.push(mask_bit | len as u8);
+    } else if len <= usize::from(u16::MAX) {
+        data.push(mask_bit | 126);
+        data.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        data.push(mask_bit | 127);
+        data.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    let start = data.len();
+    if masked {
+        let key = fastrand::u32(..).to_be_bytes();
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&frame.payload);
+        apply_mask(&mut data[start + 4..], key);
+    } else {
+        data.extend_from_slice(&frame.payload);
+    }
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(data.len() as u64)
+}
+
+// Frames and bytes read from one side
+#[derive(Default)]
+pub(crate) struct Flow {
+    pub bytes: AtomicU64,
+    pub frames: AtomicU64,
+    pub close_code: AtomicU64, // 0 until a close frame with a code is read
+}
+
+impl Flow {
+    pub(crate) fn close_code(&self) -> Option<u16> {
+        match self.close_code.load(Ordering::Relaxed) {
+            0 => None,
+            code => Some(code as u16),
+        }
+    }
+}
+
+// Writing half of a side, and whether the frames written to it are masked. Nothing is sent after a
+// close frame
+struct Peer<W> {
+    writer: Mutex<W>,
+    masked: bool,
+    closed: AtomicBool,
+}
+
+impl<W: AsyncWrite + Unpin> Peer<W> {
+    fn new(writer: W, masked: bool) -> Peer<W> {
+        Peer {
+            writer: Mutex::new(writer),
+            masked,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    async fn send(&self, frame: &Frame) -> io::Result<u64> {
+        let mut writer = self.writer.lock().await;
+        if self.closed.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        if frame.opcode == OP_CLOSE {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        write_frame(&mut *writer, frame, self.masked).await
+    }
+}
+
+// Settings of the relay of a connection
+pub(crate) struct Relay<'a> {
+    pub max_size: usize, // of frames, and of messages with handlers
+    pub path: &'a str,
+    pub handlers: &'a [Arc<dyn WsMessageHandler>],
+}
+
+impl Relay<'_> {
+    // Passes the message through the handlers, stopping at the first one not forwarding it
+    async fn handle(&self, message: &mut WsMessage) -> WsAction {
+        for handler in self.handlers {
+            match handler.on_message(self.path, message).await {
+                WsAction::Forward => (),
+                action => return action,
+            }
+        }
+        WsAction::Forward
+    }
+}
+
+// Sends a close frame with the code to the side the frames come from, and tells the other one
+async fn close_both<W, B>(to: &Peer<W>, back: &Peer<B>, code: u16)
+where
+    W: AsyncWrite + Unpin,
+    B: AsyncWrite + Unpin,
+{
+    let _ = back.send(&Frame::close(code)).await;
+    let _ = to.send(&Frame::close(GOING_AWAY)).await;
+}
+
+// Relays the frames read from one side to the other, until a close frame is relayed or the side is
+// gone. Invalid frames close both sides
+async fn forward<R, W, B>(
+    mut from: R,
+    direction: WsDirection,
+    to: &Peer<W>,
+    back: &Peer<B>,
+    relay: &Relay<'_>,
+    flow: &Flow,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    B: AsyncWrite + Unpin,
+{
+    // frames of clients are masked
+    let masked = direction == WsDirection::ClientToUpstream;
+    // message being reassembled, with handlers
+    let mut message: Option<WsMessage> = None;
+    loop {
+        let buffered = message.as_ref().map_or(0, |m| m.payload.len());
+        let max_size = relay.max_size - buffered;
+        let (frame, wire) = match read_frame(&mut from, masked, max_size).await {
+            Ok(Some(read)) => read,
+            Ok(None) => {
+                let _ = to.send(&Frame::close(GOING_AWAY)).await;
+                return;
+            }
+            Err(FrameError::Io(e)) => {
+                debug!("WebSocket connection error. {}", e);
+                let _ = to.send(&Frame::close(GOING_AWAY)).await;
+                return;
+            }
+            Err(FrameError::TooBig) => {
+                debug!("WebSocket message larger than {} bytes", relay.max_size);
+                close_both(to, back, TOO_BIG).await;
+                return;
+            }
+            Err(FrameError::Invalid(msg)) => {
+                debug!("Invalid WebSocket frame. {}", msg);
+                close_both(to, back, PROTOCOL_ERROR).await;
+                return;
+            }
+        };
+        flow.bytes.fetch_add(wire, Ordering::Relaxed);
+        flow.frames.fetch_add(1, Ordering::Relaxed);
+        if let Some(code) = frame.close_code() {
+            flow.close_code.store(u64::from(code), Ordering::Relaxed);
+        }
+        // control frames (ping, pong) are relayed as they are, interleaved with the messages
+        if relay.handlers.is_empty() || frame.opcode & 8 != 0 {
+            if to.send(&frame).await.is_err() || frame.opcode == OP_CLOSE {
+                return;
+            }
+            continue;
+        }
+
+        // extensions are removed from the handshake when there are handlers
+        if frame.rsv != 0 {
+            debug!("Invalid WebSocket frame. Unexpected extension bits");
+            close_both(to, back, PROTOCOL_ERROR).await;
+            return;
+        }
+        let kind = match frame.opcode {
+            OP_TEXT => Some(WsMessageKind::Text),
+            OP_BINARY => Some(WsMessageKind::Binary),
+            _ => None,
+        };
+        match (&mut message, kind) {
+            (None, Some(kind)) => {
+                message = Some(WsMessage {
+                    direction,
+                    kind,
+                    payload: frame.payload,
+                })
+            }
+            (Some(message), None) if frame.opcode == OP_CONTINUATION => {
+                message.payload.extend_from_slice(&frame.payload)
+            }
+            _ => {
+                debug!("Invalid WebSocket frame. Unexpected message fragment");
+                close_both(to, back, PROTOCOL_ERROR).await;
+                return;
+            }
+        }
+        if !frame.fin {
+            continue;
+        }
+
+        let mut message = match message.take() {
+            Some(message) => message,
+            None => continue,
+        };
+        match relay.handle(&mut message).await {
+            WsAction::Forward => {
+                let opcode = match message.kind {
+                    WsMessageKind::Text => OP_TEXT,
+                    WsMessageKind::Binary => OP_BINARY,
+                };
+                let frame = Frame {
+                    fin: true,
+                    rsv: 0,
+                    opcode,
+                    payload: message.payload,
+                };
+                if to.send(&frame).await.is_err() {
+                    return;
+                }
+            }
+            WsAction::Drop => debug!("WebSocket message of {} dropped", relay.path),
+            WsAction::Close(code) => {
+                debug!("WebSocket {} closed by a handler ({})", relay.path, code);
+                let _ = back.send(&Frame::close(code)).await;
+                let _ = to.send(&Frame::close(code)).await;
+                return;
+            }
+        }
+    }
+}
+
+// Relays frames between the client and the upstream until both sides have closed, or one of them is
+// gone and the other doesnt close in time
+pub(crate) async fn relay<C, U>(
+    client: C,
+    upstream: U,
+    relay: &Relay<'_>,
+    from_client: &Flow,
+    from_upstream: &Flow,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (client_read, client_write) = tokio::io::split(client);
+    let (upstream_read, upstream_write) = tokio::io::split(upstream);
+    let client_peer = Peer::new(client_write, false);
+    let upstream_peer = Peer::new(upstream_write, true);
+    let to_upstream = Box::pin(forward(
+        BufReader::new(client_read),
+        WsDirection::ClientToUpstream,
+        &upstream_peer,
+        &client_peer,
+        relay,
+        from_client,
+    ));
+    let to_client = Box::pin(forward(
+        BufReader::new(upstream_read),
+        WsDirection::UpstreamToClient,
+        &client_peer,
+        &upstream_peer,
+        relay,
+        from_upstream,
+    ));
+    match future::select(to_upstream, to_client).await {
+        Either::Left((_, to_client)) => {
+            let _ = runtime::timeout(CLOSE_TIMEOUT, to_client).await;
+        }
+        Either::Right((_, to_upstream)) => {
+            let _ = runtime::timeout(CLOSE_TIMEOUT, to_upstream).await;
+        }
+    }
+    let _ = client_peer.writer.lock().await.shutdown().await;
+    let _ = upstream_peer.writer.lock().await.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Filter {}
+
+    #[async_trait]
+    impl WsMessageHandler for Filter {
+        async fn on_message(&self, _path: &str, message: &mut WsMessage) -> WsAction {
+            match message.text() {
+                Some("bye") => WsAction::Close(1008),
+                Some("drop me") => WsAction::Drop,
+                Some(text) if text.contains("secret") => {
+                    message.set_text(text.replace("secret", "[redacted]"));
+                    WsAction::Forward
+                }
+                _ => WsAction::Forward,
+            }
+        }
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &str) -> Frame {
+        Frame {
+            fin,
+            rsv: 0,
+            opcode,
+            payload: payload.as_bytes().to_vec(),
+        }
+    }
+
+    async fn next<R: AsyncRead + Unpin>(reader: &mut R, masked: bool) -> Frame {
+        match read_frame(reader, masked, 1024).await {
+            Ok(Some((frame, _))) => frame,
+            _ => panic!("No frame"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_handlers() {
+        let (mut client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(1024);
+        let relaying = tokio::spawn(async move {
+            let handlers: Vec<Arc<dyn WsMessageHandler>> = vec![Arc::new(Filter {})];
+            let conf = Relay {
+                max_size: 1024,
+                path: "/chat",
+                handlers: &handlers,
+            };
+            let (from_client, from_upstream) = (Flow::default(), Flow::default());
+            relay(
+                proxy_client,
+                proxy_upstream,
+                &conf,
+                &from_client,
+                &from_upstream,
+            )
+            .await;
+            from_client.frames.load(Ordering::Relaxed)
+        });
+
+        // fragments are joined, pings arent held back
+        write_frame(&mut client, &frame(false, OP_TEXT, "my sec"), true)
+            .await
+            .unwrap();
+        write_frame(&mut client, &frame(true, 9, "p"), true)
+            .await
+            .unwrap();
+        write_frame(&mut client, &frame(true, OP_CONTINUATION, "ret"), true)
+            .await
+            .unwrap();
+        assert_eq!(next(&mut upstream, true).await, frame(true, 9, "p"));
+        assert_eq!(
+            next(&mut upstream, true).await,
+            frame(true, OP_TEXT, "my [redacted]")
+        );
+
+        // dropped messages arent relayed
+        write_frame(&mut upstream, &frame(true, OP_TEXT, "drop me"), false)
+            .await
+            .unwrap();
+        write_frame(&mut upstream, &frame(true, OP_BINARY, "ok"), false)
+            .await
+            .unwrap();
+        assert_eq!(next(&mut client, false).await, frame(true, OP_BINARY, "ok"));
+
+        // both sides are closed with the code of the handler
+        write_frame(&mut client, &frame(true, OP_TEXT, "bye"), true)
+            .await
+            .unwrap();
+        assert_eq!(next(&mut client, false).await, Frame::close(1008));
+        assert_eq!(next(&mut upstream, true).await, Frame::close(1008));
+        drop((client, upstream));
+        assert_eq!(relaying.await.unwrap(), 4);
+    }
+}
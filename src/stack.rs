@@ -1,13 +1,34 @@
 use super::*;
+use crate::admission::{AdmissionController, Priority};
+use crate::auto_headers::AutoHeaders;
+use crate::body::BodyPassthrough;
+use crate::channel_pool::{ChannelPool, PooledChannel};
+use crate::error_pages::ErrorPages;
 use crate::errors::{RhodError, RhodResult};
+use crate::handlers::RequestPredicate;
+use crate::limits::{Deadline, ResponseLimits};
+use crate::negotiation::Accept;
 use crate::request::*;
 use crate::response::*;
+use crate::state::StateMap;
 use async_trait::async_trait;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // A stack is a list of handlers/dynamic handlers and one service
 pub struct RhodStack<C> {
     pub handlers: Vec<RhodHandlerInStack<C>>,
     pub service: Box<dyn RhodService<C>>,
+    slow_threshold: Option<Duration>,
+    state: Arc<StateMap>,
+    response_limits: ResponseLimits,
+    auto_headers: AutoHeaders,
+    error_pages: Option<ErrorPages>,
+    admission: Option<Arc<AdmissionController>>,
+    body_passthrough: bool,
+    channels: Option<ChannelPool<C>>,
 }
 
 impl<C> RhodStack<C> {
@@ -15,13 +36,476 @@ impl<C> RhodStack<C> {
         handlers: Vec<RhodHandlerInStack<C>>,
         service: Box<dyn RhodService<C>>,
     ) -> RhodStack<C> {
-        RhodStack { handlers, service }
+        RhodStack {
+            handlers,
+            service,
+            slow_threshold: None,
+            state: Arc::new(StateMap::new()),
+            response_limits: ResponseLimits::default(),
+            auto_headers: AutoHeaders::default(),
+            error_pages: None,
+            admission: None,
+            body_passthrough: false,
+            channels: None,
+        }
+    }
+
+    // Shares the state with other stacks, instead of starting with an empty one
+    pub fn with_state(self, state: Arc<StateMap>) -> Self {
+        RhodStack { state, ..self }
+    }
+
+    // State shared by the requests of the stack, also injected into every request context
+    pub fn state(&self) -> &Arc<StateMap> {
+        &self.state
+    }
+
+    // Logs a warning when a handler or the service takes longer than the threshold on a stage
+    pub fn with_slow_threshold(self, threshold: Duration) -> Self {
+        RhodStack {
+            slow_threshold: Some(threshold),
+            ..self
+        }
+    }
+
+    // Size and time limits of the service responses
+    pub fn with_response_limits(self, response_limits: ResponseLimits) -> Self {
+        RhodStack {
+            response_limits,
+            ..self
+        }
+    }
+
+    // Headers kept right by the executor on the final response (Date, Server, Content-Length)
+    pub fn with_auto_headers(self, auto_headers: AutoHeaders) -> Self {
+        RhodStack {
+            auto_headers,
+            ..self
+        }
+    }
+
+    // Pages rendered for error responses, errors without a response are then answered with a 500
+    pub fn with_error_pages(self, error_pages: ErrorPages) -> Self {
+        RhodStack {
+            error_pages: Some(error_pages),
+            ..self
+        }
+    }
+
+    // Limits the requests served at once by the service, shedding the ones that cant wait. Requests are
+    // admitted after the request handlers, so they can set their Priority
+    pub fn with_admission(self, admission: Arc<AdmissionController>) -> Self {
+        RhodStack {
+            admission: Some(admission),
+            ..self
+        }
+    }
+
+    pub fn admission(&self) -> Option<&Arc<AdmissionController>> {
+        self.admission.as_ref()
+    }
+
+    // Leaves the bodies streaming from the client to the service and back untouched (ie: proxies): the
+    // executor doesnt read them, so error pages only replace empty bodies and the client doesnt keep
+    // request bodies to retry. Only enabled when no handler needs the bodies (RhodHandler::needs_body)
+    pub fn with_body_passthrough(self) -> Self {
+        let needing: Vec<&str> = self
+            .handlers
+            .iter()
+            .filter(|handler| handler.needs_body())
+            .map(|handler| handler.name())
+            .collect();
+        if !needing.is_empty() {
+            warn!(
+                "Body passthrough not enabled, the bodies are needed by {}",
+                needing.join(", ")
+            );
+            return self;
+        }
+        RhodStack {
+            body_passthrough: true,
+            ..self
+        }
+    }
+
+    pub fn body_passthrough(&self) -> bool {
+        self.body_passthrough
+    }
+
+    // Reuses the communication channels between requests, keeping up to max_idle of them
+    pub fn with_channel_pool(self, max_idle: usize) -> Self {
+        RhodStack {
+            channels: Some(ChannelPool::new(max_idle)),
+            ..self
+        }
+    }
+
+    pub fn channel_pool(&self) -> Option<&ChannelPool<C>> {
+        self.channels.as_ref()
+    }
+
+    // Time since start, warning if the stage was slow
+    fn timed(&self, name: &str, stage: &str, start: Instant) -> (String, Duration) {
+        let elapsed = start.elapsed();
+        match self.slow_threshold {
+            Some(threshold) if elapsed > threshold => {
+                warn!("{} took {:?} in {}", name, elapsed, stage)
+            }
+            _ => (),
+        }
+        (name.to_string(), elapsed)
+    }
+
+    // Ordered list of handler names plus the service name
+    pub fn describe(&self) -> RhodStackDescription {
+        RhodStackDescription {
+            handlers: self.handlers.iter().map(|h| h.name().to_string()).collect(),
+            service: self.service.name().to_string(),
+        }
+    }
+}
+
+impl<C: CommunicationChannel> RhodStack<C> {
+    // Runs the request through the handlers, the service, and the response back through the handlers.
+    // Handlers inside groups are executed as if they were part of the stack.
+    pub async fn execute(&self, conn: &RhodConnInfo, req: RhodRequest) -> RhodResult<RhodResponse> {
+        let head = req.method() == http::Method::HEAD;
+        let accept = Accept::from_request(&req);
+        let context = req.context().clone();
+        let result = self.run(conn, req).await;
+        let res = match (result, &self.error_pages) {
+            (Ok(res), _) => res,
+            (Err(_), Some(_)) => {
+                let mut res = ErrorPages::internal_error();
+                res.attach_context(&context);
+                res
+            }
+            (Err(e), None) => return Err(e),
+        };
+        self.finish(res, &accept, head).await
+    }
+
+    // Error page and automatic headers of the final response
+    async fn finish(
+        &self,
+        mut res: RhodResponse,
+        accept: &Accept,
+        head: bool,
+    ) -> RhodResult<RhodResponse> {
+        if let Some(error_pages) = &self.error_pages {
+            error_pages
+                .render(&mut res, accept, self.body_passthrough)
+                .await;
+        }
+        self.auto_headers.apply(&mut res, head);
+        Ok(res)
+    }
+
+    async fn run(&self, conn: &RhodConnInfo, mut req: RhodRequest) -> RhodResult<RhodResponse> {
+        req.context().insert(Arc::clone(&self.state));
+        if self.body_passthrough {
+            req.context().insert(BodyPassthrough);
+        }
+        let created = match &self.channels {
+            Some(pool) => pool.checkout(conn, &req).await,
+            None => C::try_new(conn, &req).await,
+        };
+        let (communication, mut err) = match created {
+            Ok(communication) => (communication, None),
+            Err(e) => {
+                debug!("Communication channel couldnt be created");
+                e.log();
+                (C::new(), Some(e))
+            }
+        };
+        let mut communication = PooledChannel::new(communication, self.channels.as_ref());
+
+        // handlers that saw the request (dynamic handlers already resolved), in execution order
+        let mut executed: Vec<ResolvedHandler<'_, C>> = Vec::with_capacity(self.handlers.len());
+        // number of handlers that handled the request before one of them failed
+        let mut answered_by = 0;
+        // iterators over the stack and the groups being executed (only groups allocate)
+        let mut handlers = self.handlers.iter();
+        let mut groups: Vec<std::slice::Iter<'_, RhodHandlerInStack<C>>> = vec![];
+        let mut timing = StackTiming::default();
+
+        // call handle_request from handlers in order:
+        loop {
+            let current = match groups.last_mut() {
+                Some(group) => group,
+                None => &mut handlers,
+            };
+            let handler = match current.next() {
+                None => match groups.pop() {
+                    Some(_) => continue,
+                    None => break,
+                },
+                // if is a group, its handlers are executed next (only if the group applies to the request)
+                Some(RhodHandlerInStack::Group(group)) => {
+                    if group.applies_to(&req) {
+                        groups.push(group.handlers.iter());
+                    }
+                    continue;
+                }
+                // if is dynamic handler, gets it. If it cant be resolved, it is handled like a failing handler
+                Some(RhodHandlerInStack::DynamicRhodHandler(dyn_handler)) => {
+                    match dyn_handler
+                        .get_handler(conn, &req, &mut communication)
+                        .await
+                    {
+                        Ok(handler) => ResolvedHandler::Borrowed(handler),
+                        Err(e) => {
+                            debug!("{} couldnt be resolved", dyn_handler.name());
+                            e.log();
+                            if err.is_none() {
+                                err = Some(e);
+                                answered_by = executed.len();
+                            }
+                            continue;
+                        }
+                    }
+                }
+                // owned dynamic handlers are kept until the response is handled
+                Some(RhodHandlerInStack::OwnedDynamicRhodHandler(dyn_handler)) => match dyn_handler
+                    .get_handler(conn, &req, &mut communication)
+                    .await
+                {
+                    Ok(handler) => ResolvedHandler::Owned(handler),
+                    Err(e) => {
+                        debug!("{} couldnt be resolved", dyn_handler.name());
+                        e.log();
+                        if err.is_none() {
+                            err = Some(e);
+                            answered_by = executed.len();
+                        }
+                        continue;
+                    }
+                },
+                Some(RhodHandlerInStack::RhodHandler(handler)) => {
+                    ResolvedHandler::Borrowed(&**handler)
+                }
+            };
+            executed.push(handler);
+            let handler = &**executed.last().unwrap();
+
+            match &err {
+                None => {
+                    let start = Instant::now();
+                    let result = handler
+                        .handle_request(conn, &mut req, &mut communication)
+                        .await;
+                    timing
+                        .requests
+                        .push(self.timed(handler.name(), "handle_request", start));
+                    match result {
+                        Ok(()) => (),
+                        Err(e) => {
+                            debug!("{} failed handling the request", handler.name());
+                            e.log();
+                            err = Some(e);
+                            // the failing handler doesnt handle the response
+                            answered_by = executed.len() - 1;
+                        }
+                    }
+                }
+                Some(e) => {
+                    handler.catch_request(conn, &req, e, &communication).await;
+                }
+            }
+        }
+
+        let context = req.context().clone();
+        context.insert(timing);
+        let mut res = match err.take() {
+            // call rhodium service:
+            None => {
+                let priority = req.context().get::<Priority>().unwrap_or_default();
+                let admitted = match &self.admission {
+                    Some(admission) => match admission.admit(priority).await {
+                        Some(admitted) => Ok(Some(admitted)),
+                        None => Err(admission.shed_response()),
+                    },
+                    None => Ok(None),
+                };
+                match admitted {
+                    Ok(admitted) => {
+                        let start = Instant::now();
+                        if let Some(timeout) = self.response_limits.timeout() {
+                            Deadline::after(timeout).tighten(req.context());
+                        }
+                        let served = self.service.serve(conn, req, &mut communication);
+                        let result = self.response_limits.serve(served).await;
+                        drop(admitted);
+                        let (_, elapsed) = self.timed(self.service.name(), "serve", start);
+                        context.update(|timing: &mut StackTiming| timing.service = Some(elapsed));
+                        match result {
+                            Ok(res) => res,
+                            Err(mut e) => {
+                                debug!("{} failed serving the request", self.service.name());
+                                e.log();
+                                match e.take_response() {
+                                    Some(res) => res,
+                                    None => return Err(e),
+                                }
+                            }
+                        }
+                    }
+                    Err(shed) => {
+                        debug!("Request shed, too many requests running");
+                        shed
+                    }
+                }
+            }
+            // if the error carries a response, answers with it through the handlers that already ran
+            Some(mut e) => match e.take_response() {
+                Some(res) => {
+                    executed.truncate(answered_by);
+                    res
+                }
+                None => return Err(e),
+            },
+        };
+        res.attach_context(&context);
+
+        // call handle_response from handlers in reverse order:
+        for handler in executed.into_iter().rev() {
+            match &err {
+                None => {
+                    let start = Instant::now();
+                    let result = handler.handle_response(conn, res, &mut communication).await;
+                    let stage = self.timed(handler.name(), "handle_response", start);
+                    context.update(|timing: &mut StackTiming| timing.responses.push(stage));
+                    match result {
+                        (new_res, Ok(())) => res = new_res,
+                        (new_res, Err(e)) => {
+                            res = new_res;
+                            debug!("{} failed handling the response", handler.name());
+                            e.log();
+                            err = Some(e);
+                        }
+                    }
+                }
+                Some(e) => {
+                    handler.catch_response(conn, &res, e, &communication).await;
+                }
+            }
+        }
+
+        match err {
+            Some(mut e) => match e.take_response() {
+                Some(mut new_res) => {
+                    new_res.attach_context(&context);
+                    Ok(new_res)
+                }
+                None => Err(e),
+            },
+            None => Ok(res),
+        }
+    }
+}
+
+// Time spent by the handlers and the service of a stack execution, available in the request and
+// response context. Catching an error is not timed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StackTiming {
+    pub requests: Vec<(String, Duration)>, // handle_request of each handler, in execution order
+    pub service: Option<Duration>,         // None if the service wasnt called
+    pub responses: Vec<(String, Duration)>, // handle_response of each handler, in execution order
+}
+
+impl StackTiming {
+    pub fn total(&self) -> Duration {
+        self.requests
+            .iter()
+            .chain(self.responses.iter())
+            .map(|(_, elapsed)| *elapsed)
+            .chain(self.service)
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RhodStackDescription {
+    pub handlers: Vec<String>,
+    pub service: String,
+}
+
+impl fmt::Display for RhodStackDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for handler in self.handlers.iter() {
+            write!(f, "{} -> ", handler)?;
+        }
+        write!(f, "{}", self.service)
     }
 }
 
 pub enum RhodHandlerInStack<C> {
     RhodHandler(Box<dyn RhodHandler<C>>),
     DynamicRhodHandler(Box<dyn DynamicRhodHandler<C>>),
+    OwnedDynamicRhodHandler(Box<dyn DynamicRhodHandlerOwned<C>>),
+    Group(RhodLayerGroup<C>),
+}
+
+impl<C> RhodHandlerInStack<C> {
+    pub fn name(&self) -> &str {
+        match self {
+            RhodHandlerInStack::RhodHandler(handler) => handler.name(),
+            RhodHandlerInStack::DynamicRhodHandler(handler) => handler.name(),
+            RhodHandlerInStack::OwnedDynamicRhodHandler(handler) => handler.name(),
+            RhodHandlerInStack::Group(group) => &group.name,
+        }
+    }
+
+    pub fn needs_body(&self) -> bool {
+        match self {
+            RhodHandlerInStack::RhodHandler(handler) => handler.needs_body(),
+            RhodHandlerInStack::DynamicRhodHandler(handler) => handler.needs_body(),
+            RhodHandlerInStack::OwnedDynamicRhodHandler(handler) => handler.needs_body(),
+            RhodHandlerInStack::Group(group) => group.handlers.iter().any(|h| h.needs_body()),
+        }
+    }
+}
+
+// A named list of handlers (a reusable middleware bundle) that is executed as part of the stack.
+// Groups can be nested, and can be restricted to the requests matching a predicate.
+pub struct RhodLayerGroup<C> {
+    name: String,
+    handlers: Vec<RhodHandlerInStack<C>>,
+    predicate: Option<RequestPredicate>,
+}
+
+impl<C> RhodLayerGroup<C> {
+    pub fn new(name: &str, handlers: Vec<RhodHandlerInStack<C>>) -> RhodLayerGroup<C> {
+        RhodLayerGroup {
+            name: name.to_string(),
+            handlers,
+            predicate: None,
+        }
+    }
+
+    // The group is only executed for requests matching the predicate
+    pub fn when(self, predicate: RequestPredicate) -> RhodLayerGroup<C> {
+        RhodLayerGroup {
+            predicate: Some(predicate),
+            ..self
+        }
+    }
+
+    // The group is only executed for requests under the path prefix
+    pub fn mount(self, prefix: &str) -> RhodLayerGroup<C> {
+        self.when(RequestPredicate::PathPrefix(prefix.to_string()))
+    }
+
+    pub fn handlers(&self) -> &[RhodHandlerInStack<C>] {
+        &self.handlers
+    }
+
+    fn applies_to(&self, req: &RhodRequest) -> bool {
+        match &self.predicate {
+            Some(predicate) => predicate.matches(req),
+            None => true,
+        }
+    }
 }
 
 //The generic type C refers to the type that will be used for communication between handlers and the service
@@ -55,9 +539,20 @@ pub trait RhodHandler<C>: Sync + Send {
         err: &RhodError,
         comm: &C,
     );
+
+    // Name used to identify the handler in logs. Defaults to the type name
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    // Whether the handler reads or replaces request or response bodies, see RhodStack::with_body_passthrough
+    fn needs_body(&self) -> bool {
+        false
+    }
 }
 
-//Dynamic Handlers are handlers that are evaluated in runtime
+//Dynamic Handlers are handlers that are evaluated in runtime.
+//If get_handler fails, the error is handled like an error returned by a handler while handling the request
 #[async_trait]
 pub trait DynamicRhodHandler<C>: Sync + Send {
     async fn get_handler<'a>(
@@ -65,7 +560,53 @@ pub trait DynamicRhodHandler<C>: Sync + Send {
         conn: &RhodConnInfo,
         req: &RhodRequest,
         comm: &mut C,
-    ) -> &'a dyn RhodHandler<C>;
+    ) -> RhodResult<&'a dyn RhodHandler<C>>;
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    // The handlers resolved at runtime arent known, so they are taken as needing the bodies
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+//Owned Dynamic Handlers return handlers that can be created for each request (ie: from per-tenant configs).
+//The executor keeps the handler until the response is handled. Errors are handled like in get_handler
+#[async_trait]
+pub trait DynamicRhodHandlerOwned<C>: Sync + Send {
+    async fn get_handler(
+        &self,
+        conn: &RhodConnInfo,
+        req: &RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<Arc<dyn RhodHandler<C>>>;
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+}
+
+// A handler resolved by the executor: borrowed from the stack or owned by the request
+enum ResolvedHandler<'a, C> {
+    Borrowed(&'a dyn RhodHandler<C>),
+    Owned(Arc<dyn RhodHandler<C>>),
+}
+
+impl<'a, C> Deref for ResolvedHandler<'a, C> {
+    type Target = dyn RhodHandler<C> + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            ResolvedHandler::Borrowed(handler) => *handler,
+            ResolvedHandler::Owned(handler) => &**handler,
+        }
+    }
 }
 
 #[async_trait]
@@ -76,4 +617,377 @@ pub trait RhodService<C>: Sync + Send {
         req: RhodRequest,
         comm: &mut C,
     ) -> RhodResult<RhodResponse>;
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+// Lets many stacks share a service (ie: the stacks rebuilt by a config reload)
+#[async_trait]
+impl<C: Send + Sync, S: RhodService<C> + ?Sized> RhodService<C> for Arc<S> {
+    async fn serve(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        (**self).serve(conn, req, comm).await
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::RhodErrorLevel;
+    use crate::handlers::BodyRewriteHandler;
+    use crate::protocols::HttpProtocol;
+
+    struct Comm {}
+    #[async_trait]
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+
+        async fn try_new(_conn: &RhodConnInfo, req: &RhodRequest) -> RhodResult<Comm> {
+            if req.headers().contains_key("x-fail") {
+                Err(RhodError::from_str("no session", RhodErrorLevel::Warning))
+            } else {
+                Ok(Comm::new())
+            }
+        }
+    }
+
+    // Appends its name to the x-trace header of the request and the response
+    struct TraceHandler {
+        name: &'static str,
+    }
+    #[async_trait]
+    impl RhodHandler<Comm> for TraceHandler {
+        async fn handle_request(
+            &self,
+            _conn: &RhodConnInfo,
+            req: &mut RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<()> {
+            req.headers_mut()
+                .append("x-trace", self.name.parse().unwrap());
+            Ok(())
+        }
+        async fn catch_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &RhodRequest,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+        async fn handle_response(
+            &self,
+            _conn: &RhodConnInfo,
+            mut res: RhodResponse,
+            _comm: &mut Comm,
+        ) -> (RhodResponse, RhodResult<()>) {
+            res.headers_mut()
+                .append("x-trace", self.name.parse().unwrap());
+            (res, Ok(()))
+        }
+        async fn catch_response(
+            &self,
+            _conn: &RhodConnInfo,
+            _res: &RhodResponse,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+        fn name(&self) -> &str {
+            self.name
+        }
+    }
+
+    // Answers early, without reaching the service
+    struct EarlyHandler {}
+    #[async_trait]
+    impl RhodHandler<Comm> for EarlyHandler {
+        async fn handle_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &mut RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<()> {
+            let res = RhodResponse::builder().body_str("early").build()?;
+            Err(RhodError::from_response(res))
+        }
+        async fn catch_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &RhodRequest,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+        async fn handle_response(
+            &self,
+            _conn: &RhodConnInfo,
+            mut res: RhodResponse,
+            _comm: &mut Comm,
+        ) -> (RhodResponse, RhodResult<()>) {
+            // the handler answering early doesnt handle the response
+            res.headers_mut()
+                .append("x-trace", "early".parse().unwrap());
+            (res, Ok(()))
+        }
+        async fn catch_response(
+            &self,
+            _conn: &RhodConnInfo,
+            _res: &RhodResponse,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+    }
+
+    // Returns the request trace as the response body
+    struct TraceService {}
+    #[async_trait]
+    impl RhodService<Comm> for TraceService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let trace: Vec<&str> = req
+                .headers()
+                .get_all("x-trace")
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect();
+            RhodResponse::builder().body_str(&trace.join(",")).build()
+        }
+    }
+
+    fn trace(name: &'static str) -> RhodHandlerInStack<Comm> {
+        RhodHandlerInStack::RhodHandler(Box::new(TraceHandler { name }))
+    }
+
+    async fn run(stack: &RhodStack<Comm>, uri: &str) -> (String, String) {
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder().uri(uri).build().unwrap();
+        let mut res = stack.execute(&conn, req).await.unwrap();
+        let res_trace: Vec<&str> = res
+            .headers()
+            .get_all("x-trace")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        let res_trace = res_trace.join(",");
+        let req_trace = String::from_utf8(res.body().await.unwrap()).unwrap();
+        (req_trace, res_trace)
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let inner = RhodLayerGroup::new("inner", vec![trace("c"), trace("d")]);
+        let api = RhodLayerGroup::new(
+            "api",
+            vec![trace("b"), RhodHandlerInStack::Group(inner), trace("e")],
+        )
+        .mount("/api");
+        let stack = RhodStack::new(
+            vec![trace("a"), RhodHandlerInStack::Group(api), trace("f")],
+            Box::new(TraceService {}),
+        );
+
+        assert_eq!(stack.describe().handlers, vec!["a", "api", "f"]);
+
+        let (req_trace, res_trace) = run(&stack, "/api/users").await;
+        assert_eq!(req_trace, "a,b,c,d,e,f");
+        assert_eq!(res_trace, "f,e,d,c,b,a");
+
+        let (req_trace, res_trace) = run(&stack, "/static").await;
+        assert_eq!(req_trace, "a,f");
+        assert_eq!(res_trace, "f,a");
+    }
+
+    #[tokio::test]
+    async fn test_failed_communication_channel() {
+        let stack = RhodStack::new(vec![trace("a")], Box::new(TraceService {}));
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .header("x-fail", "1")
+            .build()
+            .unwrap();
+
+        let err = stack.execute(&conn, req).await.err().unwrap();
+        assert_eq!(err.to_string(), "no session");
+    }
+
+    // Fails resolving requests with an x-unresolved header
+    struct FailingDynamicHandler {
+        handler: TraceHandler,
+    }
+    #[async_trait]
+    impl DynamicRhodHandler<Comm> for FailingDynamicHandler {
+        async fn get_handler<'a>(
+            &'a self,
+            _conn: &RhodConnInfo,
+            req: &RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<&'a dyn RhodHandler<Comm>> {
+            if req.headers().contains_key("x-unresolved") {
+                let res = RhodResponse::builder().body_str("unresolved").build()?;
+                Err(RhodError::from_response(res))
+            } else {
+                Ok(&self.handler)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unresolved_dynamic_handler() {
+        let stack = RhodStack::new(
+            vec![
+                trace("a"),
+                RhodHandlerInStack::DynamicRhodHandler(Box::new(FailingDynamicHandler {
+                    handler: TraceHandler { name: "b" },
+                })),
+                trace("c"),
+            ],
+            Box::new(TraceService {}),
+        );
+
+        let (req_trace, res_trace) = run(&stack, "/").await;
+        assert_eq!(req_trace, "a,b,c");
+        assert_eq!(res_trace, "c,b,a");
+
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .header("x-unresolved", "1")
+            .build()
+            .unwrap();
+        let mut res = stack.execute(&conn, req).await.unwrap();
+        assert_eq!(res.headers().get("x-trace").unwrap(), "a");
+        assert_eq!(res.body().await.unwrap(), b"unresolved".to_vec());
+    }
+
+    // Creates a handler for every request, named by the path
+    struct PerRequestHandler {}
+    #[async_trait]
+    impl DynamicRhodHandlerOwned<Comm> for PerRequestHandler {
+        async fn get_handler(
+            &self,
+            _conn: &RhodConnInfo,
+            req: &RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<Arc<dyn RhodHandler<Comm>>> {
+            let name = match req.uri().path() {
+                "/x" => "x",
+                _ => "y",
+            };
+            Ok(Arc::new(TraceHandler { name }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_owned_dynamic_handler() {
+        let stack = RhodStack::new(
+            vec![
+                trace("a"),
+                RhodHandlerInStack::OwnedDynamicRhodHandler(Box::new(PerRequestHandler {})),
+            ],
+            Box::new(TraceService {}),
+        );
+
+        let (req_trace, res_trace) = run(&stack, "/x").await;
+        assert_eq!(req_trace, "a,x");
+        assert_eq!(res_trace, "x,a");
+
+        let (req_trace, res_trace) = run(&stack, "/").await;
+        assert_eq!(req_trace, "a,y");
+        assert_eq!(res_trace, "y,a");
+    }
+
+    #[tokio::test]
+    async fn test_early_response() {
+        let stack = RhodStack::new(
+            vec![
+                trace("a"),
+                trace("b"),
+                RhodHandlerInStack::RhodHandler(Box::new(EarlyHandler {})),
+                trace("c"),
+            ],
+            Box::new(TraceService {}),
+        );
+
+        let (body, res_trace) = run(&stack, "/").await;
+        assert_eq!(body, "early");
+        assert_eq!(res_trace, "b,a");
+    }
+
+    #[tokio::test]
+    async fn test_stack_timing() {
+        let stack = RhodStack::new(vec![trace("a"), trace("b")], Box::new(TraceService {}))
+            .with_slow_threshold(Duration::from_secs(1));
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder().build().unwrap();
+        let res = stack.execute(&conn, req).await.unwrap();
+
+        let timing: StackTiming = res.context().get().unwrap();
+        let names = |stages: &[(String, Duration)]| -> Vec<String> {
+            stages.iter().map(|(name, _)| name.clone()).collect()
+        };
+        assert_eq!(names(&timing.requests), vec!["a", "b"]);
+        assert_eq!(names(&timing.responses), vec!["b", "a"]);
+        assert!(timing.service.is_some());
+        assert!(timing.total() >= timing.service.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        let state = Arc::new(StateMap::new());
+        state.insert(String::from("shared"));
+        let stack = RhodStack::new(vec![trace("a")], Box::new(TraceService {}))
+            .with_state(Arc::clone(&state));
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+
+        for _ in 0..2 {
+            let req = RhodRequest::builder().build().unwrap();
+            let res = stack.execute(&conn, req).await.unwrap();
+            let injected: Arc<StateMap> = res.context().get().unwrap();
+            assert!(Arc::ptr_eq(&injected, &state));
+            assert_eq!(*injected.get::<String>().unwrap(), "shared");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_passthrough() {
+        let stack =
+            RhodStack::new(vec![trace("a")], Box::new(TraceService {})).with_body_passthrough();
+        assert!(stack.body_passthrough());
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder().build().unwrap();
+        let res = stack.execute(&conn, req).await.unwrap();
+        assert!(res.context().get::<BodyPassthrough>().is_some());
+
+        // a group with a handler needing the bodies
+        let group = RhodLayerGroup::new(
+            "rewrite",
+            vec![
+                trace("b"),
+                RhodHandlerInStack::RhodHandler(Box::new(BodyRewriteHandler::new())),
+            ],
+        );
+        let stack = RhodStack::new(
+            vec![trace("a"), RhodHandlerInStack::Group(group)],
+            Box::new(TraceService {}),
+        )
+        .with_body_passthrough();
+        assert!(!stack.body_passthrough());
+    }
 }
@@ -0,0 +1,107 @@
+use dashmap::DashMap;
+use std::any::{Any, TypeId};
+use std::fmt;
+use std::sync::Arc;
+
+type AnyValue = Arc<dyn Any + Send + Sync>;
+
+// Typed map (one value per type) shared by every request served by a stack.
+// It is the place for state living across requests (counters, caches, circuit breakers), instead of statics.
+// Values are shared, so they should use interior mutability (atomics, mutexes) to be updated.
+// The stack injects its StateMap into the context of every request: req.context().get::<Arc<StateMap>>()
+#[derive(Default)]
+pub struct StateMap {
+    map: DashMap<TypeId, AnyValue>,
+}
+
+impl StateMap {
+    pub fn new() -> StateMap {
+        StateMap::default()
+    }
+
+    // Inserts a value, returning the previous value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<Arc<T>> {
+        self.map
+            .insert(TypeId::of::<T>(), Arc::new(value))
+            .and_then(|prev| prev.downcast::<T>().ok())
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|v| Arc::clone(v.value()).downcast::<T>().ok())
+    }
+
+    // Returns the value of type T, inserting the one returned by f if there is none.
+    // f is called at most once even if many requests get the value at the same time
+    pub fn get_or_insert_with<T: Send + Sync + 'static>(&self, f: impl FnOnce() -> T) -> Arc<T> {
+        let value = self
+            .map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Arc::new(f()));
+        Arc::clone(value.value())
+            .downcast::<T>()
+            .unwrap_or_else(|_| unreachable!("values are stored by their type"))
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|(_, prev)| prev.downcast::<T>().ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for StateMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StateMap {{ {} values }}", self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Hits(AtomicUsize);
+
+    #[test]
+    fn test_state_map() {
+        let state = Arc::new(StateMap::new());
+        assert!(state.is_empty());
+        assert!(state.insert(String::from("config")).is_none());
+        assert_eq!(*state.get::<String>().unwrap(), "config");
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let state = Arc::clone(&state);
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let hits = state.get_or_insert_with(Hits::default);
+                        hits.0.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(state.get::<Hits>().unwrap().0.load(Ordering::Relaxed), 400);
+
+        assert_eq!(*state.remove::<String>().unwrap(), "config");
+        assert!(!state.contains::<String>());
+        assert_eq!(state.len(), 1);
+    }
+}
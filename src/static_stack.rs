@@ -0,0 +1,358 @@
+// Stacks composed at compile time, for latency sensitive deployments. The handlers are a Chain of
+// statically typed handlers ending with End, so calls to them arent dynamically dispatched and the
+// executor keeps no lists per request (the futures of the handlers are still boxed by async_trait).
+// rhod_stack! builds them:
+//
+//  let stack = rhod_stack![AccessLogHandler::new(sink), RequestIdHandler::new() => MyService::new()];
+//  let res = stack.execute::<MyComm>(&conn, req).await;
+//
+// The flow is the one of a RhodStack (see lib.rs), without groups, dynamic handlers nor the options of
+// RhodStack (timing, limits, error pages...). A StaticStack is also a RhodService, so Rhodium serves it
+// as the service of a stack without handlers:
+//
+//  Rhodium::new(RhodStack::new(vec![], Box::new(stack)), addr, protocol)
+use crate::errors::{RhodError, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::{RhodHandler, RhodService};
+use crate::{CommunicationChannel, RhodConnInfo};
+use async_trait::async_trait;
+use std::future::Future;
+
+// Result of running a chain
+pub enum ChainResult {
+    Response(RhodResponse),
+    Failed(RhodResponse, RhodError), // a handler failed handling the response, the outer ones catch it
+    Error(RhodError),                // ends the flow without response
+}
+
+// Handlers of a StaticStack, implemented by Chain and End
+pub trait HandlerChain<C>: Send + Sync {
+    // Runs the request through the handlers and the service, and the response back
+    fn run<'a, S: RhodService<C>>(
+        &'a self,
+        conn: &'a RhodConnInfo,
+        req: RhodRequest,
+        comm: &'a mut C,
+        service: &'a S,
+    ) -> impl Future<Output = ChainResult> + Send + 'a;
+
+    fn catch_request<'a>(
+        &'a self,
+        conn: &'a RhodConnInfo,
+        req: &'a RhodRequest,
+        err: &'a RhodError,
+        comm: &'a C,
+    ) -> impl Future<Output = ()> + Send + 'a;
+}
+
+// A handler followed by the rest of the chain
+pub struct Chain<H, T> {
+    handler: H,
+    next: T,
+}
+
+impl<H, T> Chain<H, T> {
+    pub fn new(handler: H, next: T) -> Chain<H, T> {
+        Chain { handler, next }
+    }
+}
+
+// End of a chain, calls the service
+#[derive(Debug, Clone, Copy, Default)]
+pub struct End;
+
+impl<C, H, T> HandlerChain<C> for Chain<H, T>
+where
+    C: Send + Sync,
+    H: RhodHandler<C>,
+    T: HandlerChain<C>,
+{
+    async fn run<'a, S: RhodService<C>>(
+        &'a self,
+        conn: &'a RhodConnInfo,
+        mut req: RhodRequest,
+        comm: &'a mut C,
+        service: &'a S,
+    ) -> ChainResult {
+        if let Err(mut e) = self.handler.handle_request(conn, &mut req, comm).await {
+            debug!("{} failed handling the request", self.handler.name());
+            e.log();
+            self.next.catch_request(conn, &req, &e, comm).await;
+            // answers through the previous handlers, the failing one doesnt handle the response
+            return match e.take_response() {
+                Some(mut res) => {
+                    res.attach_context(req.context());
+                    ChainResult::Response(res)
+                }
+                None => ChainResult::Error(e),
+            };
+        }
+        match self.next.run(conn, req, comm, service).await {
+            ChainResult::Response(res) => {
+                match self.handler.handle_response(conn, res, comm).await {
+                    (res, Ok(())) => ChainResult::Response(res),
+                    (res, Err(e)) => {
+                        debug!("{} failed handling the response", self.handler.name());
+                        e.log();
+                        ChainResult::Failed(res, e)
+                    }
+                }
+            }
+            ChainResult::Failed(res, e) => {
+                self.handler.catch_response(conn, &res, &e, comm).await;
+                ChainResult::Failed(res, e)
+            }
+            ChainResult::Error(e) => ChainResult::Error(e),
+        }
+    }
+
+    async fn catch_request<'a>(
+        &'a self,
+        conn: &'a RhodConnInfo,
+        req: &'a RhodRequest,
+        err: &'a RhodError,
+        comm: &'a C,
+    ) {
+        self.handler.catch_request(conn, req, err, comm).await;
+        self.next.catch_request(conn, req, err, comm).await;
+    }
+}
+
+impl<C: Send + Sync> HandlerChain<C> for End {
+    async fn run<'a, S: RhodService<C>>(
+        &'a self,
+        conn: &'a RhodConnInfo,
+        req: RhodRequest,
+        comm: &'a mut C,
+        service: &'a S,
+    ) -> ChainResult {
+        let context = req.context().clone();
+        let mut res = match service.serve(conn, req, comm).await {
+            Ok(res) => res,
+            Err(mut e) => {
+                debug!("{} failed serving the request", service.name());
+                e.log();
+                match e.take_response() {
+                    Some(res) => res,
+                    None => return ChainResult::Error(e),
+                }
+            }
+        };
+        res.attach_context(&context);
+        ChainResult::Response(res)
+    }
+
+    async fn catch_request<'a>(
+        &'a self,
+        _conn: &'a RhodConnInfo,
+        _req: &'a RhodRequest,
+        _err: &'a RhodError,
+        _comm: &'a C,
+    ) {
+    }
+}
+
+// Builds a StaticStack: rhod_stack![handler1, handler2 => service], or rhod_stack![service]
+#[macro_export]
+macro_rules! rhod_stack {
+    (@chain $handler:expr) => {
+        $crate::static_stack::Chain::new($handler, $crate::static_stack::End)
+    };
+    (@chain $handler:expr, $($rest:expr),+) => {
+        $crate::static_stack::Chain::new($handler, $crate::rhod_stack!(@chain $($rest),+))
+    };
+    ($($handler:expr),+ => $service:expr $(,)?) => {
+        $crate::static_stack::StaticStack::new($crate::rhod_stack!(@chain $($handler),+), $service)
+    };
+    ($service:expr $(,)?) => {
+        $crate::static_stack::StaticStack::new($crate::static_stack::End, $service)
+    };
+}
+
+pub struct StaticStack<H, S> {
+    handlers: H,
+    service: S,
+}
+
+impl<H, S> StaticStack<H, S> {
+    pub fn new(handlers: H, service: S) -> StaticStack<H, S> {
+        StaticStack { handlers, service }
+    }
+
+    // Runs the request with the channel of the caller
+    async fn run<C>(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<RhodResponse>
+    where
+        C: Send + Sync,
+        H: HandlerChain<C>,
+        S: RhodService<C>,
+    {
+        let context = req.context().clone();
+        match self.handlers.run(conn, req, comm, &self.service).await {
+            ChainResult::Response(res) => Ok(res),
+            ChainResult::Failed(_, mut e) => match e.take_response() {
+                Some(mut res) => {
+                    res.attach_context(&context);
+                    Ok(res)
+                }
+                None => Err(e),
+            },
+            ChainResult::Error(e) => Err(e),
+        }
+    }
+
+    pub async fn execute<C>(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+    ) -> RhodResult<RhodResponse>
+    where
+        C: CommunicationChannel,
+        H: HandlerChain<C>,
+        S: RhodService<C>,
+    {
+        match C::try_new(conn, &req).await {
+            Ok(mut communication) => self.run(conn, req, &mut communication).await,
+            Err(mut e) => {
+                debug!("Communication channel couldnt be created");
+                e.log();
+                self.handlers.catch_request(conn, &req, &e, &C::new()).await;
+                match e.take_response() {
+                    Some(mut res) => {
+                        res.attach_context(req.context());
+                        Ok(res)
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C, H, S> RhodService<C> for StaticStack<H, S>
+where
+    C: Send + Sync,
+    H: HandlerChain<C>,
+    S: RhodService<C>,
+{
+    async fn serve(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        self.run(conn, req, comm).await
+    }
+
+    fn name(&self) -> &str {
+        "StaticStack"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    struct Comm {}
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm {}
+        }
+    }
+
+    // Appends its name to the x-trace header of the request and the response, answering early when
+    // the request asks for it
+    struct Trace(&'static str);
+    #[async_trait]
+    impl RhodHandler<Comm> for Trace {
+        async fn handle_request(
+            &self,
+            _conn: &RhodConnInfo,
+            req: &mut RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<()> {
+            if req.uri().path() == format!("/{}", self.0) {
+                let res = RhodResponse::builder().body_str("early").build()?;
+                return Err(RhodError::from_response(res));
+            }
+            req.headers_mut().append("x-trace", self.0.parse().unwrap());
+            Ok(())
+        }
+        async fn catch_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &RhodRequest,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+        async fn handle_response(
+            &self,
+            _conn: &RhodConnInfo,
+            mut res: RhodResponse,
+            _comm: &mut Comm,
+        ) -> (RhodResponse, RhodResult<()>) {
+            res.headers_mut().append("x-trace", self.0.parse().unwrap());
+            (res, Ok(()))
+        }
+        async fn catch_response(
+            &self,
+            _conn: &RhodConnInfo,
+            _res: &RhodResponse,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+    }
+
+    // Returns the request trace as the response body
+    struct TraceService;
+    #[async_trait]
+    impl RhodService<Comm> for TraceService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            let trace: Vec<&str> = req
+                .headers()
+                .get_all("x-trace")
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect();
+            RhodResponse::builder().body_str(&trace.join(",")).build()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_stack() {
+        let stack = rhod_stack![Trace("a"), Trace("b"), Trace("c") => TraceService];
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        for (path, body, trace) in [("/", "a,b,c", "c,b,a"), ("/b", "early", "a")] {
+            let req = RhodRequest::builder().uri(path).build().unwrap();
+            let mut res = stack.execute::<Comm>(&conn, req).await.unwrap();
+            let res_trace: Vec<&str> = res
+                .headers()
+                .get_all("x-trace")
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect();
+            assert_eq!(res_trace.join(","), trace);
+            assert_eq!(res.body().await.unwrap(), body.as_bytes());
+        }
+
+        // served as the service of a stack
+        let stack = crate::stack::RhodStack::new(vec![], Box::new(rhod_stack![TraceService]));
+        let req = RhodRequest::builder().build().unwrap();
+        let mut res = stack.execute(&conn, req).await.unwrap();
+        assert_eq!(res.body().await.unwrap(), b"");
+    }
+}
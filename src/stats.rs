@@ -0,0 +1,178 @@
+// Connection, TLS handshake and request counters of a running server (see Rhodium::stats)
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Upper bounds (milliseconds) of the handshake duration buckets. The last bucket counts the slower ones
+pub const HANDSHAKE_BUCKETS_MS: [u64; 9] = [1, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    connections: AtomicU64,
+    open_connections: AtomicU64,
+    requests: AtomicU64,
+    in_flight: AtomicU64,
+    accept_errors: AtomicU64,
+    tls: TlsStats,
+}
+
+#[derive(Debug, Default)]
+struct TlsStats {
+    handshakes: AtomicU64,
+    in_progress: AtomicU64,
+    timed_out: AtomicU64,
+    protocol_errors: AtomicU64,
+    io_errors: AtomicU64,
+    durations: [AtomicU64; HANDSHAKE_BUCKETS_MS.len() + 1],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct StatsSnapshot {
+    pub connections: u64, // accepted (and handshaked on HTTPS) since the server started
+    pub open_connections: u64,
+    pub requests: u64, // received since the server started
+    pub in_flight: u64,
+    pub accept_errors: u64, // failed accepts, usually too many open files
+    pub tls: TlsSnapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct TlsSnapshot {
+    pub handshakes: u64, // completed
+    pub in_progress: u64,
+    pub timed_out: u64,
+    pub protocol_errors: u64, // bad ClientHello, no shared cipher suite, plain HTTP...
+    pub io_errors: u64,       // connection closed or reset during the handshake
+    pub durations: [u64; HANDSHAKE_BUCKETS_MS.len() + 1], // completed handshakes by HANDSHAKE_BUCKETS_MS
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats::default()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            tls: TlsSnapshot {
+                handshakes: self.tls.handshakes.load(Ordering::Relaxed),
+                in_progress: self.tls.in_progress.load(Ordering::Relaxed),
+                timed_out: self.tls.timed_out.load(Ordering::Relaxed),
+                protocol_errors: self.tls.protocol_errors.load(Ordering::Relaxed),
+                io_errors: self.tls.io_errors.load(Ordering::Relaxed),
+                durations: std::array::from_fn(|i| self.tls.durations[i].load(Ordering::Relaxed)),
+            },
+        }
+    }
+
+    pub(crate) fn accept_error(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_started(&self) {
+        self.tls.in_progress.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Counts a finished handshake by its result
+    pub(crate) fn handshake_finished<T>(&self, elapsed: Duration, result: &io::Result<T>) {
+        self.tls.in_progress.fetch_sub(1, Ordering::Relaxed);
+        let counter = match result {
+            Ok(_) => {
+                let ms = elapsed.as_millis() as u64;
+                let bucket = HANDSHAKE_BUCKETS_MS
+                    .iter()
+                    .position(|bound| ms < *bound)
+                    .unwrap_or(HANDSHAKE_BUCKETS_MS.len());
+                self.tls.durations[bucket].fetch_add(1, Ordering::Relaxed);
+                &self.tls.handshakes
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => &self.tls.timed_out,
+            // rustls reports TLS alerts and malformed messages as invalid data
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => &self.tls.protocol_errors,
+            Err(_) => &self.tls.io_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Counts an accepted connection, open until the guard is dropped
+    pub(crate) fn connection(self: &Arc<Self>) -> StatsGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        StatsGuard {
+            stats: Arc::clone(self),
+            request: false,
+        }
+    }
+
+    // Counts a request, in flight until the guard is dropped
+    pub(crate) fn request(self: &Arc<Self>) -> StatsGuard {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        StatsGuard {
+            stats: Arc::clone(self),
+            request: true,
+        }
+    }
+}
+
+pub(crate) struct StatsGuard {
+    stats: Arc<ServerStats>,
+    request: bool,
+}
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        let open = match self.request {
+            true => &self.stats.in_flight,
+            false => &self.stats.open_connections,
+        };
+        open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = Arc::new(ServerStats::new());
+        let conn = stats.connection();
+        let first = stats.request();
+        drop(stats.request());
+        stats.handshake_started();
+        stats.handshake_finished(Duration::from_millis(7), &Ok(()));
+        stats.handshake_started();
+        let refused = io::Error::new(io::ErrorKind::InvalidData, "bad ClientHello");
+        stats.handshake_finished::<()>(Duration::from_millis(1), &Err(refused));
+        let mut durations = [0; 10];
+        durations[2] = 1;
+        assert_eq!(
+            stats.snapshot(),
+            StatsSnapshot {
+                connections: 1,
+                open_connections: 1,
+                requests: 2,
+                in_flight: 1,
+                accept_errors: 0,
+                tls: TlsSnapshot {
+                    handshakes: 1,
+                    protocol_errors: 1,
+                    durations,
+                    ..TlsSnapshot::default()
+                },
+            }
+        );
+
+        drop(first);
+        drop(conn);
+        assert_eq!(stats.snapshot().open_connections, 0);
+        assert_eq!(stats.snapshot().in_flight, 0);
+    }
+}
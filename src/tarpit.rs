@@ -0,0 +1,149 @@
+// Tarpit for the clients blocked by security handlers (WafAction::Tarpit, BotAction::Tarpit). Instead
+// of a 403 telling a scanner it was caught, it gets a 200:
+// - drip: the body trickles one byte every interval, holding the scanner until max_duration
+// - decoy: a fake page, answered right away
+// Dripping holds a connection and a timer per client, so it is capped: beyond max_active tarpitted
+// responses at once, clients get the 403 of the handler instead, and every response ends after
+// max_duration. A Tarpit can be cloned to share the cap between handlers.
+use crate::body::{BoxError, RhodBody};
+use crate::errors::RhodResult;
+use crate::response::RhodResponse;
+use crate::runtime::{self, Sleep};
+use bytes::Bytes;
+use http_body::{Body, Frame};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Tarpit {
+    decoy: Option<Bytes>, // page answered instead of dripping
+    interval: Duration,
+    max_duration: Duration,
+    max_active: usize,
+    active: Arc<AtomicUsize>, // dripping responses
+}
+
+impl Tarpit {
+    pub fn drip() -> Tarpit {
+        Tarpit {
+            decoy: None,
+            interval: Duration::from_secs(1),
+            max_duration: Duration::from_secs(60),
+            max_active: 64,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn decoy(page: &str) -> Tarpit {
+        Tarpit {
+            decoy: Some(Bytes::copy_from_slice(page.as_bytes())),
+            ..Tarpit::drip()
+        }
+    }
+
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Tarpit { interval, ..self }
+    }
+
+    pub fn with_max_duration(self, max_duration: Duration) -> Self {
+        Tarpit {
+            max_duration,
+            ..self
+        }
+    }
+
+    pub fn with_max_active(self, max_active: usize) -> Self {
+        Tarpit { max_active, ..self }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Response for a blocked client, None if there are too many dripping already
+    pub(crate) fn response(&self) -> RhodResult<Option<RhodResponse>> {
+        let builder = RhodResponse::builder().header("content-type", "text/html; charset=utf-8");
+        if let Some(decoy) = &self.decoy {
+            return builder.body_bytes(decoy).build().map(Some);
+        }
+        let active = self.active.fetch_add(1, Ordering::Relaxed);
+        let guard = Active(Arc::clone(&self.active));
+        if active >= self.max_active {
+            return Ok(None);
+        }
+        let mut res = builder.build()?;
+        res.set_body(RhodBody::new(DripBody {
+            timer: runtime::sleep(self.interval),
+            interval: self.interval,
+            deadline: Instant::now() + self.max_duration,
+            _active: guard,
+        }));
+        Ok(Some(res))
+    }
+}
+
+// Counts a dripping response while alive
+struct Active(Arc<AtomicUsize>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct DripBody {
+    timer: Sleep,
+    interval: Duration,
+    deadline: Instant,
+    _active: Active,
+}
+
+impl Body for DripBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(None);
+        }
+        ready!(self.timer.as_mut().poll(cx));
+        self.timer = runtime::sleep(self.interval);
+        Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b" ")))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_tarpit() {
+        let tarpit = Tarpit::drip()
+            .with_interval(Duration::from_millis(10))
+            .with_max_duration(Duration::from_millis(55))
+            .with_max_active(1);
+        let mut res = tarpit.response().unwrap().unwrap();
+        assert_eq!(tarpit.active(), 1);
+        // capped
+        assert!(tarpit.response().unwrap().is_none());
+        assert_eq!(tarpit.active(), 1);
+
+        let started = Instant::now();
+        let body = res.take_body().collect().await.unwrap().to_bytes();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(!body.is_empty() && body.len() <= 6);
+        drop(res);
+        assert_eq!(tarpit.active(), 0);
+
+        let decoy = Tarpit::decoy("<html>admin</html>");
+        let mut res = decoy.response().unwrap().unwrap();
+        assert_eq!(res.body().await.unwrap(), b"<html>admin</html>");
+    }
+}
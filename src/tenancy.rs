@@ -0,0 +1,400 @@
+// Stacks per tenant. A MultiTenantStack resolves the tenant of every request (from a header such as an
+// API key, the subdomain or the first path segment), looks up its TenantConfig in a TenantStore and runs
+// the request through a stack built for that tenant: its handlers, its limits and a service built from
+// its upstreams. It is the service of the outer stack, so handlers shared by every tenant go there:
+//
+//  let tenants = MultiTenantStack::new(TenantSource::Subdomain("api.example.com".into()), store, factory);
+//  Rhodium::new(RhodStack::new(vec![access_log], Box::new(tenants)), addr, protocol)
+//
+// The tenant is saved in the request context as TenantId (the access log writes it), and requests are
+// counted by tenant (see MultiTenantStack::stats).
+// Built stacks are reused until the refresh interval passes, then the config is looked up again and the
+// stack rebuilt only if it changed. While the store fails, the last stack of the tenant keeps serving.
+use crate::admission::AdmissionController;
+use crate::config::HandlerSettings;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::limits::ResponseLimits;
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::{RhodService, RhodStack};
+use crate::{CommunicationChannel, RhodConnInfo};
+use async_trait::async_trait;
+use http::header::HeaderName;
+use http::uri::PathAndQuery;
+use http::{StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Tenant of the request, saved in the request context
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TenantId(pub String);
+
+// Where the tenant of a request is taken from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TenantSource {
+    Header(HeaderName), // ie: X-Api-Key or X-Tenant
+    Subdomain(String), // label under the domain: acme.api.example.com under api.example.com is acme
+    PathPrefix,        // first path segment, removed from the uri: /acme/users is acme with /users
+}
+
+impl TenantSource {
+    // Tenant of the request, removing it from the path when it is taken from there
+    fn resolve(&self, req: &mut RhodRequest) -> Option<String> {
+        match self {
+            TenantSource::Header(name) => req
+                .header_str(name)
+                .map(str::trim)
+                .filter(|tenant| !tenant.is_empty())
+                .map(String::from),
+            TenantSource::Subdomain(domain) => {
+                let host = req.host()?.to_ascii_lowercase();
+                let tenant = host.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+                match tenant.is_empty() || tenant.contains('.') {
+                    true => None,
+                    false => Some(tenant.to_string()),
+                }
+            }
+            TenantSource::PathPrefix => {
+                let path = req.uri().path().trim_start_matches('/');
+                let (tenant, rest) = match path.find('/') {
+                    Some(slash) => (&path[..slash], &path[slash..]),
+                    None => (path, "/"),
+                };
+                if tenant.is_empty() {
+                    return None;
+                }
+                let tenant = tenant.to_string();
+                let path_and_query = match req.uri().query() {
+                    Some(query) => format!("{}?{}", rest, query),
+                    None => rest.to_string(),
+                };
+                let mut parts = req.uri().clone().into_parts();
+                parts.path_and_query = Some(path_and_query.parse::<PathAndQuery>().ok()?);
+                *req.uri_mut() = Uri::from_parts(parts).ok()?;
+                Some(tenant)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantLimits {
+    pub max_concurrent: Option<usize>, // requests served at once, the rest are shed with 503
+    pub max_queue: usize,              // requests waiting for a slot, with max_concurrent
+    pub response_secs: Option<u64>,
+    pub max_response_body: Option<u64>,
+}
+
+// Configuration of a tenant, as kept by a TenantStore
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub handlers: Vec<HandlerSettings>, // enabled built-in handlers, in stack order
+    pub upstreams: Vec<String>,         // given to the TenantServiceFactory
+    pub limits: TenantLimits,
+}
+
+#[async_trait]
+pub trait TenantStore: Send + Sync {
+    // None for unknown tenants
+    async fn get(&self, tenant: &str) -> RhodResult<Option<TenantConfig>>;
+}
+
+#[derive(Default)]
+pub struct MemoryTenantStore {
+    tenants: RwLock<HashMap<String, TenantConfig>>,
+}
+
+impl MemoryTenantStore {
+    pub fn new() -> MemoryTenantStore {
+        MemoryTenantStore::default()
+    }
+
+    pub fn insert(&self, tenant: &str, config: TenantConfig) {
+        self.tenants
+            .write()
+            .unwrap()
+            .insert(tenant.to_string(), config);
+    }
+
+    pub fn remove(&self, tenant: &str) {
+        self.tenants.write().unwrap().remove(tenant);
+    }
+}
+
+#[async_trait]
+impl TenantStore for MemoryTenantStore {
+    async fn get(&self, tenant: &str) -> RhodResult<Option<TenantConfig>> {
+        Ok(self.tenants.read().unwrap().get(tenant).cloned())
+    }
+}
+
+// Builds the service of a tenant stack (ie: a proxy to its upstreams)
+pub type TenantServiceFactory<C> =
+    Box<dyn Fn(&str, &TenantConfig) -> RhodResult<Box<dyn RhodService<C>>> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct TenantStats {
+    pub requests: u64,
+    pub failed: u64, // answered with 5xx or without response
+}
+
+struct TenantStack<C> {
+    config: TenantConfig,
+    stack: Arc<RhodStack<C>>,
+    checked: Instant,
+}
+
+pub struct MultiTenantStack<C> {
+    source: TenantSource,
+    store: Arc<dyn TenantStore>,
+    factory: TenantServiceFactory<C>,
+    refresh: Duration,
+    stacks: Mutex<HashMap<String, TenantStack<C>>>,
+    stats: Mutex<HashMap<String, TenantStats>>,
+}
+
+fn rejected(status: StatusCode, msg: String) -> RhodError {
+    RhodError::with_status(status, msg, RhodErrorLevel::Warning)
+}
+
+impl<C: CommunicationChannel> MultiTenantStack<C> {
+    pub fn new(
+        source: TenantSource,
+        store: Arc<dyn TenantStore>,
+        factory: TenantServiceFactory<C>,
+    ) -> MultiTenantStack<C> {
+        MultiTenantStack {
+            source,
+            store,
+            factory,
+            refresh: Duration::from_secs(30),
+            stacks: Mutex::new(HashMap::new()),
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // How long a built stack is used before looking up the config of its tenant again
+    pub fn with_refresh(self, refresh: Duration) -> Self {
+        MultiTenantStack { refresh, ..self }
+    }
+
+    // Drops the stack of the tenant, so its config is looked up on the next request
+    pub fn invalidate(&self, tenant: &str) {
+        self.stacks.lock().unwrap().remove(tenant);
+    }
+
+    pub fn stats(&self) -> HashMap<String, TenantStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn build(&self, tenant: &str, config: &TenantConfig) -> RhodResult<RhodStack<C>> {
+        let handlers = config
+            .handlers
+            .iter()
+            .map(HandlerSettings::handler)
+            .collect();
+        let service = (self.factory)(tenant, config)?;
+        let mut limits = ResponseLimits::new();
+        if let Some(secs) = config.limits.response_secs {
+            limits = limits.with_timeout(Duration::from_secs(secs));
+        }
+        if let Some(size) = config.limits.max_response_body {
+            limits = limits.with_max_body_size(size);
+        }
+        let mut stack = RhodStack::new(handlers, service).with_response_limits(limits);
+        if let Some(max_concurrent) = config.limits.max_concurrent {
+            stack = stack.with_admission(Arc::new(AdmissionController::new(
+                max_concurrent,
+                config.limits.max_queue,
+            )));
+        }
+        Ok(stack)
+    }
+
+    fn cached(&self, tenant: &str) -> Option<(Arc<RhodStack<C>>, bool)> {
+        let stacks = self.stacks.lock().unwrap();
+        let cached = stacks.get(tenant)?;
+        Some((
+            Arc::clone(&cached.stack),
+            cached.checked.elapsed() < self.refresh,
+        ))
+    }
+
+    async fn stack(&self, tenant: &str) -> RhodResult<Arc<RhodStack<C>>> {
+        let stale = match self.cached(tenant) {
+            Some((stack, true)) => return Ok(stack),
+            Some((stack, false)) => Some(stack),
+            None => None,
+        };
+        let config = match self.store.get(tenant).await {
+            Ok(Some(config)) => config,
+            Ok(None) => {
+                self.invalidate(tenant);
+                return Err(rejected(
+                    StatusCode::NOT_FOUND,
+                    format!("Unknown tenant {}", tenant),
+                ));
+            }
+            Err(e) => match stale {
+                Some(stack) => {
+                    warn!(
+                        "Tenant {} config couldnt be looked up, keeping its stack",
+                        tenant
+                    );
+                    e.log();
+                    return Ok(stack);
+                }
+                None => return Err(e),
+            },
+        };
+        let mut stacks = self.stacks.lock().unwrap();
+        if let Some(cached) = stacks.get_mut(tenant) {
+            if cached.config == config {
+                cached.checked = Instant::now();
+                return Ok(Arc::clone(&cached.stack));
+            }
+        }
+        let stack = match self.build(tenant, &config) {
+            Ok(stack) => Arc::new(stack),
+            Err(e) => {
+                error!("Tenant {} stack couldnt be built", tenant);
+                return Err(e);
+            }
+        };
+        debug!("Tenant {} stack built: {}", tenant, stack.describe());
+        stacks.insert(
+            tenant.to_string(),
+            TenantStack {
+                config,
+                stack: Arc::clone(&stack),
+                checked: Instant::now(),
+            },
+        );
+        Ok(stack)
+    }
+
+    fn count(&self, tenant: &str, failed: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(tenant.to_string()).or_default();
+        stats.requests += 1;
+        if failed {
+            stats.failed += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl<C: CommunicationChannel> RhodService<C> for MultiTenantStack<C> {
+    async fn serve(
+        &self,
+        conn: &RhodConnInfo,
+        mut req: RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        let tenant = match self.source.resolve(&mut req) {
+            Some(tenant) => tenant,
+            None => {
+                return Err(rejected(
+                    StatusCode::BAD_REQUEST,
+                    "Request without tenant".to_string(),
+                ))
+            }
+        };
+        req.context().insert(TenantId(tenant.clone()));
+        // unknown tenants arent counted, the stats only grow with the tenants of the store
+        let stack = self.stack(&tenant).await?;
+        let result = stack.execute(conn, req).await;
+        let failed = match &result {
+            Ok(res) => res.status_as_int() >= 500,
+            Err(e) => e.response().is_none(),
+        };
+        if failed {
+            debug!("Tenant {} request failed", tenant);
+        }
+        self.count(&tenant, failed);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    struct Comm;
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm
+        }
+    }
+
+    // Answers with the upstream of the tenant and the path it got
+    struct Upstream(String);
+    #[async_trait]
+    impl RhodService<Comm> for Upstream {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            req: RhodRequest,
+            _comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            RhodResponse::builder()
+                .body_str(&format!("{} {}", self.0, req.uri().path()))
+                .build()
+        }
+    }
+
+    fn tenant(upstream: &str) -> TenantConfig {
+        TenantConfig {
+            upstreams: vec![upstream.to_string()],
+            ..TenantConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_tenant_stack() {
+        let store = Arc::new(MemoryTenantStore::new());
+        store.insert("acme", tenant("10.0.0.1"));
+        let tenants: MultiTenantStack<Comm> = MultiTenantStack::new(
+            TenantSource::PathPrefix,
+            Arc::clone(&store) as Arc<dyn TenantStore>,
+            Box::new(|_, config| Ok(Box::new(Upstream(config.upstreams[0].clone())))),
+        )
+        .with_refresh(Duration::ZERO);
+        let conn = RhodConnInfo::new("127.0.0.1:80".parse().unwrap(), HttpProtocol::HTTP);
+        let get = |uri: &str| RhodRequest::builder().uri(uri).build().unwrap();
+
+        let req = get("/acme/users?page=2");
+        let context = req.context().clone();
+        let mut res = tenants.serve(&conn, req, &mut Comm).await.unwrap();
+        assert_eq!(res.body().await.unwrap(), b"10.0.0.1 /users");
+        assert_eq!(
+            context.get::<TenantId>(),
+            Some(TenantId("acme".to_string()))
+        );
+
+        // config changes rebuild the stack
+        store.insert("acme", tenant("10.0.0.2"));
+        let mut res = tenants.serve(&conn, get("/acme"), &mut Comm).await.unwrap();
+        assert_eq!(res.body().await.unwrap(), b"10.0.0.2 /");
+
+        let err = tenants
+            .serve(&conn, get("/other/users"), &mut Comm)
+            .await
+            .unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 404);
+        let err = tenants.serve(&conn, get("/"), &mut Comm).await.unwrap_err();
+        assert_eq!(err.response().unwrap().status_as_int(), 400);
+        assert_eq!(
+            tenants.stats().get("acme"),
+            Some(&TenantStats {
+                requests: 2,
+                failed: 0
+            })
+        );
+    }
+}
@@ -0,0 +1,328 @@
+// Fingerprints of the TLS clients, computed from the ClientHello of the handshake and exposed on
+// RhodConnInfo (tls_fingerprint) for classifying clients beyond their User-Agent, ie: in WAF rules or
+// bot detection. ja3 is the MD5 of the JA3 string (version, ciphers, extensions, groups and point
+// formats), and ja4 the JA4 fingerprint, which sorts ciphers and extensions so it doesnt change when
+// clients shuffle them. GREASE values are ignored by both.
+use crate::util;
+use md5::{Digest, Md5};
+use serde::Serialize;
+
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const MAX_HELLO_SIZE: usize = 64 * 1024; // ClientHellos are usually a few KiB, bigger ones arent fingerprinted
+
+const SERVER_NAME: u16 = 0x0000;
+const SUPPORTED_GROUPS: u16 = 0x000a;
+const POINT_FORMATS: u16 = 0x000b;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TlsFingerprint {
+    pub ja3: String,
+    pub ja3_full: String, // string hashed by ja3
+    pub ja4: String,
+}
+
+// ClientHello read so far from the records of a connection
+pub(crate) enum ClientHello {
+    Incomplete,
+    Invalid, // not a ClientHello, or too big
+    Complete(Vec<u8>),
+}
+
+impl ClientHello {
+    // Handshake message of the ClientHello, from the first bytes sent by the client. It may be split in
+    // several records
+    pub(crate) fn read(records: &[u8]) -> ClientHello {
+        let mut message = Vec::new();
+        let mut rest = records;
+        while rest.len() >= 5 {
+            if rest[0] != HANDSHAKE_RECORD {
+                return ClientHello::Invalid;
+            }
+            let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            let fragment = match rest.get(5..5 + len) {
+                Some(fragment) => fragment,
+                None => break,
+            };
+            message.extend_from_slice(fragment);
+            rest = &rest[5 + len..];
+            if message.len() >= 4 {
+                if message[0] != CLIENT_HELLO {
+                    return ClientHello::Invalid;
+                }
+                let total =
+                    4 + u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+                if message.len() >= total {
+                    message.truncate(total);
+                    return ClientHello::Complete(message);
+                }
+            }
+        }
+        if records.len() > MAX_HELLO_SIZE {
+            ClientHello::Invalid
+        } else {
+            ClientHello::Incomplete
+        }
+    }
+}
+
+// Reads the fields of the ClientHello, None if it is truncated
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    // Field prefixed by its length in n bytes
+    fn prefixed(&mut self, n: usize) -> Option<Reader<'a>> {
+        let len = match n {
+            1 => self.u8()? as usize,
+            _ => self.u16()? as usize,
+        };
+        self.take(len).map(Reader)
+    }
+
+    fn u16_list(mut self) -> Vec<u16> {
+        let mut list = vec![];
+        while let Some(value) = self.u16() {
+            list.push(value);
+        }
+        list
+    }
+}
+
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn hex(values: &[u16]) -> Vec<String> {
+    values.iter().map(|v| format!("{:04x}", v)).collect()
+}
+
+// First 12 hex characters of the SHA-256, or zeros for an empty list
+fn truncated_hash(value: &str) -> String {
+    if value.is_empty() {
+        return "000000000000".to_string();
+    }
+    util::sha256_hex(value.as_bytes())[..12].to_string()
+}
+
+fn ja4_version(version: u16) -> &'static str {
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    }
+}
+
+// First and last characters of the first ALPN protocol, in hex if they arent alphanumeric
+fn ja4_alpn(alpn: Option<&[u8]>) -> String {
+    match alpn {
+        Some(alpn) if !alpn.is_empty() => {
+            let (first, last) = (alpn[0], alpn[alpn.len() - 1]);
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                let first = format!("{:02x}", first);
+                let last = format!("{:02x}", last);
+                format!("{}{}", &first[..1], &last[1..])
+            }
+        }
+        _ => "00".to_string(),
+    }
+}
+
+impl TlsFingerprint {
+    // Fingerprint of a ClientHello handshake message, None if it isnt valid
+    pub fn from_client_hello(message: &[u8]) -> Option<TlsFingerprint> {
+        let mut hello = Reader(message);
+        if hello.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        hello.take(3)?;
+        let version = hello.u16()?;
+        hello.take(32)?; // random
+        hello.prefixed(1)?; // session id
+        let ciphers: Vec<u16> = hello
+            .prefixed(2)?
+            .u16_list()
+            .into_iter()
+            .filter(|c| !is_grease(*c))
+            .collect();
+        hello.prefixed(1)?; // compression methods
+
+        let mut extensions = vec![];
+        let mut groups = vec![];
+        let mut point_formats = vec![];
+        let mut signature_algorithms = vec![];
+        let mut alpn = None;
+        let mut versions = vec![];
+        let mut sni = false;
+        if let Some(mut list) = hello.prefixed(2) {
+            while !list.0.is_empty() {
+                let kind = list.u16()?;
+                let mut data = list.prefixed(2)?;
+                if is_grease(kind) {
+                    continue;
+                }
+                extensions.push(kind);
+                match kind {
+                    SERVER_NAME => sni = true,
+                    SUPPORTED_GROUPS => groups = data.prefixed(2)?.u16_list(),
+                    POINT_FORMATS => point_formats = data.prefixed(1)?.0.to_vec(),
+                    SIGNATURE_ALGORITHMS => signature_algorithms = data.prefixed(2)?.u16_list(),
+                    ALPN => alpn = data.prefixed(2)?.prefixed(1).map(|p| p.0),
+                    SUPPORTED_VERSIONS => versions = data.prefixed(1)?.u16_list(),
+                    _ => {}
+                }
+            }
+        }
+        groups.retain(|g| !is_grease(*g));
+        signature_algorithms.retain(|s| !is_grease(*s));
+
+        let ja3_full = format!(
+            "{},{},{},{},{}",
+            version,
+            join(&ciphers, "-"),
+            join(&extensions, "-"),
+            join(&groups, "-"),
+            join(&point_formats, "-")
+        );
+        let ja3 = util::hex(&Md5::digest(ja3_full.as_bytes()));
+
+        let highest = versions
+            .into_iter()
+            .filter(|v| !is_grease(*v))
+            .max()
+            .unwrap_or(version);
+        let mut sorted_ciphers = hex(&ciphers);
+        sorted_ciphers.sort();
+        let mut sorted_extensions = hex(&extensions
+            .iter()
+            .copied()
+            .filter(|e| *e != SERVER_NAME && *e != ALPN)
+            .collect::<Vec<_>>());
+        sorted_extensions.sort();
+        let mut hashed_extensions = sorted_extensions.join(",");
+        if !signature_algorithms.is_empty() {
+            hashed_extensions = format!(
+                "{}_{}",
+                hashed_extensions,
+                hex(&signature_algorithms).join(",")
+            );
+        }
+        let ja4 = format!(
+            "t{}{}{:02}{:02}{}_{}_{}",
+            ja4_version(highest),
+            if sni { "d" } else { "i" },
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            ja4_alpn(alpn),
+            truncated_hash(&sorted_ciphers.join(",")),
+            truncated_hash(&hashed_extensions)
+        );
+
+        Some(TlsFingerprint { ja3, ja3_full, ja4 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ClientHello record with GREASE values, SNI "a.b" and ALPN h2
+    fn client_hello() -> Vec<u8> {
+        let mut extensions = vec![];
+        let mut extension = |kind: u16, data: &[u8]| {
+            extensions.extend_from_slice(&kind.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(data);
+        };
+        extension(0x0a0a, &[]);
+        extension(SERVER_NAME, &[0, 6, 0, 0, 3, b'a', b'.', b'b']);
+        extension(SUPPORTED_GROUPS, &[0, 6, 0x1a, 0x1a, 0, 29, 0, 23]);
+        extension(POINT_FORMATS, &[1, 0]);
+        extension(SIGNATURE_ALGORITHMS, &[0, 4, 0x04, 0x03, 0x08, 0x04]);
+        extension(ALPN, &[0, 3, 2, b'h', b'2']);
+        extension(SUPPORTED_VERSIONS, &[4, 0x03, 0x04, 0x03, 0x03]);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0, 6, 0x2a, 0x2a, 0x13, 0x01, 0xc0, 0x2f]);
+        body.extend_from_slice(&[1, 0]); // compression
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut message = vec![CLIENT_HELLO, 0];
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&body);
+        message
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let message = client_hello();
+
+        // split in two records, read as they arrive
+        let mut records = vec![];
+        for fragment in [&message[..10], &message[10..]] {
+            records.extend_from_slice(&[HANDSHAKE_RECORD, 3, 1]);
+            records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            records.extend_from_slice(fragment);
+        }
+        assert!(matches!(
+            ClientHello::read(&records[..20]),
+            ClientHello::Incomplete
+        ));
+        assert!(matches!(
+            ClientHello::read(b"GET / HTTP/1.1\r\n"),
+            ClientHello::Invalid
+        ));
+        let read = match ClientHello::read(&records) {
+            ClientHello::Complete(read) => read,
+            _ => panic!("ClientHello not read"),
+        };
+        assert_eq!(read, message);
+
+        let fingerprint = TlsFingerprint::from_client_hello(&read).unwrap();
+        assert_eq!(
+            fingerprint.ja3_full,
+            "771,4865-49199,0-10-11-13-16-43,29-23,0"
+        );
+        assert_eq!(fingerprint.ja3.len(), 32);
+        let ja4: Vec<&str> = fingerprint.ja4.split('_').collect();
+        assert_eq!(ja4[0], "t13d0206h2");
+        assert_eq!(ja4[1], truncated_hash("1301,c02f"));
+        assert_eq!(ja4[2], truncated_hash("000a,000b,000d,002b_0403,0804"));
+    }
+}
@@ -0,0 +1,376 @@
+// Adapters between Rhodium and tower:
+//  - TowerService: a tower Service (optionally wrapped by tower Layers) used as the RhodService of a stack.
+//    The layers wrap the service, so they see the request after the handlers.
+//  - RhodServiceAdapter: a RhodService as a tower Service, to be wrapped by tower Layers.
+//  - StackService: a whole RhodStack as a tower Service, to be mounted in tower based servers.
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use futures_util::future::poll_fn;
+use http::Request as HyperRequest;
+use http::Response as HyperResponse;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::body::RhodBody;
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::response::RhodResponse;
+use crate::stack::{RhodService, RhodStack};
+use crate::{CommunicationChannel, RhodConnInfo};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+// Carries the communication channel of the request through tower services.
+// Extensions must be Clone, so the channel is shared and taken once
+struct CommSlot<C>(Arc<Mutex<Option<C>>>);
+
+impl<C> CommSlot<C> {
+    fn new(comm: C) -> CommSlot<C> {
+        CommSlot(Arc::new(Mutex::new(Some(comm))))
+    }
+
+    fn take(&self) -> Option<C> {
+        self.0.lock().ok().and_then(|mut comm| comm.take())
+    }
+}
+
+impl<C> Clone for CommSlot<C> {
+    fn clone(&self) -> CommSlot<C> {
+        CommSlot(Arc::clone(&self.0))
+    }
+}
+
+pub struct TowerService<S> {
+    inner: S,
+}
+
+impl<S> TowerService<S> {
+    pub fn new(inner: S) -> TowerService<S> {
+        TowerService { inner }
+    }
+}
+
+impl<C: CommunicationChannel> TowerService<RhodServiceAdapter<C>> {
+    // Wraps service with a tower layer. The communication channel and connection info
+    // set by the handlers reach the service.
+    pub fn layered<L>(layer: L, service: Box<dyn RhodService<C>>) -> TowerService<L::Service>
+    where
+        L: Layer<RhodServiceAdapter<C>>,
+    {
+        TowerService::new(layer.layer(RhodServiceAdapter::new(service)))
+    }
+}
+
+#[async_trait]
+impl<C, S> RhodService<C> for TowerService<S>
+where
+    C: CommunicationChannel,
+    S: Service<HyperRequest<RhodBody>, Response = HyperResponse<RhodBody>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Error: Display + Send,
+    S::Future: Send,
+{
+    async fn serve(
+        &self,
+        conn: &RhodConnInfo,
+        req: RhodRequest,
+        comm: &mut C,
+    ) -> RhodResult<RhodResponse> {
+        let mut h_req = req.into_hyper_request();
+        h_req.extensions_mut().insert(conn.clone());
+        h_req
+            .extensions_mut()
+            .insert(CommSlot::new(std::mem::replace(comm, C::new())));
+
+        let mut service = self.inner.clone();
+        let result = match poll_fn(|cx| service.poll_ready(cx)).await {
+            Ok(()) => service.call(h_req).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(mut res) => {
+                // the channel is back if the request reached a RhodServiceAdapter
+                let slot = res.extensions_mut().remove::<CommSlot<C>>();
+                if let Some(returned) = slot.and_then(|slot| slot.take()) {
+                    *comm = returned;
+                }
+                Ok(RhodResponse::new(res))
+            }
+            Err(e) => Err(RhodError::from_string(
+                format!("Tower service error. {}", e),
+                RhodErrorLevel::Error,
+            )),
+        }
+    }
+}
+
+pub struct RhodServiceAdapter<C> {
+    service: Arc<dyn RhodService<C>>,
+}
+
+impl<C> RhodServiceAdapter<C> {
+    pub fn new(service: Box<dyn RhodService<C>>) -> RhodServiceAdapter<C> {
+        RhodServiceAdapter {
+            service: Arc::from(service),
+        }
+    }
+}
+
+impl<C> Clone for RhodServiceAdapter<C> {
+    fn clone(&self) -> RhodServiceAdapter<C> {
+        RhodServiceAdapter {
+            service: Arc::clone(&self.service),
+        }
+    }
+}
+
+impl<C: CommunicationChannel> Service<HyperRequest<RhodBody>> for RhodServiceAdapter<C> {
+    type Response = HyperResponse<RhodBody>;
+    type Error = RhodError;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Needs the connection info inserted by TowerService or StackService
+    fn call(&mut self, mut h_req: HyperRequest<RhodBody>) -> Self::Future {
+        let service = Arc::clone(&self.service);
+        Box::pin(async move {
+            let conn = match h_req.extensions_mut().remove::<RhodConnInfo>() {
+                Some(conn) => conn,
+                None => {
+                    return Err(RhodError::from_str(
+                        "Missing connection info in the request",
+                        RhodErrorLevel::Error,
+                    ))
+                }
+            };
+            let slot = h_req.extensions_mut().remove::<CommSlot<C>>();
+            let mut comm = slot.and_then(|slot| slot.take()).unwrap_or_else(C::new);
+
+            let result = service
+                .serve(&conn, RhodRequest::new(h_req), &mut comm)
+                .await;
+            let mut res = match result {
+                Ok(res) => res,
+                Err(mut e) => match e.take_response() {
+                    Some(res) => res,
+                    None => return Err(e),
+                },
+            }
+            .into_hyper_response();
+            res.extensions_mut().insert(CommSlot::new(comm));
+            Ok(res)
+        })
+    }
+}
+
+pub struct StackService<C> {
+    stack: Arc<RhodStack<C>>,
+    conn: RhodConnInfo, // used if the request doesnt carry a RhodConnInfo extension
+}
+
+impl<C> StackService<C> {
+    pub fn new(stack: Arc<RhodStack<C>>, conn: RhodConnInfo) -> StackService<C> {
+        StackService { stack, conn }
+    }
+}
+
+impl<C> Clone for StackService<C> {
+    fn clone(&self) -> StackService<C> {
+        StackService {
+            stack: Arc::clone(&self.stack),
+            conn: self.conn.clone(),
+        }
+    }
+}
+
+impl<C: CommunicationChannel> Service<HyperRequest<RhodBody>> for StackService<C> {
+    type Response = HyperResponse<RhodBody>;
+    type Error = RhodError;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, h_req: HyperRequest<RhodBody>) -> Self::Future {
+        let stack = Arc::clone(&self.stack);
+        let conn = h_req
+            .extensions()
+            .get::<RhodConnInfo>()
+            .cloned()
+            .unwrap_or_else(|| self.conn.clone());
+        Box::pin(async move {
+            let res = stack.execute(&conn, RhodRequest::new(h_req)).await?;
+            Ok(res.into_hyper_response())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::{RhodHandler, RhodHandlerInStack};
+    use http::header::HeaderValue;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    struct Comm {
+        user: Option<String>,
+    }
+    impl CommunicationChannel for Comm {
+        fn new() -> Comm {
+            Comm { user: None }
+        }
+    }
+
+    // Sets the user in the channel, and checks it is there while handling the response
+    struct UserHandler {}
+    #[async_trait]
+    impl RhodHandler<Comm> for UserHandler {
+        async fn handle_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &mut RhodRequest,
+            comm: &mut Comm,
+        ) -> RhodResult<()> {
+            comm.user = Some("alice".to_string());
+            Ok(())
+        }
+        async fn catch_request(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: &RhodRequest,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+        async fn handle_response(
+            &self,
+            _conn: &RhodConnInfo,
+            mut res: RhodResponse,
+            comm: &mut Comm,
+        ) -> (RhodResponse, RhodResult<()>) {
+            let user = comm.user.clone().unwrap_or_default();
+            res.headers_mut()
+                .insert("x-user-back", HeaderValue::from_str(&user).unwrap());
+            (res, Ok(()))
+        }
+        async fn catch_response(
+            &self,
+            _conn: &RhodConnInfo,
+            _res: &RhodResponse,
+            _err: &RhodError,
+            _comm: &Comm,
+        ) {
+        }
+    }
+
+    struct UserService {}
+    #[async_trait]
+    impl RhodService<Comm> for UserService {
+        async fn serve(
+            &self,
+            _conn: &RhodConnInfo,
+            _req: RhodRequest,
+            comm: &mut Comm,
+        ) -> RhodResult<RhodResponse> {
+            RhodResponse::builder()
+                .body_str(comm.user.as_deref().unwrap_or("nobody"))
+                .build()
+        }
+    }
+
+    // tower layer adding a header to the response
+    #[derive(Clone)]
+    struct Tag<S>(S);
+    impl<S> Service<HyperRequest<RhodBody>> for Tag<S>
+    where
+        S: Service<HyperRequest<RhodBody>, Response = HyperResponse<RhodBody>>,
+        S::Future: Send + 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = BoxFuture<Result<S::Response, S::Error>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: HyperRequest<RhodBody>) -> Self::Future {
+            let fut = self.0.call(req);
+            Box::pin(async move {
+                let mut res = fut.await?;
+                res.headers_mut()
+                    .insert("x-tower", HeaderValue::from_static("yes"));
+                Ok(res)
+            })
+        }
+    }
+
+    #[derive(Clone)]
+    struct Hello;
+    impl Service<HyperRequest<RhodBody>> for Hello {
+        type Response = HyperResponse<RhodBody>;
+        type Error = Infallible;
+        type Future = futures_util::future::Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: HyperRequest<RhodBody>) -> Self::Future {
+            futures_util::future::ready(Ok(HyperResponse::new(RhodBody::from("hello"))))
+        }
+    }
+
+    fn conn() -> RhodConnInfo {
+        RhodConnInfo::new(SocketAddr::from(([127, 0, 0, 1], 1)), HttpProtocol::HTTP)
+    }
+
+    #[tokio::test]
+    async fn test_layered_service() {
+        let service = TowerService::layered(
+            tower_layer::layer_fn(Tag),
+            Box::new(UserService {}) as Box<dyn RhodService<Comm>>,
+        );
+        let stack = RhodStack::new(
+            vec![RhodHandlerInStack::RhodHandler(Box::new(UserHandler {}))],
+            Box::new(service),
+        );
+
+        let mut res = stack
+            .execute(&conn(), RhodRequest::builder().build().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.headers().get("x-tower").unwrap(), "yes");
+        assert_eq!(res.headers().get("x-user-back").unwrap(), "alice");
+        assert_eq!(res.body().await.unwrap(), b"alice".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_tower_service() {
+        let stack: RhodStack<Comm> = RhodStack::new(vec![], Box::new(TowerService::new(Hello)));
+
+        // and the stack as a tower service
+        let mut service = StackService::new(Arc::new(stack), conn());
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let res = service
+            .call(HyperRequest::new(RhodBody::empty()))
+            .await
+            .unwrap();
+        let body = res.into_body().to_bytes().await.unwrap();
+        assert_eq!(body, "hello");
+    }
+}
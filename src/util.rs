@@ -0,0 +1,148 @@
+use ring::digest::{digest, SHA256};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::hash::Hash;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often WindowCounters forgets the ended windows
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// Lowercase hex of bytes
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex(digest(&SHA256, data).as_ref())
+}
+
+// Secs since the unix epoch, 0 if the clock is before it
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Counters of fixed windows by key (ie: the requests of a client), each key with its own window.
+// Ended windows are forgotten at most once per SWEEP_INTERVAL, so counting doesnt go through every key
+pub(crate) struct WindowCounters<K> {
+    counters: HashMap<K, (Instant, u32)>, // key -> (window end, count)
+    next_sweep: Instant,
+}
+
+impl<K: Hash + Eq> WindowCounters<K> {
+    pub(crate) fn new() -> WindowCounters<K> {
+        WindowCounters {
+            counters: HashMap::new(),
+            next_sweep: Instant::now() + SWEEP_INTERVAL,
+        }
+    }
+
+    // Counts one for key, returning the count of its current window
+    pub(crate) fn count(&mut self, key: K, window: Duration) -> u32 {
+        let now = Instant::now();
+        if now >= self.next_sweep {
+            self.counters.retain(|_, (end, _)| *end > now);
+            self.next_sweep = now + SWEEP_INTERVAL;
+        }
+        let (end, count) = self.counters.entry(key).or_insert((now + window, 0));
+        if *end <= now {
+            *end = now + window;
+            *count = 0;
+        }
+        *count += 1;
+        *count
+    }
+}
+
+// Values looked up by key (ie: resolutions of a slow backend), evicting the oldest one when full
+pub(crate) struct Lookups<T> {
+    capacity: usize,
+    entries: HashMap<String, (T, Instant)>, // with the lookup time
+    order: VecDeque<(String, Instant)>, // oldest first, the entries looked up again leave their old place
+}
+
+impl<T: Clone> Lookups<T> {
+    pub(crate) fn new(capacity: usize) -> Lookups<T> {
+        Lookups {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // The value looked up less than ttl ago, None keeps them until they are evicted
+    pub(crate) fn get(&self, key: &str, ttl: Option<Duration>) -> Option<T> {
+        self.entries
+            .get(key)
+            .filter(|(_, looked_up)| ttl.is_none_or(|ttl| looked_up.elapsed() < ttl))
+            .map(|(value, _)| value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: String, value: T) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let (oldest, looked_up) = match self.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if self
+                .entries
+                .get(&oldest)
+                .is_some_and(|(_, at)| *at == looked_up)
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        let now = Instant::now();
+        self.entries.insert(key.clone(), (value, now));
+        self.order.push_back((key, now));
+        if self.order.len() > 2 * self.capacity {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, looked_up)| entries.get(key).is_some_and(|(_, at)| at == looked_up));
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&[0x00, 0x0f, 0xab]), "000fab");
+        assert_eq!(
+            sha256_hex(b"test"),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+    }
+
+    #[test]
+    fn test_window_counters() {
+        let mut counters = WindowCounters::new();
+        let (short, long) = (Duration::from_millis(1), Duration::from_secs(60));
+        assert_eq!(counters.count("a", long), 1);
+        assert_eq!(counters.count("b", short), 1);
+        std::thread::sleep(Duration::from_millis(5));
+        // each key ends its own window
+        assert_eq!(counters.count("a", long), 2);
+        assert_eq!(counters.count("b", short), 1);
+        // ended windows are forgotten on the next sweep
+        counters.next_sweep = Instant::now();
+        std::thread::sleep(Duration::from_millis(5));
+        counters.count("a", long);
+        assert_eq!(counters.counters.len(), 1);
+    }
+}
@@ -0,0 +1,8 @@
+// Web application firewall: a handler that evaluates rules against the requests.
+// Every rule inspects some parts of the request (targets), after applying transformations,
+// with an operator. When it matches, its action is executed (block, log, tag, rate limit).
+// Matches are saved in the request context (WafAudit) so later handlers can write an audit log.
+mod handler;
+mod rule;
+pub use handler::{WafAudit, WafHandler, WafMatch, WafTags};
+pub use rule::{WafAction, WafOperator, WafRule, WafTarget, WafTransform};
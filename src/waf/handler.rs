@@ -0,0 +1,435 @@
+use super::rule::{WafAction, WafRule};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::log_sink::LogSink;
+use crate::request::{BodyProcessor, RhodRequest};
+use crate::response::RhodResponse;
+use crate::stack::RhodHandler;
+use crate::tarpit::Tarpit;
+use crate::util::WindowCounters;
+use crate::RhodConnInfo;
+use async_trait::async_trait;
+use http::header::TE;
+use http::{StatusCode, Version};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// A rule that matched a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WafMatch {
+    pub rule_id: u32,
+    pub msg: String,
+    pub target: String,
+    pub value: String, // transformed value that matched (truncated)
+    pub action: String,
+}
+
+// Every match of the request, saved in the request context
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WafAudit {
+    pub matches: Vec<WafMatch>,
+}
+
+// Tags added by the rules with a Tag action, saved in the request context
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WafTags(pub Vec<String>);
+
+// Evaluates the rules in order against every request. The first Block, Tarpit (or exceeded RateLimit) ends the
+// evaluation. In detection only mode, Block, Tarpit and RateLimit actions are only logged.
+pub struct WafHandler {
+    rules: Vec<WafRule>,
+    detection_only: bool,
+    max_body_size: u64, // only the first bytes of larger bodies are inspected
+    rate_limits: Mutex<WindowCounters<(u32, IpAddr)>>, // matches by (rule, client), in the window of the rule
+    audit: Option<AuditLog>,
+    sink: Option<Arc<dyn LogSink>>,
+    tarpit: Tarpit, // for Tarpit actions
+}
+
+impl WafHandler {
+    pub fn new(rules: Vec<WafRule>) -> WafHandler {
+        WafHandler {
+            rules,
+            detection_only: false,
+            max_body_size: 128 * 1024,
+            rate_limits: Mutex::new(WindowCounters::new()),
+            audit: None,
+            sink: None,
+            tarpit: Tarpit::drip(),
+        }
+    }
+
+    pub fn detection_only(self) -> WafHandler {
+        WafHandler {
+            detection_only: true,
+            ..self
+        }
+    }
+
+    pub fn with_max_body_size(self, max_body_size: u64) -> WafHandler {
+        WafHandler {
+            max_body_size,
+            ..self
+        }
+    }
+
+    // Writes every match to the audit log
+    pub fn with_audit(self, audit: AuditLog) -> WafHandler {
+        WafHandler {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    // Writes the matches of every request as a record to the sink
+    pub fn with_log_sink(self, sink: Arc<dyn LogSink>) -> WafHandler {
+        WafHandler {
+            sink: Some(sink),
+            ..self
+        }
+    }
+
+    // Tarpit of the Tarpit actions, dripping with the default settings if not set
+    pub fn with_tarpit(self, tarpit: Tarpit) -> WafHandler {
+        WafHandler { tarpit, ..self }
+    }
+
+    pub fn rules(&self) -> &[WafRule] {
+        &self.rules
+    }
+
+    // Counts a match of the client, returning true if the limit was exceeded
+    fn rate_limited(&self, rule: &WafRule, ip: IpAddr) -> bool {
+        let (max, window) = match &rule.action {
+            WafAction::RateLimit { max, window } => (*max, *window),
+            _ => return false,
+        };
+        let count = self
+            .rate_limits
+            .lock()
+            .unwrap()
+            .count((rule.id, ip), window);
+        count > max
+    }
+
+    // Answers with the tarpit, or 403 when it is full
+    fn tarpitted(&self, rule: &WafRule) -> RhodError {
+        match self.tarpit.response() {
+            Ok(Some(res)) => RhodError::from_string(
+                format!("Request tarpitted by WAF rule {}. {}", rule.id, rule.msg),
+                RhodErrorLevel::Warning,
+            )
+            .with_response(res),
+            Ok(None) => answer(StatusCode::FORBIDDEN, rule),
+            Err(e) => e,
+        }
+    }
+
+    async fn read_body(&self, req: &mut RhodRequest) -> RhodResult<Option<String>> {
+        // gRPC streams would be broken by buffering. Only the requests that can reach a gRPC service
+        // (HTTP/2 with te: trailers) are skipped, the Content-Type alone doesnt bypass the rules
+        let grpc = req.body_processor() == Some(BodyProcessor::GRPC)
+            && req.version() == Version::HTTP_2
+            && req.header_str(TE).is_some_and(|te| te.contains("trailers"));
+        if grpc {
+            return Ok(None);
+        }
+        // with or without Content-Length, the rest of larger bodies is streamed as it is
+        let limit = usize::try_from(self.max_body_size).unwrap_or(usize::MAX);
+        let (body, _) = req.body_prefix(limit).await?;
+        Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+fn answer(status: StatusCode, rule: &WafRule) -> RhodError {
+    RhodError::with_status(
+        status,
+        format!("Request blocked by WAF rule {}. {}", rule.id, rule.msg),
+        RhodErrorLevel::Warning,
+    )
+}
+
+#[async_trait]
+impl<C: Send + Sync> RhodHandler<C> for WafHandler {
+    async fn handle_request(
+        &self,
+        conn: &RhodConnInfo,
+        req: &mut RhodRequest,
+        _comm: &mut C,
+    ) -> RhodResult<()> {
+        let body = if self.rules.iter().any(|r| r.inspects_body()) {
+            self.read_body(req).await?
+        } else {
+            None
+        };
+
+        let mut audit = WafAudit::default();
+        let mut tags = WafTags::default();
+        let mut result = Ok(());
+        for rule in self.rules.iter() {
+            let (target, value) = match rule.evaluate(conn, req, body.as_deref()) {
+                Some(matched) => matched,
+                None => continue,
+            };
+            warn!(
+                "WAF rule {} matched ({}) on {} from {}: {}",
+                rule.id, rule.msg, target, conn.addr, value
+            );
+            let matched = WafMatch {
+                rule_id: rule.id,
+                msg: rule.msg.clone(),
+                target,
+                value,
+                action: rule.action.to_string(),
+            };
+            if let Some(log) = &self.audit {
+                log.emit(
+                    AuditEvent::new("waf_match")
+                        .with_client(&conn.addr.to_string())
+                        .with_detail("rule_id", &matched.rule_id.to_string())
+                        .with_detail("msg", &matched.msg)
+                        .with_detail("target", &matched.target)
+                        .with_detail("value", &matched.value)
+                        .with_detail("action", &matched.action)
+                        .with_detail("request", &req.request_line()),
+                )
+                .await;
+            }
+            audit.matches.push(matched);
+
+            let status = match &rule.action {
+                WafAction::Block | WafAction::Tarpit => StatusCode::FORBIDDEN,
+                WafAction::RateLimit { .. } if self.rate_limited(rule, conn.addr.ip()) => {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+                WafAction::Tag(tag) => {
+                    tags.0.push(tag.clone());
+                    continue;
+                }
+                WafAction::Log | WafAction::RateLimit { .. } => continue,
+            };
+            if !self.detection_only {
+                result = Err(match rule.action {
+                    WafAction::Tarpit => self.tarpitted(rule),
+                    _ => answer(status, rule),
+                });
+                break;
+            }
+        }
+
+        if let Some(sink) = self.sink.as_ref().filter(|_| !audit.matches.is_empty()) {
+            let matches: Vec<_> = audit
+                .matches
+                .iter()
+                .map(|m| {
+                    json!({
+                        "rule_id": m.rule_id,
+                        "msg": m.msg,
+                        "target": m.target,
+                        "value": m.value,
+                        "action": m.action,
+                    })
+                })
+                .collect();
+            let record = json!({
+                "type": "waf",
+                "client": conn.addr.to_string(),
+                "request": req.request_line(),
+                "blocked": result.is_err(),
+                "matches": matches,
+            });
+            if let Err(e) = sink.write(&record).await {
+                warn!("Couldnt write WAF record. {}", e);
+            }
+        }
+        if !audit.matches.is_empty() {
+            req.context().insert(audit);
+        }
+        if !tags.0.is_empty() {
+            req.context().insert(tags);
+        }
+        result
+    }
+
+    async fn catch_request(
+        &self,
+        _conn: &RhodConnInfo,
+        _req: &RhodRequest,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    async fn handle_response(
+        &self,
+        _conn: &RhodConnInfo,
+        res: RhodResponse,
+        _comm: &mut C,
+    ) -> (RhodResponse, RhodResult<()>) {
+        (res, Ok(()))
+    }
+
+    async fn catch_response(
+        &self,
+        _conn: &RhodConnInfo,
+        _res: &RhodResponse,
+        _err: &RhodError,
+        _comm: &C,
+    ) {
+    }
+
+    fn needs_body(&self) -> bool {
+        self.rules.iter().any(|r| r.inspects_body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+    use crate::waf::{WafOperator, WafTarget};
+    use http::Method;
+    use std::time::Duration;
+
+    fn rules() -> Vec<WafRule> {
+        vec![
+            WafRule::new(
+                100,
+                "Admin area",
+                WafTarget::Path,
+                WafOperator::BeginsWith("/admin".to_string()),
+                WafAction::Block,
+            ),
+            WafRule::new(
+                200,
+                "Api client",
+                WafTarget::Header("x-api-client".to_string()),
+                WafOperator::regex(".+").unwrap(),
+                WafAction::Tag("api".to_string()),
+            ),
+            WafRule::new(
+                300,
+                "Login attempt",
+                WafTarget::Path,
+                WafOperator::Equals("/login".to_string()),
+                WafAction::RateLimit {
+                    max: 2,
+                    window: Duration::from_secs(60),
+                },
+            ),
+            WafRule::new(
+                400,
+                "SQL injection",
+                WafTarget::Body,
+                WafOperator::regex("(?i)union\\s+select").unwrap(),
+                WafAction::Block,
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_keep_their_windows() {
+        let login = |id, max, window| {
+            WafRule::new(
+                id,
+                "Login attempt",
+                WafTarget::Path,
+                WafOperator::Equals("/login".to_string()),
+                WafAction::RateLimit { max, window },
+            )
+        };
+        // a rule with a short window doesnt reset the counters of the longer one
+        let waf = WafHandler::new(vec![
+            login(1, 100, Duration::from_millis(1)),
+            login(2, 2, Duration::from_secs(60)),
+        ]);
+        for expected in [true, true, false].iter() {
+            let req = RhodRequest::builder().uri("/login").build().unwrap();
+            let (result, _) = run(&waf, req).await;
+            assert_eq!(result.is_ok(), *expected);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    async fn run(waf: &WafHandler, mut req: RhodRequest) -> (RhodResult<()>, RhodRequest) {
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let result = RhodHandler::<()>::handle_request(waf, &conn, &mut req, &mut ()).await;
+        (result, req)
+    }
+
+    #[tokio::test]
+    async fn test_waf_handler() {
+        let waf = WafHandler::new(rules());
+
+        let req = RhodRequest::builder().uri("/admin/users").build().unwrap();
+        let (result, req) = run(&waf, req).await;
+        let err = result.err().unwrap();
+        assert_eq!(err.response().unwrap().status_as_int(), 403);
+        let audit = req.context().get::<WafAudit>().unwrap();
+        assert_eq!(audit.matches[0].rule_id, 100);
+        assert_eq!(audit.matches[0].action, "block");
+
+        let req = RhodRequest::builder()
+            .header("X-Api-Client", "mobile")
+            .build()
+            .unwrap();
+        let (result, req) = run(&waf, req).await;
+        assert!(result.is_ok());
+        assert_eq!(req.context().get::<WafTags>().unwrap().0, vec!["api"]);
+
+        for expected in [true, true, false].iter() {
+            let req = RhodRequest::builder().uri("/login").build().unwrap();
+            let (result, _) = run(&waf, req).await;
+            assert_eq!(result.is_ok(), *expected);
+        }
+
+        let req = RhodRequest::builder()
+            .method(Method::POST)
+            .header("Content-Length", "21")
+            .body_str("id=1 UNION  SELECT pw")
+            .build()
+            .unwrap();
+        let (result, mut req) = run(&waf, req).await;
+        assert!(result.is_err());
+        // body is still available downstream
+        assert_eq!(req.body().await.unwrap().len(), 21);
+
+        // the Content-Type of gRPC isnt enough to skip the body
+        let req = RhodRequest::builder()
+            .method(Method::POST)
+            .header("content-type", "application/grpc")
+            .body_str("id=1 union select pw")
+            .build()
+            .unwrap();
+        assert!(run(&waf, req).await.0.is_err());
+
+        // bodies without Content-Length are inspected, and the first bytes of larger ones
+        let waf = WafHandler::new(rules()).with_max_body_size(24);
+        for body in [
+            "id=1 union select pw",
+            "id=1 union select pw&pad=0123456789",
+        ]
+        .iter()
+        {
+            let req = RhodRequest::builder()
+                .method(Method::POST)
+                .body_str(body)
+                .build()
+                .unwrap();
+            let (result, mut req) = run(&waf, req).await;
+            assert!(result.is_err());
+            assert_eq!(req.body().await.unwrap(), body.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detection_only() {
+        let waf = WafHandler::new(rules()).detection_only();
+        let req = RhodRequest::builder().uri("/admin").build().unwrap();
+        let (result, req) = run(&waf, req).await;
+        assert!(result.is_ok());
+        assert_eq!(req.context().get::<WafAudit>().unwrap().matches.len(), 1);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::request::RhodRequest;
+use crate::RhodConnInfo;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+
+// Part of the request inspected by a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WafTarget {
+    Method,
+    Path,
+    Query,
+    Uri,
+    Header(String), // every value of the header
+    AnyHeader,      // every value of every header
+    HeaderNames,
+    Body,
+    ClientIp,
+    Ja3, // TLS fingerprints of the connection, nothing on plain HTTP
+    Ja4,
+}
+
+impl fmt::Display for WafTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WafTarget::Method => write!(f, "method"),
+            WafTarget::Path => write!(f, "path"),
+            WafTarget::Query => write!(f, "query"),
+            WafTarget::Uri => write!(f, "uri"),
+            WafTarget::Header(name) => write!(f, "header:{}", name.to_lowercase()),
+            WafTarget::AnyHeader => write!(f, "headers"),
+            WafTarget::HeaderNames => write!(f, "header_names"),
+            WafTarget::Body => write!(f, "body"),
+            WafTarget::ClientIp => write!(f, "client_ip"),
+            WafTarget::Ja3 => write!(f, "ja3"),
+            WafTarget::Ja4 => write!(f, "ja4"),
+        }
+    }
+}
+
+// Transformation applied to the target values before the operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WafTransform {
+    Lowercase,
+    UrlDecode,
+    CompressWhitespace,
+}
+
+impl WafTransform {
+    fn apply<'a>(&self, value: Cow<'a, str>) -> Cow<'a, str> {
+        match self {
+            WafTransform::Lowercase => Cow::Owned(value.to_lowercase()),
+            WafTransform::UrlDecode => Cow::Owned(
+                percent_decode_str(&value.replace('+', " "))
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            ),
+            WafTransform::CompressWhitespace => {
+                Cow::Owned(value.split_whitespace().collect::<Vec<&str>>().join(" "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum WafOperator {
+    Regex(Regex),
+    Contains(String),
+    Equals(String),
+    BeginsWith(String),
+    EndsWith(String),
+    LengthGreaterThan(usize),
+}
+
+impl WafOperator {
+    // Compiles the regex, Err if it is invalid
+    pub fn regex(re: &str) -> RhodResult<WafOperator> {
+        Regex::new(re).map(WafOperator::Regex).map_err(|e| {
+            RhodError::from_string(
+                format!("Invalid WAF regex {:?}. {}", re, e),
+                RhodErrorLevel::Error,
+            )
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            WafOperator::Regex(re) => re.is_match(value),
+            WafOperator::Contains(s) => value.contains(s.as_str()),
+            WafOperator::Equals(s) => value == s,
+            WafOperator::BeginsWith(s) => value.starts_with(s.as_str()),
+            WafOperator::EndsWith(s) => value.ends_with(s.as_str()),
+            WafOperator::LengthGreaterThan(len) => value.len() > *len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WafAction {
+    Block,                                    // answers 403
+    Log,                                      // only logs and audits the match
+    Tag(String),                              // adds a tag to the request (WafTags in the context)
+    RateLimit { max: u32, window: Duration }, // answers 429 after max matches of a client in the window
+    Tarpit, // answers with the tarpit of the handler (WafHandler::with_tarpit)
+}
+
+impl fmt::Display for WafAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WafAction::Block => write!(f, "block"),
+            WafAction::Log => write!(f, "log"),
+            WafAction::Tag(tag) => write!(f, "tag:{}", tag),
+            WafAction::RateLimit { .. } => write!(f, "rate_limit"),
+            WafAction::Tarpit => write!(f, "tarpit"),
+        }
+    }
+}
+
+pub struct WafRule {
+    pub id: u32,
+    pub msg: String,
+    pub(crate) targets: Vec<WafTarget>,
+    pub(crate) transforms: Vec<WafTransform>,
+    pub(crate) operator: WafOperator,
+    pub(crate) negate: bool, // matches when the operator doesnt match
+    pub action: WafAction,
+}
+
+impl WafRule {
+    pub fn new(
+        id: u32,
+        msg: &str,
+        target: WafTarget,
+        operator: WafOperator,
+        action: WafAction,
+    ) -> WafRule {
+        WafRule {
+            id,
+            msg: msg.to_string(),
+            targets: vec![target],
+            transforms: vec![],
+            operator,
+            negate: false,
+            action,
+        }
+    }
+
+    pub fn target(mut self, target: WafTarget) -> WafRule {
+        self.targets.push(target);
+        self
+    }
+
+    pub fn transform(mut self, transform: WafTransform) -> WafRule {
+        self.transforms.push(transform);
+        self
+    }
+
+    pub fn negate(self) -> WafRule {
+        WafRule {
+            negate: true,
+            ..self
+        }
+    }
+
+    pub(crate) fn inspects_body(&self) -> bool {
+        self.targets.contains(&WafTarget::Body)
+    }
+
+    // Returns the first target and value matching the rule
+    pub(crate) fn evaluate(
+        &self,
+        conn: &RhodConnInfo,
+        req: &RhodRequest,
+        body: Option<&str>,
+    ) -> Option<(String, String)> {
+        for target in self.targets.iter() {
+            let values: Vec<Cow<str>> = match target {
+                WafTarget::Method => vec![Cow::Borrowed(req.method_str())],
+                WafTarget::Path => vec![Cow::Borrowed(req.uri().path())],
+                WafTarget::Query => vec![Cow::Borrowed(req.uri().query().unwrap_or(""))],
+                WafTarget::Uri => vec![Cow::Owned(req.uri().to_string())],
+                WafTarget::Header(name) => req
+                    .headers()
+                    .get_all(name.as_str())
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()))
+                    .collect(),
+                WafTarget::AnyHeader => req
+                    .headers()
+                    .values()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()))
+                    .collect(),
+                WafTarget::HeaderNames => req
+                    .headers()
+                    .keys()
+                    .map(|k| Cow::Borrowed(k.as_str()))
+                    .collect(),
+                WafTarget::Body => body.map(Cow::Borrowed).into_iter().collect(),
+                WafTarget::ClientIp => vec![Cow::Owned(conn.addr.ip().to_string())],
+                WafTarget::Ja3 => conn
+                    .tls_fingerprint
+                    .iter()
+                    .map(|f| Cow::Borrowed(f.ja3.as_str()))
+                    .collect(),
+                WafTarget::Ja4 => conn
+                    .tls_fingerprint
+                    .iter()
+                    .map(|f| Cow::Borrowed(f.ja4.as_str()))
+                    .collect(),
+            };
+
+            for value in values {
+                let value = self.transforms.iter().fold(value, |v, t| t.apply(v));
+                if self.operator.matches(&value) != self.negate {
+                    return Some((target.to_string(), value.chars().take(128).collect()));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::HttpProtocol;
+
+    #[test]
+    fn test_evaluate() {
+        let conn = RhodConnInfo::new("10.0.0.1:4000".parse().unwrap(), HttpProtocol::HTTP);
+        let req = RhodRequest::builder()
+            .uri("/search?q=%3Cscript%3Ealert(1)")
+            .header("User-Agent", "sqlmap/1.5")
+            .build()
+            .unwrap();
+
+        let xss = WafRule::new(
+            1,
+            "XSS",
+            WafTarget::Query,
+            WafOperator::regex("<script").unwrap(),
+            WafAction::Block,
+        );
+        assert!(WafOperator::regex("<script(").is_err());
+        assert_eq!(xss.evaluate(&conn, &req, None), None);
+        let xss = xss.transform(WafTransform::UrlDecode);
+        assert_eq!(
+            xss.evaluate(&conn, &req, None),
+            Some(("query".to_string(), "q=<script>alert(1)".to_string()))
+        );
+
+        let scanner = WafRule::new(
+            2,
+            "Scanner",
+            WafTarget::Header("user-agent".to_string()),
+            WafOperator::Contains("sqlmap".to_string()),
+            WafAction::Block,
+        );
+        assert!(scanner.evaluate(&conn, &req, None).is_some());
+
+        let internal = WafRule::new(
+            3,
+            "Not internal",
+            WafTarget::ClientIp,
+            WafOperator::BeginsWith("10.".to_string()),
+            WafAction::Log,
+        )
+        .negate();
+        assert!(internal.evaluate(&conn, &req, None).is_none());
+
+        let body = WafRule::new(
+            4,
+            "Body",
+            WafTarget::Path,
+            WafOperator::Equals("DROP TABLE".to_string()),
+            WafAction::Block,
+        )
+        .target(WafTarget::Body)
+        .transform(WafTransform::CompressWhitespace);
+        assert!(body.inspects_body());
+        assert_eq!(
+            body.evaluate(&conn, &req, Some("DROP    TABLE")),
+            Some(("body".to_string(), "DROP TABLE".to_string()))
+        );
+    }
+}
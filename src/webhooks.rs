@@ -0,0 +1,510 @@
+// Outbound webhooks. Handlers and services enqueue events on a Webhooks handle, and dispatch_job delivers
+// them in the background to the subscribers of their kind, as a POST of the event in JSON signed with the
+// secret of the subscriber. Signatures are the ones verified by SignatureHandler (x-signature headers,
+// signing the host, content-type and x-webhook-id headers besides the timestamp and body), so a rhodium
+// receiver only needs a SignatureHandler with the same key.
+// Failed deliveries (transport errors, timeouts, non 2xx answers) are sent again with the backoff of
+// the retry policy, and after its retries they are kept as dead letters (the oldest dropped beyond
+// max_dead_letters) to be inspected or enqueued again. enqueue never waits: with the queue full, the
+// event is dropped and counted in the stats.
+use crate::background::BackgroundJob;
+use crate::client::{RetryPolicy, RhodClient};
+use crate::errors::{RhodError, RhodErrorLevel, RhodResult};
+use crate::handlers::{SignatureHandler, SignatureScheme};
+use crate::request::RhodRequest;
+use crate::runtime;
+use http::{Method, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Semaphore};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    pub kind: String, // ie: order.created
+    pub time: u64,    // unix millis
+    pub data: serde_json::Value,
+}
+
+impl WebhookEvent {
+    pub fn new(kind: &str, data: serde_json::Value) -> WebhookEvent {
+        WebhookEvent {
+            id: format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..)),
+            kind: kind.to_string(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            data,
+        }
+    }
+}
+
+pub struct WebhookSubscriber {
+    url: Uri,
+    kinds: Vec<String>, // empty for every kind
+    signer: SignatureHandler,
+}
+
+impl WebhookSubscriber {
+    pub fn new(url: &str, secret: &[u8]) -> RhodResult<WebhookSubscriber> {
+        let url: Uri = url.parse().map_err(|_| {
+            RhodError::from_string(
+                format!("Invalid webhook url {}", url),
+                RhodErrorLevel::Error,
+            )
+        })?;
+        if url.host().is_none() {
+            return Err(RhodError::from_string(
+                format!("Webhook url {} without host", url),
+                RhodErrorLevel::Error,
+            ));
+        }
+        let scheme = SignatureScheme {
+            signed_headers: vec![
+                "host".to_string(),
+                "content-type".to_string(),
+                "x-webhook-id".to_string(),
+            ],
+            ..SignatureScheme::default()
+        };
+        Ok(WebhookSubscriber {
+            url,
+            kinds: vec![],
+            signer: SignatureHandler::new(scheme).with_key(KEY_ID, secret),
+        })
+    }
+
+    // Signature headers of the deliveries, the default scheme of SignatureHandler if not set
+    pub fn with_scheme(self, scheme: SignatureScheme, secret: &[u8]) -> Self {
+        WebhookSubscriber {
+            signer: SignatureHandler::new(scheme).with_key(KEY_ID, secret),
+            ..self
+        }
+    }
+
+    // Kinds of events delivered to the subscriber, all of them if not set
+    pub fn with_kind(mut self, kind: &str) -> Self {
+        self.kinds.push(kind.to_string());
+        self
+    }
+
+    fn subscribed(&self, kind: &str) -> bool {
+        self.kinds.is_empty() || self.kinds.iter().any(|k| k == kind)
+    }
+}
+
+// Key id sent with the signatures of the deliveries
+const KEY_ID: &str = "webhook";
+
+// Delivery that failed every attempt
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+    pub event: WebhookEvent,
+    pub url: String,
+    pub attempts: u32,
+    pub error: String, // of the last attempt
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct WebhookStats {
+    pub enqueued: u64,
+    pub dropped: u64,   // enqueued with the queue full
+    pub delivered: u64, // deliveries answered with 2xx
+    pub retries: u64,
+    pub dead_lettered: u64,
+    pub in_flight: u64, // deliveries being sent or waiting to retry
+}
+
+#[derive(Default)]
+struct Counters {
+    enqueued: AtomicU64,
+    dropped: AtomicU64,
+    delivered: AtomicU64,
+    retries: AtomicU64,
+    dead_lettered: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+struct Dispatcher {
+    subscribers: Vec<Arc<WebhookSubscriber>>,
+    client: RhodClient,
+    retry: RetryPolicy,
+    timeout: Duration,
+    max_in_flight: usize,
+    max_dead_letters: usize,
+    dead_letters: Mutex<VecDeque<DeadLetter>>,
+    counters: Counters,
+}
+
+// Handle to enqueue events. Clones enqueue to the same dispatcher
+//
+//  let orders = WebhookSubscriber::new("https://hooks.example.com/orders", b"secret")?
+//      .with_kind("order.created");
+//  let webhooks = Webhooks::new(1024).with_subscriber(orders);
+//  rhod.with_background_job(webhooks.dispatch_job());
+//  webhooks.enqueue(WebhookEvent::new("order.created", json!({"id": 42})));
+#[derive(Clone)]
+pub struct Webhooks {
+    sender: mpsc::Sender<WebhookEvent>,
+    receiver: Arc<Mutex<Option<mpsc::Receiver<WebhookEvent>>>>,
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl Webhooks {
+    // Queue of queue_size events waiting to be dispatched
+    pub fn new(queue_size: usize) -> Webhooks {
+        let (sender, receiver) = mpsc::channel(queue_size.max(1));
+        Webhooks {
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            dispatcher: Arc::new(Dispatcher {
+                subscribers: vec![],
+                client: RhodClient::new(),
+                retry: RetryPolicy::new(5)
+                    .with_backoff(Duration::from_secs(1), Duration::from_secs(60)),
+                timeout: Duration::from_secs(10),
+                max_in_flight: 32,
+                max_dead_letters: 1000,
+                dead_letters: Mutex::new(VecDeque::new()),
+                counters: Counters::default(),
+            }),
+        }
+    }
+
+    // Settings are set before cloning the handle or dispatching
+    fn update<F: FnOnce(&mut Dispatcher)>(mut self, update: F) -> Self {
+        match Arc::get_mut(&mut self.dispatcher) {
+            Some(dispatcher) => update(dispatcher),
+            None => warn!("Webhooks settings ignored, the handle was already cloned"),
+        }
+        self
+    }
+
+    pub fn with_subscriber(self, subscriber: WebhookSubscriber) -> Self {
+        self.update(|d| d.subscribers.push(Arc::new(subscriber)))
+    }
+
+    pub fn with_client(self, client: RhodClient) -> Self {
+        self.update(|d| d.client = client)
+    }
+
+    // Retries of a failed delivery and their backoff, 5 retries from 1s up to 60s by default.
+    // Unlike the client, every failed delivery is retried, whatever the status of the answer
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        self.update(|d| d.retry = retry)
+    }
+
+    // Of every attempt, until the whole answer is received
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.update(|d| d.timeout = timeout)
+    }
+
+    // Deliveries sent (or waiting to retry) at once. Events wait in the queue beyond them
+    pub fn with_max_in_flight(self, max_in_flight: usize) -> Self {
+        self.update(|d| d.max_in_flight = max_in_flight.max(1))
+    }
+
+    pub fn with_max_dead_letters(self, max_dead_letters: usize) -> Self {
+        self.update(|d| d.max_dead_letters = max_dead_letters)
+    }
+
+    // Queues the event, false if it was dropped because the queue is full
+    pub fn enqueue(&self, event: WebhookEvent) -> bool {
+        let counters = &self.dispatcher.counters;
+        match self.sender.try_send(event) {
+            Ok(()) => {
+                counters.enqueued.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                warn!("Webhook event dropped. {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn stats(&self) -> WebhookStats {
+        let counters = &self.dispatcher.counters;
+        WebhookStats {
+            enqueued: counters.enqueued.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            delivered: counters.delivered.load(Ordering::Relaxed),
+            retries: counters.retries.load(Ordering::Relaxed),
+            dead_lettered: counters.dead_lettered.load(Ordering::Relaxed),
+            in_flight: counters.in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        let dead_letters = self.dispatcher.dead_letters.lock().unwrap();
+        dead_letters.iter().cloned().collect()
+    }
+
+    // Removes the dead letters, ie: to enqueue their events again once the subscriber is back
+    pub fn take_dead_letters(&self) -> Vec<DeadLetter> {
+        let mut dead_letters = self.dispatcher.dead_letters.lock().unwrap();
+        dead_letters.drain(..).collect()
+    }
+
+    // Job delivering the queued events. There is a single dispatcher per queue: a second job
+    // only logs an error
+    pub fn dispatch_job(&self) -> BackgroundJob {
+        let receiver = self.receiver.lock().unwrap().take();
+        let dispatcher = Arc::clone(&self.dispatcher);
+        BackgroundJob::once(async move {
+            match receiver {
+                Some(receiver) => dispatcher.run(receiver).await,
+                None => error!("Webhooks are already dispatched by another job"),
+            }
+        })
+    }
+}
+
+impl Dispatcher {
+    async fn run(self: Arc<Self>, mut receiver: mpsc::Receiver<WebhookEvent>) {
+        let permits = Arc::new(Semaphore::new(self.max_in_flight));
+        while let Some(event) = receiver.recv().await {
+            let event = Arc::new(event);
+            for subscriber in &self.subscribers {
+                if !subscriber.subscribed(&event.kind) {
+                    continue;
+                }
+                let permit = match Arc::clone(&permits).acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+                self.counters.in_flight.fetch_add(1, Ordering::Relaxed);
+                let dispatcher = Arc::clone(&self);
+                let subscriber = Arc::clone(subscriber);
+                let event = Arc::clone(&event);
+                drop(runtime::spawn(async move {
+                    dispatcher.deliver(&subscriber, &event).await;
+                    dispatcher
+                        .counters
+                        .in_flight
+                        .fetch_sub(1, Ordering::Relaxed);
+                    drop(permit);
+                }));
+            }
+        }
+    }
+
+    async fn deliver(&self, subscriber: &WebhookSubscriber, event: &WebhookEvent) {
+        let mut attempt = 0;
+        loop {
+            let error = match self.send(subscriber, event).await {
+                Ok(()) => {
+                    self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(e) => e,
+            };
+            if attempt >= self.retry.max_retries {
+                warn!(
+                    "Webhook {} to {} dead lettered after {} attempts. {}",
+                    event.id,
+                    subscriber.url,
+                    attempt + 1,
+                    error
+                );
+                self.dead_letter(DeadLetter {
+                    event: event.clone(),
+                    url: subscriber.url.to_string(),
+                    attempts: attempt + 1,
+                    error,
+                });
+                return;
+            }
+            debug!(
+                "Webhook {} to {} failed, retrying. {}",
+                event.id, subscriber.url, error
+            );
+            runtime::sleep(self.retry.backoff(attempt)).await;
+            attempt += 1;
+            self.counters.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn send(
+        &self,
+        subscriber: &WebhookSubscriber,
+        event: &WebhookEvent,
+    ) -> Result<(), String> {
+        let host = subscriber.url.authority().map_or("", |a| a.as_str());
+        let mut req = RhodRequest::builder()
+            .method(Method::POST)
+            .uri(&subscriber.url.to_string())
+            .header("host", host)
+            .header("x-webhook-id", &event.id)
+            .header("x-webhook-event", &event.kind)
+            .body_json(event)
+            .build()
+            .map_err(|e| e.to_string())?;
+        subscriber
+            .signer
+            .sign(&mut req, KEY_ID)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let sent = async {
+            let mut res = self.client.send(req).await?;
+            // read the whole answer, so the connection goes back to the pool
+            res.body().await?;
+            Ok::<_, RhodError>(res.status_as_int())
+        };
+        match runtime::timeout(self.timeout, sent).await {
+            Ok(Ok(status)) if (200..300).contains(&status) => Ok(()),
+            Ok(Ok(status)) => Err(format!("Answered {}", status)),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("Timed out after {:?}", self.timeout)),
+        }
+    }
+
+    fn dead_letter(&self, dead_letter: DeadLetter) {
+        self.counters.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        dead_letters.push_back(dead_letter);
+        while dead_letters.len() > self.max_dead_letters {
+            dead_letters.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::RhodBody;
+    use crate::handlers::Principal;
+    use crate::protocols::HttpProtocol;
+    use crate::stack::RhodHandler;
+    use crate::RhodConnInfo;
+    use http::{Request as HyperRequest, Response as HyperResponse, StatusCode};
+    use hyper::body::Incoming;
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use std::convert::Infallible;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    // Receiver verifying the signatures, failing the first call to /flaky and every call to /down
+    async fn spawn_receiver(received: Arc<Mutex<Vec<WebhookEvent>>>) -> std::net::SocketAddr {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let flaky_calls = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = tcp.accept().await {
+                let received = Arc::clone(&received);
+                let flaky_calls = Arc::clone(&flaky_calls);
+                let service = service_fn(move |req: HyperRequest<Incoming>| {
+                    let received = Arc::clone(&received);
+                    let flaky_calls = Arc::clone(&flaky_calls);
+                    async move {
+                        let mut req = RhodRequest::new(req.map(RhodBody::new));
+                        let verifier = SignatureHandler::new(SignatureScheme {
+                            signed_headers: vec![
+                                "host".to_string(),
+                                "content-type".to_string(),
+                                "x-webhook-id".to_string(),
+                            ],
+                            ..SignatureScheme::default()
+                        })
+                        .with_key(KEY_ID, b"secret");
+                        let conn = RhodConnInfo::new(
+                            "127.0.0.1:4000".parse().unwrap(),
+                            HttpProtocol::HTTP,
+                        );
+                        let verified =
+                            RhodHandler::<()>::handle_request(&verifier, &conn, &mut req, &mut ())
+                                .await
+                                .is_ok()
+                                && req.context().get::<Principal>().is_some();
+                        let status = match req.uri().path() {
+                            _ if !verified => StatusCode::UNAUTHORIZED,
+                            "/flaky" if flaky_calls.fetch_add(1, Ordering::SeqCst) == 0 => {
+                                StatusCode::SERVICE_UNAVAILABLE
+                            }
+                            "/down" => StatusCode::INTERNAL_SERVER_ERROR,
+                            _ => {
+                                let body = req.body().await.unwrap();
+                                received
+                                    .lock()
+                                    .unwrap()
+                                    .push(serde_json::from_slice(&body).unwrap());
+                                StatusCode::OK
+                            }
+                        };
+                        let mut res = HyperResponse::new(RhodBody::empty());
+                        *res.status_mut() = status;
+                        Ok::<_, Infallible>(res)
+                    }
+                });
+                tokio::spawn(async move {
+                    let _ = Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let addr = spawn_receiver(Arc::clone(&received)).await;
+        let url = |path: &str| format!("http://{}{}", addr, path);
+        let webhooks = Webhooks::new(16)
+            .with_subscriber(
+                WebhookSubscriber::new(&url("/flaky"), b"secret")
+                    .unwrap()
+                    .with_kind("order.created"),
+            )
+            .with_subscriber(WebhookSubscriber::new(&url("/down"), b"secret").unwrap())
+            .with_subscriber(WebhookSubscriber::new(&url("/forged"), b"wrong").unwrap())
+            .with_retry(
+                RetryPolicy::new(2)
+                    .with_backoff(Duration::from_millis(5), Duration::from_millis(10)),
+            );
+        let job = match webhooks.dispatch_job() {
+            BackgroundJob::Once(job) => job,
+            _ => panic!("Not a one time job"),
+        };
+        let dispatching = tokio::spawn(job);
+
+        let event = WebhookEvent::new("order.created", serde_json::json!({"id": 42}));
+        assert!(webhooks.enqueue(event.clone()));
+        assert!(webhooks.enqueue(WebhookEvent::new("order.deleted", serde_json::json!({}))));
+
+        // 1 delivered, 4 dead letters (down and forged for both events)
+        for _ in 0..200 {
+            let stats = webhooks.stats();
+            if stats.dead_lettered == 4 && stats.delivered == 1 && stats.in_flight == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(received.lock().unwrap().clone(), vec![event]);
+        let stats = webhooks.stats();
+        assert_eq!(stats.enqueued, 2);
+        assert_eq!(stats.delivered, 1);
+        assert_eq!(stats.dead_lettered, 4);
+        // 1 of flaky, 2 of every dead letter
+        assert_eq!(stats.retries, 9);
+        assert_eq!(stats.in_flight, 0);
+
+        let dead_letters = webhooks.take_dead_letters();
+        assert_eq!(dead_letters.len(), 4);
+        assert!(dead_letters
+            .iter()
+            .any(|d| d.url.ends_with("/down") && d.attempts == 3 && d.error == "Answered 500"));
+        assert!(dead_letters.iter().any(|d| d.error == "Answered 401"));
+        assert!(webhooks.dead_letters().is_empty());
+        dispatching.abort();
+    }
+}
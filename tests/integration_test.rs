@@ -1,8 +1,10 @@
 use async_trait::async_trait;
-use hyper::{body::Body, client::connect::HttpConnector, Client, Response, StatusCode};
+use http::{Response, StatusCode};
 use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
 use native_tls::{Certificate, TlsConnector};
-use rhodium::{errors::*, request::*, response::*, stack::*, *};
+use rhodium::{body::RhodBody, errors::*, request::*, response::*, stack::*, *};
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -35,7 +37,7 @@ impl RhodService<Comm> for Service {
         } else {
             let res = Response::builder()
                 .status(StatusCode::OK)
-                .body(Body::empty())
+                .body(RhodBody::empty())
                 .unwrap();
 
             let res = RhodResponse::new(res);
@@ -92,7 +94,7 @@ fn spawn_rhod(rhod: Rhodium<Comm>) {
         let rt = Runtime::new().unwrap();
 
         // Execute the future, blocking the current thread until completion
-        rt.block_on(rhod.run());
+        rt.block_on(rhod.run()).unwrap();
     });
 
     thread::sleep(time::Duration::from_millis(5000));
@@ -110,9 +112,177 @@ async fn test_complete_transaction() {
     spawn_rhod(rhod);
 
     //Creates client and gets response
-    let client = Client::new();
+    let client = Client::builder(TokioExecutor::new()).build_http::<RhodBody>();
     let uri = "http://127.0.0.1:3000".parse().unwrap();
     client.get(uri).await.unwrap();
+
+    //HTTP/2 with prior knowledge (h2c)
+    let client = Client::builder(TokioExecutor::new())
+        .http2_only(true)
+        .build_http::<RhodBody>();
+    let uri = "http://127.0.0.1:3000".parse().unwrap();
+    let res = client.get(uri).await.unwrap();
+    assert_eq!(res.version(), http::Version::HTTP_2);
+}
+
+#[tokio::test]
+async fn test_current_thread_runtime() {
+    //create server in a single threaded runtime
+    let stack = RhodStack::new(vec![], Box::new(Service {}));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3005),
+        protocols::HttpProtocolConf::HTTP,
+    );
+    thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(rhod.run()).unwrap();
+    });
+    thread::sleep(time::Duration::from_millis(5000));
+
+    //Creates client and gets response
+    let client = Client::builder(TokioExecutor::new()).build_http::<RhodBody>();
+    let uri = "http://127.0.0.1:3005".parse().unwrap();
+    let res = client.get(uri).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_many_acceptors() {
+    //create server with 4 acceptors
+    let stack = RhodStack::new(vec![], Box::new(Service {}));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3003),
+        protocols::HttpProtocolConf::HTTP,
+    )
+    .with_acceptors(4);
+    spawn_rhod(rhod);
+
+    //Creates clients and gets responses
+    for _ in 0..8 {
+        let client = Client::builder(TokioExecutor::new()).build_http::<RhodBody>();
+        let uri = "http://127.0.0.1:3003".parse().unwrap();
+        let res = client.get(uri).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn test_draining() {
+    //create server, keeping the drain switch
+    let stack = RhodStack::new(vec![], Box::new(Service {}));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3006),
+        protocols::HttpProtocolConf::HTTP,
+    )
+    .with_drain(drain::Drain::new().with_exempt_path("/health"));
+    let drain = rhod.drain();
+    spawn_rhod(rhod);
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<RhodBody>();
+    let res = client.get("http://127.0.0.1:3006".parse().unwrap()).await;
+    assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+    drain.start();
+    let res = client.get("http://127.0.0.1:3006".parse().unwrap()).await;
+    let res = res.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.headers().contains_key(http::header::RETRY_AFTER));
+    let res = client
+        .get("http://127.0.0.1:3006/health".parse().unwrap())
+        .await;
+    assert_eq!(res.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_inherited_listener() {
+    //listener bound before creating the server, as the previous process would
+    let addr = SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3007);
+    let listener = protocols::SocketConf::new().bind_listener(&addr).unwrap();
+    let stack = RhodStack::new(vec![], Box::new(Service {}));
+    let rhod = Rhodium::new(Arc::new(stack), addr, protocols::HttpProtocolConf::HTTP)
+        .with_listeners(vec![listener]);
+    spawn_rhod(rhod);
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<RhodBody>();
+    let res = client.get("http://127.0.0.1:3007".parse().unwrap()).await;
+    assert_eq!(res.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_hyper_builder() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    //create server with an option not wrapped by ConnectionConf
+    let stack = RhodStack::new(vec![], Box::new(Service {}));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3008),
+        protocols::HttpProtocolConf::HTTP,
+    )
+    .with_hyper_builder(|mut builder| {
+        builder.http1().title_case_headers(true);
+        builder
+    });
+    spawn_rhod(rhod);
+
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3008")
+        .await
+        .unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    client.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200"));
+    assert!(res.contains("\r\nContent-Length: "));
+}
+
+#[tokio::test]
+async fn test_connect_tunnel() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    //echo server, target of the tunnel
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = echo.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    //create proxy
+    let stack: RhodStack<Comm> =
+        RhodStack::new(vec![], Box::new(services::ConnectTunnelService::new()));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3004),
+        protocols::HttpProtocolConf::HTTP,
+    );
+    spawn_rhod(rhod);
+
+    //opens the tunnel and talks with the echo server through it
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3004")
+        .await
+        .unwrap();
+    let connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+    client.write_all(connect.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(client.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200"));
+
+    client.write_all(b"ping").await.unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
 }
 
 #[tokio::test]
@@ -130,7 +300,7 @@ async fn test_error_handler() {
     spawn_rhod(rhod);
 
     //Creates client and gets response
-    let client = Client::new();
+    let client = Client::builder(TokioExecutor::new()).build_http::<RhodBody>();
     let uri = "http://127.0.0.1:3001".parse().unwrap();
     assert!(client.get(uri).await.is_err());
 }
@@ -162,7 +332,221 @@ async fn test_ssl() {
     let https = HttpsConnector::from((http, tls.into()));
 
     //Creates client and gets response
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    let client = Client::builder(TokioExecutor::new()).build::<_, RhodBody>(https);
     let uri = "https://localhost:3002".parse().unwrap();
     client.get(uri).await.unwrap();
 }
+
+#[test]
+fn test_describe() {
+    let stack = RhodStack::new(
+        vec![RhodHandlerInStack::RhodHandler(Box::new(ErrorHandler {}))],
+        Box::new(Service {}),
+    );
+    let description = stack.describe();
+    assert_eq!(
+        description.handlers,
+        vec!["integration_test::ErrorHandler".to_string()]
+    );
+    assert_eq!(description.service, "integration_test::Service");
+    assert_eq!(
+        description.to_string(),
+        "integration_test::ErrorHandler -> integration_test::Service"
+    );
+}
+
+// Answers with the request body
+struct EchoService {}
+#[async_trait]
+impl RhodService<Comm> for EchoService {
+    async fn serve(
+        &self,
+        _conn: &RhodConnInfo,
+        mut req: RhodRequest,
+        _comm: &mut Comm,
+    ) -> RhodResult<RhodResponse> {
+        let body = req.body().await?;
+        RhodResponse::builder().body_bytes(&body).build()
+    }
+}
+
+#[tokio::test]
+async fn test_expect_continue() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let handler = handlers::ExpectContinueHandler::new().with_max_body_size(10);
+    let stack = RhodStack::new(
+        vec![RhodHandlerInStack::RhodHandler(Box::new(handler))],
+        Box::new(EchoService {}),
+    );
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3009),
+        protocols::HttpProtocolConf::HTTP,
+    );
+    spawn_rhod(rhod);
+
+    // the body is sent after 100 Continue
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3009")
+        .await
+        .unwrap();
+    client
+        .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut interim = [0; 25];
+    client.read_exact(&mut interim).await.unwrap();
+    assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+    client.write_all(b"hello").await.unwrap();
+    let mut res = String::new();
+    client.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200"));
+    assert!(res.ends_with("hello"));
+
+    // a body too large is rejected before it is sent
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3009")
+        .await
+        .unwrap();
+    client
+        .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 100\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    client.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 413"));
+}
+
+// Hints the stylesheet, and answers after a while
+struct EarlyHintsService {}
+#[async_trait]
+impl RhodService<Comm> for EarlyHintsService {
+    async fn serve(
+        &self,
+        _conn: &RhodConnInfo,
+        req: RhodRequest,
+        _comm: &mut Comm,
+    ) -> RhodResult<RhodResponse> {
+        if let Some(informational) = req.context().get::<informational::Informational>() {
+            informational.early_hints(&["</app.css>; rel=preload; as=style"]);
+        }
+        tokio::time::sleep(time::Duration::from_millis(300)).await;
+        RhodResponse::builder().body_str("done").build()
+    }
+}
+
+#[tokio::test]
+async fn test_early_hints() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let stack = RhodStack::new(vec![], Box::new(EarlyHintsService {}));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3011),
+        protocols::HttpProtocolConf::HTTP,
+    );
+    spawn_rhod(rhod);
+
+    // the hints arrive while the response is being prepared
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3011")
+        .await
+        .unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let hints = "HTTP/1.1 103 Early Hints\r\nlink: </app.css>; rel=preload; as=style\r\n\r\n";
+    let mut interim = vec![0; hints.len()];
+    tokio::time::timeout(
+        time::Duration::from_millis(200),
+        client.read_exact(&mut interim),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(interim, hints.as_bytes());
+    let mut res = String::new();
+    client.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200"));
+    assert!(res.ends_with("done"));
+
+    // HTTP/1.0 clients dont get them
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3011")
+        .await
+        .unwrap();
+    client
+        .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    client.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.0 200"));
+}
+
+// Refuses the second connection, and records the stats of every closed connection
+struct ConnTracker {
+    connected: std::sync::atomic::AtomicUsize,
+    closed: Arc<std::sync::Mutex<Vec<conn_handler::ConnStats>>>,
+}
+#[async_trait]
+impl conn_handler::RhodConnHandler for ConnTracker {
+    async fn on_connect(&self, _conn: &RhodConnInfo) -> RhodResult<()> {
+        match self
+            .connected
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        {
+            1 => Err(RhodError::from_str(
+                "Second connection",
+                RhodErrorLevel::Warning,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_disconnect(&self, _conn: &RhodConnInfo, stats: &conn_handler::ConnStats) {
+        self.closed.lock().unwrap().push(*stats);
+    }
+}
+
+#[tokio::test]
+async fn test_conn_handler() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let closed = Arc::new(std::sync::Mutex::new(vec![]));
+    let tracker = ConnTracker {
+        connected: std::sync::atomic::AtomicUsize::new(0),
+        closed: Arc::clone(&closed),
+    };
+    let stack = RhodStack::new(vec![], Box::new(Service {}));
+    let rhod = Rhodium::new(
+        Arc::new(stack),
+        SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 3010),
+        protocols::HttpProtocolConf::HTTP,
+    )
+    .with_conn_handler(Box::new(tracker));
+    spawn_rhod(rhod);
+
+    // two requests on the first connection
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3010")
+        .await
+        .unwrap();
+    let req = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    client.write_all(req).await.unwrap();
+    let mut res = vec![];
+    client.read_to_end(&mut res).await.unwrap();
+
+    // the second one is closed without being served
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3010")
+        .await
+        .unwrap();
+    let mut refused = vec![];
+    client.read_to_end(&mut refused).await.unwrap();
+    assert!(refused.is_empty());
+
+    thread::sleep(time::Duration::from_millis(100));
+    let closed = closed.lock().unwrap();
+    assert_eq!(closed.len(), 2);
+    assert_eq!(closed[0].requests, 2);
+    assert_eq!(closed[0].bytes_read, req.len() as u64);
+    assert_eq!(closed[0].bytes_written, res.len() as u64);
+    assert_eq!(closed[1].requests, 0);
+}